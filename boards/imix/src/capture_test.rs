@@ -0,0 +1,81 @@
+//! Runs `capsules::test::capture`, in which the frames that a simulated node
+//! exchanges with another are captured to a `TestUart`.
+//!
+//! The radios and Mac layers of the nodes are instantiated by
+//! `sim_lowpan_test::static_init_awake_mac`, so the test does not depend on
+//! the RF233 or on USART0, and runs whether or not the `ieee802154_capture`
+//! feature is enabled. It can be run by calling `capture_test::run()` at the
+//! end of `reset_handler`.
+
+use capsules::aes_ccm;
+use capsules::ieee802154::capture::Capture;
+use capsules::ieee802154::device::MacDevice;
+use capsules::ieee802154::framer::Framer;
+use capsules::ieee802154::mac::Mac;
+use capsules::sim_radio::{SimAlarm, SimClock, SimMedium};
+use capsules::test::capture::{CaptureTest, SimCapture, TestUart};
+use capsules::test::sim_lowpan::{NODE0_ADDR_LONG, NODE1_ADDR_LONG};
+use kernel::hil::radio;
+use kernel::hil::symmetric_encryption::AES128_BLOCK_SIZE;
+use kernel::hil::uart::UART;
+use sam4l::aes::AES;
+use sim_lowpan_test::{static_init_awake_mac, static_init_framer, AESCCM};
+
+type CaptureFramer = Framer<'static, SimCapture<'static>, AESCCM>;
+
+pub unsafe fn run() {
+    let clock = static_init!(SimClock<'static>, SimClock::new());
+    let medium_alarm = static_init!(SimAlarm<'static>, SimAlarm::new(clock));
+    clock.add_alarm(medium_alarm);
+    let medium = static_init!(
+        SimMedium<'static, SimAlarm<'static>>,
+        SimMedium::new(medium_alarm)
+    );
+    medium_alarm.set_client(medium);
+
+    const CRYPT_SIZE: usize = 7 * AES128_BLOCK_SIZE;
+    let crypt_buf = static_init!([u8; CRYPT_SIZE], [0x00; CRYPT_SIZE]);
+    let aes_ccm = static_init!(AESCCM, aes_ccm::AES128CCM::new(&AES, crypt_buf));
+
+    // Node 0 is instantiated as in the kernel, with the capture between its
+    // Mac layer and its Framer
+    let awake_mac = static_init_awake_mac(clock, medium, NODE0_ADDR_LONG, 1);
+    let stream_buf = static_init!([u8; 4096], [0x00; 4096]);
+    let uart = static_init!(TestUart, TestUart::new(stream_buf));
+    let capture_alarm = static_init!(SimAlarm<'static>, SimAlarm::new(clock));
+    clock.add_alarm(capture_alarm);
+    let capture_buf1 = static_init!([u8; 1024], [0x00; 1024]);
+    let capture_buf2 = static_init!([u8; 1024], [0x00; 1024]);
+    let capture = static_init!(
+        SimCapture<'static>,
+        Capture::new(
+            awake_mac,
+            uart,
+            921600,
+            capture_alarm,
+            capture_buf1,
+            capture_buf2
+        )
+    );
+    awake_mac.set_transmit_client(capture);
+    awake_mac.set_receive_client(capture);
+    uart.set_client(capture);
+
+    let mac0 = static_init!(CaptureFramer, Framer::new(capture, aes_ccm));
+    capture.set_transmit_client(mac0);
+    capture.set_receive_client(mac0);
+    capture.set_config_client(mac0);
+    let mac1 = static_init_framer(clock, medium, aes_ccm, NODE1_ADDR_LONG, 2);
+
+    let tx_buf = static_init!([u8; radio::MAX_BUF_SIZE], [0x00; radio::MAX_BUF_SIZE]);
+    let t = static_init!(
+        CaptureTest<'static>,
+        CaptureTest::new(clock, medium, capture, uart, mac0, mac1, tx_buf)
+    );
+    mac0.set_transmit_client(t);
+    mac0.set_receive_client(t);
+    mac1.set_transmit_client(t);
+    mac1.set_receive_client(t);
+
+    t.run();
+}
@@ -0,0 +1,90 @@
+//! Runs `capsules::test::coap`, in which a CoAP server on one simulated node
+//! answers the requests of another over UDP and 6LoWPAN.
+//!
+//! The nodes are instantiated by `sim_lowpan_test::static_init_node`, so the
+//! test does not depend on the RF233. It can be run by calling
+//! `coap_test::run()` at the end of `reset_handler`.
+
+use capsules::aes_ccm;
+use capsules::net::coap::layer::{CoapLayer, MessageLayer, Transport};
+use capsules::net::coap::message::HEADER_SIZE;
+use capsules::net::coap::server::CoapServer;
+use capsules::net::coap::udp::{UdpTransport, COAP_PORT, IP6_HEADER_SIZE, UDP_HEADER_SIZE};
+use capsules::net::ieee802154::MacAddress;
+use capsules::net::sixlowpan_compression::Context;
+use capsules::sim_radio::{SimAlarm, SimClock, SimMedium};
+use capsules::test::coap::{CoapTest, BUF_LEN, CLIENT_PORT, SERVER_MESSAGE_ID};
+use capsules::test::sim_lowpan::{link_local_addr, NODE0_ADDR_LONG, NODE1_ADDR_LONG};
+use kernel::hil::symmetric_encryption::AES128_BLOCK_SIZE;
+use sam4l::aes::AES;
+use sim_lowpan_test::{static_init_node, SixlowpanDevice, AESCCM};
+
+type UdpTransportDevice = UdpTransport<'static, SimAlarm<'static>, Context>;
+
+const PACKET_LEN: usize = IP6_HEADER_SIZE + UDP_HEADER_SIZE + BUF_LEN;
+
+pub unsafe fn run() {
+    let clock = static_init!(SimClock<'static>, SimClock::new());
+    let medium_alarm = static_init!(SimAlarm<'static>, SimAlarm::new(clock));
+    clock.add_alarm(medium_alarm);
+    let medium = static_init!(
+        SimMedium<'static, SimAlarm<'static>>,
+        SimMedium::new(medium_alarm)
+    );
+    medium_alarm.set_client(medium);
+
+    const CRYPT_SIZE: usize = 7 * AES128_BLOCK_SIZE;
+    let crypt_buf = static_init!([u8; CRYPT_SIZE], [0x00; CRYPT_SIZE]);
+    let aes_ccm = static_init!(AESCCM, aes_ccm::AES128CCM::new(&AES, crypt_buf));
+
+    let node0 = static_init_node(clock, medium, aes_ccm, NODE0_ADDR_LONG, 1);
+    let node1 = static_init_node(clock, medium, aes_ccm, NODE1_ADDR_LONG, 2);
+    let client = static_init_transport(node0, NODE0_ADDR_LONG, CLIENT_PORT);
+    let server_udp = static_init_transport(node1, NODE1_ADDR_LONG, COAP_PORT);
+
+    let coap_alarm = static_init!(SimAlarm<'static>, SimAlarm::new(clock));
+    clock.add_alarm(coap_alarm);
+    let empty_buf = static_init!([u8; HEADER_SIZE], [0x00; HEADER_SIZE]);
+    let layer = static_init!(
+        CoapLayer<'static, SimAlarm<'static>>,
+        CoapLayer::new(server_udp, coap_alarm, empty_buf, SERVER_MESSAGE_ID)
+    );
+    coap_alarm.set_client(layer);
+    Transport::set_client(server_udp, layer);
+
+    let tx_buf1 = static_init!([u8; BUF_LEN], [0x00; BUF_LEN]);
+    let tx_buf2 = static_init!([u8; BUF_LEN], [0x00; BUF_LEN]);
+    let server = static_init!(CoapServer<'static>, CoapServer::new(layer, tx_buf1));
+    server.add_tx_buffer(tx_buf2);
+    MessageLayer::set_client(layer, server);
+
+    let test_alarm = static_init!(SimAlarm<'static>, SimAlarm::new(clock));
+    clock.add_alarm(test_alarm);
+    let request = static_init!([u8; BUF_LEN], [0x00; BUF_LEN]);
+    let t = static_init!(
+        CoapTest<'static>,
+        CoapTest::new(clock, medium, test_alarm, client, server, server_udp, request)
+    );
+    test_alarm.set_client(t);
+    Transport::set_client(client, t);
+    server.add_resource(t);
+
+    t.run();
+}
+
+/// Instantiates a `UdpTransport` on `port` of the node with the extended
+/// address `addr_long`.
+unsafe fn static_init_transport(
+    sixlowpan: &'static SixlowpanDevice,
+    addr_long: [u8; 8],
+    port: u16,
+) -> &'static UdpTransportDevice {
+    let packet = static_init!([u8; PACKET_LEN], [0x00; PACKET_LEN]);
+    let transport = static_init!(
+        UdpTransportDevice,
+        UdpTransport::new(sixlowpan, packet, port)
+    );
+    transport.set_address(link_local_addr(addr_long), MacAddress::Long(addr_long));
+    transport.start();
+    transport
+}
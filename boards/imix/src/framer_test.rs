@@ -0,0 +1,48 @@
+//! Runs `capsules::test::framer`, in which two simulated nodes send frames to
+//! a third, including retransmissions whose acknowledgement was lost.
+//!
+//! The radios and Mac layers of the nodes are instantiated by
+//! `sim_lowpan_test::static_init_framer`, so the test does not depend on the
+//! RF233. It can be run by calling `framer_test::run()` at the end of
+//! `reset_handler`.
+
+use capsules::aes_ccm;
+use capsules::ieee802154::device::MacDevice;
+use capsules::sim_radio::{SimAlarm, SimClock, SimMedium};
+use capsules::test::framer::{FramerTest, NODE2_ADDR_LONG};
+use capsules::test::sim_lowpan::{NODE0_ADDR_LONG, NODE1_ADDR_LONG};
+use kernel::hil::radio;
+use kernel::hil::symmetric_encryption::AES128_BLOCK_SIZE;
+use sam4l::aes::AES;
+use sim_lowpan_test::{static_init_framer, AESCCM};
+
+pub unsafe fn run() {
+    let clock = static_init!(SimClock<'static>, SimClock::new());
+    let medium_alarm = static_init!(SimAlarm<'static>, SimAlarm::new(clock));
+    clock.add_alarm(medium_alarm);
+    let medium = static_init!(
+        SimMedium<'static, SimAlarm<'static>>,
+        SimMedium::new(medium_alarm)
+    );
+    medium_alarm.set_client(medium);
+
+    const CRYPT_SIZE: usize = 7 * AES128_BLOCK_SIZE;
+    let crypt_buf = static_init!([u8; CRYPT_SIZE], [0x00; CRYPT_SIZE]);
+    let aes_ccm = static_init!(AESCCM, aes_ccm::AES128CCM::new(&AES, crypt_buf));
+
+    let mac0 = static_init_framer(clock, medium, aes_ccm, NODE0_ADDR_LONG, 1);
+    let mac1 = static_init_framer(clock, medium, aes_ccm, NODE1_ADDR_LONG, 2);
+    let mac2 = static_init_framer(clock, medium, aes_ccm, NODE2_ADDR_LONG, 3);
+
+    let tx_buf1 = static_init!([u8; radio::MAX_BUF_SIZE], [0x00; radio::MAX_BUF_SIZE]);
+    let tx_buf2 = static_init!([u8; radio::MAX_BUF_SIZE], [0x00; radio::MAX_BUF_SIZE]);
+    let t = static_init!(
+        FramerTest<'static>,
+        FramerTest::new(clock, medium, mac0, mac2, tx_buf1, tx_buf2)
+    );
+    mac0.set_transmit_client(t);
+    mac2.set_transmit_client(t);
+    mac1.set_receive_client(t);
+
+    t.run();
+}
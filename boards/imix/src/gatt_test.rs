@@ -0,0 +1,22 @@
+//! Runs `capsules::test::gatt`, which checks the ATT codec and a GATT server
+//! against recorded ATT PDUs.
+//!
+//! The server sends its responses to a `TestBearer` rather than over a BLE
+//! connection, so the test does not need a radio. It can be run by calling
+//! `gatt_test::run()` at the end of `reset_handler`.
+
+use capsules::net::ble::att::AttBearer;
+use capsules::net::ble::gatt::GattServer;
+use capsules::test::gatt::{GattTest, TestBearer, TX_BUF_LEN};
+
+pub unsafe fn run() {
+    let bearer = static_init!(TestBearer<'static>, TestBearer::new());
+    let tx_buf = static_init!([u8; TX_BUF_LEN], [0x00; TX_BUF_LEN]);
+    let server = static_init!(GattServer<'static>, GattServer::new(bearer, tx_buf));
+    bearer.set_client(server);
+
+    let t = static_init!(GattTest<'static>, GattTest::new(server, bearer));
+    server.set_client(t);
+
+    t.run();
+}
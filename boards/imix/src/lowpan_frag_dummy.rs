@@ -30,6 +30,7 @@
 //! // Radio initialization code
 //! ...
 //! let lowpan_frag_test = lowpan_frag_dummy::initialize_all(radio_mac as &'static MacDevice,
+//!                                                          mlme as &'static Mlme,
 //!                                                          mux_alarm as &'static
 //!                                                             MuxAlarm<'static,
 //!                                                                 sam4l::ast::Ast>);
@@ -40,7 +41,8 @@
 
 use capsules;
 extern crate sam4l;
-use capsules::ieee802154::device::MacDevice;
+use capsules::ieee802154::device::{MacDevice, SecurityError};
+use capsules::ieee802154::mlme::Mlme;
 use capsules::net::ieee802154::MacAddress;
 use capsules::net::ip::{IP6Header, IPAddr, ip6_nh};
 use capsules::net::sixlowpan::{Sixlowpan, SixlowpanClient};
@@ -123,6 +125,7 @@ pub struct LowpanTest<'a, A: time::Alarm + 'a, T: time::Alarm + 'a> {
 
 pub unsafe fn initialize_all(
     radio_mac: &'static MacDevice,
+    mlme: &'static Mlme<'static>,
     mux_alarm: &'static MuxAlarm<'static, sam4l::ast::Ast>,
 ) -> &'static LowpanTest<
     'static,
@@ -156,6 +159,7 @@ pub unsafe fn initialize_all(
 
     radio_mac.set_transmit_client(&lowpan_frag_test.frag_state);
     radio_mac.set_receive_client(&lowpan_frag_test.frag_state);
+    mlme.add_association_client(&lowpan_frag_test.frag_state);
 
     lowpan_frag_test.init();
     lowpan_frag_test
@@ -363,6 +367,10 @@ impl<'a, A: time::Alarm, T: time::Alarm + 'a> SixlowpanClient for LowpanTest<'a,
         debug!("Send completed");
         self.schedule_next();
     }
+
+    fn receive_security_failure(&self, _: Option<MacAddress>, error: SecurityError) {
+        debug!("Receive failed security: {:?}", error);
+    }
 }
 
 static mut IP6_DGRAM: [u8; IP6_HDR_SIZE + PAYLOAD_LEN] = [0; IP6_HDR_SIZE + PAYLOAD_LEN];
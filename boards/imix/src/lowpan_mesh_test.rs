@@ -0,0 +1,61 @@
+//! Runs `capsules::test::lowpan_mesh`, in which four simulated nodes in a
+//! line forward 6LoWPAN frames with mesh and broadcast headers.
+//!
+//! The nodes are instantiated by `sim_lowpan_test::static_init_node`, so the
+//! test does not depend on the RF233. It can be run by calling
+//! `lowpan_mesh_test::run()` at the end of `reset_handler`.
+
+use capsules::aes_ccm;
+use capsules::sim_radio::{SimAlarm, SimClock, SimMedium};
+use capsules::test::lowpan_mesh::{MeshNode, MeshTest, HOPS_LEFT, NODE_ADDRS_LONG, PACKET_LEN};
+use kernel::hil::radio;
+use kernel::hil::symmetric_encryption::AES128_BLOCK_SIZE;
+use sam4l::aes::AES;
+use sim_lowpan_test::{static_init_node, AESCCM};
+
+pub unsafe fn run() {
+    let clock = static_init!(SimClock<'static>, SimClock::new());
+    let medium_alarm = static_init!(SimAlarm<'static>, SimAlarm::new(clock));
+    clock.add_alarm(medium_alarm);
+    let medium = static_init!(
+        SimMedium<'static, SimAlarm<'static>>,
+        SimMedium::new(medium_alarm)
+    );
+    medium_alarm.set_client(medium);
+
+    const CRYPT_SIZE: usize = 7 * AES128_BLOCK_SIZE;
+    let crypt_buf = static_init!([u8; CRYPT_SIZE], [0x00; CRYPT_SIZE]);
+    let aes_ccm = static_init!(AESCCM, aes_ccm::AES128CCM::new(&AES, crypt_buf));
+
+    let nodes = [
+        static_init_mesh_node(clock, medium, aes_ccm, 0),
+        static_init_mesh_node(clock, medium, aes_ccm, 1),
+        static_init_mesh_node(clock, medium, aes_ccm, 2),
+        static_init_mesh_node(clock, medium, aes_ccm, 3),
+    ];
+    let t = static_init!(MeshTest<'static>, MeshTest::new(clock, medium, nodes));
+
+    t.run();
+}
+
+/// Instantiates node `index` of the line, with mesh-under forwarding enabled
+unsafe fn static_init_mesh_node(
+    clock: &'static SimClock<'static>,
+    medium: &'static SimMedium<'static, SimAlarm<'static>>,
+    aes_ccm: &'static AESCCM,
+    index: usize,
+) -> &'static MeshNode<'static> {
+    let sixlowpan = static_init_node(
+        clock,
+        medium,
+        aes_ccm,
+        NODE_ADDRS_LONG[index],
+        index as u32 + 1,
+    );
+    let packet = static_init!([u8; PACKET_LEN], [0x00; PACKET_LEN]);
+    let node = static_init!(MeshNode<'static>, MeshNode::new(index, sixlowpan, packet));
+    let fwd_buf = static_init!([u8; radio::MAX_BUF_SIZE], [0x00; radio::MAX_BUF_SIZE]);
+    sixlowpan.set_client(node);
+    sixlowpan.enable_mesh(node, fwd_buf, HOPS_LEFT[index]);
+    node
+}
@@ -0,0 +1,46 @@
+//! Runs `capsules::test::lowpan_rx`, which passes UDP packets to the
+//! `Sixlowpan` layer of a simulated node as if they had been received in
+//! secured or unsecured frames, and checks the ICMPv6 errors that the node
+//! sends back.
+//!
+//! The nodes are instantiated by `sim_lowpan_test::static_init_node`, so the
+//! test does not depend on the RF233. It can be run by calling
+//! `lowpan_rx_test::run()` at the end of `reset_handler`.
+
+use capsules::aes_ccm;
+use capsules::sim_radio::{SimAlarm, SimClock, SimMedium};
+use capsules::test::lowpan_rx::{LowpanRxTest, BUF_LEN, ICMP_ERROR_LEN};
+use capsules::test::sim_lowpan::{NODE0_ADDR_LONG, NODE1_ADDR_LONG};
+use kernel::hil::symmetric_encryption::AES128_BLOCK_SIZE;
+use sam4l::aes::AES;
+use sim_lowpan_test::{static_init_node, AESCCM};
+
+pub unsafe fn run() {
+    let clock = static_init!(SimClock<'static>, SimClock::new());
+    let medium_alarm = static_init!(SimAlarm<'static>, SimAlarm::new(clock));
+    clock.add_alarm(medium_alarm);
+    let medium = static_init!(
+        SimMedium<'static, SimAlarm<'static>>,
+        SimMedium::new(medium_alarm)
+    );
+    medium_alarm.set_client(medium);
+
+    const CRYPT_SIZE: usize = 7 * AES128_BLOCK_SIZE;
+    let crypt_buf = static_init!([u8; CRYPT_SIZE], [0x00; CRYPT_SIZE]);
+    let aes_ccm = static_init!(AESCCM, aes_ccm::AES128CCM::new(&AES, crypt_buf));
+
+    let node0 = static_init_node(clock, medium, aes_ccm, NODE0_ADDR_LONG, 1);
+    let node1 = static_init_node(clock, medium, aes_ccm, NODE1_ADDR_LONG, 2);
+    let icmp_error_buf = static_init!([u8; ICMP_ERROR_LEN], [0x00; ICMP_ERROR_LEN]);
+    node1.enable_icmp_errors(icmp_error_buf);
+
+    let received = static_init!([u8; BUF_LEN], [0x00; BUF_LEN]);
+    let t = static_init!(
+        LowpanRxTest<'static>,
+        LowpanRxTest::new(clock, medium, node1, received)
+    );
+    node0.set_client(t);
+    node1.set_client(t);
+
+    t.run();
+}
@@ -12,10 +12,13 @@ extern crate sam4l;
 use capsules::alarm::AlarmDriver;
 use capsules::ieee802154::device::MacDevice;
 use capsules::ieee802154::mac::{AwakeMac, Mac};
+use capsules::ieee802154::mlme::Mlme;
 use capsules::rf233::RF233;
 use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
 use capsules::virtual_i2c::{I2CDevice, MuxI2C};
 use capsules::virtual_spi::{MuxSpiMaster, VirtualSpiMasterDevice};
+use core::slice;
+use core::str;
 use kernel::hil;
 use kernel::hil::Controller;
 use kernel::hil::radio;
@@ -41,6 +44,33 @@ mod aes_test;
 #[allow(dead_code)]
 mod aes_ccm_test;
 
+#[allow(dead_code)]
+mod sim_lowpan_test;
+
+#[allow(dead_code)]
+mod framer_test;
+
+#[allow(dead_code)]
+mod lowpan_mesh_test;
+
+#[allow(dead_code)]
+mod lowpan_rx_test;
+
+#[allow(dead_code)]
+mod mlme_test;
+
+#[allow(dead_code)]
+mod tsch_test;
+
+#[allow(dead_code)]
+mod capture_test;
+
+#[allow(dead_code)]
+mod gatt_test;
+
+#[allow(dead_code)]
+mod coap_test;
+
 #[allow(dead_code)]
 mod power;
 
@@ -57,11 +87,65 @@ static mut APP_MEMORY: [u8; 16384] = [0; 16384];
 static mut PROCESSES: [Option<kernel::Process<'static>>; NUM_PROCS] = [None, None];
 
 // Save some deep nesting
-type RF233Device =
-    capsules::rf233::RF233<'static, VirtualSpiMasterDevice<'static, sam4l::spi::Spi>>;
+type RF233Device = capsules::rf233::RF233<
+    'static,
+    VirtualSpiMasterDevice<'static, sam4l::spi::Spi>,
+    VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>,
+>;
+type AwakeMacDevice = AwakeMac<'static, RF233Device, VirtualMuxAlarm<'static, sam4l::ast::Ast>>;
+#[cfg(feature = "ieee802154_capture")]
+type CaptureDevice = capsules::ieee802154::capture::Capture<
+    'static,
+    AwakeMacDevice,
+    sam4l::usart::USART,
+    VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+>;
+// The Mac layer below the Framer, which is tapped for capture if the
+// `ieee802154_capture` feature is enabled
+#[cfg(feature = "ieee802154_capture")]
+type FramedMacDevice = CaptureDevice;
+#[cfg(not(feature = "ieee802154_capture"))]
+type FramedMacDevice = AwakeMacDevice;
+
+type SixlowpanDevice = capsules::net::sixlowpan::Sixlowpan<
+    'static,
+    VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>,
+    capsules::net::sixlowpan_compression::Context,
+>;
+type UdpTransportDevice = capsules::net::coap::udp::UdpTransport<
+    'static,
+    VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>,
+    capsules::net::sixlowpan_compression::Context,
+>;
+type CoapLayerDevice = capsules::net::coap::layer::CoapLayer<
+    'static,
+    VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>,
+>;
+
+type UsbClient = capsules::usbc_client::Client<'static, sam4l::usbc::Usbc<'static>>;
+type DfuDevice = capsules::usb_dfu::UsbDfu<
+    'static,
+    sam4l::flashcalw::FLASHCALW,
+    VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>,
+>;
+type SdCard = capsules::sdcard::SDCard<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>>;
+type MscDevice = capsules::usb_msc::UsbMassStorage<
+    'static,
+    sam4l::usbc::Usbc<'static>,
+    VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>,
+>;
+type HidDevice = capsules::usb_hid::UsbHid<'static, sam4l::usbc::Usbc<'static>>;
+#[cfg(feature = "usb_console")]
+type CdcDevice = capsules::usb_cdc::CdcAcm<'static, sam4l::usbc::Usbc<'static>>;
+// The UART carrying the console, which is a USB CDC-ACM serial port if the
+// `usb_console` feature is enabled
+#[cfg(feature = "usb_console")]
+type ConsoleDevice = CdcDevice;
+#[cfg(not(feature = "usb_console"))]
+type ConsoleDevice = sam4l::usart::USART;
 
 struct Imix {
-    console: &'static capsules::console::Console<'static, sam4l::usart::USART>,
+    console: &'static capsules::console::Console<'static, ConsoleDevice>,
     gpio: &'static capsules::gpio::GPIO<'static, sam4l::gpio::GPIOPin>,
     alarm: &'static AlarmDriver<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>>,
     temp: &'static capsules::temperature::TemperatureSensor<'static>,
@@ -74,11 +158,10 @@ struct Imix {
     ipc: kernel::ipc::IPC,
     ninedof: &'static capsules::ninedof::NineDof<'static>,
     radio_driver: &'static capsules::ieee802154::RadioDriver<'static>,
+    coap: &'static capsules::net::coap::CoapDriver<'static>,
     crc: &'static capsules::crc::Crc<'static, sam4l::crccu::Crccu<'static>>,
-    usb_driver: &'static capsules::usb_user::UsbSyscallDriver<
-        'static,
-        capsules::usbc_client::Client<'static, sam4l::usbc::Usbc<'static>>,
-    >,
+    usb_driver: &'static capsules::usb_user::UsbSyscallDriver<'static, UsbClient>,
+    usb_hid: &'static HidDevice,
     nrf51822: &'static capsules::nrf51822_serialization::Nrf51822Serialization<
         'static,
         sam4l::usart::USART,
@@ -104,11 +187,53 @@ static mut RF233_REG_READ: [u8; 2] = [0x00; 2];
 // for reception.
 static mut RADIO_BUF: [u8; radio::MAX_BUF_SIZE] = [0x00; radio::MAX_BUF_SIZE];
 
+// The MAC layer management entity sends beacons and beacon requests from its
+// own buffer.
+static mut MLME_BUF: [u8; radio::MAX_BUF_SIZE] = [0x00; radio::MAX_BUF_SIZE];
+
+// Frames held by the MAC layer management entity until the sleepy devices they
+// are addressed to poll for them.
+static mut MLME_INDIRECT_BUFS: [[u8; radio::MAX_BUF_SIZE];
+    capsules::ieee802154::mlme::MAX_PENDING_TRANSACTIONS] =
+    [[0x00; radio::MAX_BUF_SIZE]; capsules::ieee802154::mlme::MAX_PENDING_TRANSACTIONS];
+
+// Buffers of the 6LoWPAN layer: one for the fragments being sent, one to
+// reassemble received packets into, and one for the ICMPv6 errors sent about
+// received packets, which only include the start of the invoking packet.
+static mut LOWPAN_FRAG_BUF: [u8; radio::MAX_BUF_SIZE] = [0x00; radio::MAX_BUF_SIZE];
+static mut LOWPAN_RX_BUF: [u8; 1280] = [0x00; 1280];
+static mut LOWPAN_ICMP_ERROR_BUF: [u8; 128] = [0x00; 128];
+
+// The CoAP server encodes its responses into two buffers, so that requests
+// can be answered while a separate response waits for its acknowledgement.
+// The UDP transport copies each message into an IPv6 packet of its own.
+const COAP_BUF_LEN: usize = 128;
+static mut COAP_TX_BUF1: [u8; COAP_BUF_LEN] = [0x00; COAP_BUF_LEN];
+static mut COAP_TX_BUF2: [u8; COAP_BUF_LEN] = [0x00; COAP_BUF_LEN];
+static mut COAP_EMPTY_BUF: [u8; capsules::net::coap::message::HEADER_SIZE] =
+    [0x00; capsules::net::coap::message::HEADER_SIZE];
+const COAP_UDP_BUF_LEN: usize = capsules::net::coap::udp::IP6_HEADER_SIZE
+    + capsules::net::coap::udp::UDP_HEADER_SIZE + COAP_BUF_LEN;
+static mut COAP_UDP_BUF: [u8; COAP_UDP_BUF_LEN] = [0x00; COAP_UDP_BUF_LEN];
+
+// The frames captured for the host are queued in one buffer while the other
+// is written to USART0.
+#[cfg(feature = "ieee802154_capture")]
+static mut CAPTURE_BUF1: [u8; 1024] = [0x00; 1024];
+#[cfg(feature = "ieee802154_capture")]
+static mut CAPTURE_BUF2: [u8; 1024] = [0x00; 1024];
+
 // This buffer is used as an intermediate buffer for AES CCM encryption
 // An upper bound on the required size is 3 * BLOCK_SIZE + radio::MAX_BUF_SIZE
 const CRYPT_SIZE: usize = 3 * symmetric_encryption::AES128_BLOCK_SIZE + radio::MAX_BUF_SIZE;
 static mut CRYPT_BUF: [u8; CRYPT_SIZE] = [0x00; CRYPT_SIZE];
 
+// The USB serial number string, derived from the SAM4L's serial number.
+static mut USB_SERIAL_NUMBER: [u8; 14] = [0x00; 14];
+
+// The flash page being downloaded or uploaded over USB DFU
+static mut DFU_PAGE: sam4l::flashcalw::Sam4lPage = sam4l::flashcalw::Sam4lPage::new();
+
 impl kernel::Platform for Imix {
     fn with_driver<F, R>(&self, driver_num: usize, f: F) -> R
     where
@@ -128,7 +253,9 @@ impl kernel::Platform for Imix {
             capsules::ninedof::DRIVER_NUM => f(Some(self.ninedof)),
             capsules::crc::DRIVER_NUM => f(Some(self.crc)),
             capsules::usb_user::DRIVER_NUM => f(Some(self.usb_driver)),
+            capsules::usb_hid::DRIVER_NUM => f(Some(self.usb_hid)),
             capsules::ieee802154::DRIVER_NUM => f(Some(self.radio_driver)),
+            capsules::net::coap::DRIVER_NUM => f(Some(self.coap)),
             capsules::nrf51822_serialization::DRIVER_NUM => f(Some(self.nrf51822)),
             kernel::ipc::DRIVER_NUM => f(Some(&self.ipc)),
             _ => f(None),
@@ -203,6 +330,74 @@ unsafe fn set_pin_primary_functions() {
     PC[31].configure(None); //... D2          -- GPIO Pin
 }
 
+/// Writes the 120-bit serial number of the SAM4L, folded into 56 bits, as
+/// hexadecimal digits to `USB_SERIAL_NUMBER` so that it fits into a USB string
+/// descriptor.
+unsafe fn usb_serial_number() -> &'static str {
+    // Each SAM4L has a unique serial number at 0x0080020C to 0x0080021A
+    let serial_number = slice::from_raw_parts(0x0080020C as *const u8, 15);
+    let mut folded = [0u8; 7];
+    for (i, byte) in serial_number.iter().enumerate() {
+        folded[i % folded.len()] ^= *byte;
+    }
+    const DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+    for (i, byte) in folded.iter().enumerate() {
+        USB_SERIAL_NUMBER[2 * i] = DIGITS[(byte >> 4) as usize];
+        USB_SERIAL_NUMBER[2 * i + 1] = DIGITS[(byte & 0xf) as usize];
+    }
+    str::from_utf8_unchecked(&USB_SERIAL_NUMBER)
+}
+
+/// Folds the serial number of the SAM4L into 16 bits, as the first message ID
+/// of the CoAP messaging layer.
+unsafe fn coap_initial_message_id() -> u16 {
+    let serial_number = slice::from_raw_parts(0x0080020C as *const u8, 15);
+    let mut folded = 0u16;
+    for (i, byte) in serial_number.iter().enumerate() {
+        folded ^= (*byte as u16) << (8 * (i % 2));
+    }
+    folded
+}
+
+/// Registers a USB CDC-ACM serial port with `usb_client`, to carry the
+/// console instead of USART3
+#[cfg(feature = "usb_console")]
+unsafe fn static_init_usb_console(usb_client: &'static UsbClient) -> &'static CdcDevice {
+    let cdc = static_init!(
+        CdcDevice,
+        capsules::usb_cdc::CdcAcm::new(&sam4l::usbc::USBC)
+    );
+    usb_client.register(cdc);
+    cdc
+}
+
+/// Taps the frames sent and received by `awake_mac`, and streams them to the
+/// host over USART0 at 921600 baud, for tools/ieee802154-capture. The
+/// timestamps of received frames come from the RF233 alarm, which shares the
+/// time base of `mac_alarm`.
+#[cfg(feature = "ieee802154_capture")]
+unsafe fn static_init_capture(
+    awake_mac: &'static AwakeMacDevice,
+    mac_alarm: &'static VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+) -> &'static CaptureDevice {
+    let capture = static_init!(
+        CaptureDevice,
+        capsules::ieee802154::capture::Capture::new(
+            awake_mac,
+            &sam4l::usart::USART0,
+            921600,
+            mac_alarm,
+            &mut CAPTURE_BUF1,
+            &mut CAPTURE_BUF2
+        )
+    );
+    awake_mac.set_transmit_client(capture);
+    awake_mac.set_receive_client(capture);
+    hil::uart::UART::set_client(&sam4l::usart::USART0, capture);
+    capture.start();
+    capture
+}
+
 #[no_mangle]
 pub unsafe fn reset_handler() {
     sam4l::init();
@@ -224,18 +419,39 @@ pub unsafe fn reset_handler() {
         trng: true,
     });
 
+    // # USB
+
+    let usb_descriptors = static_init!(
+        capsules::usb::DescriptorBuilder<'static>,
+        capsules::usb::DescriptorBuilder::new(0x6667, 0xabcd).strings(
+            "Tock",
+            "imix",
+            usb_serial_number()
+        )
+    );
+    let usb_client = static_init!(
+        UsbClient,
+        capsules::usbc_client::Client::new(&sam4l::usbc::USBC, usb_descriptors)
+    );
+    sam4l::usbc::USBC.set_client(usb_client);
+
     // # CONSOLE
 
+    #[cfg(feature = "usb_console")]
+    let console_uart = static_init_usb_console(usb_client);
+    #[cfg(not(feature = "usb_console"))]
+    let console_uart = &sam4l::usart::USART3;
+
     let console = static_init!(
-        capsules::console::Console<sam4l::usart::USART>,
+        capsules::console::Console<ConsoleDevice>,
         capsules::console::Console::new(
-            &sam4l::usart::USART3,
+            console_uart,
             115200,
             &mut capsules::console::WRITE_BUF,
             kernel::Grant::create()
         )
     );
-    hil::uart::UART::set_client(&sam4l::usart::USART3, console);
+    hil::uart::UART::set_client(console_uart, console);
     console.initialize();
 
     // Attach the kernel debug interface to this console
@@ -361,11 +577,17 @@ pub unsafe fn reset_handler() {
         VirtualSpiMasterDevice<'static, sam4l::spi::Spi>,
         VirtualSpiMasterDevice::new(mux_spi, 3)
     );
-    // Create the RF233 driver, passing its pins and SPI client
-    let rf233: &RF233<'static, VirtualSpiMasterDevice<'static, sam4l::spi::Spi>> = static_init!(
-        RF233<'static, VirtualSpiMasterDevice<'static, sam4l::spi::Spi>>,
+    // Create the RF233 driver, passing its pins, SPI client, and the alarm
+    // used to timestamp received frames
+    let rf233_alarm = static_init!(
+        VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    let rf233: &RF233Device = static_init!(
+        RF233Device,
         RF233::new(
             rf233_spi,
+            rf233_alarm,
             &sam4l::gpio::PA[09], // reset
             &sam4l::gpio::PA[10], // sleep
             &sam4l::gpio::PA[08], // irq
@@ -497,24 +719,38 @@ pub unsafe fn reset_handler() {
     sam4l::aes::AES.set_client(aes_ccm);
     sam4l::aes::AES.enable();
 
-    // Keeps the radio on permanently; pass-through layer
-    let awake_mac: &AwakeMac<RF233Device> =
-        static_init!(AwakeMac<'static, RF233Device>, AwakeMac::new(rf233));
+    // Keeps the radio on permanently; pass-through layer. The RF233 performs
+    // CSMA-CA in hardware, so software CSMA-CA is left disabled.
+    let mac_alarm = static_init!(
+        VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    let awake_mac: &AwakeMacDevice = static_init!(
+        AwakeMacDevice,
+        AwakeMac::new(rf233, mac_alarm, &sam4l::trng::TRNG)
+    );
     rf233.set_transmit_client(awake_mac);
     rf233.set_receive_client(awake_mac, &mut RF233_RX_BUF);
+    mac_alarm.set_client(awake_mac);
+    sam4l::trng::TRNG.set_client(awake_mac);
+
+    #[cfg(feature = "ieee802154_capture")]
+    let framed_mac = static_init_capture(awake_mac, mac_alarm);
+    #[cfg(not(feature = "ieee802154_capture"))]
+    let framed_mac = awake_mac;
 
     let mac_device = static_init!(
         capsules::ieee802154::framer::Framer<
             'static,
-            AwakeMac<'static, RF233Device>,
+            FramedMacDevice,
             capsules::aes_ccm::AES128CCM<'static, sam4l::aes::Aes<'static>>,
         >,
-        capsules::ieee802154::framer::Framer::new(awake_mac, aes_ccm)
+        capsules::ieee802154::framer::Framer::new(framed_mac, aes_ccm)
     );
     aes_ccm.set_client(mac_device);
-    awake_mac.set_transmit_client(mac_device);
-    awake_mac.set_receive_client(mac_device);
-    awake_mac.set_config_client(mac_device);
+    framed_mac.set_transmit_client(mac_device);
+    framed_mac.set_receive_client(mac_device);
+    framed_mac.set_config_client(mac_device);
 
     let mux_mac = static_init!(
         capsules::ieee802154::virtual_mac::MuxMac<'static>,
@@ -541,19 +777,191 @@ pub unsafe fn reset_handler() {
     radio_mac.set_pan(0xABCD);
     radio_mac.set_address(0x1008);
 
-    // Configure the USB controller
-    let usb_client = static_init!(
-        capsules::usbc_client::Client<'static, sam4l::usbc::Usbc<'static>>,
-        capsules::usbc_client::Client::new(&sam4l::usbc::USBC)
+    // Link quality estimates of the neighbors, exposed to userspace
+    let neighbor_table = static_init!(
+        capsules::ieee802154::neighbors::NeighborTable,
+        capsules::ieee802154::neighbors::NeighborTable::new()
     );
-    sam4l::usbc::USBC.set_client(usb_client);
+    mac_device.set_neighbor_table(neighbor_table);
+    radio_driver.set_neighbor_table(neighbor_table);
+
+    // Beacons and channel scanning
+    let mlme_mac = static_init!(
+        capsules::ieee802154::virtual_mac::MacUser<'static>,
+        capsules::ieee802154::virtual_mac::MacUser::new(mux_mac)
+    );
+    mux_mac.add_user(mlme_mac);
+    let mlme_alarm = static_init!(
+        VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    let mlme = static_init!(
+        capsules::ieee802154::mlme::MacManager<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+        capsules::ieee802154::mlme::MacManager::new(mlme_mac, mlme_alarm, &mut MLME_BUF)
+    );
+    mlme_mac.set_transmit_client(mlme);
+    mlme_mac.set_receive_client(mlme);
+    mlme_alarm.set_client(mlme);
+    mlme.set_scan_client(radio_driver);
+    mlme.add_association_client(radio_driver);
+    mlme.set_indirect_buffers(&mut MLME_INDIRECT_BUFS);
+    mlme.set_indirect_client(radio_driver);
+    radio_driver.set_mlme(mlme);
+
+    // # COAP
+
+    // IPv6 over 6LoWPAN, from the link-local address derived from the short
+    // address of the radio
+    let lowpan_mac = static_init!(
+        capsules::ieee802154::virtual_mac::MacUser<'static>,
+        capsules::ieee802154::virtual_mac::MacUser::new(mux_mac)
+    );
+    mux_mac.add_user(lowpan_mac);
+    let lowpan_alarm = static_init!(
+        VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    let sixlowpan = static_init!(
+        SixlowpanDevice,
+        capsules::net::sixlowpan::Sixlowpan::new(
+            lowpan_mac,
+            capsules::net::sixlowpan_compression::Context {
+                prefix: [0; 16],
+                prefix_len: 0,
+                id: 0,
+                compress: false,
+            },
+            &mut LOWPAN_FRAG_BUF,
+            lowpan_alarm
+        )
+    );
+    let lowpan_rx_state = static_init!(
+        capsules::net::sixlowpan::RxState<'static>,
+        capsules::net::sixlowpan::RxState::new(&mut LOWPAN_RX_BUF)
+    );
+    sixlowpan.add_rx_state(lowpan_rx_state);
+    sixlowpan.enable_icmp_errors(&mut LOWPAN_ICMP_ERROR_BUF);
+    lowpan_mac.set_transmit_client(sixlowpan);
+    lowpan_mac.set_receive_client(sixlowpan);
+
+    let mac_addr = capsules::net::ieee802154::MacAddress::Short(0x1008);
+    let mut ip_addr = capsules::net::ip::IPAddr::new();
+    ip_addr.set_unicast_link_local();
+    ip_addr.0[8..16].copy_from_slice(&capsules::net::sixlowpan_compression::compute_iid(
+        &mac_addr,
+    ));
+    let udp_transport = static_init!(
+        UdpTransportDevice,
+        capsules::net::coap::udp::UdpTransport::new(
+            sixlowpan,
+            &mut COAP_UDP_BUF,
+            capsules::net::coap::udp::COAP_PORT
+        )
+    );
+    udp_transport.set_address(ip_addr, mac_addr);
+    udp_transport.start();
+
+    let coap_alarm = static_init!(
+        VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    // Message IDs start from a value derived from the serial number, so that
+    // boards on the same network do not use the same ones
+    let coap_layer = static_init!(
+        CoapLayerDevice,
+        capsules::net::coap::layer::CoapLayer::new(
+            udp_transport,
+            coap_alarm,
+            &mut COAP_EMPTY_BUF,
+            coap_initial_message_id()
+        )
+    );
+    coap_alarm.set_client(coap_layer);
+    capsules::net::coap::layer::Transport::set_client(udp_transport, coap_layer);
+
+    let coap_server = static_init!(
+        capsules::net::coap::server::CoapServer<'static>,
+        capsules::net::coap::server::CoapServer::new(coap_layer, &mut COAP_TX_BUF1)
+    );
+    coap_server.add_tx_buffer(&mut COAP_TX_BUF2);
+    capsules::net::coap::layer::MessageLayer::set_client(coap_layer, coap_server);
+
+    let coap_driver = static_init!(
+        capsules::net::coap::CoapDriver<'static>,
+        capsules::net::coap::CoapDriver::new(coap_server, kernel::Grant::create())
+    );
+    coap_server.add_resource(coap_driver);
+
+    // The USB device has a DFU interface, through which the host can read
+    // and write the applications, from PROG_ORIGIN on, with dfu-util
+    sam4l::flashcalw::FLASH_CONTROLLER.configure();
+    let dfu_regions = static_init!(
+        [capsules::usb_dfu::Region; 1],
+        [capsules::usb_dfu::Region {
+            start_page: 0x40000 / 512,
+            num_pages: 0x40000 / 512,
+        }]
+    );
+    let dfu_alarm = static_init!(
+        VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    let dfu = static_init!(
+        DfuDevice,
+        capsules::usb_dfu::UsbDfu::new(
+            &sam4l::flashcalw::FLASH_CONTROLLER,
+            dfu_alarm,
+            dfu_regions,
+            &mut DFU_PAGE
+        )
+    );
+    hil::flash::HasClient::set_client(&sam4l::flashcalw::FLASH_CONTROLLER, dfu);
+    dfu_alarm.set_client(dfu);
+    usb_client.register(dfu);
+
+    // The SD card on the SPI bus, with chip select NPCS2, is a USB mass
+    // storage device, so that the host can copy files off it
+    let sdcard_spi = static_init!(
+        VirtualSpiMasterDevice<'static, sam4l::spi::Spi>,
+        VirtualSpiMasterDevice::new(mux_spi, 2)
+    );
+    let sdcard_alarm = static_init!(
+        VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    let sdcard = static_init!(
+        SdCard,
+        capsules::sdcard::SDCard::new(
+            sdcard_spi,
+            sdcard_alarm,
+            None,
+            &mut capsules::sdcard::TXBUFFER,
+            &mut capsules::sdcard::RXBUFFER
+        )
+    );
+    sdcard_spi.set_client(sdcard);
+    sdcard_alarm.set_client(sdcard);
+    let msc = static_init!(
+        MscDevice,
+        capsules::usb_msc::UsbMassStorage::new(
+            &sam4l::usbc::USBC,
+            sdcard,
+            &mut capsules::usb_msc::BUFFER
+        )
+    );
+    sdcard.set_exclusive_client(msc);
+    usb_client.register(msc);
+
+    // Applications define the reports of the HID interface
+    let usb_hid = static_init!(
+        HidDevice,
+        capsules::usb_hid::UsbHid::new(&sam4l::usbc::USBC, kernel::Grant::create())
+    );
+    usb_client.register(usb_hid);
 
     // Configure the USB userspace driver
     let usb_driver = static_init!(
-        capsules::usb_user::UsbSyscallDriver<
-            'static,
-            capsules::usbc_client::Client<'static, sam4l::usbc::Usbc<'static>>,
-        >,
+        capsules::usb_user::UsbSyscallDriver<'static, UsbClient>,
         capsules::usb_user::UsbSyscallDriver::new(usb_client, kernel::Grant::create())
     );
 
@@ -572,7 +980,9 @@ pub unsafe fn reset_handler() {
         ipc: kernel::ipc::IPC::new(),
         ninedof: ninedof,
         radio_driver: radio_driver,
+        coap: coap_driver,
         usb_driver: usb_driver,
+        usb_hid: usb_hid,
         nrf51822: nrf_serialization,
     };
 
@@ -595,6 +1005,11 @@ pub unsafe fn reset_handler() {
     rf233.reset();
     rf233.start();
 
+    // Enumerate, so that the DFU interface, the mass storage device, the HID
+    // interface, and the console with the `usb_console` feature, are available
+    hil::usb::Client::enable(usb_client);
+    hil::usb::Client::attach(usb_client);
+
     debug!("Initialization complete. Entering main loop");
     extern "C" {
         /// Beginning of the ROM region containing app images.
@@ -0,0 +1,66 @@
+//! Runs `capsules::test::mlme`, in which a simulated device scans for,
+//! associates with and leaves the PAN of a simulated coordinator.
+//!
+//! The radios and Mac layers of the nodes are instantiated by
+//! `sim_lowpan_test::static_init_framer`, so the test does not depend on the
+//! RF233. It can be run by calling `mlme_test::run()` at the end of
+//! `reset_handler`.
+
+use capsules::aes_ccm;
+use capsules::ieee802154::device::MacDevice;
+use capsules::ieee802154::mlme::{MacManager, Mlme};
+use capsules::sim_radio::{SimAlarm, SimClock, SimMedium};
+use capsules::test::mlme::{MlmeTest, SimMacManager};
+use capsules::test::sim_lowpan::{NODE0_ADDR_LONG, NODE1_ADDR_LONG};
+use kernel::hil::radio;
+use kernel::hil::symmetric_encryption::AES128_BLOCK_SIZE;
+use sam4l::aes::AES;
+use sim_lowpan_test::{static_init_framer, FramerDevice, AESCCM};
+
+pub unsafe fn run() {
+    let clock = static_init!(SimClock<'static>, SimClock::new());
+    let medium_alarm = static_init!(SimAlarm<'static>, SimAlarm::new(clock));
+    clock.add_alarm(medium_alarm);
+    let medium = static_init!(
+        SimMedium<'static, SimAlarm<'static>>,
+        SimMedium::new(medium_alarm)
+    );
+    medium_alarm.set_client(medium);
+
+    const CRYPT_SIZE: usize = 7 * AES128_BLOCK_SIZE;
+    let crypt_buf = static_init!([u8; CRYPT_SIZE], [0x00; CRYPT_SIZE]);
+    let aes_ccm = static_init!(AESCCM, aes_ccm::AES128CCM::new(&AES, crypt_buf));
+
+    let coord_mac = static_init_framer(clock, medium, aes_ccm, NODE0_ADDR_LONG, 1);
+    let coord = static_init_mlme(clock, coord_mac);
+    let device_mac = static_init_framer(clock, medium, aes_ccm, NODE1_ADDR_LONG, 2);
+    let device = static_init_mlme(clock, device_mac);
+
+    let t = static_init!(
+        MlmeTest<'static>,
+        MlmeTest::new(clock, medium, coord, coord_mac, device, device_mac)
+    );
+    device.set_scan_client(t);
+    coord.add_association_client(t);
+    device.add_association_client(t);
+
+    t.run();
+}
+
+/// Instantiates the MLME of a node on top of its `Framer`
+unsafe fn static_init_mlme(
+    clock: &'static SimClock<'static>,
+    framer: &'static FramerDevice,
+) -> &'static SimMacManager<'static> {
+    let alarm = static_init!(SimAlarm<'static>, SimAlarm::new(clock));
+    clock.add_alarm(alarm);
+    let tx_buf = static_init!([u8; radio::MAX_BUF_SIZE], [0x00; radio::MAX_BUF_SIZE]);
+    let mlme = static_init!(
+        SimMacManager<'static>,
+        MacManager::new(framer, alarm, tx_buf)
+    );
+    framer.set_transmit_client(mlme);
+    framer.set_receive_client(mlme);
+    alarm.set_client(mlme);
+    mlme
+}
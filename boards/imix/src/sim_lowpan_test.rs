@@ -0,0 +1,141 @@
+//! Runs `capsules::test::sim_lowpan`, in which two simulated nodes exchange
+//! fragmented 6LoWPAN packets over a lossy `SimMedium`.
+//!
+//! The nodes only use the radio, alarms and random number generators of the
+//! simulation, so the test does not depend on the RF233. It can be run by
+//! calling `sim_lowpan_test::run()` at the end of `reset_handler`.
+
+use capsules::aes_ccm;
+use capsules::ieee802154::device::MacDevice;
+use capsules::ieee802154::framer::Framer;
+use capsules::ieee802154::mac::{AwakeMac, CsmaConfig, Mac};
+use capsules::net::sixlowpan::{RxState, Sixlowpan};
+use capsules::net::sixlowpan_compression::Context;
+use capsules::sim_radio::{SimAlarm, SimClock, SimMedium, SimRadio, SimRng};
+use capsules::test::sim_lowpan::{SimLowpanTest, NODE0_ADDR_LONG, NODE1_ADDR_LONG, PACKET_LEN,
+                                 PAN};
+use kernel::hil::radio::{self, RadioConfig, RadioData};
+use kernel::hil::symmetric_encryption::AES128_BLOCK_SIZE;
+use sam4l::aes::{Aes, AES};
+
+type SimRadioDevice = SimRadio<'static, SimAlarm<'static>>;
+pub type AwakeMacDevice = AwakeMac<'static, SimRadioDevice, SimAlarm<'static>>;
+// The nodes do not secure their frames, so the AES engine is never used
+pub type AESCCM = aes_ccm::AES128CCM<'static, Aes<'static>>;
+pub type FramerDevice = Framer<'static, AwakeMacDevice, AESCCM>;
+pub type SixlowpanDevice = Sixlowpan<'static, SimAlarm<'static>, Context>;
+
+pub unsafe fn run() {
+    let clock = static_init!(SimClock<'static>, SimClock::new());
+    let medium_alarm = static_init!(SimAlarm<'static>, SimAlarm::new(clock));
+    clock.add_alarm(medium_alarm);
+    let medium = static_init!(
+        SimMedium<'static, SimAlarm<'static>>,
+        SimMedium::new(medium_alarm)
+    );
+    medium_alarm.set_client(medium);
+
+    const CRYPT_SIZE: usize = 7 * AES128_BLOCK_SIZE;
+    let crypt_buf = static_init!([u8; CRYPT_SIZE], [0x00; CRYPT_SIZE]);
+    let aes_ccm = static_init!(AESCCM, aes_ccm::AES128CCM::new(&AES, crypt_buf));
+
+    let sender = static_init_node(clock, medium, aes_ccm, NODE0_ADDR_LONG, 1);
+    let receiver = static_init_node(clock, medium, aes_ccm, NODE1_ADDR_LONG, 2);
+
+    let packet = static_init!([u8; PACKET_LEN], [0x00; PACKET_LEN]);
+    let t = static_init!(
+        SimLowpanTest<'static>,
+        SimLowpanTest::new(clock, medium, sender, packet)
+    );
+    sender.set_client(t);
+    receiver.set_client(t);
+
+    t.run();
+}
+
+/// Instantiates the stack of a node, from its radio on `medium` to its
+/// `Sixlowpan` layer. `seed` seeds the backoffs of its Mac layer.
+pub unsafe fn static_init_node(
+    clock: &'static SimClock<'static>,
+    medium: &'static SimMedium<'static, SimAlarm<'static>>,
+    aes_ccm: &'static AESCCM,
+    addr_long: [u8; 8],
+    seed: u32,
+) -> &'static SixlowpanDevice {
+    let framer = static_init_framer(clock, medium, aes_ccm, addr_long, seed);
+    // Sixlowpan only reads the time from its alarm
+    let alarm = static_init!(SimAlarm<'static>, SimAlarm::new(clock));
+
+    let tx_buf = static_init!([u8; radio::MAX_BUF_SIZE], [0x00; radio::MAX_BUF_SIZE]);
+    let rx_state_buf = static_init!([u8; 1280], [0x00; 1280]);
+    let rx_state = static_init!(RxState<'static>, RxState::new(rx_state_buf));
+    let sixlowpan = static_init!(
+        SixlowpanDevice,
+        Sixlowpan::new(
+            framer,
+            Context {
+                prefix: [0; 16],
+                prefix_len: 0,
+                id: 0,
+                compress: false,
+            },
+            tx_buf,
+            alarm,
+        )
+    );
+    sixlowpan.add_rx_state(rx_state);
+    framer.set_transmit_client(sixlowpan);
+    framer.set_receive_client(sixlowpan);
+    sixlowpan
+}
+
+/// Instantiates the stack of a node, from its radio on `medium` to its
+/// `Framer`, and starts the radio. `seed` seeds the backoffs of its Mac
+/// layer.
+pub unsafe fn static_init_framer(
+    clock: &'static SimClock<'static>,
+    medium: &'static SimMedium<'static, SimAlarm<'static>>,
+    aes_ccm: &'static AESCCM,
+    addr_long: [u8; 8],
+    seed: u32,
+) -> &'static FramerDevice {
+    let awake_mac = static_init_awake_mac(clock, medium, addr_long, seed);
+    let framer = static_init!(FramerDevice, Framer::new(awake_mac, aes_ccm));
+    awake_mac.set_transmit_client(framer);
+    awake_mac.set_receive_client(framer);
+    awake_mac.set_config_client(framer);
+    framer
+}
+
+/// Instantiates the stack of a node, from its radio on `medium` to its
+/// `AwakeMac`, and starts the radio. `seed` seeds the backoffs of the Mac
+/// layer, whose clients are left to the caller.
+pub unsafe fn static_init_awake_mac(
+    clock: &'static SimClock<'static>,
+    medium: &'static SimMedium<'static, SimAlarm<'static>>,
+    addr_long: [u8; 8],
+    seed: u32,
+) -> &'static AwakeMacDevice {
+    let radio = static_init!(SimRadioDevice, SimRadio::new(medium));
+    medium.add_node(radio);
+    let radio_rx_buf = static_init!([u8; radio::MAX_BUF_SIZE], [0x00; radio::MAX_BUF_SIZE]);
+
+    let rng_alarm = static_init!(SimAlarm<'static>, SimAlarm::new(clock));
+    clock.add_alarm(rng_alarm);
+    let rng = static_init!(SimRng<'static>, SimRng::new(rng_alarm, seed));
+    rng_alarm.set_client(rng);
+
+    let mac_alarm = static_init!(SimAlarm<'static>, SimAlarm::new(clock));
+    clock.add_alarm(mac_alarm);
+    let awake_mac = static_init!(AwakeMacDevice, AwakeMac::new(radio, mac_alarm, rng));
+    radio.set_transmit_client(awake_mac);
+    radio.set_receive_client(awake_mac, radio_rx_buf);
+    mac_alarm.set_client(awake_mac);
+    rng.set_client(awake_mac);
+    awake_mac.set_csma(Some(CsmaConfig::default()));
+    awake_mac.set_pan(PAN);
+    awake_mac.set_address_long(addr_long);
+
+    radio.start();
+    awake_mac
+}
@@ -0,0 +1,85 @@
+//! Runs `capsules::test::tsch`, in which a simulated node joins the TSCH
+//! network of a simulated coordinator and exchanges frames with it.
+//!
+//! The nodes only use the radios and alarms of the simulation, so the test
+//! does not depend on the RF233. It can be run by calling `tsch_test::run()`
+//! at the end of `reset_handler`.
+
+use capsules::aes_ccm;
+use capsules::ieee802154::device::MacDevice;
+use capsules::ieee802154::framer::Framer;
+use capsules::ieee802154::mac::Mac;
+use capsules::ieee802154::tsch::TschMac;
+use capsules::sim_radio::{SimAlarm, SimClock, SimMedium, SimRadio};
+use capsules::test::sim_lowpan::{NODE0_ADDR_LONG, NODE1_ADDR_LONG, PAN};
+use capsules::test::tsch::{SimTschMac, TschTest};
+use kernel::hil::radio::{self, RadioConfig, RadioData};
+use kernel::hil::symmetric_encryption::AES128_BLOCK_SIZE;
+use sam4l::aes::AES;
+use sim_lowpan_test::AESCCM;
+
+type TschFramer = Framer<'static, SimTschMac<'static>, AESCCM>;
+
+pub unsafe fn run() {
+    let clock = static_init!(SimClock<'static>, SimClock::new());
+    let medium_alarm = static_init!(SimAlarm<'static>, SimAlarm::new(clock));
+    clock.add_alarm(medium_alarm);
+    let medium = static_init!(
+        SimMedium<'static, SimAlarm<'static>>,
+        SimMedium::new(medium_alarm)
+    );
+    medium_alarm.set_client(medium);
+
+    const CRYPT_SIZE: usize = 7 * AES128_BLOCK_SIZE;
+    let crypt_buf = static_init!([u8; CRYPT_SIZE], [0x00; CRYPT_SIZE]);
+    let aes_ccm = static_init!(AESCCM, aes_ccm::AES128CCM::new(&AES, crypt_buf));
+
+    let (coord, coord_mac) = static_init_tsch(clock, medium, aes_ccm, NODE0_ADDR_LONG);
+    let (node, node_mac) = static_init_tsch(clock, medium, aes_ccm, NODE1_ADDR_LONG);
+
+    let tx_buf = static_init!([u8; radio::MAX_BUF_SIZE], [0x00; radio::MAX_BUF_SIZE]);
+    let t = static_init!(
+        TschTest<'static>,
+        TschTest::new(clock, medium, coord, coord_mac, node, node_mac, tx_buf)
+    );
+    coord_mac.set_transmit_client(t);
+    coord_mac.set_receive_client(t);
+    node_mac.set_transmit_client(t);
+    node_mac.set_receive_client(t);
+
+    t.run();
+}
+
+/// Instantiates the stack of a node, from its radio on `medium` to the
+/// `Framer` on top of its TSCH layer
+unsafe fn static_init_tsch(
+    clock: &'static SimClock<'static>,
+    medium: &'static SimMedium<'static, SimAlarm<'static>>,
+    aes_ccm: &'static AESCCM,
+    addr_long: [u8; 8],
+) -> (&'static SimTschMac<'static>, &'static TschFramer) {
+    let radio = static_init!(SimRadio<'static, SimAlarm<'static>>, SimRadio::new(medium));
+    medium.add_node(radio);
+    // TSCH sends its own acknowledgements, which carry time corrections
+    radio.set_auto_ack(false);
+    let radio_rx_buf = static_init!([u8; radio::MAX_BUF_SIZE], [0x00; radio::MAX_BUF_SIZE]);
+
+    let alarm = static_init!(SimAlarm<'static>, SimAlarm::new(clock));
+    clock.add_alarm(alarm);
+    let tsch = static_init!(SimTschMac<'static>, TschMac::new(radio, alarm));
+    alarm.set_client(tsch);
+    radio.set_transmit_client(tsch);
+    radio.set_receive_client(tsch, radio_rx_buf);
+    radio.set_config_client(tsch);
+
+    let mac_buf = static_init!([u8; radio::MAX_BUF_SIZE], [0x00; radio::MAX_BUF_SIZE]);
+    tsch.initialize(mac_buf);
+
+    let framer = static_init!(TschFramer, Framer::new(tsch, aes_ccm));
+    tsch.set_transmit_client(framer);
+    tsch.set_receive_client(framer);
+    tsch.set_config_client(framer);
+    tsch.set_pan(PAN);
+    tsch.set_address_long(addr_long);
+    (tsch, framer)
+}
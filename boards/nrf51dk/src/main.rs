@@ -85,7 +85,7 @@ static mut APP_MEMORY: [u8; 8192] = [0; 8192];
 static mut PROCESSES: [Option<kernel::Process<'static>>; NUM_PROCS] = [None];
 
 pub struct Platform {
-    ble_radio: &'static nrf5x::ble_advertising_driver::BLE<
+    ble_radio: &'static capsules::ble_advertising_driver::BLE<
         'static,
         nrf51::radio::Radio,
         VirtualMuxAlarm<'static, Rtc>,
@@ -111,7 +111,7 @@ impl kernel::Platform for Platform {
             capsules::led::DRIVER_NUM => f(Some(self.led)),
             capsules::button::DRIVER_NUM => f(Some(self.button)),
             capsules::rng::DRIVER_NUM => f(Some(self.rng)),
-            nrf5x::ble_advertising_driver::DRIVER_NUM => f(Some(self.ble_radio)),
+            capsules::ble_advertising_driver::DRIVER_NUM => f(Some(self.ble_radio)),
             capsules::temperature::DRIVER_NUM => f(Some(self.temp)),
             _ => f(None),
         }
@@ -279,24 +279,24 @@ pub unsafe fn reset_handler() {
     nrf5x::trng::TRNG.set_client(rng);
 
     let ble_radio = static_init!(
-        nrf5x::ble_advertising_driver::BLE<
+        capsules::ble_advertising_driver::BLE<
             'static,
             nrf51::radio::Radio,
             VirtualMuxAlarm<'static, Rtc>,
         >,
-        nrf5x::ble_advertising_driver::BLE::new(
+        capsules::ble_advertising_driver::BLE::new(
             &mut nrf51::radio::RADIO,
             kernel::Grant::create(),
-            &mut nrf5x::ble_advertising_driver::BUF,
+            &mut capsules::ble_advertising_driver::BUF,
             ble_radio_virtual_alarm
         ),
         256 / 8
     );
-    nrf5x::ble_advertising_hil::BleAdvertisementDriver::set_receive_client(
+    kernel::hil::ble_advertising::BleAdvertisementDriver::set_receive_client(
         &nrf51::radio::RADIO,
         ble_radio,
     );
-    nrf5x::ble_advertising_hil::BleAdvertisementDriver::set_transmit_client(
+    kernel::hil::ble_advertising::BleAdvertisementDriver::set_transmit_client(
         &nrf51::radio::RADIO,
         ble_radio,
     );
@@ -0,0 +1,38 @@
+//! Runs `capsules::test::ble_hci`, which exchanges H4 packets with an HCI
+//! controller on top of a mock radio, UART and alarm.
+//!
+//! The test does not use the radio or the UART of the board, and can be run
+//! by calling `ble_hci_test::run()` at the end of `reset_handler`.
+
+use capsules::ble_hci::{self, HciController};
+use capsules::test::ble_hci::{HciTest, MockAlarm, MockRadio, MockUart, TestController,
+                              PACKET_LENGTH};
+use kernel::hil::ble_advertising::BleAdvertisementDriver;
+use kernel::hil::uart::UART;
+
+pub unsafe fn run() {
+    let rx_buf = static_init!([u8; PACKET_LENGTH], [0x00; PACKET_LENGTH]);
+    let radio = static_init!(MockRadio, MockRadio::new(rx_buf));
+    let uart = static_init!(MockUart, MockUart::new());
+    let alarm = static_init!(MockAlarm, MockAlarm::new());
+    let hci = static_init!(
+        TestController<'static>,
+        HciController::new(
+            radio,
+            uart,
+            1000000,
+            alarm,
+            &mut ble_hci::RX_BUF,
+            &mut ble_hci::EVENT_BUF1,
+            &mut ble_hci::EVENT_BUF2,
+            &mut ble_hci::RADIO_BUF
+        )
+    );
+    radio.set_receive_client(hci);
+    radio.set_transmit_client(hci);
+    uart.set_client(hci);
+    alarm.set_client(hci);
+
+    let t = static_init!(HciTest<'static>, HciTest::new(hci, radio, uart, alarm));
+    t.run();
+}
@@ -95,6 +95,9 @@ pub mod io;
 #[allow(dead_code)]
 mod aes_test;
 
+#[allow(dead_code)]
+mod ble_hci_test;
+
 // State for loading and holding applications.
 // How should the kernel respond when a process faults.
 const FAULT_RESPONSE: kernel::process::FaultResponse = kernel::process::FaultResponse::Panic;
@@ -107,14 +110,37 @@ static mut APP_MEMORY: [u8; 32768] = [0; 32768];
 
 static mut PROCESSES: [Option<kernel::Process<'static>>; NUM_PROCS] = [None, None, None, None];
 
+#[cfg(not(feature = "ble_hci"))]
+type BleLinkLayer = capsules::ble_link_layer::LinkLayer<
+    'static,
+    nrf52::radio::Radio,
+    VirtualMuxAlarm<'static, Rtc>,
+>;
+#[cfg(not(feature = "ble_hci"))]
+type BleDriver =
+    capsules::ble_advertising_driver::BLE<'static, BleLinkLayer, VirtualMuxAlarm<'static, Rtc>>;
+#[cfg(not(feature = "ble_hci"))]
+type AttBearer = capsules::net::ble::l2cap::L2capBearer<'static, BleLinkLayer>;
+
+/// Flags and the complete local name, advertised so that GATT clients can
+/// connect
+#[cfg(not(feature = "ble_hci"))]
+const ADVERTISING_DATA: [u8; 9] = [0x02, 0x01, 0x06, 0x05, 0x09, b'T', b'o', b'c', b'k'];
+#[cfg(not(feature = "ble_hci"))]
+static DEVICE_NAME: &'static [u8] = b"Tock";
+
+#[cfg(not(feature = "ble_hci"))]
+static mut GATT_BUF: [u8; capsules::net::ble::gatt::MAX_MTU] =
+    [0; capsules::net::ble::gatt::MAX_MTU];
+
 pub struct Platform {
-    ble_radio: &'static nrf5x::ble_advertising_driver::BLE<
-        'static,
-        nrf52::radio::Radio,
-        VirtualMuxAlarm<'static, Rtc>,
-    >,
+    #[cfg(not(feature = "ble_hci"))]
+    ble_radio: &'static BleDriver,
     button: &'static capsules::button::Button<'static, nrf5x::gpio::GPIOPin>,
+    #[cfg(not(feature = "ble_hci"))]
     console: &'static capsules::console::Console<'static, nrf52::uart::UARTE>,
+    #[cfg(not(feature = "ble_hci"))]
+    gatt: &'static capsules::net::ble::GattDriver<'static>,
     gpio: &'static capsules::gpio::GPIO<'static, nrf5x::gpio::GPIOPin>,
     led: &'static capsules::led::LED<'static, nrf5x::gpio::GPIOPin>,
     rng: &'static capsules::rng::SimpleRng<'static, nrf5x::trng::Trng<'static>>,
@@ -132,13 +158,17 @@ impl kernel::Platform for Platform {
         F: FnOnce(Option<&kernel::Driver>) -> R,
     {
         match driver_num {
+            #[cfg(not(feature = "ble_hci"))]
             capsules::console::DRIVER_NUM => f(Some(self.console)),
             capsules::gpio::DRIVER_NUM => f(Some(self.gpio)),
             capsules::alarm::DRIVER_NUM => f(Some(self.alarm)),
             capsules::led::DRIVER_NUM => f(Some(self.led)),
             capsules::button::DRIVER_NUM => f(Some(self.button)),
             capsules::rng::DRIVER_NUM => f(Some(self.rng)),
-            nrf5x::ble_advertising_driver::DRIVER_NUM => f(Some(self.ble_radio)),
+            #[cfg(not(feature = "ble_hci"))]
+            capsules::ble_advertising_driver::DRIVER_NUM => f(Some(self.ble_radio)),
+            #[cfg(not(feature = "ble_hci"))]
+            capsules::net::ble::DRIVER_NUM => f(Some(self.gatt)),
             capsules::temperature::DRIVER_NUM => f(Some(self.temp)),
             _ => f(None),
         }
@@ -268,55 +298,19 @@ pub unsafe fn reset_handler() {
         capsules::alarm::AlarmDriver::new(virtual_alarm1, kernel::Grant::create())
     );
     virtual_alarm1.set_client(alarm);
-    let ble_radio_virtual_alarm = static_init!(
-        capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>,
-        capsules::virtual_alarm::VirtualMuxAlarm::new(mux_alarm)
-    );
-
     nrf52::uart::UART0.configure(
         nrf5x::pinmux::Pinmux::new(6), // tx
         nrf5x::pinmux::Pinmux::new(8), // rx
         nrf5x::pinmux::Pinmux::new(7), // cts
         nrf5x::pinmux::Pinmux::new(5),
     ); // rts
-    let console = static_init!(
-        capsules::console::Console<nrf52::uart::UARTE>,
-        capsules::console::Console::new(
-            &nrf52::uart::UART0,
-            115200,
-            &mut capsules::console::WRITE_BUF,
-            kernel::Grant::create()
-        )
-    );
-    kernel::hil::uart::UART::set_client(&nrf52::uart::UART0, console);
-    console.initialize();
 
-    // Attach the kernel debug interface to this console
-    let kc = static_init!(capsules::console::App, capsules::console::App::default());
-    kernel::debug::assign_console_driver(Some(console), kc);
-
-    let ble_radio = static_init!(
-        nrf5x::ble_advertising_driver::BLE<
-            'static,
-            nrf52::radio::Radio,
-            VirtualMuxAlarm<'static, Rtc>,
-        >,
-        nrf5x::ble_advertising_driver::BLE::new(
-            &mut nrf52::radio::RADIO,
-            kernel::Grant::create(),
-            &mut nrf5x::ble_advertising_driver::BUF,
-            ble_radio_virtual_alarm
-        )
-    );
-    nrf5x::ble_advertising_hil::BleAdvertisementDriver::set_receive_client(
-        &nrf52::radio::RADIO,
-        ble_radio,
-    );
-    nrf5x::ble_advertising_hil::BleAdvertisementDriver::set_transmit_client(
-        &nrf52::radio::RADIO,
-        ble_radio,
-    );
-    ble_radio_virtual_alarm.set_client(ble_radio);
+    // With the `ble_hci` feature, UART0 and the radio are used by the HCI
+    // controller instead of the console and the BLE advertising driver
+    #[cfg(not(feature = "ble_hci"))]
+    let (console, ble_radio, gatt) = static_init_console_ble(mux_alarm);
+    #[cfg(feature = "ble_hci")]
+    static_init_hci(mux_alarm);
 
     let temp = static_init!(
         capsules::temperature::TemperatureSensor<'static>,
@@ -361,8 +355,12 @@ pub unsafe fn reset_handler() {
     let platform = Platform {
         // aes: aes,
         button: button,
+        #[cfg(not(feature = "ble_hci"))]
         ble_radio: ble_radio,
+        #[cfg(not(feature = "ble_hci"))]
         console: console,
+        #[cfg(not(feature = "ble_hci"))]
+        gatt: gatt,
         led: led,
         gpio: gpio,
         rng: rng,
@@ -375,6 +373,7 @@ pub unsafe fn reset_handler() {
 
     let mut chip = nrf52::chip::NRF52::new();
 
+    #[cfg(not(feature = "ble_hci"))]
     debug!("Initialization complete. Entering main loop\r");
     extern "C" {
         /// Beginning of the ROM region containing app images.
@@ -394,3 +393,164 @@ pub unsafe fn reset_handler() {
         &kernel::ipc::IPC::new(),
     );
 }
+
+/// Instantiates the console on UART0, which `debug!` writes to as well, and
+/// the BLE advertising driver and the GATT driver on top of the link layer,
+/// which starts advertising so that GATT clients can connect
+#[cfg(not(feature = "ble_hci"))]
+unsafe fn static_init_console_ble(
+    mux_alarm: &'static capsules::virtual_alarm::MuxAlarm<'static, Rtc>,
+) -> (
+    &'static capsules::console::Console<'static, nrf52::uart::UARTE>,
+    &'static BleDriver,
+    &'static capsules::net::ble::GattDriver<'static>,
+) {
+    use capsules::net::ble::att::Uuid;
+    use capsules::net::ble::gatt::{self, GattServer};
+
+    let ble_radio_virtual_alarm = static_init!(
+        VirtualMuxAlarm<'static, Rtc>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    let ble_link_layer_virtual_alarm = static_init!(
+        VirtualMuxAlarm<'static, Rtc>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+
+    let console = static_init!(
+        capsules::console::Console<nrf52::uart::UARTE>,
+        capsules::console::Console::new(
+            &nrf52::uart::UART0,
+            115200,
+            &mut capsules::console::WRITE_BUF,
+            kernel::Grant::create()
+        )
+    );
+    kernel::hil::uart::UART::set_client(&nrf52::uart::UART0, console);
+    console.initialize();
+
+    // Attach the kernel debug interface to this console
+    let kc = static_init!(capsules::console::App, capsules::console::App::default());
+    kernel::debug::assign_console_driver(Some(console), kc);
+
+    // The BLE link layer shares the radio with the advertising driver
+    let ble_link_layer = static_init!(
+        BleLinkLayer,
+        capsules::ble_link_layer::LinkLayer::new(
+            &nrf52::radio::RADIO,
+            ble_link_layer_virtual_alarm,
+            &mut capsules::ble_link_layer::ADV_BUF,
+            &mut capsules::ble_link_layer::TX_BUF,
+            &mut capsules::ble_link_layer::RX_BUF,
+            &mut capsules::ble_link_layer::UPPER_BUF
+        )
+    );
+    kernel::hil::ble_advertising::BleAdvertisementDriver::set_receive_client(
+        &nrf52::radio::RADIO,
+        ble_link_layer,
+    );
+    kernel::hil::ble_advertising::BleAdvertisementDriver::set_transmit_client(
+        &nrf52::radio::RADIO,
+        ble_link_layer,
+    );
+    ble_link_layer_virtual_alarm.set_client(ble_link_layer);
+
+    let ble_radio = static_init!(
+        BleDriver,
+        capsules::ble_advertising_driver::BLE::new(
+            ble_link_layer,
+            kernel::Grant::create(),
+            &mut capsules::ble_advertising_driver::BUF,
+            ble_radio_virtual_alarm
+        )
+    );
+    kernel::hil::ble_advertising::BleAdvertisementDriver::set_receive_client(
+        ble_link_layer,
+        ble_radio,
+    );
+    kernel::hil::ble_advertising::BleAdvertisementDriver::set_transmit_client(
+        ble_link_layer,
+        ble_radio,
+    );
+    ble_radio_virtual_alarm.set_client(ble_radio);
+
+    // GATT server on the connections of the link layer, with the GAP service
+    // and the services of the applications
+    let att_bearer = static_init!(
+        AttBearer,
+        capsules::net::ble::l2cap::L2capBearer::new(
+            ble_link_layer,
+            &mut capsules::net::ble::l2cap::TX_BUF,
+            &mut capsules::net::ble::l2cap::RX_BUF
+        )
+    );
+    capsules::ble_link_layer_hil::BleLinkLayer::set_client(ble_link_layer, att_bearer);
+    let gatt_server = static_init!(
+        GattServer<'static>,
+        GattServer::new(att_bearer, &mut GATT_BUF)
+    );
+    capsules::net::ble::att::AttBearer::set_client(att_bearer, gatt_server);
+    let gap = gatt_server
+        .add_service(Uuid::Uuid16(gatt::uuid::GAP_SERVICE), 2)
+        .unwrap();
+    gatt_server
+        .add_static_characteristic(gap, Uuid::Uuid16(gatt::uuid::DEVICE_NAME), DEVICE_NAME)
+        .unwrap();
+
+    let gatt = static_init!(
+        capsules::net::ble::GattDriver<'static>,
+        capsules::net::ble::GattDriver::new(gatt_server, kernel::Grant::create())
+    );
+    gatt_server.set_client(gatt);
+
+    capsules::ble_link_layer_hil::BleLinkLayer::set_address(
+        ble_link_layer,
+        nrf52::ficr::FICR_INSTANCE.address(),
+    );
+    capsules::ble_link_layer_hil::BleLinkLayer::set_advertising_data(
+        ble_link_layer,
+        &ADVERTISING_DATA,
+    );
+    capsules::ble_link_layer_hil::BleLinkLayer::start_advertising(ble_link_layer);
+
+    (console, ble_radio, gatt)
+}
+
+/// Makes the board a Bluetooth controller for a host attached to UART0, with
+/// the HCI controller of `capsules::ble_hci`
+#[cfg(feature = "ble_hci")]
+unsafe fn static_init_hci(mux_alarm: &'static capsules::virtual_alarm::MuxAlarm<'static, Rtc>) {
+    let hci_alarm = static_init!(
+        VirtualMuxAlarm<'static, Rtc>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    let hci = static_init!(
+        capsules::ble_hci::HciController<
+            'static,
+            nrf52::radio::Radio,
+            nrf52::uart::UARTE,
+            VirtualMuxAlarm<'static, Rtc>,
+        >,
+        capsules::ble_hci::HciController::new(
+            &nrf52::radio::RADIO,
+            &nrf52::uart::UART0,
+            1000000,
+            hci_alarm,
+            &mut capsules::ble_hci::RX_BUF,
+            &mut capsules::ble_hci::EVENT_BUF1,
+            &mut capsules::ble_hci::EVENT_BUF2,
+            &mut capsules::ble_hci::RADIO_BUF
+        )
+    );
+    kernel::hil::ble_advertising::BleAdvertisementDriver::set_receive_client(
+        &nrf52::radio::RADIO,
+        hci,
+    );
+    kernel::hil::ble_advertising::BleAdvertisementDriver::set_transmit_client(
+        &nrf52::radio::RADIO,
+        hci,
+    );
+    kernel::hil::uart::UART::set_client(&nrf52::uart::UART0, hci);
+    hci_alarm.set_client(hci);
+    hci.start();
+}
@@ -0,0 +1,243 @@
+//! Bluetooth Low Energy advertising and scan response data
+//!
+//! The data of advertising channel PDUs is a sequence of AD structures, each
+//! made of a length, an AD type and the AD data, and at most 31 bytes long.
+//! A length of zero ends the significant part of the data, and the rest is
+//! padding.
+//!
+//! BLUETOOTH SPECIFICATION Version 4.2 [Vol 3, Part C], section 11 Advertising
+//! and Scan Response Data Format
+//!
+//! ```
+//!   AD structure  +-----------+      +-----------+      +--------------------+
+//!                 | Length    |  -   | AD Type   |  -   | AD Data            |
+//!                 | (1 byte)  |      | (1 byte)  |      | (Length - 1 bytes) |
+//!                 +-----------+      +-----------+      +--------------------+
+//! ```
+//!
+//! `AdStructures` decodes data received from other devices, and
+//! `AdvertisingData` builds the data of an advertisement or a scan response,
+//! with one AD structure per AD type that can be replaced or removed without
+//! rebuilding the others.
+
+use ble_link_layer_hil::MAX_ADVERTISING_DATA_LENGTH;
+use kernel::returncode::ReturnCode;
+
+/// Largest AD data in a single AD structure
+pub const MAX_AD_DATA_LENGTH: usize = MAX_ADVERTISING_DATA_LENGTH - 2;
+
+/// AD types, from the Generic Access Profile assigned numbers
+pub mod ad_type {
+    pub const FLAGS: u8 = 0x01;
+    pub const INCOMPLETE_LIST_16BIT_SERVICE_IDS: u8 = 0x02;
+    pub const COMPLETE_LIST_16BIT_SERVICE_IDS: u8 = 0x03;
+    pub const INCOMPLETE_LIST_32BIT_SERVICE_IDS: u8 = 0x04;
+    pub const COMPLETE_LIST_32BIT_SERVICE_IDS: u8 = 0x05;
+    pub const INCOMPLETE_LIST_128BIT_SERVICE_IDS: u8 = 0x06;
+    pub const COMPLETE_LIST_128BIT_SERVICE_IDS: u8 = 0x07;
+    pub const SHORTENED_LOCAL_NAME: u8 = 0x08;
+    pub const COMPLETE_LOCAL_NAME: u8 = 0x09;
+    pub const TX_POWER_LEVEL: u8 = 0x0a;
+    pub const DEVICE_ID: u8 = 0x10;
+    pub const SLAVE_CONNECTION_INTERVAL_RANGE: u8 = 0x12;
+    pub const LIST_16BIT_SOLICITATION_IDS: u8 = 0x14;
+    pub const LIST_128BIT_SOLICITATION_IDS: u8 = 0x15;
+    pub const SERVICE_DATA: u8 = 0x16;
+    pub const APPEARANCE: u8 = 0x19;
+    pub const ADVERTISING_INTERVAL: u8 = 0x1a;
+    pub const MANUFACTURER_SPECIFIC_DATA: u8 = 0xff;
+}
+
+/// Bits of the Flags AD type
+pub mod flags {
+    pub const LE_LIMITED_DISCOVERABLE: u8 = 0x01;
+    pub const LE_GENERAL_DISCOVERABLE: u8 = 0x02;
+    pub const BR_EDR_NOT_SUPPORTED: u8 = 0x04;
+}
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct AdStructure<'a> {
+    pub ad_type: u8,
+    pub data: &'a [u8],
+}
+
+impl<'a> AdStructure<'a> {
+    /// Number of bytes taken up by the AD structure, including its length
+    /// and AD type
+    pub fn encoded_len(&self) -> usize {
+        2 + self.data.len()
+    }
+}
+
+/// Iterator over the AD structures of advertising or scan response data,
+/// along with the offset of each AD structure in the data. It stops at the
+/// end of the significant part, or at an AD structure that does not fit into
+/// the data.
+pub struct AdStructures<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> AdStructures<'a> {
+    pub fn new(data: &'a [u8]) -> AdStructures<'a> {
+        AdStructures {
+            data: data,
+            offset: 0,
+        }
+    }
+}
+
+impl<'a> Iterator for AdStructures<'a> {
+    type Item = (usize, AdStructure<'a>);
+
+    fn next(&mut self) -> Option<(usize, AdStructure<'a>)> {
+        let offset = self.offset;
+        if offset >= self.data.len() {
+            return None;
+        }
+        let len = self.data[offset] as usize;
+        if len == 0 || offset + 1 + len > self.data.len() {
+            self.offset = self.data.len();
+            return None;
+        }
+        self.offset = offset + 1 + len;
+        Some((
+            offset,
+            AdStructure {
+                ad_type: self.data[offset + 1],
+                data: &self.data[offset + 2..offset + 1 + len],
+            },
+        ))
+    }
+}
+
+/// Returns the length of the significant part of `data`, or an error if it
+/// is not valid advertising or scan response data: `ESIZE` if it does not fit
+/// into 31 bytes and `EINVAL` if an AD structure does not fit into it. Zero
+/// padding beyond 31 bytes is allowed, so that the data can be built in a
+/// larger buffer.
+pub fn validate(data: &[u8]) -> Result<usize, ReturnCode> {
+    let mut end = 0;
+    for (offset, structure) in AdStructures::new(data) {
+        end = offset + structure.encoded_len();
+    }
+    if end < data.len() && data[end] != 0 {
+        return Err(ReturnCode::EINVAL);
+    }
+    let mut padding = data.iter().skip(MAX_ADVERTISING_DATA_LENGTH);
+    if end > MAX_ADVERTISING_DATA_LENGTH || padding.any(|&byte| byte != 0) {
+        return Err(ReturnCode::ESIZE);
+    }
+    Ok(end)
+}
+
+/// Returns the data of the first AD structure with the AD type
+pub fn find(data: &[u8], ad_type: u8) -> Option<&[u8]> {
+    AdStructures::new(data)
+        .find(|&(_, structure)| structure.ad_type == ad_type)
+        .map(|(_, structure)| structure.data)
+}
+
+/// Advertising or scan response data under construction
+#[derive(Copy, Clone)]
+pub struct AdvertisingData {
+    buf: [u8; MAX_ADVERTISING_DATA_LENGTH],
+    len: usize,
+}
+
+impl Default for AdvertisingData {
+    fn default() -> AdvertisingData {
+        AdvertisingData::new()
+    }
+}
+
+impl AdvertisingData {
+    pub fn new() -> AdvertisingData {
+        AdvertisingData {
+            buf: [0; MAX_ADVERTISING_DATA_LENGTH],
+            len: 0,
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn structures(&self) -> AdStructures {
+        AdStructures::new(self.as_slice())
+    }
+
+    /// Returns the data of the AD structure with the AD type
+    pub fn get(&self, ad_type: u8) -> Option<&[u8]> {
+        find(self.as_slice(), ad_type)
+    }
+
+    /// Replaces all the data with pre-built AD structures, which are checked
+    /// with `validate`. Padding is dropped.
+    pub fn set_all(&mut self, data: &[u8]) -> ReturnCode {
+        match validate(data) {
+            Ok(len) => {
+                self.buf[..len].copy_from_slice(&data[..len]);
+                self.len = len;
+                ReturnCode::SUCCESS
+            }
+            Err(error) => error,
+        }
+    }
+
+    /// Sets the AD structure with the AD type, replacing the existing one in
+    /// place or appending a new one. Returns `EINVAL` for the reserved AD type
+    /// 0 and `ESIZE` if the data would no longer fit into 31 bytes, in which
+    /// case it is left unchanged.
+    pub fn set(&mut self, ad_type: u8, data: &[u8]) -> ReturnCode {
+        if ad_type == 0 {
+            return ReturnCode::EINVAL;
+        }
+        let (start, end) = self.position(ad_type).unwrap_or((self.len, self.len));
+        let len = self.len - (end - start) + 2 + data.len();
+        if data.len() > MAX_AD_DATA_LENGTH || len > MAX_ADVERTISING_DATA_LENGTH {
+            return ReturnCode::ESIZE;
+        }
+
+        let mut buf = [0; MAX_ADVERTISING_DATA_LENGTH];
+        buf[..start].copy_from_slice(&self.buf[..start]);
+        buf[start] = (data.len() + 1) as u8;
+        buf[start + 1] = ad_type;
+        buf[start + 2..start + 2 + data.len()].copy_from_slice(data);
+        let tail = self.len - end;
+        buf[start + 2 + data.len()..len].copy_from_slice(&self.buf[end..end + tail]);
+        self.buf = buf;
+        self.len = len;
+        ReturnCode::SUCCESS
+    }
+
+    /// Removes the AD structure with the AD type. Returns `EINVAL` if there is
+    /// none.
+    pub fn remove(&mut self, ad_type: u8) -> ReturnCode {
+        match self.position(ad_type) {
+            Some((start, end)) => {
+                for i in 0..self.len - end {
+                    self.buf[start + i] = self.buf[end + i];
+                }
+                self.len -= end - start;
+                ReturnCode::SUCCESS
+            }
+            None => ReturnCode::EINVAL,
+        }
+    }
+
+    // Start and end of the AD structure with the AD type
+    fn position(&self, ad_type: u8) -> Option<(usize, usize)> {
+        self.structures()
+            .find(|&(_, structure)| structure.ad_type == ad_type)
+            .map(|(offset, structure)| (offset, offset + structure.encoded_len()))
+    }
+}
@@ -7,8 +7,21 @@
 //! driver but processes can request an advertising or scanning interval.
 //! Processes can also control the TX power used for their advertisements.
 //!
+//! Scanning is either passive, or active: the driver then answers scannable
+//! advertisements (`ADV_IND` and `ADV_SCAN_IND`) with a `SCAN_REQ` and reports
+//! them together with the data of the advertiser's `SCAN_RSP`. Advertising
+//! processes that provide a scan response send `ADV_SCAN_IND`s and answer the
+//! `SCAN_REQ`s they receive. Scanning processes can filter out duplicate
+//! advertisements, advertisements from addresses not in a list, and
+//! advertisements without a given AD type.
+//!
 //! Data payloads are limited to 31 bytes since the maximum advertising channel
-//! protocol data unit (PDU) is 37 bytes and includes a 6-byte header.
+//! protocol data unit (PDU) is 37 bytes and includes a 6-byte header. The
+//! driver builds the advertising data of each process with
+//! `ble_advertising_data::AdvertisingData`: processes either allow a complete
+//! payload, or set AD structures one at a time, which replaces the AD
+//! structure of the same AD type and leaves the others as they are. Scanning
+//! processes can have the driver index the AD structures it receives.
 //!
 //! ### Allow system call
 //! The allow systems calls are used for buffers from allocated by userland
@@ -21,7 +34,8 @@
 //! * Advertisement
 //!
 //!
-//! The following allow numbers are supported:
+//! The following allow numbers are supported, where the ones named after an
+//! AD type set the AD structure of that type to the data of the buffer:
 //!
 //! * 1: «Flags»
 //! Bluetooth Core Specification:Vol. 3, Part C, section 8.1.3
@@ -54,8 +68,19 @@
 //! Bluetooth Core Specification:Core Specification Supplement, Part A, section 1.12
 //! * 26: «Advertising Interval»
 //! Bluetooth Core Specification:Core Specification Supplement, Part A, section 1.15
-//! * 49: Passive Scanning
+//! * 49: Scanning
 //! * 50: Advertising
+//! * 51: Scan response data, up to 31 bytes of AD structures, which may be
+//!       followed by zero padding
+//! * 52: Scanning address filter, a list of 6-byte addresses
+//! * 53: Advertising data, up to 31 bytes of AD structures replacing the
+//!       current ones, which may be followed by zero padding
+//! * 54: A single AD structure (length, AD type and data) to add, or to
+//!       replace the one of the same AD type
+//! * 55: Scanning AD index, which the driver fills with a 3-byte entry (AD
+//!       type, offset of the AD data in the scanning buffer and its length)
+//!       per AD structure reported, followed by an AD type of 0 if there is
+//!       room for it
 //! * 255: «Manufacturer Specific Data» Bluetooth Core Specification:Vol. 3, Part C, section 8.1.4
 //!
 //! The possible return codes from the 'allow' system call indicate the following:
@@ -63,7 +88,9 @@
 //! * SUCCESS: The buffer has successfully been filled
 //! * ENOSUPPORT: Invalid allow_num
 //! * ENOMEM: No sufficient memory available
-//! * EINVAL: Invalid address of the buffer or other error
+//! * ESIZE: The advertising or scan response data would exceed 31 bytes
+//! * EINVAL: Invalid address of the buffer, malformed AD structures or other
+//!           error
 //! * EBUSY: The driver is currently busy with other tasks
 //! * ENOSUPPORT: The operation is not supported
 //!
@@ -73,6 +100,9 @@
 //!
 //! * 0: provides a callback user-space when a device scanning for advertisements
 //!      and the callback is used to invoke user-space processes.
+//!      The scanning buffer then holds the advertising channel PDU, whose
+//!      length is the second argument, followed by the data of its scan
+//!      response when scanning actively, whose length is the third argument.
 //!
 //! The possible return codes from the 'allow' system call indicate the following:
 //!
@@ -89,8 +119,12 @@
 //! * 2: configure tx power
 //! * 3: configure advertisement interval
 //! * 4: clear the advertisement payload
-//! * 5: start scanning
+//! * 5: start passive scanning
 //! * 6: initialize driver
+//! * 7: start active scanning
+//! * 8: filter out duplicate advertisements when scanning
+//! * 9: only report advertisements with an AD type when scanning
+//! * 10: remove the AD structure of an AD type from the advertising data
 //!
 //! The possible return codes from the 'command' system call indicate the following:
 //!
@@ -171,21 +205,21 @@
 //!           +-------------------------------+
 //! ```
 //!
-//! You need a device that provides the `nrf5x::ble_advertising_hil::BleAdvertisementDriver` trait
+//! You need a device that provides the `kernel::hil::ble_advertising::BleAdvertisementDriver` trait
 //! along with a virtual timer to perform events and not block the entire kernel
 //!
 //! ```rust
 //!     let ble_radio = static_init!(
-//!     nrf5x::ble_advertising_driver::BLE
+//!     capsules::ble_advertising_driver::BLE
 //!     <'static, nrf52::radio::Radio, VirtualMuxAlarm<'static, Rtc>>,
-//!     nrf5x::ble_advertising_driver::BLE::new(
+//!     capsules::ble_advertising_driver::BLE::new(
 //!         &mut nrf52::radio::RADIO,
 //!     kernel::Grant::create(),
-//!         &mut nrf5x::ble_advertising_driver::BUF,
+//!         &mut capsules::ble_advertising_driver::BUF,
 //!         ble_radio_virtual_alarm));
-//!    nrf5x::ble_advertising_hil::BleAdvertisementDriver::set_rx_client(&nrf52::radio::RADIO,
+//!    kernel::hil::ble_advertising::BleAdvertisementDriver::set_rx_client(&nrf52::radio::RADIO,
 //!                                                                      ble_radio);
-//!    nrf5x::ble_advertising_hil::BleAdvertisementDriver::set_tx_client(&nrf52::radio::RADIO,
+//!    kernel::hil::ble_advertising::BleAdvertisementDriver::set_tx_client(&nrf52::radio::RADIO,
 //!                                                                      ble_radio);
 //!    ble_radio_virtual_alarm.set_client(ble_radio);
 //! ```
@@ -195,11 +229,14 @@
 //! * Fredrik Nilsson <frednils@student.chalmers.se>
 //! * Date: June 22, 2017
 
-use ble_advertising_hil;
-use ble_advertising_hil::RadioChannel;
+use ble_advertising_data;
+use ble_advertising_data::{AdStructures, AdvertisingData};
+use ble_link_layer_hil::MAX_ADVERTISING_DATA_LENGTH;
 use core::cell::Cell;
 use core::cmp;
 use kernel;
+use kernel::hil::ble_advertising;
+use kernel::hil::ble_advertising::{RadioChannel, Turnaround};
 use kernel::hil::time::Frequency;
 use kernel::returncode::ReturnCode;
 
@@ -215,6 +252,11 @@ enum AllowType {
     BLEGap(BLEGapType),
     PassiveScanning,
     InitAdvertisementBuffer,
+    ScanResponse,
+    AddressFilter,
+    AdvertisingData,
+    AdStructure,
+    AdIndex,
 }
 
 impl AllowType {
@@ -241,6 +283,11 @@ impl AllowType {
             0x1A => Some(AllowType::BLEGap(BLEGapType::AdvertisingInterval)),
             0x31 => Some(AllowType::PassiveScanning),
             0x32 => Some(AllowType::InitAdvertisementBuffer),
+            0x33 => Some(AllowType::ScanResponse),
+            0x34 => Some(AllowType::AddressFilter),
+            0x35 => Some(AllowType::AdvertisingData),
+            0x36 => Some(AllowType::AdStructure),
+            0x37 => Some(AllowType::AdIndex),
             0xFF => Some(AllowType::BLEGap(BLEGapType::ManufacturerSpecificData)),
             _ => None,
         }
@@ -348,14 +395,36 @@ const PACKET_ADDR_END: usize = 7;
 const PACKET_PAYLOAD_START: usize = 8;
 const PACKET_LENGTH: usize = 39;
 
+const PDU_TYPE_MASK: u8 = 0x0f;
+const PDU_TX_ADD: u8 = 1 << 6;
+const PDU_RX_ADD: u8 = 1 << 7;
+const PDU_LENGTH_MASK: u8 = 0x3f;
+const ADDRESS_LENGTH: usize = 6;
+const SCAN_REQUEST_LENGTH: usize = 2 * ADDRESS_LENGTH;
+
+// Entries of the AD index: AD type, offset and length
+const AD_INDEX_ENTRY_LENGTH: usize = 3;
+
+// How long to listen for a SCAN_REQ after an advertisement, or for a SCAN_RSP
+// after a SCAN_REQ
+const RESPONSE_TIMEOUT_MS: u32 = 1;
+
+// Number of advertisers remembered by the duplicate filter of an app
+const DUPLICATE_FILTER_SIZE: usize = 8;
+
 #[derive(PartialEq, Debug)]
 enum BLEState {
     NotInitialized,
     Initialized,
     ScanningIdle,
     Scanning(RadioChannel),
+    // Sent a SCAN_REQ and listening for the SCAN_RSP
+    RequestingScanResponse(RadioChannel),
     AdvertisingIdle,
     Advertising(RadioChannel),
+    // Listening for a SCAN_REQ after an advertisement
+    WaitingForScanRequest(RadioChannel),
+    SendingScanResponse(RadioChannel),
 }
 
 #[derive(Copy, Clone)]
@@ -381,10 +450,23 @@ impl AlarmData {
 
 pub struct App {
     advertisement_buf: Option<kernel::AppSlice<kernel::Shared, u8>>,
-    app_write: Option<kernel::AppSlice<kernel::Shared, u8>>,
     app_read: Option<kernel::AppSlice<kernel::Shared, u8>>,
+    scan_response_buf: Option<kernel::AppSlice<kernel::Shared, u8>>,
+    address_filter: Option<kernel::AppSlice<kernel::Shared, u8>>,
+    ad_index: Option<kernel::AppSlice<kernel::Shared, u8>>,
     scan_callback: Option<kernel::Callback>,
-    idx: usize,
+    active_scanning: bool,
+    duplicate_filter: bool,
+    ad_type_filter: Option<u8>,
+    /// Advertisers already reported, when duplicates are filtered out
+    reported: [Option<[u8; ADDRESS_LENGTH]>; DUPLICATE_FILTER_SIZE],
+    reported_idx: usize,
+    /// Length of the advertisement written to `app_read` while waiting for
+    /// its scan response
+    pending_advertisement_len: usize,
+    /// The payload of the advertisements, copied to `advertisement_buf`
+    /// whenever it changes
+    advertising_data: AdvertisingData,
     process_status: Option<BLEState>,
     advertisement_interval_ms: u32,
     alarm_data: AlarmData,
@@ -402,10 +484,18 @@ impl Default for App {
         App {
             advertisement_buf: None,
             alarm_data: AlarmData::new(),
-            app_write: None,
             app_read: None,
+            scan_response_buf: None,
+            address_filter: None,
+            ad_index: None,
             scan_callback: None,
-            idx: PACKET_PAYLOAD_START,
+            active_scanning: false,
+            duplicate_filter: false,
+            ad_type_filter: None,
+            reported: [None; DUPLICATE_FILTER_SIZE],
+            reported_idx: 0,
+            pending_advertisement_len: 0,
+            advertising_data: AdvertisingData::new(),
             process_status: Some(BLEState::NotInitialized),
             tx_power: 0,
             advertisement_interval_ms: 200,
@@ -445,16 +535,13 @@ impl App {
     // Byte 6            0xf0
     // FIXME: For now use AppId as "randomness"
     fn generate_random_address(&mut self, appid: kernel::AppId) -> ReturnCode {
+        let len = ADDRESS_LENGTH + self.advertising_data.len();
         self.advertisement_buf
             .as_mut()
             .map(|data| {
-                data.as_mut()[PACKET_HDR_LEN] = 6;
-                data.as_mut()[PACKET_ADDR_START] = 0xf0;
-                data.as_mut()[PACKET_ADDR_START + 1] = (appid.idx() & 0xff) as u8;
-                data.as_mut()[PACKET_ADDR_START + 2] = ((appid.idx() << 8) & 0xff) as u8;
-                data.as_mut()[PACKET_ADDR_START + 3] = ((appid.idx() << 16) & 0xff) as u8;
-                data.as_mut()[PACKET_ADDR_START + 4] = ((appid.idx() << 24) & 0xff) as u8;
-                data.as_mut()[PACKET_ADDR_END] = 0xf0;
+                data.as_mut()[PACKET_HDR_LEN] = len as u8;
+                data.as_mut()[PACKET_ADDR_START..PACKET_ADDR_END + 1]
+                    .copy_from_slice(&random_address(appid));
                 ReturnCode::SUCCESS
             })
             .unwrap_or_else(|| ReturnCode::ESIZE)
@@ -462,26 +549,40 @@ impl App {
 
     fn reset_payload(&mut self) -> ReturnCode {
         match self.process_status {
-            Some(BLEState::Advertising(_)) | Some(BLEState::Scanning(_)) => ReturnCode::EBUSY,
+            Some(BLEState::Advertising(_))
+            | Some(BLEState::WaitingForScanRequest(_))
+            | Some(BLEState::SendingScanResponse(_))
+            | Some(BLEState::Scanning(_))
+            | Some(BLEState::RequestingScanResponse(_)) => ReturnCode::EBUSY,
             _ => {
-                let res = self.advertisement_buf
-                    .as_mut()
-                    .map(|data| {
-                        for byte in data.as_mut()[PACKET_PAYLOAD_START..PACKET_LENGTH].iter_mut() {
-                            *byte = 0x00;
-                        }
-                        ReturnCode::SUCCESS
-                    })
-                    .unwrap_or_else(|| ReturnCode::EINVAL);
-                if res == ReturnCode::SUCCESS {
-                    self.idx = PACKET_PAYLOAD_START;
-                }
-                res
+                self.advertising_data.clear();
+                self.write_payload()
             }
         }
     }
 
-    // Hard-coded to ADV_NONCONN_IND
+    // Copies the advertising data to the advertisement buffer and updates the
+    // length of the PDU
+    fn write_payload(&mut self) -> ReturnCode {
+        let payload = self.advertising_data.as_slice();
+        self.advertisement_buf
+            .as_mut()
+            .map(|data| {
+                let data = data.as_mut();
+                data[PACKET_HDR_LEN] = (ADDRESS_LENGTH + payload.len()) as u8;
+                let (used, unused) =
+                    data[PACKET_PAYLOAD_START..PACKET_LENGTH].split_at_mut(payload.len());
+                used.copy_from_slice(payload);
+                for byte in unused.iter_mut() {
+                    *byte = 0x00;
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|| ReturnCode::EINVAL)
+    }
+
+    // ADV_NONCONN_IND, which `send_advertisement` turns into an ADV_SCAN_IND
+    // when the app provides a scan response
     fn configure_advertisement_pdu(&mut self) -> ReturnCode {
         self.advertisement_buf
             .as_mut()
@@ -492,53 +593,34 @@ impl App {
             .unwrap_or_else(|| ReturnCode::ESIZE)
     }
 
-    fn set_gap_data(&mut self, gap_type: BLEGapType) -> ReturnCode {
-        self.app_write
-            .take()
-            .as_ref()
-            .map(|slice| {
-                let idx = self.idx;
-                let end = idx + slice.len() + 2;
-
-                if end <= PACKET_LENGTH {
-                    let result = self.advertisement_buf
-                        .as_mut()
-                        .map(|data| {
-                            // set header and length
-                            data.as_mut()[idx] = (slice.len() + 1) as u8;
-                            data.as_mut()[idx + 1] = gap_type as u8;
-
-                            // update total packet size
-                            data.as_mut()[PACKET_HDR_LEN] = (end - 2) as u8;
-
-                            // set data
-                            for (dst, src) in data.as_mut()[idx + 2..end]
-                                .iter_mut()
-                                .zip(slice.as_ref()[0..slice.len()].iter())
-                            {
-                                *dst = *src;
-                            }
-                            ReturnCode::SUCCESS
-                        })
-                        .unwrap_or_else(|| ReturnCode::EINVAL);
+    // Changes the advertising data with `update` and copies it to the
+    // advertisement buffer, unless `update` fails
+    fn update_advertising_data<F>(&mut self, update: F) -> ReturnCode
+    where
+        F: FnOnce(&mut AdvertisingData) -> ReturnCode,
+    {
+        if self.advertisement_buf.is_none() {
+            return ReturnCode::EINVAL;
+        }
+        match update(&mut self.advertising_data) {
+            ReturnCode::SUCCESS => self.write_payload(),
+            error => error,
+        }
+    }
 
-                    // If the operation was successful => update idx
-                    if result == ReturnCode::SUCCESS {
-                        self.idx = end;
-                    }
-                    result
-                } else {
-                    ReturnCode::ESIZE
-                }
-            })
-            .unwrap_or_else(|| ReturnCode::EINVAL)
+    fn set_gap_data(&mut self, gap_type: BLEGapType, data: &[u8]) -> ReturnCode {
+        self.update_advertising_data(|advertising_data| advertising_data.set(gap_type as u8, data))
     }
 
     fn send_advertisement<'a, B, A>(&self, ble: &BLE<'a, B, A>, channel: RadioChannel) -> ReturnCode
     where
-        B: ble_advertising_hil::BleAdvertisementDriver + ble_advertising_hil::BleConfig + 'a,
+        B: ble_advertising::BleAdvertisementDriver
+        + ble_advertising::BleConfig
+        + ble_advertising::BleLinkLayerRadio
+        + 'a,
         A: kernel::hil::time::Alarm + 'a,
     {
+        let scannable = self.scan_response_buf.is_some();
         self.advertisement_buf
             .as_ref()
             .map(|slice| {
@@ -551,6 +633,12 @@ impl App {
                         {
                             *out = *inp;
                         }
+                        if scannable {
+                            // Listen for SCAN_REQs right after the advertisement
+                            data[PACKET_HDR_PDU] = (data[PACKET_HDR_PDU] & !PDU_TYPE_MASK)
+                                | BLEAdvertisementType::ScanUndirected as u8;
+                            ble.radio.set_turnaround(Turnaround::Receive);
+                        }
                         let result = ble.radio
                             .transmit_advertisement(data, PACKET_LENGTH, channel);
                         ble.kernel_tx.replace(result);
@@ -583,11 +671,159 @@ impl App {
         let period_ms = (self.advertisement_interval_ms + nonce) * F::frequency() / 1000;
         self.alarm_data.expiration = Expiration::Abs(now.wrapping_add(period_ms));
     }
+
+    // Set the alarm for this app to stop waiting for a SCAN_REQ or a SCAN_RSP.
+    fn set_response_timeout<F: Frequency>(&mut self, now: u32) {
+        self.alarm_data.t0 = now;
+        let timeout = RESPONSE_TIMEOUT_MS * F::frequency() / 1000;
+        self.alarm_data.expiration = Expiration::Abs(now.wrapping_add(timeout));
+    }
+
+    // Whether an advertising channel PDU from `address` passes the address
+    // filter and the duplicate filter of the app
+    fn accepts_address(&self, address: &[u8]) -> bool {
+        let allowed = self.address_filter.as_ref().map_or(true, |filter| {
+            filter
+                .as_ref()
+                .chunks(ADDRESS_LENGTH)
+                .any(|entry| entry == address)
+        });
+        let duplicate = self.duplicate_filter
+            && self.reported
+                .iter()
+                .any(|reported| reported.as_ref().map_or(false, |reported| reported == address));
+        allowed && !duplicate
+    }
+
+    fn reset_reported(&mut self) {
+        self.reported = [None; DUPLICATE_FILTER_SIZE];
+        self.reported_idx = 0;
+    }
+
+    // Notifies the app of an advertising channel PDU written to `app_read`,
+    // followed by the data of its scan response if any, unless they don't
+    // contain the AD type the app filters on
+    fn report_advertisement(&mut self, advertisement_len: usize, scan_response_len: usize) {
+        let mut address = [0; ADDRESS_LENGTH];
+        let matches = match self.app_read.as_ref() {
+            Some(slice) => {
+                let data = slice.as_ref();
+                address.copy_from_slice(&data[PACKET_ADDR_START..PACKET_ADDR_END + 1]);
+                self.ad_type_filter.map_or(true, |ad_type| {
+                    received_data(data, advertisement_len, scan_response_len)
+                        .iter()
+                        .any(|&(_, data)| ble_advertising_data::find(data, ad_type).is_some())
+                })
+            }
+            None => false,
+        };
+        if !matches {
+            return;
+        }
+        self.index_ad_structures(advertisement_len, scan_response_len);
+
+        if self.duplicate_filter {
+            self.reported[self.reported_idx] = Some(address);
+            self.reported_idx = (self.reported_idx + 1) % DUPLICATE_FILTER_SIZE;
+        }
+        self.scan_callback.map(|mut cb| {
+            cb.schedule(
+                usize::from(ReturnCode::SUCCESS),
+                advertisement_len,
+                scan_response_len,
+            );
+        });
+    }
+
+    // Writes an entry to the AD index for each AD structure reported in
+    // `app_read`, followed by an AD type of 0 if there is room for it
+    fn index_ad_structures(&mut self, advertisement_len: usize, scan_response_len: usize) {
+        let data = match self.app_read.as_ref() {
+            Some(slice) => slice.as_ref(),
+            None => return,
+        };
+        self.ad_index.as_mut().map(|index| {
+            let mut entries = index
+                .as_mut()
+                .chunks_mut(AD_INDEX_ENTRY_LENGTH)
+                .filter(|entry| entry.len() == AD_INDEX_ENTRY_LENGTH);
+            for &(start, data) in received_data(data, advertisement_len, scan_response_len).iter() {
+                for (offset, structure) in AdStructures::new(data) {
+                    match entries.next() {
+                        Some(entry) => {
+                            entry[0] = structure.ad_type;
+                            entry[1] = (start + offset + 2) as u8;
+                            entry[2] = structure.data.len() as u8;
+                        }
+                        None => return,
+                    }
+                }
+            }
+            entries.next().map(|entry| entry[0] = 0);
+        });
+    }
+}
+
+// See `App::generate_random_address`
+fn random_address(appid: kernel::AppId) -> [u8; ADDRESS_LENGTH] {
+    [
+        0xf0,
+        (appid.idx() & 0xff) as u8,
+        ((appid.idx() << 8) & 0xff) as u8,
+        ((appid.idx() << 16) & 0xff) as u8,
+        ((appid.idx() << 24) & 0xff) as u8,
+        0xf0,
+    ]
+}
+
+// Whether the PDU type is ADV_IND or ADV_SCAN_IND, which can be answered with
+// a SCAN_REQ
+fn is_scannable(header: u8) -> bool {
+    let pdu_type = header & PDU_TYPE_MASK;
+    pdu_type == BLEAdvertisementType::ConnectUndirected as u8
+        || pdu_type == BLEAdvertisementType::ScanUndirected as u8
+}
+
+// Returns the AD structures of an ADV_IND, ADV_NONCONN_IND, ADV_SCAN_IND or
+// SCAN_RSP, which are empty for the other advertising channel PDUs
+fn advertisement_data(pdu: &[u8]) -> &[u8] {
+    let pdu_type = pdu[PACKET_HDR_PDU] & PDU_TYPE_MASK;
+    let has_data = pdu_type == BLEAdvertisementType::ConnectUndirected as u8
+        || pdu_type == BLEAdvertisementType::NonConnectUndirected as u8
+        || pdu_type == BLEAdvertisementType::ScanUndirected as u8
+        || pdu_type == BLEAdvertisementType::ScanResponse as u8;
+    if has_data {
+        &pdu[PACKET_PAYLOAD_START..]
+    } else {
+        &[]
+    }
+}
+
+// The data reported to a scanning app: the AD structures of the advertisement
+// and those of its scan response, with their offsets in `app_read`
+fn received_data(
+    data: &[u8],
+    advertisement_len: usize,
+    scan_response_len: usize,
+) -> [(usize, &[u8]); 2] {
+    [
+        (
+            PACKET_PAYLOAD_START,
+            advertisement_data(&data[..advertisement_len]),
+        ),
+        (
+            advertisement_len,
+            &data[advertisement_len..advertisement_len + scan_response_len],
+        ),
+    ]
 }
 
 pub struct BLE<'a, B, A>
 where
-    B: ble_advertising_hil::BleAdvertisementDriver + ble_advertising_hil::BleConfig + 'a,
+    B: ble_advertising::BleAdvertisementDriver
+        + ble_advertising::BleConfig
+        + ble_advertising::BleLinkLayerRadio
+        + 'a,
     A: kernel::hil::time::Alarm + 'a,
 {
     radio: &'a B,
@@ -601,7 +837,10 @@ where
 
 impl<'a, B, A> BLE<'a, B, A>
 where
-    B: ble_advertising_hil::BleAdvertisementDriver + ble_advertising_hil::BleConfig + 'a,
+    B: ble_advertising::BleAdvertisementDriver
+        + ble_advertising::BleConfig
+        + ble_advertising::BleLinkLayerRadio
+        + 'a,
     A: kernel::hil::time::Alarm + 'a,
 {
     pub fn new(
@@ -648,12 +887,172 @@ where
             self.alarm.set_alarm(next_alarm);
         }
     }
+
+    // Continues the advertising event of the app on the next channel, or ends
+    // it after channel 39
+    fn advertise_next(&self, app: &mut App, channel: RadioChannel) {
+        match channel {
+            RadioChannel::AdvertisingChannel37 => {
+                app.process_status =
+                    Some(BLEState::Advertising(RadioChannel::AdvertisingChannel38));
+                app.alarm_data.expiration = Expiration::Disabled;
+                self.radio.set_tx_power(app.tx_power);
+                app.send_advertisement(&self, RadioChannel::AdvertisingChannel38);
+            }
+            RadioChannel::AdvertisingChannel38 => {
+                app.process_status =
+                    Some(BLEState::Advertising(RadioChannel::AdvertisingChannel39));
+                app.send_advertisement(&self, RadioChannel::AdvertisingChannel39);
+            }
+            _ => {
+                self.busy.set(false);
+                app.process_status = Some(BLEState::AdvertisingIdle);
+                app.set_next_alarm::<A::Frequency>(self.alarm.now());
+            }
+        }
+    }
+
+    fn scan(&self, app: &mut App, channel: RadioChannel) {
+        app.process_status = Some(BLEState::Scanning(channel));
+        if app.active_scanning {
+            // Be ready to answer scannable advertisements with a SCAN_REQ
+            self.radio.set_turnaround(Turnaround::Transmit);
+        }
+        self.radio.receive_advertisement(channel);
+    }
+
+    // Continues the scanning event of the app on the next channel, or ends it
+    // after channel 39
+    fn scan_next(&self, app: &mut App, channel: RadioChannel) {
+        match channel {
+            RadioChannel::AdvertisingChannel37 => {
+                app.alarm_data.expiration = Expiration::Disabled;
+                self.radio.set_tx_power(app.tx_power);
+                self.scan(app, RadioChannel::AdvertisingChannel38);
+            }
+            RadioChannel::AdvertisingChannel38 => {
+                self.scan(app, RadioChannel::AdvertisingChannel39);
+            }
+            _ => {
+                self.busy.set(false);
+                app.process_status = Some(BLEState::ScanningIdle);
+                app.set_next_alarm::<A::Frequency>(self.alarm.now());
+            }
+        }
+    }
+
+    // Handles an advertising channel PDU received while scanning. Returns
+    // whether a SCAN_REQ is sent in response, in which case the PDU is only
+    // reported once the SCAN_RSP is received.
+    fn receive_scanned_pdu(&self, app: &mut App, appid: kernel::AppId, pdu: &[u8]) -> bool {
+        if !app.accepts_address(&pdu[PACKET_ADDR_START..PACKET_ADDR_END + 1]) {
+            return false;
+        }
+        let copied = app.app_read.as_mut().map_or(false, |dest| {
+            if dest.len() >= pdu.len() {
+                // write to buffer in userland
+                dest.as_mut()[..pdu.len()].copy_from_slice(pdu);
+                true
+            } else {
+                false
+            }
+        });
+        if !copied {
+            return false;
+        }
+        if !app.active_scanning || !is_scannable(pdu[PACKET_HDR_PDU]) {
+            app.report_advertisement(pdu.len(), 0);
+            return false;
+        }
+
+        // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.3.2.1
+        // The SCAN_REQ is sent from the address of the app to the advertiser,
+        // T_IFS after the advertisement
+        let advertiser_random = pdu[PACKET_HDR_PDU] & PDU_TX_ADD != 0;
+        app.pending_advertisement_len = pdu.len();
+        self.kernel_tx.take().map(|data| {
+            data[PACKET_HDR_PDU] = BLEAdvertisementType::ScanRequest as u8 | PDU_TX_ADD;
+            if advertiser_random {
+                data[PACKET_HDR_PDU] |= PDU_RX_ADD;
+            }
+            data[PACKET_HDR_LEN] = SCAN_REQUEST_LENGTH as u8;
+            data[PACKET_ADDR_START..PACKET_PAYLOAD_START].copy_from_slice(&random_address(appid));
+            data[PACKET_PAYLOAD_START..PACKET_PAYLOAD_START + ADDRESS_LENGTH]
+                .copy_from_slice(&pdu[PACKET_ADDR_START..PACKET_ADDR_END + 1]);
+            let data = self.radio
+                .set_response(data, PACKET_PAYLOAD_START + ADDRESS_LENGTH);
+            self.kernel_tx.replace(data);
+        });
+        // Listen for the SCAN_RSP right after the SCAN_REQ
+        self.radio.set_turnaround(Turnaround::Receive);
+        true
+    }
+
+    // Handles an advertising channel PDU received after a SCAN_REQ, and
+    // reports the advertisement the SCAN_REQ answered
+    fn receive_scan_response(&self, app: &mut App, pdu: Option<&[u8]>) {
+        let advertisement_len = app.pending_advertisement_len;
+        let scan_response_len = match pdu {
+            Some(pdu) if pdu[PACKET_HDR_PDU] & PDU_TYPE_MASK
+                == BLEAdvertisementType::ScanResponse as u8 =>
+            {
+                app.app_read.as_mut().map_or(0, |dest| {
+                    let dest = dest.as_mut();
+                    let data = advertisement_data(pdu);
+                    let end = advertisement_len + data.len();
+                    // Only the SCAN_RSP of the advertiser that was asked counts
+                    let advertiser = &dest[PACKET_ADDR_START..PACKET_ADDR_END + 1];
+                    if advertiser == &pdu[PACKET_ADDR_START..PACKET_ADDR_END + 1]
+                        && end <= dest.len()
+                    {
+                        dest[advertisement_len..end].copy_from_slice(data);
+                        data.len()
+                    } else {
+                        0
+                    }
+                })
+            }
+            _ => 0,
+        };
+        app.report_advertisement(advertisement_len, scan_response_len);
+    }
+
+    // Answers a SCAN_REQ sent to the app, T_IFS after it. Returns whether the
+    // app has a scan response.
+    fn send_scan_response(&self, app: &App) -> bool {
+        let (advertisement, scan_response) =
+            match (app.advertisement_buf.as_ref(), app.scan_response_buf.as_ref()) {
+                (Some(advertisement), Some(scan_response)) => (advertisement, scan_response),
+                _ => return false,
+            };
+        // Checked by `ble_advertising_data::validate` when allowed, so that
+        // anything beyond 31 bytes is padding
+        let len = cmp::min(scan_response.len(), MAX_ADVERTISING_DATA_LENGTH);
+
+        // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.3.2.2
+        self.kernel_tx.take().map(|data| {
+            let advertisement = advertisement.as_ref();
+            data[PACKET_HDR_PDU] = BLEAdvertisementType::ScanResponse as u8
+                | (advertisement[PACKET_HDR_PDU] & PDU_TX_ADD);
+            data[PACKET_HDR_LEN] = (ADDRESS_LENGTH + len) as u8;
+            data[PACKET_ADDR_START..PACKET_PAYLOAD_START]
+                .copy_from_slice(&advertisement[PACKET_ADDR_START..PACKET_PAYLOAD_START]);
+            data[PACKET_PAYLOAD_START..PACKET_PAYLOAD_START + len]
+                .copy_from_slice(&scan_response.as_ref()[..len]);
+            let data = self.radio.set_response(data, PACKET_PAYLOAD_START + len);
+            self.kernel_tx.replace(data);
+        });
+        true
+    }
 }
 
 // Timer alarm
 impl<'a, B, A> kernel::hil::time::Client for BLE<'a, B, A>
 where
-    B: ble_advertising_hil::BleAdvertisementDriver + ble_advertising_hil::BleConfig + 'a,
+    B: ble_advertising::BleAdvertisementDriver
+        + ble_advertising::BleConfig
+        + ble_advertising::BleLinkLayerRadio
+        + 'a,
     A: kernel::hil::time::Alarm + 'a,
 {
     // When an alarm is fired, we find which apps have expired timers. Expired
@@ -676,6 +1075,24 @@ where
                 let expired =
                     now.wrapping_sub(app.alarm_data.t0) >= exp.wrapping_sub(app.alarm_data.t0);
                 if expired {
+                    // The other device did not answer in time
+                    match app.process_status {
+                        Some(BLEState::WaitingForScanRequest(channel)) => {
+                            self.radio.disable();
+                            app.alarm_data.expiration = Expiration::Disabled;
+                            self.advertise_next(app, channel);
+                            return;
+                        }
+                        Some(BLEState::RequestingScanResponse(channel)) => {
+                            self.radio.disable();
+                            app.alarm_data.expiration = Expiration::Disabled;
+                            self.receive_scan_response(app, None);
+                            self.scan_next(app, channel);
+                            return;
+                        }
+                        _ => (),
+                    }
+
                     if self.busy.get() {
                         // The radio is currently busy, so we won't be able to start the
                         // operation at the appropriate time. Instead, reschedule the
@@ -697,12 +1114,10 @@ where
                         }
                         Some(BLEState::ScanningIdle) => {
                             self.busy.set(true);
-                            app.process_status =
-                                Some(BLEState::Scanning(RadioChannel::AdvertisingChannel37));
                             self.receiving_app.set(Some(app.appid()));
+                            self.sending_app.set(Some(app.appid()));
                             self.radio.set_tx_power(app.tx_power);
-                            self.radio
-                                .receive_advertisement(RadioChannel::AdvertisingChannel37);
+                            self.scan(app, RadioChannel::AdvertisingChannel37);
                         }
                         _ => debug!(
                             "app: {:?} \t invalid state {:?}",
@@ -718,12 +1133,15 @@ where
 }
 
 // Callback from the radio once a RX event occur
-impl<'a, B, A> ble_advertising_hil::RxClient for BLE<'a, B, A>
+impl<'a, B, A> ble_advertising::RxClient for BLE<'a, B, A>
 where
-    B: ble_advertising_hil::BleAdvertisementDriver + ble_advertising_hil::BleConfig + 'a,
+    B: ble_advertising::BleAdvertisementDriver
+        + ble_advertising::BleConfig
+        + ble_advertising::BleLinkLayerRadio
+        + 'a,
     A: kernel::hil::time::Alarm + 'a,
 {
-    fn receive_event(&self, buf: &'static mut [u8], len: u8, result: ReturnCode) {
+    fn receive_event(&self, buf: &'static mut [u8], _len: u8, result: ReturnCode) {
         if let Some(appid) = self.receiving_app.get() {
             let _ = self.app.enter(appid, |app, _| {
                 // Validate the received data, because ordinary BLE packets can be bigger than 39
@@ -734,48 +1152,57 @@ where
                 // channels 37, 38 and 39 should only be used for advertisements!
                 // Packets that are bigger than 39 bytes are likely "Channel PDUs" which should
                 // only be sent on the other 37 RadioChannel channels.
-
-                let notify_userland = if len <= PACKET_LENGTH as u8 && app.app_read.is_some()
-                    && result == ReturnCode::SUCCESS
-                {
-                    let dest = app.app_read.as_mut().unwrap();
-                    let d = &mut dest.as_mut();
-                    // write to buffer in userland
-                    for (i, c) in buf[0..len as usize].iter().enumerate() {
-                        d[i] = *c;
+                let pdu = if result == ReturnCode::SUCCESS && buf.len() > PACKET_HDR_LEN {
+                    let len = (buf[PACKET_HDR_LEN] & PDU_LENGTH_MASK) as usize + 2;
+                    if len >= PACKET_PAYLOAD_START && len <= PACKET_LENGTH && len <= buf.len() {
+                        Some(&buf[..len])
+                    } else {
+                        None
                     }
-                    true
                 } else {
-                    false
+                    None
                 };
 
-                if notify_userland {
-                    app.scan_callback.map(|mut cb| {
-                        cb.schedule(usize::from(result), len as usize, 0);
-                    });
-                }
-
                 match app.process_status {
-                    Some(BLEState::Scanning(RadioChannel::AdvertisingChannel37)) => {
-                        app.process_status =
-                            Some(BLEState::Scanning(RadioChannel::AdvertisingChannel38));
-                        app.alarm_data.expiration = Expiration::Disabled;
-                        self.receiving_app.set(Some(app.appid()));
-                        self.radio.set_tx_power(app.tx_power);
-                        self.radio
-                            .receive_advertisement(RadioChannel::AdvertisingChannel38);
+                    Some(BLEState::Scanning(channel)) => {
+                        let requested =
+                            pdu.map_or(false, |pdu| self.receive_scanned_pdu(app, appid, pdu));
+                        if requested {
+                            app.process_status = Some(BLEState::RequestingScanResponse(channel));
+                        } else {
+                            if app.active_scanning {
+                                // Cancel the SCAN_REQ
+                                self.radio.disable();
+                            }
+                            self.scan_next(app, channel);
+                        }
                     }
-                    Some(BLEState::Scanning(RadioChannel::AdvertisingChannel38)) => {
-                        app.process_status =
-                            Some(BLEState::Scanning(RadioChannel::AdvertisingChannel39));
-                        self.receiving_app.set(Some(app.appid()));
-                        self.radio
-                            .receive_advertisement(RadioChannel::AdvertisingChannel39);
+                    Some(BLEState::RequestingScanResponse(channel)) => {
+                        app.alarm_data.expiration = Expiration::Disabled;
+                        self.receive_scan_response(app, pdu);
+                        self.scan_next(app, channel);
                     }
-                    Some(BLEState::Scanning(RadioChannel::AdvertisingChannel39)) => {
-                        self.busy.set(false);
-                        app.process_status = Some(BLEState::ScanningIdle);
-                        app.set_next_alarm::<A::Frequency>(self.alarm.now());
+                    Some(BLEState::WaitingForScanRequest(channel)) => {
+                        app.alarm_data.expiration = Expiration::Disabled;
+                        // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.3.2.1
+                        let requested = pdu.map_or(false, |pdu| {
+                            let advertiser = app.advertisement_buf.as_ref().map_or(false, |adv| {
+                                adv.as_ref()[PACKET_ADDR_START..PACKET_PAYLOAD_START]
+                                    == pdu[PACKET_PAYLOAD_START..]
+                            });
+                            pdu[PACKET_HDR_PDU] & PDU_TYPE_MASK
+                                == BLEAdvertisementType::ScanRequest as u8
+                                && pdu[PACKET_HDR_LEN] as usize == SCAN_REQUEST_LENGTH
+                                && advertiser
+                        });
+                        if requested && self.send_scan_response(app) {
+                            app.process_status = Some(BLEState::SendingScanResponse(channel));
+                            self.sending_app.set(Some(appid));
+                        } else {
+                            // Cancel the SCAN_RSP
+                            self.radio.disable();
+                            self.advertise_next(app, channel);
+                        }
                     }
                     // Invalid state => don't care
                     _ => (),
@@ -787,9 +1214,12 @@ where
 }
 
 // Callback from the radio once a TX event occur
-impl<'a, B, A> ble_advertising_hil::TxClient for BLE<'a, B, A>
+impl<'a, B, A> ble_advertising::TxClient for BLE<'a, B, A>
 where
-    B: ble_advertising_hil::BleAdvertisementDriver + ble_advertising_hil::BleConfig + 'a,
+    B: ble_advertising::BleAdvertisementDriver
+        + ble_advertising::BleConfig
+        + ble_advertising::BleLinkLayerRadio
+        + 'a,
     A: kernel::hil::time::Alarm + 'a,
 {
     // The ReturnCode indicates valid CRC or not, not used yet but could be used for
@@ -798,27 +1228,29 @@ where
         if let Some(appid) = self.sending_app.get() {
             let _ = self.app.enter(appid, |app, _| {
                 match app.process_status {
-                    Some(BLEState::Advertising(RadioChannel::AdvertisingChannel37)) => {
-                        app.process_status =
-                            Some(BLEState::Advertising(RadioChannel::AdvertisingChannel38));
-                        app.alarm_data.expiration = Expiration::Disabled;
-                        self.sending_app.set(Some(app.appid()));
-                        self.radio.set_tx_power(app.tx_power);
-                        app.send_advertisement(&self, RadioChannel::AdvertisingChannel38);
+                    Some(BLEState::Advertising(channel)) => {
+                        if app.scan_response_buf.is_some() {
+                            // The radio is now listening for SCAN_REQs, be ready
+                            // to answer them
+                            app.process_status = Some(BLEState::WaitingForScanRequest(channel));
+                            self.receiving_app.set(Some(appid));
+                            self.radio.set_turnaround(Turnaround::Transmit);
+                            app.set_response_timeout::<A::Frequency>(self.alarm.now());
+                        } else {
+                            self.advertise_next(app, channel);
+                        }
                     }
 
-                    Some(BLEState::Advertising(RadioChannel::AdvertisingChannel38)) => {
-                        app.process_status =
-                            Some(BLEState::Advertising(RadioChannel::AdvertisingChannel39));
-                        self.sending_app.set(Some(app.appid()));
-                        app.send_advertisement(&self, RadioChannel::AdvertisingChannel39);
+                    Some(BLEState::SendingScanResponse(channel)) => {
+                        self.advertise_next(app, channel);
                     }
 
-                    Some(BLEState::Advertising(RadioChannel::AdvertisingChannel39)) => {
-                        self.busy.set(false);
-                        app.process_status = Some(BLEState::AdvertisingIdle);
-                        app.set_next_alarm::<A::Frequency>(self.alarm.now());
+                    // The SCAN_REQ was sent and the radio is now listening for
+                    // the SCAN_RSP
+                    Some(BLEState::RequestingScanResponse(_)) => {
+                        app.set_response_timeout::<A::Frequency>(self.alarm.now());
                     }
+
                     // Invalid state => don't care
                     _ => (),
                 }
@@ -831,7 +1263,10 @@ where
 // System Call implementation
 impl<'a, B, A> kernel::Driver for BLE<'a, B, A>
 where
-    B: ble_advertising_hil::BleAdvertisementDriver + ble_advertising_hil::BleConfig + 'a,
+    B: ble_advertising::BleAdvertisementDriver
+        + ble_advertising::BleConfig
+        + ble_advertising::BleLinkLayerRadio
+        + 'a,
     A: kernel::hil::time::Alarm + 'a,
 {
     fn command(
@@ -906,9 +1341,11 @@ where
             // FIXME: add check that data is a multiple of 0.625
             3 => self.app
                 .enter(appid, |app, _| match app.process_status {
-                    Some(BLEState::Scanning(_)) | Some(BLEState::Advertising(_)) => {
-                        ReturnCode::EBUSY
-                    }
+                    Some(BLEState::Scanning(_))
+                    | Some(BLEState::RequestingScanResponse(_))
+                    | Some(BLEState::Advertising(_))
+                    | Some(BLEState::WaitingForScanRequest(_))
+                    | Some(BLEState::SendingScanResponse(_)) => ReturnCode::EBUSY,
                     _ => {
                         app.advertisement_interval_ms = cmp::max(20, cmp::min(10240, data as u32));
                         ReturnCode::SUCCESS
@@ -923,11 +1360,13 @@ where
                 .enter(appid, |app, _| app.reset_payload())
                 .unwrap_or_else(|err| err.into()),
 
-            // Passive scanning mode
-            5 => self.app
+            // Passive scanning mode (5) or active scanning mode (7)
+            5 | 7 => self.app
                 .enter(appid, |app, _| {
                     if let Some(BLEState::Initialized) = app.process_status {
                         app.process_status = Some(BLEState::ScanningIdle);
+                        app.active_scanning = command_num == 7;
+                        app.reset_reported();
                         app.set_next_alarm::<A::Frequency>(self.alarm.now());
                         self.reset_active_alarm();
                         ReturnCode::SUCCESS
@@ -956,6 +1395,54 @@ where
                 })
                 .unwrap_or_else(|err| err.into()),
 
+            // Filter out duplicate advertisements when scanning
+            //
+            // data - 0 to report every advertisement, or 1 to report each
+            // advertiser only once per scan
+            8 => self.app
+                .enter(appid, |app, _| match app.process_status {
+                    Some(BLEState::Scanning(_)) | Some(BLEState::RequestingScanResponse(_)) => {
+                        ReturnCode::EBUSY
+                    }
+                    _ => {
+                        app.duplicate_filter = data != 0;
+                        app.reset_reported();
+                        ReturnCode::SUCCESS
+                    }
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // Only report advertisements containing an AD type when scanning
+            //
+            // data - the AD type, or 0 to report advertisements of any type
+            9 => self.app
+                .enter(appid, |app, _| match app.process_status {
+                    Some(BLEState::Scanning(_)) | Some(BLEState::RequestingScanResponse(_)) => {
+                        ReturnCode::EBUSY
+                    }
+                    _ if data > 0xff => ReturnCode::EINVAL,
+                    _ => {
+                        app.ad_type_filter = if data != 0 { Some(data as u8) } else { None };
+                        ReturnCode::SUCCESS
+                    }
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // Remove an AD structure from the advertising data
+            //
+            // data - the AD type
+            10 => self.app
+                .enter(appid, |app, _| {
+                    if data > 0xff {
+                        ReturnCode::EINVAL
+                    } else {
+                        app.update_advertising_data(|advertising_data| {
+                            advertising_data.remove(data as u8)
+                        })
+                    }
+                })
+                .unwrap_or_else(|err| err.into()),
+
             _ => ReturnCode::ENOSUPPORT,
         }
     }
@@ -970,9 +1457,7 @@ where
             Some(AllowType::BLEGap(gap_type)) => self.app
                 .enter(appid, |app, _| {
                     if app.process_status != Some(BLEState::NotInitialized) {
-                        app.app_write = Some(slice);
-                        app.set_gap_data(gap_type);
-                        ReturnCode::SUCCESS
+                        app.set_gap_data(gap_type, slice.as_ref())
                     } else {
                         ReturnCode::EINVAL
                     }
@@ -1002,6 +1487,74 @@ where
                     }
                 })
                 .unwrap_or_else(|err| err.into()),
+
+            // An empty buffer disables scan responses
+            Some(AllowType::ScanResponse) => self.app
+                .enter(appid, |app, _| match app.process_status {
+                    Some(BLEState::NotInitialized) => ReturnCode::EINVAL,
+                    Some(BLEState::Advertising(_))
+                    | Some(BLEState::WaitingForScanRequest(_))
+                    | Some(BLEState::SendingScanResponse(_)) => ReturnCode::EBUSY,
+                    _ => match ble_advertising_data::validate(slice.as_ref()) {
+                        Ok(_) => {
+                            app.scan_response_buf =
+                                if slice.len() > 0 { Some(slice) } else { None };
+                            ReturnCode::SUCCESS
+                        }
+                        Err(error) => error,
+                    },
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // A list of 6-byte addresses, an empty buffer disables the filter
+            Some(AllowType::AddressFilter) => self.app
+                .enter(appid, |app, _| match app.process_status {
+                    Some(BLEState::Scanning(_)) | Some(BLEState::RequestingScanResponse(_)) => {
+                        ReturnCode::EBUSY
+                    }
+                    _ if slice.len() % ADDRESS_LENGTH != 0 => ReturnCode::EINVAL,
+                    _ => {
+                        app.address_filter = if slice.len() > 0 { Some(slice) } else { None };
+                        ReturnCode::SUCCESS
+                    }
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // A complete payload of AD structures, which is copied
+            Some(AllowType::AdvertisingData) => self.app
+                .enter(appid, |app, _| {
+                    app.update_advertising_data(|advertising_data| {
+                        advertising_data.set_all(slice.as_ref())
+                    })
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // A single AD structure, which is copied
+            Some(AllowType::AdStructure) => self.app
+                .enter(appid, |app, _| {
+                    let structure = AdStructures::new(slice.as_ref()).next();
+                    match structure {
+                        Some((_, structure)) if structure.encoded_len() == slice.len() => app
+                            .update_advertising_data(|advertising_data| {
+                                advertising_data.set(structure.ad_type, structure.data)
+                            }),
+                        _ => ReturnCode::EINVAL,
+                    }
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // An empty buffer disables the AD index
+            Some(AllowType::AdIndex) => self.app
+                .enter(appid, |app, _| match app.process_status {
+                    Some(BLEState::Scanning(_)) | Some(BLEState::RequestingScanResponse(_)) => {
+                        ReturnCode::EBUSY
+                    }
+                    _ => {
+                        app.ad_index = if slice.len() > 0 { Some(slice) } else { None };
+                        ReturnCode::SUCCESS
+                    }
+                })
+                .unwrap_or_else(|err| err.into()),
             _ => ReturnCode::ENOSUPPORT,
         }
     }
@@ -0,0 +1,1335 @@
+//! Bluetooth Low Energy controller for a host stack, over an HCI UART
+//!
+//! `HciController` makes a board act as a Bluetooth controller for a host
+//! such as BlueZ on Linux. HCI packets are exchanged over a UART with the H4
+//! transport, where each packet is preceded by a byte giving its type. The
+//! controller decodes HCI commands, maps the LE advertising and scanning
+//! commands onto the radio, and answers each command with a Command Complete
+//! event, or a Command Status event for unknown commands. Advertising channel
+//! PDUs received while scanning are reported with LE Advertising Report
+//! events.
+//!
+//! BLUETOOTH SPECIFICATION Version 4.2 [Vol 4, Part A], UART Transport Layer
+//! BLUETOOTH SPECIFICATION Version 4.2 [Vol 2, Part E], Host Controller Interface
+//!
+//! ```
+//!   H4 packet  +-----------+      +-------------------------------------+
+//!              | Type      |  -   | HCI command, ACL data or HCI event  |
+//!              | (1 byte)  |      |                                     |
+//!              +-----------+      +-------------------------------------+
+//! ```
+//!
+//! The controller supports the advertising and scanning states only:
+//! non-connectable and scannable undirected advertising, and passive and
+//! active scanning. Connectable advertising, connections and the white list
+//! are not supported, and the commands that need them fail with Unsupported
+//! Feature or Parameter Value, or Unknown HCI Command. ACL data from the host
+//! is discarded. The controller has no public device address, so the host
+//! sets a random address with LE Set Random Address before advertising or
+//! scanning actively. Scanning is continuous, on an advertising channel that
+//! changes every scan interval, and the RSSI of reports is not available.
+//!
+//! The protocol logic only relies on the radio, UART and alarm HILs, so it
+//! can be tested by exchanging H4 packets with it on top of a mock radio, as
+//! `capsules::test::ble_hci` does.
+//!
+//! Usage
+//! -----
+//!
+//! This is how `boards/nrf52dk` instantiates the controller when it is built
+//! with its `ble_hci` feature:
+//!
+//! ```rust
+//! let hci_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, Rtc>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let hci = static_init!(
+//!     capsules::ble_hci::HciController<
+//!         'static,
+//!         nrf52::radio::Radio,
+//!         nrf52::uart::UARTE,
+//!         VirtualMuxAlarm<'static, Rtc>,
+//!     >,
+//!     capsules::ble_hci::HciController::new(
+//!         &nrf52::radio::RADIO,
+//!         &nrf52::uart::UART0,
+//!         1000000,
+//!         hci_alarm,
+//!         &mut capsules::ble_hci::RX_BUF,
+//!         &mut capsules::ble_hci::EVENT_BUF1,
+//!         &mut capsules::ble_hci::EVENT_BUF2,
+//!         &mut capsules::ble_hci::RADIO_BUF
+//!     )
+//! );
+//! kernel::hil::ble_advertising::BleAdvertisementDriver::set_receive_client(
+//!     &nrf52::radio::RADIO,
+//!     hci,
+//! );
+//! kernel::hil::ble_advertising::BleAdvertisementDriver::set_transmit_client(
+//!     &nrf52::radio::RADIO,
+//!     hci,
+//! );
+//! kernel::hil::uart::UART::set_client(&nrf52::uart::UART0, hci);
+//! hci_alarm.set_client(hci);
+//! hci.start();
+//! ```
+//!
+//! The UART uses hardware flow control, as the H4 transport requires, and can
+//! then be attached on the host with `btattach -B /dev/ttyACM0 -S 1000000`.
+
+use ble_advertising_data::AdvertisingData;
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::take_cell::TakeCell;
+use kernel::hil::ble_advertising;
+use kernel::hil::ble_advertising::{RadioChannel, Turnaround};
+use kernel::hil::time::{self, Alarm, Frequency};
+use kernel::hil::uart::{self, UART};
+use kernel::returncode::ReturnCode;
+
+/// Largest parameters of an HCI command
+const MAX_PARAMETERS_LENGTH: usize = 255;
+
+/// Size of the buffers in which events are queued
+pub const EVENT_BUFFER_LENGTH: usize = 256;
+
+/// Largest advertising channel PDU, header included
+const PACKET_LENGTH: usize = 39;
+
+pub static mut RX_BUF: [u8; MAX_PARAMETERS_LENGTH] = [0; MAX_PARAMETERS_LENGTH];
+pub static mut EVENT_BUF1: [u8; EVENT_BUFFER_LENGTH] = [0; EVENT_BUFFER_LENGTH];
+pub static mut EVENT_BUF2: [u8; EVENT_BUFFER_LENGTH] = [0; EVENT_BUFFER_LENGTH];
+pub static mut RADIO_BUF: [u8; PACKET_LENGTH] = [0; PACKET_LENGTH];
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 4, Part A], section 2 Protocol
+const HCI_COMMAND_PACKET: u8 = 0x01;
+const HCI_ACL_DATA_PACKET: u8 = 0x02;
+const HCI_EVENT_PACKET: u8 = 0x04;
+
+const COMMAND_HEADER_LENGTH: usize = 3;
+const ACL_HEADER_LENGTH: usize = 4;
+const EVENT_HEADER_LENGTH: usize = 3;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 2, Part E], section 7 HCI Commands
+// and Events
+const SET_EVENT_MASK: u16 = 0x0c01;
+const RESET: u16 = 0x0c03;
+const READ_LOCAL_VERSION_INFORMATION: u16 = 0x1001;
+const READ_LOCAL_SUPPORTED_COMMANDS: u16 = 0x1002;
+const READ_LOCAL_SUPPORTED_FEATURES: u16 = 0x1003;
+const READ_BD_ADDR: u16 = 0x1009;
+const LE_SET_EVENT_MASK: u16 = 0x2001;
+const LE_READ_BUFFER_SIZE: u16 = 0x2002;
+const LE_READ_LOCAL_SUPPORTED_FEATURES: u16 = 0x2003;
+const LE_SET_RANDOM_ADDRESS: u16 = 0x2005;
+const LE_SET_ADVERTISING_PARAMETERS: u16 = 0x2006;
+const LE_READ_ADVERTISING_CHANNEL_TX_POWER: u16 = 0x2007;
+const LE_SET_ADVERTISING_DATA: u16 = 0x2008;
+const LE_SET_SCAN_RESPONSE_DATA: u16 = 0x2009;
+const LE_SET_ADVERTISE_ENABLE: u16 = 0x200a;
+const LE_SET_SCAN_PARAMETERS: u16 = 0x200b;
+const LE_SET_SCAN_ENABLE: u16 = 0x200c;
+const LE_READ_WHITE_LIST_SIZE: u16 = 0x200f;
+const LE_CLEAR_WHITE_LIST: u16 = 0x2010;
+const LE_READ_SUPPORTED_STATES: u16 = 0x201c;
+
+const COMMAND_COMPLETE_EVENT: u8 = 0x0e;
+const COMMAND_STATUS_EVENT: u8 = 0x0f;
+const LE_META_EVENT: u8 = 0x3e;
+const LE_ADVERTISING_REPORT_EVENT: u8 = 0x02;
+
+// The host may only send a command once the previous one has been answered
+const NUM_HCI_COMMAND_PACKETS: u8 = 1;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 2, Part D], section 1.3 List of Error Codes
+const SUCCESS: u8 = 0x00;
+const UNKNOWN_HCI_COMMAND: u8 = 0x01;
+const COMMAND_DISALLOWED: u8 = 0x0c;
+const UNSUPPORTED_FEATURE_OR_PARAMETER_VALUE: u8 = 0x11;
+const INVALID_HCI_COMMAND_PARAMETERS: u8 = 0x12;
+
+// Bluetooth Core Specification 4.1, and no assigned company identifier, as
+// for the link layer
+const HCI_VERSION: u8 = 0x07;
+const COMPANY_ID: u16 = 0xffff;
+
+// Set Event Mask and Reset; Read Local Version Information and Read Local
+// Supported Features; Read BD_ADDR; the LE commands up to LE Set Advertising
+// Data; LE Set Scan Response Data to LE Set Scan Enable, LE Read White List
+// Size and LE Clear White List; LE Read Supported States
+const SUPPORTED_COMMANDS_LENGTH: usize = 64;
+const SUPPORTED_COMMANDS: [(usize, u8); 6] = [
+    (5, 0xc0),
+    (14, 0x28),
+    (15, 0x02),
+    (25, 0xf7),
+    (26, 0xcf),
+    (28, 0x08),
+];
+
+// BR/EDR Not Supported and LE Supported (Controller)
+const LMP_FEATURES: [u8; 8] = [0, 0, 0, 0, 0x60, 0, 0, 0];
+
+// Non-connectable and scannable advertising, passive and active scanning, and
+// their combinations
+const LE_STATES: [u8; 8] = [0x33, 0x33, 0, 0, 0, 0, 0, 0];
+
+const DEFAULT_EVENT_MASK: u64 = 0x0000_1fff_ffff_ffff;
+const DEFAULT_LE_EVENT_MASK: u64 = 0x1f;
+const LE_META_EVENT_MASK: u64 = 1 << 61;
+const LE_ADVERTISING_REPORT_MASK: u64 = 1 << 1;
+
+// Payloads of LE data packets the host may send, which are discarded
+const LE_ACL_DATA_PACKET_LENGTH: u16 = 27;
+const TOTAL_NUM_LE_ACL_DATA_PACKETS: u8 = 1;
+
+const TX_POWER_DBM: u8 = 0;
+const RSSI_NOT_AVAILABLE: u8 = 127;
+
+// Advertising_Type of LE Set Advertising Parameters
+const ADV_IND_TYPE: u8 = 0x00;
+const ADV_SCAN_IND_TYPE: u8 = 0x02;
+const ADV_NONCONN_IND_TYPE: u8 = 0x03;
+const ADV_DIRECT_IND_LOW_DUTY_CYCLE_TYPE: u8 = 0x04;
+
+// Event_Type of LE Advertising Report
+const SCAN_RSP_EVENT_TYPE: u8 = 0x04;
+
+const RANDOM_ADDRESS_TYPE: u8 = 0x01;
+
+// Ranges of the advertising and scanning parameters, in units of 0.625 ms
+const MIN_NON_CONNECTABLE_ADVERTISING_INTERVAL: u16 = 0x00a0;
+const MAX_ADVERTISING_INTERVAL: u16 = 0x4000;
+const MIN_SCAN_INTERVAL: u16 = 0x0004;
+const MAX_SCAN_INTERVAL: u16 = 0x4000;
+const INTERVAL_UNIT_US: u64 = 625;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.3 Advertising Channel PDU
+const ADV_IND: u8 = 0x00;
+const ADV_DIRECT_IND: u8 = 0x01;
+const ADV_NONCONN_IND: u8 = 0x02;
+const SCAN_REQ: u8 = 0x03;
+const SCAN_RSP: u8 = 0x04;
+const ADV_SCAN_IND: u8 = 0x06;
+const PDU_TYPE_MASK: u8 = 0x0f;
+const TX_ADD: u8 = 1 << 6;
+const RX_ADD: u8 = 1 << 7;
+const PDU_LENGTH_MASK: u8 = 0x3f;
+const ADDRESS_LENGTH: usize = 6;
+const PAYLOAD_START: usize = 2 + ADDRESS_LENGTH;
+
+// How long to listen for a SCAN_REQ after an advertisement, or for a SCAN_RSP
+// after a SCAN_REQ
+const RESPONSE_TIMEOUT_US: u32 = 1000;
+// The pseudo-random advDelay added to the advertising interval is up to 10 ms
+const MAX_ADVERTISING_DELAY_MS: u32 = 10;
+// Alarms are set at least this far in the future, so that they do fire
+const MIN_ALARM_TICS: u32 = 2;
+
+// Number of advertisers remembered when filtering out duplicate reports
+const DUPLICATE_FILTER_SIZE: usize = 8;
+
+// The largest event sent in answer to a command is a Command Complete event
+// with the bitmask of Read Local Supported Commands, and the largest report
+// is an LE Advertising Report event of 31 bytes of data
+const MAX_RESPONSE_LENGTH: usize = EVENT_HEADER_LENGTH + 4 + SUPPORTED_COMMANDS_LENGTH;
+const MAX_REPORT_PARAMETERS_LENGTH: usize = 12 + 31;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum RxState {
+    PacketType,
+    CommandHeader,
+    CommandParameters,
+    AclHeader,
+    // Discarding the data of an ACL data packet, with the number of bytes left
+    AclData(usize),
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum RadioState {
+    Idle,
+    Advertising(RadioChannel),
+    // Listening for a SCAN_REQ after a scannable advertisement
+    WaitingForScanRequest(RadioChannel),
+    SendingScanResponse(RadioChannel),
+    Scanning(RadioChannel),
+    // Sent a SCAN_REQ and listening for the SCAN_RSP
+    RequestingScanResponse(RadioChannel),
+}
+
+#[derive(Copy, Clone)]
+struct AdvertisingParameters {
+    /// Interval in units of 0.625 ms
+    interval: u16,
+    advertising_type: u8,
+    own_address_type: u8,
+    channel_map: u8,
+}
+
+impl Default for AdvertisingParameters {
+    fn default() -> AdvertisingParameters {
+        AdvertisingParameters {
+            interval: 0x0800,
+            advertising_type: ADV_IND_TYPE,
+            own_address_type: 0,
+            channel_map: 0x07,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct ScanParameters {
+    active: bool,
+    /// Interval in units of 0.625 ms
+    interval: u16,
+    own_address_type: u8,
+}
+
+impl Default for ScanParameters {
+    fn default() -> ScanParameters {
+        ScanParameters {
+            active: false,
+            interval: 0x0010,
+            own_address_type: 0,
+        }
+    }
+}
+
+// Address type followed by the address of a device
+type Device = [u8; 1 + ADDRESS_LENGTH];
+
+fn device(address_type: u8, address: &[u8]) -> Device {
+    let mut device = [address_type; 1 + ADDRESS_LENGTH];
+    device[1..].copy_from_slice(address);
+    device
+}
+
+fn decode_u16(buf: &[u8]) -> u16 {
+    buf[0] as u16 | (buf[1] as u16) << 8
+}
+
+fn decode_u64(buf: &[u8]) -> u64 {
+    buf[..8]
+        .iter()
+        .rev()
+        .fold(0, |value, &byte| value << 8 | byte as u64)
+}
+
+// Whether the time of the alarm `time` is not in the future
+fn expired(time: u32, now: u32) -> bool {
+    now.wrapping_sub(time) as i32 >= 0
+}
+
+// The first channel of the channel map after `after`, or the first one
+fn advertising_channel(channel_map: u8, after: Option<RadioChannel>) -> Option<RadioChannel> {
+    let channels = [
+        RadioChannel::AdvertisingChannel37,
+        RadioChannel::AdvertisingChannel38,
+        RadioChannel::AdvertisingChannel39,
+    ];
+    let start = after.map_or(0, |channel| channel.get_channel_index() as usize - 36);
+    (start..channels.len())
+        .find(|&i| channel_map & (1 << i) != 0)
+        .map(|i| channels[i])
+}
+
+// Writes an advertising channel PDU and returns its length
+fn write_pdu(buf: &mut [u8], header: u8, address: &[u8], payload: &[u8]) -> usize {
+    buf[0] = header;
+    buf[1] = (ADDRESS_LENGTH + payload.len()) as u8;
+    buf[2..PAYLOAD_START].copy_from_slice(address);
+    buf[PAYLOAD_START..PAYLOAD_START + payload.len()].copy_from_slice(payload);
+    PAYLOAD_START + payload.len()
+}
+
+pub struct HciController<'a, R, U, A>
+where
+    R: ble_advertising::BleAdvertisementDriver
+        + ble_advertising::BleConfig
+        + ble_advertising::BleLinkLayerRadio
+        + 'a,
+    U: UART + 'a,
+    A: Alarm + 'a,
+{
+    radio: &'a R,
+    uart: &'a U,
+    baud_rate: u32,
+    alarm: &'a A,
+
+    rx_state: Cell<RxState>,
+    rx_buf: TakeCell<'static, [u8]>,
+    // Opcode of the command whose parameters are being received
+    opcode: Cell<u16>,
+    // The buffer in which events are queued, and the buffer being written to
+    // the UART, which is absent during a write
+    queue: TakeCell<'static, [u8]>,
+    queue_len: Cell<usize>,
+    spare: TakeCell<'static, [u8]>,
+
+    event_mask: Cell<u64>,
+    le_event_mask: Cell<u64>,
+    random_address: Cell<Option<[u8; ADDRESS_LENGTH]>>,
+    advertising_enabled: Cell<bool>,
+    advertising_parameters: Cell<AdvertisingParameters>,
+    advertising_data: Cell<AdvertisingData>,
+    scan_response_data: Cell<AdvertisingData>,
+    scanning_enabled: Cell<bool>,
+    scan_parameters: Cell<ScanParameters>,
+    filter_duplicates: Cell<bool>,
+    /// Event types and devices already reported, when duplicates are filtered
+    /// out
+    reported: Cell<[Option<(u8, Device)>; DUPLICATE_FILTER_SIZE]>,
+    reported_idx: Cell<usize>,
+
+    radio_buf: TakeCell<'static, [u8]>,
+    radio_state: Cell<RadioState>,
+    scan_channel: Cell<RadioChannel>,
+    // The advertiser a SCAN_REQ was sent to
+    requested: Cell<Device>,
+    // When the next advertising event starts, when scanning moves to the next
+    // channel, and when to stop waiting for a SCAN_REQ or a SCAN_RSP
+    next_advertising_event: Cell<Option<u32>>,
+    next_scan_channel: Cell<Option<u32>>,
+    response_timeout: Cell<Option<u32>>,
+    random_nonce: Cell<u32>,
+}
+
+impl<'a, R, U, A> HciController<'a, R, U, A>
+where
+    R: ble_advertising::BleAdvertisementDriver
+        + ble_advertising::BleConfig
+        + ble_advertising::BleLinkLayerRadio
+        + 'a,
+    U: UART + 'a,
+    A: Alarm + 'a,
+{
+    pub fn new(
+        radio: &'a R,
+        uart: &'a U,
+        baud_rate: u32,
+        alarm: &'a A,
+        rx_buf: &'static mut [u8],
+        event_buf1: &'static mut [u8],
+        event_buf2: &'static mut [u8],
+        radio_buf: &'static mut [u8],
+    ) -> HciController<'a, R, U, A> {
+        HciController {
+            radio: radio,
+            uart: uart,
+            baud_rate: baud_rate,
+            alarm: alarm,
+            rx_state: Cell::new(RxState::PacketType),
+            rx_buf: TakeCell::new(rx_buf),
+            opcode: Cell::new(0),
+            queue: TakeCell::new(event_buf1),
+            queue_len: Cell::new(0),
+            spare: TakeCell::new(event_buf2),
+            event_mask: Cell::new(DEFAULT_EVENT_MASK),
+            le_event_mask: Cell::new(DEFAULT_LE_EVENT_MASK),
+            random_address: Cell::new(None),
+            advertising_enabled: Cell::new(false),
+            advertising_parameters: Cell::new(AdvertisingParameters::default()),
+            advertising_data: Cell::new(AdvertisingData::new()),
+            scan_response_data: Cell::new(AdvertisingData::new()),
+            scanning_enabled: Cell::new(false),
+            scan_parameters: Cell::new(ScanParameters::default()),
+            filter_duplicates: Cell::new(false),
+            reported: Cell::new([None; DUPLICATE_FILTER_SIZE]),
+            reported_idx: Cell::new(0),
+            radio_buf: TakeCell::new(radio_buf),
+            radio_state: Cell::new(RadioState::Idle),
+            scan_channel: Cell::new(RadioChannel::AdvertisingChannel37),
+            requested: Cell::new([0; 1 + ADDRESS_LENGTH]),
+            next_advertising_event: Cell::new(None),
+            next_scan_channel: Cell::new(None),
+            response_timeout: Cell::new(None),
+            // Just use any non-zero starting value by default
+            random_nonce: Cell::new(0xdeadbeef),
+        }
+    }
+
+    /// Initializes the UART and the radio, and starts receiving HCI packets
+    pub fn start(&self) {
+        self.uart.init(uart::UARTParams {
+            baud_rate: self.baud_rate,
+            stop_bits: uart::StopBits::One,
+            parity: uart::Parity::None,
+            hw_flow_control: true,
+        });
+        self.radio.set_access_address(
+            ble_advertising::ADVERTISING_ACCESS_ADDRESS,
+            ble_advertising::ADVERTISING_CRC_INIT,
+        );
+        self.radio.set_tx_power(TX_POWER_DBM);
+        self.receive(RxState::PacketType, 1);
+    }
+
+    fn receive(&self, state: RxState, len: usize) {
+        self.rx_state.set(state);
+        self.rx_buf.take().map(|buf| self.uart.receive(buf, len));
+    }
+
+    // The state in which to discard the rest of an ACL data packet of `len`
+    // bytes, and how much of it to receive next into a buffer of `buf_len`
+    fn discard(len: usize, buf_len: usize) -> (RxState, usize) {
+        if len == 0 {
+            (RxState::PacketType, 1)
+        } else {
+            (RxState::AclData(len), cmp::min(len, buf_len))
+        }
+    }
+
+    /// Queues an event to be written to the UART. Unless the event answers a
+    /// command, it is dropped if there would not be room left for an answer
+    /// after it, so that no answer is ever dropped: the host waits for the
+    /// answer to a command before sending the next one.
+    fn send_event(&self, code: u8, parameters: &[u8], answer: bool) -> bool {
+        let len = EVENT_HEADER_LENGTH + parameters.len();
+        let reserved = if answer { 0 } else { MAX_RESPONSE_LENGTH };
+        let queued = self.queue.map_or(false, |queue| {
+            let start = self.queue_len.get();
+            if start + len + reserved > queue.len() {
+                return false;
+            }
+            queue[start] = HCI_EVENT_PACKET;
+            queue[start + 1] = code;
+            queue[start + 2] = parameters.len() as u8;
+            queue[start + EVENT_HEADER_LENGTH..start + len].copy_from_slice(parameters);
+            self.queue_len.set(start + len);
+            true
+        });
+        if queued {
+            self.flush();
+        }
+        queued
+    }
+
+    /// Writes the queued events to the UART unless it is busy
+    fn flush(&self) {
+        if self.queue_len.get() == 0 || self.spare.is_none() {
+            return;
+        }
+        let next_queue = self.spare.take();
+        self.queue.take().map(|queue| {
+            self.uart.transmit(queue, self.queue_len.get());
+        });
+        self.queue_len.set(0);
+        next_queue.map(|next_queue| self.queue.replace(next_queue));
+    }
+
+    fn command_complete(&self, opcode: u16, status: u8, return_parameters: &[u8]) {
+        let mut parameters = [0; MAX_RESPONSE_LENGTH - EVENT_HEADER_LENGTH];
+        let len = 4 + return_parameters.len();
+        parameters[0] = NUM_HCI_COMMAND_PACKETS;
+        parameters[1] = opcode as u8;
+        parameters[2] = (opcode >> 8) as u8;
+        parameters[3] = status;
+        parameters[4..len].copy_from_slice(return_parameters);
+        self.send_event(COMMAND_COMPLETE_EVENT, &parameters[..len], true);
+    }
+
+    fn command_status(&self, opcode: u16, status: u8) {
+        let parameters = [
+            status,
+            NUM_HCI_COMMAND_PACKETS,
+            opcode as u8,
+            (opcode >> 8) as u8,
+        ];
+        self.send_event(COMMAND_STATUS_EVENT, &parameters, true);
+    }
+
+    // Length of the parameters of the supported commands
+    fn parameters_length(opcode: u16) -> Option<usize> {
+        match opcode {
+            SET_EVENT_MASK | LE_SET_EVENT_MASK => Some(8),
+            RESET
+            | READ_LOCAL_VERSION_INFORMATION
+            | READ_LOCAL_SUPPORTED_COMMANDS
+            | READ_LOCAL_SUPPORTED_FEATURES
+            | READ_BD_ADDR
+            | LE_READ_BUFFER_SIZE
+            | LE_READ_LOCAL_SUPPORTED_FEATURES
+            | LE_READ_ADVERTISING_CHANNEL_TX_POWER
+            | LE_READ_WHITE_LIST_SIZE
+            | LE_CLEAR_WHITE_LIST
+            | LE_READ_SUPPORTED_STATES => Some(0),
+            LE_SET_RANDOM_ADDRESS => Some(ADDRESS_LENGTH),
+            LE_SET_ADVERTISING_PARAMETERS => Some(15),
+            LE_SET_ADVERTISING_DATA | LE_SET_SCAN_RESPONSE_DATA => Some(32),
+            LE_SET_ADVERTISE_ENABLE => Some(1),
+            LE_SET_SCAN_PARAMETERS => Some(7),
+            LE_SET_SCAN_ENABLE => Some(2),
+            _ => None,
+        }
+    }
+
+    // Executes the command whose parameters have been received and answers it
+    fn execute_command(&self, parameters: &[u8]) {
+        let opcode = self.opcode.get();
+        let mut return_parameters = [0; SUPPORTED_COMMANDS_LENGTH];
+        let result = match Self::parameters_length(opcode) {
+            None => {
+                self.command_status(opcode, UNKNOWN_HCI_COMMAND);
+                return;
+            }
+            Some(len) if len != parameters.len() => Err(INVALID_HCI_COMMAND_PARAMETERS),
+            Some(_) => self.execute(opcode, parameters, &mut return_parameters),
+        };
+        match result {
+            Ok(len) => self.command_complete(opcode, SUCCESS, &return_parameters[..len]),
+            Err(status) => self.command_complete(opcode, status, &[]),
+        }
+    }
+
+    // Executes a supported command with parameters of the right length.
+    // Returns the length of the return parameters written to `ret`, not
+    // counting the status, or the error status.
+    fn execute(&self, opcode: u16, p: &[u8], ret: &mut [u8]) -> Result<usize, u8> {
+        match opcode {
+            SET_EVENT_MASK => {
+                self.event_mask.set(decode_u64(p));
+                Ok(0)
+            }
+            RESET => {
+                self.reset();
+                Ok(0)
+            }
+            READ_LOCAL_VERSION_INFORMATION => {
+                ret[..8].copy_from_slice(&[
+                    HCI_VERSION,
+                    0,
+                    0,
+                    HCI_VERSION,
+                    COMPANY_ID as u8,
+                    (COMPANY_ID >> 8) as u8,
+                    0,
+                    0,
+                ]);
+                Ok(8)
+            }
+            READ_LOCAL_SUPPORTED_COMMANDS => {
+                for byte in ret[..SUPPORTED_COMMANDS_LENGTH].iter_mut() {
+                    *byte = 0;
+                }
+                for &(octet, bits) in SUPPORTED_COMMANDS.iter() {
+                    ret[octet] |= bits;
+                }
+                Ok(SUPPORTED_COMMANDS_LENGTH)
+            }
+            READ_LOCAL_SUPPORTED_FEATURES => {
+                ret[..8].copy_from_slice(&LMP_FEATURES);
+                Ok(8)
+            }
+            READ_BD_ADDR => {
+                // There is no public device address
+                ret[..ADDRESS_LENGTH].copy_from_slice(&[0; ADDRESS_LENGTH]);
+                Ok(ADDRESS_LENGTH)
+            }
+            LE_SET_EVENT_MASK => {
+                self.le_event_mask.set(decode_u64(p));
+                Ok(0)
+            }
+            LE_READ_BUFFER_SIZE => {
+                ret[0] = LE_ACL_DATA_PACKET_LENGTH as u8;
+                ret[1] = (LE_ACL_DATA_PACKET_LENGTH >> 8) as u8;
+                ret[2] = TOTAL_NUM_LE_ACL_DATA_PACKETS;
+                Ok(3)
+            }
+            LE_READ_LOCAL_SUPPORTED_FEATURES => {
+                ret[..8].copy_from_slice(&[0; 8]);
+                Ok(8)
+            }
+            LE_SET_RANDOM_ADDRESS => {
+                if self.advertising_enabled.get() || self.scanning_enabled.get() {
+                    return Err(COMMAND_DISALLOWED);
+                }
+                let mut address = [0; ADDRESS_LENGTH];
+                address.copy_from_slice(p);
+                self.random_address.set(Some(address));
+                Ok(0)
+            }
+            LE_SET_ADVERTISING_PARAMETERS => self.set_advertising_parameters(p),
+            LE_READ_ADVERTISING_CHANNEL_TX_POWER => {
+                ret[0] = TX_POWER_DBM;
+                Ok(1)
+            }
+            LE_SET_ADVERTISING_DATA => Self::set_data(&self.advertising_data, p),
+            LE_SET_SCAN_RESPONSE_DATA => Self::set_data(&self.scan_response_data, p),
+            LE_SET_ADVERTISE_ENABLE => self.set_advertise_enable(p[0]),
+            LE_SET_SCAN_PARAMETERS => self.set_scan_parameters(p),
+            LE_SET_SCAN_ENABLE => self.set_scan_enable(p[0], p[1]),
+            LE_READ_WHITE_LIST_SIZE => {
+                ret[0] = 0;
+                Ok(1)
+            }
+            LE_CLEAR_WHITE_LIST => Ok(0),
+            LE_READ_SUPPORTED_STATES => {
+                ret[..8].copy_from_slice(&LE_STATES);
+                Ok(8)
+            }
+            _ => Err(UNKNOWN_HCI_COMMAND),
+        }
+    }
+
+    // Stops advertising and scanning and restores the default parameters
+    fn reset(&self) {
+        self.radio.disable();
+        self.radio_state.set(RadioState::Idle);
+        self.next_advertising_event.set(None);
+        self.next_scan_channel.set(None);
+        self.response_timeout.set(None);
+        self.alarm.disable();
+
+        self.event_mask.set(DEFAULT_EVENT_MASK);
+        self.le_event_mask.set(DEFAULT_LE_EVENT_MASK);
+        self.random_address.set(None);
+        self.advertising_enabled.set(false);
+        self.advertising_parameters
+            .set(AdvertisingParameters::default());
+        self.advertising_data.set(AdvertisingData::new());
+        self.scan_response_data.set(AdvertisingData::new());
+        self.scanning_enabled.set(false);
+        self.scan_parameters.set(ScanParameters::default());
+        self.filter_duplicates.set(false);
+    }
+
+    // BLUETOOTH SPECIFICATION Version 4.2 [Vol 2, Part E], section 7.8.5
+    fn set_advertising_parameters(&self, p: &[u8]) -> Result<usize, u8> {
+        if self.advertising_enabled.get() {
+            return Err(COMMAND_DISALLOWED);
+        }
+        let interval_min = decode_u16(&p[0..2]);
+        let interval_max = decode_u16(&p[2..4]);
+        let advertising_type = p[4];
+        let own_address_type = p[5];
+        let channel_map = p[13];
+        let filter_policy = p[14];
+
+        if advertising_type > ADV_DIRECT_IND_LOW_DUTY_CYCLE_TYPE
+            || interval_min < MIN_NON_CONNECTABLE_ADVERTISING_INTERVAL
+            || interval_max > MAX_ADVERTISING_INTERVAL
+            || interval_min > interval_max
+            || own_address_type > 0x03
+            || channel_map == 0
+            || channel_map > 0x07
+            || filter_policy > 0x03
+        {
+            return Err(INVALID_HCI_COMMAND_PARAMETERS);
+        }
+        // Connectable advertising, private addresses and the white list are
+        // not supported
+        if (advertising_type != ADV_SCAN_IND_TYPE && advertising_type != ADV_NONCONN_IND_TYPE)
+            || own_address_type > RANDOM_ADDRESS_TYPE
+            || filter_policy != 0
+        {
+            return Err(UNSUPPORTED_FEATURE_OR_PARAMETER_VALUE);
+        }
+
+        self.advertising_parameters.set(AdvertisingParameters {
+            interval: interval_min,
+            advertising_type: advertising_type,
+            own_address_type: own_address_type,
+            channel_map: channel_map,
+        });
+        Ok(0)
+    }
+
+    // LE Set Advertising Data and LE Set Scan Response Data, whose AD
+    // structures must be valid
+    fn set_data(data: &Cell<AdvertisingData>, p: &[u8]) -> Result<usize, u8> {
+        let len = p[0] as usize;
+        let mut new_data = AdvertisingData::new();
+        if len >= p.len() || new_data.set_all(&p[1..1 + len]) != ReturnCode::SUCCESS {
+            return Err(INVALID_HCI_COMMAND_PARAMETERS);
+        }
+        data.set(new_data);
+        Ok(0)
+    }
+
+    // The address used for advertising or scanning with the own address type,
+    // which is only available once the host has set a random address
+    fn own_address(&self, own_address_type: u8) -> Option<[u8; ADDRESS_LENGTH]> {
+        if own_address_type == RANDOM_ADDRESS_TYPE {
+            self.random_address.get()
+        } else {
+            None
+        }
+    }
+
+    fn set_advertise_enable(&self, enable: u8) -> Result<usize, u8> {
+        match enable {
+            0 => {
+                // An ongoing advertising event is completed
+                self.advertising_enabled.set(false);
+                self.next_advertising_event.set(None);
+                self.reset_alarm();
+                Ok(0)
+            }
+            1 => {
+                let parameters = self.advertising_parameters.get();
+                if parameters.advertising_type != ADV_SCAN_IND_TYPE
+                    && parameters.advertising_type != ADV_NONCONN_IND_TYPE
+                {
+                    return Err(UNSUPPORTED_FEATURE_OR_PARAMETER_VALUE);
+                }
+                if self.own_address(parameters.own_address_type).is_none() {
+                    return Err(INVALID_HCI_COMMAND_PARAMETERS);
+                }
+                if !self.advertising_enabled.get() {
+                    self.advertising_enabled.set(true);
+                    self.next_advertising_event.set(Some(self.alarm.now()));
+                    self.advertise_if_due();
+                    self.reset_alarm();
+                }
+                Ok(0)
+            }
+            _ => Err(INVALID_HCI_COMMAND_PARAMETERS),
+        }
+    }
+
+    // BLUETOOTH SPECIFICATION Version 4.2 [Vol 2, Part E], section 7.8.10
+    fn set_scan_parameters(&self, p: &[u8]) -> Result<usize, u8> {
+        if self.scanning_enabled.get() {
+            return Err(COMMAND_DISALLOWED);
+        }
+        let scan_type = p[0];
+        let interval = decode_u16(&p[1..3]);
+        let window = decode_u16(&p[3..5]);
+        let own_address_type = p[5];
+        let filter_policy = p[6];
+
+        if scan_type > 0x01
+            || interval < MIN_SCAN_INTERVAL
+            || interval > MAX_SCAN_INTERVAL
+            || window < MIN_SCAN_INTERVAL
+            || window > interval
+            || own_address_type > 0x03
+            || filter_policy > 0x03
+        {
+            return Err(INVALID_HCI_COMMAND_PARAMETERS);
+        }
+        if own_address_type > RANDOM_ADDRESS_TYPE || filter_policy != 0 {
+            return Err(UNSUPPORTED_FEATURE_OR_PARAMETER_VALUE);
+        }
+
+        // The radio scans continuously, which covers any scan window
+        self.scan_parameters.set(ScanParameters {
+            active: scan_type == 0x01,
+            interval: interval,
+            own_address_type: own_address_type,
+        });
+        Ok(0)
+    }
+
+    fn set_scan_enable(&self, enable: u8, filter_duplicates: u8) -> Result<usize, u8> {
+        match (enable, filter_duplicates) {
+            (0, 0...1) => {
+                self.scanning_enabled.set(false);
+                self.next_scan_channel.set(None);
+                if let RadioState::Scanning(_) = self.radio_state.get() {
+                    self.radio.disable();
+                    self.resume();
+                }
+                self.reset_alarm();
+                Ok(0)
+            }
+            (1, 0...1) => {
+                let parameters = self.scan_parameters.get();
+                // A SCAN_REQ carries the address of the scanner
+                if parameters.active && self.own_address(parameters.own_address_type).is_none() {
+                    return Err(INVALID_HCI_COMMAND_PARAMETERS);
+                }
+                self.filter_duplicates.set(filter_duplicates == 1);
+                self.reported.set([None; DUPLICATE_FILTER_SIZE]);
+                self.reported_idx.set(0);
+                if !self.scanning_enabled.get() {
+                    self.scanning_enabled.set(true);
+                    self.scan_channel.set(RadioChannel::AdvertisingChannel37);
+                    let now = self.alarm.now();
+                    let interval = self.interval_tics(parameters.interval);
+                    self.next_scan_channel.set(Some(now.wrapping_add(interval)));
+                    if self.radio_state.get() == RadioState::Idle {
+                        self.resume();
+                    }
+                    self.reset_alarm();
+                }
+                Ok(0)
+            }
+            _ => Err(INVALID_HCI_COMMAND_PARAMETERS),
+        }
+    }
+
+    fn tics(&self, us: u32) -> u32 {
+        (us as u64 * <A::Frequency>::frequency() as u64 / 1_000_000) as u32
+    }
+
+    // Tics of an advertising or scanning interval in units of 0.625 ms
+    fn interval_tics(&self, interval: u16) -> u32 {
+        (interval as u64 * INTERVAL_UNIT_US * <A::Frequency>::frequency() as u64 / 1_000_000) as u32
+    }
+
+    // Returns a new pseudo-random number for advDelay, using the
+    // [Xorshift](https://en.wikipedia.org/wiki/Xorshift) algorithm
+    fn random_nonce(&self) -> u32 {
+        let mut next_nonce = ::core::num::Wrapping(self.random_nonce.get());
+        next_nonce ^= next_nonce << 13;
+        next_nonce ^= next_nonce >> 17;
+        next_nonce ^= next_nonce << 5;
+        self.random_nonce.set(next_nonce.0);
+        next_nonce.0
+    }
+
+    // Sets the alarm to the earliest of the times the controller waits for,
+    // or disables it
+    fn reset_alarm(&self) {
+        let now = self.alarm.now();
+        let times = [
+            self.next_advertising_event.get(),
+            self.next_scan_channel.get(),
+            self.response_timeout.get(),
+        ];
+        let next = times
+            .iter()
+            .filter_map(|time| *time)
+            .min_by_key(|time| time.wrapping_sub(now) as i32);
+        match next {
+            Some(time) => {
+                let delay = cmp::max(time.wrapping_sub(now) as i32, MIN_ALARM_TICS as i32);
+                self.alarm.set_alarm(now.wrapping_add(delay as u32));
+            }
+            None => self.alarm.disable(),
+        }
+    }
+
+    // Starts what the radio does once an operation is over: an advertising
+    // event if one is due, or else scanning
+    fn resume(&self) {
+        let now = self.alarm.now();
+        let advertising_due = self.advertising_enabled.get()
+            && self.next_advertising_event
+                .get()
+                .map_or(false, |time| expired(time, now));
+        let first_channel =
+            advertising_channel(self.advertising_parameters.get().channel_map, None);
+        match first_channel {
+            Some(channel) if advertising_due => {
+                self.next_advertising_event.set(None);
+                self.advertise(channel);
+            }
+            _ => {
+                if self.scanning_enabled.get() {
+                    self.scan(self.scan_channel.get());
+                } else {
+                    self.radio_state.set(RadioState::Idle);
+                }
+            }
+        }
+    }
+
+    // Interrupts scanning, or leaves the radio idle, for an advertising event
+    // that is due
+    fn advertise_if_due(&self) {
+        match self.radio_state.get() {
+            RadioState::Idle => self.resume(),
+            RadioState::Scanning(_) => {
+                let now = self.alarm.now();
+                let due = self.next_advertising_event
+                    .get()
+                    .map_or(false, |time| expired(time, now));
+                if due {
+                    self.radio.disable();
+                    self.resume();
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // Sends the advertisement on a channel of the advertising event.
+    //
+    // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 4.4.2
+    fn advertise(&self, channel: RadioChannel) {
+        let parameters = self.advertising_parameters.get();
+        let scannable = parameters.advertising_type == ADV_SCAN_IND_TYPE;
+        let address = self.own_address(parameters.own_address_type)
+            .unwrap_or([0; ADDRESS_LENGTH]);
+        let data = self.advertising_data.get();
+        self.radio_state.set(RadioState::Advertising(channel));
+        self.radio_buf.take().map(|buf| {
+            // The only address of the controller is a random one
+            let pdu_type = if scannable {
+                ADV_SCAN_IND
+            } else {
+                ADV_NONCONN_IND
+            };
+            let header = pdu_type | TX_ADD;
+            let len = write_pdu(buf, header, &address, data.as_slice());
+            if scannable {
+                // Listen for SCAN_REQs right after the advertisement
+                self.radio.set_turnaround(Turnaround::Receive);
+            }
+            let buf = self.radio.transmit_advertisement(buf, len, channel);
+            self.radio_buf.replace(buf);
+        });
+    }
+
+    // Continues the advertising event on the next channel, or ends it
+    fn advertise_next(&self, channel: RadioChannel) {
+        let channel_map = self.advertising_parameters.get().channel_map;
+        let next = advertising_channel(channel_map, Some(channel));
+        match next {
+            Some(next) if self.advertising_enabled.get() => self.advertise(next),
+            _ => {
+                if self.advertising_enabled.get() {
+                    let now = self.alarm.now();
+                    let interval = self.interval_tics(self.advertising_parameters.get().interval);
+                    let delay = self.random_nonce() % (MAX_ADVERTISING_DELAY_MS + 1);
+                    let delay = self.tics(delay * 1000);
+                    self.next_advertising_event
+                        .set(Some(now.wrapping_add(interval + delay)));
+                }
+                self.resume();
+            }
+        }
+    }
+
+    fn scan(&self, channel: RadioChannel) {
+        self.radio_state.set(RadioState::Scanning(channel));
+        if self.scan_parameters.get().active {
+            // Be ready to answer scannable advertisements with a SCAN_REQ
+            self.radio.set_turnaround(Turnaround::Transmit);
+        }
+        self.radio.receive_advertisement(channel);
+    }
+
+    // Reports an advertising channel PDU received while scanning. Returns
+    // whether a SCAN_REQ is sent in response.
+    fn receive_scanned_pdu(&self, pdu: &[u8]) -> bool {
+        let pdu_type = pdu[0] & PDU_TYPE_MASK;
+        let address_type = (pdu[0] & TX_ADD != 0) as u8;
+        let advertiser = device(address_type, &pdu[2..PAYLOAD_START]);
+        let own_address = self.own_address(self.scan_parameters.get().own_address_type);
+
+        // BLUETOOTH SPECIFICATION Version 4.2 [Vol 2, Part E], section 7.7.65.2
+        let (event_type, data) = match pdu_type {
+            ADV_IND => (0x00, &pdu[PAYLOAD_START..]),
+            ADV_DIRECT_IND => {
+                // Only directed advertisements to the controller are reported
+                let to_us = pdu.len() == PAYLOAD_START + ADDRESS_LENGTH
+                    && pdu[0] & RX_ADD != 0
+                    && own_address.map_or(false, |own_address| own_address == pdu[PAYLOAD_START..]);
+                if !to_us {
+                    return false;
+                }
+                (0x01, &[][..])
+            }
+            ADV_SCAN_IND => (0x02, &pdu[PAYLOAD_START..]),
+            ADV_NONCONN_IND => (0x03, &pdu[PAYLOAD_START..]),
+            _ => return false,
+        };
+        self.report(event_type, advertiser, data);
+
+        let scannable = pdu_type == ADV_IND || pdu_type == ADV_SCAN_IND;
+        if !self.scan_parameters.get().active
+            || !scannable
+            || self.is_duplicate(SCAN_RSP_EVENT_TYPE, advertiser)
+        {
+            return false;
+        }
+        let own_address = match own_address {
+            Some(own_address) => own_address,
+            None => return false,
+        };
+
+        // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.3.2.1
+        self.requested.set(advertiser);
+        self.radio_buf.take().map(|buf| {
+            let header = SCAN_REQ | TX_ADD | if address_type != 0 { RX_ADD } else { 0 };
+            let len = write_pdu(buf, header, &own_address, &pdu[2..PAYLOAD_START]);
+            let buf = self.radio.set_response(buf, len);
+            self.radio_buf.replace(buf);
+        });
+        // Listen for the SCAN_RSP right after the SCAN_REQ
+        self.radio.set_turnaround(Turnaround::Receive);
+        true
+    }
+
+    // Reports the SCAN_RSP of the advertiser a SCAN_REQ was sent to
+    fn receive_scan_response(&self, pdu: &[u8]) {
+        let address_type = (pdu[0] & TX_ADD != 0) as u8;
+        let advertiser = device(address_type, &pdu[2..PAYLOAD_START]);
+        if pdu[0] & PDU_TYPE_MASK == SCAN_RSP && advertiser == self.requested.get() {
+            self.report(SCAN_RSP_EVENT_TYPE, advertiser, &pdu[PAYLOAD_START..]);
+        }
+    }
+
+    // Answers a SCAN_REQ sent to the controller. Returns whether it was one.
+    fn receive_scan_request(&self, pdu: &[u8]) -> bool {
+        let own_address = self.own_address(self.advertising_parameters.get().own_address_type);
+        let requested = pdu[0] & PDU_TYPE_MASK == SCAN_REQ
+            && pdu.len() == PAYLOAD_START + ADDRESS_LENGTH
+            && pdu[0] & RX_ADD != 0
+            && own_address.map_or(false, |own_address| own_address == pdu[PAYLOAD_START..]);
+        if !requested {
+            return false;
+        }
+
+        // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.3.2.2
+        let data = self.scan_response_data.get();
+        self.radio_buf.take().map(|buf| {
+            let len = write_pdu(
+                buf,
+                SCAN_RSP | TX_ADD,
+                &pdu[PAYLOAD_START..],
+                data.as_slice(),
+            );
+            let buf = self.radio.set_response(buf, len);
+            self.radio_buf.replace(buf);
+        });
+        true
+    }
+
+    fn is_duplicate(&self, event_type: u8, advertiser: Device) -> bool {
+        self.filter_duplicates.get()
+            && self.reported
+                .get()
+                .iter()
+                .any(|reported| *reported == Some((event_type, advertiser)))
+    }
+
+    // Sends an LE Advertising Report event, unless it is masked or a duplicate
+    //
+    // BLUETOOTH SPECIFICATION Version 4.2 [Vol 2, Part E], section 7.7.65.2
+    fn report(&self, event_type: u8, advertiser: Device, data: &[u8]) {
+        let masked = self.event_mask.get() & LE_META_EVENT_MASK == 0
+            || self.le_event_mask.get() & LE_ADVERTISING_REPORT_MASK == 0;
+        if masked || self.is_duplicate(event_type, advertiser) {
+            return;
+        }
+
+        let mut parameters = [0; MAX_REPORT_PARAMETERS_LENGTH];
+        let len = 12 + data.len();
+        parameters[0] = LE_ADVERTISING_REPORT_EVENT;
+        // Num_Reports
+        parameters[1] = 1;
+        parameters[2] = event_type;
+        parameters[3..10].copy_from_slice(&advertiser);
+        parameters[10] = data.len() as u8;
+        parameters[11..11 + data.len()].copy_from_slice(data);
+        parameters[len - 1] = RSSI_NOT_AVAILABLE;
+        let sent = self.send_event(LE_META_EVENT, &parameters[..len], false);
+
+        if sent && self.filter_duplicates.get() {
+            let mut reported = self.reported.get();
+            reported[self.reported_idx.get()] = Some((event_type, advertiser));
+            self.reported.set(reported);
+            self.reported_idx
+                .set((self.reported_idx.get() + 1) % DUPLICATE_FILTER_SIZE);
+        }
+    }
+}
+
+impl<'a, R, U, A> uart::Client for HciController<'a, R, U, A>
+where
+    R: ble_advertising::BleAdvertisementDriver
+        + ble_advertising::BleConfig
+        + ble_advertising::BleLinkLayerRadio
+        + 'a,
+    U: UART + 'a,
+    A: Alarm + 'a,
+{
+    fn transmit_complete(&self, buffer: &'static mut [u8], _error: uart::Error) {
+        self.spare.replace(buffer);
+        self.flush();
+    }
+
+    fn receive_complete(&self, buffer: &'static mut [u8], rx_len: usize, error: uart::Error) {
+        if error != uart::Error::CommandComplete {
+            // Start over with the next packet
+            self.rx_buf.replace(buffer);
+            self.receive(RxState::PacketType, 1);
+            return;
+        }
+
+        let (state, len) = match self.rx_state.get() {
+            RxState::PacketType => match buffer[0] {
+                HCI_COMMAND_PACKET => (RxState::CommandHeader, COMMAND_HEADER_LENGTH),
+                HCI_ACL_DATA_PACKET => (RxState::AclHeader, ACL_HEADER_LENGTH),
+                // Synchronous data is not supported, and the only way to find
+                // the next packet is to try every byte
+                _ => (RxState::PacketType, 1),
+            },
+            RxState::CommandHeader => {
+                self.opcode.set(decode_u16(&buffer[0..2]));
+                match buffer[2] as usize {
+                    0 => {
+                        self.execute_command(&[]);
+                        (RxState::PacketType, 1)
+                    }
+                    len => (RxState::CommandParameters, len),
+                }
+            }
+            RxState::CommandParameters => {
+                self.execute_command(&buffer[..rx_len]);
+                (RxState::PacketType, 1)
+            }
+            RxState::AclHeader => Self::discard(decode_u16(&buffer[2..4]) as usize, buffer.len()),
+            RxState::AclData(left) => Self::discard(left - cmp::min(rx_len, left), buffer.len()),
+        };
+        self.rx_buf.replace(buffer);
+        self.receive(state, len);
+    }
+}
+
+impl<'a, R, U, A> time::Client for HciController<'a, R, U, A>
+where
+    R: ble_advertising::BleAdvertisementDriver
+        + ble_advertising::BleConfig
+        + ble_advertising::BleLinkLayerRadio
+        + 'a,
+    U: UART + 'a,
+    A: Alarm + 'a,
+{
+    fn fired(&self) {
+        let now = self.alarm.now();
+
+        // The other device did not answer in time
+        if self.response_timeout
+            .get()
+            .map_or(false, |time| expired(time, now))
+        {
+            self.response_timeout.set(None);
+            match self.radio_state.get() {
+                RadioState::WaitingForScanRequest(channel) => {
+                    self.radio.disable();
+                    self.advertise_next(channel);
+                }
+                RadioState::RequestingScanResponse(_) => {
+                    self.radio.disable();
+                    self.resume();
+                }
+                _ => (),
+            }
+        }
+
+        if self.next_scan_channel
+            .get()
+            .map_or(false, |time| expired(time, now))
+        {
+            let next_channel = match self.scan_channel.get() {
+                RadioChannel::AdvertisingChannel37 => RadioChannel::AdvertisingChannel38,
+                RadioChannel::AdvertisingChannel38 => RadioChannel::AdvertisingChannel39,
+                _ => RadioChannel::AdvertisingChannel37,
+            };
+            self.scan_channel.set(next_channel);
+            let interval = self.interval_tics(self.scan_parameters.get().interval);
+            self.next_scan_channel.set(Some(now.wrapping_add(interval)));
+            if let RadioState::Scanning(_) = self.radio_state.get() {
+                self.radio.disable();
+                self.resume();
+            }
+        }
+
+        self.advertise_if_due();
+        self.reset_alarm();
+    }
+}
+
+impl<'a, R, U, A> ble_advertising::RxClient for HciController<'a, R, U, A>
+where
+    R: ble_advertising::BleAdvertisementDriver
+        + ble_advertising::BleConfig
+        + ble_advertising::BleLinkLayerRadio
+        + 'a,
+    U: UART + 'a,
+    A: Alarm + 'a,
+{
+    fn receive_event(&self, buf: &'static mut [u8], _len: u8, result: ReturnCode) {
+        // Only advertising channel PDUs are expected
+        let pdu = if result == ReturnCode::SUCCESS && buf.len() > 1 {
+            let len = (buf[1] & PDU_LENGTH_MASK) as usize + 2;
+            if len >= PAYLOAD_START && len <= PACKET_LENGTH && len <= buf.len() {
+                Some(&buf[..len])
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+
+        match self.radio_state.get() {
+            RadioState::Scanning(channel) => {
+                let requested = pdu.map_or(false, |pdu| self.receive_scanned_pdu(pdu));
+                if requested {
+                    self.radio_state
+                        .set(RadioState::RequestingScanResponse(channel));
+                } else {
+                    if self.scan_parameters.get().active {
+                        // Cancel the SCAN_REQ
+                        self.radio.disable();
+                    }
+                    self.resume();
+                }
+            }
+            RadioState::RequestingScanResponse(_) => {
+                self.response_timeout.set(None);
+                pdu.map(|pdu| self.receive_scan_response(pdu));
+                self.resume();
+            }
+            RadioState::WaitingForScanRequest(channel) => {
+                self.response_timeout.set(None);
+                if pdu.map_or(false, |pdu| self.receive_scan_request(pdu)) {
+                    self.radio_state
+                        .set(RadioState::SendingScanResponse(channel));
+                } else {
+                    // Cancel the SCAN_RSP
+                    self.radio.disable();
+                    self.advertise_next(channel);
+                }
+            }
+            _ => (),
+        }
+        self.reset_alarm();
+    }
+}
+
+impl<'a, R, U, A> ble_advertising::TxClient for HciController<'a, R, U, A>
+where
+    R: ble_advertising::BleAdvertisementDriver
+        + ble_advertising::BleConfig
+        + ble_advertising::BleLinkLayerRadio
+        + 'a,
+    U: UART + 'a,
+    A: Alarm + 'a,
+{
+    fn transmit_event(&self, _result: ReturnCode) {
+        let now = self.alarm.now();
+        match self.radio_state.get() {
+            RadioState::Advertising(channel) => {
+                let scannable =
+                    self.advertising_parameters.get().advertising_type == ADV_SCAN_IND_TYPE;
+                if scannable {
+                    // The radio is now listening for SCAN_REQs, be ready to
+                    // answer them
+                    self.radio_state
+                        .set(RadioState::WaitingForScanRequest(channel));
+                    self.radio.set_turnaround(Turnaround::Transmit);
+                    self.response_timeout
+                        .set(Some(now.wrapping_add(self.tics(RESPONSE_TIMEOUT_US))));
+                } else {
+                    self.advertise_next(channel);
+                }
+            }
+            RadioState::SendingScanResponse(channel) => self.advertise_next(channel),
+            // The SCAN_REQ was sent and the radio is now listening for the
+            // SCAN_RSP
+            RadioState::RequestingScanResponse(_) => {
+                self.response_timeout
+                    .set(Some(now.wrapping_add(self.tics(RESPONSE_TIMEOUT_US))));
+            }
+            _ => (),
+        }
+        self.reset_alarm();
+    }
+}
@@ -0,0 +1,1462 @@
+//! Bluetooth Low Energy Link Layer, peripheral role
+//!
+//! The link layer advertises with connectable undirected advertising events,
+//! i.e. it sends an `ADV_IND` on each advertising channel and listens for a
+//! `CONNECT_REQ` right after it. Once a master sends one, it enters the
+//! connection state as the slave: at every connection event, it hops to the
+//! next data channel of the channel map, listens for the master's packet
+//! around the anchor point and answers it, T_IFS later, with either the
+//! unacknowledged PDU, the next queued PDU or an empty PDU. The event
+//! continues as long as either side has more data to send.
+//!
+//! The link layer handles the LL control procedures started by the master
+//! (connection update, channel map update, termination, feature exchange,
+//! version exchange and LE ping) and can start the termination procedure
+//! itself. Higher layers see the payloads of data PDUs through the
+//! `ble_link_layer_hil::BleLinkLayer` trait.
+//!
+//! The slave always listens at every connection event, even though the slave
+//! latency would allow it to skip some of them when it has nothing to send.
+//!
+//! The link layer sits between the radio and the advertising driver
+//! (`ble_advertising_driver`), which it shares the radio with: the operations
+//! of the advertising driver run between advertising and connection events,
+//! and are interrupted (and reported as failed) when the link layer needs the
+//! radio back.
+//!
+//! Timing
+//! ------
+//!
+//! The timing of connection events comes from an alarm, so its resolution is
+//! that of the alarm. The anchor points are derived from the time at which
+//! the radio reports the master's packets, which is later than their end on
+//! the air, so the receive windows are widened by a margin on top of the
+//! window widening required by the sleep clock accuracies (Vol 6, Part B,
+//! section 4.5.7).
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let ble_link_layer = static_init!(
+//!     capsules::ble_link_layer::LinkLayer<
+//!         'static,
+//!         nrf52::radio::Radio,
+//!         VirtualMuxAlarm<'static, Rtc>,
+//!     >,
+//!     capsules::ble_link_layer::LinkLayer::new(
+//!         &nrf52::radio::RADIO,
+//!         ble_link_layer_virtual_alarm,
+//!         &mut capsules::ble_link_layer::ADV_BUF,
+//!         &mut capsules::ble_link_layer::TX_BUF,
+//!         &mut capsules::ble_link_layer::RX_BUF,
+//!         &mut capsules::ble_link_layer::UPPER_BUF
+//!     )
+//! );
+//! kernel::hil::ble_advertising::BleAdvertisementDriver::set_receive_client(
+//!     &nrf52::radio::RADIO,
+//!     ble_link_layer,
+//! );
+//! kernel::hil::ble_advertising::BleAdvertisementDriver::set_transmit_client(
+//!     &nrf52::radio::RADIO,
+//!     ble_link_layer,
+//! );
+//! ble_link_layer_virtual_alarm.set_client(ble_link_layer);
+//! ```
+//!
+//! The advertising driver then uses `ble_link_layer` as its radio.
+
+use ble_advertising_data;
+use ble_link_layer_hil;
+use ble_link_layer_hil::{ConnectionParameters, DataPduType};
+use core::cell::Cell;
+use core::cmp;
+use kernel;
+use kernel::common::take_cell::TakeCell;
+use kernel::hil::ble_advertising;
+use kernel::hil::ble_advertising::{RadioChannel, Turnaround};
+use kernel::hil::time::Frequency;
+use kernel::returncode::ReturnCode;
+
+/// Largest advertising channel PDU, header included
+pub const PACKET_LENGTH: usize = 39;
+
+pub static mut ADV_BUF: [u8; PACKET_LENGTH] = [0; PACKET_LENGTH];
+pub static mut TX_BUF: [u8; PACKET_LENGTH] = [0; PACKET_LENGTH];
+pub static mut RX_BUF: [u8; ble_link_layer_hil::MAX_DATA_PAYLOAD_LENGTH] =
+    [0; ble_link_layer_hil::MAX_DATA_PAYLOAD_LENGTH];
+pub static mut UPPER_BUF: [u8; PACKET_LENGTH] = [0; PACKET_LENGTH];
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.3 Advertising Channel PDU
+const ADV_IND: u8 = 0x00;
+const CONNECT_REQ: u8 = 0x05;
+const PDU_TYPE_MASK: u8 = 0x0f;
+const TX_ADD: u8 = 1 << 6;
+const RX_ADD: u8 = 1 << 7;
+const ADDRESS_LENGTH: usize = 6;
+const CONNECT_REQ_LENGTH: usize = 34;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.4 Data Channel PDU
+const LLID_MASK: u8 = 0x03;
+const LLID_CONTROL: u8 = 0x03;
+const NESN: u8 = 1 << 2;
+const SN: u8 = 1 << 3;
+const MD: u8 = 1 << 4;
+const LENGTH_MASK: u8 = 0x1f;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.4.2 LL Control PDU
+const LL_CONNECTION_UPDATE_REQ: u8 = 0x00;
+const LL_CHANNEL_MAP_REQ: u8 = 0x01;
+const LL_TERMINATE_IND: u8 = 0x02;
+const LL_ENC_REQ: u8 = 0x03;
+const LL_UNKNOWN_RSP: u8 = 0x07;
+const LL_FEATURE_REQ: u8 = 0x08;
+const LL_FEATURE_RSP: u8 = 0x09;
+const LL_VERSION_IND: u8 = 0x0C;
+const LL_REJECT_IND: u8 = 0x0D;
+const LL_PING_REQ: u8 = 0x12;
+const LL_PING_RSP: u8 = 0x13;
+
+// Bluetooth Core Specification 4.1, and no assigned company identifier
+const LL_VERSION: u8 = 0x07;
+const COMPANY_ID: u16 = 0xffff;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 2, Part D], section 1.3 List of Error Codes
+const UNSUPPORTED_REMOTE_FEATURE: u8 = 0x1A;
+
+const NUM_DATA_CHANNELS: u8 = 37;
+
+// Unit of the connection timing parameters
+const UNIT_US: u32 = 1250;
+const T_IFS_US: u32 = 150;
+// Time the radio needs to start receiving
+const RAMP_UP_US: u32 = 140;
+// How long to listen for a CONNECT_REQ after an ADV_IND, long enough for the
+// 44 bytes of a CONNECT_REQ to be received
+const CONNECT_REQ_TIMEOUT_US: u32 = 1000;
+// How long to listen after the time a packet from the master is expected, long
+// enough for the longest data channel packet (37 bytes) to be received
+const RX_TIMEOUT_US: u32 = 500;
+// Accounts for the resolution of the alarm and the time between the end of a
+// packet on the air and its callback
+const TIMING_MARGIN_US: u32 = 100;
+// A connection event is only continued if the next one starts later than this
+const EVENT_GUARD_US: u32 = 2500;
+// Operations of the advertising driver are only started if they end before
+// the link layer needs the radio
+const UPPER_TRANSMIT_GUARD_US: u32 = 2000;
+// Sleep clock accuracy of the slave, in ppm
+const SLAVE_SCA_PPM: u32 = 50;
+// Transmitting power of the link layer, in dBm
+const TX_POWER: u8 = 0;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.3.3.1
+// Upper bound of the master's sleep clock accuracy, in ppm, indexed by the SCA
+// field of the CONNECT_REQ
+const MASTER_SCA_PPM: [u32; 8] = [500, 250, 150, 100, 75, 50, 30, 20];
+
+fn read_u16(buf: &[u8]) -> u16 {
+    buf[0] as u16 | (buf[1] as u16) << 8
+}
+
+fn write_u16(buf: &mut [u8], value: u16) {
+    buf[0] = value as u8;
+    buf[1] = (value >> 8) as u8;
+}
+
+fn us_to_ticks<F: Frequency>(us: u32) -> u32 {
+    (us as u64 * F::frequency() as u64 / 1_000_000) as u32
+}
+
+fn ticks_to_us<F: Frequency>(ticks: u32) -> u32 {
+    (ticks as u64 * 1_000_000 / F::frequency() as u64) as u32
+}
+
+// Time a packet takes on the air: preamble, access address, PDU and CRC
+fn air_time_us(pdu_len: usize) -> u32 {
+    (1 + 4 + pdu_len as u32 + 3) * 8
+}
+
+// Whether `later` is after `earlier`, assuming they are less than half the
+// range of the alarm apart
+fn is_after(later: u32, earlier: u32) -> bool {
+    let diff = later.wrapping_sub(earlier);
+    diff != 0 && diff < u32::max_value() / 2
+}
+
+fn is_channel_used(channel_map: &[u8; 5], channel: u8) -> bool {
+    channel_map[(channel / 8) as usize] & (1 << (channel % 8)) != 0
+}
+
+fn num_used_channels(channel_map: &[u8; 5]) -> u8 {
+    (0..NUM_DATA_CHANNELS)
+        .filter(|&channel| is_channel_used(channel_map, channel))
+        .count() as u8
+}
+
+fn read_channel_map(buf: &[u8]) -> [u8; 5] {
+    let mut channel_map = [0; 5];
+    channel_map.copy_from_slice(&buf[0..5]);
+    // Channels 37 to 39 are the advertising channels
+    channel_map[4] &= 0x1f;
+    channel_map
+}
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 5.1.1
+// The instant has passed if it is not in the future, in which case the
+// connection is lost
+fn instant_passed(instant: u16, event_counter: u16) -> bool {
+    let diff = instant.wrapping_sub(event_counter);
+    diff == 0 || diff >= 32767
+}
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 4.5.2
+// The parameters of a CONNECT_REQ or LL_CONNECTION_UPDATE_REQ are in range
+fn valid_timing(window_size: u8, window_offset: u16, parameters: &ConnectionParameters) -> bool {
+    parameters.interval >= 6
+        && parameters.interval <= 3200
+        && parameters.latency <= 499
+        && parameters.timeout >= 10
+        && parameters.timeout <= 3200
+        && parameters.timeout as u32 * 10_000
+            > (1 + parameters.latency as u32) * parameters.interval as u32 * UNIT_US * 2
+        && window_size >= 1
+        && window_size <= 8
+        && (window_size as u16) < parameters.interval
+        && window_offset <= parameters.interval
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum State {
+    Idle,
+    /// Waiting for the next advertising event
+    AdvertisingIdle,
+    /// Sending an ADV_IND on the channel
+    Advertising(RadioChannel),
+    /// Listening for a CONNECT_REQ on the channel after an ADV_IND
+    WaitingForConnectRequest(RadioChannel),
+    /// Waiting for the next connection event
+    ConnectionIdle,
+    /// Listening for a packet from the master in a connection event
+    ConnectionReceiving,
+    /// Answering the master in a connection event
+    ConnectionTransmitting,
+}
+
+/// An LL control PDU sent in response to the master, or to terminate the
+/// connection
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum ControlPdu {
+    FeatureRsp,
+    VersionInd,
+    PingRsp,
+    UnknownRsp(u8),
+    RejectInd(u8),
+    TerminateInd(u8),
+}
+
+impl ControlPdu {
+    /// Writes the payload of the PDU and returns its length
+    fn encode(&self, buf: &mut [u8]) -> usize {
+        match *self {
+            ControlPdu::FeatureRsp => {
+                // No optional features are supported
+                buf[0] = LL_FEATURE_RSP;
+                for byte in buf[1..9].iter_mut() {
+                    *byte = 0;
+                }
+                9
+            }
+            ControlPdu::VersionInd => {
+                buf[0] = LL_VERSION_IND;
+                buf[1] = LL_VERSION;
+                write_u16(&mut buf[2..4], COMPANY_ID);
+                write_u16(&mut buf[4..6], 0);
+                6
+            }
+            ControlPdu::PingRsp => {
+                buf[0] = LL_PING_RSP;
+                1
+            }
+            ControlPdu::UnknownRsp(opcode) => {
+                buf[0] = LL_UNKNOWN_RSP;
+                buf[1] = opcode;
+                2
+            }
+            ControlPdu::RejectInd(error_code) => {
+                buf[0] = LL_REJECT_IND;
+                buf[1] = error_code;
+                2
+            }
+            ControlPdu::TerminateInd(error_code) => {
+                buf[0] = LL_TERMINATE_IND;
+                buf[1] = error_code;
+                2
+            }
+        }
+    }
+}
+
+/// The PDU the slave sends, which it retransmits until the master
+/// acknowledges it
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum TxPdu {
+    /// The last PDU sent was acknowledged
+    None,
+    Empty,
+    Control(ControlPdu),
+    /// The PDU queued by the higher layers
+    Data,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum UpperOperation {
+    Transmit(usize, RadioChannel),
+    Receive(RadioChannel),
+}
+
+#[derive(Copy, Clone, Debug)]
+struct ConnectionUpdate {
+    window_size: u8,
+    window_offset: u16,
+    parameters: ConnectionParameters,
+    instant: u16,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Connection {
+    peer_address: [u8; 6],
+    access_address: u32,
+    crc_init: u32,
+    parameters: ConnectionParameters,
+    channel_map: [u8; 5],
+    hop_increment: u8,
+    master_sca_ppm: u32,
+    last_unmapped_channel: u8,
+    channel: RadioChannel,
+    event_counter: u16,
+    transmit_seq_num: bool,
+    next_expected_seq_num: bool,
+    /// Whether a packet was received from the master
+    established: bool,
+    /// Start and size of the window in which the master sends its first
+    /// packet, `events_since_window` connection events ago. Once established,
+    /// it is the last anchor point, with a window of size 0.
+    window_start: u32,
+    window_size_us: u32,
+    events_since_window: u32,
+    /// Last time the slave synchronized with the master, from which the
+    /// supervision timeout and the window widening are computed
+    last_sync: u32,
+    update: Option<ConnectionUpdate>,
+    channel_map_update: Option<([u8; 5], u16)>,
+}
+
+impl Connection {
+    // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.3.3.1
+    //
+    //   LLData  +--------+----------+---------+-----------+----------+
+    //           | AA     | CRCInit  | WinSize | WinOffset | Interval |
+    //           | 4 bytes| 3 bytes  | 1 byte  | 2 bytes   | 2 bytes  |
+    //           +--------+----------+---------+-----------+----------+
+    //           +---------+---------+---------+---------+---------+
+    //           | Latency | Timeout | ChM     | Hop     | SCA     |
+    //           | 2 bytes | 2 bytes | 5 bytes | 5 bits  | 3 bits  |
+    //           +---------+---------+---------+---------+---------+
+    //
+    // `pdu` is the payload of the CONNECT_REQ, which starts with the addresses
+    // of the initiator and of the advertiser, and `received` the time at which
+    // it was received. Returns `None` if the parameters are invalid.
+    fn from_connect_request<F: Frequency>(pdu: &[u8], received: u32) -> Option<Connection> {
+        let data = &pdu[2 * ADDRESS_LENGTH..CONNECT_REQ_LENGTH];
+        let window_size = data[7];
+        let window_offset = read_u16(&data[8..10]);
+        let parameters = ConnectionParameters {
+            interval: read_u16(&data[10..12]),
+            latency: read_u16(&data[12..14]),
+            timeout: read_u16(&data[14..16]),
+        };
+        let channel_map = read_channel_map(&data[16..21]);
+        let hop_increment = data[21] & 0x1f;
+
+        let valid = valid_timing(window_size, window_offset, &parameters)
+            && hop_increment >= 5
+            && hop_increment <= 16
+            && num_used_channels(&channel_map) >= 2;
+        if !valid {
+            return None;
+        }
+
+        let mut peer_address = [0; 6];
+        peer_address.copy_from_slice(&pdu[0..ADDRESS_LENGTH]);
+        // The transmit window starts 1.25 ms plus the window offset after the
+        // end of the CONNECT_REQ
+        let window_start =
+            received.wrapping_add(us_to_ticks::<F>((1 + window_offset as u32) * UNIT_US));
+        Some(Connection {
+            peer_address: peer_address,
+            access_address: read_u16(&data[0..2]) as u32 | (read_u16(&data[2..4]) as u32) << 16,
+            crc_init: read_u16(&data[4..6]) as u32 | (data[6] as u32) << 16,
+            parameters: parameters,
+            channel_map: channel_map,
+            hop_increment: hop_increment,
+            master_sca_ppm: MASTER_SCA_PPM[(data[21] >> 5) as usize],
+            last_unmapped_channel: 0,
+            channel: RadioChannel::DataChannel0,
+            event_counter: 0,
+            transmit_seq_num: false,
+            next_expected_seq_num: false,
+            established: false,
+            window_start: window_start,
+            window_size_us: window_size as u32 * UNIT_US,
+            events_since_window: 0,
+            last_sync: received,
+            update: None,
+            channel_map_update: None,
+        })
+    }
+
+    // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 4.5.8.2
+    // Channel Selection
+    fn next_channel(&mut self) {
+        let unmapped_channel =
+            (self.last_unmapped_channel + self.hop_increment) % NUM_DATA_CHANNELS;
+        self.last_unmapped_channel = unmapped_channel;
+
+        let index = if is_channel_used(&self.channel_map, unmapped_channel) {
+            unmapped_channel
+        } else {
+            let remapping_index = unmapped_channel % num_used_channels(&self.channel_map);
+            (0..NUM_DATA_CHANNELS)
+                .filter(|&channel| is_channel_used(&self.channel_map, channel))
+                .nth(remapping_index as usize)
+                .unwrap_or(0)
+        };
+        self.channel =
+            RadioChannel::from_channel_index(index).unwrap_or(RadioChannel::DataChannel0);
+    }
+
+    fn interval_us(&self) -> u32 {
+        self.parameters.interval as u32 * UNIT_US
+    }
+
+    /// Start of the window of the current connection event, before widening
+    fn current_window_start<F: Frequency>(&self) -> u32 {
+        self.window_start.wrapping_add(us_to_ticks::<F>(
+            self.events_since_window * self.interval_us(),
+        ))
+    }
+
+    /// Start of the window of the next connection event, before widening
+    fn next_window_start<F: Frequency>(&self) -> u32 {
+        self.window_start.wrapping_add(us_to_ticks::<F>(
+            (self.events_since_window + 1) * self.interval_us(),
+        ))
+    }
+
+    /// Returns when to start listening for the master in the current
+    /// connection event, and when to give up if nothing was received
+    fn receive_window<F: Frequency>(&self) -> (u32, u32) {
+        let start = self.current_window_start::<F>();
+
+        // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 4.5.7 Window Widening
+        let drift_us = ticks_to_us::<F>(start.wrapping_sub(self.last_sync)) as u64;
+        let widening_us =
+            ((self.master_sca_ppm + SLAVE_SCA_PPM) as u64 * drift_us / 1_000_000) as u32 + 16;
+        let widening_us =
+            cmp::min(widening_us, self.interval_us() / 2 - T_IFS_US) + TIMING_MARGIN_US;
+
+        let listen = start.wrapping_sub(us_to_ticks::<F>(widening_us + RAMP_UP_US));
+        let timeout = start.wrapping_add(us_to_ticks::<F>(
+            self.window_size_us + widening_us + RX_TIMEOUT_US,
+        ));
+        (listen, timeout)
+    }
+
+    /// Called with the anchor point of the current connection event, when
+    /// the first packet of the master in it was received
+    fn synchronize(&mut self, anchor: u32) {
+        self.established = true;
+        self.window_start = anchor;
+        self.window_size_us = 0;
+        self.events_since_window = 0;
+        self.last_sync = anchor;
+    }
+
+    fn end_event(&mut self) {
+        self.event_counter = self.event_counter.wrapping_add(1);
+        self.events_since_window += 1;
+    }
+
+    /// Applies the procedures whose instant is the current connection event,
+    /// and returns whether the connection parameters changed
+    fn apply_instants<F: Frequency>(&mut self) -> bool {
+        if let Some((channel_map, instant)) = self.channel_map_update {
+            if instant == self.event_counter {
+                self.channel_map = channel_map;
+                self.channel_map_update = None;
+            }
+        }
+
+        match self.update {
+            Some(update) if update.instant == self.event_counter => {
+                // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 5.1.1
+                // The transmit window starts the window offset after the anchor
+                // point the instant would have had with the old parameters
+                let old_anchor = self.current_window_start::<F>();
+                self.window_start = old_anchor
+                    .wrapping_add(us_to_ticks::<F>(update.window_offset as u32 * UNIT_US));
+                self.window_size_us = update.window_size as u32 * UNIT_US;
+                self.events_since_window = 0;
+                self.parameters = update.parameters;
+                self.update = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 4.5.2 Supervision Timeout
+    // Returns why the connection is lost, if it is
+    fn lost<F: Frequency>(&self, now: u32) -> Option<u8> {
+        if !self.established {
+            if self.event_counter >= 6 {
+                Some(ble_link_layer_hil::CONNECTION_FAILED_TO_BE_ESTABLISHED)
+            } else {
+                None
+            }
+        } else if ticks_to_us::<F>(now.wrapping_sub(self.last_sync))
+            > self.parameters.timeout as u32 * 10_000
+        {
+            Some(ble_link_layer_hil::CONNECTION_TIMEOUT)
+        } else {
+            None
+        }
+    }
+}
+
+pub struct LinkLayer<'a, R, A>
+where
+    R: ble_advertising::BleAdvertisementDriver
+        + ble_advertising::BleConfig
+        + ble_advertising::BleLinkLayerRadio
+        + 'a,
+    A: kernel::hil::time::Alarm + 'a,
+{
+    radio: &'a R,
+    alarm: &'a A,
+    state: Cell<State>,
+    client: Cell<Option<&'static ble_link_layer_hil::ConnectionClient>>,
+
+    // Advertising
+    address: Cell<[u8; 6]>,
+    adv_buf: TakeCell<'static, [u8]>,
+    adv_len: Cell<usize>,
+    advertising_interval_ms: Cell<u32>,
+    random_nonce: Cell<u32>,
+
+    // Connection
+    connection: Cell<Option<Connection>>,
+    tx_buf: TakeCell<'static, [u8]>,
+    tx_pdu: Cell<TxPdu>,
+    rx_buf: TakeCell<'static, [u8]>,
+    control_pdu: Cell<Option<ControlPdu>>,
+    data_buf: TakeCell<'static, [u8]>,
+    data_len: Cell<usize>,
+    data_type: Cell<DataPduType>,
+    packets_in_event: Cell<usize>,
+    crc_errors: Cell<usize>,
+    event_continues: Cell<bool>,
+    /// Set when the connection is closed at the end of the current
+    /// connection event, with the reason
+    closing: Cell<Option<u8>>,
+
+    // Operations of the advertising driver
+    upper_rx_client: Cell<Option<&'static ble_advertising::RxClient>>,
+    upper_tx_client: Cell<Option<&'static ble_advertising::TxClient>>,
+    upper_tx_power: Cell<u8>,
+    upper_buf: TakeCell<'static, [u8]>,
+    upper_pending: Cell<Option<UpperOperation>>,
+    upper_active: Cell<Option<UpperOperation>>,
+    upper_turnaround: Cell<Turnaround>,
+}
+
+impl<'a, R, A> LinkLayer<'a, R, A>
+where
+    R: ble_advertising::BleAdvertisementDriver
+        + ble_advertising::BleConfig
+        + ble_advertising::BleLinkLayerRadio
+        + 'a,
+    A: kernel::hil::time::Alarm + 'a,
+{
+    pub fn new(
+        radio: &'a R,
+        alarm: &'a A,
+        adv_buf: &'static mut [u8],
+        tx_buf: &'static mut [u8],
+        rx_buf: &'static mut [u8],
+        upper_buf: &'static mut [u8],
+    ) -> LinkLayer<'a, R, A> {
+        // An ADV_IND from a random device address, without advertising data
+        adv_buf[0] = ADV_IND | TX_ADD;
+        adv_buf[1] = ADDRESS_LENGTH as u8;
+        LinkLayer {
+            radio: radio,
+            alarm: alarm,
+            state: Cell::new(State::Idle),
+            client: Cell::new(None),
+            address: Cell::new([0; 6]),
+            adv_buf: TakeCell::new(adv_buf),
+            adv_len: Cell::new(2 + ADDRESS_LENGTH),
+            advertising_interval_ms: Cell::new(100),
+            random_nonce: Cell::new(0xdeadbeef),
+            connection: Cell::new(None),
+            tx_buf: TakeCell::new(tx_buf),
+            tx_pdu: Cell::new(TxPdu::None),
+            rx_buf: TakeCell::new(rx_buf),
+            control_pdu: Cell::new(None),
+            data_buf: TakeCell::empty(),
+            data_len: Cell::new(0),
+            data_type: Cell::new(DataPduType::Start),
+            packets_in_event: Cell::new(0),
+            crc_errors: Cell::new(0),
+            event_continues: Cell::new(false),
+            closing: Cell::new(None),
+            upper_rx_client: Cell::new(None),
+            upper_tx_client: Cell::new(None),
+            upper_tx_power: Cell::new(0),
+            upper_buf: TakeCell::new(upper_buf),
+            upper_pending: Cell::new(None),
+            upper_active: Cell::new(None),
+            upper_turnaround: Cell::new(Turnaround::Disable),
+        }
+    }
+
+    /// Whether the link layer is using the radio
+    fn owns_radio(&self) -> bool {
+        match self.state.get() {
+            State::Advertising(_)
+            | State::WaitingForConnectRequest(_)
+            | State::ConnectionReceiving
+            | State::ConnectionTransmitting => true,
+            _ => false,
+        }
+    }
+
+    // Returns a new pseudo-random number, like `ble_advertising_driver` does
+    // for the advertising delay
+    fn random_nonce(&self) -> u32 {
+        let mut next_nonce = ::core::num::Wrapping(self.random_nonce.get());
+        next_nonce ^= next_nonce << 13;
+        next_nonce ^= next_nonce >> 17;
+        next_nonce ^= next_nonce << 5;
+        self.random_nonce.set(next_nonce.0);
+        next_nonce.0
+    }
+
+    // Starts the operation requested by the advertising driver, if the radio
+    // is free
+    fn start_upper_operation(&self) {
+        if self.owns_radio() || self.upper_active.get().is_some() {
+            return;
+        }
+        let operation = match self.upper_pending.get() {
+            Some(operation) => operation,
+            None => return,
+        };
+
+        // Only start a transmission if it ends before the next event of the
+        // link layer. Receptions last until a packet is received, so they are
+        // interrupted by the link layer anyway.
+        let next_event = match self.state.get() {
+            State::AdvertisingIdle | State::ConnectionIdle => Some(self.alarm.get_alarm()),
+            _ => None,
+        };
+        if let (UpperOperation::Transmit(..), Some(next_event)) = (operation, next_event) {
+            let guard = us_to_ticks::<A::Frequency>(UPPER_TRANSMIT_GUARD_US);
+            if next_event.wrapping_sub(self.alarm.now()) < guard {
+                return;
+            }
+        }
+
+        self.upper_pending.set(None);
+        self.upper_active.set(Some(operation));
+        self.radio.set_tx_power(self.upper_tx_power.get());
+        self.radio.set_access_address(
+            ble_advertising::ADVERTISING_ACCESS_ADDRESS,
+            ble_advertising::ADVERTISING_CRC_INIT,
+        );
+        self.radio.set_turnaround(self.upper_turnaround.get());
+        match operation {
+            UpperOperation::Transmit(len, channel) => {
+                self.upper_buf.take().map(|buf| {
+                    let buf = self.radio.transmit_advertisement(buf, len, channel);
+                    self.upper_buf.replace(buf);
+                });
+            }
+            UpperOperation::Receive(channel) => self.radio.receive_advertisement(channel),
+        }
+    }
+
+    // Interrupts the operation of the advertising driver, because the link
+    // layer needs the radio
+    fn preempt_upper_operation(&self) {
+        if let Some(operation) = self.upper_active.get() {
+            self.upper_active.set(None);
+            self.upper_turnaround.set(Turnaround::Disable);
+            self.radio.disable();
+            match operation {
+                UpperOperation::Transmit(..) => {
+                    self.upper_tx_client
+                        .get()
+                        .map(|client| client.transmit_event(ReturnCode::FAIL));
+                }
+                UpperOperation::Receive(_) => {
+                    self.upper_rx_client
+                        .get()
+                        .map(|client| client.receive_event(&mut [], 0, ReturnCode::FAIL));
+                }
+            }
+        }
+    }
+
+    // Called when an operation of the advertising driver ends. If it asked the
+    // radio to turn around, the operation continues with the next packet.
+    fn end_upper_operation(&self) {
+        let channel = match self.upper_active.get() {
+            Some(UpperOperation::Transmit(_, channel)) | Some(UpperOperation::Receive(channel)) => {
+                channel
+            }
+            None => return,
+        };
+        let operation = match self.upper_turnaround.get() {
+            Turnaround::Disable => None,
+            Turnaround::Receive => Some(UpperOperation::Receive(channel)),
+            Turnaround::Transmit => Some(UpperOperation::Transmit(0, channel)),
+        };
+        self.upper_turnaround.set(Turnaround::Disable);
+        self.upper_active.set(operation);
+    }
+
+    fn schedule_advertising_event(&self) {
+        // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 4.4.2.2
+        // A pseudo-random delay of 0 to 10 ms is added to each advertising interval
+        let delay_us = self.advertising_interval_ms.get() * 1000 + self.random_nonce() % 10_000;
+        self.state.set(State::AdvertisingIdle);
+        self.alarm.set_alarm(
+            self.alarm
+                .now()
+                .wrapping_add(us_to_ticks::<A::Frequency>(delay_us)),
+        );
+    }
+
+    fn advertise(&self, channel: RadioChannel) {
+        self.state.set(State::Advertising(channel));
+        self.radio.set_tx_power(TX_POWER);
+        self.radio.set_access_address(
+            ble_advertising::ADVERTISING_ACCESS_ADDRESS,
+            ble_advertising::ADVERTISING_CRC_INIT,
+        );
+        // Listen for a CONNECT_REQ right after the ADV_IND
+        self.radio.set_turnaround(Turnaround::Receive);
+        let len = self.adv_len.get();
+        self.adv_buf.take().map(|buf| {
+            let buf = self.radio.transmit_advertisement(buf, len, channel);
+            self.adv_buf.replace(buf);
+        });
+    }
+
+    // Continues the advertising event on the next advertising channel, or ends
+    // it
+    fn advertise_next(&self, channel: RadioChannel) {
+        match channel {
+            RadioChannel::AdvertisingChannel37 => {
+                self.advertise(RadioChannel::AdvertisingChannel38)
+            }
+            RadioChannel::AdvertisingChannel38 => {
+                self.advertise(RadioChannel::AdvertisingChannel39)
+            }
+            _ => {
+                self.schedule_advertising_event();
+                self.start_upper_operation();
+            }
+        }
+    }
+
+    fn receive_connect_request(&self, buf: &[u8], result: ReturnCode, channel: RadioChannel) {
+        let header = buf[0];
+        let is_connect_request = result == ReturnCode::SUCCESS
+            && header & PDU_TYPE_MASK == CONNECT_REQ
+            && buf[1] as usize == CONNECT_REQ_LENGTH
+            && header & RX_ADD != 0
+            && buf[2 + ADDRESS_LENGTH..2 + 2 * ADDRESS_LENGTH] == self.address.get();
+        let connection = if is_connect_request {
+            Connection::from_connect_request::<A::Frequency>(
+                &buf[2..2 + CONNECT_REQ_LENGTH],
+                self.alarm.now(),
+            )
+        } else {
+            None
+        };
+
+        match connection {
+            Some(connection) => {
+                self.connection.set(Some(connection));
+                self.tx_pdu.set(TxPdu::None);
+                self.control_pdu.set(None);
+                self.closing.set(None);
+                self.schedule_connection_event();
+                if self.connection.get().is_some() {
+                    self.client.get().map(|client| {
+                        client.connected(connection.peer_address, connection.parameters)
+                    });
+                }
+            }
+            None => self.advertise_next(channel),
+        }
+    }
+
+    // Sets the alarm for the next connection event, skipping those that are
+    // too close to be prepared in time
+    fn schedule_connection_event(&self) {
+        let mut connection = match self.connection.get() {
+            Some(connection) => connection,
+            None => return,
+        };
+        let now = self.alarm.now();
+        let min_delay = us_to_ticks::<A::Frequency>(TIMING_MARGIN_US);
+        let mut updated = false;
+        loop {
+            if let Some(reason) = connection.lost::<A::Frequency>(now) {
+                self.connection.set(Some(connection));
+                self.close_connection(reason);
+                return;
+            }
+            updated |= connection.apply_instants::<A::Frequency>();
+            let (listen, _) = connection.receive_window::<A::Frequency>();
+            if is_after(listen, now.wrapping_add(min_delay)) {
+                self.connection.set(Some(connection));
+                self.state.set(State::ConnectionIdle);
+                self.alarm.set_alarm(listen);
+                break;
+            }
+            // Skipped connection events still count and hop channels
+            connection.next_channel();
+            connection.end_event();
+        }
+
+        if updated {
+            self.client
+                .get()
+                .map(|client| client.parameters_updated(connection.parameters));
+        }
+    }
+
+    fn start_connection_event(&self) {
+        let mut connection = match self.connection.get() {
+            Some(connection) => connection,
+            None => return,
+        };
+        connection.next_channel();
+        let (_, timeout) = connection.receive_window::<A::Frequency>();
+        self.connection.set(Some(connection));
+
+        self.state.set(State::ConnectionReceiving);
+        self.preempt_upper_operation();
+        self.packets_in_event.set(0);
+        self.crc_errors.set(0);
+
+        self.radio.set_tx_power(TX_POWER);
+        self.radio
+            .set_access_address(connection.access_address, connection.crc_init);
+        // The slave answers every packet from the master
+        self.radio.set_turnaround(Turnaround::Transmit);
+        self.radio.receive_advertisement(connection.channel);
+        self.alarm.set_alarm(timeout);
+    }
+
+    fn end_connection_event(&self) {
+        if let Some(reason) = self.closing.get() {
+            self.close_connection(reason);
+            return;
+        }
+        self.connection.get().map(|mut connection| {
+            connection.end_event();
+            self.connection.set(Some(connection));
+        });
+        self.schedule_connection_event();
+        self.start_upper_operation();
+    }
+
+    fn close_connection(&self, reason: u8) {
+        if self.owns_radio() {
+            self.radio.disable();
+        }
+        self.alarm.disable();
+        self.state.set(State::Idle);
+        self.connection.set(None);
+        self.tx_pdu.set(TxPdu::None);
+        self.control_pdu.set(None);
+        self.closing.set(None);
+
+        let buf = self.data_buf.take();
+        self.client.get().map(|client| {
+            buf.map(|buf| client.transmit_done(buf, ReturnCode::FAIL));
+            client.disconnected(reason);
+        });
+        self.start_upper_operation();
+    }
+
+    fn send_control_pdu(&self, pdu: ControlPdu) {
+        // A termination takes precedence over anything else
+        if self.control_pdu.get().is_none() {
+            self.control_pdu.set(Some(pdu));
+        }
+    }
+
+    // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 5 LINK LAYER CONTROL
+    fn receive_control_pdu(&self, connection: &mut Connection, pdu: &[u8]) {
+        if pdu.is_empty() {
+            return;
+        }
+        match pdu[0] {
+            LL_CONNECTION_UPDATE_REQ => {
+                if pdu.len() != 12 {
+                    return;
+                }
+                let instant = read_u16(&pdu[10..12]);
+                if instant_passed(instant, connection.event_counter) {
+                    self.closing.set(Some(ble_link_layer_hil::INSTANT_PASSED));
+                    return;
+                }
+                let update = ConnectionUpdate {
+                    window_size: pdu[1],
+                    window_offset: read_u16(&pdu[2..4]),
+                    parameters: ConnectionParameters {
+                        interval: read_u16(&pdu[4..6]),
+                        latency: read_u16(&pdu[6..8]),
+                        timeout: read_u16(&pdu[8..10]),
+                    },
+                    instant: instant,
+                };
+                if !valid_timing(update.window_size, update.window_offset, &update.parameters) {
+                    self.closing
+                        .set(Some(ble_link_layer_hil::INVALID_LL_PARAMETERS));
+                    return;
+                }
+                connection.update = Some(update);
+            }
+            LL_CHANNEL_MAP_REQ => {
+                if pdu.len() != 8 {
+                    return;
+                }
+                let channel_map = read_channel_map(&pdu[1..6]);
+                let instant = read_u16(&pdu[6..8]);
+                if instant_passed(instant, connection.event_counter) {
+                    self.closing.set(Some(ble_link_layer_hil::INSTANT_PASSED));
+                } else if num_used_channels(&channel_map) >= 2 {
+                    connection.channel_map_update = Some((channel_map, instant));
+                }
+            }
+            LL_TERMINATE_IND => {
+                if pdu.len() == 2 {
+                    self.closing.set(Some(pdu[1]));
+                }
+            }
+            LL_FEATURE_REQ => self.send_control_pdu(ControlPdu::FeatureRsp),
+            LL_VERSION_IND => self.send_control_pdu(ControlPdu::VersionInd),
+            LL_PING_REQ => self.send_control_pdu(ControlPdu::PingRsp),
+            LL_ENC_REQ => self.send_control_pdu(ControlPdu::RejectInd(UNSUPPORTED_REMOTE_FEATURE)),
+            // Answers to procedures the slave does not start
+            LL_UNKNOWN_RSP | LL_REJECT_IND | LL_FEATURE_RSP => {}
+            opcode => self.send_control_pdu(ControlPdu::UnknownRsp(opcode)),
+        }
+    }
+
+    // Puts the next PDU to send in `tx_buf`, once the previous one was
+    // acknowledged: control PDUs first, then the PDU queued by the higher
+    // layers, or an empty PDU
+    fn prepare_next_pdu(&self) {
+        self.tx_buf.map(|tx| {
+            if let Some(pdu) = self.control_pdu.get() {
+                self.control_pdu.set(None);
+                tx[0] = LLID_CONTROL;
+                tx[1] = pdu.encode(&mut tx[2..]) as u8;
+                self.tx_pdu.set(TxPdu::Control(pdu));
+            } else if self.data_buf.is_some() {
+                let len = self.data_len.get();
+                self.data_buf
+                    .map(|data| tx[2..2 + len].copy_from_slice(&data[..len]));
+                tx[0] = self.data_type.get() as u8;
+                tx[1] = len as u8;
+                self.tx_pdu.set(TxPdu::Data);
+            } else {
+                tx[0] = DataPduType::Continuation as u8;
+                tx[1] = 0;
+                self.tx_pdu.set(TxPdu::Empty);
+            }
+        });
+    }
+
+    // Whether there is something to send after the current PDU
+    fn has_more_data(&self) -> bool {
+        self.control_pdu.get().is_some()
+            || (self.data_buf.is_some() && self.tx_pdu.get() != TxPdu::Data)
+    }
+
+    // Handles a packet from the master and sets the answer, which the radio
+    // sends T_IFS after the end of the packet. The higher layers are called
+    // once the answer is set.
+    fn receive_data_pdu(&self, buf: &[u8], result: ReturnCode) {
+        let mut connection = match self.connection.get() {
+            Some(connection) => connection,
+            None => return,
+        };
+        let now = self.alarm.now();
+        let first_packet = self.packets_in_event.get() == 0;
+        self.packets_in_event.set(self.packets_in_event.get() + 1);
+
+        let header = buf[0];
+        let len = (buf[1] & LENGTH_MASK) as usize;
+        let valid = result == ReturnCode::SUCCESS
+            && len <= ble_link_layer_hil::MAX_DATA_PAYLOAD_LENGTH
+            && 2 + len <= buf.len();
+
+        let mut master_more_data = false;
+        let mut received = None;
+        let mut acknowledged = None;
+        if valid {
+            self.crc_errors.set(0);
+            if first_packet {
+                // The anchor point is the start of the first packet of the
+                // master in the connection event
+                let anchor = now.wrapping_sub(us_to_ticks::<A::Frequency>(air_time_us(2 + len)));
+                connection.synchronize(anchor);
+            }
+            master_more_data = header & MD != 0;
+
+            // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 4.5.9
+            // Acknowledgement and Flow Control
+            if (header & NESN != 0) != connection.transmit_seq_num {
+                connection.transmit_seq_num = !connection.transmit_seq_num;
+                acknowledged = Some(self.tx_pdu.get());
+                self.tx_pdu.set(TxPdu::None);
+            }
+            if (header & SN != 0) == connection.next_expected_seq_num {
+                connection.next_expected_seq_num = !connection.next_expected_seq_num;
+                let payload = &buf[2..2 + len];
+                match header & LLID_MASK {
+                    LLID_CONTROL => self.receive_control_pdu(&mut connection, payload),
+                    llid if len > 0 => {
+                        self.rx_buf.map(|rx| rx[..len].copy_from_slice(payload));
+                        received = Some(if llid == DataPduType::Start as u8 {
+                            DataPduType::Start
+                        } else {
+                            DataPduType::Continuation
+                        });
+                    }
+                    // Empty PDU
+                    _ => {}
+                }
+            }
+        } else {
+            self.crc_errors.set(self.crc_errors.get() + 1);
+        }
+
+        let mut done_buf = None;
+        match acknowledged {
+            Some(TxPdu::Data) => done_buf = self.data_buf.take(),
+            Some(TxPdu::Control(ControlPdu::TerminateInd(_))) => {
+                self.closing.set(Some(
+                    ble_link_layer_hil::CONNECTION_TERMINATED_BY_LOCAL_HOST,
+                ));
+            }
+            _ => {}
+        }
+
+        // Answer with the unacknowledged PDU, or the next one
+        if self.tx_pdu.get() == TxPdu::None {
+            self.prepare_next_pdu();
+        }
+        let more_data = self.has_more_data();
+        let mut flags = 0;
+        if connection.next_expected_seq_num {
+            flags |= NESN;
+        }
+        if connection.transmit_seq_num {
+            flags |= SN;
+        }
+        if more_data {
+            flags |= MD;
+        }
+        self.tx_buf.take().map(|tx| {
+            tx[0] = (tx[0] & LLID_MASK) | flags;
+            let len = 2 + tx[1] as usize;
+            let tx = self.radio.set_response(tx, len);
+            self.tx_buf.replace(tx);
+        });
+
+        // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 4.5.6
+        // Closing Connection Events
+        let event_continues = self.closing.get().is_none()
+            && self.crc_errors.get() < 2
+            && (master_more_data || more_data || !valid)
+            && is_after(
+                connection.next_window_start::<A::Frequency>(),
+                now.wrapping_add(us_to_ticks::<A::Frequency>(EVENT_GUARD_US)),
+            );
+        self.event_continues.set(event_continues);
+        self.radio.set_turnaround(if event_continues {
+            Turnaround::Receive
+        } else {
+            Turnaround::Disable
+        });
+        self.state.set(State::ConnectionTransmitting);
+        self.connection.set(Some(connection));
+
+        self.client.get().map(|client| {
+            received.map(|pdu_type| {
+                self.rx_buf.map(|rx| client.receive(&rx[..len], pdu_type));
+            });
+            done_buf.map(|buf| client.transmit_done(buf, ReturnCode::SUCCESS));
+        });
+    }
+}
+
+// Timer alarm
+impl<'a, R, A> kernel::hil::time::Client for LinkLayer<'a, R, A>
+where
+    R: ble_advertising::BleAdvertisementDriver
+        + ble_advertising::BleConfig
+        + ble_advertising::BleLinkLayerRadio
+        + 'a,
+    A: kernel::hil::time::Alarm + 'a,
+{
+    fn fired(&self) {
+        // A virtual alarm may fire a bit early, together with another one that
+        // expires just before it, which is too early for the link layer
+        let when = self.alarm.get_alarm();
+        if is_after(when, self.alarm.now()) {
+            self.alarm.set_alarm(when);
+            return;
+        }
+
+        match self.state.get() {
+            State::AdvertisingIdle => {
+                self.state
+                    .set(State::Advertising(RadioChannel::AdvertisingChannel37));
+                self.preempt_upper_operation();
+                self.advertise(RadioChannel::AdvertisingChannel37);
+            }
+            State::WaitingForConnectRequest(channel) => {
+                self.radio.disable();
+                self.advertise_next(channel);
+            }
+            State::ConnectionIdle => self.start_connection_event(),
+            State::ConnectionReceiving => {
+                // Nothing (more) was received from the master
+                self.radio.disable();
+                self.end_connection_event();
+            }
+            _ => {}
+        }
+    }
+}
+
+// Callback from the radio once a RX event occur
+impl<'a, R, A> ble_advertising::RxClient for LinkLayer<'a, R, A>
+where
+    R: ble_advertising::BleAdvertisementDriver
+        + ble_advertising::BleConfig
+        + ble_advertising::BleLinkLayerRadio
+        + 'a,
+    A: kernel::hil::time::Alarm + 'a,
+{
+    fn receive_event(&self, buf: &'static mut [u8], len: u8, result: ReturnCode) {
+        if self.upper_active.get().is_some() {
+            self.end_upper_operation();
+            self.upper_rx_client
+                .get()
+                .map(move |client| client.receive_event(buf, len, result));
+            return;
+        }
+
+        match self.state.get() {
+            State::WaitingForConnectRequest(channel) => {
+                self.receive_connect_request(buf, result, channel)
+            }
+            State::ConnectionReceiving => self.receive_data_pdu(buf, result),
+            _ => {}
+        }
+    }
+}
+
+// Callback from the radio once a TX event occur
+impl<'a, R, A> ble_advertising::TxClient for LinkLayer<'a, R, A>
+where
+    R: ble_advertising::BleAdvertisementDriver
+        + ble_advertising::BleConfig
+        + ble_advertising::BleLinkLayerRadio
+        + 'a,
+    A: kernel::hil::time::Alarm + 'a,
+{
+    fn transmit_event(&self, result: ReturnCode) {
+        if self.upper_active.get().is_some() {
+            self.end_upper_operation();
+            self.upper_tx_client
+                .get()
+                .map(|client| client.transmit_event(result));
+            return;
+        }
+
+        match self.state.get() {
+            State::Advertising(channel) => {
+                // The radio is now listening for a CONNECT_REQ
+                self.state.set(State::WaitingForConnectRequest(channel));
+                self.alarm.set_alarm(
+                    self.alarm
+                        .now()
+                        .wrapping_add(us_to_ticks::<A::Frequency>(CONNECT_REQ_TIMEOUT_US)),
+                );
+            }
+            State::ConnectionTransmitting => {
+                if self.event_continues.get() {
+                    // The radio is now listening for the next packet of the master
+                    self.state.set(State::ConnectionReceiving);
+                    self.alarm.set_alarm(
+                        self.alarm
+                            .now()
+                            .wrapping_add(us_to_ticks::<A::Frequency>(T_IFS_US + RX_TIMEOUT_US)),
+                    );
+                } else {
+                    self.end_connection_event();
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<'a, R, A> ble_link_layer_hil::BleLinkLayer for LinkLayer<'a, R, A>
+where
+    R: ble_advertising::BleAdvertisementDriver
+        + ble_advertising::BleConfig
+        + ble_advertising::BleLinkLayerRadio
+        + 'a,
+    A: kernel::hil::time::Alarm + 'a,
+{
+    fn set_address(&self, address: [u8; 6]) {
+        self.address.set(address);
+        self.adv_buf
+            .map(|buf| buf[2..2 + ADDRESS_LENGTH].copy_from_slice(&address));
+    }
+
+    fn set_advertising_data(&self, data: &[u8]) -> ReturnCode {
+        let len = match ble_advertising_data::validate(data) {
+            Ok(len) => len,
+            Err(error) => return error,
+        };
+        let start = 2 + ADDRESS_LENGTH;
+        self.adv_buf
+            .map(|buf| {
+                buf[start..start + len].copy_from_slice(&data[..len]);
+                buf[1] = (ADDRESS_LENGTH + len) as u8;
+                self.adv_len.set(start + len);
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or(ReturnCode::EBUSY)
+    }
+
+    // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 4.4.2.2
+    fn set_advertising_interval(&self, interval_ms: u32) -> ReturnCode {
+        if interval_ms < 20 || interval_ms > 10240 {
+            return ReturnCode::EINVAL;
+        }
+        self.advertising_interval_ms.set(interval_ms);
+        ReturnCode::SUCCESS
+    }
+
+    fn start_advertising(&self) -> ReturnCode {
+        if self.connection.get().is_some() {
+            return ReturnCode::EBUSY;
+        }
+        if self.state.get() != State::Idle {
+            return ReturnCode::EALREADY;
+        }
+        self.random_nonce
+            .set(self.random_nonce.get() ^ self.alarm.now());
+        self.state.set(State::AdvertisingIdle);
+        self.alarm.set_alarm(
+            self.alarm
+                .now()
+                .wrapping_add(us_to_ticks::<A::Frequency>(self.random_nonce() % 10_000)),
+        );
+        ReturnCode::SUCCESS
+    }
+
+    fn stop_advertising(&self) -> ReturnCode {
+        match self.state.get() {
+            State::AdvertisingIdle => {
+                self.alarm.disable();
+                self.state.set(State::Idle);
+                ReturnCode::SUCCESS
+            }
+            State::Advertising(_) | State::WaitingForConnectRequest(_) => {
+                self.radio.disable();
+                self.alarm.disable();
+                self.state.set(State::Idle);
+                self.start_upper_operation();
+                ReturnCode::SUCCESS
+            }
+            _ => ReturnCode::EALREADY,
+        }
+    }
+
+    fn transmit(
+        &self,
+        buf: &'static mut [u8],
+        len: usize,
+        pdu_type: DataPduType,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if self.connection.get().is_none() {
+            return (ReturnCode::EOFF, Some(buf));
+        }
+        if len > ble_link_layer_hil::MAX_DATA_PAYLOAD_LENGTH || len > buf.len() {
+            return (ReturnCode::ESIZE, Some(buf));
+        }
+        if self.data_buf.is_some() {
+            return (ReturnCode::EBUSY, Some(buf));
+        }
+        self.data_buf.replace(buf);
+        self.data_len.set(len);
+        self.data_type.set(pdu_type);
+        (ReturnCode::SUCCESS, None)
+    }
+
+    // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 5.1.3
+    // Termination Procedure
+    fn disconnect(&self) -> ReturnCode {
+        if self.connection.get().is_none() {
+            return ReturnCode::EOFF;
+        }
+        let terminating = match (self.control_pdu.get(), self.tx_pdu.get()) {
+            (Some(ControlPdu::TerminateInd(_)), _)
+            | (_, TxPdu::Control(ControlPdu::TerminateInd(_))) => true,
+            _ => false,
+        };
+        if terminating {
+            return ReturnCode::EALREADY;
+        }
+        self.control_pdu.set(Some(ControlPdu::TerminateInd(
+            ble_link_layer_hil::REMOTE_USER_TERMINATED_CONNECTION,
+        )));
+        ReturnCode::SUCCESS
+    }
+
+    fn get_connection_parameters(&self) -> Option<ConnectionParameters> {
+        self.connection
+            .get()
+            .map(|connection| connection.parameters)
+    }
+
+    fn set_client(&self, client: &'static ble_link_layer_hil::ConnectionClient) {
+        self.client.set(Some(client));
+    }
+}
+
+// The advertising driver uses the radio through the link layer
+impl<'a, R, A> ble_advertising::BleAdvertisementDriver for LinkLayer<'a, R, A>
+where
+    R: ble_advertising::BleAdvertisementDriver
+        + ble_advertising::BleConfig
+        + ble_advertising::BleLinkLayerRadio
+        + 'a,
+    A: kernel::hil::time::Alarm + 'a,
+{
+    fn transmit_advertisement(
+        &self,
+        buf: &'static mut [u8],
+        len: usize,
+        channel: RadioChannel,
+    ) -> &'static mut [u8] {
+        // The packet may only be sent once the radio is free, so keep a copy
+        let len = cmp::min(len, cmp::min(buf.len(), PACKET_LENGTH));
+        self.upper_buf
+            .map(|upper| upper[..len].copy_from_slice(&buf[..len]));
+        self.upper_pending
+            .set(Some(UpperOperation::Transmit(len, channel)));
+        self.start_upper_operation();
+        buf
+    }
+
+    fn receive_advertisement(&self, channel: RadioChannel) {
+        self.upper_pending
+            .set(Some(UpperOperation::Receive(channel)));
+        self.start_upper_operation();
+    }
+
+    fn set_receive_client(&self, client: &'static ble_advertising::RxClient) {
+        self.upper_rx_client.set(Some(client));
+    }
+
+    fn set_transmit_client(&self, client: &'static ble_advertising::TxClient) {
+        self.upper_tx_client.set(Some(client));
+    }
+}
+
+impl<'a, R, A> ble_advertising::BleConfig for LinkLayer<'a, R, A>
+where
+    R: ble_advertising::BleAdvertisementDriver
+        + ble_advertising::BleConfig
+        + ble_advertising::BleLinkLayerRadio
+        + 'a,
+    A: kernel::hil::time::Alarm + 'a,
+{
+    // The power is validated by the radio, and used for the operations of the
+    // advertising driver only
+    fn set_tx_power(&self, power: u8) -> ReturnCode {
+        let result = self.radio.set_tx_power(power);
+        if result == ReturnCode::SUCCESS {
+            self.upper_tx_power.set(power);
+        }
+        result
+    }
+}
+
+// The advertising driver answers packets T_IFS after them, like the link layer
+impl<'a, R, A> ble_advertising::BleLinkLayerRadio for LinkLayer<'a, R, A>
+where
+    R: ble_advertising::BleAdvertisementDriver
+        + ble_advertising::BleConfig
+        + ble_advertising::BleLinkLayerRadio
+        + 'a,
+    A: kernel::hil::time::Alarm + 'a,
+{
+    // The operations of the advertising driver always use the advertising
+    // access address
+    fn set_access_address(&self, _access_address: u32, _crc_init: u32) {}
+
+    fn set_turnaround(&self, turnaround: Turnaround) {
+        self.upper_turnaround.set(turnaround);
+        if self.upper_active.get().is_some() {
+            self.radio.set_turnaround(turnaround);
+        }
+    }
+
+    fn set_response(&self, buf: &'static mut [u8], len: usize) -> &'static mut [u8] {
+        if self.upper_active.get().is_some() {
+            self.radio.set_response(buf, len)
+        } else {
+            buf
+        }
+    }
+
+    fn disable(&self) {
+        self.upper_pending.set(None);
+        self.upper_turnaround.set(Turnaround::Disable);
+        if self.upper_active.get().is_some() {
+            self.upper_active.set(None);
+            self.radio.disable();
+        }
+    }
+}
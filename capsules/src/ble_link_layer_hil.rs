@@ -0,0 +1,114 @@
+//! Bluetooth Low Energy Link Layer HIL, peripheral role
+//!
+//! The interface between the link layer, which advertises with connectable
+//! undirected advertising events and maintains connections with a central
+//! device, and the higher layers of the host (L2CAP and above).
+//!
+//! Higher layers only exchange the payloads of LL data PDUs, i.e. fragments of
+//! L2CAP PDUs of up to `MAX_DATA_PAYLOAD_LENGTH` bytes. LL control PDUs, which
+//! update the connection parameters or the channel map and terminate the
+//! connection, are handled by the link layer itself.
+//!
+//! BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.4 Data Channel PDU
+//!
+//! ```
+//!   PDU     +----------+      +---------------+      +-------------+
+//!           | Header   |  -   | Payload       |  -   | MIC         |
+//!           | (2 bytes)|      | (0-27 bytes)  |      | (not used)  |
+//!           +----------+      +---------------+      +-------------+
+//! ```
+
+use kernel::ReturnCode;
+
+/// Largest payload of a data channel PDU without the data length extension
+pub const MAX_DATA_PAYLOAD_LENGTH: usize = 27;
+
+/// Largest payload of an `ADV_IND`, not counting the advertiser address
+pub const MAX_ADVERTISING_DATA_LENGTH: usize = 31;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 2, Part D], section 1.3 List of Error Codes
+pub const CONNECTION_TIMEOUT: u8 = 0x08;
+pub const REMOTE_USER_TERMINATED_CONNECTION: u8 = 0x13;
+pub const CONNECTION_TERMINATED_BY_LOCAL_HOST: u8 = 0x16;
+pub const INVALID_LL_PARAMETERS: u8 = 0x1E;
+pub const INSTANT_PASSED: u8 = 0x28;
+pub const CONNECTION_FAILED_TO_BE_ESTABLISHED: u8 = 0x3E;
+
+/// LLID of a data channel PDU carrying data for the higher layers
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum DataPduType {
+    /// Continuation fragment of an L2CAP message
+    Continuation = 0x01,
+    /// Start of an L2CAP message, or a complete L2CAP message
+    Start = 0x02,
+}
+
+/// Parameters of a connection, set by the master
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct ConnectionParameters {
+    /// Connection interval in units of 1.25 ms
+    pub interval: u16,
+    /// Number of connection events the slave may skip
+    pub latency: u16,
+    /// Supervision timeout in units of 10 ms
+    pub timeout: u16,
+}
+
+pub trait BleLinkLayer {
+    /// Sets the static random device address used for advertising and
+    /// connections, in over-the-air order (least significant byte first)
+    fn set_address(&self, address: [u8; 6]);
+
+    /// Sets the AD structures advertised in `ADV_IND`s, which may be followed
+    /// by zero padding. Returns `ESIZE` if they do not fit into 31 bytes and
+    /// `EINVAL` if they are malformed.
+    fn set_advertising_data(&self, data: &[u8]) -> ReturnCode;
+
+    /// Sets the time between two advertising events, between 20 ms and
+    /// 10.24 s
+    fn set_advertising_interval(&self, interval_ms: u32) -> ReturnCode;
+
+    /// Starts connectable undirected advertising, which stops once a master
+    /// connects
+    fn start_advertising(&self) -> ReturnCode;
+
+    fn stop_advertising(&self) -> ReturnCode;
+
+    /// Queues a data channel PDU for transmission in the connection. Only one
+    /// PDU can be queued at a time, and `ConnectionClient::transmit_done` is
+    /// called once the master acknowledged it.
+    fn transmit(
+        &self,
+        buf: &'static mut [u8],
+        len: usize,
+        pdu_type: DataPduType,
+    ) -> (ReturnCode, Option<&'static mut [u8]>);
+
+    /// Terminates the connection with the LL termination procedure
+    fn disconnect(&self) -> ReturnCode;
+
+    /// Returns the parameters of the current connection, if any
+    fn get_connection_parameters(&self) -> Option<ConnectionParameters>;
+
+    fn set_client(&self, client: &'static ConnectionClient);
+}
+
+pub trait ConnectionClient {
+    /// Called when a master accepted an advertisement and created a
+    /// connection, which stops advertising
+    fn connected(&self, peer_address: [u8; 6], parameters: ConnectionParameters);
+
+    /// Called when the master changed the connection parameters
+    fn parameters_updated(&self, parameters: ConnectionParameters);
+
+    /// Called for each non-empty data channel PDU received from the master
+    fn receive(&self, data: &[u8], pdu_type: DataPduType);
+
+    /// Called when the master acknowledged the PDU queued with `transmit`, or
+    /// with `ReturnCode::FAIL` if the connection was closed before
+    fn transmit_done(&self, buf: &'static mut [u8], result: ReturnCode);
+
+    /// Called when the connection is closed, with the error code explaining
+    /// why
+    fn disconnected(&self, reason: u8);
+}
@@ -0,0 +1,399 @@
+//! Captures the IEEE 802.15.4 frames sent and received by a node and streams
+//! them over a UART in the pcap-ng format, so that they can be inspected with
+//! Wireshark.
+//!
+//! `Capture` is a pass-through Mac layer that is inserted between a Mac layer
+//! and the `MacDevice` above it. Every frame that it passes up or down is
+//! written to the UART as a pcap-ng Enhanced Packet Block, with the
+//! `LINKTYPE_IEEE802_15_4_TAP` link type so that the channel, and the RSSI
+//! and LQI of received frames, are recorded along with the frame. Frames are
+//! captured without their FCS. Received frames are timestamped with the time
+//! at which the radio received them if it reports it, so the alarm should
+//! share the time base of the radio's alarm.
+//!
+//! `start` writes the pcap-ng Section Header Block and Interface Description
+//! Block, which `pcapng` shares with the host tool, and frames are captured
+//! from then on. The blocks are queued in two buffers provided by the board:
+//! one is written to the UART while frames are queued in the other. When a frame does not fit, it is dropped, and the
+//! number of frames dropped is recorded in the next captured block. The
+//! `tools/ieee802154-capture` host tool turns the stream into a `.pcapng` file.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let capture_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let capture = static_init!(
+//!     capsules::ieee802154::capture::Capture<
+//!         'static,
+//!         AwakeMac<'static, RF233Device, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!         sam4l::usart::USART,
+//!         VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+//!     >,
+//!     capsules::ieee802154::capture::Capture::new(
+//!         awake_mac,
+//!         &sam4l::usart::USART0,
+//!         921600,
+//!         capture_alarm,
+//!         &mut CAPTURE_BUF1,
+//!         &mut CAPTURE_BUF2
+//!     )
+//! );
+//! awake_mac.set_transmit_client(capture);
+//! awake_mac.set_receive_client(capture);
+//! hil::uart::UART::set_client(&sam4l::usart::USART0, capture);
+//! capture.start();
+//! ```
+
+use core::cell::Cell;
+use core::cmp::{max, min};
+use ieee802154::mac::Mac;
+use ieee802154::pcapng::*;
+use kernel::ReturnCode;
+use kernel::common::take_cell::TakeCell;
+use kernel::hil::radio;
+use kernel::hil::time::{Alarm, Frequency};
+use kernel::hil::uart::{self, UART};
+
+/// Directions of the `epb_flags` option
+const EPB_FLAGS_INBOUND: u32 = 1;
+const EPB_FLAGS_OUTBOUND: u32 = 2;
+
+/// TAP TLV types
+const TAP_FCS_TYPE: u16 = 0;
+const TAP_RSS: u16 = 1;
+const TAP_CHANNEL_ASSIGNMENT: u16 = 3;
+const TAP_LQI: u16 = 10;
+
+/// The FCS type of frames captured without their FCS
+const TAP_FCS_NONE: u8 = 0;
+
+/// The size of the largest block written, which is an Enhanced Packet Block
+/// with all of its options carrying a received frame of the maximum size
+const MAX_BLOCK_SIZE: usize = 28 + MAX_TAP_HEADER_SIZE + radio::MAX_FRAME_SIZE + 3 + 28;
+
+/// The size of the TAP header with the FCS type, RSS, channel and LQI TLVs
+const MAX_TAP_HEADER_SIZE: usize = 4 + 4 * 8;
+
+/// The single-precision IEEE 754 representation of the integer `dbm`, which
+/// the TAP RSS field expects. `f32::to_bits` is not available in `core`, but
+/// RSSI values are small integers and are encoded exactly.
+fn dbm_f32_bits(dbm: i8) -> u32 {
+    if dbm == 0 {
+        return 0;
+    }
+    let sign = if dbm < 0 { 1 << 31 } else { 0 };
+    let magnitude = (dbm as i32).abs() as u32;
+    let exponent = 31 - magnitude.leading_zeros();
+    let mantissa = (magnitude << (23 - exponent)) & 0x7fffff;
+    sign | ((exponent + 127) << 23) | mantissa
+}
+
+pub struct Capture<'a, M: Mac + 'a, U: UART + 'a, A: Alarm + 'a> {
+    mac: &'a M,
+    uart: &'a U,
+    baud_rate: u32,
+    alarm: &'a A,
+    tx_client: Cell<Option<&'static radio::TxClient>>,
+    rx_client: Cell<Option<&'static radio::RxClient>>,
+
+    // The frame being transmitted, which is captured once it has been sent
+    tx_len: Cell<usize>,
+
+    started: Cell<bool>,
+    // The buffer in which blocks are queued, and the buffer being written to
+    // the UART, which is absent during a write
+    queue: TakeCell<'static, [u8]>,
+    queue_len: Cell<usize>,
+    spare: TakeCell<'static, [u8]>,
+    dropped: Cell<u64>,
+
+    // The time of the last captured frame, in alarm tics since the capture
+    // started, and as returned by the alarm, to extend it past 32 bits
+    elapsed: Cell<u64>,
+    last_time: Cell<u32>,
+}
+
+impl<'a, M: Mac + 'a, U: UART + 'a, A: Alarm + 'a> Capture<'a, M, U, A> {
+    pub fn new(
+        mac: &'a M,
+        uart: &'a U,
+        baud_rate: u32,
+        alarm: &'a A,
+        buf1: &'static mut [u8],
+        buf2: &'static mut [u8],
+    ) -> Capture<'a, M, U, A> {
+        Capture {
+            mac: mac,
+            uart: uart,
+            baud_rate: baud_rate,
+            alarm: alarm,
+            tx_client: Cell::new(None),
+            rx_client: Cell::new(None),
+            tx_len: Cell::new(0),
+            started: Cell::new(false),
+            queue: TakeCell::new(buf1),
+            queue_len: Cell::new(0),
+            spare: TakeCell::new(buf2),
+            dropped: Cell::new(0),
+            elapsed: Cell::new(0),
+            last_time: Cell::new(0),
+        }
+    }
+
+    /// Initializes the UART, writes the pcap-ng headers and starts capturing
+    /// frames
+    pub fn start(&self) {
+        if self.started.get() {
+            return;
+        }
+        self.uart.init(uart::UARTParams {
+            baud_rate: self.baud_rate,
+            stop_bits: uart::StopBits::One,
+            parity: uart::Parity::None,
+            hw_flow_control: false,
+        });
+        self.started.set(true);
+        self.last_time.set(self.alarm.now());
+
+        let mut headers = [0; HEADERS_SIZE];
+        let len = write_headers(&mut headers);
+        self.enqueue(&headers[..len]);
+    }
+
+    /// Converts a time of the alarm into microseconds since the capture
+    /// started. Received frames can be slightly older than the last captured
+    /// frame, so time may go backwards.
+    fn timestamp_us(&self, time: u32) -> u64 {
+        let delta = time.wrapping_sub(self.last_time.get()) as i32 as i64;
+        let elapsed = max(self.elapsed.get() as i64 + delta, 0) as u64;
+        self.elapsed.set(elapsed);
+        self.last_time.set(time);
+        elapsed * 1_000_000 / <A::Frequency>::frequency() as u64
+    }
+
+    /// Queues an Enhanced Packet Block for the frame in `buf`, with the RSSI
+    /// and LQI of received frames
+    fn capture(&self, buf: &[u8], frame_len: usize, rx_info: Option<radio::RxInfo>) {
+        if !self.started.get() {
+            return;
+        }
+        let time = rx_info
+            .and_then(|info| info.timestamp)
+            .unwrap_or_else(|| self.alarm.now());
+        let timestamp = self.timestamp_us(time);
+        let frame_len = min(frame_len, buf.len() - radio::PSDU_OFFSET);
+        let frame = &buf[radio::PSDU_OFFSET..radio::PSDU_OFFSET + frame_len];
+
+        let mut tap_header = [0; MAX_TAP_HEADER_SIZE];
+        let tap_len = {
+            let mut writer = BlockWriter::new(&mut tap_header);
+            writer.u8(0);
+            writer.u8(0);
+            writer.u16(0);
+            writer.option(TAP_FCS_TYPE, &[TAP_FCS_NONE]);
+            if let Some(info) = rx_info {
+                writer.option(TAP_RSS, &le_u32(dbm_f32_bits(info.rssi)));
+            }
+            let channel = self.mac.get_channel();
+            writer.option(TAP_CHANNEL_ASSIGNMENT, &[channel, 0, 0]);
+            if let Some(info) = rx_info {
+                writer.option(TAP_LQI, &[info.lqi]);
+            }
+            let len = writer.offset;
+            writer.buf[2..4].copy_from_slice(&[len as u8, (len >> 8) as u8]);
+            len
+        };
+
+        let mut block = [0; MAX_BLOCK_SIZE];
+        let len = {
+            let mut writer = BlockWriter::new(&mut block);
+            writer.u32(ENHANCED_PACKET_BLOCK);
+            writer.u32(0);
+            // Interface ID
+            writer.u32(0);
+            writer.u32((timestamp >> 32) as u32);
+            writer.u32(timestamp as u32);
+            let packet_len = (tap_len + frame_len) as u32;
+            writer.u32(packet_len);
+            writer.u32(packet_len);
+            writer.bytes(&tap_header[..tap_len]);
+            writer.bytes(frame);
+            writer.pad();
+            let direction = if rx_info.is_some() {
+                EPB_FLAGS_INBOUND
+            } else {
+                EPB_FLAGS_OUTBOUND
+            };
+            writer.option(EPB_FLAGS, &le_u32(direction));
+            if self.dropped.get() > 0 {
+                writer.option(EPB_DROPCOUNT, &le_u64(self.dropped.get()));
+            }
+            writer.option(OPT_ENDOFOPT, &[]);
+            writer.finish_block()
+        };
+        if self.enqueue(&block[..len]) {
+            self.dropped.set(0);
+        } else {
+            self.dropped.set(self.dropped.get() + 1);
+        }
+    }
+
+    /// Queues a block to be written to the UART. Returns false if there is no
+    /// room for it.
+    fn enqueue(&self, block: &[u8]) -> bool {
+        let queued = self.queue.map_or(false, |queue| {
+            let start = self.queue_len.get();
+            if start + block.len() > queue.len() {
+                return false;
+            }
+            queue[start..start + block.len()].copy_from_slice(block);
+            self.queue_len.set(start + block.len());
+            true
+        });
+        if queued {
+            self.flush();
+        }
+        queued
+    }
+
+    /// Writes the queued blocks to the UART unless it is busy
+    fn flush(&self) {
+        if self.queue_len.get() == 0 || self.spare.is_none() {
+            return;
+        }
+        let next_queue = self.spare.take();
+        self.queue.take().map(|queue| {
+            self.uart.transmit(queue, self.queue_len.get());
+        });
+        self.queue_len.set(0);
+        next_queue.map(|next_queue| self.queue.replace(next_queue));
+    }
+}
+
+impl<'a, M: Mac + 'a, U: UART + 'a, A: Alarm + 'a> Mac for Capture<'a, M, U, A> {
+    fn initialize(&self, mac_buf: &'static mut [u8]) -> ReturnCode {
+        self.mac.initialize(mac_buf)
+    }
+
+    fn set_config_client(&self, client: &'static radio::ConfigClient) {
+        self.mac.set_config_client(client)
+    }
+
+    fn set_transmit_client(&self, client: &'static radio::TxClient) {
+        self.tx_client.set(Some(client));
+    }
+
+    fn set_receive_client(&self, client: &'static radio::RxClient) {
+        self.rx_client.set(Some(client));
+    }
+
+    fn set_receive_buffer(&self, buffer: &'static mut [u8]) {
+        self.mac.set_receive_buffer(buffer)
+    }
+
+    fn get_address(&self) -> u16 {
+        self.mac.get_address()
+    }
+
+    fn get_address_long(&self) -> [u8; 8] {
+        self.mac.get_address_long()
+    }
+
+    fn get_pan(&self) -> u16 {
+        self.mac.get_pan()
+    }
+
+    fn get_channel(&self) -> u8 {
+        self.mac.get_channel()
+    }
+
+    fn set_address(&self, addr: u16) {
+        self.mac.set_address(addr)
+    }
+
+    fn set_address_long(&self, addr: [u8; 8]) {
+        self.mac.set_address_long(addr)
+    }
+
+    fn set_pan(&self, id: u16) {
+        self.mac.set_pan(id)
+    }
+
+    fn set_channel(&self, chan: u8) -> ReturnCode {
+        self.mac.set_channel(chan)
+    }
+
+    fn set_ack_frame_pending(&self, pending: bool) {
+        self.mac.set_ack_frame_pending(pending)
+    }
+
+    fn config_commit(&self) {
+        self.mac.config_commit()
+    }
+
+    fn is_on(&self) -> bool {
+        self.mac.is_on()
+    }
+
+    fn transmit(
+        &self,
+        full_mac_frame: &'static mut [u8],
+        frame_len: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        self.tx_len.set(frame_len);
+        self.mac.transmit(full_mac_frame, frame_len)
+    }
+}
+
+impl<'a, M: Mac + 'a, U: UART + 'a, A: Alarm + 'a> radio::TxClient for Capture<'a, M, U, A> {
+    fn send_done(&self, buf: &'static mut [u8], acked: bool, result: ReturnCode) {
+        self.send_done_info(buf, acked, result, radio::TxInfo::default());
+    }
+
+    fn send_done_info(
+        &self,
+        buf: &'static mut [u8],
+        acked: bool,
+        result: ReturnCode,
+        info: radio::TxInfo,
+    ) {
+        // Frames that could not be sent never made it to the air
+        if result == ReturnCode::SUCCESS || result == ReturnCode::ENOACK {
+            self.capture(buf, self.tx_len.get(), None);
+        }
+        self.tx_client.get().map(move |c| {
+            c.send_done_info(buf, acked, result, info);
+        });
+    }
+}
+
+impl<'a, M: Mac + 'a, U: UART + 'a, A: Alarm + 'a> radio::RxClient for Capture<'a, M, U, A> {
+    fn receive(
+        &self,
+        buf: &'static mut [u8],
+        frame_len: usize,
+        crc_valid: bool,
+        info: radio::RxInfo,
+        result: ReturnCode,
+    ) {
+        if crc_valid && result == ReturnCode::SUCCESS {
+            self.capture(buf, frame_len, Some(info));
+        }
+        self.rx_client.get().map(move |c| {
+            c.receive(buf, frame_len, crc_valid, info, result);
+        });
+    }
+}
+
+impl<'a, M: Mac + 'a, U: UART + 'a, A: Alarm + 'a> uart::Client for Capture<'a, M, U, A> {
+    fn transmit_complete(&self, buffer: &'static mut [u8], _error: uart::Error) {
+        self.spare.replace(buffer);
+        self.flush();
+    }
+
+    fn receive_complete(&self, _buffer: &'static mut [u8], _rx_len: usize, _error: uart::Error) {}
+}
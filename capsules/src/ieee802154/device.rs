@@ -13,7 +13,8 @@
 
 use ieee802154::framer::Frame;
 use kernel::ReturnCode;
-use net::ieee802154::{Header, KeyId, MacAddress, PanID, SecurityLevel};
+use kernel::hil::radio;
+use net::ieee802154::{Header, KeyId, MacAddress, PanID, SecurityLevel, SuperframeSpec};
 
 pub trait MacDevice<'a> {
     /// Sets the transmission client of this MAC device
@@ -27,6 +28,8 @@ pub trait MacDevice<'a> {
     fn get_address_long(&self) -> [u8; 8];
     /// The 16-bit PAN ID of the MAC device
     fn get_pan(&self) -> u16;
+    /// The 802.15.4 channel of the MAC device
+    fn get_channel(&self) -> u8;
 
     /// Set the short 16-bit address of the MAC device
     fn set_address(&self, addr: u16);
@@ -34,11 +37,18 @@ pub trait MacDevice<'a> {
     fn set_address_long(&self, addr: [u8; 8]);
     /// Set the 16-bit PAN ID of the MAC device
     fn set_pan(&self, id: u16);
+    /// Set the 802.15.4 channel of the MAC device. Returns EINVAL if the
+    /// channel is not supported by the radio.
+    fn set_channel(&self, chan: u8) -> ReturnCode;
+    /// Set whether the acknowledgements sent in response to data requests
+    /// indicate that frames are pending for the polling device. This is
+    /// used by coordinators that hold frames for indirect transmission.
+    fn set_ack_frame_pending(&self, pending: bool);
 
     /// This method must be called after one or more calls to `set_*`. If
     /// `set_*` is called without calling `config_commit`, there is no guarantee
     /// that the underlying hardware configuration (addresses, pan ID) is in
-    /// line with this MAC device implementation.
+    /// line with this MAC device implementation. This includes the channel.
     fn config_commit(&self);
 
     /// Returns if the MAC device is currently on.
@@ -69,6 +79,55 @@ pub trait MacDevice<'a> {
         security_needed: Option<(SecurityLevel, KeyId)>,
     ) -> Result<Frame, &'static mut [u8]>;
 
+    /// Prepares a mutable buffer slice as an 802.15.4 beacon frame. The
+    /// superframe specification is written into the frame, followed by empty
+    /// GTS and pending address fields, so that the frame can be transmitted as
+    /// is or have a beacon payload appended to it. Beacons are not secured.
+    ///
+    /// - `buf`: The mutable buffer slice to use
+    /// - `src_pan`: The PAN ID of the coordinator sending the beacon
+    /// - `src_addr`: The MAC address of the coordinator sending the beacon
+    /// - `superframe_spec`: The superframe specification of the PAN
+    ///
+    /// Returns either a Frame that is ready to be transmitted, or the mutable
+    /// buffer if the frame cannot be prepared for any reason
+    fn prepare_beacon_frame(
+        &self,
+        buf: &'static mut [u8],
+        src_pan: PanID,
+        src_addr: MacAddress,
+        superframe_spec: SuperframeSpec,
+    ) -> Result<Frame, &'static mut [u8]>;
+
+    /// Prepares a mutable buffer slice as an 802.15.4 MAC command frame. The
+    /// command identifier is written as the first byte of the payload, and the
+    /// command content must then be appended to the frame. Acknowledgement is
+    /// requested unless the frame is broadcast.
+    ///
+    /// - `buf`: The mutable buffer slice to use
+    /// - `dst_pan`: The destination PAN ID
+    /// - `dst_addr`: The destination MAC address
+    /// - `src_pan`: The source PAN ID, ignored if there is no source address
+    /// - `src_addr`: The source MAC address. Some commands, such as the beacon
+    /// request, omit it.
+    /// - `command_id`: The command frame identifier, from
+    /// `net::ieee802154::mac_command`
+    /// - `security_needed`: Whether or not this frame should be secured
+    ///
+    /// Returns either a Frame that is ready to have the command content
+    /// appended to it, or the mutable buffer if the frame cannot be prepared
+    /// for any reason
+    fn prepare_command_frame(
+        &self,
+        buf: &'static mut [u8],
+        dst_pan: PanID,
+        dst_addr: MacAddress,
+        src_pan: PanID,
+        src_addr: Option<MacAddress>,
+        command_id: u8,
+        security_needed: Option<(SecurityLevel, KeyId)>,
+    ) -> Result<Frame, &'static mut [u8]>;
+
     /// Transmits a frame that has been prepared by the above process. If the
     /// transmission process fails, the buffer inside the frame is returned so
     /// that it can be re-used.
@@ -89,6 +148,22 @@ pub trait TxClient {
     /// - `result`: This is `ReturnCode::SUCCESS` if the frame was transmitted,
     /// otherwise an error occured in the transmission pipeline.
     fn send_done(&self, spi_buf: &'static mut [u8], acked: bool, result: ReturnCode);
+
+    /// Like `send_done`, but also reports the retransmissions and busy
+    /// channels counted by the MAC layer, if it performs them in software.
+    /// The default implementation discards them.
+    ///
+    /// - `info`: The number of retransmissions of the frame, and of clear
+    /// channel assessments that found the channel busy.
+    fn send_done_info(
+        &self,
+        spi_buf: &'static mut [u8],
+        acked: bool,
+        result: ReturnCode,
+        _info: radio::TxInfo,
+    ) {
+        self.send_done(spi_buf, acked, result);
+    }
 }
 
 /// Trait to be implemented by users of the IEEE 802.15.4 device that wish to
@@ -106,9 +181,45 @@ pub trait RxClient {
     /// - `header`: A fully-parsed representation of the MAC header, with the
     /// caveat that the auxiliary security header is still included if the frame
     /// was previously secured.
+    /// - `info`: The RSSI, LQI and timestamp of the frame, as measured by the
+    /// radio.
     /// - `data_offset`: Offset of the data payload relative to
     /// `buf`, so that the payload of the frame is contained in
     /// `buf[data_offset..data_offset + data_len]`.
     /// - `data_len`: Length of the data payload
-    fn receive<'a>(&self, buf: &'a [u8], header: Header<'a>, data_offset: usize, data_len: usize);
+    fn receive<'a>(
+        &self,
+        buf: &'a [u8],
+        header: Header<'a>,
+        info: radio::RxInfo,
+        data_offset: usize,
+        data_len: usize,
+    );
+
+    /// This callback is triggered instead of `receive` when a secured frame
+    /// is dropped because it failed the incoming frame security procedure,
+    /// for example because it was replayed or its MIC was invalid. Only the
+    /// header of the frame is exposed, as the payload cannot be trusted.
+    ///
+    /// - `header`: The MAC header of the dropped frame
+    /// - `error`: The step of the security procedure that failed
+    fn receive_security_failure<'a>(&self, header: Header<'a>, error: SecurityError);
+}
+
+/// Reasons for which a received frame can fail the incoming frame security
+/// procedure (IEEE 802.15.4-2015, 9.2.3)
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum SecurityError {
+    /// The frame is secured with the unsupported IEEE 802.15.4-2003 security
+    UnsupportedLegacy,
+    /// No key matches the auxiliary security header of the frame
+    UnavailableKey,
+    /// The extended address of the source device is unknown
+    UnavailableDevice,
+    /// The frame counter is exhausted, or not greater than the frame counter
+    /// of the last frame accepted from the source device, so the frame may
+    /// have been replayed
+    CounterError,
+    /// The MIC of the frame is invalid
+    MicFailure,
 }
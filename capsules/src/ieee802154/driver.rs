@@ -2,19 +2,52 @@
 //!
 //! Implements a userspace interface for sending and receiving IEEE 802.15.4
 //! frames. Also provides a minimal list-based interface for managing keys and
-//! known link neighbors, which is needed for 802.15.4 security.
+//! known link neighbors, which is needed for 802.15.4 security, and, if an
+//! MLME is provided with `set_mlme`, for scanning channels for PANs, acting
+//! as a PAN coordinator, associating with a PAN, and exchanging frames with
+//! sleepy devices through indirect transmission. If the MAC layer
+//! duty-cycles the radio and is provided with `set_duty_cycle`, its energy
+//! usage statistics are also exposed. The RSSI, LQI and timestamp of each
+//! received frame can be queried, as can the link quality estimates of each
+//! neighbor if a `NeighborTable` is provided with `set_neighbor_table`.
 
 use core::cell::Cell;
 use core::cmp::min;
-use ieee802154::{device, framer};
+use ieee802154::{device, framer, mac, mlme};
+use ieee802154::neighbors::{LinkStats, NeighborTable};
 use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
 use kernel::common::take_cell::{MapCell, TakeCell};
+use kernel::hil::radio;
 use net::ieee802154::{AddressMode, Header, KeyId, MacAddress, PanID, SecurityLevel};
+use net::ieee802154::BROADCAST_ADDR;
 use net::stream::{decode_bytes, encode_bytes, SResult, decode_u8, encode_u8};
 
 const MAX_NEIGHBORS: usize = 4;
 const MAX_KEYS: usize = 4;
 
+/// Size of a PAN descriptor in the config buffer after a scan
+const PAN_DESCRIPTOR_SIZE: usize = 15;
+
+/// Size of the coordinator description in the config buffer when associating
+const ASSOCIATE_CFG_SIZE: usize = 13;
+
+/// Size of the duty-cycle statistics in the config buffer
+const DUTY_CYCLE_STATS_SIZE: usize = 20;
+
+/// Size of the metadata of a received frame in the config buffer
+const RX_INFO_SIZE: usize = 7;
+
+/// Size of the link quality estimates of a neighbor in the config buffer
+const LINK_STATS_SIZE: usize = 13;
+
+/// The events reported to the association callback
+mod association_event {
+    pub const ASSOCIATE_DONE: usize = 0;
+    pub const DISASSOCIATED: usize = 1;
+    pub const DEVICE_ASSOCIATED: usize = 2;
+    pub const DEVICE_DISASSOCIATED: usize = 3;
+}
+
 /// Syscall number
 pub const DRIVER_NUM: usize = 0x30001;
 
@@ -22,6 +55,8 @@ pub const DRIVER_NUM: usize = 0x30001;
 struct DeviceDescriptor {
     short_addr: u16,
     long_addr: [u8; 8],
+    /// Frame counter of the last secured frame accepted from this neighbor
+    frame_counter: Option<u32>,
 }
 
 impl Default for DeviceDescriptor {
@@ -29,6 +64,7 @@ impl Default for DeviceDescriptor {
         DeviceDescriptor {
             short_addr: 0,
             long_addr: [0; 8],
+            frame_counter: None,
         }
     }
 }
@@ -73,6 +109,22 @@ fn encode_key_id(key_id: &KeyId, buf: &mut [u8]) -> SResult {
     stream_done!(off);
 }
 
+/// Decodes the security level and key ID of a transmission request, in the
+/// format produced by the userland driver.
+fn decode_tx_security(cfg: &[u8]) -> Option<Option<(SecurityLevel, KeyId)>> {
+    if cfg.len() != 11 {
+        return None;
+    }
+    let level = SecurityLevel::from_scf(cfg[0])?;
+    if level == SecurityLevel::None {
+        Some(None)
+    } else {
+        decode_key_id(&cfg[1..])
+            .done()
+            .map(|(_, key_id)| Some((level, key_id)))
+    }
+}
+
 /// Decodes a key ID that is in the format produced by the userland driver.
 fn decode_key_id(buf: &[u8]) -> SResult<KeyId> {
     stream_len_cond!(buf, 1);
@@ -147,10 +199,15 @@ impl KeyDescriptor {
 pub struct App {
     rx_callback: Option<Callback>,
     tx_callback: Option<Callback>,
+    scan_callback: Option<Callback>,
+    association_callback: Option<Callback>,
+    indirect_callback: Option<Callback>,
     app_read: Option<AppSlice<Shared, u8>>,
     app_write: Option<AppSlice<Shared, u8>>,
     app_cfg: Option<AppSlice<Shared, u8>>,
     pending_tx: Option<(u16, Option<(SecurityLevel, KeyId)>)>,
+    /// Metadata of the last frame received into `app_read`
+    rx_info: Option<radio::RxInfo>,
 }
 
 impl Default for App {
@@ -158,10 +215,14 @@ impl Default for App {
         App {
             rx_callback: None,
             tx_callback: None,
+            scan_callback: None,
+            association_callback: None,
+            indirect_callback: None,
             app_read: None,
             app_write: None,
             app_cfg: None,
             pending_tx: None,
+            rx_info: None,
         }
     }
 }
@@ -189,6 +250,18 @@ pub struct RadioDriver<'a> {
 
     /// Buffer that stores the IEEE 802.15.4 frame to be transmitted.
     kernel_tx: TakeCell<'static, [u8]>,
+
+    /// MAC layer management entity used for scans, coordinator mode and
+    /// indirect transmission.
+    mlme: Cell<Option<&'a mlme::Mlme<'a>>>,
+    /// ID of app whose scan is in progress.
+    scan_app: Cell<Option<AppId>>,
+
+    /// MAC layer that duty-cycles the radio, if any.
+    duty_cycle: Cell<Option<&'a mac::DutyCycle>>,
+
+    /// Link quality estimates of the neighbors, if they are tracked.
+    neighbor_table: Cell<Option<&'a NeighborTable>>,
 }
 
 impl<'a> RadioDriver<'a> {
@@ -206,21 +279,79 @@ impl<'a> RadioDriver<'a> {
             apps: grant,
             current_app: Cell::new(None),
             kernel_tx: TakeCell::new(kernel_tx),
+            mlme: Cell::new(None),
+            scan_app: Cell::new(None),
+            duty_cycle: Cell::new(None),
+            neighbor_table: Cell::new(None),
+        }
+    }
+
+    /// Sets the MAC layer management entity used to scan for PANs, to act as
+    /// a PAN coordinator, to associate with a PAN and to hold frames for
+    /// indirect transmission. The driver must also be set as its scan,
+    /// association and indirect client.
+    pub fn set_mlme(&self, mlme: &'a mlme::Mlme<'a>) {
+        self.mlme.set(Some(mlme));
+    }
+
+    /// Sets the MAC layer whose duty-cycle statistics are exposed to
+    /// userspace.
+    pub fn set_duty_cycle(&self, duty_cycle: &'a mac::DutyCycle) {
+        self.duty_cycle.set(Some(duty_cycle));
+    }
+
+    /// Sets the table of link quality estimates that is exposed to
+    /// userspace. It should be the table that the MAC device records the
+    /// link quality of frames in.
+    pub fn set_neighbor_table(&self, neighbor_table: &'a NeighborTable) {
+        self.neighbor_table.set(Some(neighbor_table));
+    }
+
+    /// Starts a scan on behalf of an app. Only one scan can be in progress at
+    /// a time.
+    fn scan(
+        &self,
+        appid: AppId,
+        scan_type: mlme::ScanType,
+        channels: u32,
+        duration: u8,
+    ) -> ReturnCode {
+        if self.scan_app.get().is_some() {
+            return ReturnCode::EBUSY;
+        }
+        let rval = self.mlme
+            .get()
+            .map_or(ReturnCode::ENOSUPPORT, |mlme| {
+                mlme.scan(scan_type, channels, duration)
+            });
+        if rval == ReturnCode::SUCCESS {
+            self.scan_app.set(Some(appid));
         }
+        rval
+    }
+
+    /// Reports an association event to all apps that subscribed to them
+    fn association_event(&self, event: usize, status: usize, short_addr: u16) {
+        self.apps.each(|app| {
+            app.association_callback
+                .take()
+                .map(|mut cb| cb.schedule(event, status, short_addr as usize));
+        });
     }
 
     // Neighbor management functions
 
     /// Add a new neighbor to the end of the list if there is still space
     /// for one, returning its new index. If the neighbor already exists,
-    /// returns the index of the existing neighbor. Returns `None` if there is
-    /// no remaining space.
+    /// returns the index of the existing neighbor, whose frame counter is
+    /// kept. Returns `None` if there is no remaining space.
     fn add_neighbor(&self, new_neighbor: DeviceDescriptor) -> Option<usize> {
         self.neighbors.and_then(|neighbors| {
             let num_neighbors = self.num_neighbors.get();
-            let position = neighbors[..num_neighbors]
-                .iter()
-                .position(|neighbor| *neighbor == new_neighbor);
+            let position = neighbors[..num_neighbors].iter().position(|neighbor| {
+                neighbor.short_addr == new_neighbor.short_addr
+                    && neighbor.long_addr == new_neighbor.long_addr
+            });
             match position {
                 Some(index) => Some(index),
                 None => {
@@ -506,6 +637,29 @@ impl<'a> framer::DeviceProcedure for RadioDriver<'a> {
                 .map(|neighbor| neighbor.long_addr)
         })
     }
+
+    /// Gets the frame counter of the last secured frame accepted from the
+    /// neighbor with the given long address.
+    fn lookup_frame_counter(&self, addr_long: [u8; 8]) -> Option<u32> {
+        self.neighbors.and_then(|neighbors| {
+            neighbors[..self.num_neighbors.get()]
+                .iter()
+                .find(|neighbor| neighbor.long_addr == addr_long)
+                .and_then(|neighbor| neighbor.frame_counter)
+        })
+    }
+
+    /// Records the frame counter of a secured frame accepted from the
+    /// neighbor with the given long address.
+    fn set_frame_counter(&self, addr_long: [u8; 8], frame_counter: u32) {
+        let num_neighbors = self.num_neighbors.get();
+        self.neighbors.map(|neighbors| {
+            neighbors[..num_neighbors]
+                .iter_mut()
+                .find(|neighbor| neighbor.long_addr == addr_long)
+                .map(|neighbor| neighbor.frame_counter = Some(frame_counter));
+        });
+    }
 }
 
 impl<'a> framer::KeyProcedure for RadioDriver<'a> {
@@ -552,7 +706,15 @@ impl<'a> Driver for RadioDriver<'a> {
     /// ### `subscribe_num`
     ///
     /// - `0`: Setup callback for when frame is received.
-    /// - `1`: Setup callback for when frame is transmitted.
+    /// - `1`: Setup callback for when frame is transmitted. The callback
+    ///        receives the result of the transmission, whether the frame was
+    ///        acknowledged, and the number of retransmissions and of busy
+    ///        channels found by the MAC layer, in bits 0-7 and 8-15 of its
+    ///        third argument.
+    /// - `2`: Setup callback for when a scan completes.
+    /// - `3`: Setup callback for when the association state changes.
+    /// - `4`: Setup callback for when a frame held for indirect transmission
+    ///        is sent or discarded.
     fn subscribe(&self, subscribe_num: usize, callback: Callback) -> ReturnCode {
         match subscribe_num {
             0 => self.do_with_app(callback.app_id(), |app| {
@@ -563,6 +725,18 @@ impl<'a> Driver for RadioDriver<'a> {
                 app.tx_callback = Some(callback);
                 ReturnCode::SUCCESS
             }),
+            2 => self.do_with_app(callback.app_id(), |app| {
+                app.scan_callback = Some(callback);
+                ReturnCode::SUCCESS
+            }),
+            3 => self.do_with_app(callback.app_id(), |app| {
+                app.association_callback = Some(callback);
+                ReturnCode::SUCCESS
+            }),
+            4 => self.do_with_app(callback.app_id(), |app| {
+                app.indirect_callback = Some(callback);
+                ReturnCode::SUCCESS
+            }),
             _ => ReturnCode::ENOSUPPORT,
         }
     }
@@ -616,7 +790,86 @@ impl<'a> Driver for RadioDriver<'a> {
     ///                      9 bytes: the key ID (might not use all bytes) +
     ///                      16 bytes: the key.
     /// - `25`: Remove the key at an index.
-    fn command(&self, command_num: usize, arg1: usize, _: usize, appid: AppId) -> ReturnCode {
+    /// - `26`: Transmit a frame to the given short address. The frame payload
+    ///        is taken from the write buffer.
+    ///        app_cfg (in): 1 byte: the security level +
+    ///                      1 byte: the key ID mode +
+    ///                      9 bytes: the key ID (might not use all bytes).
+    /// - `27`: Passive scan of the channels set in the mask given as the first
+    ///        argument, for a duration of `960 * (2^n + 1)` symbols per
+    ///        channel, where `n` is the second argument. When the scan
+    ///        completes, the scan callback receives the result, the number
+    ///        of PAN descriptors written into the config buffer, and the
+    ///        number of beacons dropped because they failed security.
+    ///        app_cfg (out): 15 bytes per PAN descriptor: 1 byte: the channel +
+    ///                       2 bytes: the PAN ID +
+    ///                       1 byte: the address mode +
+    ///                       8 bytes: the coordinator address +
+    ///                       2 bytes: the superframe specification +
+    ///                       1 byte: the LQI of the beacon.
+    ///                       Multi-byte fields are little-endian, except long
+    ///                       addresses, which are in the order of
+    ///                       command 9.
+    /// - `28`: Active scan, with the same arguments and results as `27`.
+    /// - `29`: Start acting as the coordinator of the configured PAN, with the
+    ///        given beacon order. Beacons are sent periodically if the beacon
+    ///        order is less than 15.
+    /// - `30`: Stop acting as a coordinator.
+    /// - `31`: Associate with a PAN coordinator, with the capability
+    ///        information given as the first argument. Once the coordinator
+    ///        responds, the association callback receives event `0`, the
+    ///        result, and the allocated short address.
+    ///        app_cfg (in): 13 bytes: the first 12 bytes of a PAN descriptor
+    ///                      (see `27`) +
+    ///                      1 byte: the capability information.
+    /// - `32`: Disassociate from the PAN coordinator. Once the coordinator is
+    ///        notified, the association callback receives event `1` and the
+    ///        disassociation reason. The callback also receives this event if
+    ///        the coordinator asks the device to leave.
+    /// - `33`: Set whether association requests are accepted while acting as
+    ///        a coordinator. When devices join or leave the PAN, the
+    ///        association callback receives event `2` or `3` respectively, 0,
+    ///        and the short address of the device.
+    /// - `34`: Ask the associated device with the given long address to leave
+    ///        the PAN.
+    ///        app_cfg (in): 8 bytes: the long MAC address.
+    /// - `35`: Get the duty-cycle statistics of the MAC layer since they were
+    ///        last reset.
+    ///        app_cfg (out): 20 bytes: 4 bytes: the time the radio was on, in ms +
+    ///                       4 bytes: the total time elapsed, in ms +
+    ///                       4 bytes: the number of preambles sent +
+    ///                       4 bytes: the number of successful rendezvous +
+    ///                       4 bytes: the current wake-up interval, in ms.
+    ///                       All fields are little-endian.
+    /// - `36`: Reset the duty-cycle statistics of the MAC layer.
+    /// - `37`: While acting as a coordinator, hold a frame for the device with
+    ///        the given short address until it polls for it. The frame
+    ///        payload is taken from the write buffer. Once the frame is sent
+    ///        or expires, the indirect callback receives the result, whether
+    ///        the frame was acknowledged, and the short address.
+    ///        app_cfg (in): as for `26`.
+    /// - `38`: Set the interval at which the coordinator is polled while
+    ///        associated, in milliseconds. 0 disables periodic polling.
+    /// - `39`: Poll the coordinator once.
+    /// - `40`: Get the metadata of the last frame received into the read
+    ///        buffer.
+    ///        app_cfg (out): 7 bytes: 1 byte: the RSSI, in dBm, signed +
+    ///                       1 byte: the LQI +
+    ///                       1 byte: 1 if the frame was timestamped, else 0 +
+    ///                       4 bytes: the time at which the start of the
+    ///                       frame was received, in tics of the radio's
+    ///                       alarm, little-endian.
+    /// - `41`: Get the link quality estimates of the neighbor at an index in
+    ///        the neighbor table. Neighbors are identified by address, so
+    ///        these are distinct from the neighbors of `13`-`18`.
+    ///        app_cfg (out): 13 bytes: 1 byte: the address mode +
+    ///                       8 bytes: the address (see `27`) +
+    ///                       1 byte: the average RSSI, in dBm, signed +
+    ///                       1 byte: the average LQI +
+    ///                       2 bytes: the ETX, multiplied by 128, or 0xffff
+    ///                       if no frame has been sent to the neighbor,
+    ///                       little-endian.
+    fn command(&self, command_num: usize, arg1: usize, arg2: usize, appid: AppId) -> ReturnCode {
         match command_num {
             0 => ReturnCode::SUCCESS,
             1 => {
@@ -640,8 +893,7 @@ impl<'a> Driver for RadioDriver<'a> {
                 self.mac.set_pan(arg1 as u16);
                 ReturnCode::SUCCESS
             }
-            // XXX: Setting channel DEPRECATED by MAC layer channel control
-            5 => ReturnCode::ENOSUPPORT,
+            5 => self.mac.set_channel(arg1 as u8),
             // XXX: Setting tx power DEPRECATED by MAC layer tx power control
             6 => ReturnCode::ENOSUPPORT,
             7 => {
@@ -666,8 +918,13 @@ impl<'a> Driver for RadioDriver<'a> {
                     value: (pan as usize) + 1,
                 }
             }
-            // XXX: Getting channel DEPRECATED by MAC layer channel control
-            11 => ReturnCode::ENOSUPPORT,
+            11 => {
+                // Guarantee that the channel is positive by adding 1
+                let channel = self.mac.get_channel();
+                ReturnCode::SuccessWithValue {
+                    value: (channel as usize) + 1,
+                }
+            }
             // XXX: Getting tx power DEPRECATED by MAC layer tx power control
             12 => ReturnCode::ENOSUPPORT,
             13 => {
@@ -747,29 +1004,10 @@ impl<'a> Driver for RadioDriver<'a> {
                         // Cannot support more than one pending tx per process.
                         return ReturnCode::EBUSY;
                     }
-                    let next_tx = app.app_cfg.as_ref().and_then(|cfg| {
-                        if cfg.len() != 11 {
-                            return None;
-                        }
-                        let dst_addr = arg1 as u16;
-                        let level = match SecurityLevel::from_scf(cfg.as_ref()[0]) {
-                            Some(level) => level,
-                            None => {
-                                return None;
-                            }
-                        };
-                        if level == SecurityLevel::None {
-                            Some((dst_addr, None))
-                        } else {
-                            let key_id = match decode_key_id(&cfg.as_ref()[1..]).done() {
-                                Some((_, key_id)) => key_id,
-                                None => {
-                                    return None;
-                                }
-                            };
-                            Some((dst_addr, Some((level, key_id))))
-                        }
-                    });
+                    let next_tx = app.app_cfg
+                        .as_ref()
+                        .and_then(|cfg| decode_tx_security(cfg.as_ref()))
+                        .map(|security_needed| (arg1 as u16, security_needed));
                     if next_tx.is_none() {
                         return ReturnCode::EINVAL;
                     }
@@ -778,6 +1016,102 @@ impl<'a> Driver for RadioDriver<'a> {
                     self.do_next_tx_sync(appid)
                 })
             }
+            27 => self.scan(appid, mlme::ScanType::Passive, arg1 as u32, arg2 as u8),
+            28 => self.scan(appid, mlme::ScanType::Active, arg1 as u32, arg2 as u8),
+            29 => self.mlme
+                .get()
+                .map_or(ReturnCode::ENOSUPPORT, |mlme| {
+                    mlme.start_coordinator(arg1 as u8)
+                }),
+            30 => self.mlme
+                .get()
+                .map_or(ReturnCode::ENOSUPPORT, |mlme| mlme.stop_coordinator()),
+            31 => self.mlme.get().map_or(ReturnCode::ENOSUPPORT, |mlme| {
+                self.do_with_cfg(appid, ASSOCIATE_CFG_SIZE, |cfg| {
+                    decode_coordinator(cfg).map_or(ReturnCode::EINVAL, |(channel, pan, addr)| {
+                        mlme.associate(channel, pan, addr, arg1 as u8)
+                    })
+                })
+            }),
+            32 => self.mlme
+                .get()
+                .map_or(ReturnCode::ENOSUPPORT, |mlme| mlme.disassociate()),
+            33 => self.mlme
+                .get()
+                .map_or(ReturnCode::ENOSUPPORT, |mlme| {
+                    mlme.set_association_permit(arg1 != 0);
+                    ReturnCode::SUCCESS
+                }),
+            34 => self.mlme.get().map_or(ReturnCode::ENOSUPPORT, |mlme| {
+                self.do_with_cfg(appid, 8, |cfg| {
+                    let mut addr_long = [0u8; 8];
+                    addr_long.copy_from_slice(cfg);
+                    mlme.disassociate_device(addr_long)
+                })
+            }),
+            35 => self.duty_cycle.get().map_or(ReturnCode::ENOSUPPORT, |duty_cycle| {
+                self.do_with_cfg_mut(appid, DUTY_CYCLE_STATS_SIZE, |cfg| {
+                    encode_duty_cycle_stats(&duty_cycle.get_duty_cycle_stats(), cfg);
+                    ReturnCode::SUCCESS
+                })
+            }),
+            36 => self.duty_cycle
+                .get()
+                .map_or(ReturnCode::ENOSUPPORT, |duty_cycle| {
+                    duty_cycle.reset_duty_cycle_stats();
+                    ReturnCode::SUCCESS
+                }),
+            37 => self.mlme.get().map_or(ReturnCode::ENOSUPPORT, |mlme| {
+                self.do_with_app(appid, |app| {
+                    let security_needed = match app.app_cfg
+                        .as_ref()
+                        .and_then(|cfg| decode_tx_security(cfg.as_ref()))
+                    {
+                        Some(security_needed) => security_needed,
+                        None => return ReturnCode::EINVAL,
+                    };
+                    app.app_write
+                        .take()
+                        .as_ref()
+                        .map_or(ReturnCode::EINVAL, |payload| {
+                            mlme.transmit_indirect(
+                                MacAddress::Short(arg1 as u16),
+                                payload.as_ref(),
+                                security_needed,
+                            )
+                        })
+                })
+            }),
+            38 => self.mlme
+                .get()
+                .map_or(ReturnCode::ENOSUPPORT, |mlme| {
+                    mlme.set_poll_interval(arg1 as u32);
+                    ReturnCode::SUCCESS
+                }),
+            39 => self.mlme
+                .get()
+                .map_or(ReturnCode::ENOSUPPORT, |mlme| mlme.poll()),
+            40 => {
+                let rx_info = self.apps.enter(appid, |app, _| app.rx_info).unwrap_or(None);
+                rx_info.map_or(ReturnCode::EINVAL, |rx_info| {
+                    self.do_with_cfg_mut(appid, RX_INFO_SIZE, |cfg| {
+                        encode_rx_info(&rx_info, cfg);
+                        ReturnCode::SUCCESS
+                    })
+                })
+            }
+            41 => self.neighbor_table
+                .get()
+                .map_or(ReturnCode::ENOSUPPORT, |neighbor_table| {
+                    neighbor_table
+                        .get_index(arg1)
+                        .map_or(ReturnCode::EINVAL, |link_stats| {
+                            self.do_with_cfg_mut(appid, LINK_STATS_SIZE, |cfg| {
+                                encode_link_stats(&link_stats, cfg);
+                                ReturnCode::SUCCESS
+                            })
+                        })
+                }),
             _ => ReturnCode::ENOSUPPORT,
         }
     }
@@ -785,12 +1119,23 @@ impl<'a> Driver for RadioDriver<'a> {
 
 impl<'a> device::TxClient for RadioDriver<'a> {
     fn send_done(&self, spi_buf: &'static mut [u8], acked: bool, result: ReturnCode) {
+        self.send_done_info(spi_buf, acked, result, radio::TxInfo::default());
+    }
+
+    fn send_done_info(
+        &self,
+        spi_buf: &'static mut [u8],
+        acked: bool,
+        result: ReturnCode,
+        info: radio::TxInfo,
+    ) {
         self.kernel_tx.replace(spi_buf);
+        let counts = (info.retries as usize) | ((info.channel_busy as usize) << 8);
         self.current_app.get().map(|appid| {
             let _ = self.apps.enter(appid, |app, _| {
                 app.tx_callback
                     .take()
-                    .map(|mut cb| cb.schedule(result.into(), acked as usize, 0));
+                    .map(|mut cb| cb.schedule(result.into(), acked as usize, counts));
             });
         });
         self.current_app.set(None);
@@ -815,7 +1160,14 @@ fn encode_address(addr: &Option<MacAddress>) -> usize {
 }
 
 impl<'a> device::RxClient for RadioDriver<'a> {
-    fn receive<'b>(&self, buf: &'b [u8], header: Header<'b>, data_offset: usize, data_len: usize) {
+    fn receive<'b>(
+        &self,
+        buf: &'b [u8],
+        header: Header<'b>,
+        info: radio::RxInfo,
+        data_offset: usize,
+        data_len: usize,
+    ) {
         self.apps.each(|app| {
             app.app_read.take().as_mut().map(|rbuf| {
                 let rbuf = rbuf.as_mut();
@@ -825,6 +1177,7 @@ impl<'a> device::RxClient for RadioDriver<'a> {
                 rbuf[..len].copy_from_slice(&buf[..len]);
                 rbuf[0] = data_offset as u8;
                 rbuf[1] = data_len as u8;
+                app.rx_info = Some(info);
 
                 // Encode useful parts of the header in 3 usizes
                 let pans = encode_pans(&header.dst_pan, &header.src_pan);
@@ -836,4 +1189,160 @@ impl<'a> device::RxClient for RadioDriver<'a> {
             });
         });
     }
+
+    fn receive_security_failure<'b>(&self, _: Header<'b>, _: device::SecurityError) {
+        // Frames that fail the security procedure are not exposed to
+        // userspace, which only receives frames that were accepted
+    }
+}
+
+/// Encodes an address mode and address into 9 bytes in the format expected by
+/// the userland driver.
+fn encode_mac_address(addr: &MacAddress, buf: &mut [u8]) {
+    buf[0] = AddressMode::from(&Some(*addr)) as u8;
+    for byte in buf[1..9].iter_mut() {
+        *byte = 0;
+    }
+    match *addr {
+        MacAddress::Short(addr) => {
+            buf[1] = addr as u8;
+            buf[2] = (addr >> 8) as u8;
+        }
+        MacAddress::Long(addr) => buf[1..9].copy_from_slice(&addr),
+    }
+}
+
+/// Encodes a PAN descriptor into a buffer in the format expected by the
+/// userland driver.
+fn encode_pan_descriptor(descriptor: &mlme::PanDescriptor, buf: &mut [u8]) {
+    buf[0] = descriptor.channel;
+    buf[1] = descriptor.coord_pan as u8;
+    buf[2] = (descriptor.coord_pan >> 8) as u8;
+    encode_mac_address(&descriptor.coord_addr, &mut buf[3..12]);
+    let superframe_spec = descriptor.superframe_spec.to_u16();
+    buf[12] = superframe_spec as u8;
+    buf[13] = (superframe_spec >> 8) as u8;
+    buf[14] = descriptor.lqi;
+}
+
+/// Encodes duty-cycle statistics into a buffer in the format expected by the
+/// userland driver.
+fn encode_duty_cycle_stats(stats: &mac::DutyCycleStats, buf: &mut [u8]) {
+    let fields = [
+        stats.awake_ms,
+        stats.elapsed_ms,
+        stats.preambles_sent,
+        stats.rendezvous,
+        stats.wake_interval_ms,
+    ];
+    for (field, bytes) in fields.iter().zip(buf.chunks_mut(4)) {
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = (*field >> (8 * i)) as u8;
+        }
+    }
+}
+
+/// Encodes the metadata of a received frame into a buffer in the format
+/// expected by the userland driver.
+fn encode_rx_info(info: &radio::RxInfo, buf: &mut [u8]) {
+    buf[0] = info.rssi as u8;
+    buf[1] = info.lqi;
+    buf[2] = info.timestamp.is_some() as u8;
+    let timestamp = info.timestamp.unwrap_or(0);
+    for (i, byte) in buf[3..7].iter_mut().enumerate() {
+        *byte = (timestamp >> (8 * i)) as u8;
+    }
+}
+
+/// Encodes the link quality estimates of a neighbor into a buffer in the
+/// format expected by the userland driver.
+fn encode_link_stats(stats: &LinkStats, buf: &mut [u8]) {
+    encode_mac_address(&stats.addr, &mut buf[..9]);
+    buf[9] = stats.rssi as u8;
+    buf[10] = stats.lqi;
+    let etx = stats.etx.unwrap_or(0xffff);
+    buf[11] = etx as u8;
+    buf[12] = (etx >> 8) as u8;
+}
+
+/// Decodes the channel, PAN ID and address of a coordinator from the start of
+/// a PAN descriptor in the format produced by `encode_pan_descriptor`.
+fn decode_coordinator(buf: &[u8]) -> Option<(u8, PanID, MacAddress)> {
+    let pan = (buf[1] as u16) | ((buf[2] as u16) << 8);
+    let addr = match AddressMode::from_mode(buf[3] as u16) {
+        Some(AddressMode::Short) => MacAddress::Short((buf[4] as u16) | ((buf[5] as u16) << 8)),
+        Some(AddressMode::Long) => {
+            let mut addr_long = [0u8; 8];
+            addr_long.copy_from_slice(&buf[4..12]);
+            MacAddress::Long(addr_long)
+        }
+        _ => return None,
+    };
+    Some((buf[0], pan, addr))
+}
+
+impl<'a> mlme::ScanClient for RadioDriver<'a> {
+    fn scan_done(
+        &self,
+        result: ReturnCode,
+        pan_descriptors: &[mlme::PanDescriptor],
+        security_failures: usize,
+    ) {
+        self.scan_app.get().map(|appid| {
+            let _ = self.apps.enter(appid, |app, _| {
+                // Write as many PAN descriptors as fit in the config buffer
+                let mut written = 0;
+                app.app_cfg.as_mut().map(|cfg| {
+                    for (descriptor, buf) in pan_descriptors
+                        .iter()
+                        .zip(cfg.as_mut().chunks_mut(PAN_DESCRIPTOR_SIZE))
+                    {
+                        if buf.len() < PAN_DESCRIPTOR_SIZE {
+                            break;
+                        }
+                        encode_pan_descriptor(descriptor, buf);
+                        written += 1;
+                    }
+                });
+                app.scan_callback
+                    .take()
+                    .map(|mut cb| cb.schedule(result.into(), written, security_failures));
+            });
+        });
+        self.scan_app.set(None);
+    }
+}
+
+impl<'a> mlme::AssociationClient for RadioDriver<'a> {
+    fn associate_done(&self, result: ReturnCode, short_addr: u16) {
+        self.association_event(association_event::ASSOCIATE_DONE, result.into(), short_addr);
+    }
+
+    fn disassociated(&self, reason: u8) {
+        self.association_event(
+            association_event::DISASSOCIATED,
+            reason as usize,
+            BROADCAST_ADDR,
+        );
+    }
+
+    fn device_associated(&self, _: [u8; 8], short_addr: u16) {
+        self.association_event(association_event::DEVICE_ASSOCIATED, 0, short_addr);
+    }
+
+    fn device_disassociated(&self, _: [u8; 8], short_addr: u16) {
+        self.association_event(association_event::DEVICE_DISASSOCIATED, 0, short_addr);
+    }
+}
+
+impl<'a> mlme::IndirectClient for RadioDriver<'a> {
+    fn indirect_done(&self, dst_addr: MacAddress, acked: bool, result: ReturnCode) {
+        // Frames are only held for short addresses
+        let short_addr = encode_address(&Some(dst_addr));
+        self.apps.each(|app| {
+            app.indirect_callback
+                .take()
+                .map(|mut cb| cb.schedule(result.into(), acked as usize, short_addr));
+        });
+    }
 }
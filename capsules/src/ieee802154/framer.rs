@@ -60,16 +60,29 @@
 //! mac_device.set_transmit_client(radio_capsule);
 //! mac_device.set_receive_client(radio_capsule);
 //! ```
+//!
+//! Secured frames are protected against replays by tracking the frame counter
+//! of each neighbor through the `DeviceProcedure`. So that the outgoing frame
+//! counter keeps increasing across reboots, a `FrameCounterStore` that
+//! persists it (in flash, for example) can also be provided:
+//! ```rust
+//! mac_device.set_frame_counter_store(frame_counter_flash);
+//! ```
+//!
+//! The link quality of the frames exchanged with each neighbor can be
+//! tracked by providing a `NeighborTable`:
+//! ```rust
+//! mac_device.set_neighbor_table(neighbor_table);
+//! ```
 
 //
 // TODO: Encryption/decryption
-// TODO: Sending beacon frames
-// TODO: Channel scanning
 //
 
 use core::cell::Cell;
-use ieee802154::device::{MacDevice, RxClient, TxClient};
+use ieee802154::device::{MacDevice, RxClient, SecurityError, TxClient};
 use ieee802154::mac::Mac;
+use ieee802154::neighbors::NeighborTable;
 use kernel::ReturnCode;
 use kernel::common::take_cell::MapCell;
 use kernel::hil::radio;
@@ -132,6 +145,19 @@ impl Frame {
 
         ReturnCode::SUCCESS
     }
+
+    /// Sets the Frame Pending field of the header, which tells the recipient
+    /// that more frames are held for it
+    pub fn set_frame_pending(&mut self, pending: bool) {
+        // The frame pending flag is in the first byte of the frame control
+        // field, which is little-endian
+        let flag = frame_control::FRAME_PENDING as u8;
+        if pending {
+            self.buf[radio::PSDU_OFFSET] |= flag;
+        } else {
+            self.buf[radio::PSDU_OFFSET] &= !flag;
+        }
+    }
 }
 
 impl FrameInfo {
@@ -162,8 +188,9 @@ impl FrameInfo {
                 unimplemented!()
             }
             FrameType::MACCommand => {
-                // Beginning of MAC command content field
-                unimplemented!()
+                // Beginning of MAC command content field, after the command
+                // frame identifier
+                self.data_offset + 1
             }
             _ => {
                 // MAC payload field, which includes payload IEs
@@ -182,12 +209,21 @@ impl FrameInfo {
             // m data is the private payload field
             (
                 private_payload_offset,
-                self.unsecured_length() | private_payload_offset,
+                self.unsecured_length() - private_payload_offset,
             )
         }
     }
 }
 
+/// Recovers the extended source address and frame counter from a CCM* nonce
+fn get_ccm_nonce_source(nonce: &[u8; 13]) -> ([u8; 8], u32) {
+    let mut device_addr = [0u8; 8];
+    device_addr.copy_from_slice(&nonce[0..8]);
+    let frame_counter = (nonce[8] as u32) << 24 | (nonce[9] as u32) << 16 | (nonce[10] as u32) << 8
+        | (nonce[11] as u32);
+    (device_addr, frame_counter)
+}
+
 fn get_ccm_nonce(device_addr: &[u8; 8], frame_counter: u32, level: SecurityLevel) -> [u8; 13] {
     let mut nonce = [0u8; 13];
     let encode_ccm_nonce = |buf: &mut [u8]| {
@@ -234,8 +270,50 @@ pub trait DeviceProcedure {
     /// address is already long, a long address should be returned only if the
     /// given address matches a known DeviceDescriptor.
     fn lookup_addr_long(&self, addr: MacAddress) -> Option<([u8; 8])>;
+
+    /// Look up the frame counter of the last secured frame accepted from the
+    /// device with the given extended address, or `None` if no frame from
+    /// that device has been accepted yet.
+    fn lookup_frame_counter(&self, addr_long: [u8; 8]) -> Option<u32>;
+
+    /// Record the frame counter of a secured frame from the device with the
+    /// given extended address once the frame has been successfully unsecured.
+    /// Subsequent frames from that device are only accepted if their frame
+    /// counter is greater.
+    fn set_frame_counter(&self, addr_long: [u8; 8], frame_counter: u32);
+}
+
+/// Trait to be implemented by an upper layer that persists the outgoing frame
+/// counter across reboots. Reusing a frame counter with the same key breaks
+/// the security of CCM*, and neighbors drop frames whose frame counter is not
+/// greater than the last one they received.
+///
+/// To limit the number of writes, the `Framer` does not store every frame
+/// counter it uses, but reserves a range of `FRAME_COUNTER_RESERVE` values at
+/// a time by storing the end of that range. After a reboot, it resumes from
+/// the stored value, skipping the unused part of the last range.
+pub trait FrameCounterStore {
+    /// Returns the value last passed to `store_frame_counter`, or 0 if no
+    /// value has been stored yet.
+    fn load_frame_counter(&self) -> u32;
+
+    /// Persists `frame_counter`. Frame counters up to, but not including,
+    /// this value are used until the next call.
+    fn store_frame_counter(&self, frame_counter: u32);
 }
 
+/// The length of the superframe specification, GTS specification and pending
+/// address specification fields of the beacons prepared by the framer
+const BEACON_FIELDS_LEN: usize = 4;
+
+/// The number of outgoing frame counter values reserved by each write to the
+/// `FrameCounterStore`
+pub const FRAME_COUNTER_RESERVE: u32 = 1024;
+
+/// The number of senders whose last sequence number is remembered to discard
+/// retransmitted frames
+const RECENT_SENDERS: usize = 4;
+
 /// This state enum describes the state of the transmission pipeline.
 /// Conditionally-present state is also included as fields in the enum variants.
 /// We can view the transmission process as a state machine driven by the
@@ -292,6 +370,13 @@ pub struct Framer<'a, M: Mac + 'a, A: AES128CCM<'a> + 'a> {
     /// DeviceDescriptor lookup procedure
     device_procedure: Cell<Option<&'a DeviceProcedure>>,
 
+    /// The frame counter of the next secured frame to be transmitted
+    frame_counter: Cell<u32>,
+    /// The frame counter value last persisted, up to which counters can be
+    /// used without writing to the store again
+    frame_counter_limit: Cell<u32>,
+    frame_counter_store: Cell<Option<&'a FrameCounterStore>>,
+
     /// Transmision pipeline state. This should never be `None`, except when
     /// transitioning between states. That is, any method that consumes the
     /// current state should always remember to replace it along with the
@@ -302,7 +387,17 @@ pub struct Framer<'a, M: Mac + 'a, A: AES128CCM<'a> + 'a> {
     /// Reception pipeline state. Similar to the above, this should never be
     /// `None`, except when transitioning between states.
     rx_state: MapCell<RxState>,
+    /// The metadata reported by the radio for the frame in the reception
+    /// pipeline
+    rx_info: Cell<radio::RxInfo>,
     rx_client: Cell<Option<&'a RxClient>>,
+    /// The source addresses and sequence numbers of the last unsecured frames
+    /// received that requested an acknowledgement, and the entry to replace
+    /// next
+    recent_frames: MapCell<[Option<(MacAddress, u8)>; RECENT_SENDERS]>,
+    recent_frames_next: Cell<usize>,
+
+    neighbor_table: Cell<Option<&'a NeighborTable>>,
 }
 
 impl<'a, M: Mac + 'a, A: AES128CCM<'a> + 'a> Framer<'a, M, A> {
@@ -313,10 +408,17 @@ impl<'a, M: Mac + 'a, A: AES128CCM<'a> + 'a> Framer<'a, M, A> {
             data_sequence: Cell::new(0),
             key_procedure: Cell::new(None),
             device_procedure: Cell::new(None),
+            frame_counter: Cell::new(0),
+            frame_counter_limit: Cell::new(0),
+            frame_counter_store: Cell::new(None),
             tx_state: MapCell::new(TxState::Idle),
             tx_client: Cell::new(None),
             rx_state: MapCell::new(RxState::Idle),
+            rx_info: Cell::new(radio::RxInfo::default()),
             rx_client: Cell::new(None),
+            recent_frames: MapCell::new([None; RECENT_SENDERS]),
+            recent_frames_next: Cell::new(0),
+            neighbor_table: Cell::new(None),
         }
     }
 
@@ -330,6 +432,41 @@ impl<'a, M: Mac + 'a, A: AES128CCM<'a> + 'a> Framer<'a, M, A> {
         self.device_procedure.set(Some(device_procedure));
     }
 
+    /// Sets the table in which the link quality of the frames exchanged with
+    /// each neighbor is recorded.
+    pub fn set_neighbor_table(&self, neighbor_table: &'a NeighborTable) {
+        self.neighbor_table.set(Some(neighbor_table));
+    }
+
+    /// Sets the store used to persist the outgoing frame counter, and resumes
+    /// the frame counter from the value previously stored in it. This should
+    /// be called before any secured frame is prepared.
+    pub fn set_frame_counter_store(&self, frame_counter_store: &'a FrameCounterStore) {
+        let frame_counter = frame_counter_store.load_frame_counter();
+        self.frame_counter.set(frame_counter);
+        self.frame_counter_limit.set(frame_counter);
+        self.frame_counter_store.set(Some(frame_counter_store));
+    }
+
+    /// Returns the frame counter to use for the next secured frame, or `None`
+    /// if the frame counter has been exhausted. If needed, a new range of
+    /// frame counters is reserved in the persistent store first.
+    fn next_frame_counter(&self) -> Option<u32> {
+        let frame_counter = self.frame_counter.get();
+        if frame_counter == 0xffffffff {
+            return None;
+        }
+        if frame_counter >= self.frame_counter_limit.get() {
+            let limit = frame_counter.saturating_add(FRAME_COUNTER_RESERVE);
+            self.frame_counter_store
+                .get()
+                .map(|store| store.store_frame_counter(limit));
+            self.frame_counter_limit.set(limit);
+        }
+        self.frame_counter.set(frame_counter + 1);
+        Some(frame_counter)
+    }
+
     /// Look up the key using the IEEE 802.15.4 KeyDescriptor lookup prodecure
     /// implemented elsewhere.
     fn lookup_key(&self, level: SecurityLevel, key_id: KeyId) -> Option<([u8; 16])> {
@@ -348,6 +485,87 @@ impl<'a, M: Mac + 'a, A: AES128CCM<'a> + 'a> Framer<'a, M, A> {
         })
     }
 
+    /// Look up the last frame counter accepted from a device using the IEEE
+    /// 802.15.4 DeviceDescriptor lookup procedure implemented elsewhere.
+    fn lookup_frame_counter(&self, addr_long: [u8; 8]) -> Option<u32> {
+        self.device_procedure
+            .get()
+            .and_then(|device_procedure| device_procedure.lookup_frame_counter(addr_long))
+    }
+
+    /// Reports a frame that failed the incoming frame security procedure to
+    /// the receive client.
+    fn report_security_failure(&self, header: Header, error: SecurityError) {
+        self.rx_client.get().map(|client| {
+            client.receive_security_failure(header, error);
+        });
+    }
+
+    /// Prepares a frame by completing the given MAC header with the sequence
+    /// number and the auxiliary security header, and encoding it into the
+    /// buffer. Returns the buffer if the requested security cannot be applied
+    /// or the header does not fit.
+    fn prepare_frame(
+        &self,
+        buf: &'static mut [u8],
+        mut header: Header,
+        security_needed: Option<(SecurityLevel, KeyId)>,
+    ) -> Result<Frame, &'static mut [u8]> {
+        // IEEE 802.15.4-2015: 9.2.1, outgoing frame security
+        // Steps a-e of the security procedure are implemented here.
+
+        // TODO: For Thread, in the case of `KeyIdMode::Source4Index`, the source
+        // address should instead be some constant defined in their
+        // specification.
+        let src_addr_long = self.get_address_long();
+        let security_desc = security_needed.and_then(|(level, key_id)| {
+            let key = self.lookup_key(level, key_id)?;
+            // Step f: the frame counter is only consumed once the key is found
+            let frame_counter = self.next_frame_counter()?;
+            let nonce = get_ccm_nonce(&src_addr_long, frame_counter, level);
+            Some((
+                Security {
+                    level: level,
+                    asn_in_nonce: false,
+                    frame_counter: Some(frame_counter),
+                    key_id: key_id,
+                },
+                key,
+                nonce,
+            ))
+        });
+        if security_needed.is_some() && security_desc.is_none() {
+            // If security was requested, fail when desired key was not found
+            // or the frame counter is exhausted.
+            return Err(buf);
+        }
+
+        // Complete MAC header
+        let security = security_desc.map(|(sec, _, _)| sec);
+        let mic_len = security.map_or(0, |sec| sec.level.mic_len());
+        // Each frame gets its own sequence number, even when several frames
+        // are prepared before the first is sent, so that receivers can tell
+        // retransmissions from new frames
+        header.seq = Some(self.data_sequence.get());
+        self.data_sequence.set(self.data_sequence.get().wrapping_add(1));
+        header.security = security;
+
+        match header.encode(&mut buf[radio::PSDU_OFFSET..], true).done() {
+            Some((data_offset, mac_payload_offset)) => Ok(Frame {
+                buf: buf,
+                info: FrameInfo {
+                    frame_type: header.frame_type,
+                    mac_payload_offset: mac_payload_offset,
+                    data_offset: data_offset,
+                    data_len: 0,
+                    mic_len: mic_len,
+                    security_params: security_desc.map(|(sec, key, nonce)| (sec.level, key, nonce)),
+                },
+            }),
+            None => Err(buf),
+        }
+    }
+
     /// IEEE 802.15.4-2015, 9.2.1, outgoing frame security procedure
     /// Performs the first checks in the security procedure. The rest of the
     /// steps are performed as part of the transmission pipeline.
@@ -396,12 +614,19 @@ impl<'a, M: Mac + 'a, A: AES128CCM<'a> + 'a> Framer<'a, M, A> {
                     // IEEE 802.15.4-2015: 9.2.3, incoming frame security procedure
                     // for security-enabled headers
                     if header.version == FrameVersion::V2003 {
+                        self.report_security_failure(header, SecurityError::UnsupportedLegacy);
+                        None
+                    } else if header.frame_type == FrameType::Beacon {
+                        // The private payload of a beacon only starts after
+                        // its GTS and pending address fields, which are not
+                        // parsed here, so secured beacons are not supported.
                         None
                     } else {
                         // Step e: Lookup the key.
                         let key = match self.lookup_key(security.level, security.key_id) {
                             Some(key) => key,
                             None => {
+                                self.report_security_failure(header, SecurityError::UnavailableKey);
                                 return None;
                             }
                         };
@@ -414,6 +639,10 @@ impl<'a, M: Mac + 'a, A: AES128CCM<'a> + 'a> Framer<'a, M, A> {
                         let device_addr = match self.lookup_addr_long(header.src_addr) {
                             Some(addr) => addr,
                             None => {
+                                self.report_security_failure(
+                                    header,
+                                    SecurityError::UnavailableDevice,
+                                );
                                 return None;
                             }
                         };
@@ -421,11 +650,19 @@ impl<'a, M: Mac + 'a, A: AES128CCM<'a> + 'a> Framer<'a, M, A> {
                         // Step g, h: Check frame counter
                         let frame_counter = match security.frame_counter {
                             Some(frame_counter) => {
-                                if frame_counter == 0xffffffff {
-                                    // Counter error
+                                // Drop frames that were already received from
+                                // this device, or replayed by an attacker. The
+                                // frame counter is only recorded once the frame
+                                // has been authenticated.
+                                let replayed = self.lookup_frame_counter(device_addr)
+                                    .map_or(false, |last| frame_counter <= last);
+                                if frame_counter == 0xffffffff || replayed {
+                                    self.report_security_failure(
+                                        header,
+                                        SecurityError::CounterError,
+                                    );
                                     return None;
                                 }
-                                // TODO: Check frame counter against source device
                                 frame_counter
                             }
                             // TSCH mode, where ASN is used instead, not supported
@@ -446,11 +683,13 @@ impl<'a, M: Mac + 'a, A: AES128CCM<'a> + 'a> Framer<'a, M, A> {
                             security_params: Some((security.level, key, nonce)),
                         })
                     }
+                } else if self.is_retransmission(&header) {
+                    // The frame was already received, but its
+                    // acknowledgement was lost
+                    None
                 } else {
                     // No security needed, can yield the frame immediately
-                    self.rx_client.get().map(|client| {
-                        client.receive(&buf, header, radio::PSDU_OFFSET + data_offset, data_len);
-                    });
+                    self.yield_frame(&buf, header, radio::PSDU_OFFSET + data_offset, data_len);
                     None
                 }
             });
@@ -548,6 +787,49 @@ impl<'a, M: Mac + 'a, A: AES128CCM<'a> + 'a> Framer<'a, M, A> {
             })
     }
 
+    /// Whether an unsecured frame repeats the last frame received from its
+    /// sender, which then retransmitted it because the acknowledgement was
+    /// lost, and otherwise records its sequence number. Secured frames are
+    /// discarded by the frame counter check instead.
+    fn is_retransmission(&self, header: &Header) -> bool {
+        let (src_addr, seq) = match (header.ack_requested, header.src_addr, header.seq) {
+            (true, Some(src_addr), Some(seq)) => (src_addr, seq),
+            _ => return false,
+        };
+        self.recent_frames.map_or(false, |recent_frames| {
+            let entry = recent_frames
+                .iter()
+                .position(|frame| frame.map_or(false, |(addr, _)| addr == src_addr));
+            match entry {
+                Some(i) if recent_frames[i] == Some((src_addr, seq)) => true,
+                Some(i) => {
+                    recent_frames[i] = Some((src_addr, seq));
+                    false
+                }
+                None => {
+                    let next = self.recent_frames_next.get();
+                    recent_frames[next] = Some((src_addr, seq));
+                    self.recent_frames_next.set((next + 1) % RECENT_SENDERS);
+                    false
+                }
+            }
+        })
+    }
+
+    /// Exposes a received frame that passed the incoming frame security
+    /// procedure to the client, and records its link quality.
+    fn yield_frame(&self, buf: &[u8], header: Header, data_offset: usize, data_len: usize) {
+        let info = self.rx_info.get();
+        if let (Some(neighbor_table), Some(src_addr)) =
+            (self.neighbor_table.get(), header.src_addr)
+        {
+            neighbor_table.frame_received(src_addr, info);
+        }
+        self.rx_client
+            .get()
+            .map(|client| client.receive(buf, header, info, data_offset, data_len));
+    }
+
     /// Advances the reception pipeline if it can be advanced.
     fn step_receive_state(&self) {
         self.rx_state.take().map(|state| {
@@ -615,14 +897,12 @@ impl<'a, M: Mac + 'a, A: AES128CCM<'a> + 'a> Framer<'a, M, A> {
                         // This is so that it is possible to tell if the
                         // frame was secured or unsecured, while still
                         // always receiving the frame payload in plaintext.
-                        self.rx_client.get().map(|client| {
-                            client.receive(
-                                &buf,
-                                header,
-                                radio::PSDU_OFFSET + data_offset,
-                                frame_len - data_offset,
-                            );
-                        });
+                        self.yield_frame(
+                            &buf,
+                            header,
+                            radio::PSDU_OFFSET + data_offset,
+                            frame_len - data_offset,
+                        );
                     }
                     (RxState::Idle, Some(buf))
                 }
@@ -659,6 +939,10 @@ impl<'a, M: Mac + 'a, A: AES128CCM<'a> + 'a> MacDevice<'a> for Framer<'a, M, A>
         self.mac.get_pan()
     }
 
+    fn get_channel(&self) -> u8 {
+        self.mac.get_channel()
+    }
+
     fn set_address(&self, addr: u16) {
         self.mac.set_address(addr)
     }
@@ -671,6 +955,14 @@ impl<'a, M: Mac + 'a, A: AES128CCM<'a> + 'a> MacDevice<'a> for Framer<'a, M, A>
         self.mac.set_pan(id)
     }
 
+    fn set_channel(&self, chan: u8) -> ReturnCode {
+        self.mac.set_channel(chan)
+    }
+
+    fn set_ack_frame_pending(&self, pending: bool) {
+        self.mac.set_ack_frame_pending(pending)
+    }
+
     fn config_commit(&self) {
         self.mac.config_commit()
     }
@@ -688,71 +980,99 @@ impl<'a, M: Mac + 'a, A: AES128CCM<'a> + 'a> MacDevice<'a> for Framer<'a, M, A>
         src_addr: MacAddress,
         security_needed: Option<(SecurityLevel, KeyId)>,
     ) -> Result<Frame, &'static mut [u8]> {
-        // IEEE 802.15.4-2015: 9.2.1, outgoing frame security
-        // Steps a-e of the security procedure are implemented here.
-
-        // TODO: For Thread, in the case of `KeyIdMode::Source4Index`, the source
-        // address should instead be some constant defined in their
-        // specification.
-        let src_addr_long = self.get_address_long();
-        let security_desc = security_needed.and_then(|(level, key_id)| {
-            self.lookup_key(level, key_id).map(|key| {
-                // TODO: lookup frame counter for device
-                let frame_counter = 0;
-                let nonce = get_ccm_nonce(&src_addr_long, frame_counter, level);
-                (
-                    Security {
-                        level: level,
-                        asn_in_nonce: false,
-                        frame_counter: Some(frame_counter),
-                        key_id: key_id,
-                    },
-                    key,
-                    nonce,
-                )
-            })
-        });
-        if security_needed.is_some() && security_desc.is_none() {
-            // If security was requested, fail when desired key was not found.
-            return Err(buf);
-        }
-
-        // Construct MAC header
-        let security = security_desc.map(|(sec, _, _)| sec);
-        let mic_len = security.map_or(0, |sec| sec.level.mic_len());
         let header = Header {
             frame_type: FrameType::Data,
-            /* TODO: determine this by looking at queue, and also set it in
-             * hardware so that ACKs set this flag to the right value. */
+            // Set with `Frame::set_frame_pending` on frames that are held for
+            // indirect transmission
             frame_pending: false,
             // Unicast data frames request acknowledgement
-            ack_requested: true,
+            ack_requested: dst_addr != MacAddress::Short(BROADCAST_ADDR),
             version: FrameVersion::V2015,
-            seq: Some(self.data_sequence.get()),
+            seq: None,
             dst_pan: Some(dst_pan),
             dst_addr: Some(dst_addr),
             src_pan: Some(src_pan),
             src_addr: Some(src_addr),
-            security: security,
+            security: None,
             header_ies: Default::default(),
             header_ies_len: 0,
             payload_ies: Default::default(),
             payload_ies_len: 0,
         };
+        self.prepare_frame(buf, header, security_needed)
+    }
 
-        match header.encode(&mut buf[radio::PSDU_OFFSET..], true).done() {
-            Some((data_offset, mac_payload_offset)) => Ok(Frame {
-                buf: buf,
-                info: FrameInfo {
-                    frame_type: FrameType::Data,
-                    mac_payload_offset: mac_payload_offset,
-                    data_offset: data_offset,
-                    data_len: 0,
-                    mic_len: mic_len,
-                    security_params: security_desc.map(|(sec, key, nonce)| (sec.level, key, nonce)),
-                },
-            }),
-            None => Err(buf),
+    fn prepare_beacon_frame(
+        &self,
+        buf: &'static mut [u8],
+        src_pan: PanID,
+        src_addr: MacAddress,
+        superframe_spec: SuperframeSpec,
+    ) -> Result<Frame, &'static mut [u8]> {
+        // Beacons are sent as 2006 frames so that devices that only
+        // understand the older frame versions can still discover the PAN
+        let header = Header {
+            frame_type: FrameType::Beacon,
+            frame_pending: false,
+            ack_requested: false,
+            version: FrameVersion::V2006,
+            seq: None,
+            dst_pan: None,
+            dst_addr: None,
+            src_pan: Some(src_pan),
+            src_addr: Some(src_addr),
+            security: None,
+            header_ies: Default::default(),
+            header_ies_len: 0,
+            payload_ies: Default::default(),
+            payload_ies_len: 0,
+        };
+        let mut frame = self.prepare_frame(buf, header, None)?;
+
+        let mut beacon = [0u8; BEACON_FIELDS_LEN];
+        let rval = match encode_beacon(&mut beacon, superframe_spec).done() {
+            Some((len, _)) => frame.append_payload(&beacon[..len]),
+            None => ReturnCode::FAIL,
+        };
+        if rval == ReturnCode::SUCCESS {
+            Ok(frame)
+        } else {
+            Err(frame.into_buf())
+        }
+    }
+
+    fn prepare_command_frame(
+        &self,
+        buf: &'static mut [u8],
+        dst_pan: PanID,
+        dst_addr: MacAddress,
+        src_pan: PanID,
+        src_addr: Option<MacAddress>,
+        command_id: u8,
+        security_needed: Option<(SecurityLevel, KeyId)>,
+    ) -> Result<Frame, &'static mut [u8]> {
+        let header = Header {
+            frame_type: FrameType::MACCommand,
+            frame_pending: false,
+            // Broadcast frames cannot be acknowledged
+            ack_requested: dst_addr != MacAddress::Short(BROADCAST_ADDR),
+            version: FrameVersion::V2006,
+            seq: None,
+            dst_pan: Some(dst_pan),
+            dst_addr: Some(dst_addr),
+            src_pan: src_addr.map(|_| src_pan),
+            src_addr: src_addr,
+            security: None,
+            header_ies: Default::default(),
+            header_ies_len: 0,
+            payload_ies: Default::default(),
+            payload_ies_len: 0,
+        };
+        let mut frame = self.prepare_frame(buf, header, security_needed)?;
+        if frame.append_payload(&[command_id]) == ReturnCode::SUCCESS {
+            Ok(frame)
+        } else {
+            Err(frame.into_buf())
         }
     }
 
@@ -780,15 +1100,46 @@ impl<'a, M: Mac + 'a, A: AES128CCM<'a> + 'a> MacDevice<'a> for Framer<'a, M, A>
 
 impl<'a, M: Mac + 'a, A: AES128CCM<'a> + 'a> radio::TxClient for Framer<'a, M, A> {
     fn send_done(&self, buf: &'static mut [u8], acked: bool, result: ReturnCode) {
-        self.data_sequence.set(self.data_sequence.get() + 1);
+        // Retransmissions that the radio performs by itself are not reported
+        self.send_done_info(buf, acked, result, radio::TxInfo::default());
+    }
+
+    fn send_done_info(
+        &self,
+        buf: &'static mut [u8],
+        acked: bool,
+        result: ReturnCode,
+        info: radio::TxInfo,
+    ) {
+        // Only the transmissions of frames that request an acknowledgement
+        // tell anything about the link to their destination. Some MAC layers
+        // report frames that were not acknowledged with ENOACK.
+        if result == ReturnCode::SUCCESS || result == ReturnCode::ENOACK {
+            self.neighbor_table.get().map(|neighbor_table| {
+                if let Some((_, (header, _))) =
+                    Header::decode(&buf[radio::PSDU_OFFSET..], false).done()
+                {
+                    if let (true, Some(dst_addr)) = (header.ack_requested, header.dst_addr) {
+                        neighbor_table.frame_sent(dst_addr, acked, info.retries);
+                    }
+                }
+            });
+        }
         self.tx_client.get().map(move |client| {
-            client.send_done(buf, acked, result);
+            client.send_done_info(buf, acked, result, info);
         });
     }
 }
 
 impl<'a, M: Mac + 'a, A: AES128CCM<'a> + 'a> radio::RxClient for Framer<'a, M, A> {
-    fn receive(&self, buf: &'static mut [u8], frame_len: usize, crc_valid: bool, _: ReturnCode) {
+    fn receive(
+        &self,
+        buf: &'static mut [u8],
+        frame_len: usize,
+        crc_valid: bool,
+        info: radio::RxInfo,
+        _: ReturnCode,
+    ) {
         // Drop all frames with invalid CRC
         if !crc_valid {
             self.mac.set_receive_buffer(buf);
@@ -800,6 +1151,7 @@ impl<'a, M: Mac + 'a, A: AES128CCM<'a> + 'a> radio::RxClient for Framer<'a, M, A
                 RxState::Idle => {
                     // We can start processing a new received frame only if
                     // the reception pipeline is free
+                    self.rx_info.set(info);
                     self.incoming_frame_security(buf, frame_len)
                 }
                 other_state => {
@@ -876,8 +1228,21 @@ impl<'a, M: Mac + 'a, A: AES128CCM<'a> + 'a> CCMClient for Framer<'a, M, A> {
                 match state {
                     RxState::Decrypting(info) => {
                         let next_state = if tag_is_valid {
+                            // IEEE 802.15.4-2015: 9.2.3, step j: the frame is
+                            // authentic, so its frame counter can be recorded
+                            if let Some((_, _, nonce)) = info.security_params {
+                                let (device_addr, frame_counter) = get_ccm_nonce_source(&nonce);
+                                self.device_procedure.get().map(|device_procedure| {
+                                    device_procedure.set_frame_counter(device_addr, frame_counter)
+                                });
+                            }
                             RxState::ReadyToYield(info, buf)
                         } else {
+                            if let Some((_, (header, _))) =
+                                Header::decode(&buf[radio::PSDU_OFFSET..], false).done()
+                            {
+                                self.report_security_failure(header, SecurityError::MicFailure);
+                            }
                             RxState::ReadyToReturn(buf)
                         };
                         self.rx_state.replace(next_state);
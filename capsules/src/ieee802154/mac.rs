@@ -8,11 +8,40 @@
 //! AwakeMac provides a default implementation of such a layer, maintaining
 //! the underlying kernel::hil::radio::Radio powered at all times and passing
 //! through each frame for transmission.
+//!
+//! Optionally, AwakeMac can perform unslotted CSMA-CA and frame retransmissions
+//! in software, for radios whose hardware does not (or should not) do so. This
+//! is disabled by default and enabled with `AwakeMac::set_csma`:
+//!
+//! * Before each transmission attempt, the layer waits a random number of unit
+//!   backoff periods in `[0, 2^BE - 1]`, starting with `BE = min_be`.
+//! * The radio is expected to perform a clear channel assessment when asked to
+//!   transmit, and to complete the transmission with `ReturnCode::EBUSY` if the
+//!   channel was busy. In this case, BE is incremented up to `max_be` and the
+//!   layer backs off again, at most `max_csma_backoffs` times before giving up
+//!   and reporting the channel access failure to the client as
+//!   `ReturnCode::EBUSY`. Radios that do not report busy channels simply never
+//!   trigger this path, but still benefit from the random backoff.
+//! * Frames that request an acknowledgement but are not acknowledged are
+//!   retransmitted, each time with a fresh CSMA-CA procedure, at most
+//!   `max_frame_retries` times.
+//!
+//! The number of retransmissions of each frame, and the number of times its
+//! clear channel assessments found the channel busy, are reported to the
+//! transmit client through `radio::TxClient::send_done_info`.
 
 use core::cell::Cell;
+use core::cmp::min;
 use kernel::ReturnCode;
+use kernel::common::take_cell::TakeCell;
 use kernel::hil::radio;
-use net::ieee802154::{Header, MacAddress};
+use kernel::hil::rng::{self, RNG};
+use kernel::hil::time::{self, Alarm, Frequency};
+use net::ieee802154::{FrameType, Header, MacAddress, BROADCAST_ADDR};
+
+/// The duration of a unit backoff period (aUnitBackoffPeriod, 20 symbols) for
+/// the 2.4 GHz O-QPSK PHY, in microseconds
+const UNIT_BACKOFF_PERIOD_US: u64 = 320;
 
 pub trait Mac {
     /// Initializes the layer; may require a buffer to temporarily retaining frames to be
@@ -34,6 +63,8 @@ pub trait Mac {
     fn get_address_long(&self) -> [u8; 8];
     /// The 16-bit PAN id of the radio
     fn get_pan(&self) -> u16;
+    /// The 802.15.4 channel of the radio
+    fn get_channel(&self) -> u8;
 
     /// Sets the short 16-bit address of the radio
     fn set_address(&self, addr: u16);
@@ -41,6 +72,12 @@ pub trait Mac {
     fn set_address_long(&self, addr: [u8; 8]);
     /// Sets the 16-bit PAN id of the radio
     fn set_pan(&self, id: u16);
+    /// Sets the 802.15.4 channel of the radio. Returns EINVAL if the channel
+    /// is not supported by the radio.
+    fn set_channel(&self, chan: u8) -> ReturnCode;
+    /// Sets whether acknowledgements to data requests indicate that frames
+    /// are pending for the polling device
+    fn set_ack_frame_pending(&self, pending: bool);
 
     /// Must be called after one or more calls to `set_*`. If
     /// `set_*` is called without calling `config_commit`, there is no guarantee
@@ -61,29 +98,200 @@ pub trait Mac {
     ) -> (ReturnCode, Option<&'static mut [u8]>);
 }
 
+/// Statistics about the energy usage of a duty-cycled Mac layer, accumulated
+/// since they were last reset
+#[derive(Copy, Clone, Default, Eq, PartialEq, Debug)]
+pub struct DutyCycleStats {
+    /// Time during which the radio was on, in milliseconds
+    pub awake_ms: u32,
+    /// Total time elapsed, in milliseconds
+    pub elapsed_ms: u32,
+    /// Number of preambles (wake-up frames) transmitted
+    pub preambles_sent: u32,
+    /// Number of transmissions for which the destination woke up and
+    /// acknowledged a preamble
+    pub rendezvous: u32,
+    /// Current interval between two wake-ups of the radio, in milliseconds
+    pub wake_interval_ms: u32,
+}
+
+/// Implemented by Mac layers that duty-cycle the radio to export statistics
+/// about their energy usage
+pub trait DutyCycle {
+    /// The statistics accumulated since the last reset
+    fn get_duty_cycle_stats(&self) -> DutyCycleStats;
+    /// Resets the accumulated statistics
+    fn reset_duty_cycle_stats(&self);
+}
+
+/// Parameters of the software unslotted CSMA-CA procedure of `AwakeMac`. The
+/// defaults are those of the IEEE 802.15.4 standard.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct CsmaConfig {
+    /// The initial backoff exponent (macMinBE)
+    pub min_be: u8,
+    /// The maximum backoff exponent (macMaxBE)
+    pub max_be: u8,
+    /// The number of times the channel may be found busy before a
+    /// transmission attempt fails (macMaxCSMABackoffs)
+    pub max_csma_backoffs: u8,
+    /// The number of retransmissions of an unacknowledged frame
+    /// (macMaxFrameRetries)
+    pub max_frame_retries: u8,
+}
+
+impl Default for CsmaConfig {
+    fn default() -> CsmaConfig {
+        CsmaConfig {
+            min_be: 3,
+            max_be: 5,
+            max_csma_backoffs: 4,
+            max_frame_retries: 3,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum CsmaState {
+    Idle,
+    WaitingForRandom,
+    Backoff,
+    Transmitting,
+}
+
 ///
 /// Default implementation of a Mac layer. Acts as a pass-through between a MacDevice
 /// implementation and the underlying radio::Radio device. Does not change the power
-/// state of the radio during operation.
+/// state of the radio during operation. When CSMA-CA is enabled, frames are held in
+/// this layer while backing off and retransmitting.
 ///
-pub struct AwakeMac<'a, R: radio::Radio + 'a> {
+pub struct AwakeMac<'a, R: radio::Radio + 'a, A: Alarm + 'a> {
     radio: &'a R,
+    alarm: &'a A,
+    rng: &'a RNG,
 
     tx_client: Cell<Option<&'static radio::TxClient>>,
     rx_client: Cell<Option<&'static radio::RxClient>>,
+
+    csma: Cell<Option<CsmaConfig>>,
+    state: Cell<CsmaState>,
+    tx_buf: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    ack_requested: Cell<bool>,
+    backoff_exponent: Cell<u8>,
+    backoffs: Cell<u8>,
+    retries: Cell<u8>,
+    // The busy channels found over all attempts, unlike `backoffs`
+    channel_busy: Cell<u8>,
 }
 
-impl<'a, R: radio::Radio + 'a> AwakeMac<'a, R> {
-    pub fn new(radio: &'a R) -> AwakeMac<'a, R> {
+impl<'a, R: radio::Radio + 'a, A: Alarm + 'a> AwakeMac<'a, R, A> {
+    pub fn new(radio: &'a R, alarm: &'a A, rng: &'a RNG) -> AwakeMac<'a, R, A> {
         AwakeMac {
             radio: radio,
+            alarm: alarm,
+            rng: rng,
             tx_client: Cell::new(None),
             rx_client: Cell::new(None),
+            csma: Cell::new(None),
+            state: Cell::new(CsmaState::Idle),
+            tx_buf: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            ack_requested: Cell::new(false),
+            backoff_exponent: Cell::new(0),
+            backoffs: Cell::new(0),
+            retries: Cell::new(0),
+            channel_busy: Cell::new(0),
+        }
+    }
+
+    /// Enables software CSMA-CA with the given parameters, or disables it if
+    /// `None`, in which case frames are passed straight to the radio. Returns
+    /// EBUSY if a frame is currently being transmitted, and EINVAL if the
+    /// backoff exponents are out of range.
+    pub fn set_csma(&self, config: Option<CsmaConfig>) -> ReturnCode {
+        if self.state.get() != CsmaState::Idle {
+            return ReturnCode::EBUSY;
+        }
+        if let Some(config) = config {
+            if config.min_be > config.max_be || config.max_be > 8 {
+                return ReturnCode::EINVAL;
+            }
+        }
+        self.csma.set(config);
+        ReturnCode::SUCCESS
+    }
+
+    /// The current CSMA-CA parameters, or `None` if CSMA-CA is disabled
+    pub fn get_csma(&self) -> Option<CsmaConfig> {
+        self.csma.get()
+    }
+
+    /// Starts a new CSMA-CA procedure for the pending frame
+    fn start_csma(&self, config: CsmaConfig) {
+        self.backoffs.set(0);
+        self.backoff_exponent.set(config.min_be);
+        self.backoff();
+    }
+
+    /// Requests randomness to wait for a random number of backoff periods
+    /// before the next transmission attempt
+    fn backoff(&self) {
+        self.state.set(CsmaState::WaitingForRandom);
+        self.rng.get();
+    }
+
+    fn transmit_attempt(&self) {
+        self.state.set(CsmaState::Transmitting);
+        self.tx_buf.take().map(|buf| {
+            let (rval, buf) = self.radio.transmit(buf, self.tx_len.get());
+            if let Some(buf) = buf {
+                // A radio that is busy receiving a frame has also found the
+                // channel busy
+                if rval == ReturnCode::EBUSY {
+                    self.channel_busy(buf);
+                } else {
+                    self.transmit_done(buf, false, rval);
+                }
+            }
+        });
+    }
+
+    /// Backs off again with a larger exponent after finding the channel busy,
+    /// unless the maximum number of backoffs has been reached
+    fn channel_busy(&self, buf: &'static mut [u8]) {
+        let config = match self.csma.get() {
+            Some(config) => config,
+            None => return self.transmit_done(buf, false, ReturnCode::EBUSY),
+        };
+        self.channel_busy
+            .set(self.channel_busy.get().saturating_add(1));
+        let backoffs = self.backoffs.get() + 1;
+        if backoffs > config.max_csma_backoffs {
+            self.transmit_done(buf, false, ReturnCode::EBUSY);
+        } else {
+            self.backoffs.set(backoffs);
+            self.backoff_exponent
+                .set(min(self.backoff_exponent.get() + 1, config.max_be));
+            self.tx_buf.replace(buf);
+            self.backoff();
         }
     }
+
+    /// Returns the pending frame to the transmit client
+    fn transmit_done(&self, buf: &'static mut [u8], acked: bool, result: ReturnCode) {
+        self.state.set(CsmaState::Idle);
+        let info = radio::TxInfo {
+            retries: self.retries.get(),
+            channel_busy: self.channel_busy.get(),
+        };
+        self.tx_client.get().map(move |c| {
+            c.send_done_info(buf, acked, result, info);
+        });
+    }
 }
 
-impl<'a, R: radio::Radio + 'a> Mac for AwakeMac<'a, R> {
+impl<'a, R: radio::Radio + 'a, A: Alarm + 'a> Mac for AwakeMac<'a, R, A> {
     fn initialize(&self, _mac_buf: &'static mut [u8]) -> ReturnCode {
         // do nothing, extra buffer unnecessary
         ReturnCode::SUCCESS
@@ -109,6 +317,14 @@ impl<'a, R: radio::Radio + 'a> Mac for AwakeMac<'a, R> {
         self.radio.set_pan(id)
     }
 
+    fn set_channel(&self, chan: u8) -> ReturnCode {
+        self.radio.set_channel(chan)
+    }
+
+    fn set_ack_frame_pending(&self, pending: bool) {
+        self.radio.set_ack_frame_pending(pending)
+    }
+
     fn get_address(&self) -> u16 {
         self.radio.get_address()
     }
@@ -121,6 +337,10 @@ impl<'a, R: radio::Radio + 'a> Mac for AwakeMac<'a, R> {
         self.radio.get_pan()
     }
 
+    fn get_channel(&self) -> u8 {
+        self.radio.get_channel()
+    }
+
     fn config_commit(&self) {
         self.radio.config_commit()
     }
@@ -142,40 +362,118 @@ impl<'a, R: radio::Radio + 'a> Mac for AwakeMac<'a, R> {
         full_mac_frame: &'static mut [u8],
         frame_len: usize,
     ) -> (ReturnCode, Option<&'static mut [u8]>) {
-        self.radio.transmit(full_mac_frame, frame_len)
+        let config = match self.csma.get() {
+            Some(config) => config,
+            None => return self.radio.transmit(full_mac_frame, frame_len),
+        };
+        if self.state.get() != CsmaState::Idle {
+            return (ReturnCode::EBUSY, Some(full_mac_frame));
+        } else if !self.radio.is_on() {
+            return (ReturnCode::EOFF, Some(full_mac_frame));
+        }
+
+        let ack_requested =
+            match Header::decode(&full_mac_frame[radio::PSDU_OFFSET..], false).done() {
+                Some((_, (header, _))) => header.ack_requested,
+                None => return (ReturnCode::FAIL, Some(full_mac_frame)),
+            };
+        self.ack_requested.set(ack_requested);
+        self.tx_buf.replace(full_mac_frame);
+        self.tx_len.set(frame_len);
+        self.retries.set(0);
+        self.channel_busy.set(0);
+        self.start_csma(config);
+        (ReturnCode::SUCCESS, None)
     }
 }
 
-impl<'a, R: radio::Radio + 'a> radio::TxClient for AwakeMac<'a, R> {
+impl<'a, R: radio::Radio + 'a, A: Alarm + 'a> radio::TxClient for AwakeMac<'a, R, A> {
     fn send_done(&self, buf: &'static mut [u8], acked: bool, result: ReturnCode) {
-        self.tx_client.get().map(move |c| {
-            c.send_done(buf, acked, result);
-        });
+        let config = match self.csma.get() {
+            Some(config) if self.state.get() == CsmaState::Transmitting => config,
+            _ => {
+                self.tx_client.get().map(move |c| {
+                    c.send_done(buf, acked, result);
+                });
+                return;
+            }
+        };
+
+        if result == ReturnCode::EBUSY {
+            self.channel_busy(buf);
+        } else if result == ReturnCode::SUCCESS
+            && self.ack_requested.get()
+            && !acked
+            && self.retries.get() < config.max_frame_retries
+        {
+            self.retries.set(self.retries.get() + 1);
+            self.tx_buf.replace(buf);
+            self.start_csma(config);
+        } else {
+            self.transmit_done(buf, acked, result);
+        }
+    }
+}
+
+impl<'a, R: radio::Radio + 'a, A: Alarm + 'a> rng::Client for AwakeMac<'a, R, A> {
+    fn randomness_available(&self, randomness: &mut Iterator<Item = u32>) -> rng::Continue {
+        if self.state.get() != CsmaState::WaitingForRandom {
+            return rng::Continue::Done;
+        }
+        match randomness.next() {
+            Some(random) => {
+                let mask = (1u32 << self.backoff_exponent.get()) - 1;
+                let periods = (random & mask) as u64;
+                if periods == 0 {
+                    self.transmit_attempt();
+                } else {
+                    let us = periods * UNIT_BACKOFF_PERIOD_US;
+                    let tics = us * <A::Frequency>::frequency() as u64 / 1_000_000;
+                    self.state.set(CsmaState::Backoff);
+                    self.alarm
+                        .set_alarm(self.alarm.now().wrapping_add(tics as u32));
+                }
+                rng::Continue::Done
+            }
+            None => rng::Continue::More,
+        }
+    }
+}
+
+impl<'a, R: radio::Radio + 'a, A: Alarm + 'a> time::Client for AwakeMac<'a, R, A> {
+    fn fired(&self) {
+        if self.state.get() == CsmaState::Backoff {
+            self.transmit_attempt();
+        }
     }
 }
 
-impl<'a, R: radio::Radio + 'a> radio::RxClient for AwakeMac<'a, R> {
+impl<'a, R: radio::Radio + 'a, A: Alarm + 'a> radio::RxClient for AwakeMac<'a, R, A> {
     fn receive(
         &self,
         buf: &'static mut [u8],
         frame_len: usize,
         crc_valid: bool,
+        info: radio::RxInfo,
         result: ReturnCode,
     ) {
-        // Filter packets by destination because radio is in promiscuous mode
+        // Filter packets by destination because radio is in promiscuous mode.
+        // Broadcast frames are accepted, as are beacons, which carry no
+        // destination address.
         let mut addr_match = false;
         if let Some((_, (header, _))) = Header::decode(&buf[radio::PSDU_OFFSET..], false).done() {
-            if let Some(dst_addr) = header.dst_addr {
-                addr_match = match dst_addr {
-                    MacAddress::Short(addr) => addr == self.radio.get_address(),
-                    MacAddress::Long(long_addr) => long_addr == self.radio.get_address_long(),
-                };
-            }
+            addr_match = match header.dst_addr {
+                Some(MacAddress::Short(addr)) => {
+                    addr == self.radio.get_address() || addr == BROADCAST_ADDR
+                }
+                Some(MacAddress::Long(long_addr)) => long_addr == self.radio.get_address_long(),
+                None => header.frame_type == FrameType::Beacon,
+            };
         }
 
         if addr_match {
             self.rx_client.get().map(move |c| {
-                c.receive(buf, frame_len, crc_valid, result);
+                c.receive(buf, frame_len, crc_valid, info, result);
             });
         } else {
             self.radio.set_receive_buffer(buf);
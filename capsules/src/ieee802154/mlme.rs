@@ -0,0 +1,1648 @@
+//! Implements a subset of the IEEE 802.15.4 MAC layer management entity
+//! (MLME): beacon transmission, active/passive channel scanning, association
+//! with a PAN, and indirect transmission.
+//!
+//! A device that acts as a PAN coordinator replies to beacon requests with a
+//! beacon advertising its PAN, and, if it is started with a beacon order lower
+//! than 15, also sends beacons periodically. Beacons are sent without
+//! superframe structure, that is, the coordinator does not sleep between them.
+//!
+//! A scan walks through a mask of channels, listening on each channel for the
+//! scan duration and collecting a PAN descriptor from each distinct beacon that
+//! is received. An active scan additionally broadcasts a beacon request when
+//! it switches to a channel, while a passive scan only listens for periodic
+//! beacons. Once all channels have been scanned, the original channel is
+//! restored and the PAN descriptors are returned to the scan client, along
+//! with the number of beacons that were dropped because they failed the
+//! incoming frame security procedure.
+//!
+//! A device joins a PAN, typically found by a scan, by sending an association
+//! request to its coordinator. The coordinator allocates a short address to the
+//! device if it permits association and has room for it, and holds the
+//! association response until the device polls for it with a data request
+//! macResponseWaitTime later (IEEE 802.15.4-2015: 6.3.1). Either side can end
+//! the association with a disassociation notification. The association client
+//! is notified whenever the association state of the device, or of the devices
+//! in the PAN that it coordinates, changes.
+//!
+//! A coordinator can also hold data frames for devices that keep their radio
+//! off most of the time, such as battery-powered end devices (indirect
+//! transmission, IEEE 802.15.4-2015: 6.7.3). A frame queued with
+//! `transmit_indirect` is held until its destination polls the coordinator
+//! with a data request, or until it expires after macTransactionPersistenceTime.
+//! While frames are held, acknowledgements to data requests have their Frame
+//! Pending field set, and a frame sent to a polling device has it set if more
+//! frames remain for that device. A device that polls while no frame is held
+//! for it is sent an empty data frame.
+//!
+//! Conversely, an associated device can poll its coordinator periodically, or
+//! on demand with `poll`. Once a data request is acknowledged, the device
+//! waits macMaxFrameTotalWaitTime for the frame, and polls again right away if
+//! the frame indicates that more are pending. Received frames are delivered to
+//! the receive clients of the MAC device as usual. Turning the radio off
+//! between polls is left to the MAC layer.
+//!
+//! Scanning, associating, polling and coordinating are mutually exclusive.
+//!
+//! Usage
+//! -----
+//!
+//! `MacManager` is a user of the MAC device, so it is usually attached to a
+//! `virtual_mac::MuxMac` alongside the other users of the radio:
+//!
+//! ```rust
+//! let mlme_mac = static_init!(
+//!     capsules::ieee802154::virtual_mac::MacUser<'static>,
+//!     capsules::ieee802154::virtual_mac::MacUser::new(mux_mac));
+//! mux_mac.add_user(mlme_mac);
+//! let mlme = static_init!(
+//!     capsules::ieee802154::mlme::MacManager<'static,
+//!         VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     capsules::ieee802154::mlme::MacManager::new(mlme_mac, mlme_alarm, &mut MLME_BUF));
+//! mlme_mac.set_transmit_client(mlme);
+//! mlme_mac.set_receive_client(mlme);
+//! mlme_alarm.set_client(mlme);
+//!
+//! mlme.set_scan_client(scan_client);
+//! mlme.add_association_client(association_client);
+//! mlme.scan(ScanType::Active, 1 << 26 | 1 << 25, 3);
+//! ```
+//!
+//! Holding frames for indirect transmission requires buffers, and a client to
+//! notify once the frames are sent:
+//!
+//! ```rust
+//! mlme.set_indirect_buffers(&mut MLME_INDIRECT_BUFS);
+//! mlme.set_indirect_client(indirect_client);
+//! ```
+
+use core::cell::Cell;
+use core::cmp::max;
+use ieee802154::device::{MacDevice, RxClient, SecurityError, TxClient};
+use ieee802154::framer::Frame;
+use kernel::ReturnCode;
+use kernel::common::take_cell::{MapCell, TakeCell};
+use kernel::hil::radio;
+use kernel::hil::time::{self, Alarm, Frequency};
+use net::ieee802154::*;
+
+/// The maximum number of PAN descriptors that can be collected by a scan
+pub const MAX_PAN_DESCRIPTORS: usize = 8;
+
+/// The channels of the 2.4 GHz O-QPSK PHY, 11 to 26, as a channel mask
+pub const CHANNELS_2450MHZ: u32 = 0x07fff800;
+
+/// The largest scan duration allowed (IEEE 802.15.4-2015: Table 8-38)
+pub const MAX_SCAN_DURATION: u8 = 14;
+
+/// The maximum number of devices that can be associated with the PAN while
+/// acting as a coordinator
+pub const MAX_ASSOCIATED_DEVICES: usize = 8;
+
+/// The maximum number of frames held for indirect transmission while acting
+/// as a coordinator
+pub const MAX_PENDING_TRANSACTIONS: usize = 4;
+
+/// The maximum number of clients notified of changes to the association state
+pub const MAX_ASSOCIATION_CLIENTS: usize = 2;
+
+/// The number of symbols forming a superframe when the superframe order is 0
+/// (aBaseSuperframeDuration)
+const BASE_SUPERFRAME_DURATION: u64 = 960;
+
+/// The number of symbols to wait after an association request is acknowledged
+/// before polling the coordinator for the response (macResponseWaitTime)
+const RESPONSE_WAIT_TIME: u64 = 32 * BASE_SUPERFRAME_DURATION;
+
+/// The number of symbols to wait for the association response once the data
+/// request polling for it is acknowledged (macMaxFrameTotalWaitTime, for the
+/// default MAC attributes of the 2.4 GHz O-QPSK PHY)
+const MAX_FRAME_TOTAL_WAIT_TIME: u64 = 1220;
+
+/// The number of unit periods during which a frame is held for indirect
+/// transmission before it expires (macTransactionPersistenceTime)
+const TRANSACTION_PERSISTENCE_TIME: u64 = 0x01f4;
+
+/// The duration of a symbol of the 2.4 GHz O-QPSK PHY, in microseconds
+const SYMBOL_DURATION_US: u64 = 16;
+
+/// Information about a PAN collected from one of its beacons during a scan
+/// (IEEE 802.15.4-2015: Table 8-16)
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct PanDescriptor {
+    /// The channel on which the beacon was received
+    pub channel: u8,
+    /// The PAN ID of the coordinator
+    pub coord_pan: PanID,
+    /// The address of the coordinator
+    pub coord_addr: MacAddress,
+    /// The superframe specification advertised in the beacon
+    pub superframe_spec: SuperframeSpec,
+    /// The link quality of the beacon, as measured by the radio
+    pub lqi: u8,
+}
+
+impl Default for PanDescriptor {
+    fn default() -> Self {
+        PanDescriptor {
+            channel: 0,
+            coord_pan: 0,
+            coord_addr: MacAddress::Short(0),
+            superframe_spec: SuperframeSpec::from_u16(0),
+            lqi: 0,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ScanType {
+    /// Broadcast a beacon request on each channel
+    Active,
+    /// Only listen for beacons on each channel
+    Passive,
+}
+
+/// Trait to be implemented by users of the MLME that request scans
+pub trait ScanClient {
+    /// Called when a scan completes. `result` is `SUCCESS` if all channels
+    /// were scanned, or `ENOMEM` if more PANs were found than could be
+    /// recorded. `pan_descriptors` contains the PANs found, in the order in
+    /// which their beacons were received. `security_failures` is the number
+    /// of beacons that failed the incoming frame security procedure, whose
+    /// PANs are not in `pan_descriptors`.
+    fn scan_done(
+        &self,
+        result: ReturnCode,
+        pan_descriptors: &[PanDescriptor],
+        security_failures: usize,
+    );
+}
+
+/// A device associated with the PAN coordinated by this device
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct AssociatedDevice {
+    /// The long address of the device
+    pub addr_long: [u8; 8],
+    /// The short address allocated to the device, or 0xfffe if it did not
+    /// request one
+    pub short_addr: u16,
+    /// The capability information sent by the device
+    pub capability: u8,
+}
+
+/// Trait to be implemented by users of the MLME that track the association
+/// state of this device, or of the devices in the PAN it coordinates
+pub trait AssociationClient {
+    /// Called when an association attempt completes. On success,
+    /// `short_addr` is the short address allocated by the coordinator, which
+    /// is now configured on the MAC device, or 0xfffe if the device should use
+    /// its long address. Otherwise, `result` is ENOMEM if the PAN is at
+    /// capacity, FAIL if the coordinator denied access, ENOACK if the
+    /// coordinator did not respond, or the error that prevented polling it.
+    fn associate_done(&self, result: ReturnCode, short_addr: u16);
+
+    /// Called when this device leaves its PAN, either because it requested to
+    /// or because its coordinator asked it to. `reason` is one of the
+    /// `disassociation_reason` values.
+    fn disassociated(&self, reason: u8);
+
+    /// Called when a device joins the PAN coordinated by this device
+    fn device_associated(&self, addr_long: [u8; 8], short_addr: u16);
+
+    /// Called when a device leaves the PAN coordinated by this device
+    fn device_disassociated(&self, addr_long: [u8; 8], short_addr: u16);
+}
+
+/// Trait to be implemented by users of the MLME that hold frames for
+/// indirect transmission
+pub trait IndirectClient {
+    /// Called when a frame held for `dst_addr` has been sent to the device
+    /// polling for it, or has been discarded. `result` is SUCCESS if the frame
+    /// was sent, in which case `acked` indicates whether the device
+    /// acknowledged it, ENOACK if the frame expired before the device polled
+    /// for it, ECANCEL if the device stopped acting as a coordinator, or the
+    /// error that prevented the transmission.
+    fn indirect_done(&self, dst_addr: MacAddress, acked: bool, result: ReturnCode);
+}
+
+/// The MAC layer management operations exposed to users of the MLME
+pub trait Mlme<'a> {
+    /// Sets the client notified when a scan completes
+    fn set_scan_client(&self, client: &'a ScanClient);
+
+    /// Starts scanning the channels whose bits are set in `channels`, spending
+    /// `aBaseSuperframeDuration * (2^duration + 1)` symbols on each channel.
+    /// Returns EBUSY if a scan is in progress or the device is acting as a
+    /// coordinator, and EINVAL if no supported channel is selected or the
+    /// duration is too long.
+    fn scan(&self, scan_type: ScanType, channels: u32, duration: u8) -> ReturnCode;
+
+    /// Starts acting as the coordinator of the PAN configured on the MAC
+    /// device, answering beacon requests. If `beacon_order` is lower than 15,
+    /// beacons are also sent every `aBaseSuperframeDuration * 2^beacon_order`
+    /// symbols. Returns EBUSY if a scan is in progress.
+    fn start_coordinator(&self, beacon_order: u8) -> ReturnCode;
+
+    /// Stops acting as a coordinator. Returns EALREADY if the device was not
+    /// acting as a coordinator.
+    fn stop_coordinator(&self) -> ReturnCode;
+
+    /// Whether the device is currently acting as a coordinator
+    fn is_coordinator(&self) -> bool;
+
+    /// Adds a client notified of changes to the association state, such as
+    /// the userspace driver or a layer that must drop state tied to the
+    /// former addresses. Returns ENOMEM if `MAX_ASSOCIATION_CLIENTS` clients
+    /// were already added.
+    fn add_association_client(&self, client: &'a AssociationClient) -> ReturnCode;
+
+    /// Requests association with the coordinator `coord_addr` of the PAN
+    /// `coord_pan` operating on `channel`. `capability` is a combination of
+    /// `capability_info` flags. The outcome is reported through
+    /// `AssociationClient::associate_done`, after which the channel and PAN
+    /// ID configured before the attempt are restored if it failed. Returns
+    /// EBUSY if another operation is in progress, EALREADY if the device is
+    /// already associated, and EINVAL if the channel is not supported.
+    fn associate(
+        &self,
+        channel: u8,
+        coord_pan: PanID,
+        coord_addr: MacAddress,
+        capability: u8,
+    ) -> ReturnCode;
+
+    /// Notifies the coordinator that this device leaves the PAN. Once the
+    /// notification has been sent, the short address and PAN ID of the device
+    /// are reset and `AssociationClient::disassociated` is called. Returns
+    /// EALREADY if the device is not associated.
+    fn disassociate(&self) -> ReturnCode;
+
+    /// The PAN ID and address of the coordinator this device is associated
+    /// with
+    fn get_coordinator(&self) -> Option<(PanID, MacAddress)>;
+
+    /// Sets whether association requests are accepted while acting as a
+    /// coordinator. This is advertised in beacons.
+    fn set_association_permit(&self, permit: bool);
+
+    /// Asks the associated device with long address `addr_long` to leave the
+    /// PAN and removes it from the PAN immediately. Returns EINVAL if no such
+    /// device is associated.
+    fn disassociate_device(&self, addr_long: [u8; 8]) -> ReturnCode;
+
+    /// Returns the `index`-th device associated with the PAN coordinated by
+    /// this device, if there are that many
+    fn get_associated_device(&self, index: usize) -> Option<AssociatedDevice>;
+
+    /// Sets the client notified when a frame held for indirect transmission
+    /// is sent or discarded
+    fn set_indirect_client(&self, client: &'a IndirectClient);
+
+    /// Holds a data frame carrying `payload` for the device `dst_addr` until
+    /// it polls this coordinator, securing it as requested by
+    /// `security_needed`. The outcome is reported through
+    /// `IndirectClient::indirect_done`. Returns EINVAL if the device is not
+    /// acting as a coordinator, ENOMEM if no more frames can be held, ESIZE
+    /// if the payload does not fit in a frame, and FAIL if the frame cannot be
+    /// secured.
+    fn transmit_indirect(
+        &self,
+        dst_addr: MacAddress,
+        payload: &[u8],
+        security_needed: Option<(SecurityLevel, KeyId)>,
+    ) -> ReturnCode;
+
+    /// Sets the interval at which this device polls its coordinator for
+    /// frames held for it while it is associated, in milliseconds. An interval
+    /// of 0 disables periodic polling.
+    fn set_poll_interval(&self, interval_ms: u32);
+
+    /// The interval at which this device polls its coordinator, in
+    /// milliseconds, or 0 if it does not poll periodically
+    fn get_poll_interval(&self) -> u32;
+
+    /// Polls the coordinator for frames held for this device. Returns EBUSY if
+    /// another operation is in progress, and EINVAL if the device is not
+    /// associated.
+    fn poll(&self) -> ReturnCode;
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum State {
+    Idle,
+    Scanning,
+    Coordinator,
+    Associating(AssociationStep),
+    /// Sending a disassociation notification to the coordinator
+    Disassociating,
+    Polling(PollStep),
+}
+
+/// The steps of an association attempt, on the device side
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum AssociationStep {
+    /// The association request is being transmitted
+    SendingRequest,
+    /// Waiting for the coordinator to prepare the association response
+    WaitingForResponse,
+    /// The data request polling for the response is being transmitted
+    SendingDataRequest,
+    /// Waiting for the coordinator to send the association response
+    ReceivingResponse,
+}
+
+/// The steps of a poll of the coordinator, on the device side
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum PollStep {
+    /// The data request is being transmitted
+    SendingRequest,
+    /// Waiting for the coordinator to send a frame held for this device
+    ReceivingData,
+}
+
+/// An entry in the table of devices associated with the PAN
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+struct DeviceEntry {
+    device: AssociatedDevice,
+    /// Whether the device has acknowledged a successful association response
+    associated: bool,
+    /// Whether an association response is held for the device to poll
+    response_pending: bool,
+}
+
+/// A data frame held for indirect transmission
+#[derive(Eq, PartialEq, Debug)]
+struct Transaction {
+    dst_addr: MacAddress,
+    frame: Frame,
+    /// The time at which the frame expires, in alarm tics
+    expiry: u32,
+}
+
+pub struct MacManager<'a, A: Alarm + 'a> {
+    mac: &'a MacDevice<'a>,
+    alarm: &'a A,
+    state: Cell<State>,
+
+    /// Buffer used to send beacons and beacon requests. It is `None` while a
+    /// frame is being transmitted.
+    tx_buf: TakeCell<'static, [u8]>,
+
+    /// Beacon order of the PAN while acting as a coordinator
+    beacon_order: Cell<u8>,
+    /// The time at which the next beacon is due, in alarm tics
+    next_beacon: Cell<u32>,
+
+    /// Parameters and progress of the current scan. `scan_channels` contains
+    /// the channels that remain to be scanned.
+    scan_type: Cell<ScanType>,
+    scan_channels: Cell<u32>,
+    scan_channel: Cell<u8>,
+    scan_duration: Cell<u8>,
+    /// The channel to return to once the scan is complete, or once an
+    /// association attempt fails
+    original_channel: Cell<u8>,
+    pan_descriptors: MapCell<[PanDescriptor; MAX_PAN_DESCRIPTORS]>,
+    num_pan_descriptors: Cell<usize>,
+    /// Whether a beacon was dropped because `pan_descriptors` was full
+    pan_descriptors_overflow: Cell<bool>,
+    /// The number of beacons dropped because they failed the incoming frame
+    /// security procedure
+    scan_security_failures: Cell<usize>,
+    scan_client: Cell<Option<&'a ScanClient>>,
+
+    /// The PAN ID and address of the coordinator, while associating with or
+    /// associated to a PAN
+    coord: Cell<Option<(PanID, MacAddress)>>,
+    /// The PAN ID to return to once an association attempt fails
+    original_pan: Cell<PanID>,
+    /// Whether association requests are accepted while acting as a
+    /// coordinator
+    association_permit: Cell<bool>,
+    devices: MapCell<[Option<DeviceEntry>; MAX_ASSOCIATED_DEVICES]>,
+    /// The long address of the last device whose association request was
+    /// rejected because `devices` was full
+    rejected_device: Cell<Option<[u8; 8]>>,
+    /// The long address of the device that an association response is being
+    /// transmitted to
+    response_dst: Cell<Option<[u8; 8]>>,
+    association_clients: [Cell<Option<&'a AssociationClient>>; MAX_ASSOCIATION_CLIENTS],
+
+    /// Frames held for indirect transmission while acting as a coordinator.
+    /// There is a free entry for each buffer in `indirect_bufs`.
+    transactions: MapCell<[Option<Transaction>; MAX_PENDING_TRANSACTIONS]>,
+    /// Buffers available to hold more frames
+    indirect_bufs: MapCell<[Option<&'static mut [u8]>; MAX_PENDING_TRANSACTIONS]>,
+    /// The destination of the held frame being transmitted
+    indirect_dst: Cell<Option<MacAddress>>,
+    /// Whether acknowledgements to data requests indicate pending frames
+    ack_frame_pending: Cell<bool>,
+    indirect_client: Cell<Option<&'a IndirectClient>>,
+
+    /// Interval between two polls of the coordinator, in milliseconds
+    poll_interval_ms: Cell<u32>,
+}
+
+impl<'a, A: Alarm + 'a> MacManager<'a, A> {
+    pub fn new(
+        mac: &'a MacDevice<'a>,
+        alarm: &'a A,
+        tx_buf: &'static mut [u8],
+    ) -> MacManager<'a, A> {
+        MacManager {
+            mac: mac,
+            alarm: alarm,
+            state: Cell::new(State::Idle),
+            tx_buf: TakeCell::new(tx_buf),
+            beacon_order: Cell::new(BEACON_ORDER_NONBEACON),
+            next_beacon: Cell::new(0),
+            scan_type: Cell::new(ScanType::Passive),
+            scan_channels: Cell::new(0),
+            scan_channel: Cell::new(0),
+            scan_duration: Cell::new(0),
+            original_channel: Cell::new(0),
+            pan_descriptors: MapCell::new(Default::default()),
+            num_pan_descriptors: Cell::new(0),
+            pan_descriptors_overflow: Cell::new(false),
+            scan_security_failures: Cell::new(0),
+            scan_client: Cell::new(None),
+            coord: Cell::new(None),
+            original_pan: Cell::new(BROADCAST_PAN),
+            association_permit: Cell::new(false),
+            devices: MapCell::new([None; MAX_ASSOCIATED_DEVICES]),
+            rejected_device: Cell::new(None),
+            response_dst: Cell::new(None),
+            association_clients: Default::default(),
+            transactions: MapCell::new(Default::default()),
+            indirect_bufs: MapCell::new(Default::default()),
+            indirect_dst: Cell::new(None),
+            ack_frame_pending: Cell::new(false),
+            indirect_client: Cell::new(None),
+            poll_interval_ms: Cell::new(0),
+        }
+    }
+
+    /// Provides the buffers used to hold frames for indirect transmission.
+    /// Without them, no frame can be held.
+    pub fn set_indirect_buffers(
+        &self,
+        bufs: &'static mut [[u8; radio::MAX_BUF_SIZE]; MAX_PENDING_TRANSACTIONS],
+    ) {
+        self.indirect_bufs.map(move |indirect_bufs| {
+            for (slot, buf) in indirect_bufs.iter_mut().zip(bufs.iter_mut()) {
+                *slot = Some(buf);
+            }
+        });
+    }
+
+    /// Converts a number of symbols to alarm tics
+    fn symbols_to_tics(&self, symbols: u64) -> u32 {
+        let us = symbols * SYMBOL_DURATION_US;
+        (us * (<A::Frequency>::frequency() as u64) / 1_000_000) as u32
+    }
+
+    /// Arms the alarm to fire after the given number of symbols
+    fn set_alarm_symbols(&self, symbols: u64) {
+        let tics = self.symbols_to_tics(symbols);
+        self.alarm.set_alarm(self.alarm.now().wrapping_add(tics));
+    }
+
+    /// Whether the time `tics` has been reached
+    fn is_due(&self, tics: u32) -> bool {
+        self.alarm.now().wrapping_sub(tics) as i32 >= 0
+    }
+
+    /// While acting as a coordinator, arms the alarm for the next beacon or
+    /// for the expiry of the next held frame, whichever comes first
+    fn set_coordinator_alarm(&self) {
+        // Times that have already been reached are due right away
+        let now = self.alarm.now();
+        let delay_until = |tics: u32| max(tics.wrapping_sub(now) as i32, 1) as u32;
+        let next_beacon = if self.beacon_order.get() < BEACON_ORDER_NONBEACON {
+            Some(delay_until(self.next_beacon.get()))
+        } else {
+            None
+        };
+        let next_expiry = self.transactions.and_then(|transactions| {
+            transactions
+                .iter()
+                .filter_map(|transaction| transaction.as_ref())
+                .map(|transaction| delay_until(transaction.expiry))
+                .min()
+        });
+        match next_beacon.into_iter().chain(next_expiry).min() {
+            Some(delay) => self.alarm.set_alarm(now.wrapping_add(delay)),
+            None => self.alarm.disable(),
+        }
+    }
+
+    /// The source address used in beacons and beacon requests: the short
+    /// address if the device has been allocated one, or else the long address
+    fn src_addr(&self) -> MacAddress {
+        let addr = self.mac.get_address();
+        if addr == BROADCAST_ADDR || addr == NO_SHORT_ADDR {
+            MacAddress::Long(self.mac.get_address_long())
+        } else {
+            MacAddress::Short(addr)
+        }
+    }
+
+    /// The superframe specification advertised while acting as a coordinator.
+    /// There is no inactive period, so the superframe order matches the beacon
+    /// order, and the whole superframe is contention-based.
+    fn superframe_spec(&self) -> SuperframeSpec {
+        SuperframeSpec {
+            beacon_order: self.beacon_order.get(),
+            superframe_order: self.beacon_order.get(),
+            final_cap_slot: 15,
+            battery_life_extension: false,
+            pan_coordinator: true,
+            association_permit: self.association_permit.get(),
+        }
+    }
+
+    /// Transmits a beacon advertising the PAN. Returns EBUSY if another frame
+    /// is being transmitted.
+    fn send_beacon(&self) -> ReturnCode {
+        let buf = match self.tx_buf.take() {
+            Some(buf) => buf,
+            None => return ReturnCode::EBUSY,
+        };
+        let frame = match self.mac.prepare_beacon_frame(
+            buf,
+            self.mac.get_pan(),
+            self.src_addr(),
+            self.superframe_spec(),
+        ) {
+            Ok(frame) => frame,
+            Err(buf) => {
+                self.tx_buf.replace(buf);
+                return ReturnCode::FAIL;
+            }
+        };
+        self.transmit(frame)
+    }
+
+    /// Broadcasts a beacon request to all PANs on the current channel
+    /// (IEEE 802.15.4-2015: 7.5.8). The request carries no source address.
+    fn send_beacon_request(&self) -> ReturnCode {
+        self.send_command(
+            BROADCAST_PAN,
+            MacAddress::Short(BROADCAST_ADDR),
+            BROADCAST_PAN,
+            None,
+            mac_command::BEACON_REQUEST,
+            &[],
+        )
+    }
+
+    /// Transmits a MAC command frame whose payload is the command identifier
+    /// followed by `content`. Returns EBUSY if another frame is being
+    /// transmitted.
+    fn send_command(
+        &self,
+        dst_pan: PanID,
+        dst_addr: MacAddress,
+        src_pan: PanID,
+        src_addr: Option<MacAddress>,
+        command_id: u8,
+        content: &[u8],
+    ) -> ReturnCode {
+        let buf = match self.tx_buf.take() {
+            Some(buf) => buf,
+            None => return ReturnCode::EBUSY,
+        };
+        let mut frame = match self.mac.prepare_command_frame(
+            buf, dst_pan, dst_addr, src_pan, src_addr, command_id, None,
+        ) {
+            Ok(frame) => frame,
+            Err(buf) => {
+                self.tx_buf.replace(buf);
+                return ReturnCode::FAIL;
+            }
+        };
+        let rval = frame.append_payload(content);
+        if rval != ReturnCode::SUCCESS {
+            self.tx_buf.replace(frame.into_buf());
+            return rval;
+        }
+        self.transmit(frame)
+    }
+
+    fn transmit(&self, frame: Frame) -> ReturnCode {
+        let (rval, buf) = self.mac.transmit(frame);
+        if let Some(buf) = buf {
+            self.tx_buf.replace(buf);
+        }
+        rval
+    }
+
+    /// Switches to the next channel that remains to be scanned, or completes
+    /// the scan if there is none left.
+    fn scan_next_channel(&self) {
+        loop {
+            let channels = self.scan_channels.get();
+            if channels == 0 {
+                self.scan_done();
+                return;
+            }
+            let channel = channels.trailing_zeros() as u8;
+            self.scan_channels.set(channels & !(1 << channel));
+
+            // Skip channels that the radio does not support
+            if self.mac.set_channel(channel) != ReturnCode::SUCCESS {
+                continue;
+            }
+            self.mac.config_commit();
+            self.scan_channel.set(channel);
+
+            // If the beacon request cannot be sent, the channel is still
+            // scanned passively
+            if self.scan_type.get() == ScanType::Active {
+                self.send_beacon_request();
+            }
+
+            let duration = self.scan_duration.get();
+            self.set_alarm_symbols(BASE_SUPERFRAME_DURATION * ((1 << duration) + 1));
+            return;
+        }
+    }
+
+    /// Restores the original channel and reports the scan results
+    fn scan_done(&self) {
+        self.mac.set_channel(self.original_channel.get());
+        self.mac.config_commit();
+        self.state.set(State::Idle);
+        self.schedule_poll();
+
+        let result = if self.pan_descriptors_overflow.get() {
+            ReturnCode::ENOMEM
+        } else {
+            ReturnCode::SUCCESS
+        };
+        let num_pan_descriptors = self.num_pan_descriptors.get();
+        let security_failures = self.scan_security_failures.get();
+        self.scan_client.get().map(|client| {
+            self.pan_descriptors.map(|pan_descriptors| {
+                client.scan_done(
+                    result,
+                    &pan_descriptors[..num_pan_descriptors],
+                    security_failures,
+                );
+            });
+        });
+    }
+
+    /// Records the PAN advertised by a beacon received during a scan, unless
+    /// it was already found on the same channel
+    fn record_beacon(&self, header: &Header, info: radio::RxInfo, payload: &[u8]) {
+        let (coord_pan, coord_addr) = match (header.src_pan, header.src_addr) {
+            (Some(pan), Some(addr)) => (pan, addr),
+            _ => return,
+        };
+        let superframe_spec = match decode_beacon(payload).done() {
+            Some((_, superframe_spec)) => superframe_spec,
+            None => return,
+        };
+        let new_descriptor = PanDescriptor {
+            channel: self.scan_channel.get(),
+            coord_pan: coord_pan,
+            coord_addr: coord_addr,
+            superframe_spec: superframe_spec,
+            lqi: info.lqi,
+        };
+
+        let num_pan_descriptors = self.num_pan_descriptors.get();
+        self.pan_descriptors.map(|pan_descriptors| {
+            let known = pan_descriptors[..num_pan_descriptors].iter().any(|descriptor| {
+                descriptor.channel == new_descriptor.channel
+                    && descriptor.coord_pan == new_descriptor.coord_pan
+                    && descriptor.coord_addr == new_descriptor.coord_addr
+            });
+            if known {
+                return;
+            }
+            if num_pan_descriptors == MAX_PAN_DESCRIPTORS {
+                self.pan_descriptors_overflow.set(true);
+                return;
+            }
+            pan_descriptors[num_pan_descriptors] = new_descriptor;
+            self.num_pan_descriptors.set(num_pan_descriptors + 1);
+        });
+    }
+
+    /// Whether a frame addressed to `addr` is addressed to this device rather
+    /// than broadcast
+    fn is_own_addr(&self, addr: Option<MacAddress>) -> bool {
+        match addr {
+            Some(MacAddress::Long(addr_long)) => addr_long == self.mac.get_address_long(),
+            Some(MacAddress::Short(addr)) => {
+                addr == self.mac.get_address() && addr != BROADCAST_ADDR && addr != NO_SHORT_ADDR
+            }
+            None => false,
+        }
+    }
+
+    /// Polls the coordinator for the frames it holds for this device, which
+    /// is identified by `src_addr`
+    fn send_data_request(&self, src_addr: MacAddress) -> ReturnCode {
+        let (coord_pan, coord_addr) = match self.coord.get() {
+            Some(coord) => coord,
+            None => return ReturnCode::FAIL,
+        };
+        self.send_command(
+            coord_pan,
+            coord_addr,
+            coord_pan,
+            Some(src_addr),
+            mac_command::DATA_REQUEST,
+            &[],
+        )
+    }
+
+    /// Arms the alarm for the next periodic poll of the coordinator, if this
+    /// device is associated and polls periodically
+    fn schedule_poll(&self) {
+        let interval_ms = self.poll_interval_ms.get();
+        if self.state.get() != State::Idle || self.coord.get().is_none() || interval_ms == 0 {
+            return;
+        }
+        let tics = (interval_ms as u64) * (<A::Frequency>::frequency() as u64) / 1000;
+        self.alarm
+            .set_alarm(self.alarm.now().wrapping_add(tics as u32));
+    }
+
+    /// Sends a data request polling the coordinator. Once associated, the
+    /// device is identified by its short address if it was allocated one.
+    fn start_poll(&self) -> ReturnCode {
+        let rval = self.send_data_request(self.src_addr());
+        if rval == ReturnCode::SUCCESS {
+            self.state.set(State::Polling(PollStep::SendingRequest));
+        }
+        rval
+    }
+
+    /// Completes a poll of the coordinator and schedules the next one
+    fn poll_done(&self) {
+        self.state.set(State::Idle);
+        self.schedule_poll();
+    }
+
+    /// Handles a data frame received while polling the coordinator. If the
+    /// frame indicates that more frames are held for this device, they are
+    /// polled for immediately.
+    fn receive_polled_data(&self, header: &Header) {
+        let from_coord = match (self.coord.get(), header.src_addr) {
+            (Some((_, coord_addr)), Some(src_addr)) => coord_addr == src_addr,
+            _ => false,
+        };
+        if !from_coord || !self.is_own_addr(header.dst_addr) {
+            return;
+        }
+        if header.frame_pending && self.start_poll() == ReturnCode::SUCCESS {
+            return;
+        }
+        self.poll_done();
+    }
+
+    /// Completes an association attempt, configuring the allocated short
+    /// address on success, or restoring the channel and PAN ID that were
+    /// configured before the attempt on failure
+    fn associate_done(&self, result: ReturnCode, short_addr: u16) {
+        self.alarm.disable();
+        self.state.set(State::Idle);
+        if result == ReturnCode::SUCCESS {
+            self.mac.set_address(short_addr);
+        } else {
+            self.coord.set(None);
+            self.mac.set_channel(self.original_channel.get());
+            self.mac.set_pan(self.original_pan.get());
+        }
+        self.mac.config_commit();
+        self.schedule_poll();
+        self.notify_association_clients(|client| client.associate_done(result, short_addr));
+    }
+
+    /// Calls `f` with each association client
+    fn notify_association_clients<F: Fn(&AssociationClient)>(&self, f: F) {
+        for client in self.association_clients.iter() {
+            client.get().map(|client| f(client));
+        }
+    }
+
+    /// Resets the short address and PAN ID once this device has left its PAN
+    fn leave_pan(&self, reason: u8) {
+        self.state.set(State::Idle);
+        self.coord.set(None);
+        self.mac.set_address(BROADCAST_ADDR);
+        self.mac.set_pan(BROADCAST_PAN);
+        self.mac.config_commit();
+        self.notify_association_clients(|client| client.disassociated(reason));
+    }
+
+    /// Whether a frame with the given source was sent by the coordinator
+    /// being associated with. The coordinator sends its association response
+    /// from its long address (IEEE 802.15.4-2015: 7.5.3), so if it is known
+    /// by its short address, any long address in its PAN is accepted.
+    fn is_from_coord(&self, src_pan: Option<PanID>, src_addr: Option<MacAddress>) -> bool {
+        match (self.coord.get(), src_addr) {
+            (Some((_, coord_addr)), Some(src_addr)) if coord_addr == src_addr => true,
+            (Some((coord_pan, MacAddress::Short(_))), Some(MacAddress::Long(_))) => {
+                src_pan == Some(coord_pan)
+            }
+            _ => false,
+        }
+    }
+
+    /// Handles the association response sent by the coordinator, which
+    /// carries the allocated short address and the association status
+    fn receive_association_response(&self, header: &Header, payload: &[u8]) {
+        if payload.len() < 4 || !self.is_own_addr(header.dst_addr)
+            || !self.is_from_coord(header.src_pan, header.src_addr)
+        {
+            return;
+        }
+        let short_addr = (payload[1] as u16) | ((payload[2] as u16) << 8);
+        let result = match payload[3] {
+            association_status::SUCCESS => ReturnCode::SUCCESS,
+            association_status::PAN_AT_CAPACITY => ReturnCode::ENOMEM,
+            _ => ReturnCode::FAIL,
+        };
+        self.associate_done(result, short_addr);
+    }
+
+    /// Handles a disassociation notification sent by the coordinator of the
+    /// PAN this device is associated with
+    fn receive_disassociation(&self, header: &Header, payload: &[u8]) {
+        let coord_pan = match self.coord.get() {
+            Some((coord_pan, _)) => coord_pan,
+            None => return,
+        };
+        if header.dst_pan != Some(coord_pan) || !self.is_own_addr(header.dst_addr) {
+            return;
+        }
+        let reason = payload
+            .get(1)
+            .cloned()
+            .unwrap_or(disassociation_reason::COORDINATOR_WISHES_DEVICE_TO_LEAVE);
+        self.leave_pan(reason);
+    }
+
+    /// Picks the lowest short address that is used neither by this device nor
+    /// by the devices associated with the PAN
+    fn allocate_short_addr(&self, devices: &[Option<DeviceEntry>]) -> u16 {
+        let own_addr = self.mac.get_address();
+        (0..NO_SHORT_ADDR)
+            .find(|&addr| {
+                addr != own_addr && !devices.iter().any(|entry| {
+                    entry.map_or(false, |entry| entry.device.short_addr == addr)
+                })
+            })
+            .unwrap_or(NO_SHORT_ADDR)
+    }
+
+    /// Handles an association request while acting as a coordinator. If the
+    /// device is accepted, it is given an entry in `devices` whose association
+    /// response is held until the device polls for it.
+    fn receive_association_request(&self, header: &Header, payload: &[u8]) {
+        let addr_long = match header.src_addr {
+            Some(MacAddress::Long(addr_long)) => addr_long,
+            _ => return,
+        };
+        let capability = match payload.get(1) {
+            Some(&capability) => capability,
+            None => return,
+        };
+        if !self.association_permit.get() {
+            return;
+        }
+
+        let accepted = self.devices.map_or(false, |devices| {
+            // A device that associates again keeps its entry
+            let index = devices
+                .iter()
+                .position(|entry| entry.map_or(false, |entry| entry.device.addr_long == addr_long))
+                .or_else(|| devices.iter().position(|entry| entry.is_none()));
+            let index = match index {
+                Some(index) => index,
+                None => return false,
+            };
+            let (short_addr, associated) = match devices[index] {
+                _ if capability & capability_info::ALLOCATE_ADDRESS == 0 => {
+                    (NO_SHORT_ADDR, false)
+                }
+                Some(entry) if entry.device.short_addr != NO_SHORT_ADDR => {
+                    (entry.device.short_addr, entry.associated)
+                }
+                _ => (self.allocate_short_addr(devices), false),
+            };
+            devices[index] = Some(DeviceEntry {
+                device: AssociatedDevice {
+                    addr_long: addr_long,
+                    short_addr: short_addr,
+                    capability: capability,
+                },
+                associated: associated,
+                response_pending: true,
+            });
+            true
+        });
+        if !accepted {
+            self.rejected_device.set(Some(addr_long));
+        }
+    }
+
+    /// Handles a data request while acting as a coordinator by sending the
+    /// association response held for the polling device, if any, or else a
+    /// frame held for indirect transmission
+    fn receive_data_request(&self, header: &Header) {
+        let src_addr = match header.src_addr {
+            Some(src_addr) => src_addr,
+            None => return,
+        };
+        if let MacAddress::Long(addr_long) = src_addr {
+            if self.send_association_response(addr_long) {
+                return;
+            }
+        }
+        self.send_held_frame(src_addr);
+    }
+
+    /// Sends the association response held for the device `addr_long`.
+    /// Returns whether a response was held for the device.
+    fn send_association_response(&self, addr_long: [u8; 8]) -> bool {
+        let pending = self.devices.and_then(|devices| {
+            devices
+                .iter()
+                .filter_map(|entry| *entry)
+                .find(|entry| entry.response_pending && entry.device.addr_long == addr_long)
+        });
+        let (short_addr, status) = match pending {
+            Some(entry) => (entry.device.short_addr, association_status::SUCCESS),
+            None if self.rejected_device.get() == Some(addr_long) => {
+                self.rejected_device.set(None);
+                (BROADCAST_ADDR, association_status::PAN_AT_CAPACITY)
+            }
+            None => return false,
+        };
+
+        let pan = self.mac.get_pan();
+        self.response_dst.set(Some(addr_long));
+        let rval = self.send_command(
+            pan,
+            MacAddress::Long(addr_long),
+            pan,
+            Some(MacAddress::Long(self.mac.get_address_long())),
+            mac_command::ASSOCIATION_RESPONSE,
+            &[short_addr as u8, (short_addr >> 8) as u8, status],
+        );
+        if rval != ReturnCode::SUCCESS {
+            // The device will time out and can try associating again
+            self.response_dst.set(None);
+        }
+        true
+    }
+
+    /// Whether two addresses designate the same device, either because they
+    /// are equal, or because they are the long and short addresses of a
+    /// device associated with the PAN
+    fn same_device(&self, a: MacAddress, b: MacAddress) -> bool {
+        a == b || self.devices.map_or(false, |devices| {
+            devices
+                .iter()
+                .filter_map(|entry| *entry)
+                .filter(|entry| entry.associated && entry.device.short_addr != NO_SHORT_ADDR)
+                .any(|entry| {
+                    let addr_long = MacAddress::Long(entry.device.addr_long);
+                    let short_addr = MacAddress::Short(entry.device.short_addr);
+                    (a == addr_long && b == short_addr) || (a == short_addr && b == addr_long)
+                })
+        })
+    }
+
+    /// Sets the Frame Pending field of acknowledgements to data requests if,
+    /// and only if, frames are held for indirect transmission. The radio
+    /// cannot tell which device sent a data request in time to acknowledge
+    /// it, so the field is set for all devices.
+    fn update_ack_frame_pending(&self) {
+        let pending = self.transactions.map_or(false, |transactions| {
+            transactions.iter().any(|transaction| transaction.is_some())
+        });
+        if pending != self.ack_frame_pending.get() {
+            self.ack_frame_pending.set(pending);
+            self.mac.set_ack_frame_pending(pending);
+            self.mac.config_commit();
+        }
+    }
+
+    /// Returns a buffer used to hold a frame to the pool
+    fn release_indirect_buf(&self, buf: &'static mut [u8]) {
+        self.indirect_bufs.map(move |indirect_bufs| {
+            indirect_bufs
+                .iter_mut()
+                .find(|slot| slot.is_none())
+                .map(move |slot| *slot = Some(buf));
+        });
+    }
+
+    /// Returns the buffer of a held frame to the pool and notifies the
+    /// indirect client that the frame is no longer held
+    fn indirect_done(
+        &self,
+        dst_addr: MacAddress,
+        buf: &'static mut [u8],
+        acked: bool,
+        result: ReturnCode,
+    ) {
+        self.release_indirect_buf(buf);
+        self.indirect_client
+            .get()
+            .map(|client| client.indirect_done(dst_addr, acked, result));
+    }
+
+    /// Discards the held frames selected by `discard`, reporting `result` to
+    /// the indirect client
+    fn discard_transactions<F>(&self, discard: F, result: ReturnCode)
+    where
+        F: Fn(&Transaction) -> bool,
+    {
+        for index in 0..MAX_PENDING_TRANSACTIONS {
+            let discarded = self.transactions.and_then(|transactions| {
+                if transactions[index].as_ref().map_or(false, |t| discard(t)) {
+                    transactions[index].take()
+                } else {
+                    None
+                }
+            });
+            discarded.map(|transaction| {
+                let buf = transaction.frame.into_buf();
+                self.indirect_done(transaction.dst_addr, buf, false, result);
+            });
+        }
+        self.update_ack_frame_pending();
+    }
+
+    /// Sends the oldest frame held for the device that polled with
+    /// `src_addr`. If no frame is held for it but the acknowledgement of its
+    /// data request indicated pending frames, an empty data frame is sent
+    /// instead so that the device does not wait in vain.
+    fn send_held_frame(&self, src_addr: MacAddress) {
+        // Only one frame can be transmitted at a time, so the device will
+        // have to poll again
+        if self.tx_buf.is_none() || self.indirect_dst.get().is_some() {
+            return;
+        }
+
+        let held_for_src = |transaction: &Option<Transaction>| {
+            transaction
+                .as_ref()
+                .map_or(false, |t| self.same_device(t.dst_addr, src_addr))
+        };
+        let now = self.alarm.now();
+        let transaction = self.transactions.and_then(|transactions| {
+            // All frames are held for the same time, so the oldest one
+            // expires first
+            let index = (0..MAX_PENDING_TRANSACTIONS)
+                .filter(|&index| held_for_src(&transactions[index]))
+                .min_by_key(|&index| {
+                    transactions[index]
+                        .as_ref()
+                        .map_or(0, |t| t.expiry.wrapping_sub(now))
+                })?;
+            let mut transaction = transactions[index].take()?;
+            let more = transactions.iter().any(held_for_src);
+            transaction.frame.set_frame_pending(more);
+            Some(transaction)
+        });
+
+        match transaction {
+            Some(Transaction {
+                dst_addr, frame, ..
+            }) => {
+                self.update_ack_frame_pending();
+                self.set_coordinator_alarm();
+                self.indirect_dst.set(Some(dst_addr));
+                let (rval, buf) = self.mac.transmit(frame);
+                if let Some(buf) = buf {
+                    self.indirect_dst.set(None);
+                    self.indirect_done(dst_addr, buf, false, rval);
+                }
+            }
+            None if self.ack_frame_pending.get() => {
+                let buf = match self.tx_buf.take() {
+                    Some(buf) => buf,
+                    None => return,
+                };
+                let pan = self.mac.get_pan();
+                match self.mac
+                    .prepare_data_frame(buf, pan, src_addr, pan, self.src_addr(), None)
+                {
+                    Ok(frame) => {
+                        self.transmit(frame);
+                    }
+                    Err(buf) => {
+                        self.tx_buf.replace(buf);
+                    }
+                }
+            }
+            None => {}
+        }
+    }
+
+    /// Completes the association of a device once it has acknowledged its
+    /// association response
+    fn confirm_association(&self, addr_long: [u8; 8]) {
+        let newly_associated = self.devices.and_then(|devices| {
+            let entry = devices
+                .iter_mut()
+                .filter_map(|entry| entry.as_mut())
+                .find(|entry| entry.response_pending && entry.device.addr_long == addr_long);
+            entry.and_then(|entry| {
+                let was_associated = entry.associated;
+                entry.response_pending = false;
+                entry.associated = true;
+                if was_associated {
+                    None
+                } else {
+                    Some(entry.device.short_addr)
+                }
+            })
+        });
+        newly_associated.map(|short_addr| {
+            self.notify_association_clients(|client| {
+                client.device_associated(addr_long, short_addr)
+            });
+        });
+    }
+
+    /// Removes a device from `devices`, returning its short address if it was
+    /// associated
+    fn remove_device(&self, addr_long: [u8; 8]) -> Option<u16> {
+        self.devices.and_then(|devices| {
+            let index = devices
+                .iter()
+                .position(|entry| entry.map_or(false, |entry| entry.device.addr_long == addr_long));
+            index
+                .and_then(|index| devices[index].take())
+                .and_then(|entry| {
+                    if entry.associated {
+                        Some(entry.device.short_addr)
+                    } else {
+                        None
+                    }
+                })
+        })
+    }
+
+    /// Handles a disassociation notification sent by a device associated with
+    /// the PAN coordinated by this device
+    fn receive_device_disassociation(&self, header: &Header) {
+        let addr_long = match header.src_addr {
+            Some(MacAddress::Long(addr_long)) => addr_long,
+            _ => return,
+        };
+        self.remove_device(addr_long).map(|short_addr| {
+            self.notify_association_clients(|client| {
+                client.device_disassociated(addr_long, short_addr)
+            });
+        });
+    }
+}
+
+impl<'a, A: Alarm + 'a> Mlme<'a> for MacManager<'a, A> {
+    fn set_scan_client(&self, client: &'a ScanClient) {
+        self.scan_client.set(Some(client));
+    }
+
+    fn scan(&self, scan_type: ScanType, channels: u32, duration: u8) -> ReturnCode {
+        if self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        let channels = channels & CHANNELS_2450MHZ;
+        if channels == 0 || duration > MAX_SCAN_DURATION {
+            return ReturnCode::EINVAL;
+        }
+
+        self.state.set(State::Scanning);
+        self.scan_type.set(scan_type);
+        self.scan_channels.set(channels);
+        self.scan_duration.set(duration);
+        self.original_channel.set(self.mac.get_channel());
+        self.num_pan_descriptors.set(0);
+        self.pan_descriptors_overflow.set(false);
+        self.scan_security_failures.set(0);
+        self.scan_next_channel();
+        ReturnCode::SUCCESS
+    }
+
+    fn start_coordinator(&self, beacon_order: u8) -> ReturnCode {
+        match self.state.get() {
+            State::Idle | State::Coordinator => {}
+            _ => return ReturnCode::EBUSY,
+        }
+        if beacon_order > BEACON_ORDER_NONBEACON {
+            return ReturnCode::EINVAL;
+        }
+
+        self.state.set(State::Coordinator);
+        self.beacon_order.set(beacon_order);
+        if beacon_order < BEACON_ORDER_NONBEACON {
+            self.send_beacon();
+            let interval = self.symbols_to_tics(BASE_SUPERFRAME_DURATION << beacon_order);
+            self.next_beacon
+                .set(self.alarm.now().wrapping_add(interval));
+        }
+        self.set_coordinator_alarm();
+        ReturnCode::SUCCESS
+    }
+
+    fn stop_coordinator(&self) -> ReturnCode {
+        if self.state.get() != State::Coordinator {
+            return ReturnCode::EALREADY;
+        }
+        self.alarm.disable();
+        self.state.set(State::Idle);
+        self.beacon_order.set(BEACON_ORDER_NONBEACON);
+        self.discard_transactions(|_| true, ReturnCode::ECANCEL);
+        ReturnCode::SUCCESS
+    }
+
+    fn is_coordinator(&self) -> bool {
+        self.state.get() == State::Coordinator
+    }
+
+    fn add_association_client(&self, client: &'a AssociationClient) -> ReturnCode {
+        match self.association_clients
+            .iter()
+            .find(|slot| slot.get().is_none())
+        {
+            Some(slot) => {
+                slot.set(Some(client));
+                ReturnCode::SUCCESS
+            }
+            None => ReturnCode::ENOMEM,
+        }
+    }
+
+    fn associate(
+        &self,
+        channel: u8,
+        coord_pan: PanID,
+        coord_addr: MacAddress,
+        capability: u8,
+    ) -> ReturnCode {
+        if self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        if self.coord.get().is_some() {
+            return ReturnCode::EALREADY;
+        }
+
+        // The PAN ID is set before sending the request so that the response
+        // is accepted
+        let original_channel = self.mac.get_channel();
+        let original_pan = self.mac.get_pan();
+        let rval = self.mac.set_channel(channel);
+        if rval != ReturnCode::SUCCESS {
+            return rval;
+        }
+        self.original_channel.set(original_channel);
+        self.original_pan.set(original_pan);
+        self.mac.set_pan(coord_pan);
+        self.mac.config_commit();
+
+        let rval = self.send_command(
+            coord_pan,
+            coord_addr,
+            BROADCAST_PAN,
+            Some(MacAddress::Long(self.mac.get_address_long())),
+            mac_command::ASSOCIATION_REQUEST,
+            &[capability],
+        );
+        if rval == ReturnCode::SUCCESS {
+            self.coord.set(Some((coord_pan, coord_addr)));
+            self.state
+                .set(State::Associating(AssociationStep::SendingRequest));
+        } else {
+            self.mac.set_channel(original_channel);
+            self.mac.set_pan(original_pan);
+            self.mac.config_commit();
+        }
+        rval
+    }
+
+    fn disassociate(&self) -> ReturnCode {
+        if self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        let (coord_pan, coord_addr) = match self.coord.get() {
+            Some(coord) => coord,
+            None => return ReturnCode::EALREADY,
+        };
+        let rval = self.send_command(
+            coord_pan,
+            coord_addr,
+            coord_pan,
+            Some(MacAddress::Long(self.mac.get_address_long())),
+            mac_command::DISASSOCIATION_NOTIFICATION,
+            &[disassociation_reason::DEVICE_WISHES_TO_LEAVE],
+        );
+        if rval == ReturnCode::SUCCESS {
+            self.state.set(State::Disassociating);
+        }
+        rval
+    }
+
+    fn get_coordinator(&self) -> Option<(PanID, MacAddress)> {
+        match self.state.get() {
+            State::Associating(_) => None,
+            _ => self.coord.get(),
+        }
+    }
+
+    fn set_association_permit(&self, permit: bool) {
+        self.association_permit.set(permit);
+    }
+
+    fn disassociate_device(&self, addr_long: [u8; 8]) -> ReturnCode {
+        let short_addr = match self.remove_device(addr_long) {
+            Some(short_addr) => short_addr,
+            None => return ReturnCode::EINVAL,
+        };
+
+        // The device is removed from the PAN even if it cannot be notified
+        let pan = self.mac.get_pan();
+        self.send_command(
+            pan,
+            MacAddress::Long(addr_long),
+            pan,
+            Some(MacAddress::Long(self.mac.get_address_long())),
+            mac_command::DISASSOCIATION_NOTIFICATION,
+            &[disassociation_reason::COORDINATOR_WISHES_DEVICE_TO_LEAVE],
+        );
+        self.notify_association_clients(|client| {
+            client.device_disassociated(addr_long, short_addr)
+        });
+        ReturnCode::SUCCESS
+    }
+
+    fn get_associated_device(&self, index: usize) -> Option<AssociatedDevice> {
+        self.devices.and_then(|devices| {
+            devices
+                .iter()
+                .filter_map(|entry| *entry)
+                .filter(|entry| entry.associated)
+                .map(|entry| entry.device)
+                .nth(index)
+        })
+    }
+
+    fn set_indirect_client(&self, client: &'a IndirectClient) {
+        self.indirect_client.set(Some(client));
+    }
+
+    fn transmit_indirect(
+        &self,
+        dst_addr: MacAddress,
+        payload: &[u8],
+        security_needed: Option<(SecurityLevel, KeyId)>,
+    ) -> ReturnCode {
+        if self.state.get() != State::Coordinator {
+            return ReturnCode::EINVAL;
+        }
+        let buf = self.indirect_bufs.and_then(|indirect_bufs| {
+            indirect_bufs
+                .iter_mut()
+                .find(|slot| slot.is_some())
+                .and_then(|slot| slot.take())
+        });
+        let buf = match buf {
+            Some(buf) => buf,
+            None => return ReturnCode::ENOMEM,
+        };
+
+        let pan = self.mac.get_pan();
+        let mut frame = match self.mac.prepare_data_frame(
+            buf,
+            pan,
+            dst_addr,
+            pan,
+            self.src_addr(),
+            security_needed,
+        ) {
+            Ok(frame) => frame,
+            Err(buf) => {
+                self.release_indirect_buf(buf);
+                return ReturnCode::FAIL;
+            }
+        };
+        if frame.append_payload(payload) != ReturnCode::SUCCESS {
+            self.release_indirect_buf(frame.into_buf());
+            return ReturnCode::ESIZE;
+        }
+
+        // The persistence time is counted in beacon intervals, or in base
+        // superframe durations if the PAN has no beacons
+        let beacon_order = self.beacon_order.get();
+        let unit_period = if beacon_order < BEACON_ORDER_NONBEACON {
+            BASE_SUPERFRAME_DURATION << beacon_order
+        } else {
+            BASE_SUPERFRAME_DURATION
+        };
+        let persistence = self.symbols_to_tics(TRANSACTION_PERSISTENCE_TIME * unit_period);
+        let transaction = Transaction {
+            dst_addr: dst_addr,
+            frame: frame,
+            expiry: self.alarm.now().wrapping_add(persistence),
+        };
+        self.transactions.map(|transactions| {
+            transactions
+                .iter_mut()
+                .find(|slot| slot.is_none())
+                .map(|slot| *slot = Some(transaction));
+        });
+        self.update_ack_frame_pending();
+        self.set_coordinator_alarm();
+        ReturnCode::SUCCESS
+    }
+
+    fn set_poll_interval(&self, interval_ms: u32) {
+        self.poll_interval_ms.set(interval_ms);
+        self.schedule_poll();
+    }
+
+    fn get_poll_interval(&self) -> u32 {
+        self.poll_interval_ms.get()
+    }
+
+    fn poll(&self) -> ReturnCode {
+        if self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        if self.coord.get().is_none() {
+            return ReturnCode::EINVAL;
+        }
+        self.start_poll()
+    }
+}
+
+impl<'a, A: Alarm + 'a> time::Client for MacManager<'a, A> {
+    fn fired(&self) {
+        match self.state.get() {
+            State::Scanning => self.scan_next_channel(),
+            State::Coordinator => {
+                let beacon_order = self.beacon_order.get();
+                if beacon_order < BEACON_ORDER_NONBEACON && self.is_due(self.next_beacon.get()) {
+                    // If the previous frame is still being transmitted, this
+                    // beacon is skipped
+                    self.send_beacon();
+                    let interval = self.symbols_to_tics(BASE_SUPERFRAME_DURATION << beacon_order);
+                    self.next_beacon
+                        .set(self.next_beacon.get().wrapping_add(interval));
+                }
+                self.discard_transactions(|t| self.is_due(t.expiry), ReturnCode::ENOACK);
+                self.set_coordinator_alarm();
+            }
+            State::Associating(AssociationStep::WaitingForResponse) => {
+                // No short address has been allocated yet
+                let rval = self.send_data_request(MacAddress::Long(self.mac.get_address_long()));
+                if rval == ReturnCode::SUCCESS {
+                    self.state
+                        .set(State::Associating(AssociationStep::SendingDataRequest));
+                } else {
+                    self.associate_done(rval, BROADCAST_ADDR);
+                }
+            }
+            State::Associating(AssociationStep::ReceivingResponse) => {
+                self.associate_done(ReturnCode::ENOACK, BROADCAST_ADDR);
+            }
+            State::Idle => {
+                if self.coord.get().is_some()
+                    && self.poll_interval_ms.get() > 0
+                    && self.start_poll() != ReturnCode::SUCCESS
+                {
+                    // Another frame is being transmitted
+                    self.schedule_poll();
+                }
+            }
+            State::Polling(PollStep::ReceivingData) => self.poll_done(),
+            State::Associating(_) | State::Disassociating | State::Polling(_) => {}
+        }
+    }
+}
+
+impl<'a, A: Alarm + 'a> TxClient for MacManager<'a, A> {
+    fn send_done(&self, spi_buf: &'static mut [u8], acked: bool, result: ReturnCode) {
+        if let Some(dst_addr) = self.indirect_dst.take() {
+            self.indirect_done(dst_addr, spi_buf, acked, result);
+            return;
+        }
+
+        self.tx_buf.replace(spi_buf);
+        let acked = acked && result == ReturnCode::SUCCESS;
+
+        if let Some(addr_long) = self.response_dst.take() {
+            if acked {
+                self.confirm_association(addr_long);
+            }
+            return;
+        }
+
+        match self.state.get() {
+            State::Associating(AssociationStep::SendingRequest) => {
+                if acked {
+                    self.state
+                        .set(State::Associating(AssociationStep::WaitingForResponse));
+                    self.set_alarm_symbols(RESPONSE_WAIT_TIME);
+                } else {
+                    self.associate_done(ReturnCode::ENOACK, BROADCAST_ADDR);
+                }
+            }
+            State::Associating(AssociationStep::SendingDataRequest) => {
+                if acked {
+                    self.state
+                        .set(State::Associating(AssociationStep::ReceivingResponse));
+                    self.set_alarm_symbols(MAX_FRAME_TOTAL_WAIT_TIME);
+                } else {
+                    self.associate_done(ReturnCode::ENOACK, BROADCAST_ADDR);
+                }
+            }
+            // The device leaves the PAN even if the coordinator did not
+            // acknowledge the notification
+            State::Disassociating => {
+                self.leave_pan(disassociation_reason::DEVICE_WISHES_TO_LEAVE)
+            }
+            State::Polling(PollStep::SendingRequest) => {
+                if acked {
+                    self.state.set(State::Polling(PollStep::ReceivingData));
+                    self.set_alarm_symbols(MAX_FRAME_TOTAL_WAIT_TIME);
+                } else {
+                    self.poll_done();
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<'a, A: Alarm + 'a> RxClient for MacManager<'a, A> {
+    fn receive<'b>(
+        &self,
+        buf: &'b [u8],
+        header: Header<'b>,
+        info: radio::RxInfo,
+        data_offset: usize,
+        data_len: usize,
+    ) {
+        let payload = &buf[data_offset..data_offset + data_len];
+        match header.frame_type {
+            FrameType::Beacon => {
+                if self.state.get() == State::Scanning {
+                    self.record_beacon(&header, info, payload);
+                }
+            }
+            // The frame may be received before the data request polling for
+            // it is reported as sent
+            FrameType::Data => {
+                if let State::Polling(_) = self.state.get() {
+                    self.receive_polled_data(&header);
+                }
+            }
+            FrameType::MACCommand => match (self.state.get(), payload.first().cloned()) {
+                (State::Coordinator, Some(mac_command::BEACON_REQUEST)) => {
+                    self.send_beacon();
+                }
+                (State::Coordinator, Some(mac_command::ASSOCIATION_REQUEST)) => {
+                    self.receive_association_request(&header, payload)
+                }
+                (State::Coordinator, Some(mac_command::DATA_REQUEST)) => {
+                    self.receive_data_request(&header)
+                }
+                (State::Coordinator, Some(mac_command::DISASSOCIATION_NOTIFICATION)) => {
+                    self.receive_device_disassociation(&header)
+                }
+                // The response may be received before the data request
+                // polling for it is reported as sent
+                (State::Associating(step), Some(mac_command::ASSOCIATION_RESPONSE))
+                    if step != AssociationStep::SendingRequest =>
+                {
+                    self.receive_association_response(&header, payload)
+                }
+                (State::Idle, Some(mac_command::DISASSOCIATION_NOTIFICATION)) => {
+                    self.receive_disassociation(&header, payload)
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    fn receive_security_failure<'b>(&self, header: Header<'b>, _: SecurityError) {
+        // The other management frames handled here are never secured, so
+        // only secured beacons can fail
+        if self.state.get() == State::Scanning && header.frame_type == FrameType::Beacon {
+            self.scan_security_failures
+                .set(self.scan_security_failures.get() + 1);
+        }
+    }
+}
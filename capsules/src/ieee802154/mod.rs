@@ -1,6 +1,11 @@
+pub mod capture;
 pub mod device;
 pub mod framer;
 pub mod mac;
+pub mod mlme;
+pub mod neighbors;
+pub mod pcapng;
+pub mod tsch;
 pub mod virtual_mac;
 pub mod xmac;
 
@@ -0,0 +1,204 @@
+//! Link quality estimates for IEEE 802.15.4 neighbors.
+//!
+//! A `NeighborTable` keeps, for each neighbor, exponentially weighted moving
+//! averages of the RSSI and LQI of the frames received from it, and of the
+//! expected transmission count (ETX) of the frames sent to it, which routing
+//! protocols can use to compare links. The `Framer` reports every frame that
+//! it delivers and every unicast frame that it transmits to the table, and
+//! the userspace driver exposes the table for diagnostics.
+//!
+//! Neighbors are identified by the address used in the frames exchanged with
+//! them, so a neighbor that uses both its short and its long address has two
+//! entries. A neighbor is added when a frame is first received from it;
+//! transmissions to unknown neighbors are not recorded. When the table is
+//! full, the neighbor that was heard from least recently is replaced.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let neighbor_table = static_init!(
+//!     capsules::ieee802154::neighbors::NeighborTable,
+//!     capsules::ieee802154::neighbors::NeighborTable::new()
+//! );
+//! mac_device.set_neighbor_table(neighbor_table);
+//! radio_driver.set_neighbor_table(neighbor_table);
+//! ```
+
+use core::cell::Cell;
+use core::cmp::max;
+use kernel::common::take_cell::MapCell;
+use kernel::hil::radio;
+use net::ieee802154::MacAddress;
+
+/// The maximum number of neighbors in the table
+pub const MAX_NEIGHBORS: usize = 8;
+
+/// ETX values are fixed-point numbers with this divisor, so that an ETX of
+/// `ETX_DIVISOR` means that every frame is acknowledged on its first
+/// transmission
+pub const ETX_DIVISOR: u16 = 128;
+
+/// The number of transmissions that a frame that was never acknowledged is
+/// counted as
+const ETX_NOACK_PENALTY: u16 = 12;
+
+/// Each new sample is weighted by 1 / 2^EWMA_SHIFT in the averages
+const EWMA_SHIFT: u32 = 3;
+
+/// The RSSI and LQI averages are kept with this many fractional bits, so that
+/// they can move by less than one unit per sample
+const AVG_FRAC_BITS: u32 = 4;
+
+/// The link quality estimates of a neighbor
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct LinkStats {
+    /// The address of the neighbor
+    pub addr: MacAddress,
+    /// The average RSSI of the frames received from the neighbor, in dBm
+    pub rssi: i8,
+    /// The average LQI of the frames received from the neighbor
+    pub lqi: u8,
+    /// The average number of transmissions needed for a frame sent to the
+    /// neighbor to be acknowledged, in units of `1 / ETX_DIVISOR`, or `None`
+    /// if no frame has been sent to the neighbor yet
+    pub etx: Option<u16>,
+    /// The time at which the last frame from the neighbor was received, if
+    /// the radio timestamps frames
+    pub last_rx_time: Option<u32>,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+struct Neighbor {
+    addr: MacAddress,
+    // Scaled by 2^AVG_FRAC_BITS
+    rssi: i32,
+    lqi: i32,
+    // Scaled by ETX_DIVISOR
+    etx: Option<i32>,
+    last_rx_time: Option<u32>,
+    // The value of the table's `heard` counter when the neighbor was last
+    // heard from, to find the least recently heard neighbor
+    heard: u32,
+}
+
+impl Neighbor {
+    fn stats(&self) -> LinkStats {
+        let round = |avg: i32| (avg + (1 << (AVG_FRAC_BITS - 1))) >> AVG_FRAC_BITS;
+        LinkStats {
+            addr: self.addr,
+            rssi: round(self.rssi) as i8,
+            lqi: round(self.lqi) as u8,
+            etx: self.etx.map(|etx| etx as u16),
+            last_rx_time: self.last_rx_time,
+        }
+    }
+}
+
+/// Moves the average `avg` towards `sample`
+fn ewma(avg: i32, sample: i32) -> i32 {
+    avg + ((sample - avg) >> EWMA_SHIFT)
+}
+
+pub struct NeighborTable {
+    neighbors: MapCell<[Option<Neighbor>; MAX_NEIGHBORS]>,
+    heard: Cell<u32>,
+}
+
+impl NeighborTable {
+    pub fn new() -> NeighborTable {
+        NeighborTable {
+            neighbors: MapCell::new([None; MAX_NEIGHBORS]),
+            heard: Cell::new(0),
+        }
+    }
+
+    /// Records the link quality of a frame received from `addr`
+    pub fn frame_received(&self, addr: MacAddress, info: radio::RxInfo) {
+        let heard = self.heard.get().wrapping_add(1);
+        self.heard.set(heard);
+        let rssi = (info.rssi as i32) << AVG_FRAC_BITS;
+        let lqi = (info.lqi as i32) << AVG_FRAC_BITS;
+        self.neighbors.map(|neighbors| {
+            if let Some(neighbor) = neighbors
+                .iter_mut()
+                .filter_map(|slot| slot.as_mut())
+                .find(|neighbor| neighbor.addr == addr)
+            {
+                neighbor.rssi = ewma(neighbor.rssi, rssi);
+                neighbor.lqi = ewma(neighbor.lqi, lqi);
+                neighbor.last_rx_time = info.timestamp;
+                neighbor.heard = heard;
+                return;
+            }
+
+            // Use a free slot, or else replace the least recently heard
+            // neighbor
+            let index = neighbors
+                .iter()
+                .position(|slot| slot.is_none())
+                .or_else(|| {
+                    (0..MAX_NEIGHBORS).max_by_key(|&index| {
+                        neighbors[index]
+                            .as_ref()
+                            .map_or(0, |neighbor| heard.wrapping_sub(neighbor.heard))
+                    })
+                });
+            index.map(|index| {
+                neighbors[index] = Some(Neighbor {
+                    addr: addr,
+                    rssi: rssi,
+                    lqi: lqi,
+                    etx: None,
+                    last_rx_time: info.timestamp,
+                    heard: heard,
+                });
+            });
+        });
+    }
+
+    /// Records the outcome of the transmission of a unicast frame to `addr`,
+    /// which was retransmitted `retries` times
+    pub fn frame_sent(&self, addr: MacAddress, acked: bool, retries: u8) {
+        let transmissions = retries as u16 + 1;
+        let transmissions = if acked {
+            transmissions
+        } else {
+            max(transmissions, ETX_NOACK_PENALTY)
+        };
+        let etx = (transmissions * ETX_DIVISOR) as i32;
+        self.neighbors.map(|neighbors| {
+            neighbors
+                .iter_mut()
+                .filter_map(|slot| slot.as_mut())
+                .find(|neighbor| neighbor.addr == addr)
+                .map(|neighbor| {
+                    neighbor.etx = Some(neighbor.etx.map_or(etx, |avg| ewma(avg, etx)));
+                });
+        });
+    }
+
+    /// Returns the link quality estimates of the neighbor `addr`, if it is in
+    /// the table
+    pub fn get(&self, addr: MacAddress) -> Option<LinkStats> {
+        self.neighbors.and_then(|neighbors| {
+            neighbors
+                .iter()
+                .filter_map(|slot| slot.as_ref())
+                .find(|neighbor| neighbor.addr == addr)
+                .map(|neighbor| neighbor.stats())
+        })
+    }
+
+    /// Returns the link quality estimates of the neighbor at `index` in the
+    /// table. Neighbors are never removed, so the table can be iterated over
+    /// until `None` is returned.
+    pub fn get_index(&self, index: usize) -> Option<LinkStats> {
+        self.neighbors.and_then(|neighbors| {
+            neighbors
+                .get(index)
+                .and_then(|slot| slot.as_ref())
+                .map(|neighbor| neighbor.stats())
+        })
+    }
+}
@@ -0,0 +1,142 @@
+//! The pcap-ng blocks written by `capture`, which describe the capture before
+//! any frame is captured.
+//!
+//! This module only depends on the language itself, so that the
+//! `tools/ieee802154-capture` host tool includes it too, and writes the same
+//! headers when it joins a capture in progress. It must stay free of `use`
+//! declarations and of any other dependency for that reason.
+
+/// pcap-ng block types
+pub const SECTION_HEADER_BLOCK: u32 = 0x0A0D0D0A;
+pub const INTERFACE_DESCRIPTION_BLOCK: u32 = 0x00000001;
+pub const ENHANCED_PACKET_BLOCK: u32 = 0x00000006;
+
+pub const BYTE_ORDER_MAGIC: u32 = 0x1A2B3C4D;
+
+/// IEEE 802.15.4 frames preceded by a TAP header of TLVs carrying metadata
+pub const LINKTYPE_IEEE802_15_4_TAP: u16 = 283;
+
+/// pcap-ng option codes
+pub const OPT_ENDOFOPT: u16 = 0;
+pub const IF_TSRESOL: u16 = 9;
+pub const EPB_FLAGS: u16 = 2;
+pub const EPB_DROPCOUNT: u16 = 4;
+
+/// Timestamps are in microseconds
+pub const TSRESOL_US: u8 = 6;
+
+/// The size of the Section Header Block and Interface Description Block
+/// written by `write_headers`
+pub const HEADERS_SIZE: usize = 28 + 32;
+
+/// Writes little-endian values into a buffer
+pub struct BlockWriter<'b> {
+    pub buf: &'b mut [u8],
+    pub offset: usize,
+}
+
+impl<'b> BlockWriter<'b> {
+    pub fn new(buf: &'b mut [u8]) -> BlockWriter<'b> {
+        BlockWriter {
+            buf: buf,
+            offset: 0,
+        }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.buf[self.offset] = value;
+        self.offset += 1;
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.u8(value as u8);
+        self.u8((value >> 8) as u8);
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.u16(value as u16);
+        self.u16((value >> 16) as u16);
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.u32(value as u32);
+        self.u32((value >> 32) as u32);
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.buf[self.offset..self.offset + bytes.len()].copy_from_slice(bytes);
+        self.offset += bytes.len();
+    }
+
+    /// Pads with zeroes to a multiple of 4 bytes, as pcap-ng and the TAP
+    /// header require for every field of variable length
+    pub fn pad(&mut self) {
+        while self.offset % 4 != 0 {
+            self.u8(0);
+        }
+    }
+
+    /// Writes a pcap-ng option, or a TAP TLV, which share the same layout
+    pub fn option(&mut self, code: u16, value: &[u8]) {
+        self.u16(code);
+        self.u16(value.len() as u16);
+        self.bytes(value);
+        self.pad();
+    }
+
+    /// Completes a block started at offset 0 by writing its total length
+    /// before and after its body
+    pub fn finish_block(&mut self) -> usize {
+        let len = self.offset + 4;
+        self.u32(len as u32);
+        self.buf[4..8].copy_from_slice(&le_u32(len as u32));
+        len
+    }
+}
+
+/// Writes the Section Header Block and the Interface Description Block of a
+/// capture into `buf`, which must hold at least `HEADERS_SIZE` bytes, and
+/// returns their size
+pub fn write_headers(buf: &mut [u8]) -> usize {
+    let shb_len = {
+        let mut writer = BlockWriter::new(buf);
+        writer.u32(SECTION_HEADER_BLOCK);
+        writer.u32(0);
+        writer.u32(BYTE_ORDER_MAGIC);
+        writer.u16(1);
+        writer.u16(0);
+        // The length of the section is not known
+        writer.u64(0xffff_ffff_ffff_ffff);
+        writer.finish_block()
+    };
+    let idb_len = {
+        let mut writer = BlockWriter::new(&mut buf[shb_len..]);
+        writer.u32(INTERFACE_DESCRIPTION_BLOCK);
+        writer.u32(0);
+        writer.u16(LINKTYPE_IEEE802_15_4_TAP);
+        writer.u16(0);
+        // No snapshot length limit
+        writer.u32(0);
+        writer.option(IF_TSRESOL, &[TSRESOL_US]);
+        writer.option(OPT_ENDOFOPT, &[]);
+        writer.finish_block()
+    };
+    shb_len + idb_len
+}
+
+pub fn le_u32(value: u32) -> [u8; 4] {
+    [
+        value as u8,
+        (value >> 8) as u8,
+        (value >> 16) as u8,
+        (value >> 24) as u8,
+    ]
+}
+
+pub fn le_u64(value: u64) -> [u8; 8] {
+    let low = le_u32(value as u32);
+    let high = le_u32((value >> 32) as u32);
+    [
+        low[0], low[1], low[2], low[3], high[0], high[1], high[2], high[3],
+    ]
+}
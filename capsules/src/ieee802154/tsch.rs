@@ -0,0 +1,1535 @@
+//! TSCH (Time-Slotted Channel Hopping) MAC layer for deterministic, low power
+//! 802.15.4 networks (IEEE 802.15.4-2015: 6.2.6).
+//!
+//! Time is divided into 10 ms timeslots, numbered by an absolute slot number
+//! (ASN) that is shared by all nodes of the network. Timeslots are grouped into
+//! slotframes that repeat over time, and the schedule of a node is a set of
+//! links, each of which allows transmitting and/or receiving in one timeslot of
+//! a slotframe. Every link has a channel offset, and the channel it uses
+//! changes every time its slotframe repeats:
+//!
+//!     channel = hopping_sequence[(asn + channel_offset) % hopping_sequence.len()]
+//!
+//! which spreads the traffic over all channels and makes the network resilient
+//! to narrow-band interference. The radio is turned off during timeslots that
+//! have no active link.
+//!
+//! Nodes join a network by listening for Enhanced Beacons on a fixed channel.
+//! Enhanced Beacons advertise the ASN, the timeslot template, the hopping
+//! sequence and the schedule of the network, and their sender becomes the time
+//! source neighbor of the joining node. Nodes then remain synchronized with
+//! their time source: frames received from the time source are used to measure
+//! the drift between the two clocks, and the Enhanced Acknowledgements sent by
+//! the time source carry the time correction measured on its side. When no
+//! frames are exchanged with the time source for a while, a keep-alive frame is
+//! sent to it, and the node leaves the network and attempts to join it again
+//! when it has been out of sync for too long. All synchronized nodes send
+//! Enhanced Beacons periodically in shared links.
+//!
+//! Additional notes:
+//!
+//!   * Only the default timeslot template and hopping sequence (both of ID 0)
+//!     are supported, so they are only advertised by their ID.
+//!   * The radio must not acknowledge frames itself, since this layer sends
+//!     Enhanced Acknowledgements carrying time corrections. Acknowledgements
+//!     reported by the radio are nevertheless accepted.
+//!   * Arrival times are read from the alarm when the radio reports a frame,
+//!     so the accuracy of the synchronization depends on the latency of the
+//!     radio driver, which must be the same on all nodes.
+//!   * Enhanced Beacons and keep-alives are sent from the long address of the
+//!     node, and only frames that use the same address as the Enhanced Beacon
+//!     of the time source are used for synchronization.
+//!   * Frames that cannot be sent after `MAX_FRAME_RETRIES` retransmissions
+//!     fail with ReturnCode::ENOACK, and frames cannot be sent at all while the
+//!     node is not synchronized.
+//!
+//! The default schedule is the minimal 6TiSCH configuration (RFC 8180): a
+//! single slotframe of 101 timeslots with one shared link in timeslot 0, which
+//! is used for all frames.
+//!
+//! Usage
+//! -----
+//! This capsule implements the `capsules::ieee802154::mac::Mac` interface, and
+//! is used like `capsules::ieee802154::xmac::XMac` as the backend for a
+//! `capsules::ieee802154::device::MacDevice`:
+//!
+//! ```rust
+//! use capsules::ieee802154::mac::Mac;
+//! use capsules::ieee802154::tsch;
+//! type TschDevice = capsules::ieee802154::tsch::TschMac<'static, RF233Device, Alarm>;
+//!
+//! // TschMac needs one buffer in addition to those provided to the radio
+//! // driver, which holds the Enhanced Beacons, keep-alives and
+//! // acknowledgements it generates.
+//! static mut MAC_BUF: [u8; radio::MAX_BUF_SIZE] = [0x00; radio::MAX_BUF_SIZE];
+//! // ...
+//! let tsch_mac: &TschDevice = static_init!(TschDevice, tsch::TschMac::new(rf233, alarm));
+//! alarm.set_client(tsch_mac);
+//!
+//! rf233.set_transmit_client(tsch_mac);
+//! rf233.set_receive_client(tsch_mac, &mut RF233_RX_BUF);
+//! rf233.set_config_client(tsch_mac);
+//!
+//! tsch_mac.initialize(&mut MAC_BUF);
+//!
+//! // Either start a new network as its coordinator...
+//! tsch_mac.start_network();
+//! // ...or join an existing one by listening for beacons on a channel.
+//! tsch_mac.join(26);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use ieee802154::mac::Mac;
+use kernel::ReturnCode;
+use kernel::common::take_cell::{MapCell, TakeCell};
+use kernel::hil::radio;
+use kernel::hil::time::{self, Alarm, Frequency};
+use net::ieee802154::*;
+use net::stream::{decode_u16, decode_u8, encode_u16, encode_u8};
+use net::stream::SResult;
+
+/// The default timeslot template (IEEE 802.15.4-2015: Table 8-99). All
+/// durations are in microseconds.
+mod timeslot {
+    /// The ID of the template
+    pub const TEMPLATE_ID: u8 = 0;
+    /// From the start of the timeslot to the start of the frame
+    pub const TX_OFFSET: u32 = 2120;
+    /// From the start of the timeslot to when the receiver starts listening
+    pub const RX_OFFSET: u32 = 1020;
+    /// How long the receiver waits for the start of a frame
+    pub const RX_WAIT: u32 = 2200;
+    /// From the end of a frame to the start of its acknowledgement
+    pub const TX_ACK_DELAY: u32 = 1000;
+    /// How long the transmitter waits for the start of an acknowledgement
+    pub const ACK_WAIT: u32 = 400;
+    pub const LENGTH: u32 = 10000;
+}
+
+/// The default hopping sequence for the 2450 MHz band
+/// (IEEE 802.15.4-2015: 6.2.10)
+pub const HOPPING_SEQUENCE: [u8; 16] = [
+    16, 17, 23, 18, 26, 15, 25, 22, 19, 11, 12, 13, 24, 14, 20, 21,
+];
+const HOPPING_SEQUENCE_ID: u8 = 0;
+
+// Synchronization header and PHY header, which precede the frame on the air
+const PHY_HEADER_SIZE: usize = 6;
+// PHY header, which follows the start-of-frame delimiter
+const PHR_SIZE: usize = 1;
+// Air time of a byte at 250 kbit/s, in microseconds
+const BYTE_DURATION_US: u32 = 32;
+// Longest acknowledgement that this layer expects
+const MAX_ACK_SIZE: usize = 32;
+
+/// The maximum number of slotframes and links in the schedule
+pub const MAX_SLOTFRAMES: usize = 2;
+pub const MAX_LINKS: usize = 8;
+
+/// The slotframe size of the minimal 6TiSCH configuration (RFC 8180: 4.1)
+pub const MINIMAL_SLOTFRAME_SIZE: u16 = 101;
+
+/// The default number of timeslots between two Enhanced Beacons
+pub const DEFAULT_EB_PERIOD: u64 = 400;
+
+// Number of timeslots without synchronization with the time source after which
+// a keep-alive is sent to it, and after which the node leaves the network
+const KEEPALIVE_PERIOD: u64 = 1200;
+const DESYNC_TIMEOUT: u64 = 2100;
+
+// Retransmissions of unacknowledged frames, and the backoff exponents of the
+// TSCH CSMA-CA algorithm used in shared links (IEEE 802.15.4-2015: 6.2.5.3)
+pub const MAX_FRAME_RETRIES: u8 = 3;
+const MIN_BE: u8 = 1;
+const MAX_BE: u8 = 5;
+
+// Layout of the content of the ACK/NACK Time Correction IE
+// (IEEE 802.15.4-2015: 7.4.2.7)
+const TIME_CORRECTION_MASK: u16 = 0x0fff;
+const TIME_CORRECTION_MAX: i32 = 2047;
+const TIME_CORRECTION_NACK: u16 = 0x8000;
+
+// Large enough for the nested IEs of an Enhanced Beacon advertising the
+// largest schedule
+const SCHEDULE_IE_SIZE: usize = 1 + MAX_SLOTFRAMES * 4 + MAX_LINKS * 5;
+const BEACON_IES_SIZE: usize = 8 + 3 + 3 + 2 + SCHEDULE_IE_SIZE;
+
+/// Options of a link (IEEE 802.15.4-2015: 7.4.4.3)
+pub mod link_options {
+    pub const TX: u8 = 1 << 0;
+    pub const RX: u8 = 1 << 1;
+    pub const SHARED: u8 = 1 << 2;
+    pub const TIMEKEEPING: u8 = 1 << 3;
+}
+
+/// A slotframe of `size` timeslots, identified by its handle. When links of
+/// several slotframes fall in the same timeslot, the slotframe with the lowest
+/// handle takes precedence.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Slotframe {
+    pub handle: u8,
+    pub size: u16,
+}
+
+/// A link in timeslot `timeslot` of a slotframe. A link without a neighbor can
+/// be used to communicate with any neighbor, and Enhanced Beacons are sent in
+/// shared links without a neighbor.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Link {
+    pub slotframe_handle: u8,
+    pub timeslot: u16,
+    pub channel_offset: u16,
+    pub options: u8,
+    pub neighbor: Option<MacAddress>,
+}
+
+impl Link {
+    fn has_option(&self, option: u8) -> bool {
+        self.options & option != 0
+    }
+
+    fn can_reach(&self, dst: Option<MacAddress>) -> bool {
+        self.neighbor.map_or(true, |neighbor| Some(neighbor) == dst)
+    }
+}
+
+/// The frames transmitted by this layer
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum TxKind {
+    Data,
+    Beacon,
+    KeepAlive,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum TschState {
+    /// Not part of a network, with the radio off
+    Off,
+    /// Listening for Enhanced Beacons in order to join a network
+    Joining,
+    /// Synchronized, and waiting for the next active timeslot
+    Idle,
+    /// Waiting for the start of a frame transmission in the current timeslot
+    TxOffset(TxKind),
+    Tx(TxKind),
+    TxAckWait(TxKind),
+    /// Listening for a frame in the current timeslot
+    RxListen,
+    /// Waiting for the start of the acknowledgement of a received frame
+    RxAckDelay,
+    RxAck,
+}
+
+pub struct TschMac<'a, R: radio::Radio + 'a, A: Alarm + 'a> {
+    radio: &'a R,
+    alarm: &'a A,
+    tx_client: Cell<Option<&'static radio::TxClient>>,
+    rx_client: Cell<Option<&'static radio::RxClient>>,
+    config_client: Cell<Option<&'static radio::ConfigClient>>,
+    // Whether the ongoing radio configuration was requested by the client, as
+    // opposed to a channel change by this layer
+    config_pending: Cell<bool>,
+    state: Cell<TschState>,
+    // Whether `fired` is running, and whether the alarm it handles must be
+    // handled again because the next deadline has already passed
+    handling_alarm: Cell<bool>,
+    alarm_due: Cell<bool>,
+
+    // The schedule
+    slotframes: MapCell<[Option<Slotframe>; MAX_SLOTFRAMES]>,
+    links: MapCell<[Option<Link>; MAX_LINKS]>,
+    join_channel: Cell<u8>,
+
+    // The current timeslot, or the next active one while idle, which is the
+    // first active one from `idle_asn` onwards. Timeslot `ref_asn` started at
+    // time `ref_time`, which is moved whenever the node synchronizes with its
+    // time source.
+    asn: Cell<u64>,
+    link: Cell<Option<Link>>,
+    idle_asn: Cell<u64>,
+    ref_asn: Cell<u64>,
+    ref_time: Cell<u32>,
+    time_source: Cell<Option<MacAddress>>,
+    last_sync_asn: Cell<u64>,
+    join_metric: Cell<u8>,
+
+    eb_period: Cell<u64>,
+    next_eb_asn: Cell<Option<u64>>,
+
+    // The frame of the client awaiting transmission
+    tx_buf: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    tx_dst: Cell<Option<MacAddress>>,
+    tx_seq: Cell<Option<u8>>,
+    tx_ack_requested: Cell<bool>,
+    tx_retries: Cell<u8>,
+    backoff_exponent: Cell<u8>,
+    backoff_window: Cell<u32>,
+    random: Cell<u32>,
+
+    // Enhanced Beacons, keep-alives and acknowledgements are built in this
+    // buffer, which is only used within a single timeslot
+    mac_buf: TakeCell<'static, [u8]>,
+    mac_seq: Cell<u8>,
+    // Destination and sequence number of the frame awaiting acknowledgement
+    ack_dst: Cell<Option<MacAddress>>,
+    ack_seq: Cell<Option<u8>>,
+    ack_len: Cell<usize>,
+}
+
+impl<'a, R: radio::Radio + 'a, A: Alarm + 'a> TschMac<'a, R, A> {
+    pub fn new(radio: &'a R, alarm: &'a A) -> TschMac<'a, R, A> {
+        TschMac {
+            radio: radio,
+            alarm: alarm,
+            tx_client: Cell::new(None),
+            rx_client: Cell::new(None),
+            config_client: Cell::new(None),
+            config_pending: Cell::new(false),
+            state: Cell::new(TschState::Off),
+            handling_alarm: Cell::new(false),
+            alarm_due: Cell::new(false),
+            slotframes: MapCell::new([None; MAX_SLOTFRAMES]),
+            links: MapCell::new([None; MAX_LINKS]),
+            join_channel: Cell::new(0),
+            asn: Cell::new(0),
+            link: Cell::new(None),
+            idle_asn: Cell::new(0),
+            ref_asn: Cell::new(0),
+            ref_time: Cell::new(0),
+            time_source: Cell::new(None),
+            last_sync_asn: Cell::new(0),
+            join_metric: Cell::new(0),
+            eb_period: Cell::new(DEFAULT_EB_PERIOD),
+            next_eb_asn: Cell::new(None),
+            tx_buf: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            tx_dst: Cell::new(None),
+            tx_seq: Cell::new(None),
+            tx_ack_requested: Cell::new(false),
+            tx_retries: Cell::new(0),
+            backoff_exponent: Cell::new(MIN_BE),
+            backoff_window: Cell::new(0),
+            random: Cell::new(0),
+            mac_buf: TakeCell::empty(),
+            mac_seq: Cell::new(0),
+            ack_dst: Cell::new(None),
+            ack_seq: Cell::new(None),
+            ack_len: Cell::new(0),
+        }
+    }
+
+    /// Starts a new network as its PAN coordinator, in which the current time
+    /// is the start of timeslot 0.
+    pub fn start_network(&self) -> ReturnCode {
+        if self.state.get() != TschState::Off {
+            return ReturnCode::EALREADY;
+        } else if self.mac_buf.is_none() {
+            return ReturnCode::EOFF;
+        }
+
+        self.time_source.set(None);
+        self.join_metric.set(0);
+        self.synchronize(0, self.alarm.now());
+        ReturnCode::SUCCESS
+    }
+
+    /// Joins a network by listening for Enhanced Beacons on `channel`. The
+    /// node adopts the schedule advertised by the first beacon it receives.
+    pub fn join(&self, channel: u8) -> ReturnCode {
+        if self.state.get() != TschState::Off {
+            return ReturnCode::EALREADY;
+        } else if self.mac_buf.is_none() {
+            return ReturnCode::EOFF;
+        }
+        let rval = self.radio.set_channel(channel);
+        if rval != ReturnCode::SUCCESS {
+            return rval;
+        }
+
+        self.join_channel.set(channel);
+        self.start_joining();
+        ReturnCode::SUCCESS
+    }
+
+    /// Leaves the network and turns off the radio. A frame awaiting
+    /// transmission is returned to the client with ReturnCode::ECANCEL.
+    pub fn stop(&self) -> ReturnCode {
+        match self.state.get() {
+            TschState::Off => return ReturnCode::EALREADY,
+            TschState::Joining | TschState::Idle => {}
+            _ => return ReturnCode::EBUSY,
+        }
+
+        self.state.set(TschState::Off);
+        self.time_source.set(None);
+        self.radio.stop();
+        self.cancel_transmission(ReturnCode::ECANCEL);
+        ReturnCode::SUCCESS
+    }
+
+    /// Whether the node is part of a network, and can send frames
+    pub fn is_synchronized(&self) -> bool {
+        match self.state.get() {
+            TschState::Off | TschState::Joining => false,
+            _ => true,
+        }
+    }
+
+    /// The current absolute slot number, or that of the next active timeslot
+    /// while no timeslot is in progress
+    pub fn get_asn(&self) -> u64 {
+        self.asn.get()
+    }
+
+    /// The neighbor this node synchronizes with, which is `None` for the PAN
+    /// coordinator
+    pub fn get_time_source(&self) -> Option<MacAddress> {
+        self.time_source.get()
+    }
+
+    /// Sets the number of timeslots between two Enhanced Beacons. A period of
+    /// 0 disables Enhanced Beacons.
+    pub fn set_eb_period(&self, period: u64) {
+        self.eb_period.set(period);
+        self.reschedule();
+    }
+
+    /// Adds a slotframe to the schedule. Returns EALREADY if there already is a
+    /// slotframe with the same handle, and ENOMEM if the schedule is full.
+    pub fn add_slotframe(&self, slotframe: Slotframe) -> ReturnCode {
+        if slotframe.size == 0 {
+            return ReturnCode::EINVAL;
+        }
+        let rval = self.slotframes.map_or(ReturnCode::FAIL, |slotframes| {
+            if slotframes
+                .iter()
+                .any(|sf| sf.map_or(false, |sf| sf.handle == slotframe.handle))
+            {
+                return ReturnCode::EALREADY;
+            }
+            slotframes
+                .iter_mut()
+                .find(|sf| sf.is_none())
+                .map_or(ReturnCode::ENOMEM, |entry| {
+                    *entry = Some(slotframe);
+                    ReturnCode::SUCCESS
+                })
+        });
+        self.reschedule();
+        rval
+    }
+
+    /// Removes a slotframe and all of its links from the schedule
+    pub fn remove_slotframe(&self, handle: u8) -> ReturnCode {
+        let rval = self.slotframes.map_or(ReturnCode::FAIL, |slotframes| {
+            slotframes
+                .iter_mut()
+                .find(|sf| sf.map_or(false, |sf| sf.handle == handle))
+                .map_or(ReturnCode::EINVAL, |entry| {
+                    *entry = None;
+                    ReturnCode::SUCCESS
+                })
+        });
+        self.links.map(|links| {
+            for entry in links.iter_mut() {
+                if entry.map_or(false, |link| link.slotframe_handle == handle) {
+                    *entry = None;
+                }
+            }
+        });
+        self.reschedule();
+        rval
+    }
+
+    /// Adds a link to the schedule. Returns EINVAL if its slotframe does not
+    /// exist or is too short, and ENOMEM if the schedule is full.
+    pub fn add_link(&self, link: Link) -> ReturnCode {
+        let fits = self
+            .slotframe_size(link.slotframe_handle)
+            .map_or(false, |size| link.timeslot < size);
+        if !fits || link.options & (link_options::TX | link_options::RX) == 0 {
+            return ReturnCode::EINVAL;
+        }
+        let rval = self.links.map_or(ReturnCode::FAIL, |links| {
+            links
+                .iter_mut()
+                .find(|entry| entry.is_none())
+                .map_or(ReturnCode::ENOMEM, |entry| {
+                    *entry = Some(link);
+                    ReturnCode::SUCCESS
+                })
+        });
+        self.reschedule();
+        rval
+    }
+
+    /// Removes the links in timeslot `timeslot` of a slotframe
+    pub fn remove_link(&self, slotframe_handle: u8, timeslot: u16) -> ReturnCode {
+        let mut rval = ReturnCode::EINVAL;
+        self.links.map(|links| {
+            for entry in links.iter_mut() {
+                let found = entry.map_or(false, |link| {
+                    link.slotframe_handle == slotframe_handle && link.timeslot == timeslot
+                });
+                if found {
+                    *entry = None;
+                    rval = ReturnCode::SUCCESS;
+                }
+            }
+        });
+        self.reschedule();
+        rval
+    }
+
+    /// Replaces the schedule with the minimal 6TiSCH configuration: a single
+    /// slotframe with one shared link in timeslot 0, channel offset 0.
+    pub fn set_minimal_schedule(&self) {
+        self.slotframes.map(|slotframes| {
+            *slotframes = [None; MAX_SLOTFRAMES];
+            slotframes[0] = Some(Slotframe {
+                handle: 0,
+                size: MINIMAL_SLOTFRAME_SIZE,
+            });
+        });
+        self.links.map(|links| {
+            *links = [None; MAX_LINKS];
+            links[0] = Some(Link {
+                slotframe_handle: 0,
+                timeslot: 0,
+                channel_offset: 0,
+                options: link_options::TX
+                    | link_options::RX
+                    | link_options::SHARED
+                    | link_options::TIMEKEEPING,
+                neighbor: None,
+            });
+        });
+        self.reschedule();
+    }
+
+    fn slotframe_size(&self, handle: u8) -> Option<u16> {
+        self.slotframes.and_then(|slotframes| {
+            slotframes
+                .iter()
+                .filter_map(|sf| *sf)
+                .find(|sf| sf.handle == handle)
+                .map(|sf| sf.size)
+        })
+    }
+
+    fn us_to_tics(&self, us: u64) -> u32 {
+        (us * <A::Frequency>::frequency() as u64 / 1_000_000) as u32
+    }
+
+    fn signed_us_to_tics(&self, us: i32) -> i32 {
+        (us as i64 * <A::Frequency>::frequency() as i64 / 1_000_000) as i32
+    }
+
+    fn signed_tics_to_us(&self, tics: i32) -> i32 {
+        (tics as i64 * 1_000_000 / <A::Frequency>::frequency() as i64) as i32
+    }
+
+    fn frame_duration_us(frame_len: usize) -> u32 {
+        (PHY_HEADER_SIZE + frame_len + radio::MFR_SIZE) as u32 * BYTE_DURATION_US
+    }
+
+    /// The time at which a timeslot starts, which may be before the reference
+    /// timeslot
+    fn slot_start(&self, asn: u64) -> u32 {
+        let ref_asn = self.ref_asn.get();
+        if asn >= ref_asn {
+            let elapsed_us = (asn - ref_asn) * timeslot::LENGTH as u64;
+            self.ref_time
+                .get()
+                .wrapping_add(self.us_to_tics(elapsed_us))
+        } else {
+            let remaining_us = (ref_asn - asn) * timeslot::LENGTH as u64;
+            self.ref_time
+                .get()
+                .wrapping_sub(self.us_to_tics(remaining_us))
+        }
+    }
+
+    /// The time at which a frame sent in the current timeslot is expected to
+    /// have been received entirely
+    fn expected_rx_time(&self, frame_len: usize) -> u32 {
+        let offset_us = timeslot::TX_OFFSET + Self::frame_duration_us(frame_len);
+        self.slot_start(self.asn.get())
+            .wrapping_add(self.us_to_tics(offset_us as u64))
+    }
+
+    fn channel(&self, asn: u64, channel_offset: u16) -> u8 {
+        let len = HOPPING_SEQUENCE.len() as u64;
+        HOPPING_SEQUENCE[((asn + channel_offset as u64) % len) as usize]
+    }
+
+    /// Arms the alarm, unless `time` has already passed
+    fn set_alarm_at(&self, time: u32) -> bool {
+        if time.wrapping_sub(self.alarm.now()) as i32 <= 0 {
+            false
+        } else {
+            self.alarm.set_alarm(time);
+            true
+        }
+    }
+
+    /// Arms the alarm, or handles it immediately if `time` has already passed.
+    /// While an alarm is being handled, the next one is only marked as due so
+    /// that a node running late does not recurse once per missed deadline.
+    fn set_alarm_or_fire(&self, time: u32) {
+        if self.set_alarm_at(time) {
+            return;
+        }
+        if self.handling_alarm.get() {
+            self.alarm_due.set(true);
+        } else {
+            time::Client::fired(self);
+        }
+    }
+
+    fn handle_alarm(&self) {
+        match self.state.get() {
+            TschState::Idle => self.start_slot(),
+            TschState::TxOffset(kind) => self.transmit_frame(kind),
+            TschState::TxAckWait(kind) => {
+                let buf = match kind {
+                    TxKind::Data => self.tx_buf.take(),
+                    _ => self.mac_buf.take(),
+                };
+                match buf {
+                    Some(buf) => self.transmission_done(kind, buf, false, ReturnCode::SUCCESS),
+                    None => self.end_slot(),
+                }
+            }
+            TschState::RxListen => self.end_slot(),
+            TschState::RxAckDelay => {
+                let result = self
+                    .mac_buf
+                    .take()
+                    .map(|buf| self.radio.transmit(buf, self.ack_len.get()));
+                match result {
+                    Some((ReturnCode::SUCCESS, _)) => self.state.set(TschState::RxAck),
+                    Some((_, Some(buf))) => {
+                        self.mac_buf.replace(buf);
+                        self.end_slot();
+                    }
+                    _ => self.end_slot(),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// xorshift32, seeded with the long address so that neighbors back off
+    /// differently
+    fn random(&self) -> u32 {
+        let mut x = self.random.get();
+        if x == 0 {
+            x = self
+                .radio
+                .get_address_long()
+                .iter()
+                .fold(0x2545f491, |acc, &b| acc.rotate_left(5) ^ b as u32);
+        }
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.random.set(x);
+        x
+    }
+
+    fn is_own_addr(&self, addr: MacAddress) -> bool {
+        match addr {
+            MacAddress::Short(addr) => addr == self.radio.get_address() || addr == BROADCAST_ADDR,
+            MacAddress::Long(addr) => addr == self.radio.get_address_long(),
+        }
+    }
+
+    fn start_joining(&self) {
+        self.state.set(TschState::Joining);
+        self.radio.set_channel(self.join_channel.get());
+        self.radio.config_commit();
+        if !self.radio.is_on() {
+            self.radio.start();
+        }
+    }
+
+    /// Aligns the start of timeslot `asn` with `time`, and resumes the schedule
+    /// from that timeslot
+    fn synchronize(&self, asn: u64, time: u32) {
+        self.ref_asn.set(asn);
+        self.ref_time.set(time);
+        self.asn.set(asn);
+        self.last_sync_asn.set(asn);
+        self.next_eb_asn.set(None);
+        self.state.set(TschState::Idle);
+        if !self.radio.is_on() {
+            self.radio.start();
+        }
+        self.schedule_from(asn);
+    }
+
+    /// Moves the timeslot boundaries by `tics`, after a measurement of the
+    /// drift with the time source
+    fn adjust_time(&self, tics: i32) {
+        let asn = self.asn.get();
+        self.ref_time
+            .set(self.slot_start(asn).wrapping_add(tics as u32));
+        self.ref_asn.set(asn);
+        self.last_sync_asn.set(asn);
+    }
+
+    /// Leaves a network the node lost synchronization with, and attempts to
+    /// join it again
+    fn desynchronize(&self) {
+        self.time_source.set(None);
+        self.cancel_transmission(ReturnCode::EOFF);
+        self.start_joining();
+    }
+
+    fn cancel_transmission(&self, result: ReturnCode) {
+        self.tx_buf.take().map(|buf| {
+            self.tx_client
+                .get()
+                .map(move |client| client.send_done(buf, false, result));
+        });
+    }
+
+    fn eb_due(&self, link: &Link, asn: u64) -> bool {
+        let period = self.eb_period.get();
+        period > 0
+            && link.has_option(link_options::SHARED)
+            && link.neighbor.is_none()
+            && self.next_eb_asn.get().map_or(true, |next| asn >= next)
+    }
+
+    fn keepalive_due(&self, link: &Link, asn: u64) -> bool {
+        self.time_source.get().map_or(false, |time_source| {
+            link.can_reach(Some(time_source)) && asn >= self.last_sync_asn.get() + KEEPALIVE_PERIOD
+        })
+    }
+
+    fn data_pending(&self, link: &Link) -> bool {
+        self.tx_buf.is_some() && link.can_reach(self.tx_dst.get())
+    }
+
+    /// Whether the node needs to wake up for a link in timeslot `asn`
+    fn link_active(&self, link: &Link, asn: u64) -> bool {
+        link.has_option(link_options::RX)
+            || (link.has_option(link_options::TX)
+                && (self.eb_due(link, asn)
+                    || self.data_pending(link)
+                    || self.keepalive_due(link, asn)))
+    }
+
+    /// Chooses the frame to send in a link, if any. A data frame in backoff
+    /// skips shared links until its backoff window expires.
+    fn select_transmission(&self, link: &Link, asn: u64) -> Option<TxKind> {
+        if !link.has_option(link_options::TX) {
+            return None;
+        }
+        if self.eb_due(link, asn) {
+            return Some(TxKind::Beacon);
+        }
+        if self.data_pending(link) {
+            let window = self.backoff_window.get();
+            if !link.has_option(link_options::SHARED) || window == 0 {
+                return Some(TxKind::Data);
+            }
+            self.backoff_window.set(window - 1);
+        }
+        if self.keepalive_due(link, asn) {
+            return Some(TxKind::KeepAlive);
+        }
+        None
+    }
+
+    /// Finds the first timeslot from `asn` onwards with an active link. Links of
+    /// slotframes with lower handles take precedence, then links in which
+    /// there is something to send.
+    fn next_active_link(&self, asn: u64) -> Option<(u64, Link)> {
+        let mut next: Option<(u64, Link)> = None;
+        self.links.map(|links| {
+            for link in links.iter().filter_map(|link| *link) {
+                let size = match self.slotframe_size(link.slotframe_handle) {
+                    Some(size) => size as u64,
+                    None => continue,
+                };
+                let link_asn = asn + (link.timeslot as u64 + size - asn % size) % size;
+                if !self.link_active(&link, link_asn) {
+                    continue;
+                }
+                let rx_only = !link.has_option(link_options::TX);
+                let better = next.map_or(true, |(next_asn, next_link)| {
+                    let next_rx_only = !next_link.has_option(link_options::TX);
+                    (link_asn, link.slotframe_handle, rx_only)
+                        < (next_asn, next_link.slotframe_handle, next_rx_only)
+                });
+                if better {
+                    next = Some((link_asn, link));
+                }
+            }
+        });
+        next
+    }
+
+    /// Arms the alarm for the first active timeslot from `asn` onwards that has
+    /// not started yet. Without any active link, the node stays idle until the
+    /// schedule changes or a frame is queued.
+    fn schedule_from(&self, asn: u64) {
+        self.idle_asn.set(asn);
+        self.link.set(None);
+        let mut asn = asn;
+        while let Some((next_asn, link)) = self.next_active_link(asn) {
+            if self.set_alarm_at(self.slot_start(next_asn)) {
+                self.asn.set(next_asn);
+                self.link.set(Some(link));
+                return;
+            }
+            // Too late for this timeslot
+            asn = next_asn + 1;
+        }
+    }
+
+    /// Schedules the next active timeslot again after a change to the
+    /// schedule or to the pending frames
+    fn reschedule(&self) {
+        if self.state.get() == TschState::Idle {
+            self.schedule_from(self.idle_asn.get());
+        }
+    }
+
+    fn start_slot(&self) {
+        let asn = self.asn.get();
+        let link = match self.link.get() {
+            Some(link) => link,
+            None => return,
+        };
+        if self.time_source.get().is_some() && asn > self.last_sync_asn.get() + DESYNC_TIMEOUT {
+            self.desynchronize();
+            return;
+        }
+
+        if !self.radio.is_on() {
+            self.radio.start();
+        }
+        self.radio
+            .set_channel(self.channel(asn, link.channel_offset));
+        self.radio.config_commit();
+
+        let slot_start = self.slot_start(asn);
+        if let Some(kind) = self.select_transmission(&link, asn) {
+            self.state.set(TschState::TxOffset(kind));
+            let tx_offset = self.us_to_tics(timeslot::TX_OFFSET as u64);
+            self.set_alarm_or_fire(slot_start.wrapping_add(tx_offset));
+        } else if link.has_option(link_options::RX) {
+            // Listen until the end of the longest frame that can start within
+            // the receive window
+            self.state.set(TschState::RxListen);
+            let rx_end_us = timeslot::RX_OFFSET
+                + timeslot::RX_WAIT
+                + Self::frame_duration_us(radio::MAX_FRAME_SIZE);
+            self.set_alarm_or_fire(slot_start.wrapping_add(self.us_to_tics(rx_end_us as u64)));
+        } else {
+            self.end_slot();
+        }
+    }
+
+    fn end_slot(&self) {
+        let asn = self.asn.get();
+        self.state.set(TschState::Idle);
+        self.schedule_from(asn + 1);
+
+        // Leave the radio on if the next timeslot is also active
+        let next_slot_active = self.link.get().is_some() && self.asn.get() == asn + 1;
+        if !next_slot_active {
+            self.radio.stop();
+        }
+    }
+
+    fn transmit_frame(&self, kind: TxKind) {
+        let result = match kind {
+            TxKind::Data => {
+                self.ack_dst.set(self.tx_dst.get());
+                self.ack_seq.set(self.tx_seq.get());
+                self.tx_buf
+                    .take()
+                    .map(|buf| self.radio.transmit(buf, self.tx_len.get()))
+            }
+            TxKind::Beacon => self
+                .mac_buf
+                .take()
+                .map(|buf| match self.prepare_beacon(buf) {
+                    Some(frame_len) => self.radio.transmit(buf, frame_len),
+                    None => (ReturnCode::FAIL, Some(buf)),
+                }),
+            TxKind::KeepAlive => self
+                .mac_buf
+                .take()
+                .map(|buf| match self.prepare_keepalive(buf) {
+                    Some(frame_len) => self.radio.transmit(buf, frame_len),
+                    None => (ReturnCode::FAIL, Some(buf)),
+                }),
+        };
+
+        match result {
+            Some((ReturnCode::SUCCESS, _)) => self.state.set(TschState::Tx(kind)),
+            Some((rval, Some(buf))) => self.transmission_done(kind, buf, false, rval),
+            _ => self.end_slot(),
+        }
+    }
+
+    /// Completes a transmission in the current timeslot. Unacknowledged data
+    /// frames are retransmitted in a later link, after a backoff in shared
+    /// links.
+    fn transmission_done(
+        &self,
+        kind: TxKind,
+        buf: &'static mut [u8],
+        acked: bool,
+        rval: ReturnCode,
+    ) {
+        let mut done = None;
+        match kind {
+            TxKind::Data => {
+                let shared = self
+                    .link
+                    .get()
+                    .map_or(false, |link| link.has_option(link_options::SHARED));
+                let success = rval == ReturnCode::SUCCESS;
+                if success && (acked || !self.tx_ack_requested.get()) {
+                    self.backoff_exponent.set(MIN_BE);
+                    done = Some((buf, acked, ReturnCode::SUCCESS));
+                } else if self.tx_retries.get() >= MAX_FRAME_RETRIES {
+                    self.backoff_exponent.set(MIN_BE);
+                    let rval = if success { ReturnCode::ENOACK } else { rval };
+                    done = Some((buf, false, rval));
+                } else {
+                    self.tx_retries.set(self.tx_retries.get() + 1);
+                    if shared {
+                        let exponent = self.backoff_exponent.get();
+                        self.backoff_window.set(self.random() % (1 << exponent));
+                        self.backoff_exponent.set(cmp::min(exponent + 1, MAX_BE));
+                    }
+                    self.tx_buf.replace(buf);
+                }
+            }
+            TxKind::Beacon => {
+                if rval == ReturnCode::SUCCESS {
+                    // The period is shortened by up to half at random, so that
+                    // beacons are not always sent on the same channels
+                    let period = self.eb_period.get();
+                    let jitter = self.random() as u64 % (period / 2 + 1);
+                    self.next_eb_asn.set(Some(self.asn.get() + period - jitter));
+                }
+                self.mac_buf.replace(buf);
+            }
+            TxKind::KeepAlive => {
+                self.mac_buf.replace(buf);
+            }
+        }
+
+        // The next timeslot is scheduled before returning the frame, in case the
+        // client immediately transmits another one.
+        self.end_slot();
+        if let Some((buf, acked, rval)) = done {
+            self.tx_client
+                .get()
+                .map(move |client| client.send_done(buf, acked, rval));
+        }
+    }
+
+    fn own_header<'b>(&self, frame_type: FrameType) -> Header<'b> {
+        Header {
+            frame_type: frame_type,
+            frame_pending: false,
+            ack_requested: false,
+            version: FrameVersion::V2015,
+            seq: None,
+            dst_pan: None,
+            dst_addr: None,
+            src_pan: None,
+            src_addr: None,
+            security: None,
+            header_ies: Default::default(),
+            header_ies_len: 0,
+            payload_ies: Default::default(),
+            payload_ies_len: 0,
+        }
+    }
+
+    /// Builds an Enhanced Beacon, and returns its length
+    fn prepare_beacon(&self, buf: &mut [u8]) -> Option<usize> {
+        let mut ies = [0u8; BEACON_IES_SIZE];
+        let ies_len = match self.encode_beacon_ies(&mut ies).done() {
+            Some((ies_len, _)) => ies_len,
+            None => return None,
+        };
+
+        let mut header = self.own_header(FrameType::Beacon);
+        header.src_pan = Some(self.radio.get_pan());
+        header.src_addr = Some(MacAddress::Long(self.radio.get_address_long()));
+        header.payload_ies[0] = PayloadIE::Undissected {
+            group_id: payload_ie_group::MLME,
+            content: &ies[..ies_len],
+        };
+        header.payload_ies_len = 1;
+        header
+            .encode(&mut buf[radio::PSDU_OFFSET..], false)
+            .done()
+            .map(|(frame_len, _)| frame_len)
+    }
+
+    fn encode_beacon_ies(&self, buf: &mut [u8]) -> SResult {
+        let asn = self.asn.get();
+        let mut sync = [0u8; 6];
+        for (i, byte) in sync[..5].iter_mut().enumerate() {
+            *byte = (asn >> (8 * i)) as u8;
+        }
+        sync[5] = self.join_metric.get();
+
+        let mut schedule = [0u8; SCHEDULE_IE_SIZE];
+        let schedule_len = match self.encode_schedule(&mut schedule).done() {
+            Some((schedule_len, _)) => schedule_len,
+            None => stream_err!(),
+        };
+
+        let nested_ies = [
+            NestedIE {
+                sub_id: mlme_sub_id::TSCH_SYNCHRONIZATION,
+                content: &sync,
+            },
+            NestedIE {
+                sub_id: mlme_sub_id::TSCH_TIMESLOT,
+                content: &[timeslot::TEMPLATE_ID],
+            },
+            NestedIE {
+                sub_id: mlme_sub_id::CHANNEL_HOPPING,
+                content: &[HOPPING_SEQUENCE_ID],
+            },
+            NestedIE {
+                sub_id: mlme_sub_id::TSCH_SLOTFRAME_AND_LINK,
+                content: &schedule[..schedule_len],
+            },
+        ];
+        let mut off = 0;
+        for ie in nested_ies.iter() {
+            off = enc_consume!(buf, off; ie; encode);
+        }
+        stream_done!(off);
+    }
+
+    /// Encodes the content of the TSCH Slotframe and Link IE
+    /// (IEEE 802.15.4-2015: 7.4.4.3), which only advertises the links that
+    /// are not dedicated to a neighbor.
+    fn encode_schedule(&self, buf: &mut [u8]) -> SResult {
+        let slotframes = self
+            .slotframes
+            .map_or([None; MAX_SLOTFRAMES], |slotframes| *slotframes);
+        let links = self.links.map_or([None; MAX_LINKS], |links| *links);
+
+        let num_slotframes = slotframes.iter().filter(|sf| sf.is_some()).count();
+        let mut off = enc_consume!(buf; encode_u8, num_slotframes as u8);
+        for slotframe in slotframes.iter().filter_map(|sf| *sf) {
+            let advertised =
+                |link: &&Link| link.slotframe_handle == slotframe.handle && link.neighbor.is_none();
+            let num_links = links
+                .iter()
+                .filter_map(|link| link.as_ref())
+                .filter(&advertised)
+                .count();
+            off = enc_consume!(buf, off; encode_u8, slotframe.handle);
+            off = enc_consume!(buf, off; encode_u16, slotframe.size.to_be());
+            off = enc_consume!(buf, off; encode_u8, num_links as u8);
+            for link in links
+                .iter()
+                .filter_map(|link| link.as_ref())
+                .filter(&advertised)
+            {
+                off = enc_consume!(buf, off; encode_u16, link.timeslot.to_be());
+                off = enc_consume!(buf, off; encode_u16, link.channel_offset.to_be());
+                off = enc_consume!(buf, off; encode_u8, link.options);
+            }
+        }
+        stream_done!(off);
+    }
+
+    /// Decodes the content of a TSCH Slotframe and Link IE into a schedule
+    fn decode_schedule(
+        buf: &[u8],
+        slotframes: &mut [Option<Slotframe>; MAX_SLOTFRAMES],
+        links: &mut [Option<Link>; MAX_LINKS],
+    ) -> SResult {
+        let (mut off, num_slotframes) = dec_try!(buf; decode_u8);
+        stream_cond!(num_slotframes as usize <= MAX_SLOTFRAMES);
+        let mut link_index = 0;
+        for slotframe in slotframes.iter_mut().take(num_slotframes as usize) {
+            let (next_off, handle) = dec_try!(buf, off; decode_u8);
+            let (next_off, size_be) = dec_try!(buf, next_off; decode_u16);
+            let (next_off, num_links) = dec_try!(buf, next_off; decode_u8);
+            off = next_off;
+            let size = u16::from_be(size_be);
+            stream_cond!(size > 0);
+            *slotframe = Some(Slotframe {
+                handle: handle,
+                size: size,
+            });
+
+            stream_cond!(link_index + num_links as usize <= MAX_LINKS);
+            for _ in 0..num_links {
+                let (next_off, timeslot_be) = dec_try!(buf, off; decode_u16);
+                let (next_off, channel_offset_be) = dec_try!(buf, next_off; decode_u16);
+                let (next_off, options) = dec_try!(buf, next_off; decode_u8);
+                off = next_off;
+                let timeslot = u16::from_be(timeslot_be);
+                stream_cond!(timeslot < size);
+                links[link_index] = Some(Link {
+                    slotframe_handle: handle,
+                    timeslot: timeslot,
+                    channel_offset: u16::from_be(channel_offset_be),
+                    options: options,
+                    neighbor: None,
+                });
+                link_index += 1;
+            }
+        }
+        stream_done!(off);
+    }
+
+    /// Builds a keep-alive for the time source, which is an empty data frame
+    /// requesting an acknowledgement, and returns its length
+    fn prepare_keepalive(&self, buf: &mut [u8]) -> Option<usize> {
+        let seq = self.mac_seq.get();
+        self.mac_seq.set(seq.wrapping_add(1));
+        self.ack_dst.set(self.time_source.get());
+        self.ack_seq.set(Some(seq));
+
+        let mut header = self.own_header(FrameType::Data);
+        header.ack_requested = true;
+        header.seq = Some(seq);
+        header.dst_pan = Some(self.radio.get_pan());
+        header.dst_addr = self.time_source.get();
+        header.src_pan = Some(self.radio.get_pan());
+        header.src_addr = Some(MacAddress::Long(self.radio.get_address_long()));
+        header
+            .encode(&mut buf[radio::PSDU_OFFSET..], false)
+            .done()
+            .map(|(frame_len, _)| frame_len)
+    }
+
+    /// Builds an Enhanced Acknowledgement carrying a time correction, and
+    /// returns its length
+    fn prepare_ack(&self, buf: &mut [u8], seq: Option<u8>, correction_us: i32) -> Option<usize> {
+        let correction = cmp::max(
+            -TIME_CORRECTION_MAX,
+            cmp::min(correction_us, TIME_CORRECTION_MAX),
+        );
+        let value = (correction as u16) & TIME_CORRECTION_MASK;
+        let content = [value as u8, (value >> 8) as u8];
+
+        let mut header = self.own_header(FrameType::Acknowledgement);
+        header.seq = seq;
+        header.header_ies[0] = HeaderIE::Undissected {
+            element_id: header_ie_id::ACK_NACK_TIME_CORRECTION,
+            content: &content,
+        };
+        header.header_ies_len = 1;
+        header
+            .encode(&mut buf[radio::PSDU_OFFSET..], false)
+            .done()
+            .map(|(frame_len, _)| frame_len)
+    }
+
+    /// Handles a frame received while waiting for an acknowledgement. Returns
+    /// `None` if the frame is not the expected acknowledgement, and otherwise
+    /// whether the frame was accepted.
+    fn receive_ack(&self, frame: &[u8]) -> Option<bool> {
+        let header = match Header::decode(frame, false).done() {
+            Some((_, (header, _))) => header,
+            None => return None,
+        };
+        if header.frame_type != FrameType::Acknowledgement || header.seq != self.ack_seq.get() {
+            return None;
+        }
+
+        let mut accepted = true;
+        for ie in header.header_ies[..header.header_ies_len].iter() {
+            match *ie {
+                HeaderIE::Undissected {
+                    element_id,
+                    content,
+                } if element_id == header_ie_id::ACK_NACK_TIME_CORRECTION && content.len() == 2 => {
+                    let value = content[0] as u16 | (content[1] as u16) << 8;
+                    accepted = value & TIME_CORRECTION_NACK == 0;
+                    // Sign-extend the 12-bit time correction
+                    let correction_us = ((value << 4) as i16 >> 4) as i32;
+                    if self.time_source.get().is_some()
+                        && self.ack_dst.get() == self.time_source.get()
+                    {
+                        self.adjust_time(self.signed_us_to_tics(correction_us));
+                    }
+                }
+                _ => {}
+            }
+        }
+        Some(accepted)
+    }
+
+    /// Joins the network advertised by an Enhanced Beacon received at `time`.
+    /// Returns false if the frame is not a suitable Enhanced Beacon.
+    fn receive_beacon(&self, frame: &[u8], time: u32) -> bool {
+        let header = match Header::decode(frame, false).done() {
+            Some((_, (header, _))) => header,
+            None => return false,
+        };
+        let (src_pan, src_addr) = match (header.src_pan, header.src_addr) {
+            (Some(src_pan), Some(src_addr)) => (src_pan, src_addr),
+            _ => return false,
+        };
+        if header.frame_type != FrameType::Beacon || header.version != FrameVersion::V2015 {
+            return false;
+        }
+
+        let mut sync = None;
+        let mut slotframes = [None; MAX_SLOTFRAMES];
+        let mut links = [None; MAX_LINKS];
+        let mut has_schedule = false;
+        for ie in header.payload_ies[..header.payload_ies_len].iter() {
+            let content = match *ie {
+                PayloadIE::Undissected { group_id, content }
+                    if group_id == payload_ie_group::MLME =>
+                {
+                    content
+                }
+                _ => continue,
+            };
+            let mut off = 0;
+            while off < content.len() {
+                let (next_off, nested) = match NestedIE::decode(&content[off..]).done() {
+                    Some(result) => result,
+                    None => return false,
+                };
+                off += next_off;
+                match nested.sub_id {
+                    mlme_sub_id::TSCH_SYNCHRONIZATION if nested.content.len() == 6 => {
+                        let asn = nested.content[..5]
+                            .iter()
+                            .rev()
+                            .fold(0, |asn, &b| (asn << 8) | b as u64);
+                        sync = Some((asn, nested.content[5]));
+                    }
+                    mlme_sub_id::TSCH_TIMESLOT | mlme_sub_id::CHANNEL_HOPPING => {
+                        // Only the defaults can be advertised by their ID
+                        let id = nested.content.first().cloned().unwrap_or(0);
+                        if id != 0 || nested.content.len() > 1 {
+                            return false;
+                        }
+                    }
+                    mlme_sub_id::TSCH_SLOTFRAME_AND_LINK => {
+                        if Self::decode_schedule(nested.content, &mut slotframes, &mut links)
+                            .done()
+                            .is_none()
+                        {
+                            return false;
+                        }
+                        has_schedule = true;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let (asn, join_metric) = match sync {
+            Some(sync) => sync,
+            None => return false,
+        };
+
+        if has_schedule {
+            self.slotframes.map(|current| *current = slotframes);
+            self.links.map(|current| *current = links);
+        }
+        self.radio.set_pan(src_pan);
+        self.time_source.set(Some(src_addr));
+        self.join_metric.set(join_metric.saturating_add(1));
+
+        // The beacon was sent at the transmit offset of timeslot `asn`
+        let offset_us = timeslot::TX_OFFSET + Self::frame_duration_us(frame.len());
+        self.synchronize(asn, time.wrapping_sub(self.us_to_tics(offset_us as u64)));
+        true
+    }
+
+    /// Handles a frame received in a receive link, acknowledging it if needed.
+    /// Returns whether the frame should be passed to the client.
+    fn receive_frame(&self, frame: &[u8], time: u32) -> bool {
+        let (data_offset, header) = match Header::decode(frame, false).done() {
+            Some((data_offset, (header, _))) => (data_offset, header),
+            None => return false,
+        };
+
+        // Measure the drift of the clock of the sender
+        let drift_tics = time.wrapping_sub(self.expected_rx_time(frame.len())) as i32;
+        if header.src_addr.is_some() && header.src_addr == self.time_source.get() {
+            self.adjust_time(drift_tics);
+        }
+
+        match header.frame_type {
+            FrameType::Data | FrameType::MACCommand => {}
+            // Enhanced Beacons and acknowledgements are handled by this layer
+            _ => return false,
+        }
+        if !header.dst_addr.map_or(false, |dst| self.is_own_addr(dst)) {
+            return false;
+        }
+
+        let unicast = header.dst_addr != Some(MacAddress::Short(BROADCAST_ADDR));
+        if header.ack_requested && unicast {
+            let correction_us = -self.signed_tics_to_us(drift_tics);
+            let ack = self.mac_buf.take().map(|buf| {
+                let ack_len = self.prepare_ack(buf, header.seq, correction_us);
+                self.mac_buf.replace(buf);
+                ack_len
+            });
+            if let Some(Some(ack_len)) = ack {
+                self.ack_len.set(ack_len);
+                self.state.set(TschState::RxAckDelay);
+                let delay = self.us_to_tics(timeslot::TX_ACK_DELAY as u64);
+                self.set_alarm_or_fire(time.wrapping_add(delay));
+            }
+        }
+
+        // Keep-alives carry no payload
+        frame.len() > data_offset
+    }
+}
+
+impl<'a, R: radio::Radio + 'a, A: Alarm + 'a> Mac for TschMac<'a, R, A> {
+    fn initialize(&self, mac_buf: &'static mut [u8]) -> ReturnCode {
+        self.mac_buf.replace(mac_buf);
+        self.set_minimal_schedule();
+        ReturnCode::SUCCESS
+    }
+
+    fn is_on(&self) -> bool {
+        self.is_synchronized()
+    }
+
+    fn set_config_client(&self, client: &'static radio::ConfigClient) {
+        self.config_client.set(Some(client));
+    }
+
+    fn set_address(&self, addr: u16) {
+        self.radio.set_address(addr)
+    }
+
+    fn set_address_long(&self, addr: [u8; 8]) {
+        self.radio.set_address_long(addr)
+    }
+
+    fn set_pan(&self, id: u16) {
+        self.radio.set_pan(id)
+    }
+
+    fn set_channel(&self, chan: u8) -> ReturnCode {
+        // The channel is chosen by the schedule; this only sets the channel
+        // used to join a network.
+        if chan < 11 || chan > 26 {
+            return ReturnCode::EINVAL;
+        }
+        self.join_channel.set(chan);
+        ReturnCode::SUCCESS
+    }
+
+    fn set_ack_frame_pending(&self, pending: bool) {
+        self.radio.set_ack_frame_pending(pending)
+    }
+
+    fn get_address(&self) -> u16 {
+        self.radio.get_address()
+    }
+
+    fn get_address_long(&self) -> [u8; 8] {
+        self.radio.get_address_long()
+    }
+
+    fn get_pan(&self) -> u16 {
+        self.radio.get_pan()
+    }
+
+    fn get_channel(&self) -> u8 {
+        self.radio.get_channel()
+    }
+
+    fn config_commit(&self) {
+        self.config_pending.set(true);
+        self.radio.config_commit()
+    }
+
+    fn set_transmit_client(&self, client: &'static radio::TxClient) {
+        self.tx_client.set(Some(client));
+    }
+
+    fn set_receive_client(&self, client: &'static radio::RxClient) {
+        self.rx_client.set(Some(client));
+    }
+
+    fn set_receive_buffer(&self, buffer: &'static mut [u8]) {
+        self.radio.set_receive_buffer(buffer);
+    }
+
+    fn transmit(
+        &self,
+        full_mac_frame: &'static mut [u8],
+        frame_len: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if !self.is_synchronized() {
+            return (ReturnCode::EOFF, Some(full_mac_frame));
+        } else if self.tx_buf.is_some() {
+            return (ReturnCode::EBUSY, Some(full_mac_frame));
+        } else if radio::PSDU_OFFSET + frame_len + radio::MFR_SIZE > full_mac_frame.len() {
+            return (ReturnCode::ESIZE, Some(full_mac_frame));
+        }
+
+        let frame = &full_mac_frame[radio::PSDU_OFFSET..radio::PSDU_OFFSET + frame_len];
+        match Header::decode(frame, false).done() {
+            Some((_, (header, _))) => {
+                self.tx_dst.set(header.dst_addr);
+                self.tx_seq.set(header.seq);
+                self.tx_ack_requested.set(header.ack_requested);
+            }
+            None => return (ReturnCode::FAIL, Some(full_mac_frame)),
+        }
+        self.tx_len.set(frame_len);
+        self.tx_retries.set(0);
+        self.backoff_window.set(0);
+        self.tx_buf.replace(full_mac_frame);
+
+        // The frame may be sent in a link that comes before the next active
+        // timeslot
+        self.reschedule();
+        (ReturnCode::SUCCESS, None)
+    }
+}
+
+impl<'a, R: radio::Radio + 'a, A: Alarm + 'a> time::Client for TschMac<'a, R, A> {
+    fn fired(&self) {
+        self.handling_alarm.set(true);
+        loop {
+            self.alarm_due.set(false);
+            self.handle_alarm();
+            if !self.alarm_due.get() {
+                break;
+            }
+        }
+        self.handling_alarm.set(false);
+    }
+}
+
+impl<'a, R: radio::Radio + 'a, A: Alarm + 'a> radio::ConfigClient for TschMac<'a, R, A> {
+    fn config_done(&self, result: ReturnCode) {
+        // Channel changes at the start of timeslots are not reported
+        if self.config_pending.get() {
+            self.config_pending.set(false);
+            self.config_client
+                .get()
+                .map(|client| client.config_done(result));
+        }
+    }
+}
+
+impl<'a, R: radio::Radio + 'a, A: Alarm + 'a> radio::TxClient for TschMac<'a, R, A> {
+    fn send_done(&self, buf: &'static mut [u8], acked: bool, result: ReturnCode) {
+        match self.state.get() {
+            TschState::Tx(kind) => {
+                let ack_requested = match kind {
+                    TxKind::Data => self.tx_ack_requested.get(),
+                    TxKind::Beacon => false,
+                    TxKind::KeepAlive => true,
+                };
+                if result == ReturnCode::SUCCESS && ack_requested && !acked {
+                    match kind {
+                        TxKind::Data => self.tx_buf.replace(buf),
+                        _ => self.mac_buf.replace(buf),
+                    };
+                    // Wait for the end of the longest acknowledgement that can
+                    // start within the acknowledgement window
+                    self.state.set(TschState::TxAckWait(kind));
+                    let wait_us = timeslot::TX_ACK_DELAY
+                        + timeslot::ACK_WAIT
+                        + Self::frame_duration_us(MAX_ACK_SIZE);
+                    let deadline = self
+                        .alarm
+                        .now()
+                        .wrapping_add(self.us_to_tics(wait_us as u64));
+                    self.set_alarm_or_fire(deadline);
+                } else {
+                    self.transmission_done(kind, buf, acked, result);
+                }
+            }
+            TschState::RxAck => {
+                self.mac_buf.replace(buf);
+                self.end_slot();
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<'a, R: radio::Radio + 'a, A: Alarm + 'a> radio::RxClient for TschMac<'a, R, A> {
+    fn receive(
+        &self,
+        buf: &'static mut [u8],
+        frame_len: usize,
+        crc_valid: bool,
+        info: radio::RxInfo,
+        result: ReturnCode,
+    ) {
+        // Frames are timed by their end, which is either computed from the
+        // time at which their start-of-frame delimiter was received, or
+        // approximated by the time at which they are reported
+        let time = info.timestamp.map_or(self.alarm.now(), |sfd_time| {
+            let psdu_us = (PHR_SIZE + frame_len + radio::MFR_SIZE) as u64 * BYTE_DURATION_US as u64;
+            sfd_time.wrapping_add(self.us_to_tics(psdu_us))
+        });
+        if !crc_valid || radio::PSDU_OFFSET + frame_len > buf.len() {
+            self.radio.set_receive_buffer(buf);
+            return;
+        }
+
+        let mut deliver = false;
+        {
+            let frame = &buf[radio::PSDU_OFFSET..radio::PSDU_OFFSET + frame_len];
+            match self.state.get() {
+                TschState::Joining => {
+                    self.receive_beacon(frame, time);
+                }
+                TschState::TxAckWait(kind) => {
+                    if let Some(accepted) = self.receive_ack(frame) {
+                        let buf = match kind {
+                            TxKind::Data => self.tx_buf.take(),
+                            _ => self.mac_buf.take(),
+                        };
+                        buf.map(|buf| {
+                            self.transmission_done(kind, buf, accepted, ReturnCode::SUCCESS)
+                        });
+                    }
+                }
+                TschState::RxListen => {
+                    deliver = self.receive_frame(frame, time);
+                    if self.state.get() == TschState::RxListen {
+                        self.end_slot();
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if deliver {
+            self.rx_client
+                .get()
+                .map(move |client| client.receive(buf, frame_len, crc_valid, info, result));
+        } else {
+            self.radio.set_receive_buffer(buf);
+        }
+    }
+}
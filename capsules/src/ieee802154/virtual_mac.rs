@@ -31,6 +31,7 @@ use ieee802154::{device, framer};
 use kernel::ReturnCode;
 use kernel::common::{List, ListLink, ListNode};
 use kernel::common::take_cell::MapCell;
+use kernel::hil::radio;
 use net::ieee802154::*;
 
 /// IEE 802.15.4 MAC device muxer that keeps a list of MAC users and sequences
@@ -44,18 +45,41 @@ pub struct MuxMac<'a> {
 
 impl<'a> device::TxClient for MuxMac<'a> {
     fn send_done(&self, spi_buf: &'static mut [u8], acked: bool, result: ReturnCode) {
+        self.send_done_info(spi_buf, acked, result, radio::TxInfo::default());
+    }
+
+    fn send_done_info(
+        &self,
+        spi_buf: &'static mut [u8],
+        acked: bool,
+        result: ReturnCode,
+        info: radio::TxInfo,
+    ) {
         self.inflight.get().map(move |user| {
             self.inflight.set(None);
-            user.send_done(spi_buf, acked, result);
+            user.send_done(spi_buf, acked, result, info);
         });
         self.do_next_op_async();
     }
 }
 
 impl<'a> device::RxClient for MuxMac<'a> {
-    fn receive<'b>(&self, buf: &'b [u8], header: Header<'b>, data_offset: usize, data_len: usize) {
+    fn receive<'b>(
+        &self,
+        buf: &'b [u8],
+        header: Header<'b>,
+        info: radio::RxInfo,
+        data_offset: usize,
+        data_len: usize,
+    ) {
+        for user in self.users.iter() {
+            user.receive(buf, header, info, data_offset, data_len);
+        }
+    }
+
+    fn receive_security_failure<'b>(&self, header: Header<'b>, error: device::SecurityError) {
         for user in self.users.iter() {
-            user.receive(buf, header, data_offset, data_len);
+            user.receive_security_failure(header, error);
         }
     }
 }
@@ -107,7 +131,7 @@ impl<'a> MuxMac<'a> {
             // If a buffer is returned, the transmission failed,
             // otherwise it succeeded.
             mbuf.map(|buf| {
-                node.send_done(buf, false, result);
+                node.send_done(buf, false, result, radio::TxInfo::default());
             }).unwrap_or_else(|| {
                 self.inflight.set(Some(node));
             });
@@ -205,16 +229,35 @@ impl<'a> MacUser<'a> {
 }
 
 impl<'a> MacUser<'a> {
-    fn send_done(&self, spi_buf: &'static mut [u8], acked: bool, result: ReturnCode) {
+    fn send_done(
+        &self,
+        spi_buf: &'static mut [u8],
+        acked: bool,
+        result: ReturnCode,
+        info: radio::TxInfo,
+    ) {
         self.tx_client
             .get()
-            .map(move |client| client.send_done(spi_buf, acked, result));
+            .map(move |client| client.send_done_info(spi_buf, acked, result, info));
     }
 
-    fn receive<'b>(&self, buf: &'b [u8], header: Header<'b>, data_offset: usize, data_len: usize) {
+    fn receive<'b>(
+        &self,
+        buf: &'b [u8],
+        header: Header<'b>,
+        info: radio::RxInfo,
+        data_offset: usize,
+        data_len: usize,
+    ) {
+        self.rx_client
+            .get()
+            .map(move |client| client.receive(buf, header, info, data_offset, data_len));
+    }
+
+    fn receive_security_failure<'b>(&self, header: Header<'b>, error: device::SecurityError) {
         self.rx_client
             .get()
-            .map(move |client| client.receive(buf, header, data_offset, data_len));
+            .map(move |client| client.receive_security_failure(header, error));
     }
 }
 
@@ -245,6 +288,10 @@ impl<'a> device::MacDevice<'a> for MacUser<'a> {
         self.mux.mac.get_pan()
     }
 
+    fn get_channel(&self) -> u8 {
+        self.mux.mac.get_channel()
+    }
+
     fn set_address(&self, addr: u16) {
         self.mux.mac.set_address(addr)
     }
@@ -257,6 +304,14 @@ impl<'a> device::MacDevice<'a> for MacUser<'a> {
         self.mux.mac.set_pan(id)
     }
 
+    fn set_channel(&self, chan: u8) -> ReturnCode {
+        self.mux.mac.set_channel(chan)
+    }
+
+    fn set_ack_frame_pending(&self, pending: bool) {
+        self.mux.mac.set_ack_frame_pending(pending)
+    }
+
     fn config_commit(&self) {
         self.mux.mac.config_commit()
     }
@@ -279,6 +334,39 @@ impl<'a> device::MacDevice<'a> for MacUser<'a> {
             .prepare_data_frame(buf, dst_pan, dst_addr, src_pan, src_addr, security_needed)
     }
 
+    fn prepare_beacon_frame(
+        &self,
+        buf: &'static mut [u8],
+        src_pan: PanID,
+        src_addr: MacAddress,
+        superframe_spec: SuperframeSpec,
+    ) -> Result<framer::Frame, &'static mut [u8]> {
+        self.mux
+            .mac
+            .prepare_beacon_frame(buf, src_pan, src_addr, superframe_spec)
+    }
+
+    fn prepare_command_frame(
+        &self,
+        buf: &'static mut [u8],
+        dst_pan: PanID,
+        dst_addr: MacAddress,
+        src_pan: PanID,
+        src_addr: Option<MacAddress>,
+        command_id: u8,
+        security_needed: Option<(SecurityLevel, KeyId)>,
+    ) -> Result<framer::Frame, &'static mut [u8]> {
+        self.mux.mac.prepare_command_frame(
+            buf,
+            dst_pan,
+            dst_addr,
+            src_pan,
+            src_addr,
+            command_id,
+            security_needed,
+        )
+    }
+
     fn transmit(&self, frame: framer::Frame) -> (ReturnCode, Option<&'static mut [u8]>) {
         // If the muxer is idle, immediately transmit the frame, otherwise
         // attempt to queue the transmission request. However, each MAC user can
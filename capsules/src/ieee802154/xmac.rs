@@ -11,10 +11,22 @@
 //! packet before returning to sleep. See comments below for implementation
 //! details.
 //!
+//! The interval between two wake-ups of a node adapts to the traffic load
+//! between configurable bounds: it is shortened whenever frames are sent or
+//! received, and lengthened after each wake-up during which nothing was heard.
+//! Wake-ups always occur at multiples of the lower bound from a fixed point in
+//! time, so a node's wake-up phase does not change when its interval does.
+//! Transmitters take advantage of this by remembering when each neighbor last
+//! acknowledged a preamble: subsequent frames to that neighbor are preceded
+//! by short trains of preambles around its predicted wake-ups only, with the
+//! radio sleeping in between, instead of a train as long as the maximum
+//! interval.
+//!
 //! Additional notes:
 //!
 //!   * Since much of a node's time is spent sleeping, transmission latency is
 //!     much higher than using a radio that is always powered on.
+//!   * All nodes in a network must use the same wake-up interval bounds.
 //!   * ReturnCode::ENOACKs may be generated when transmitting, if the
 //!     destination node cannot acknowledge within the maximum retry interval.
 //!   * Since X-MAC relies on proper sleep/wake behavior for all nodes, any
@@ -64,6 +76,11 @@
 //!
 //! xmac.initialize(&mut MAC_BUF);
 //!
+//! // Optionally, change the bounds of the wake-up interval, and export
+//! // duty-cycle statistics to userspace.
+//! xmac.set_wake_interval_bounds(100, 500);
+//! radio_driver.set_duty_cycle(xmac);
+//!
 //! // We can now use the XMac driver to instantiate a MacDevice like a Framer
 //! let mac_device = static_init!(
 //!     capsules::ieee802154::framer::Framer<'static, XMacDevice>,
@@ -76,7 +93,6 @@
 //
 // TODO: Test no-preamble transmission with randomized backoff, requires 3
 //       devices.
-// TODO: Modifying sleep time with traffic load to optimize energy usage.
 // TODO: Remove expectation that radios cancel pending sleeps when receiving a
 //       new packet (see line 652).
 //
@@ -85,9 +101,10 @@
 //
 
 use core::cell::Cell;
-use ieee802154::mac::Mac;
+use core::cmp::{max, min};
+use ieee802154::mac::{DutyCycle, DutyCycleStats, Mac};
 use kernel::ReturnCode;
-use kernel::common::take_cell::TakeCell;
+use kernel::common::take_cell::{MapCell, TakeCell};
 use kernel::hil::radio;
 use kernel::hil::rng::{self, RNG};
 use kernel::hil::time::{self, Alarm, Frequency, Time};
@@ -99,14 +116,22 @@ use net::ieee802154::*;
 // we are very likely to pick up any incoming preambles, and is half as much
 // as the 20 ms lower bound in Buettner et al.
 const WAKE_TIME_MS: u32 = 10;
-// Time the radio will sleep between wakes. Configurable to any desired value
-// less than or equal to the max time the transmitter sends preambles before
-// abandoning the transmission.
-const SLEEP_TIME_MS: u32 = 250;
-// Time the radio will continue to send preamble packets before aborting the
-// transmission and returning ENOACK. Should be at least as large as the maximum
-// sleep time for any node in the network.
-const PREAMBLE_TX_MS: u32 = 251;
+// Default bounds of the interval between two wake-ups of the radio. The
+// interval is always a multiple of the lower bound. The transmitter sends
+// preambles for slightly longer than the upper bound before abandoning the
+// transmission and returning ENOACK.
+pub const DEFAULT_MIN_WAKE_INTERVAL_MS: u32 = 50;
+pub const DEFAULT_MAX_WAKE_INTERVAL_MS: u32 = 250;
+
+// Number of neighbors whose wake-up phase is remembered.
+const MAX_NEIGHBORS: usize = 4;
+// Time after which a learned wake-up phase is considered inaccurate, because
+// of clock drift between nodes.
+const PHASE_LIFETIME_MS: u32 = 30000;
+// Preambles are sent from this long before a neighbor's predicted wake-up
+// until this long after it. The neighbor's wake-up precedes its acknowledgement
+// of a preamble by up to WAKE_TIME_MS.
+const PHASE_GUARD_MS: u32 = WAKE_TIME_MS;
 
 // Maximum backoff for a transmitter attempting to send a data packet, when the
 // node has detected a data packet sent to the same destination from another
@@ -123,13 +148,14 @@ enum XMacState {
     // The primary purpose of these states is to manage the timer that runs the
     // protocol and determines the state of the radio (e.g. if in SLEEP, a fired
     // timer indicates we should transition to AWAKE).
-    AWAKE,       // Awake and listening for incoming preambles
-    DELAY_SLEEP, // Receiving done; waiting for any other incoming data packets
-    SLEEP,       // Asleep and not receiving or transmitting
-    STARTUP,     // Radio waking up, PowerClient::on() transitions to next state
-    TX_PREAMBLE, // Transmitting preambles and waiting for an ACK
-    TX,          // Transmitting data packet to the destination node
-    TX_DELAY,    // Backing off to send data directly without preamble
+    AWAKE,         // Awake and listening for incoming preambles
+    DELAY_SLEEP,   // Receiving done; waiting for any other incoming data packets
+    SLEEP,         // Asleep and not receiving or transmitting
+    STARTUP,       // Radio waking up, PowerClient::on() transitions to next state
+    TX_PREAMBLE,   // Transmitting preambles and waiting for an ACK
+    TX_PHASE_WAIT, // Waiting for the next predicted wake-up of the destination
+    TX,            // Transmitting data packet to the destination node
+    TX_DELAY,      // Backing off to send data directly without preamble
 }
 
 // Information extracted for each packet from the data buffer provided to
@@ -143,6 +169,14 @@ pub struct XMacHeaderInfo {
     pub src_addr: Option<MacAddress>,
 }
 
+// The time at which a neighbor acknowledged a preamble, from which its future
+// wake-ups can be predicted.
+#[derive(Copy, Clone, Eq, PartialEq)]
+struct NeighborPhase {
+    addr: MacAddress,
+    rendezvous: u32,
+}
+
 // The X-MAC `driver` consists primarily of a backend radio driver, an alarm for
 // transitioning between different portions of the protocol, and a source of
 // randomness for transmit backoffs. In addition, we maintain two packet buffers
@@ -168,6 +202,28 @@ pub struct XMac<'a, R: radio::Radio + 'a, A: Alarm + 'a> {
     tx_preamble_buf: TakeCell<'static, [u8]>,
 
     rx_pending: Cell<bool>,
+
+    // Adaptive wake-up schedule: the radio wakes up every `wake_cycles`
+    // multiples of the minimum wake-up interval. `wake_anchor` is the last
+    // scheduled wake-up.
+    min_wake_interval_ms: Cell<u32>,
+    max_wake_interval_ms: Cell<u32>,
+    wake_cycles: Cell<u32>,
+    wake_anchor: Cell<u32>,
+
+    // Learned wake-up phases of neighbors. When transmitting to a neighbor with
+    // a known phase, `tx_window` is its next predicted wake-up.
+    neighbors: MapCell<[Option<NeighborPhase>; MAX_NEIGHBORS]>,
+    tx_window: Cell<Option<u32>>,
+    tx_deadline: Cell<u32>,
+
+    // Duty-cycle statistics
+    radio_awake: Cell<bool>,
+    last_power_change: Cell<u32>,
+    awake_tics: Cell<u64>,
+    asleep_tics: Cell<u64>,
+    preambles_sent: Cell<u32>,
+    rendezvous: Cell<u32>,
 }
 
 impl<'a, R: radio::Radio + 'a, A: Alarm + 'a> XMac<'a, R, A> {
@@ -187,13 +243,208 @@ impl<'a, R: radio::Radio + 'a, A: Alarm + 'a> XMac<'a, R, A> {
             tx_preamble_seq_num: Cell::new(0),
             tx_preamble_buf: TakeCell::empty(),
             rx_pending: Cell::new(false),
+            min_wake_interval_ms: Cell::new(DEFAULT_MIN_WAKE_INTERVAL_MS),
+            max_wake_interval_ms: Cell::new(DEFAULT_MAX_WAKE_INTERVAL_MS),
+            wake_cycles: Cell::new(1),
+            wake_anchor: Cell::new(0),
+            neighbors: MapCell::new(Default::default()),
+            tx_window: Cell::new(None),
+            tx_deadline: Cell::new(0),
+            radio_awake: Cell::new(false),
+            last_power_change: Cell::new(0),
+            awake_tics: Cell::new(0),
+            asleep_tics: Cell::new(0),
+            preambles_sent: Cell::new(0),
+            rendezvous: Cell::new(0),
+        }
+    }
+
+    /// Sets the bounds between which the interval between two wake-ups of the
+    /// radio adapts to the traffic load. The interval is always a multiple of
+    /// `min_ms`, which must be longer than the time the radio stays awake
+    /// (10 ms). All nodes in the network must use the same bounds.
+    pub fn set_wake_interval_bounds(&self, min_ms: u32, max_ms: u32) -> ReturnCode {
+        if min_ms <= WAKE_TIME_MS || max_ms < min_ms {
+            return ReturnCode::EINVAL;
+        }
+        self.min_wake_interval_ms.set(min_ms);
+        self.max_wake_interval_ms.set(max_ms);
+        self.wake_cycles
+            .set(min(self.wake_cycles.get(), max_ms / min_ms));
+        ReturnCode::SUCCESS
+    }
+
+    /// The current interval between two wake-ups of the radio
+    pub fn wake_interval_ms(&self) -> u32 {
+        self.wake_cycles.get() * self.min_wake_interval_ms.get()
+    }
+
+    // Wake up more often when there is traffic, backing off multiplicatively.
+    fn traffic_observed(&self) {
+        self.wake_cycles.set(max(1, self.wake_cycles.get() / 2));
+    }
+
+    // Wake up less often after waking up for nothing, backing off additively.
+    fn idle_wake_up(&self) {
+        let max_cycles = self.max_wake_interval_ms.get() / self.min_wake_interval_ms.get();
+        self.wake_cycles
+            .set(min(self.wake_cycles.get() + 1, max_cycles));
+    }
+
+    // The time of the next wake-up. If the scheduled wake-up was missed (for
+    // example, while transmitting), this is the next multiple of the minimum
+    // wake-up interval, so as to keep the wake-up phase unchanged.
+    fn next_wake_up(&self) -> u32 {
+        let base = self.ms_to_tics(self.min_wake_interval_ms.get());
+        let now = self.alarm.now();
+        let next = self
+            .wake_anchor
+            .get()
+            .wrapping_add(self.wake_cycles.get() * base);
+        let late = now.wrapping_sub(next);
+        if (late as i32) >= 0 {
+            next.wrapping_add((late / base + 1) * base)
+        } else {
+            next
+        }
+    }
+
+    fn ms_to_tics(&self, ms: u32) -> u32 {
+        (ms as u64 * <A::Frequency>::frequency() as u64 / 1000) as u32
+    }
+
+    fn tics_to_ms(&self, tics: u64) -> u32 {
+        (tics * 1000 / <A::Frequency>::frequency() as u64) as u32
+    }
+
+    // Sets the timer to fire at the given time, or as soon as possible if
+    // it has already passed.
+    fn set_timer_at(&self, time: u32) {
+        let now = self.alarm.now();
+        if (time.wrapping_sub(now) as i32) <= 0 {
+            self.alarm.set_alarm(now.wrapping_add(1));
+        } else {
+            self.alarm.set_alarm(time);
         }
     }
 
-    fn sleep_time(&self) -> u32 {
-        // TODO (ongoing) modify based on traffic load to efficiently schedule
-        // sleep. Currently sleeps for a constant amount of time.
-        SLEEP_TIME_MS
+    // Turns the radio on or off, accounting for the time spent in the previous
+    // power state.
+    fn set_radio_power(&self, on: bool) {
+        self.power_changed(on);
+        if on {
+            self.radio.start();
+        } else {
+            self.radio.stop();
+        }
+    }
+
+    fn power_changed(&self, on: bool) {
+        let now = self.alarm.now();
+        let elapsed = now.wrapping_sub(self.last_power_change.get()) as u64;
+        if self.radio_awake.get() {
+            self.awake_tics.set(self.awake_tics.get() + elapsed);
+        } else {
+            self.asleep_tics.set(self.asleep_tics.get() + elapsed);
+        }
+        self.last_power_change.set(now);
+        self.radio_awake.set(on);
+    }
+
+    // The unicast destination of the pending transmission, if any
+    fn tx_unicast_dst(&self) -> Option<MacAddress> {
+        self.tx_header
+            .get()
+            .and_then(|hdr| hdr.dst_addr)
+            .and_then(|addr| match addr {
+                MacAddress::Short(BROADCAST_ADDR) => None,
+                addr => Some(addr),
+            })
+    }
+
+    // The time at which a neighbor last acknowledged a preamble, if recent
+    // enough to predict its wake-ups.
+    fn neighbor_phase(&self, addr: MacAddress) -> Option<u32> {
+        let now = self.alarm.now();
+        let lifetime = self.ms_to_tics(PHASE_LIFETIME_MS);
+        self.neighbors.map_or(None, |neighbors| {
+            neighbors
+                .iter()
+                .filter_map(|neighbor| *neighbor)
+                .find(|neighbor| neighbor.addr == addr)
+                .and_then(|neighbor| {
+                    if now.wrapping_sub(neighbor.rendezvous) < lifetime {
+                        Some(neighbor.rendezvous)
+                    } else {
+                        None
+                    }
+                })
+        })
+    }
+
+    // Remembers the wake-up phase of a neighbor, replacing the oldest entry if
+    // there is no room.
+    fn learn_phase(&self, addr: MacAddress, rendezvous: u32) {
+        let now = self.alarm.now();
+        self.neighbors.map(|neighbors| {
+            let mut slot = 0;
+            let mut oldest = 0;
+            for (i, neighbor) in neighbors.iter().enumerate() {
+                match *neighbor {
+                    Some(neighbor) if neighbor.addr == addr => {
+                        slot = i;
+                        break;
+                    }
+                    Some(neighbor) => {
+                        let age = now.wrapping_sub(neighbor.rendezvous);
+                        if age > oldest {
+                            oldest = age;
+                            slot = i;
+                        }
+                    }
+                    None => {
+                        oldest = u32::max_value();
+                        slot = i;
+                    }
+                }
+            }
+            neighbors[slot] = Some(NeighborPhase {
+                addr: addr,
+                rendezvous: rendezvous,
+            });
+        });
+    }
+
+    fn forget_phase(&self, addr: MacAddress) {
+        self.neighbors.map(|neighbors| {
+            for neighbor in neighbors.iter_mut() {
+                if neighbor.map_or(false, |neighbor| neighbor.addr == addr) {
+                    *neighbor = None;
+                }
+            }
+        });
+    }
+
+    // Starts sending preambles, waking the radio first if necessary. Preambles
+    // are sent until the end of the current window around the destination's
+    // predicted wake-up, or until the deadline if its phase is unknown.
+    fn start_preambles(&self) {
+        if self.radio.is_on() {
+            self.state.set(XMacState::TX_PREAMBLE);
+            self.set_timer_at(self.preamble_train_end());
+            self.transmit_preamble();
+        } else {
+            self.state.set(XMacState::STARTUP);
+            self.tx_preamble_pending.set(true);
+            self.set_radio_power(true);
+        }
+    }
+
+    fn preamble_train_end(&self) -> u32 {
+        match self.tx_window.get() {
+            Some(wake_up) => wake_up.wrapping_add(self.ms_to_tics(PHASE_GUARD_MS)),
+            None => self.tx_deadline.get(),
+        }
     }
 
     fn sleep(&self) {
@@ -206,9 +457,11 @@ impl<'a, R: radio::Radio + 'a, A: Alarm + 'a> XMac<'a, R, A> {
 
             // Otherwise, don't sleep if expecting a data packet or transmitting
             } else if !self.rx_pending.get() {
-                self.radio.stop();
+                self.set_radio_power(false);
                 self.state.set(XMacState::SLEEP);
-                self.set_timer_ms::<A>(self.sleep_time());
+                let wake_up = self.next_wake_up();
+                self.wake_anchor.set(wake_up);
+                self.alarm.set_alarm(wake_up);
             }
         }
     }
@@ -252,12 +505,15 @@ impl<'a, R: radio::Radio + 'a, A: Alarm + 'a> XMac<'a, R, A> {
             };
 
             self.tx_preamble_seq_num
-                .set(self.tx_preamble_seq_num.get() + 1);
+                .set(self.tx_preamble_seq_num.get().wrapping_add(1));
 
             match header.encode(&mut buf[radio::PSDU_OFFSET..], true).done() {
                 // If we can successfully encode the preamble, transmit.
                 Some((data_offset, _)) => {
                     result = self.radio.transmit(buf, data_offset + radio::PSDU_OFFSET);
+                    if result.0 == ReturnCode::SUCCESS {
+                        self.preambles_sent.set(self.preambles_sent.get() + 1);
+                    }
                 }
                 None => {
                     self.tx_preamble_buf.replace(buf);
@@ -288,9 +544,21 @@ impl<'a, R: radio::Radio + 'a, A: Alarm + 'a> XMac<'a, R, A> {
         }
     }
 
+    // The destination acknowledged a preamble, so it is awake: remember when
+    // this happened to predict its next wake-ups, and send it the data.
+    fn rendezvous_done(&self) {
+        self.rendezvous.set(self.rendezvous.get() + 1);
+        let now = self.alarm.now();
+        self.tx_unicast_dst()
+            .map(|addr| self.learn_phase(addr, now));
+        self.state.set(XMacState::TX);
+        self.transmit_packet();
+    }
+
     // Reports back to client that transmission is complete, radio can turn off
     // if not kept awake by other portions of the protocol.
     fn call_tx_client(&self, buf: &'static mut [u8], acked: bool, result: ReturnCode) {
+        self.traffic_observed();
         self.state.set(XMacState::AWAKE);
         self.sleep();
         self.tx_client.get().map(move |c| {
@@ -305,13 +573,15 @@ impl<'a, R: radio::Radio + 'a, A: Alarm + 'a> XMac<'a, R, A> {
         buf: &'static mut [u8],
         len: usize,
         crc_valid: bool,
+        info: radio::RxInfo,
         result: ReturnCode,
     ) {
+        self.traffic_observed();
         self.delay_sleep.set(true);
         self.sleep();
 
         self.rx_client.get().map(move |c| {
-            c.receive(buf, len, crc_valid, result);
+            c.receive(buf, len, crc_valid, info, result);
         });
     }
 }
@@ -342,19 +612,48 @@ impl<'a, R: radio::Radio + 'a, A: Alarm + 'a> rng::Client for XMac<'a, R, A> {
     }
 }
 
+impl<'a, R: radio::Radio + 'a, A: Alarm + 'a> DutyCycle for XMac<'a, R, A> {
+    fn get_duty_cycle_stats(&self) -> DutyCycleStats {
+        let elapsed = self.alarm.now().wrapping_sub(self.last_power_change.get()) as u64;
+        let (awake, asleep) = if self.radio_awake.get() {
+            (self.awake_tics.get() + elapsed, self.asleep_tics.get())
+        } else {
+            (self.awake_tics.get(), self.asleep_tics.get() + elapsed)
+        };
+        DutyCycleStats {
+            awake_ms: self.tics_to_ms(awake),
+            elapsed_ms: self.tics_to_ms(awake + asleep),
+            preambles_sent: self.preambles_sent.get(),
+            rendezvous: self.rendezvous.get(),
+            wake_interval_ms: self.wake_interval_ms(),
+        }
+    }
+
+    fn reset_duty_cycle_stats(&self) {
+        self.last_power_change.set(self.alarm.now());
+        self.awake_tics.set(0);
+        self.asleep_tics.set(0);
+        self.preambles_sent.set(0);
+        self.rendezvous.set(0);
+    }
+}
+
 // The vast majority of these calls pass through to the underlying radio driver.
 impl<'a, R: radio::Radio + 'a, A: Alarm> Mac for XMac<'a, R, A> {
     fn initialize(&self, mac_buf: &'static mut [u8]) -> ReturnCode {
         self.tx_preamble_buf.replace(mac_buf);
         self.state.set(XMacState::STARTUP);
+        self.wake_anchor.set(self.alarm.now());
+        self.reset_duty_cycle_stats();
         ReturnCode::SUCCESS
     }
 
     // Always lie and say the radio is on when sleeping, as XMAC will wake up
     // itself to send preambles if necessary.
     fn is_on(&self) -> bool {
-        if self.state.get() == XMacState::SLEEP {
-            return true;
+        match self.state.get() {
+            XMacState::SLEEP | XMacState::TX_PHASE_WAIT => return true,
+            _ => {}
         }
         self.radio.is_on()
     }
@@ -375,6 +674,14 @@ impl<'a, R: radio::Radio + 'a, A: Alarm> Mac for XMac<'a, R, A> {
         self.radio.set_pan(id)
     }
 
+    fn set_channel(&self, chan: u8) -> ReturnCode {
+        self.radio.set_channel(chan)
+    }
+
+    fn set_ack_frame_pending(&self, pending: bool) {
+        self.radio.set_ack_frame_pending(pending)
+    }
+
     fn get_address(&self) -> u16 {
         self.radio.get_address()
     }
@@ -387,6 +694,10 @@ impl<'a, R: radio::Radio + 'a, A: Alarm> Mac for XMac<'a, R, A> {
         self.radio.get_pan()
     }
 
+    fn get_channel(&self) -> u8 {
+        self.radio.get_channel()
+    }
+
     fn config_commit(&self) {
         self.radio.config_commit()
     }
@@ -444,18 +755,34 @@ impl<'a, R: radio::Radio + 'a, A: Alarm> Mac for XMac<'a, R, A> {
 
         self.tx_preamble_seq_num.set(0);
 
-        // If the radio is on, start the preamble timer and start transmitting
-        if self.radio.is_on() {
-            self.state.set(XMacState::TX_PREAMBLE);
-            self.set_timer_ms::<A>(PREAMBLE_TX_MS);
-            self.transmit_preamble();
-
-        // If the radio is currently sleeping, wake it and indicate that when
-        // ready, it should begin transmitting preambles
-        } else {
-            self.state.set(XMacState::STARTUP);
-            self.tx_preamble_pending.set(true);
-            self.radio.start();
+        let now = self.alarm.now();
+        let max_interval = self.ms_to_tics(self.max_wake_interval_ms.get());
+        match self
+            .tx_unicast_dst()
+            .and_then(|addr| self.neighbor_phase(addr))
+        {
+            // If we know when the destination wakes up, wait for its next
+            // predicted wake-up, which is a multiple of the minimum wake-up
+            // interval after the last one we observed. Try again at each
+            // multiple until one full maximum interval has elapsed.
+            Some(rendezvous) => {
+                let base = self.ms_to_tics(self.min_wake_interval_ms.get());
+                let guard = self.ms_to_tics(PHASE_GUARD_MS);
+                let since = now.wrapping_add(guard).wrapping_sub(rendezvous);
+                let wake_up = rendezvous.wrapping_add((since / base + 1) * base);
+                self.tx_window.set(Some(wake_up));
+                self.tx_deadline.set(wake_up.wrapping_add(max_interval));
+                self.state.set(XMacState::TX_PHASE_WAIT);
+                self.set_timer_at(wake_up.wrapping_sub(guard));
+            }
+            // Otherwise, send preambles for longer than the destination can
+            // possibly sleep.
+            None => {
+                self.tx_window.set(None);
+                self.tx_deadline
+                    .set(now.wrapping_add(max_interval + self.ms_to_tics(1)));
+                self.start_preambles();
+            }
         }
 
         (ReturnCode::SUCCESS, None)
@@ -472,7 +799,7 @@ impl<'a, R: radio::Radio + 'a, A: Alarm + 'a> time::Client for XMac<'a, R, A> {
                 // indicate that the radio is ready
                 if !self.radio.is_on() {
                     self.state.set(XMacState::STARTUP);
-                    self.radio.start();
+                    self.set_radio_power(true);
                 } else {
                     self.set_timer_ms::<A>(WAKE_TIME_MS);
                     self.state.set(XMacState::AWAKE);
@@ -481,6 +808,9 @@ impl<'a, R: radio::Radio + 'a, A: Alarm + 'a> time::Client for XMac<'a, R, A> {
             // If we've been delaying sleep or haven't heard any incoming
             // preambles, turn the radio off.
             XMacState::AWAKE => {
+                if !self.delay_sleep.get() && !self.rx_pending.get() {
+                    self.idle_wake_up();
+                }
                 self.sleep();
             }
             XMacState::DELAY_SLEEP => {
@@ -490,9 +820,41 @@ impl<'a, R: radio::Radio + 'a, A: Alarm + 'a> time::Client for XMac<'a, R, A> {
             }
             // If we've sent preambles for longer than the maximum sleep time of
             // any node in the network, then our destination is non-responsive;
-            // return ENOACK to the client.
+            // return ENOACK to the client. If we were sending preambles around
+            // a predicted wake-up, sleep until the next one instead, unless it
+            // is past the deadline.
             XMacState::TX_PREAMBLE => {
-                self.call_tx_client(self.tx_payload.take().unwrap(), false, ReturnCode::ENOACK);
+                let base = self.ms_to_tics(self.min_wake_interval_ms.get());
+                let next_window = self
+                    .tx_window
+                    .get()
+                    .map(|wake_up| wake_up.wrapping_add(base));
+                match next_window {
+                    Some(wake_up) if (self.tx_deadline.get().wrapping_sub(wake_up) as i32) > 0 => {
+                        self.tx_window.set(Some(wake_up));
+                        self.state.set(XMacState::TX_PHASE_WAIT);
+                        // If a preamble is still being sent, the radio is
+                        // turned off once it completes.
+                        if self.tx_preamble_buf.is_some() {
+                            self.set_radio_power(false);
+                        }
+                        self.set_timer_at(wake_up.wrapping_sub(self.ms_to_tics(PHASE_GUARD_MS)));
+                    }
+                    _ => {
+                        // The destination's wake-up phase may have changed.
+                        if next_window.is_some() {
+                            self.tx_unicast_dst().map(|addr| self.forget_phase(addr));
+                        }
+                        self.call_tx_client(
+                            self.tx_payload.take().unwrap(),
+                            false,
+                            ReturnCode::ENOACK,
+                        );
+                    }
+                }
+            }
+            XMacState::TX_PHASE_WAIT => {
+                self.start_preambles();
             }
             // After a randomized backoff period, transmit the data directly.
             XMacState::TX_DELAY => {
@@ -510,11 +872,14 @@ impl<'a, R: radio::Radio + 'a, A: Alarm + 'a> radio::PowerClient for XMac<'a, R,
         // listening for incoming preambles or start transmitting preambles if
         // the radio was turned on for a transmission.
         if on {
+            if !self.radio_awake.get() {
+                self.power_changed(true);
+            }
             if let XMacState::STARTUP = self.state.get() {
                 if self.tx_preamble_pending.get() {
                     self.tx_preamble_pending.set(false);
                     self.state.set(XMacState::TX_PREAMBLE);
-                    self.set_timer_ms::<A>(PREAMBLE_TX_MS);
+                    self.set_timer_at(self.preamble_train_end());
                     self.transmit_preamble();
                 } else {
                     self.state.set(XMacState::AWAKE);
@@ -537,13 +902,22 @@ impl<'a, R: radio::Radio + 'a, A: Alarm + 'a> radio::TxClient for XMac<'a, R, A>
                 self.tx_preamble_buf.replace(buf);
                 if acked {
                     // Destination signals ready to receive data
-                    self.state.set(XMacState::TX);
-                    self.transmit_packet();
+                    self.rendezvous_done();
                 } else {
                     // Continue resending preambles
                     self.transmit_preamble();
                 }
             }
+            // A preamble sent at the end of a window around the destination's
+            // predicted wake-up completed.
+            XMacState::TX_PHASE_WAIT => {
+                self.tx_preamble_buf.replace(buf);
+                if acked {
+                    self.rendezvous_done();
+                } else {
+                    self.set_radio_power(false);
+                }
+            }
             XMacState::TX_DELAY | XMacState::SLEEP => {
                 // If, while sending preambles, we switch to TX_DELAY mode, the
                 // last preamble sent will complete afterwards. If no ACK, the
@@ -565,6 +939,7 @@ impl<'a, R: radio::Radio + 'a, A: Alarm + 'a> radio::RxClient for XMac<'a, R, A>
         buf: &'static mut [u8],
         frame_len: usize,
         crc_valid: bool,
+        info: radio::RxInfo,
         result: ReturnCode,
     ) {
         let mut data_received: bool = false;
@@ -576,7 +951,9 @@ impl<'a, R: radio::Radio + 'a, A: Alarm + 'a> radio::RxClient for XMac<'a, R, A>
         if let Some((_, (header, _))) = Header::decode(&buf[radio::PSDU_OFFSET..], false).done() {
             if let Some(dst_addr) = header.dst_addr {
                 let addr_match = match dst_addr {
-                    MacAddress::Short(addr) => addr == self.radio.get_address(),
+                    MacAddress::Short(addr) => {
+                        addr == self.radio.get_address() || addr == BROADCAST_ADDR
+                    }
                     MacAddress::Long(long_addr) => long_addr == self.radio.get_address_long(),
                 };
                 // The destination doesn't match our address, check to see if we
@@ -604,13 +981,17 @@ impl<'a, R: radio::Radio + 'a, A: Alarm + 'a> radio::RxClient for XMac<'a, R, A>
                             continue_sleep = false;
                             self.rx_pending.set(true);
                         }
-                        FrameType::Data => {
+                        FrameType::Data | FrameType::MACCommand => {
                             continue_sleep = false;
                             data_received = true;
                         }
                         _ => {}
                     }
                 }
+            } else if header.frame_type == FrameType::Beacon {
+                // Beacons carry no destination address
+                continue_sleep = false;
+                data_received = true;
             }
         }
 
@@ -625,7 +1006,7 @@ impl<'a, R: radio::Radio + 'a, A: Alarm + 'a> radio::RxClient for XMac<'a, R, A>
 
         if data_received {
             self.rx_pending.set(false);
-            self.call_rx_client(buf, frame_len, crc_valid, result);
+            self.call_rx_client(buf, frame_len, crc_valid, info, result);
         } else {
             self.radio.set_receive_buffer(buf);
         }
@@ -34,6 +34,7 @@ pub mod fxos8700cq;
 pub mod crc;
 pub mod rf233;
 pub mod rf233_const;
+pub mod sim_radio;
 pub mod rng;
 pub mod ninedof;
 pub mod ltc294x;
@@ -45,6 +46,10 @@ pub mod nonvolatile_to_pages;
 pub mod nonvolatile_storage_driver;
 pub mod app_flash_driver;
 pub mod usb;
+pub mod usb_cdc;
+pub mod usb_dfu;
+pub mod usb_hid;
+pub mod usb_msc;
 pub mod usb_user;
 pub mod usbc_client;
 #[macro_use]
@@ -53,6 +58,11 @@ pub mod ieee802154;
 pub mod temperature;
 pub mod humidity;
 pub mod aes_ccm;
+pub mod ble_advertising_data;
+pub mod ble_advertising_driver;
+pub mod ble_hci;
+pub mod ble_link_layer;
+pub mod ble_link_layer_hil;
 //pub mod nrf_internal_temp_sensor;
 
 pub mod playground;
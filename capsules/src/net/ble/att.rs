@@ -0,0 +1,587 @@
+//! Implements ATT (Attribute Protocol) PDU encoding and decoding.
+//!
+//! BLUETOOTH SPECIFICATION Version 4.2 [Vol 3, Part F], section 3.3 Attribute PDU
+//!
+//! ```
+//!   PDU     +----------+      +----------------------+      +-------------+
+//!           | Opcode   |  -   | Attribute Parameters |  -   | Signature   |
+//!           | (1 byte) |      | (0 to ATT_MTU - 1)   |      | (not used)  |
+//!           +----------+      +----------------------+      +-------------+
+//! ```
+//!
+//! All multi-byte fields are little-endian. Variable-length parameters, such
+//! as attribute values and the lists in the responses to Find Information,
+//! Read By Type and Read By Group Type requests, extend to the end of the PDU
+//! and are borrowed from the decoded buffer.
+//!
+//! ATT PDUs are carried on the fixed L2CAP channel `ATT_CID` of a connection,
+//! which is abstracted by the `AttBearer` trait so that the protocol can run
+//! on top of any link layer (or on the host, with recorded PDUs).
+
+use kernel::ReturnCode;
+use net::stream::{decode_u16_le, decode_u8, encode_bytes, encode_u16_le, encode_u8};
+use net::stream::SResult;
+
+/// L2CAP channel ID of the attribute protocol
+pub const ATT_CID: u16 = 0x0004;
+
+/// ATT_MTU of a BLE connection until it is changed with an Exchange MTU
+/// request
+pub const DEFAULT_MTU: u16 = 23;
+
+/// BLUETOOTH SPECIFICATION Version 4.2 [Vol 3, Part F], section 3.4.8
+/// Attribute Opcode Summary
+pub mod opcode {
+    pub const ERROR_RSP: u8 = 0x01;
+    pub const EXCHANGE_MTU_REQ: u8 = 0x02;
+    pub const EXCHANGE_MTU_RSP: u8 = 0x03;
+    pub const FIND_INFORMATION_REQ: u8 = 0x04;
+    pub const FIND_INFORMATION_RSP: u8 = 0x05;
+    pub const FIND_BY_TYPE_VALUE_REQ: u8 = 0x06;
+    pub const FIND_BY_TYPE_VALUE_RSP: u8 = 0x07;
+    pub const READ_BY_TYPE_REQ: u8 = 0x08;
+    pub const READ_BY_TYPE_RSP: u8 = 0x09;
+    pub const READ_REQ: u8 = 0x0a;
+    pub const READ_RSP: u8 = 0x0b;
+    pub const READ_BLOB_REQ: u8 = 0x0c;
+    pub const READ_BLOB_RSP: u8 = 0x0d;
+    pub const READ_MULTIPLE_REQ: u8 = 0x0e;
+    pub const READ_MULTIPLE_RSP: u8 = 0x0f;
+    pub const READ_BY_GROUP_TYPE_REQ: u8 = 0x10;
+    pub const READ_BY_GROUP_TYPE_RSP: u8 = 0x11;
+    pub const WRITE_REQ: u8 = 0x12;
+    pub const WRITE_RSP: u8 = 0x13;
+    pub const WRITE_CMD: u8 = 0x52;
+    pub const PREPARE_WRITE_REQ: u8 = 0x16;
+    pub const PREPARE_WRITE_RSP: u8 = 0x17;
+    pub const EXECUTE_WRITE_REQ: u8 = 0x18;
+    pub const EXECUTE_WRITE_RSP: u8 = 0x19;
+    pub const HANDLE_VALUE_NTF: u8 = 0x1b;
+    pub const HANDLE_VALUE_IND: u8 = 0x1d;
+    pub const HANDLE_VALUE_CFM: u8 = 0x1e;
+    pub const SIGNED_WRITE_CMD: u8 = 0xd2;
+
+    /// Commands are never answered, not even with an Error Response
+    pub fn is_command(opcode: u8) -> bool {
+        opcode & 0x40 != 0
+    }
+}
+
+/// BLUETOOTH SPECIFICATION Version 4.2 [Vol 3, Part F], section 3.4.1.1
+/// Error Response
+pub mod error {
+    pub const INVALID_HANDLE: u8 = 0x01;
+    pub const READ_NOT_PERMITTED: u8 = 0x02;
+    pub const WRITE_NOT_PERMITTED: u8 = 0x03;
+    pub const INVALID_PDU: u8 = 0x04;
+    pub const INSUFFICIENT_AUTHENTICATION: u8 = 0x05;
+    pub const REQUEST_NOT_SUPPORTED: u8 = 0x06;
+    pub const INVALID_OFFSET: u8 = 0x07;
+    pub const INSUFFICIENT_AUTHORIZATION: u8 = 0x08;
+    pub const PREPARE_QUEUE_FULL: u8 = 0x09;
+    pub const ATTRIBUTE_NOT_FOUND: u8 = 0x0a;
+    pub const ATTRIBUTE_NOT_LONG: u8 = 0x0b;
+    pub const INSUFFICIENT_ENCRYPTION_KEY_SIZE: u8 = 0x0c;
+    pub const INVALID_ATTRIBUTE_VALUE_LENGTH: u8 = 0x0d;
+    pub const UNLIKELY_ERROR: u8 = 0x0e;
+    pub const INSUFFICIENT_ENCRYPTION: u8 = 0x0f;
+    pub const UNSUPPORTED_GROUP_TYPE: u8 = 0x10;
+    pub const INSUFFICIENT_RESOURCES: u8 = 0x11;
+}
+
+/// Format of the entries of a Find Information Response
+pub mod format {
+    /// Handles with 16-bit UUIDs
+    pub const UUID_16: u8 = 0x01;
+    /// Handles with 128-bit UUIDs
+    pub const UUID_128: u8 = 0x02;
+}
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 3, Part B], section 2.5.1 UUID
+// 00000000-0000-1000-8000-00805F9B34FB, least significant byte first
+const BLUETOOTH_BASE_UUID: [u8; 16] = [
+    0xfb, 0x34, 0x9b, 0x5f, 0x80, 0x00, 0x00, 0x80, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// An attribute type. 16-bit UUIDs are shorthands for 128-bit UUIDs derived
+/// from the Bluetooth Base UUID, and the two forms of such a UUID are
+/// considered equal.
+#[derive(Copy, Clone, Debug)]
+pub enum Uuid {
+    Uuid16(u16),
+    /// Least significant byte first, as sent over the air
+    Uuid128([u8; 16]),
+}
+
+impl Uuid {
+    /// Returns the 128-bit form of the UUID
+    pub fn to_uuid128(&self) -> [u8; 16] {
+        match *self {
+            Uuid::Uuid16(uuid) => {
+                let mut bytes = BLUETOOTH_BASE_UUID;
+                bytes[12] = uuid as u8;
+                bytes[13] = (uuid >> 8) as u8;
+                bytes
+            }
+            Uuid::Uuid128(bytes) => bytes,
+        }
+    }
+
+    /// Returns the shortest form of the UUID, i.e. a `Uuid16` if it is
+    /// derived from the Bluetooth Base UUID
+    pub fn shortened(&self) -> Uuid {
+        match *self {
+            Uuid::Uuid128(bytes) => {
+                if bytes[..12] == BLUETOOTH_BASE_UUID[..12] && bytes[14..] == [0, 0] {
+                    Uuid::Uuid16((bytes[13] as u16) << 8 | (bytes[12] as u16))
+                } else {
+                    *self
+                }
+            }
+            Uuid::Uuid16(_) => *self,
+        }
+    }
+
+    /// Number of bytes in the shortest encoding of the UUID
+    pub fn len(&self) -> usize {
+        match self.shortened() {
+            Uuid::Uuid16(_) => 2,
+            Uuid::Uuid128(_) => 16,
+        }
+    }
+
+    /// Encodes the shortest form of the UUID
+    pub fn encode(&self, buf: &mut [u8]) -> SResult {
+        match self.shortened() {
+            Uuid::Uuid16(uuid) => encode_u16_le(buf, uuid),
+            Uuid::Uuid128(bytes) => encode_bytes(buf, &bytes),
+        }
+    }
+
+    /// Decodes a UUID that takes up the whole buffer, which must be 2 or 16
+    /// bytes long
+    pub fn decode(buf: &[u8]) -> SResult<Uuid> {
+        match buf.len() {
+            2 => {
+                let (off, uuid) = dec_try!(buf; decode_u16_le);
+                stream_done!(off, Uuid::Uuid16(uuid));
+            }
+            16 => {
+                let mut bytes = [0; 16];
+                bytes.copy_from_slice(buf);
+                stream_done!(16, Uuid::Uuid128(bytes));
+            }
+            _ => stream_err!(),
+        }
+    }
+}
+
+impl PartialEq for Uuid {
+    fn eq(&self, other: &Uuid) -> bool {
+        self.to_uuid128() == other.to_uuid128()
+    }
+}
+
+impl Eq for Uuid {}
+
+/// An ATT PDU, borrowing its variable-length parameters from a buffer
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum AttPdu<'a> {
+    ErrorRsp {
+        request: u8,
+        handle: u16,
+        error: u8,
+    },
+    ExchangeMtuReq {
+        mtu: u16,
+    },
+    ExchangeMtuRsp {
+        mtu: u16,
+    },
+    FindInformationReq {
+        start: u16,
+        end: u16,
+    },
+    /// `data` is a list of handle and UUID pairs, with 16-bit or 128-bit
+    /// UUIDs depending on `format`
+    FindInformationRsp {
+        format: u8,
+        data: &'a [u8],
+    },
+    FindByTypeValueReq {
+        start: u16,
+        end: u16,
+        attribute_type: u16,
+        value: &'a [u8],
+    },
+    /// `data` is a list of found handle and group end handle pairs
+    FindByTypeValueRsp {
+        data: &'a [u8],
+    },
+    ReadByTypeReq {
+        start: u16,
+        end: u16,
+        attribute_type: Uuid,
+    },
+    /// `data` is a list of handle and value pairs of `length` bytes each
+    ReadByTypeRsp {
+        length: u8,
+        data: &'a [u8],
+    },
+    ReadReq {
+        handle: u16,
+    },
+    ReadRsp {
+        value: &'a [u8],
+    },
+    ReadBlobReq {
+        handle: u16,
+        offset: u16,
+    },
+    ReadBlobRsp {
+        value: &'a [u8],
+    },
+    ReadByGroupTypeReq {
+        start: u16,
+        end: u16,
+        group_type: Uuid,
+    },
+    /// `data` is a list of handle, group end handle and value triples of
+    /// `length` bytes each
+    ReadByGroupTypeRsp {
+        length: u8,
+        data: &'a [u8],
+    },
+    WriteReq {
+        handle: u16,
+        value: &'a [u8],
+    },
+    WriteRsp,
+    WriteCmd {
+        handle: u16,
+        value: &'a [u8],
+    },
+    HandleValueNtf {
+        handle: u16,
+        value: &'a [u8],
+    },
+    HandleValueInd {
+        handle: u16,
+        value: &'a [u8],
+    },
+    HandleValueCfm,
+    /// Any PDU this module does not decode (such as prepared and signed
+    /// writes), which a server answers with `REQUEST_NOT_SUPPORTED` unless it
+    /// is a command
+    Unsupported {
+        opcode: u8,
+    },
+}
+
+impl<'a> AttPdu<'a> {
+    pub fn opcode(&self) -> u8 {
+        match *self {
+            AttPdu::ErrorRsp { .. } => opcode::ERROR_RSP,
+            AttPdu::ExchangeMtuReq { .. } => opcode::EXCHANGE_MTU_REQ,
+            AttPdu::ExchangeMtuRsp { .. } => opcode::EXCHANGE_MTU_RSP,
+            AttPdu::FindInformationReq { .. } => opcode::FIND_INFORMATION_REQ,
+            AttPdu::FindInformationRsp { .. } => opcode::FIND_INFORMATION_RSP,
+            AttPdu::FindByTypeValueReq { .. } => opcode::FIND_BY_TYPE_VALUE_REQ,
+            AttPdu::FindByTypeValueRsp { .. } => opcode::FIND_BY_TYPE_VALUE_RSP,
+            AttPdu::ReadByTypeReq { .. } => opcode::READ_BY_TYPE_REQ,
+            AttPdu::ReadByTypeRsp { .. } => opcode::READ_BY_TYPE_RSP,
+            AttPdu::ReadReq { .. } => opcode::READ_REQ,
+            AttPdu::ReadRsp { .. } => opcode::READ_RSP,
+            AttPdu::ReadBlobReq { .. } => opcode::READ_BLOB_REQ,
+            AttPdu::ReadBlobRsp { .. } => opcode::READ_BLOB_RSP,
+            AttPdu::ReadByGroupTypeReq { .. } => opcode::READ_BY_GROUP_TYPE_REQ,
+            AttPdu::ReadByGroupTypeRsp { .. } => opcode::READ_BY_GROUP_TYPE_RSP,
+            AttPdu::WriteReq { .. } => opcode::WRITE_REQ,
+            AttPdu::WriteRsp => opcode::WRITE_RSP,
+            AttPdu::WriteCmd { .. } => opcode::WRITE_CMD,
+            AttPdu::HandleValueNtf { .. } => opcode::HANDLE_VALUE_NTF,
+            AttPdu::HandleValueInd { .. } => opcode::HANDLE_VALUE_IND,
+            AttPdu::HandleValueCfm => opcode::HANDLE_VALUE_CFM,
+            AttPdu::Unsupported { opcode } => opcode,
+        }
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> SResult {
+        let mut off = enc_consume!(buf; encode_u8, self.opcode());
+        match *self {
+            AttPdu::ErrorRsp {
+                request,
+                handle,
+                error,
+            } => {
+                off = enc_consume!(buf, off; encode_u8, request);
+                off = enc_consume!(buf, off; encode_u16_le, handle);
+                off = enc_consume!(buf, off; encode_u8, error);
+            }
+            AttPdu::ExchangeMtuReq { mtu } | AttPdu::ExchangeMtuRsp { mtu } => {
+                off = enc_consume!(buf, off; encode_u16_le, mtu);
+            }
+            AttPdu::FindInformationReq { start, end } => {
+                off = enc_consume!(buf, off; encode_u16_le, start);
+                off = enc_consume!(buf, off; encode_u16_le, end);
+            }
+            AttPdu::FindInformationRsp { format, data } => {
+                off = enc_consume!(buf, off; encode_u8, format);
+                off = enc_consume!(buf, off; encode_bytes, data);
+            }
+            AttPdu::FindByTypeValueReq {
+                start,
+                end,
+                attribute_type,
+                value,
+            } => {
+                off = enc_consume!(buf, off; encode_u16_le, start);
+                off = enc_consume!(buf, off; encode_u16_le, end);
+                off = enc_consume!(buf, off; encode_u16_le, attribute_type);
+                off = enc_consume!(buf, off; encode_bytes, value);
+            }
+            AttPdu::FindByTypeValueRsp { data } => {
+                off = enc_consume!(buf, off; encode_bytes, data);
+            }
+            AttPdu::ReadByTypeReq {
+                start,
+                end,
+                attribute_type: uuid,
+            }
+            | AttPdu::ReadByGroupTypeReq {
+                start,
+                end,
+                group_type: uuid,
+            } => {
+                off = enc_consume!(buf, off; encode_u16_le, start);
+                off = enc_consume!(buf, off; encode_u16_le, end);
+                off = enc_consume!(buf, off; uuid; encode);
+            }
+            AttPdu::ReadByTypeRsp { length, data }
+            | AttPdu::ReadByGroupTypeRsp { length, data } => {
+                off = enc_consume!(buf, off; encode_u8, length);
+                off = enc_consume!(buf, off; encode_bytes, data);
+            }
+            AttPdu::ReadReq { handle } => {
+                off = enc_consume!(buf, off; encode_u16_le, handle);
+            }
+            AttPdu::ReadRsp { value } | AttPdu::ReadBlobRsp { value } => {
+                off = enc_consume!(buf, off; encode_bytes, value);
+            }
+            AttPdu::ReadBlobReq { handle, offset } => {
+                off = enc_consume!(buf, off; encode_u16_le, handle);
+                off = enc_consume!(buf, off; encode_u16_le, offset);
+            }
+            AttPdu::WriteReq { handle, value }
+            | AttPdu::WriteCmd { handle, value }
+            | AttPdu::HandleValueNtf { handle, value }
+            | AttPdu::HandleValueInd { handle, value } => {
+                off = enc_consume!(buf, off; encode_u16_le, handle);
+                off = enc_consume!(buf, off; encode_bytes, value);
+            }
+            AttPdu::WriteRsp | AttPdu::HandleValueCfm | AttPdu::Unsupported { .. } => {}
+        }
+        stream_done!(off);
+    }
+
+    /// Decodes the PDU that takes up the whole buffer. Returns an error if the
+    /// parameters are malformed, e.g. a UUID that is neither 2 nor 16 bytes
+    /// long or a Find Information Response with an unknown format.
+    pub fn decode(buf: &'a [u8]) -> SResult<AttPdu<'a>> {
+        let (off, op) = dec_try!(buf; decode_u8);
+        match op {
+            opcode::ERROR_RSP => {
+                let (off, request) = dec_try!(buf, off; decode_u8);
+                let (off, handle) = dec_try!(buf, off; decode_u16_le);
+                let (off, error) = dec_try!(buf, off; decode_u8);
+                stream_done!(
+                    off,
+                    AttPdu::ErrorRsp {
+                        request: request,
+                        handle: handle,
+                        error: error,
+                    }
+                );
+            }
+            opcode::EXCHANGE_MTU_REQ | opcode::EXCHANGE_MTU_RSP => {
+                let (off, mtu) = dec_try!(buf, off; decode_u16_le);
+                if op == opcode::EXCHANGE_MTU_REQ {
+                    stream_done!(off, AttPdu::ExchangeMtuReq { mtu: mtu });
+                } else {
+                    stream_done!(off, AttPdu::ExchangeMtuRsp { mtu: mtu });
+                }
+            }
+            opcode::FIND_INFORMATION_REQ => {
+                let (off, start) = dec_try!(buf, off; decode_u16_le);
+                let (off, end) = dec_try!(buf, off; decode_u16_le);
+                stream_done!(
+                    off,
+                    AttPdu::FindInformationReq {
+                        start: start,
+                        end: end,
+                    }
+                );
+            }
+            opcode::FIND_INFORMATION_RSP => {
+                let (off, fmt) = dec_try!(buf, off; decode_u8);
+                let entry_len = match fmt {
+                    format::UUID_16 => 4,
+                    format::UUID_128 => 18,
+                    _ => stream_err!(),
+                };
+                stream_cond!((buf.len() - off) % entry_len == 0);
+                stream_done!(
+                    buf.len(),
+                    AttPdu::FindInformationRsp {
+                        format: fmt,
+                        data: &buf[off..],
+                    }
+                );
+            }
+            opcode::FIND_BY_TYPE_VALUE_REQ => {
+                let (off, start) = dec_try!(buf, off; decode_u16_le);
+                let (off, end) = dec_try!(buf, off; decode_u16_le);
+                let (off, attribute_type) = dec_try!(buf, off; decode_u16_le);
+                stream_done!(
+                    buf.len(),
+                    AttPdu::FindByTypeValueReq {
+                        start: start,
+                        end: end,
+                        attribute_type: attribute_type,
+                        value: &buf[off..],
+                    }
+                );
+            }
+            opcode::FIND_BY_TYPE_VALUE_RSP => {
+                stream_cond!((buf.len() - off) % 4 == 0);
+                stream_done!(buf.len(), AttPdu::FindByTypeValueRsp { data: &buf[off..] });
+            }
+            opcode::READ_BY_TYPE_REQ | opcode::READ_BY_GROUP_TYPE_REQ => {
+                let (off, start) = dec_try!(buf, off; decode_u16_le);
+                let (off, end) = dec_try!(buf, off; decode_u16_le);
+                let (off, uuid) = dec_try!(buf, off; Uuid::decode);
+                if op == opcode::READ_BY_TYPE_REQ {
+                    stream_done!(
+                        off,
+                        AttPdu::ReadByTypeReq {
+                            start: start,
+                            end: end,
+                            attribute_type: uuid,
+                        }
+                    );
+                } else {
+                    stream_done!(
+                        off,
+                        AttPdu::ReadByGroupTypeReq {
+                            start: start,
+                            end: end,
+                            group_type: uuid,
+                        }
+                    );
+                }
+            }
+            opcode::READ_BY_TYPE_RSP | opcode::READ_BY_GROUP_TYPE_RSP => {
+                let (off, length) = dec_try!(buf, off; decode_u8);
+                stream_cond!(length > 0 && (buf.len() - off) % length as usize == 0);
+                let data = &buf[off..];
+                if op == opcode::READ_BY_TYPE_RSP {
+                    stream_done!(
+                        buf.len(),
+                        AttPdu::ReadByTypeRsp {
+                            length: length,
+                            data: data,
+                        }
+                    );
+                } else {
+                    stream_done!(
+                        buf.len(),
+                        AttPdu::ReadByGroupTypeRsp {
+                            length: length,
+                            data: data,
+                        }
+                    );
+                }
+            }
+            opcode::READ_REQ => {
+                let (off, handle) = dec_try!(buf, off; decode_u16_le);
+                stream_done!(off, AttPdu::ReadReq { handle: handle });
+            }
+            opcode::READ_RSP => {
+                stream_done!(buf.len(), AttPdu::ReadRsp { value: &buf[off..] });
+            }
+            opcode::READ_BLOB_REQ => {
+                let (off, handle) = dec_try!(buf, off; decode_u16_le);
+                let (off, offset) = dec_try!(buf, off; decode_u16_le);
+                stream_done!(
+                    off,
+                    AttPdu::ReadBlobReq {
+                        handle: handle,
+                        offset: offset,
+                    }
+                );
+            }
+            opcode::READ_BLOB_RSP => {
+                stream_done!(buf.len(), AttPdu::ReadBlobRsp { value: &buf[off..] });
+            }
+            opcode::WRITE_REQ
+            | opcode::WRITE_CMD
+            | opcode::HANDLE_VALUE_NTF
+            | opcode::HANDLE_VALUE_IND => {
+                let (off, handle) = dec_try!(buf, off; decode_u16_le);
+                let value = &buf[off..];
+                let pdu = match op {
+                    opcode::WRITE_REQ => AttPdu::WriteReq {
+                        handle: handle,
+                        value: value,
+                    },
+                    opcode::WRITE_CMD => AttPdu::WriteCmd {
+                        handle: handle,
+                        value: value,
+                    },
+                    opcode::HANDLE_VALUE_NTF => AttPdu::HandleValueNtf {
+                        handle: handle,
+                        value: value,
+                    },
+                    _ => AttPdu::HandleValueInd {
+                        handle: handle,
+                        value: value,
+                    },
+                };
+                stream_done!(buf.len(), pdu);
+            }
+            opcode::WRITE_RSP => stream_done!(off, AttPdu::WriteRsp),
+            opcode::HANDLE_VALUE_CFM => stream_done!(off, AttPdu::HandleValueCfm),
+            _ => stream_done!(buf.len(), AttPdu::Unsupported { opcode: op }),
+        }
+    }
+}
+
+/// The channel ATT PDUs are exchanged on, usually the fixed L2CAP channel
+/// `ATT_CID` of a BLE connection. Only one PDU is passed to the bearer at a
+/// time.
+pub trait AttBearer<'a> {
+    fn set_client(&self, client: &'a AttBearerClient);
+
+    /// Sends the first `len` bytes of `buf` as one ATT PDU. The buffer is
+    /// returned in `AttBearerClient::send_done`, or immediately on error.
+    fn send(
+        &self,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])>;
+}
+
+pub trait AttBearerClient {
+    /// Called when a connection is established. The ATT_MTU is
+    /// `DEFAULT_MTU` until it is exchanged again.
+    fn connected(&self);
+
+    /// Called for each ATT PDU received from the peer
+    fn receive(&self, pdu: &[u8]);
+
+    fn send_done(&self, buf: &'static mut [u8], result: ReturnCode);
+
+    /// Called when the connection is closed. A PDU passed to `send` that has
+    /// not been sent yet is returned with `ReturnCode::FAIL` first.
+    fn disconnected(&self);
+}
@@ -0,0 +1,398 @@
+//! GATT userspace interface for serving application services.
+//!
+//! Each application can register one primary service with up to
+//! `MAX_CHARACTERISTICS` characteristics. The service is described in a
+//! buffer, and the characteristic values live in another buffer shared with
+//! the kernel, so that the `GattServer` answers reads without waiting for the
+//! application. Applications are told about reads, writes and changes of the
+//! peer's notification configuration through a callback, and update values
+//! and send notifications or indications with commands.
+//!
+//! The service description is a service UUID followed by one entry per
+//! characteristic, where UUIDs are prefixed by their length (2 or 16 bytes)
+//! and least significant byte first:
+//!
+//! ```
+//!   Service         +----------+------+
+//!                   | UUID len | UUID |
+//!                   +----------+------+
+//!
+//!   Characteristic  +------------+----------+------+---------------+
+//!                   | Properties | UUID len | UUID | Max value len |
+//!                   +------------+----------+------+---------------+
+//! ```
+//!
+//! Properties are the GATT characteristic properties and must not be zero.
+//! The values are laid out back to back in the value buffer, each taking up
+//! its maximum length.
+//!
+//! Usage
+//! -----
+//!
+//! `boards/nrf52dk` registers the driver, with a `GattServer` on top of an
+//! `l2cap::L2capBearer`:
+//!
+//! ```rust
+//! let gatt_driver = static_init!(
+//!     capsules::net::ble::GattDriver<'static>,
+//!     capsules::net::ble::GattDriver::new(gatt_server, kernel::Grant::create())
+//! );
+//! gatt_server.set_client(gatt_driver);
+//! ```
+
+use core::cmp::min;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+use net::ble::att::{error, Uuid};
+use net::ble::gatt::{characteristic_attributes, GattClient, GattServer};
+use net::stream::decode_u8;
+use net::stream::SResult;
+
+/// Syscall number
+pub const DRIVER_NUM: usize = 0x30004;
+
+pub const MAX_CHARACTERISTICS: usize = 8;
+
+/// Events passed to the application's callback
+mod event {
+    pub const READ: usize = 0;
+    pub const WRITE: usize = 1;
+    pub const CONFIGURATION_CHANGED: usize = 2;
+    pub const NOTIFY_DONE: usize = 3;
+}
+
+/// A characteristic of an application's service
+#[derive(Copy, Clone, Default)]
+struct Characteristic {
+    handle: u16,
+    // Location of the value in the value buffer
+    offset: usize,
+    max_len: usize,
+    len: usize,
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    description: Option<AppSlice<Shared, u8>>,
+    values: Option<AppSlice<Shared, u8>>,
+    service: Option<u16>,
+    characteristics: [Characteristic; MAX_CHARACTERISTICS],
+    num_characteristics: usize,
+}
+
+impl App {
+    /// Returns the index of the characteristic with the value at `handle`
+    fn characteristic(&self, handle: u16) -> Option<usize> {
+        self.characteristics[..self.num_characteristics]
+            .iter()
+            .position(|characteristic| characteristic.handle == handle)
+    }
+
+    fn schedule(&mut self, event: usize, index: usize, arg: usize) {
+        self.callback
+            .map(|mut callback| callback.schedule(event, index, arg));
+    }
+}
+
+/// Decodes a UUID prefixed by its length
+fn decode_uuid(buf: &[u8]) -> SResult<Uuid> {
+    let (off, len) = dec_try!(buf; decode_u8);
+    stream_len_cond!(buf, off + len as usize);
+    let (off, uuid) = dec_try!(Uuid::decode(&buf[off..off + len as usize]), off);
+    stream_done!(off, uuid);
+}
+
+/// Decodes a characteristic entry of a service description into its
+/// properties, UUID and maximum value length
+fn decode_characteristic(buf: &[u8]) -> SResult<(u8, Uuid, usize)> {
+    let (off, properties) = dec_try!(buf; decode_u8);
+    let (off, uuid) = dec_try!(buf, off; decode_uuid);
+    let (off, max_len) = dec_try!(buf, off; decode_u8);
+    stream_cond!(properties != 0);
+    stream_done!(off, (properties, uuid, max_len as usize));
+}
+
+pub struct GattDriver<'a> {
+    server: &'a GattServer<'a>,
+    apps: Grant<App>,
+}
+
+impl<'a> GattDriver<'a> {
+    pub fn new(server: &'a GattServer<'a>, grant: Grant<App>) -> GattDriver<'a> {
+        GattDriver {
+            server: server,
+            apps: grant,
+        }
+    }
+
+    /// Utility function to perform an action on an app in a system call.
+    #[inline]
+    fn do_with_app<F>(&self, appid: AppId, closure: F) -> ReturnCode
+    where
+        F: FnOnce(&mut App) -> ReturnCode,
+    {
+        self.apps
+            .enter(appid, |app, _| closure(app))
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Performs an action on the app that registered the characteristic with
+    /// the value at `handle`, passing the characteristic's index
+    fn with_characteristic<F, R>(&self, handle: u16, default: R, closure: F) -> R
+    where
+        F: FnOnce(&mut App, usize) -> R,
+    {
+        let mut closure = Some(closure);
+        let mut result = None;
+        for app in self.apps.iter() {
+            app.enter(|app, _| {
+                if let Some(index) = app.characteristic(handle) {
+                    closure
+                        .take()
+                        .map(|closure| result = Some(closure(app, index)));
+                }
+            });
+        }
+        result.unwrap_or(default)
+    }
+
+    /// Adds the service described in the first `len` bytes of the
+    /// description buffer to the GATT server
+    fn register(&self, appid: AppId, len: usize) -> ReturnCode {
+        self.do_with_app(appid, |app| {
+            if app.service.is_some() {
+                return ReturnCode::EALREADY;
+            }
+            let description = match app.description {
+                Some(ref description) if len <= description.len() => &description.as_ref()[..len],
+                _ => return ReturnCode::EINVAL,
+            };
+            let values_len = app.values.as_ref().map_or(0, |values| values.len());
+
+            // Check the whole description before adding anything
+            let mut characteristics = [(0, Uuid::Uuid16(0), 0); MAX_CHARACTERISTICS];
+            let (mut off, service_uuid) = match decode_uuid(description) {
+                SResult::Done(off, uuid) => (off, uuid),
+                _ => return ReturnCode::EINVAL,
+            };
+            let mut count = 0;
+            let mut attributes = 0;
+            let mut value_offset = 0;
+            while off < len {
+                if count == MAX_CHARACTERISTICS {
+                    return ReturnCode::ESIZE;
+                }
+                match decode_characteristic(&description[off..]) {
+                    SResult::Done(entry_len, characteristic) => {
+                        let (properties, _, max_len) = characteristic;
+                        characteristics[count] = characteristic;
+                        attributes += characteristic_attributes(properties);
+                        value_offset += max_len;
+                        count += 1;
+                        off += entry_len;
+                    }
+                    _ => return ReturnCode::EINVAL,
+                }
+            }
+            if value_offset > values_len {
+                return ReturnCode::ESIZE;
+            }
+
+            let service = match self.server.add_service(service_uuid, attributes) {
+                Ok(service) => service,
+                Err(err) => return err,
+            };
+            let mut value_offset = 0;
+            for (index, &(properties, uuid, max_len)) in characteristics[..count].iter().enumerate()
+            {
+                // The service has reserved enough handles
+                let handle = self.server
+                    .add_characteristic(service, uuid, properties)
+                    .unwrap_or(0);
+                app.characteristics[index] = Characteristic {
+                    handle: handle,
+                    offset: value_offset,
+                    max_len: max_len,
+                    len: 0,
+                };
+                value_offset += max_len;
+            }
+            app.num_characteristics = count;
+            app.service = Some(service);
+            ReturnCode::SUCCESS
+        })
+    }
+
+    fn unregister(&self, appid: AppId) -> ReturnCode {
+        self.do_with_app(appid, |app| match app.service.take() {
+            Some(service) => {
+                app.num_characteristics = 0;
+                self.server.remove_service(service)
+            }
+            None => ReturnCode::EINVAL,
+        })
+    }
+
+    fn set_value_len(&self, appid: AppId, index: usize, len: usize) -> ReturnCode {
+        self.do_with_app(appid, |app| {
+            if index >= app.num_characteristics {
+                return ReturnCode::EINVAL;
+            }
+            let characteristic = &mut app.characteristics[index];
+            if len > characteristic.max_len {
+                return ReturnCode::ESIZE;
+            }
+            characteristic.len = len;
+            ReturnCode::SUCCESS
+        })
+    }
+
+    fn notify(&self, appid: AppId, index: usize, indicate: bool) -> ReturnCode {
+        self.do_with_app(appid, |app| {
+            if index >= app.num_characteristics {
+                return ReturnCode::EINVAL;
+            }
+            let characteristic = app.characteristics[index];
+            let value = match app.values {
+                Some(ref values) => {
+                    let start = characteristic.offset;
+                    &values.as_ref()[start..start + characteristic.len]
+                }
+                None => return ReturnCode::EINVAL,
+            };
+            if indicate {
+                self.server.indicate(characteristic.handle, value)
+            } else {
+                self.server.notify(characteristic.handle, value)
+            }
+        })
+    }
+}
+
+impl<'a> GattClient for GattDriver<'a> {
+    fn read(&self, handle: u16, offset: usize, buf: &mut [u8]) -> Result<usize, u8> {
+        self.with_characteristic(handle, Err(error::INVALID_HANDLE), |app, index| {
+            let characteristic = app.characteristics[index];
+            if offset > characteristic.len {
+                return Err(error::INVALID_OFFSET);
+            }
+            let len = min(characteristic.len - offset, buf.len());
+            let start = characteristic.offset + offset;
+            app.values.as_ref().map(|values| {
+                buf[..len].copy_from_slice(&values.as_ref()[start..start + len]);
+            });
+            app.schedule(event::READ, index, offset);
+            Ok(len)
+        })
+    }
+
+    fn write(&self, handle: u16, value: &[u8], _response: bool) -> Result<(), u8> {
+        self.with_characteristic(handle, Err(error::INVALID_HANDLE), |app, index| {
+            let characteristic = &mut app.characteristics[index];
+            if value.len() > characteristic.max_len {
+                return Err(error::INVALID_ATTRIBUTE_VALUE_LENGTH);
+            }
+            let start = characteristic.offset;
+            match app.values {
+                Some(ref mut values) => {
+                    values.as_mut()[start..start + value.len()].copy_from_slice(value);
+                }
+                None => return Err(error::UNLIKELY_ERROR),
+            }
+            characteristic.len = value.len();
+            app.schedule(event::WRITE, index, value.len());
+            Ok(())
+        })
+    }
+
+    fn configuration_changed(&self, handle: u16, configuration: u16) {
+        self.with_characteristic(handle, (), |app, index| {
+            app.schedule(event::CONFIGURATION_CHANGED, index, configuration as usize);
+        });
+    }
+
+    fn notify_done(&self, handle: u16, result: ReturnCode) {
+        self.with_characteristic(handle, (), |app, index| {
+            app.schedule(event::NOTIFY_DONE, index, usize::from(result));
+        });
+    }
+}
+
+impl<'a> Driver for GattDriver<'a> {
+    /// Setup buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Service description buffer, in the format described above.
+    /// - `1`: Value buffer. Holds the values of the characteristics, each at
+    ///        a fixed offset given by the maximum lengths of the values
+    ///        before it. It must not be replaced while the service is
+    ///        registered.
+    fn allow(&self, appid: AppId, allow_num: usize, slice: AppSlice<Shared, u8>) -> ReturnCode {
+        match allow_num {
+            0 => self.do_with_app(appid, |app| {
+                app.description = Some(slice);
+                ReturnCode::SUCCESS
+            }),
+            1 => self.do_with_app(appid, |app| {
+                if app.service.is_some() {
+                    return ReturnCode::EBUSY;
+                }
+                app.values = Some(slice);
+                ReturnCode::SUCCESS
+            }),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Setup callback for GATT events. The callback signature is
+    ///        `fn(event, characteristic_index, arg)`, where `event` is:
+    ///   - `0`: The peer read the value, starting at offset `arg`.
+    ///   - `1`: The peer wrote a value of `arg` bytes.
+    ///   - `2`: The peer changed the configuration of notifications (bit 0)
+    ///          and indications (bit 1) to `arg`.
+    ///   - `3`: A notification was sent or an indication was confirmed, with
+    ///          the `ReturnCode` in `arg`.
+    fn subscribe(&self, subscribe_num: usize, callback: Callback) -> ReturnCode {
+        match subscribe_num {
+            0 => self.do_with_app(callback.app_id(), |app| {
+                app.callback = Some(callback);
+                ReturnCode::SUCCESS
+            }),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// GATT service control.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Register the service described in the first `arg1` bytes of
+    ///        the description buffer. Returns EALREADY if the app has
+    ///        registered a service, ESIZE if there are too many
+    ///        characteristics or the value buffer is too small, and ENOMEM if
+    ///        the GATT database is full.
+    /// - `2`: Unregister the app's service.
+    /// - `3`: Set the length of the value of characteristic `arg1`, which
+    ///        has been written to the value buffer, to `arg2`.
+    /// - `4`: Send a notification of the value of characteristic `arg1`.
+    ///        Returns EOFF if the peer has not enabled notifications.
+    /// - `5`: Send an indication of the value of characteristic `arg1`.
+    ///        Returns EOFF if the peer has not enabled indications.
+    fn command(&self, command_num: usize, arg1: usize, arg2: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self.register(appid, arg1),
+            2 => self.unregister(appid),
+            3 => self.set_value_len(appid, arg1, arg2),
+            4 => self.notify(appid, arg1, false),
+            5 => self.notify(appid, arg1, true),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
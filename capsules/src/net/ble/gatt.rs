@@ -0,0 +1,1091 @@
+//! A GATT (Generic Attribute Profile) server.
+//!
+//! The server keeps a database of attributes, grouped into primary services,
+//! and answers the ATT requests a GATT client uses to discover services,
+//! characteristics and descriptors, to read and write characteristic values
+//! and to configure notifications and indications:
+//!
+//! ```
+//!   Handle  Attribute type                     Value
+//!   0x0001  Primary Service (0x2800)           Service UUID
+//!   0x0002  Characteristic (0x2803)            Properties, 0x0003, UUID
+//!   0x0003  Characteristic UUID                Characteristic value
+//!   0x0004  Client Char. Configuration (0x2902) Notifications/indications
+//!   0x0005  Descriptor UUID                    Descriptor value
+//!   0x0006  Primary Service (0x2800)           Service UUID
+//!   ...
+//! ```
+//!
+//! Services are added with `GattServer::add_service`, which reserves handles
+//! for the service's characteristics and descriptors so that they stay
+//! contiguous, and then filled with `add_characteristic`,
+//! `add_static_characteristic` and `add_descriptor`. A Client Characteristic
+//! Configuration descriptor is added automatically to characteristics that
+//! support notifications or indications.
+//!
+//! Declarations, configuration descriptors and static values are stored in
+//! the database. All other values are read and written synchronously through
+//! the `GattClient`, so every request is answered with a single response.
+//!
+//! The server handles one connection, with Exchange MTU, Find Information,
+//! Find By Type Value (for primary services), Read By Type, Read, Read Blob,
+//! Read By Group Type, Write requests and commands, notifications and
+//! indications. Other requests (prepared writes, Read Multiple and signed
+//! writes) are answered with `REQUEST_NOT_SUPPORTED`. Configurations are not
+//! kept across connections, as there is no bonding.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! static mut GATT_BUF: [u8; 64] = [0; 64];
+//! static DEVICE_NAME: &'static [u8] = b"Tock";
+//!
+//! let gatt_server = static_init!(
+//!     capsules::net::ble::gatt::GattServer<'static>,
+//!     capsules::net::ble::gatt::GattServer::new(att_bearer, &mut GATT_BUF)
+//! );
+//! att_bearer.set_client(gatt_server);
+//!
+//! let gap = gatt_server
+//!     .add_service(Uuid::Uuid16(gatt::uuid::GAP_SERVICE), 2)
+//!     .unwrap();
+//! gatt_server.add_static_characteristic(gap, Uuid::Uuid16(gatt::uuid::DEVICE_NAME), DEVICE_NAME);
+//! ```
+
+use core::cell::Cell;
+use core::cmp::min;
+use kernel::ReturnCode;
+use kernel::common::take_cell::{MapCell, TakeCell};
+use net::ble::att::{error, format, opcode, AttBearer, AttBearerClient, AttPdu, Uuid, DEFAULT_MTU};
+use net::stream::{encode_bytes, encode_u16_le};
+use net::stream::SResult;
+
+/// Number of attributes in the database
+pub const MAX_ATTRIBUTES: usize = 40;
+
+/// Largest ATT_MTU the server agrees to, if its buffer is large enough
+pub const MAX_MTU: usize = 128;
+
+// BLUETOOTH SPECIFICATION Core Specification Supplement Version 7 [Part B],
+// section 1.2 Common Profile and Service Error Codes
+pub const CCCD_IMPROPERLY_CONFIGURED: u8 = 0xfd;
+
+/// Attribute types and services defined by GATT and GAP
+pub mod uuid {
+    pub const GAP_SERVICE: u16 = 0x1800;
+    pub const GATT_SERVICE: u16 = 0x1801;
+
+    pub const PRIMARY_SERVICE: u16 = 0x2800;
+    pub const SECONDARY_SERVICE: u16 = 0x2801;
+    pub const INCLUDE: u16 = 0x2802;
+    pub const CHARACTERISTIC: u16 = 0x2803;
+
+    pub const CHARACTERISTIC_EXTENDED_PROPERTIES: u16 = 0x2900;
+    pub const CHARACTERISTIC_USER_DESCRIPTION: u16 = 0x2901;
+    pub const CLIENT_CHARACTERISTIC_CONFIGURATION: u16 = 0x2902;
+
+    pub const DEVICE_NAME: u16 = 0x2a00;
+    pub const APPEARANCE: u16 = 0x2a01;
+}
+
+/// BLUETOOTH SPECIFICATION Version 4.2 [Vol 3, Part G], section 3.3.1.1
+/// Characteristic Properties
+pub mod properties {
+    pub const BROADCAST: u8 = 0x01;
+    pub const READ: u8 = 0x02;
+    pub const WRITE_WITHOUT_RESPONSE: u8 = 0x04;
+    pub const WRITE: u8 = 0x08;
+    pub const NOTIFY: u8 = 0x10;
+    pub const INDICATE: u8 = 0x20;
+    pub const AUTHENTICATED_SIGNED_WRITES: u8 = 0x40;
+    pub const EXTENDED_PROPERTIES: u8 = 0x80;
+}
+
+/// BLUETOOTH SPECIFICATION Version 4.2 [Vol 3, Part G], section 3.3.3.3
+/// Client Characteristic Configuration
+pub mod configuration {
+    pub const NOTIFICATIONS: u16 = 0x0001;
+    pub const INDICATIONS: u16 = 0x0002;
+}
+
+/// Number of handles taken up by a characteristic with the given properties,
+/// not counting descriptors other than the Client Characteristic
+/// Configuration
+pub fn characteristic_attributes(properties: u8) -> usize {
+    if properties & (properties::NOTIFY | properties::INDICATE) != 0 {
+        3
+    } else {
+        2
+    }
+}
+
+/// An entry of the attribute database, whose handle is its index plus one
+#[derive(Copy, Clone, Debug)]
+enum Attribute<'a> {
+    Unused,
+    /// Reserved for a characteristic or descriptor of the service before it
+    Reserved,
+    PrimaryService(Uuid),
+    /// Characteristic declaration. The value attribute follows it directly.
+    Characteristic {
+        properties: u8,
+        uuid: Uuid,
+    },
+    /// Characteristic value or descriptor, accessed through the `GattClient`.
+    /// `permissions` uses the bits of the characteristic properties.
+    Value {
+        uuid: Uuid,
+        permissions: u8,
+    },
+    /// Read-only value stored in the database
+    Static {
+        uuid: Uuid,
+        value: &'a [u8],
+    },
+    Configuration {
+        value_handle: u16,
+        properties: u8,
+        configuration: u16,
+    },
+}
+
+impl<'a> Attribute<'a> {
+    fn attribute_type(&self) -> Option<Uuid> {
+        match *self {
+            Attribute::Unused | Attribute::Reserved => None,
+            Attribute::PrimaryService(_) => Some(Uuid::Uuid16(uuid::PRIMARY_SERVICE)),
+            Attribute::Characteristic { .. } => Some(Uuid::Uuid16(uuid::CHARACTERISTIC)),
+            Attribute::Value { uuid, .. } | Attribute::Static { uuid, .. } => Some(uuid),
+            Attribute::Configuration { .. } => {
+                Some(Uuid::Uuid16(uuid::CLIENT_CHARACTERISTIC_CONFIGURATION))
+            }
+        }
+    }
+}
+
+/// Implemented by the owner of the characteristic values and descriptors
+/// that are not stored in the database
+pub trait GattClient {
+    /// Copies the value of the attribute at `handle`, starting at `offset`,
+    /// into `buf`, truncating it to the length of `buf`. Returns the number of
+    /// bytes copied, or an ATT error code such as `INVALID_OFFSET`.
+    fn read(&self, handle: u16, offset: usize, buf: &mut [u8]) -> Result<usize, u8>;
+
+    /// Sets the value of the attribute at `handle`. `response` is false for a
+    /// Write Command, to which the peer does not get an answer. Returns an
+    /// ATT error code such as `INVALID_ATTRIBUTE_VALUE_LENGTH` if the value
+    /// is rejected.
+    fn write(&self, handle: u16, value: &[u8], response: bool) -> Result<(), u8>;
+
+    /// Called when the peer enables or disables notifications or indications
+    /// of the characteristic value at `handle`, and with a configuration of 0
+    /// for every enabled characteristic when the connection is closed
+    fn configuration_changed(&self, handle: u16, configuration: u16);
+
+    /// Called when a notification has been sent or an indication has been
+    /// confirmed by the peer, or with `ReturnCode::FAIL` if it was lost
+    fn notify_done(&self, handle: u16, result: ReturnCode);
+}
+
+/// The answer to a request, which is encoded once the buffer is available
+#[derive(Copy, Clone, Debug)]
+enum Response {
+    Error {
+        request: u8,
+        handle: u16,
+        error: u8,
+    },
+    ExchangeMtu,
+    FindInformation {
+        start: u16,
+        end: u16,
+    },
+    FindByTypeValue {
+        start: u16,
+        end: u16,
+        uuid: Uuid,
+    },
+    ReadByType {
+        start: u16,
+        end: u16,
+        attribute_type: Uuid,
+    },
+    Read {
+        handle: u16,
+    },
+    ReadBlob {
+        handle: u16,
+        offset: u16,
+    },
+    ReadByGroupType {
+        start: u16,
+        end: u16,
+    },
+    Write,
+}
+
+impl Response {
+    fn request(&self) -> u8 {
+        match *self {
+            Response::Error { request, .. } => request,
+            Response::ExchangeMtu => opcode::EXCHANGE_MTU_REQ,
+            Response::FindInformation { .. } => opcode::FIND_INFORMATION_REQ,
+            Response::FindByTypeValue { .. } => opcode::FIND_BY_TYPE_VALUE_REQ,
+            Response::ReadByType { .. } => opcode::READ_BY_TYPE_REQ,
+            Response::Read { .. } => opcode::READ_REQ,
+            Response::ReadBlob { .. } => opcode::READ_BLOB_REQ,
+            Response::ReadByGroupType { .. } => opcode::READ_BY_GROUP_TYPE_REQ,
+            Response::Write => opcode::WRITE_REQ,
+        }
+    }
+}
+
+/// Checks the handle range of a request
+fn check_range(request: u8, start: u16, end: u16) -> Option<Response> {
+    if start == 0 || start > end {
+        Some(Response::Error {
+            request: request,
+            handle: start,
+            error: error::INVALID_HANDLE,
+        })
+    } else {
+        None
+    }
+}
+
+/// Copies `value` from `offset` on into `buf`
+fn copy_value(value: &[u8], offset: usize, buf: &mut [u8]) -> Result<usize, u8> {
+    if offset > value.len() {
+        return Err(error::INVALID_OFFSET);
+    }
+    let len = min(value.len() - offset, buf.len());
+    buf[..len].copy_from_slice(&value[offset..offset + len]);
+    Ok(len)
+}
+
+pub struct GattServer<'a> {
+    bearer: &'a AttBearer<'a>,
+    client: Cell<Option<&'a GattClient>>,
+    database: MapCell<[Attribute<'a>; MAX_ATTRIBUTES]>,
+
+    // Buffer in which responses, notifications and indications are encoded.
+    // It is held by the bearer while a PDU is being sent.
+    tx_buf: TakeCell<'static, [u8]>,
+    max_mtu: u16,
+    mtu: Cell<u16>,
+    connected: Cell<bool>,
+    response: Cell<Option<Response>>,
+    // Handle of the notification or indication being sent, and whether it is
+    // an indication
+    sending: Cell<Option<(u16, bool)>>,
+    // Handle of the indication waiting for a confirmation
+    indicating: Cell<Option<u16>>,
+}
+
+impl<'a> GattServer<'a> {
+    /// Creates a new `GattServer` with an empty database. `tx_buf` must be at
+    /// least `DEFAULT_MTU` bytes long, and its length bounds the ATT_MTU.
+    pub fn new(bearer: &'a AttBearer<'a>, tx_buf: &'static mut [u8]) -> GattServer<'a> {
+        let max_mtu = min(tx_buf.len(), MAX_MTU) as u16;
+        assert!(max_mtu >= DEFAULT_MTU);
+        GattServer {
+            bearer: bearer,
+            client: Cell::new(None),
+            database: MapCell::new([Attribute::Unused; MAX_ATTRIBUTES]),
+            tx_buf: TakeCell::new(tx_buf),
+            max_mtu: max_mtu,
+            mtu: Cell::new(DEFAULT_MTU),
+            connected: Cell::new(false),
+            response: Cell::new(None),
+            sending: Cell::new(None),
+            indicating: Cell::new(None),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a GattClient) {
+        self.client.set(Some(client));
+    }
+
+    /// The ATT_MTU of the current connection
+    pub fn mtu(&self) -> u16 {
+        self.mtu.get()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected.get()
+    }
+
+    fn attribute(&self, handle: u16) -> Attribute<'a> {
+        if handle == 0 || handle as usize > MAX_ATTRIBUTES {
+            return Attribute::Unused;
+        }
+        self.database
+            .map_or(Attribute::Unused, |database| database[handle as usize - 1])
+    }
+
+    fn set_attribute(&self, handle: u16, attribute: Attribute<'a>) {
+        self.database.map(|database| {
+            database[handle as usize - 1] = attribute;
+        });
+    }
+
+    /// Last handle of a request's range that can be in use
+    fn last_handle(end: u16) -> u16 {
+        min(end, MAX_ATTRIBUTES as u16)
+    }
+
+    /// Last handle of the service declared at `service`
+    fn group_end(&self, service: u16) -> u16 {
+        let mut end = service;
+        while (end as usize) < MAX_ATTRIBUTES {
+            match self.attribute(end + 1) {
+                Attribute::Unused | Attribute::PrimaryService(_) => break,
+                _ => end += 1,
+            }
+        }
+        end
+    }
+
+    /// Adds a primary service and reserves `attributes` handles after it for
+    /// its characteristics and descriptors. Returns the handle of the service
+    /// declaration, or `ENOMEM` if the database has no room for the service.
+    pub fn add_service(&self, uuid: Uuid, attributes: usize) -> Result<u16, ReturnCode> {
+        let len = attributes + 1;
+        let mut run = 0;
+        let mut service = None;
+        for handle in 1..MAX_ATTRIBUTES as u16 + 1 {
+            match self.attribute(handle) {
+                Attribute::Unused => run += 1,
+                _ => run = 0,
+            }
+            if run == len {
+                service = Some(handle + 1 - len as u16);
+                break;
+            }
+        }
+        let service = service.ok_or(ReturnCode::ENOMEM)?;
+        self.set_attribute(service, Attribute::PrimaryService(uuid));
+        for handle in service + 1..service + len as u16 {
+            self.set_attribute(handle, Attribute::Reserved);
+        }
+        Ok(service)
+    }
+
+    /// Removes a service with all its characteristics and descriptors
+    pub fn remove_service(&self, service: u16) -> ReturnCode {
+        match self.attribute(service) {
+            Attribute::PrimaryService(_) => {}
+            _ => return ReturnCode::EINVAL,
+        }
+        for handle in service..self.group_end(service) + 1 {
+            self.set_attribute(handle, Attribute::Unused);
+        }
+        ReturnCode::SUCCESS
+    }
+
+    /// Returns the first of `count` consecutive handles reserved by
+    /// `service`
+    fn reserved_handles(&self, service: u16, count: usize) -> Result<u16, ReturnCode> {
+        match self.attribute(service) {
+            Attribute::PrimaryService(_) => {}
+            _ => return Err(ReturnCode::EINVAL),
+        }
+        let end = self.group_end(service);
+        let mut first = service + 1;
+        while first <= end {
+            match self.attribute(first) {
+                Attribute::Reserved => break,
+                _ => first += 1,
+            }
+        }
+        if first as usize + count > end as usize + 1 {
+            return Err(ReturnCode::ENOMEM);
+        }
+        Ok(first)
+    }
+
+    /// Adds a characteristic whose value is accessed through the
+    /// `GattClient`, followed by a Client Characteristic Configuration
+    /// descriptor if `properties` include `NOTIFY` or `INDICATE`. Returns the
+    /// handle of the characteristic value, or `ENOMEM` if the service has not
+    /// reserved enough handles.
+    pub fn add_characteristic(
+        &self,
+        service: u16,
+        uuid: Uuid,
+        properties: u8,
+    ) -> Result<u16, ReturnCode> {
+        let count = characteristic_attributes(properties);
+        let handle = self.reserved_handles(service, count)?;
+        self.set_attribute(
+            handle,
+            Attribute::Characteristic {
+                properties: properties,
+                uuid: uuid,
+            },
+        );
+        self.set_attribute(
+            handle + 1,
+            Attribute::Value {
+                uuid: uuid,
+                permissions: properties,
+            },
+        );
+        if count == 3 {
+            self.set_attribute(
+                handle + 2,
+                Attribute::Configuration {
+                    value_handle: handle + 1,
+                    properties: properties,
+                    configuration: 0,
+                },
+            );
+        }
+        Ok(handle + 1)
+    }
+
+    /// Adds a read-only characteristic whose value is stored in the
+    /// database, such as the GAP device name
+    pub fn add_static_characteristic(
+        &self,
+        service: u16,
+        uuid: Uuid,
+        value: &'a [u8],
+    ) -> Result<u16, ReturnCode> {
+        let handle = self.reserved_handles(service, 2)?;
+        self.set_attribute(
+            handle,
+            Attribute::Characteristic {
+                properties: properties::READ,
+                uuid: uuid,
+            },
+        );
+        self.set_attribute(
+            handle + 1,
+            Attribute::Static {
+                uuid: uuid,
+                value: value,
+            },
+        );
+        Ok(handle + 1)
+    }
+
+    /// Adds a descriptor to the characteristic added last to `service`. Its
+    /// value is accessed through the `GattClient`, and `permissions` is a
+    /// combination of `properties::READ` and `properties::WRITE`.
+    pub fn add_descriptor(
+        &self,
+        service: u16,
+        uuid: Uuid,
+        permissions: u8,
+    ) -> Result<u16, ReturnCode> {
+        let handle = self.reserved_handles(service, 1)?;
+        if handle == service + 1 {
+            // There is no characteristic yet
+            return Err(ReturnCode::EINVAL);
+        }
+        self.set_attribute(
+            handle,
+            Attribute::Value {
+                uuid: uuid,
+                permissions: permissions,
+            },
+        );
+        Ok(handle)
+    }
+
+    /// Returns the configuration of the characteristic value at `handle`, or
+    /// `None` if it supports neither notifications nor indications
+    pub fn configuration(&self, handle: u16) -> Option<u16> {
+        match self.attribute(handle + 1) {
+            Attribute::Configuration {
+                value_handle,
+                configuration,
+                ..
+            } if value_handle == handle => Some(configuration),
+            _ => None,
+        }
+    }
+
+    /// Sends a notification of the characteristic value at `handle`. Returns
+    /// `EOFF` if the peer is not connected or has not enabled notifications,
+    /// `ESIZE` if the value does not fit into a PDU, and `EBUSY` if another
+    /// PDU is being sent. `GattClient::notify_done` is called once the
+    /// notification has been sent.
+    pub fn notify(&self, handle: u16, value: &[u8]) -> ReturnCode {
+        self.send_handle_value(handle, value, false)
+    }
+
+    /// Sends an indication of the characteristic value at `handle`, like
+    /// `notify`. Only one indication can be unconfirmed at a time, and
+    /// `GattClient::notify_done` is called once it has been confirmed.
+    pub fn indicate(&self, handle: u16, value: &[u8]) -> ReturnCode {
+        self.send_handle_value(handle, value, true)
+    }
+
+    fn send_handle_value(&self, handle: u16, value: &[u8], indicate: bool) -> ReturnCode {
+        let enabled = if indicate {
+            configuration::INDICATIONS
+        } else {
+            configuration::NOTIFICATIONS
+        };
+        match self.configuration(handle) {
+            Some(configuration) => {
+                if !self.connected.get() || configuration & enabled == 0 {
+                    return ReturnCode::EOFF;
+                }
+            }
+            None => return ReturnCode::EINVAL,
+        }
+        if value.len() + 3 > self.mtu.get() as usize {
+            return ReturnCode::ESIZE;
+        }
+        // Responses go first
+        if self.response.get().is_some() || (indicate && self.indicating.get().is_some()) {
+            return ReturnCode::EBUSY;
+        }
+        let buf = match self.tx_buf.take() {
+            Some(buf) => buf,
+            None => return ReturnCode::EBUSY,
+        };
+        let pdu = if indicate {
+            AttPdu::HandleValueInd {
+                handle: handle,
+                value: value,
+            }
+        } else {
+            AttPdu::HandleValueNtf {
+                handle: handle,
+                value: value,
+            }
+        };
+        let len = match pdu.encode(buf) {
+            SResult::Done(len, ()) => len,
+            _ => {
+                self.tx_buf.replace(buf);
+                return ReturnCode::ESIZE;
+            }
+        };
+        match self.bearer.send(buf, len) {
+            Ok(()) => {
+                self.sending.set(Some((handle, indicate)));
+                ReturnCode::SUCCESS
+            }
+            Err((result, buf)) => {
+                self.tx_buf.replace(buf);
+                result
+            }
+        }
+    }
+
+    fn read_value(&self, handle: u16, offset: usize, buf: &mut [u8]) -> Result<usize, u8> {
+        let attribute = self.attribute(handle);
+        match attribute {
+            Attribute::Unused | Attribute::Reserved => Err(error::INVALID_HANDLE),
+            Attribute::Value { permissions, .. } => {
+                if permissions & properties::READ == 0 {
+                    return Err(error::READ_NOT_PERMITTED);
+                }
+                self.client
+                    .get()
+                    .map_or(Err(error::UNLIKELY_ERROR), |client| {
+                        client.read(handle, offset, buf)
+                    })
+            }
+            Attribute::Static { value, .. } => copy_value(value, offset, buf),
+            Attribute::PrimaryService(uuid) => {
+                let mut value = [0; 16];
+                uuid.encode(&mut value);
+                copy_value(&value[..uuid.len()], offset, buf)
+            }
+            Attribute::Characteristic { properties, uuid } => {
+                let mut value = [0; 19];
+                value[0] = properties;
+                encode_u16_le(&mut value[1..], handle + 1);
+                uuid.encode(&mut value[3..]);
+                copy_value(&value[..3 + uuid.len()], offset, buf)
+            }
+            Attribute::Configuration { configuration, .. } => {
+                let mut value = [0; 2];
+                encode_u16_le(&mut value, configuration);
+                copy_value(&value, offset, buf)
+            }
+        }
+    }
+
+    fn write_value(&self, handle: u16, value: &[u8], response: bool) -> Result<(), u8> {
+        match self.attribute(handle) {
+            Attribute::Unused | Attribute::Reserved => Err(error::INVALID_HANDLE),
+            Attribute::Value { permissions, .. } => {
+                let permitted = if response {
+                    properties::WRITE
+                } else {
+                    properties::WRITE_WITHOUT_RESPONSE
+                };
+                if permissions & permitted == 0 {
+                    return Err(error::WRITE_NOT_PERMITTED);
+                }
+                self.client
+                    .get()
+                    .map_or(Err(error::UNLIKELY_ERROR), |client| {
+                        client.write(handle, value, response)
+                    })
+            }
+            Attribute::Configuration {
+                value_handle,
+                properties,
+                configuration,
+            } => {
+                if value.len() != 2 {
+                    return Err(error::INVALID_ATTRIBUTE_VALUE_LENGTH);
+                }
+                let mut supported = 0;
+                if properties & properties::NOTIFY != 0 {
+                    supported |= configuration::NOTIFICATIONS;
+                }
+                if properties & properties::INDICATE != 0 {
+                    supported |= configuration::INDICATIONS;
+                }
+                let new_configuration = (value[1] as u16) << 8 | (value[0] as u16);
+                if new_configuration & !supported != 0 {
+                    return Err(CCCD_IMPROPERLY_CONFIGURED);
+                }
+                self.set_attribute(
+                    handle,
+                    Attribute::Configuration {
+                        value_handle: value_handle,
+                        properties: properties,
+                        configuration: new_configuration,
+                    },
+                );
+                if new_configuration != configuration {
+                    self.client.get().map(|client| {
+                        client.configuration_changed(value_handle, new_configuration)
+                    });
+                }
+                Ok(())
+            }
+            _ => Err(error::WRITE_NOT_PERMITTED),
+        }
+    }
+
+    /// Handles a PDU received from the peer and returns the response to send
+    fn handle_pdu(&self, pdu: AttPdu) -> Option<Response> {
+        match pdu {
+            AttPdu::ExchangeMtuReq { mtu } => {
+                self.mtu.set(min(mtu, self.max_mtu).max(DEFAULT_MTU));
+                Some(Response::ExchangeMtu)
+            }
+            AttPdu::FindInformationReq { start, end } => {
+                check_range(opcode::FIND_INFORMATION_REQ, start, end).or(Some(
+                    Response::FindInformation {
+                        start: start,
+                        end: end,
+                    },
+                ))
+            }
+            AttPdu::FindByTypeValueReq {
+                start,
+                end,
+                attribute_type,
+                value,
+            } => check_range(opcode::FIND_BY_TYPE_VALUE_REQ, start, end).or_else(|| {
+                // Only used to discover primary services by UUID
+                match Uuid::decode(value) {
+                    SResult::Done(_, uuid) if attribute_type == uuid::PRIMARY_SERVICE => {
+                        Some(Response::FindByTypeValue {
+                            start: start,
+                            end: end,
+                            uuid: uuid,
+                        })
+                    }
+                    _ => Some(Response::Error {
+                        request: opcode::FIND_BY_TYPE_VALUE_REQ,
+                        handle: start,
+                        error: error::ATTRIBUTE_NOT_FOUND,
+                    }),
+                }
+            }),
+            AttPdu::ReadByTypeReq {
+                start,
+                end,
+                attribute_type,
+            } => check_range(opcode::READ_BY_TYPE_REQ, start, end).or(Some(Response::ReadByType {
+                start: start,
+                end: end,
+                attribute_type: attribute_type,
+            })),
+            AttPdu::ReadReq { handle } => Some(Response::Read { handle: handle }),
+            AttPdu::ReadBlobReq { handle, offset } => Some(Response::ReadBlob {
+                handle: handle,
+                offset: offset,
+            }),
+            AttPdu::ReadByGroupTypeReq {
+                start,
+                end,
+                group_type,
+            } => check_range(opcode::READ_BY_GROUP_TYPE_REQ, start, end).or_else(|| {
+                if group_type == Uuid::Uuid16(uuid::PRIMARY_SERVICE) {
+                    Some(Response::ReadByGroupType {
+                        start: start,
+                        end: end,
+                    })
+                } else {
+                    // There are no secondary services
+                    let error = if group_type == Uuid::Uuid16(uuid::SECONDARY_SERVICE) {
+                        error::ATTRIBUTE_NOT_FOUND
+                    } else {
+                        error::UNSUPPORTED_GROUP_TYPE
+                    };
+                    Some(Response::Error {
+                        request: opcode::READ_BY_GROUP_TYPE_REQ,
+                        handle: start,
+                        error: error,
+                    })
+                }
+            }),
+            AttPdu::WriteReq { handle, value } => match self.write_value(handle, value, true) {
+                Ok(()) => Some(Response::Write),
+                Err(error) => Some(Response::Error {
+                    request: opcode::WRITE_REQ,
+                    handle: handle,
+                    error: error,
+                }),
+            },
+            AttPdu::WriteCmd { handle, value } => {
+                let _ = self.write_value(handle, value, false);
+                None
+            }
+            AttPdu::HandleValueCfm => {
+                self.indicating.get().map(|handle| {
+                    self.indicating.set(None);
+                    self.client
+                        .get()
+                        .map(|client| client.notify_done(handle, ReturnCode::SUCCESS));
+                });
+                None
+            }
+            AttPdu::Unsupported { opcode } => {
+                if opcode::is_command(opcode) {
+                    None
+                } else {
+                    Some(Response::Error {
+                        request: opcode,
+                        handle: 0,
+                        error: error::REQUEST_NOT_SUPPORTED,
+                    })
+                }
+            }
+            // PDUs sent to a GATT client
+            _ => None,
+        }
+    }
+
+    /// Sends the pending response if the buffer is available
+    fn send_response(&self) {
+        let response = match self.response.get() {
+            Some(response) => response,
+            None => return,
+        };
+        self.tx_buf.take().map(|buf| {
+            self.response.set(None);
+            let mtu = self.mtu.get() as usize;
+            let mut data = [0; MAX_MTU];
+            let pdu = self.response_pdu(response, &mut data[..mtu - 1]);
+            match pdu.encode(&mut buf[..mtu]) {
+                SResult::Done(len, ()) => {
+                    if let Err((_, buf)) = self.bearer.send(buf, len) {
+                        self.tx_buf.replace(buf);
+                    }
+                }
+                _ => {
+                    self.tx_buf.replace(buf);
+                }
+            }
+        });
+    }
+
+    /// Builds the PDU answering a request, using `data` (the space after the
+    /// opcode) for its parameters
+    fn response_pdu<'b>(&self, response: Response, data: &'b mut [u8]) -> AttPdu<'b> {
+        let result = match response {
+            Response::Error {
+                request,
+                handle,
+                error,
+            } => Err((request, handle, error)),
+            Response::ExchangeMtu => Ok(AttPdu::ExchangeMtuRsp { mtu: self.max_mtu }),
+            Response::Write => Ok(AttPdu::WriteRsp),
+            Response::FindInformation { start, end } => self.find_information(start, end, data),
+            Response::FindByTypeValue { start, end, uuid } => {
+                self.find_by_type_value(start, end, uuid, data)
+            }
+            Response::ReadByType {
+                start,
+                end,
+                attribute_type,
+            } => self.read_by_type(start, end, attribute_type, data),
+            Response::Read { handle } => match self.read_value(handle, 0, data) {
+                Ok(len) => {
+                    let data: &'b [u8] = data;
+                    Ok(AttPdu::ReadRsp {
+                        value: &data[..len],
+                    })
+                }
+                Err(error) => Err((response.request(), handle, error)),
+            },
+            Response::ReadBlob { handle, offset } => {
+                match self.read_value(handle, offset as usize, data) {
+                    Ok(len) => {
+                        let data: &'b [u8] = data;
+                        Ok(AttPdu::ReadBlobRsp {
+                            value: &data[..len],
+                        })
+                    }
+                    Err(error) => Err((response.request(), handle, error)),
+                }
+            }
+            Response::ReadByGroupType { start, end } => self.read_by_group_type(start, end, data),
+        };
+        result.unwrap_or_else(|(request, handle, error)| AttPdu::ErrorRsp {
+            request: request,
+            handle: handle,
+            error: error,
+        })
+    }
+
+    fn not_found<'b>(request: u8, start: u16) -> Result<AttPdu<'b>, (u8, u16, u8)> {
+        Err((request, start, error::ATTRIBUTE_NOT_FOUND))
+    }
+
+    fn find_information<'b>(
+        &self,
+        start: u16,
+        end: u16,
+        data: &'b mut [u8],
+    ) -> Result<AttPdu<'b>, (u8, u16, u8)> {
+        // The format takes up the first byte
+        let max_len = data.len() - 1;
+        let mut len = 0;
+        let mut uuid_len = 0;
+        for handle in start..GattServer::last_handle(end) + 1 {
+            let uuid = match self.attribute(handle).attribute_type() {
+                Some(uuid) => uuid,
+                None => continue,
+            };
+            if uuid_len == 0 {
+                uuid_len = uuid.len();
+            }
+            if uuid.len() != uuid_len || len + 2 + uuid_len > max_len {
+                break;
+            }
+            encode_u16_le(&mut data[len..], handle);
+            uuid.encode(&mut data[len + 2..]);
+            len += 2 + uuid_len;
+        }
+        if len == 0 {
+            return GattServer::not_found(opcode::FIND_INFORMATION_REQ, start);
+        }
+        let data: &'b [u8] = data;
+        Ok(AttPdu::FindInformationRsp {
+            format: if uuid_len == 2 {
+                format::UUID_16
+            } else {
+                format::UUID_128
+            },
+            data: &data[..len],
+        })
+    }
+
+    fn find_by_type_value<'b>(
+        &self,
+        start: u16,
+        end: u16,
+        service_uuid: Uuid,
+        data: &'b mut [u8],
+    ) -> Result<AttPdu<'b>, (u8, u16, u8)> {
+        let mut len = 0;
+        for handle in start..GattServer::last_handle(end) + 1 {
+            match self.attribute(handle) {
+                Attribute::PrimaryService(uuid) if uuid == service_uuid => {}
+                _ => continue,
+            }
+            if len + 4 > data.len() {
+                break;
+            }
+            encode_u16_le(&mut data[len..], handle);
+            encode_u16_le(&mut data[len + 2..], self.group_end(handle));
+            len += 4;
+        }
+        if len == 0 {
+            return GattServer::not_found(opcode::FIND_BY_TYPE_VALUE_REQ, start);
+        }
+        let data: &'b [u8] = data;
+        Ok(AttPdu::FindByTypeValueRsp { data: &data[..len] })
+    }
+
+    fn read_by_type<'b>(
+        &self,
+        start: u16,
+        end: u16,
+        attribute_type: Uuid,
+        data: &'b mut [u8],
+    ) -> Result<AttPdu<'b>, (u8, u16, u8)> {
+        // The length takes up the first byte, and values are truncated to
+        // fit into the first entry
+        let max_len = data.len() - 1;
+        let mut value = [0; MAX_MTU];
+        let mut len = 0;
+        let mut entry_len = 0;
+        for handle in start..GattServer::last_handle(end) + 1 {
+            if self.attribute(handle).attribute_type() != Some(attribute_type) {
+                continue;
+            }
+            let value_len = match self.read_value(handle, 0, &mut value[..max_len - 2]) {
+                Ok(value_len) => value_len,
+                Err(error) => {
+                    if len == 0 {
+                        return Err((opcode::READ_BY_TYPE_REQ, handle, error));
+                    }
+                    break;
+                }
+            };
+            if entry_len == 0 {
+                entry_len = 2 + value_len;
+            }
+            if 2 + value_len != entry_len || len + entry_len > max_len {
+                break;
+            }
+            encode_u16_le(&mut data[len..], handle);
+            encode_bytes(&mut data[len + 2..], &value[..value_len]);
+            len += entry_len;
+        }
+        if len == 0 {
+            return GattServer::not_found(opcode::READ_BY_TYPE_REQ, start);
+        }
+        let data: &'b [u8] = data;
+        Ok(AttPdu::ReadByTypeRsp {
+            length: entry_len as u8,
+            data: &data[..len],
+        })
+    }
+
+    fn read_by_group_type<'b>(
+        &self,
+        start: u16,
+        end: u16,
+        data: &'b mut [u8],
+    ) -> Result<AttPdu<'b>, (u8, u16, u8)> {
+        // The length takes up the first byte
+        let max_len = data.len() - 1;
+        let mut len = 0;
+        let mut entry_len = 0;
+        for handle in start..GattServer::last_handle(end) + 1 {
+            let uuid = match self.attribute(handle) {
+                Attribute::PrimaryService(uuid) => uuid,
+                _ => continue,
+            };
+            if entry_len == 0 {
+                entry_len = 4 + uuid.len();
+            }
+            if 4 + uuid.len() != entry_len || len + entry_len > max_len {
+                break;
+            }
+            encode_u16_le(&mut data[len..], handle);
+            encode_u16_le(&mut data[len + 2..], self.group_end(handle));
+            uuid.encode(&mut data[len + 4..]);
+            len += entry_len;
+        }
+        if len == 0 {
+            return GattServer::not_found(opcode::READ_BY_GROUP_TYPE_REQ, start);
+        }
+        let data: &'b [u8] = data;
+        Ok(AttPdu::ReadByGroupTypeRsp {
+            length: entry_len as u8,
+            data: &data[..len],
+        })
+    }
+}
+
+impl<'a> AttBearerClient for GattServer<'a> {
+    fn connected(&self) {
+        self.connected.set(true);
+        self.mtu.set(DEFAULT_MTU);
+    }
+
+    fn receive(&self, pdu: &[u8]) {
+        if pdu.len() == 0 {
+            return;
+        }
+        let request = pdu[0];
+        let response = match AttPdu::decode(pdu) {
+            SResult::Done(_, pdu) => self.handle_pdu(pdu),
+            _ => Some(Response::Error {
+                request: request,
+                handle: 0,
+                error: error::INVALID_PDU,
+            }),
+        };
+        if opcode::is_command(request) {
+            return;
+        }
+        // The peer waits for the response before sending another request, so
+        // this does not overwrite a response that has not been sent yet
+        if response.is_some() {
+            self.response.set(response);
+            self.send_response();
+        }
+    }
+
+    fn send_done(&self, buf: &'static mut [u8], result: ReturnCode) {
+        self.tx_buf.replace(buf);
+        let sent = self.sending.get();
+        self.sending.set(None);
+        self.send_response();
+        sent.map(|(handle, indicate)| {
+            if indicate && result == ReturnCode::SUCCESS {
+                self.indicating.set(Some(handle));
+            } else {
+                self.client
+                    .get()
+                    .map(|client| client.notify_done(handle, result));
+            }
+        });
+    }
+
+    fn disconnected(&self) {
+        self.connected.set(false);
+        self.mtu.set(DEFAULT_MTU);
+        self.response.set(None);
+        self.indicating.get().map(|handle| {
+            self.indicating.set(None);
+            self.client
+                .get()
+                .map(|client| client.notify_done(handle, ReturnCode::FAIL));
+        });
+        for handle in 1..MAX_ATTRIBUTES as u16 + 1 {
+            if let Attribute::Configuration {
+                value_handle,
+                properties,
+                configuration,
+            } = self.attribute(handle)
+            {
+                if configuration == 0 {
+                    continue;
+                }
+                self.set_attribute(
+                    handle,
+                    Attribute::Configuration {
+                        value_handle: value_handle,
+                        properties: properties,
+                        configuration: 0,
+                    },
+                );
+                self.client
+                    .get()
+                    .map(|client| client.configuration_changed(value_handle, 0));
+            }
+        }
+    }
+}
@@ -0,0 +1,247 @@
+//! Carries ATT PDUs over the L2CAP channel `ATT_CID` of a connection of the
+//! BLE link layer.
+//!
+//! BLUETOOTH SPECIFICATION Version 4.2 [Vol 3, Part A], section 3.1 Connection-oriented
+//! Channels in Basic L2CAP Mode
+//!
+//! ```
+//!   PDU     +----------+      +------------+      +--------------------+
+//!           | Length   |  -   | Channel ID |  -   | Information        |
+//!           | (2 bytes)|      | (2 bytes)  |      | (0 to Length bytes)|
+//!           +----------+      +------------+      +--------------------+
+//! ```
+//!
+//! The `L2capBearer` is the `ConnectionClient` of the link layer. It splits
+//! the ATT PDUs it sends into data PDUs of up to `MAX_DATA_PAYLOAD_LENGTH`
+//! bytes, and reassembles the ATT PDUs it receives into its receive buffer,
+//! whose length bounds the ATT_MTU the peer may use. PDUs of other channels,
+//! such as the LE signaling channel, are dropped.
+//!
+//! The bearer restarts advertising when a connection is closed, so that the
+//! device stays connectable once advertising has been started.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let att_bearer = static_init!(
+//!     capsules::net::ble::l2cap::L2capBearer<'static, BleLinkLayer>,
+//!     capsules::net::ble::l2cap::L2capBearer::new(
+//!         ble_link_layer,
+//!         &mut capsules::net::ble::l2cap::TX_BUF,
+//!         &mut capsules::net::ble::l2cap::RX_BUF
+//!     )
+//! );
+//! ble_link_layer.set_client(att_bearer);
+//! ```
+
+use ble_link_layer_hil::{BleLinkLayer, ConnectionClient, ConnectionParameters, DataPduType,
+                         MAX_DATA_PAYLOAD_LENGTH};
+use core::cell::Cell;
+use core::cmp::min;
+use kernel::ReturnCode;
+use kernel::common::take_cell::TakeCell;
+use net::ble::att::{AttBearer, AttBearerClient, ATT_CID};
+use net::ble::gatt::MAX_MTU;
+use net::stream::{decode_u16_le, encode_u16_le};
+use net::stream::SResult;
+
+/// Length of the basic L2CAP header
+pub const HEADER_LENGTH: usize = 4;
+
+pub static mut TX_BUF: [u8; MAX_DATA_PAYLOAD_LENGTH] = [0; MAX_DATA_PAYLOAD_LENGTH];
+pub static mut RX_BUF: [u8; MAX_MTU] = [0; MAX_MTU];
+
+/// Encodes the basic L2CAP header of an ATT PDU of `len` bytes
+fn encode_header(buf: &mut [u8], len: u16) -> SResult {
+    let off = enc_consume!(buf; encode_u16_le, len);
+    let off = enc_consume!(buf, off; encode_u16_le, ATT_CID);
+    stream_done!(off);
+}
+
+/// Decodes the length and the channel ID of a basic L2CAP header
+fn decode_header(buf: &[u8]) -> SResult<(u16, u16)> {
+    let (off, len) = dec_try!(buf; decode_u16_le);
+    let (off, cid) = dec_try!(buf, off; decode_u16_le);
+    stream_done!(off, (len, cid));
+}
+
+pub struct L2capBearer<'a, L: BleLinkLayer + 'a> {
+    link_layer: &'a L,
+    client: Cell<Option<&'a AttBearerClient>>,
+
+    // Buffer of the data PDU being sent, held by the link layer meanwhile
+    tx_buf: TakeCell<'static, [u8]>,
+    // The ATT PDU being sent, its length, how much of it has been
+    // acknowledged and how much of it the data PDU being sent carries
+    tx_pdu: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    tx_sent: Cell<usize>,
+    tx_fragment_len: Cell<usize>,
+
+    // The ATT PDU being reassembled, its length and how much of it has been
+    // received. A length of zero means that the rest of the L2CAP PDU is
+    // dropped.
+    rx_buf: TakeCell<'static, [u8]>,
+    rx_len: Cell<usize>,
+    rx_received: Cell<usize>,
+}
+
+impl<'a, L: BleLinkLayer> L2capBearer<'a, L> {
+    /// `tx_buf` must hold at least `MAX_DATA_PAYLOAD_LENGTH` bytes, and
+    /// received ATT PDUs longer than `rx_buf` are dropped
+    pub fn new(
+        link_layer: &'a L,
+        tx_buf: &'static mut [u8],
+        rx_buf: &'static mut [u8],
+    ) -> L2capBearer<'a, L> {
+        L2capBearer {
+            link_layer: link_layer,
+            client: Cell::new(None),
+            tx_buf: TakeCell::new(tx_buf),
+            tx_pdu: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            tx_sent: Cell::new(0),
+            tx_fragment_len: Cell::new(0),
+            rx_buf: TakeCell::new(rx_buf),
+            rx_len: Cell::new(0),
+            rx_received: Cell::new(0),
+        }
+    }
+
+    /// Passes the next fragment of the ATT PDU being sent to the link layer
+    fn send_fragment(&self) -> ReturnCode {
+        let buf = match self.tx_buf.take() {
+            Some(buf) => buf,
+            None => return ReturnCode::EBUSY,
+        };
+        let sent = self.tx_sent.get();
+        let remaining = self.tx_len.get() - sent;
+        let (offset, pdu_type) = if sent == 0 {
+            if encode_header(buf, remaining as u16).done().is_none() {
+                self.tx_buf.replace(buf);
+                return ReturnCode::ESIZE;
+            }
+            (HEADER_LENGTH, DataPduType::Start)
+        } else {
+            (0, DataPduType::Continuation)
+        };
+        let fragment_len = min(remaining, MAX_DATA_PAYLOAD_LENGTH - offset);
+        self.tx_pdu.map(|pdu| {
+            buf[offset..offset + fragment_len].copy_from_slice(&pdu[sent..sent + fragment_len]);
+        });
+        self.tx_fragment_len.set(fragment_len);
+        let (result, buf) = self.link_layer
+            .transmit(buf, offset + fragment_len, pdu_type);
+        buf.map(|buf| self.tx_buf.replace(buf));
+        result
+    }
+
+    /// Returns the ATT PDU being sent to the client
+    fn send_done(&self, result: ReturnCode) {
+        self.tx_pdu.take().map(|pdu| {
+            self.client
+                .get()
+                .map(move |client| client.send_done(pdu, result));
+        });
+    }
+
+    /// Appends a fragment to the ATT PDU being reassembled, and passes the
+    /// PDU to the client once it is complete
+    fn reassemble(&self, fragment: &[u8]) {
+        let len = self.rx_len.get();
+        let received = self.rx_received.get();
+        if len == 0 || received + fragment.len() > len {
+            self.rx_len.set(0);
+            return;
+        }
+        self.rx_buf.map(|buf| {
+            buf[received..received + fragment.len()].copy_from_slice(fragment);
+            if received + fragment.len() == len {
+                self.client.get().map(|client| client.receive(&buf[..len]));
+            }
+        });
+        if received + fragment.len() == len {
+            self.rx_len.set(0);
+        } else {
+            self.rx_received.set(received + fragment.len());
+        }
+    }
+}
+
+impl<'a, L: BleLinkLayer> AttBearer<'a> for L2capBearer<'a, L> {
+    fn set_client(&self, client: &'a AttBearerClient) {
+        self.client.set(Some(client));
+    }
+
+    fn send(
+        &self,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if self.tx_pdu.is_some() {
+            return Err((ReturnCode::EBUSY, buf));
+        }
+        if len > buf.len() || len > u16::max_value() as usize {
+            return Err((ReturnCode::ESIZE, buf));
+        }
+        self.tx_pdu.replace(buf);
+        self.tx_len.set(len);
+        self.tx_sent.set(0);
+        match self.send_fragment() {
+            ReturnCode::SUCCESS => Ok(()),
+            result => self.tx_pdu.take().map_or(Ok(()), |buf| Err((result, buf))),
+        }
+    }
+}
+
+impl<'a, L: BleLinkLayer> ConnectionClient for L2capBearer<'a, L> {
+    fn connected(&self, _peer_address: [u8; 6], _parameters: ConnectionParameters) {
+        self.rx_len.set(0);
+        self.client.get().map(|client| client.connected());
+    }
+
+    fn parameters_updated(&self, _parameters: ConnectionParameters) {}
+
+    fn receive(&self, data: &[u8], pdu_type: DataPduType) {
+        if pdu_type == DataPduType::Continuation {
+            self.reassemble(data);
+            return;
+        }
+        // A new L2CAP PDU drops any incomplete one
+        self.rx_len.set(0);
+        let (len, cid) = match decode_header(data).done() {
+            Some((_, header)) => header,
+            None => return,
+        };
+        let len = len as usize;
+        if cid != ATT_CID || len == 0 || len > self.rx_buf.map_or(0, |buf| buf.len()) {
+            return;
+        }
+        self.rx_len.set(len);
+        self.rx_received.set(0);
+        self.reassemble(&data[HEADER_LENGTH..]);
+    }
+
+    fn transmit_done(&self, buf: &'static mut [u8], result: ReturnCode) {
+        self.tx_buf.replace(buf);
+        let sent = self.tx_sent.get() + self.tx_fragment_len.get();
+        self.tx_sent.set(sent);
+        if result != ReturnCode::SUCCESS || sent == self.tx_len.get() {
+            self.send_done(result);
+            return;
+        }
+        let result = self.send_fragment();
+        if result != ReturnCode::SUCCESS {
+            self.send_done(result);
+        }
+    }
+
+    fn disconnected(&self, _reason: u8) {
+        // The link layer returned the data PDU being sent, if any, and so the
+        // ATT PDU it was part of, with `transmit_done` before
+        self.rx_len.set(0);
+        self.client.get().map(|client| client.disconnected());
+        self.link_layer.start_advertising();
+    }
+}
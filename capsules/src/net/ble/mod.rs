@@ -0,0 +1,39 @@
+//! Bluetooth Low Energy host support: the attribute protocol and a GATT
+//! server.
+//!
+//! The modules other than `l2cap` do not depend on a radio, only on an
+//! `att::AttBearer` that carries ATT PDUs over a connection:
+//!
+//! - `att`: encoding and decoding of ATT PDUs and UUIDs, using the
+//!   `net::stream` helpers.
+//! - `gatt`: a GATT server, with a database of services, characteristics and
+//!   descriptors, that answers ATT requests and sends notifications and
+//!   indications.
+//! - `l2cap`: an `att::AttBearer` on top of a connection of the link layer
+//!   of `ble_link_layer`.
+//! - `driver`: a syscall driver that lets applications serve GATT services.
+//!
+//! ```
+//!   +--------------+ +------------------------+
+//!   | GattDriver   | | Kernel services (GAP)  |
+//!   +--------------+ +------------------------+
+//!           | GattClient          | Static values
+//!           v                     v
+//!         +---------------------------+
+//!         |        GattServer         |
+//!         +---------------------------+
+//!                      | AttBearer
+//!                      v
+//!         +---------------------------+
+//!         | L2CAP channel 0x0004 over |
+//!         | a BLE link layer          |
+//!         +---------------------------+
+//! ```
+
+pub mod att;
+pub mod gatt;
+pub mod l2cap;
+
+mod driver;
+
+pub use self::driver::*;
@@ -0,0 +1,271 @@
+//! CoAP userspace interface for serving application resources.
+//!
+//! Each application can register one resource at a Uri-Path of its choice.
+//! Requests for that path are passed to the application through a callback,
+//! and the application answers with a response code and payload. The driver
+//! itself is registered as a single `CoapResource` with the `CoapServer`, and
+//! dispatches requests to applications by path.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let coap_driver = static_init!(
+//!     capsules::net::coap::CoapDriver<'static>,
+//!     capsules::net::coap::CoapDriver::new(coap_server, kernel::Grant::create())
+//! );
+//! coap_server.add_resource(coap_driver);
+//! ```
+
+use core::cmp::min;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+use net::coap::message::{code, Message};
+use net::coap::server::{CoapResource, CoapServer};
+
+/// Syscall number
+pub const DRIVER_NUM: usize = 0x30003;
+
+/// The longest Uri-Path an app can register, in bytes
+pub const MAX_PATH_LEN: usize = 64;
+
+#[derive(Default)]
+pub struct App {
+    request_callback: Option<Callback>,
+    app_path: Option<AppSlice<Shared, u8>>,
+    app_read: Option<AppSlice<Shared, u8>>,
+    app_write: Option<AppSlice<Shared, u8>>,
+    registered: bool,
+    pending_request: Option<usize>,
+}
+
+impl App {
+    fn serves(&self, request: &Message) -> bool {
+        self.registered
+            && self.app_path
+                .as_ref()
+                .map_or(false, |path| request.uri_path_matches(path.as_ref()))
+    }
+}
+
+pub struct CoapDriver<'a> {
+    server: &'a CoapServer<'a>,
+    apps: Grant<App>,
+}
+
+impl<'a> CoapDriver<'a> {
+    pub fn new(server: &'a CoapServer<'a>, grant: Grant<App>) -> CoapDriver<'a> {
+        CoapDriver {
+            server: server,
+            apps: grant,
+        }
+    }
+
+    /// Utility function to perform an action on an app in a system call.
+    #[inline]
+    fn do_with_app<F>(&self, appid: AppId, closure: F) -> ReturnCode
+    where
+        F: FnOnce(&mut App) -> ReturnCode,
+    {
+        self.apps
+            .enter(appid, |app, _| closure(app))
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Checks whether another app has registered a resource at the path
+    /// currently allowed by `appid`.
+    fn path_in_use(&self, appid: AppId) -> bool {
+        let mut path = [0u8; MAX_PATH_LEN];
+        let path_len = self.apps
+            .enter(appid, |app, _| {
+                app.app_path.as_ref().map_or(0, |app_path| {
+                    let len = min(path.len(), app_path.len());
+                    path[..len].copy_from_slice(&app_path.as_ref()[..len]);
+                    len
+                })
+            })
+            .unwrap_or(0);
+
+        let mut in_use = false;
+        for app in self.apps.iter() {
+            app.enter(|app, _| {
+                if app.appid() != appid && app.registered {
+                    in_use = in_use || app.app_path.as_ref().map_or(false, |other| {
+                        other.as_ref() == &path[..path_len]
+                    });
+                }
+            });
+        }
+        in_use
+    }
+
+    fn register(&self, appid: AppId) -> ReturnCode {
+        let path_len = self.apps
+            .enter(appid, |app, _| {
+                app.app_path.as_ref().map_or(0, |path| path.len())
+            })
+            .unwrap_or(0);
+        if path_len == 0 {
+            return ReturnCode::EINVAL;
+        } else if path_len > MAX_PATH_LEN {
+            return ReturnCode::ESIZE;
+        }
+        if self.path_in_use(appid) {
+            return ReturnCode::EALREADY;
+        }
+        self.do_with_app(appid, |app| {
+            app.registered = true;
+            ReturnCode::SUCCESS
+        })
+    }
+
+    fn unregister(&self, appid: AppId) -> ReturnCode {
+        self.do_with_app(appid, |app| {
+            app.registered = false;
+            // Answer any request the app has not responded to yet, so that
+            // the server releases it
+            app.pending_request.take().map(|request_id| {
+                self.server
+                    .respond(request_id, code::SERVICE_UNAVAILABLE, None, &[])
+            });
+            ReturnCode::SUCCESS
+        })
+    }
+
+    fn respond(&self, appid: AppId, request_id: usize, code: u8, len: usize) -> ReturnCode {
+        // Only success, client error and server error codes can be sent
+        match code::class(code) {
+            2 | 4 | 5 => {}
+            _ => return ReturnCode::EINVAL,
+        }
+        self.do_with_app(appid, |app| {
+            if app.pending_request != Some(request_id) {
+                return ReturnCode::EINVAL;
+            }
+            let payload: &[u8] = match app.app_write {
+                Some(ref slice) => {
+                    if len > slice.len() {
+                        return ReturnCode::ESIZE;
+                    }
+                    &slice.as_ref()[..len]
+                }
+                None => {
+                    if len > 0 {
+                        return ReturnCode::EINVAL;
+                    }
+                    &[]
+                }
+            };
+            app.pending_request = None;
+            self.server.respond(request_id, code, None, payload)
+        })
+    }
+}
+
+impl<'a> CoapResource for CoapDriver<'a> {
+    fn matches(&self, request: &Message) -> bool {
+        let mut matches = false;
+        for app in self.apps.iter() {
+            app.enter(|app, _| {
+                matches = matches || app.serves(request);
+            });
+        }
+        matches
+    }
+
+    fn handle_request(&self, request_id: usize, request: &Message) -> ReturnCode {
+        let mut result = ReturnCode::EINVAL;
+        for app in self.apps.iter() {
+            app.enter(|app, _| {
+                if result != ReturnCode::EINVAL || !app.serves(request) {
+                    return;
+                }
+                if app.pending_request.is_some() {
+                    result = ReturnCode::EBUSY;
+                    return;
+                }
+                let payload_len = app.app_read.as_mut().map_or(0, |rbuf| {
+                    let len = min(rbuf.len(), request.payload.len());
+                    rbuf.as_mut()[..len].copy_from_slice(&request.payload[..len]);
+                    len
+                });
+                app.pending_request = Some(request_id);
+                result = app.request_callback
+                    .map_or(ReturnCode::FAIL, |mut cb| {
+                        cb.schedule(request_id, request.header.code as usize, payload_len);
+                        ReturnCode::SUCCESS
+                    });
+                if result != ReturnCode::SUCCESS {
+                    app.pending_request = None;
+                }
+            });
+        }
+        result
+    }
+}
+
+impl<'a> Driver for CoapDriver<'a> {
+    /// Setup buffers to read/write from.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Path buffer. Contains the Uri-Path of the app's resource, with
+    ///        segments separated by `/` (e.g. `led/state`).
+    /// - `1`: Read buffer. Will contain the payload of a received request,
+    ///        truncated to the length of the buffer.
+    /// - `2`: Write buffer. Contains the payload of the response.
+    fn allow(&self, appid: AppId, allow_num: usize, slice: AppSlice<Shared, u8>) -> ReturnCode {
+        match allow_num {
+            0 | 1 | 2 => self.do_with_app(appid, |app| {
+                match allow_num {
+                    0 => app.app_path = Some(slice),
+                    1 => app.app_read = Some(slice),
+                    2 => app.app_write = Some(slice),
+                    _ => {}
+                }
+                ReturnCode::SUCCESS
+            }),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Setup callback for when a request is received. The callback
+    ///        signature is `fn(request_id, method, payload_len)`, where `method`
+    ///        is the CoAP method code (1 = GET, 2 = POST, 3 = PUT, 4 = DELETE).
+    fn subscribe(&self, subscribe_num: usize, callback: Callback) -> ReturnCode {
+        match subscribe_num {
+            0 => self.do_with_app(callback.app_id(), |app| {
+                app.request_callback = Some(callback);
+                ReturnCode::SUCCESS
+            }),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// CoAP resource control.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Register a resource at the path in the path buffer. Returns
+    ///        EALREADY if another app serves the same path, and ESIZE if the
+    ///        path is longer than `MAX_PATH_LEN`.
+    /// - `2`: Unregister the app's resource.
+    /// - `3`: Respond to the request with ID `arg1`. The lower 8 bits of `arg2`
+    ///        are the response code (class << 5 | detail, e.g. 0x45 = 2.05
+    ///        Content), and the remaining bits are the length of the payload in
+    ///        the write buffer. Returns EINVAL if the code is not of class 2,
+    ///        4 or 5, and ESIZE if the payload is longer than the buffer.
+    fn command(&self, command_num: usize, arg1: usize, arg2: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self.register(appid),
+            2 => self.unregister(appid),
+            3 => self.respond(appid, arg1, (arg2 & 0xff) as u8, arg2 >> 8),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
@@ -0,0 +1,746 @@
+//! Implements the CoAP messaging layer (RFC 7252, Section 4) on top of a
+//! datagram transport.
+//!
+//! The messaging layer sits between a transport (usually UDP) and the
+//! request/response layer. It is responsible for:
+//!
+//! - Reliable delivery of confirmable (CON) messages: each outgoing CON is
+//!   retransmitted with exponential back-off until it is acknowledged or reset
+//!   by the peer, or until `MAX_RETRANSMIT` retransmissions have been made.
+//! - Duplicate detection: the message IDs of received CON and NON messages are
+//!   remembered for `EXCHANGE_LIFETIME_S` seconds, and duplicates are not
+//!   passed up to the client again.
+//! - Acknowledging received CON requests. A response sent soon enough is
+//!   piggybacked on the acknowledgement. Otherwise an empty acknowledgement is
+//!   sent after `PIGGYBACK_TIMEOUT_MS`, and the response is later sent as a
+//!   separate CON message.
+//!
+//! All timing is driven by a single alarm, which should be a virtual alarm
+//! since the layer keeps it armed while any exchange is in progress.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! static mut COAP_EMPTY_BUF: [u8; capsules::net::coap::message::HEADER_SIZE] =
+//!     [0; capsules::net::coap::message::HEADER_SIZE];
+//!
+//! let coap_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let coap_layer = static_init!(
+//!     capsules::net::coap::layer::CoapLayer<'static,
+//!         VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     capsules::net::coap::layer::CoapLayer::new(
+//!         udp_transport, coap_alarm, &mut COAP_EMPTY_BUF, initial_message_id)
+//! );
+//! coap_alarm.set_client(coap_layer);
+//! udp_transport.set_client(coap_layer);
+//! ```
+
+use core::cell::Cell;
+use kernel::ReturnCode;
+use kernel::common::take_cell::{MapCell, TakeCell};
+use kernel::hil::time::{self, Alarm, Frequency};
+use net::coap::message::{self, code, CoapHeader, Message, MessageType};
+use net::ip::IPAddr;
+
+// Transmission parameters (RFC 7252, Section 4.8)
+const ACK_TIMEOUT_S: u32 = 2;
+const MAX_RETRANSMIT: u8 = 4;
+const EXCHANGE_LIFETIME_S: u32 = 247;
+
+// How long a received confirmable request waits for its response to be
+// piggybacked on the acknowledgement before an empty acknowledgement is sent
+// instead. This must be well below ACK_TIMEOUT so the peer does not
+// retransmit.
+const PIGGYBACK_TIMEOUT_MS: u32 = 500;
+
+// Number of outgoing messages that can be in progress at the same time
+const MAX_EXCHANGES: usize = 4;
+// Number of received message IDs remembered for duplicate detection
+const MAX_RECEIVED: usize = 8;
+
+/// A transport-level address, i.e. an IPv6 address and a UDP port
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Endpoint {
+    pub addr: IPAddr,
+    pub port: u16,
+}
+
+impl Endpoint {
+    pub fn new(addr: IPAddr, port: u16) -> Endpoint {
+        Endpoint {
+            addr: addr,
+            port: port,
+        }
+    }
+}
+
+impl Default for Endpoint {
+    fn default() -> Endpoint {
+        Endpoint::new(IPAddr::new(), 0)
+    }
+}
+
+/// The datagram service the messaging layer runs on. Only one datagram is
+/// passed to the transport at a time.
+pub trait Transport<'a> {
+    fn set_client(&self, client: &'a TransportClient);
+
+    /// Sends the first `len` bytes of `buf` to `dst`. The buffer is returned
+    /// in `TransportClient::send_done`, or immediately on error.
+    fn send_to(
+        &self,
+        dst: Endpoint,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])>;
+}
+
+pub trait TransportClient {
+    fn receive(&self, src: Endpoint, buf: &[u8]);
+    fn send_done(&self, buf: &'static mut [u8], result: ReturnCode);
+}
+
+/// Implemented by the user of the messaging layer, usually a `CoapServer`.
+pub trait CoapClient {
+    /// Called for each request or response that is not a duplicate.
+    /// Piggybacked responses are delivered after the corresponding
+    /// confirmable message has completed.
+    fn receive(&self, src: Endpoint, message: &Message);
+
+    /// Called when a message passed to `send` or `send_response` is done.
+    /// For a confirmable message, `result` is `SUCCESS` if it was
+    /// acknowledged, `FAIL` if it was reset by the peer, and `ENOACK` if all
+    /// retransmissions timed out.
+    fn send_done(&self, buf: &'static mut [u8], result: ReturnCode);
+}
+
+/// The interface of the messaging layer used by the request/response layer
+pub trait MessageLayer<'a> {
+    fn set_client(&self, client: &'a CoapClient);
+
+    /// Allocates a fresh message ID for a new CON or NON message
+    fn next_message_id(&self) -> u16;
+
+    /// Sends an encoded message. The message type and message ID in the
+    /// header are used as-is.
+    fn send(
+        &self,
+        dst: Endpoint,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])>;
+
+    /// Sends an encoded response to the request with `request_id`. The
+    /// message type and message ID in the header are overwritten: the response
+    /// is piggybacked on the acknowledgement if the request has not been
+    /// acknowledged yet, and sent as a separate message otherwise.
+    fn send_response(
+        &self,
+        dst: Endpoint,
+        request_id: u16,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])>;
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum ExchangeState {
+    Free,
+    // Waiting for the transport to become available
+    Queued,
+    // The buffer is with the transport
+    Sending,
+    // A confirmable message was sent and is waiting for an ACK or RST
+    AwaitingAck,
+    // An ACK or RST arrived while a retransmission was being sent
+    Finished(ReturnCode),
+}
+
+/// An outgoing message, along with its retransmission state
+struct Exchange {
+    state: Cell<ExchangeState>,
+    buf: TakeCell<'static, [u8]>,
+    len: Cell<usize>,
+    dst: Cell<Endpoint>,
+    message_id: Cell<u16>,
+    confirmable: Cell<bool>,
+    retransmissions: Cell<u8>,
+    timeout: Cell<u32>,
+    sent_at: Cell<u32>,
+}
+
+impl Exchange {
+    fn new() -> Exchange {
+        Exchange {
+            state: Cell::new(ExchangeState::Free),
+            buf: TakeCell::empty(),
+            len: Cell::new(0),
+            dst: Cell::new(Endpoint::default()),
+            message_id: Cell::new(0),
+            confirmable: Cell::new(false),
+            retransmissions: Cell::new(0),
+            timeout: Cell::new(0),
+            sent_at: Cell::new(0),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum ReceivedState {
+    Free,
+    // Passed to the client, which has not responded yet
+    Processing,
+    // An empty ACK needs to be sent
+    AckQueued,
+    // An empty ACK was sent; any response will be a separate message
+    Acked,
+    // A response was piggybacked on the ACK, or no ACK was needed
+    Responded,
+    // A RST needs to be sent
+    RstQueued,
+    // A RST was sent
+    Rejected,
+}
+
+/// A received message ID, remembered for duplicate detection
+#[derive(Copy, Clone)]
+struct ReceivedMessage {
+    state: ReceivedState,
+    src: Endpoint,
+    message_id: u16,
+    confirmable: bool,
+    idempotent: bool,
+    received_at: u32,
+}
+
+impl Default for ReceivedMessage {
+    fn default() -> ReceivedMessage {
+        ReceivedMessage {
+            state: ReceivedState::Free,
+            src: Endpoint::default(),
+            message_id: 0,
+            confirmable: false,
+            idempotent: false,
+            received_at: 0,
+        }
+    }
+}
+
+// Returns the earlier of `next` and the time remaining until `duration` tics
+// after `start`
+fn earliest(next: Option<u32>, now: u32, start: u32, duration: u32) -> Option<u32> {
+    let remaining = duration.saturating_sub(now.wrapping_sub(start));
+    match next {
+        Some(next) if next < remaining => Some(next),
+        _ => Some(remaining),
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum TxSource {
+    Exchange(usize),
+    Empty,
+}
+
+pub struct CoapLayer<'a, A: Alarm + 'a> {
+    transport: &'a Transport<'a>,
+    alarm: &'a A,
+    client: Cell<Option<&'a CoapClient>>,
+
+    exchanges: [Exchange; MAX_EXCHANGES],
+    received: MapCell<[ReceivedMessage; MAX_RECEIVED]>,
+
+    // Buffer used for empty ACK and RST messages
+    empty_buf: TakeCell<'static, [u8]>,
+    tx_source: Cell<Option<TxSource>>,
+    message_id: Cell<u16>,
+}
+
+impl<'a, A: Alarm + 'a> CoapLayer<'a, A> {
+    /// Creates a new `CoapLayer`
+    ///
+    /// # Arguments
+    ///
+    /// * `empty_buf` - A buffer of at least `message::HEADER_SIZE` bytes, used
+    /// for sending empty acknowledgements and resets.
+    ///
+    /// * `initial_message_id` - The first message ID to use. This should be
+    /// randomized so that message IDs are not reused after a reboot.
+    pub fn new(
+        transport: &'a Transport<'a>,
+        alarm: &'a A,
+        empty_buf: &'static mut [u8],
+        initial_message_id: u16,
+    ) -> CoapLayer<'a, A> {
+        CoapLayer {
+            transport: transport,
+            alarm: alarm,
+            client: Cell::new(None),
+            exchanges: [
+                Exchange::new(),
+                Exchange::new(),
+                Exchange::new(),
+                Exchange::new(),
+            ],
+            received: MapCell::new(Default::default()),
+            empty_buf: TakeCell::new(empty_buf),
+            tx_source: Cell::new(None),
+            message_id: Cell::new(initial_message_id),
+        }
+    }
+
+    fn ms_to_tics(ms: u32) -> u32 {
+        let freq = A::Frequency::frequency();
+        (freq / 1000) * ms + ((freq % 1000) * ms) / 1000
+    }
+
+    // Picks the initial retransmission timeout uniformly between ACK_TIMEOUT
+    // and ACK_TIMEOUT * ACK_RANDOM_FACTOR (1.5), using the low bits of the
+    // clock as a cheap source of jitter.
+    fn initial_timeout(&self) -> u32 {
+        let base = ACK_TIMEOUT_S * A::Frequency::frequency();
+        base + (base / 2 / 256) * (self.alarm.now() & 0xff)
+    }
+
+    fn enqueue(
+        &self,
+        dst: Endpoint,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        let mtype = MessageType::from_bits(buf[0] >> 4);
+        let message_id = (buf[2] as u16) << 8 | (buf[3] as u16);
+        match self.exchanges
+            .iter()
+            .find(|ex| ex.state.get() == ExchangeState::Free)
+        {
+            None => Err((ReturnCode::EBUSY, buf)),
+            Some(ex) => {
+                ex.buf.replace(buf);
+                ex.len.set(len);
+                ex.dst.set(dst);
+                ex.message_id.set(message_id);
+                ex.confirmable.set(mtype == MessageType::Confirmable);
+                ex.retransmissions.set(0);
+                ex.timeout.set(self.initial_timeout());
+                ex.state.set(ExchangeState::Queued);
+                self.send_next();
+                Ok(())
+            }
+        }
+    }
+
+    fn finish_exchange(&self, index: usize, result: ReturnCode) {
+        let ex = &self.exchanges[index];
+        ex.state.set(ExchangeState::Free);
+        ex.buf.take().map(|buf| {
+            self.client.get().map(move |client| client.send_done(buf, result));
+        });
+    }
+
+    // Passes the next pending message to the transport, if it is idle. Empty
+    // acknowledgements and resets are sent first, since the peer is already
+    // waiting for them.
+    fn send_next(&self) {
+        if self.tx_source.get().is_some() {
+            return;
+        }
+
+        if self.empty_buf.is_some() {
+            let empty = self.received.and_then(|received| {
+                received
+                    .iter_mut()
+                    .find(|r| {
+                        r.state == ReceivedState::AckQueued || r.state == ReceivedState::RstQueued
+                    })
+                    .map(|r| {
+                        let mtype = if r.state == ReceivedState::AckQueued {
+                            r.state = ReceivedState::Acked;
+                            MessageType::Acknowledgement
+                        } else {
+                            r.state = ReceivedState::Rejected;
+                            MessageType::Reset
+                        };
+                        (r.src, mtype, r.message_id)
+                    })
+            });
+            if let Some((dst, mtype, message_id)) = empty {
+                let buf = self.empty_buf
+                    .take()
+                    .expect("Error: `empty_buf` is None in call to send_next.");
+                let _ = CoapHeader::empty(mtype, message_id).encode(buf);
+                match self.transport.send_to(dst, buf, message::HEADER_SIZE) {
+                    Ok(()) => {
+                        self.tx_source.set(Some(TxSource::Empty));
+                        return;
+                    }
+                    Err((_, buf)) => {
+                        // The peer will retransmit and trigger another attempt
+                        self.empty_buf.replace(buf);
+                    }
+                }
+            }
+        }
+
+        for (index, ex) in self.exchanges.iter().enumerate() {
+            if ex.state.get() != ExchangeState::Queued {
+                continue;
+            }
+            let buf = match ex.buf.take() {
+                Some(buf) => buf,
+                None => continue,
+            };
+            match self.transport.send_to(ex.dst.get(), buf, ex.len.get()) {
+                Ok(()) => {
+                    ex.state.set(ExchangeState::Sending);
+                    self.tx_source.set(Some(TxSource::Exchange(index)));
+                    return;
+                }
+                Err((result, buf)) => {
+                    ex.buf.replace(buf);
+                    self.finish_exchange(index, result);
+                    // The client may have sent another message in its callback
+                    if self.tx_source.get().is_some() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    // Arms the alarm for the earliest retransmission or piggyback deadline,
+    // or disables it if nothing is pending.
+    fn schedule_alarm(&self) {
+        let now = self.alarm.now();
+        let mut next: Option<u32> = None;
+
+        for ex in self.exchanges.iter() {
+            if ex.state.get() == ExchangeState::AwaitingAck {
+                next = earliest(next, now, ex.sent_at.get(), ex.timeout.get());
+            }
+        }
+        let piggyback_timeout = Self::ms_to_tics(PIGGYBACK_TIMEOUT_MS);
+        self.received.map(|received| {
+            for r in received.iter() {
+                if r.state == ReceivedState::Processing && r.confirmable {
+                    next = earliest(next, now, r.received_at, piggyback_timeout);
+                }
+            }
+        });
+
+        match next {
+            Some(remaining) => {
+                // Avoid setting an alarm in the past
+                let remaining = if remaining == 0 { 1 } else { remaining };
+                self.alarm.set_alarm(now.wrapping_add(remaining));
+            }
+            None => {
+                if self.alarm.is_armed() {
+                    self.alarm.disable();
+                }
+            }
+        }
+    }
+
+    // Remembers a received message ID, replacing an expired entry or the
+    // oldest one if the table is full.
+    fn remember(&self, src: Endpoint, header: &CoapHeader, state: ReceivedState) {
+        let now = self.alarm.now();
+        self.received.map(|received| {
+            let mut slot = 0;
+            let mut oldest_age = 0;
+            for (i, r) in received.iter().enumerate() {
+                if r.state == ReceivedState::Free {
+                    slot = i;
+                    break;
+                }
+                let age = now.wrapping_sub(r.received_at);
+                if age >= oldest_age {
+                    oldest_age = age;
+                    slot = i;
+                }
+            }
+            received[slot] = ReceivedMessage {
+                state: state,
+                src: src,
+                message_id: header.message_id,
+                confirmable: header.mtype == MessageType::Confirmable,
+                idempotent: code::is_idempotent(header.code),
+                received_at: now,
+            };
+        });
+    }
+
+    // Returns `None` if the message is new, and otherwise whether the
+    // duplicate should be delivered to the client again. Duplicates of
+    // idempotent requests whose response was piggybacked are processed again
+    // (RFC 7252, Section 4.5), since the response is not stored.
+    fn check_duplicate(&self, src: Endpoint, message_id: u16) -> Option<bool> {
+        let now = self.alarm.now();
+        let lifetime = EXCHANGE_LIFETIME_S * A::Frequency::frequency();
+        self.received.and_then(|received| {
+            received
+                .iter_mut()
+                .find(|r| {
+                    r.state != ReceivedState::Free && r.src == src && r.message_id == message_id
+                        && now.wrapping_sub(r.received_at) < lifetime
+                })
+                .map(|r| {
+                    if !r.confirmable {
+                        return false;
+                    }
+                    match r.state {
+                        ReceivedState::Acked => {
+                            r.state = ReceivedState::AckQueued;
+                            false
+                        }
+                        ReceivedState::Rejected => {
+                            r.state = ReceivedState::RstQueued;
+                            false
+                        }
+                        ReceivedState::Responded => {
+                            if r.idempotent {
+                                r.state = ReceivedState::Processing;
+                                r.received_at = now;
+                                true
+                            } else {
+                                r.state = ReceivedState::AckQueued;
+                                false
+                            }
+                        }
+                        _ => false,
+                    }
+                })
+        })
+    }
+
+    fn receive_reply(&self, src: Endpoint, message: &Message) {
+        let acked = message.header.mtype == MessageType::Acknowledgement;
+        let index = self.exchanges.iter().position(|ex| {
+            ex.state.get() != ExchangeState::Free && ex.confirmable.get()
+                && ex.message_id.get() == message.header.message_id && ex.dst.get() == src
+        });
+        // Replies that do not match an outstanding exchange are duplicates or
+        // unsolicited, and are silently ignored
+        let index = match index {
+            Some(index) => index,
+            None => return,
+        };
+        let result = if acked {
+            ReturnCode::SUCCESS
+        } else {
+            ReturnCode::FAIL
+        };
+        if self.exchanges[index].state.get() == ExchangeState::Sending {
+            self.exchanges[index]
+                .state
+                .set(ExchangeState::Finished(result));
+        } else {
+            self.finish_exchange(index, result);
+        }
+
+        if acked && !message.header.is_empty() {
+            self.client
+                .get()
+                .map(|client| client.receive(src, message));
+        }
+    }
+
+    fn receive_message(&self, src: Endpoint, message: &Message) {
+        let confirmable = message.header.mtype == MessageType::Confirmable;
+
+        // An empty confirmable message is a ping, which is answered with a
+        // reset. Empty non-confirmable messages are invalid and ignored.
+        if message.header.is_empty() {
+            if confirmable {
+                self.remember(src, &message.header, ReceivedState::RstQueued);
+            }
+            return;
+        }
+
+        match self.check_duplicate(src, message.header.message_id) {
+            Some(false) => return,
+            Some(true) => {}
+            None => {
+                let state = if !confirmable {
+                    ReceivedState::Responded
+                } else if code::is_response(message.header.code) {
+                    // Separate responses are acknowledged immediately
+                    ReceivedState::AckQueued
+                } else {
+                    ReceivedState::Processing
+                };
+                self.remember(src, &message.header, state);
+            }
+        }
+
+        self.client
+            .get()
+            .map(|client| client.receive(src, message));
+    }
+}
+
+impl<'a, A: Alarm + 'a> MessageLayer<'a> for CoapLayer<'a, A> {
+    fn set_client(&self, client: &'a CoapClient) {
+        self.client.set(Some(client));
+    }
+
+    fn next_message_id(&self) -> u16 {
+        let message_id = self.message_id.get();
+        self.message_id.set(message_id.wrapping_add(1));
+        message_id
+    }
+
+    fn send(
+        &self,
+        dst: Endpoint,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if len < message::HEADER_SIZE || len > buf.len() {
+            return Err((ReturnCode::EINVAL, buf));
+        }
+        self.enqueue(dst, buf, len)
+    }
+
+    fn send_response(
+        &self,
+        dst: Endpoint,
+        request_id: u16,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if len < message::HEADER_SIZE || len > buf.len() {
+            return Err((ReturnCode::EINVAL, buf));
+        }
+        let mtype = self.received
+            .and_then(|received| {
+                received
+                    .iter_mut()
+                    .find(|r| {
+                        r.state != ReceivedState::Free && r.src == dst && r.message_id == request_id
+                    })
+                    .map(|r| {
+                        if !r.confirmable {
+                            MessageType::NonConfirmable
+                        } else if r.state == ReceivedState::Processing
+                            || r.state == ReceivedState::AckQueued
+                        {
+                            r.state = ReceivedState::Responded;
+                            MessageType::Acknowledgement
+                        } else {
+                            MessageType::Confirmable
+                        }
+                    })
+            })
+            // If the request has been forgotten, reliably send a separate
+            // response
+            .unwrap_or(MessageType::Confirmable);
+
+        let message_id = if mtype == MessageType::Acknowledgement {
+            request_id
+        } else {
+            self.next_message_id()
+        };
+        message::rewrite_type_and_id(buf, mtype, message_id);
+        let result = self.enqueue(dst, buf, len);
+        self.schedule_alarm();
+        result
+    }
+}
+
+impl<'a, A: Alarm + 'a> TransportClient for CoapLayer<'a, A> {
+    fn receive(&self, src: Endpoint, buf: &[u8]) {
+        match Message::decode(buf).done() {
+            Some((_, message)) => match message.header.mtype {
+                MessageType::Acknowledgement | MessageType::Reset => {
+                    self.receive_reply(src, &message)
+                }
+                MessageType::Confirmable | MessageType::NonConfirmable => {
+                    self.receive_message(src, &message)
+                }
+            },
+            None => {
+                // Confirmable messages with a format error are rejected with a
+                // reset if the message ID can be recovered (RFC 7252, 4.2)
+                if buf.len() >= message::HEADER_SIZE && buf[0] >> 6 == message::COAP_VERSION
+                    && MessageType::from_bits(buf[0] >> 4) == MessageType::Confirmable
+                {
+                    let message_id = (buf[2] as u16) << 8 | (buf[3] as u16);
+                    let header = CoapHeader::empty(MessageType::Confirmable, message_id);
+                    self.remember(src, &header, ReceivedState::RstQueued);
+                }
+            }
+        }
+        self.send_next();
+        self.schedule_alarm();
+    }
+
+    fn send_done(&self, buf: &'static mut [u8], result: ReturnCode) {
+        let source = self.tx_source.get();
+        self.tx_source.set(None);
+        match source {
+            Some(TxSource::Empty) | None => {
+                self.empty_buf.replace(buf);
+            }
+            Some(TxSource::Exchange(index)) => {
+                let ex = &self.exchanges[index];
+                ex.buf.replace(buf);
+                match ex.state.get() {
+                    ExchangeState::Finished(reply) => self.finish_exchange(index, reply),
+                    _ => {
+                        if result != ReturnCode::SUCCESS {
+                            self.finish_exchange(index, result);
+                        } else if ex.confirmable.get() {
+                            ex.sent_at.set(self.alarm.now());
+                            ex.state.set(ExchangeState::AwaitingAck);
+                        } else {
+                            self.finish_exchange(index, ReturnCode::SUCCESS);
+                        }
+                    }
+                }
+            }
+        }
+        self.send_next();
+        self.schedule_alarm();
+    }
+}
+
+impl<'a, A: Alarm + 'a> time::Client for CoapLayer<'a, A> {
+    fn fired(&self) {
+        let now = self.alarm.now();
+
+        for (index, ex) in self.exchanges.iter().enumerate() {
+            if ex.state.get() != ExchangeState::AwaitingAck
+                || now.wrapping_sub(ex.sent_at.get()) < ex.timeout.get()
+            {
+                continue;
+            }
+            if ex.retransmissions.get() >= MAX_RETRANSMIT {
+                self.finish_exchange(index, ReturnCode::ENOACK);
+            } else {
+                ex.retransmissions.set(ex.retransmissions.get() + 1);
+                ex.timeout.set(ex.timeout.get() * 2);
+                ex.state.set(ExchangeState::Queued);
+            }
+        }
+
+        let piggyback_timeout = Self::ms_to_tics(PIGGYBACK_TIMEOUT_MS);
+        self.received.map(|received| {
+            for r in received.iter_mut() {
+                if r.state == ReceivedState::Processing && r.confirmable
+                    && now.wrapping_sub(r.received_at) >= piggyback_timeout
+                {
+                    r.state = ReceivedState::AckQueued;
+                }
+            }
+        });
+
+        self.send_next();
+        self.schedule_alarm();
+    }
+}
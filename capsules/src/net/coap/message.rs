@@ -0,0 +1,500 @@
+//! Implements CoAP (RFC 7252) message encoding and decoding.
+//!
+//! A CoAP message consists of a fixed 4-byte header, a token of 0 to 8 bytes,
+//! a sequence of options and an optional payload:
+//!
+//! ```
+//!  0                   1                   2                   3
+//!  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |Ver| T |  TKL  |      Code     |          Message ID           |
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |   Token (if any, TKL bytes) ...
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |   Options (if any) ...
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |1 1 1 1 1 1 1 1|    Payload (if any) ...
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! ```
+//!
+//! Options are delta-encoded with respect to the number of the previous
+//! option, so they must be encoded in increasing order of option number. The
+//! `OptionWriter` keeps track of this when encoding, while decoding is done
+//! lazily through the `OptionIter` returned by `Message::options`.
+
+use net::stream::{decode_u16, decode_u8, encode_bytes, encode_u16, encode_u8};
+use net::stream::SResult;
+
+pub const COAP_VERSION: u8 = 1;
+pub const HEADER_SIZE: usize = 4;
+pub const MAX_TOKEN_LEN: usize = 8;
+pub const PAYLOAD_MARKER: u8 = 0xff;
+
+/// Request methods and response codes, encoded as `class << 5 | detail`
+pub mod code {
+    pub const EMPTY: u8 = 0x00;
+
+    // Requests (0.xx)
+    pub const GET: u8 = 0x01;
+    pub const POST: u8 = 0x02;
+    pub const PUT: u8 = 0x03;
+    pub const DELETE: u8 = 0x04;
+
+    // Success (2.xx)
+    pub const CREATED: u8 = 0x41;
+    pub const DELETED: u8 = 0x42;
+    pub const VALID: u8 = 0x43;
+    pub const CHANGED: u8 = 0x44;
+    pub const CONTENT: u8 = 0x45;
+
+    // Client errors (4.xx)
+    pub const BAD_REQUEST: u8 = 0x80;
+    pub const UNAUTHORIZED: u8 = 0x81;
+    pub const BAD_OPTION: u8 = 0x82;
+    pub const FORBIDDEN: u8 = 0x83;
+    pub const NOT_FOUND: u8 = 0x84;
+    pub const METHOD_NOT_ALLOWED: u8 = 0x85;
+    pub const NOT_ACCEPTABLE: u8 = 0x86;
+    pub const PRECONDITION_FAILED: u8 = 0x8c;
+    pub const REQUEST_ENTITY_TOO_LARGE: u8 = 0x8d;
+    pub const UNSUPPORTED_CONTENT_FORMAT: u8 = 0x8f;
+
+    // Server errors (5.xx)
+    pub const INTERNAL_SERVER_ERROR: u8 = 0xa0;
+    pub const NOT_IMPLEMENTED: u8 = 0xa1;
+    pub const BAD_GATEWAY: u8 = 0xa2;
+    pub const SERVICE_UNAVAILABLE: u8 = 0xa3;
+    pub const GATEWAY_TIMEOUT: u8 = 0xa4;
+    pub const PROXYING_NOT_SUPPORTED: u8 = 0xa5;
+
+    pub fn class(code: u8) -> u8 {
+        code >> 5
+    }
+
+    pub fn detail(code: u8) -> u8 {
+        code & 0x1f
+    }
+
+    pub fn is_request(code: u8) -> bool {
+        class(code) == 0 && code != EMPTY
+    }
+
+    pub fn is_response(code: u8) -> bool {
+        class(code) >= 2
+    }
+
+    /// Whether retransmissions of a request with this method may be processed
+    /// again without changing the outcome (RFC 7252, Section 5.1)
+    pub fn is_idempotent(code: u8) -> bool {
+        code == GET || code == PUT || code == DELETE
+    }
+}
+
+/// Option numbers registered in RFC 7252
+pub mod option_num {
+    pub const IF_MATCH: u16 = 1;
+    pub const URI_HOST: u16 = 3;
+    pub const ETAG: u16 = 4;
+    pub const IF_NONE_MATCH: u16 = 5;
+    pub const OBSERVE: u16 = 6;
+    pub const URI_PORT: u16 = 7;
+    pub const LOCATION_PATH: u16 = 8;
+    pub const URI_PATH: u16 = 11;
+    pub const CONTENT_FORMAT: u16 = 12;
+    pub const MAX_AGE: u16 = 14;
+    pub const URI_QUERY: u16 = 15;
+    pub const ACCEPT: u16 = 17;
+    pub const LOCATION_QUERY: u16 = 20;
+    pub const PROXY_URI: u16 = 35;
+    pub const PROXY_SCHEME: u16 = 39;
+    pub const SIZE1: u16 = 60;
+
+    /// Unrecognized critical options must cause a request to be rejected
+    pub fn is_critical(number: u16) -> bool {
+        number & 1 == 1
+    }
+}
+
+/// Content formats registered in RFC 7252
+pub mod content_format {
+    pub const TEXT_PLAIN: u16 = 0;
+    pub const LINK_FORMAT: u16 = 40;
+    pub const XML: u16 = 41;
+    pub const OCTET_STREAM: u16 = 42;
+    pub const EXI: u16 = 47;
+    pub const JSON: u16 = 50;
+}
+
+mod option_header {
+    pub const NIBBLE_MAX: u16 = 12;
+    pub const NIBBLE_8BIT: u8 = 13;
+    pub const NIBBLE_16BIT: u8 = 14;
+    pub const NIBBLE_RESERVED: u8 = 15;
+    pub const OFFSET_8BIT: u16 = 13;
+    pub const OFFSET_16BIT: u16 = 269;
+}
+
+#[repr(u8)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum MessageType {
+    Confirmable = 0,
+    NonConfirmable = 1,
+    Acknowledgement = 2,
+    Reset = 3,
+}
+
+impl MessageType {
+    pub fn from_bits(bits: u8) -> MessageType {
+        match bits & 0b11 {
+            0 => MessageType::Confirmable,
+            1 => MessageType::NonConfirmable,
+            2 => MessageType::Acknowledgement,
+            _ => MessageType::Reset,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct CoapHeader {
+    pub mtype: MessageType,
+    pub code: u8,
+    pub message_id: u16,
+    token_len: u8,
+    token: [u8; MAX_TOKEN_LEN],
+}
+
+impl CoapHeader {
+    /// Creates a header with the given token. Panics if the token is longer
+    /// than `MAX_TOKEN_LEN` bytes.
+    pub fn new(mtype: MessageType, code: u8, message_id: u16, token: &[u8]) -> CoapHeader {
+        assert!(token.len() <= MAX_TOKEN_LEN);
+        let mut header = CoapHeader {
+            mtype: mtype,
+            code: code,
+            message_id: message_id,
+            token_len: token.len() as u8,
+            token: [0; MAX_TOKEN_LEN],
+        };
+        header.token[..token.len()].copy_from_slice(token);
+        header
+    }
+
+    /// Creates the header of an empty message, as used for acknowledgements
+    /// that do not carry a response, resets and pings.
+    pub fn empty(mtype: MessageType, message_id: u16) -> CoapHeader {
+        CoapHeader::new(mtype, code::EMPTY, message_id, &[])
+    }
+
+    pub fn token(&self) -> &[u8] {
+        &self.token[..self.token_len as usize]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.code == code::EMPTY
+    }
+
+    /// Encodes the fixed header and the token
+    pub fn encode(&self, buf: &mut [u8]) -> SResult {
+        stream_len_cond!(buf, HEADER_SIZE + self.token_len as usize);
+        let first = (COAP_VERSION << 6) | ((self.mtype as u8) << 4) | self.token_len;
+        let off = enc_consume!(buf; encode_u8, first);
+        let off = enc_consume!(buf, off; encode_u8, self.code);
+        let off = enc_consume!(buf, off; encode_u16, self.message_id);
+        let off = enc_consume!(buf, off; encode_bytes, self.token());
+        stream_done!(off);
+    }
+
+    /// Decodes the fixed header and the token. Messages with an unknown
+    /// version number or a reserved token length are format errors.
+    pub fn decode(buf: &[u8]) -> SResult<CoapHeader> {
+        let (off, first) = dec_try!(buf; decode_u8);
+        stream_cond!(first >> 6 == COAP_VERSION);
+        let token_len = first & 0x0f;
+        stream_cond!(token_len as usize <= MAX_TOKEN_LEN);
+        let (off, code) = dec_try!(buf, off; decode_u8);
+        let (off, message_id) = dec_try!(buf, off; decode_u16);
+        stream_len_cond!(buf, off + token_len as usize);
+
+        let mut header = CoapHeader::new(MessageType::from_bits(first >> 4), code, message_id, &[]);
+        header.token_len = token_len;
+        header.token[..token_len as usize].copy_from_slice(&buf[off..off + token_len as usize]);
+
+        // An empty message must not contain a token, options or a payload
+        stream_cond!(code != code::EMPTY || (token_len == 0 && buf.len() == HEADER_SIZE));
+        stream_done!(off + token_len as usize, header);
+    }
+}
+
+/// Overwrites the message type and message ID of an already encoded message.
+/// This is used by the messaging layer to turn a prepared response into
+/// either a piggybacked or a separate response.
+pub fn rewrite_type_and_id(buf: &mut [u8], mtype: MessageType, message_id: u16) {
+    buf[0] = (buf[0] & !0x30) | ((mtype as u8) << 4);
+    buf[2] = (message_id >> 8) as u8;
+    buf[3] = message_id as u8;
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct CoapOption<'a> {
+    pub number: u16,
+    pub value: &'a [u8],
+}
+
+/// Splits a delta or length into the 4-bit nibble and its extended form
+fn split_nibble(value: u16) -> (u8, Option<u16>, usize) {
+    if value <= option_header::NIBBLE_MAX {
+        (value as u8, None, 0)
+    } else if value < option_header::OFFSET_16BIT {
+        (
+            option_header::NIBBLE_8BIT,
+            Some(value - option_header::OFFSET_8BIT),
+            1,
+        )
+    } else {
+        (
+            option_header::NIBBLE_16BIT,
+            Some(value - option_header::OFFSET_16BIT),
+            2,
+        )
+    }
+}
+
+fn encode_extended(buf: &mut [u8], extended: Option<u16>, len: usize) -> SResult {
+    match extended {
+        None => stream_done!(0),
+        Some(value) => {
+            if len == 1 {
+                encode_u8(buf, value as u8)
+            } else {
+                encode_u16(buf, value)
+            }
+        }
+    }
+}
+
+fn decode_extended(buf: &[u8], nibble: u8) -> SResult<u16> {
+    match nibble {
+        option_header::NIBBLE_8BIT => {
+            let (off, value) = dec_try!(buf; decode_u8);
+            stream_done!(off, value as u16 + option_header::OFFSET_8BIT);
+        }
+        option_header::NIBBLE_16BIT => {
+            let (off, value) = dec_try!(buf; decode_u16);
+            let value = stream_from_option!(value.checked_add(option_header::OFFSET_16BIT));
+            stream_done!(off, value);
+        }
+        option_header::NIBBLE_RESERVED => stream_err!(),
+        _ => stream_done!(0, nibble as u16),
+    }
+}
+
+impl<'a> CoapOption<'a> {
+    /// Encodes the option relative to the number of the previously encoded
+    /// option, which must not be greater than this option's number.
+    pub fn encode(&self, buf: &mut [u8], prev_number: u16) -> SResult {
+        stream_cond!(self.number >= prev_number);
+        stream_cond!(self.value.len() <= u16::max_value() as usize);
+        let (delta_nibble, delta_ext, delta_len) = split_nibble(self.number - prev_number);
+        let (len_nibble, len_ext, len_len) = split_nibble(self.value.len() as u16);
+        stream_len_cond!(buf, 1 + delta_len + len_len + self.value.len());
+
+        let off = enc_consume!(buf; encode_u8, (delta_nibble << 4) | len_nibble);
+        let off = enc_consume!(buf, off; encode_extended, delta_ext, delta_len);
+        let off = enc_consume!(buf, off; encode_extended, len_ext, len_len);
+        let off = enc_consume!(buf, off; encode_bytes, self.value);
+        stream_done!(off);
+    }
+
+    /// Decodes an option whose delta is relative to `prev_number`. The
+    /// payload marker is not an option and results in an error.
+    pub fn decode(buf: &'a [u8], prev_number: u16) -> SResult<CoapOption<'a>> {
+        let (off, first) = dec_try!(buf; decode_u8);
+        stream_cond!(first != PAYLOAD_MARKER);
+        let (off, delta) = dec_try!(buf, off; decode_extended, first >> 4);
+        let (off, len) = dec_try!(buf, off; decode_extended, first & 0x0f);
+        let number = stream_from_option!(prev_number.checked_add(delta));
+        stream_len_cond!(buf, off + len as usize);
+        stream_done!(
+            off + len as usize,
+            CoapOption {
+                number: number,
+                value: &buf[off..off + len as usize],
+            }
+        );
+    }
+
+    /// Interprets the option value as a variable-length unsigned integer
+    /// (RFC 7252, Section 3.2). Returns `None` if the value is too long.
+    pub fn value_uint(&self) -> Option<u32> {
+        if self.value.len() > 4 {
+            return None;
+        }
+        Some(
+            self.value
+                .iter()
+                .fold(0u32, |acc, &b| (acc << 8) | (b as u32)),
+        )
+    }
+}
+
+/// Encodes options in order, keeping track of the previous option number so
+/// that the delta encoding is correct.
+pub struct OptionWriter {
+    prev_number: u16,
+}
+
+impl OptionWriter {
+    pub fn new() -> OptionWriter {
+        OptionWriter { prev_number: 0 }
+    }
+
+    pub fn write(&mut self, buf: &mut [u8], number: u16, value: &[u8]) -> SResult {
+        let option = CoapOption {
+            number: number,
+            value: value,
+        };
+        let off = enc_consume!(buf; option; encode, self.prev_number);
+        self.prev_number = number;
+        stream_done!(off);
+    }
+
+    /// Writes an unsigned integer option using the shortest encoding
+    pub fn write_uint(&mut self, buf: &mut [u8], number: u16, value: u32) -> SResult {
+        let bytes = [
+            (value >> 24) as u8,
+            (value >> 16) as u8,
+            (value >> 8) as u8,
+            value as u8,
+        ];
+        let skip = bytes.iter().take_while(|&&b| b == 0).count();
+        self.write(buf, number, &bytes[skip..])
+    }
+
+    /// Writes each segment of a `/`-separated path as a separate option,
+    /// e.g. for Uri-Path or Location-Path.
+    pub fn write_path(&mut self, buf: &mut [u8], number: u16, path: &[u8]) -> SResult {
+        let mut off = 0;
+        for segment in path.split(|&b| b == b'/').filter(|s| !s.is_empty()) {
+            off = enc_consume!(buf, off; self; write, number, segment);
+        }
+        stream_done!(off);
+    }
+}
+
+/// Encodes the payload marker followed by the payload. Nothing is written for
+/// an empty payload, since a marker followed by a zero-length payload is a
+/// format error.
+pub fn encode_payload(buf: &mut [u8], payload: &[u8]) -> SResult {
+    if payload.is_empty() {
+        stream_done!(0);
+    }
+    let off = enc_consume!(buf; encode_u8, PAYLOAD_MARKER);
+    let off = enc_consume!(buf, off; encode_bytes, payload);
+    stream_done!(off);
+}
+
+/// Iterates over the options of a decoded message
+pub struct OptionIter<'a> {
+    buf: &'a [u8],
+    prev_number: u16,
+}
+
+impl<'a> Iterator for OptionIter<'a> {
+    type Item = CoapOption<'a>;
+
+    fn next(&mut self) -> Option<CoapOption<'a>> {
+        match CoapOption::decode(self.buf, self.prev_number).done() {
+            Some((off, option)) => {
+                self.buf = &self.buf[off..];
+                self.prev_number = option.number;
+                Some(option)
+            }
+            None => {
+                self.buf = &[];
+                None
+            }
+        }
+    }
+}
+
+/// A decoded CoAP message. The options and payload borrow from the buffer the
+/// message was decoded from.
+#[derive(Copy, Clone, Debug)]
+pub struct Message<'a> {
+    pub header: CoapHeader,
+    options: &'a [u8],
+    pub payload: &'a [u8],
+}
+
+impl<'a> Message<'a> {
+    /// Decodes and validates an entire message, including all of its options.
+    pub fn decode(buf: &'a [u8]) -> SResult<Message<'a>> {
+        let (options_start, header) = dec_try!(buf; CoapHeader::decode);
+
+        let mut off = options_start;
+        let mut prev_number = 0;
+        while off < buf.len() && buf[off] != PAYLOAD_MARKER {
+            let (next_off, option) = dec_try!(buf, off; CoapOption::decode, prev_number);
+            off = next_off;
+            prev_number = option.number;
+        }
+        let options = &buf[options_start..off];
+
+        let payload = if off < buf.len() {
+            // A payload marker must be followed by a non-empty payload
+            stream_cond!(off + 1 < buf.len());
+            &buf[off + 1..]
+        } else {
+            &buf[off..]
+        };
+
+        stream_done!(
+            buf.len(),
+            Message {
+                header: header,
+                options: options,
+                payload: payload,
+            }
+        );
+    }
+
+    pub fn options(&self) -> OptionIter<'a> {
+        OptionIter {
+            buf: self.options,
+            prev_number: 0,
+        }
+    }
+
+    /// Returns the first option with the given number, if present
+    pub fn find_option(&self, number: u16) -> Option<CoapOption<'a>> {
+        self.options().find(|option| option.number == number)
+    }
+
+    /// Returns the first critical option that is not in `known`. A request
+    /// containing such an option must be rejected with 4.02 (Bad Option).
+    pub fn unknown_critical_option(&self, known: &[u16]) -> Option<u16> {
+        self.options()
+            .map(|option| option.number)
+            .find(|&number| option_num::is_critical(number) && !known.contains(&number))
+    }
+
+    /// Checks whether the Uri-Path options of this message are equal to the
+    /// segments of `path`, which is separated by `/`. Leading, trailing and
+    /// repeated separators are ignored.
+    pub fn uri_path_matches(&self, path: &[u8]) -> bool {
+        let mut segments = path.split(|&b| b == b'/').filter(|s| !s.is_empty());
+        let mut options = self.options()
+            .filter(|option| option.number == option_num::URI_PATH);
+        loop {
+            match (segments.next(), options.next()) {
+                (None, None) => return true,
+                (Some(segment), Some(option)) => {
+                    if segment != option.value {
+                        return false;
+                    }
+                }
+                _ => return false,
+            }
+        }
+    }
+}
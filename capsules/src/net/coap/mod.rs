@@ -0,0 +1,46 @@
+//! CoAP (Constrained Application Protocol, RFC 7252) support.
+//!
+//! The implementation is split into layers, from the bottom up:
+//!
+//! - `message`: encoding and decoding of CoAP messages and options, using the
+//!   `net::stream` helpers.
+//! - `layer`: the messaging layer, which runs on any datagram `Transport` and
+//!   provides retransmission of confirmable messages, matching of
+//!   acknowledgements and deduplication of received messages.
+//! - `server`: a request/response layer that dispatches requests to
+//!   registered `CoapResource`s.
+//! - `resources`: resources that expose kernel sensors.
+//! - `udp`: a `Transport` that carries messages in UDP datagrams over
+//!   6LoWPAN.
+//! - `driver`: a syscall driver that lets applications serve resources.
+//!
+//! ```
+//!   +--------------+ +-----------------------+
+//!   | CoapDriver   | | TemperatureResource.. |
+//!   +--------------+ +-----------------------+
+//!           |  CoapResource  |
+//!           v                v
+//!         +--------------------+
+//!         |     CoapServer     |
+//!         +--------------------+
+//!                   | MessageLayer
+//!                   v
+//!         +--------------------+
+//!         |     CoapLayer      | <-- Alarm
+//!         +--------------------+
+//!                   | Transport
+//!                   v
+//!         +--------------------+
+//!         |    UdpTransport    |
+//!         +--------------------+
+//! ```
+
+pub mod message;
+pub mod layer;
+pub mod server;
+pub mod resources;
+pub mod udp;
+
+mod driver;
+
+pub use self::driver::*;
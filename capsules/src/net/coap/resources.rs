@@ -0,0 +1,216 @@
+//! CoAP resources that expose kernel sensors over `GET`.
+//!
+//! Each resource serves a single Uri-Path, chosen by the board, and answers a
+//! `GET` with the current sensor reading as a decimal `text/plain` payload, in
+//! the same units as the corresponding `hil::sensors` client callback:
+//!
+//! - `TemperatureResource`: hundredths of degrees Celsius
+//! - `HumidityResource`: hundredths of percent
+//! - `AmbientLightResource`: lux
+//!
+//! Only one reading is in progress per resource; a request that arrives while
+//! a reading is pending is answered with 5.03 (Service Unavailable).
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let temp_resource = static_init!(
+//!     capsules::net::coap::resources::TemperatureResource<'static>,
+//!     capsules::net::coap::resources::TemperatureResource::new(
+//!         si7021, coap_server, b"sensors/temperature")
+//! );
+//! kernel::hil::sensors::TemperatureDriver::set_client(si7021, temp_resource);
+//! coap_server.add_resource(temp_resource);
+//! ```
+//!
+//! Since sensor drivers support a single client, a sensor exposed through CoAP
+//! cannot also be used by the corresponding syscall driver.
+
+use core::cell::Cell;
+use kernel::ReturnCode;
+use kernel::hil;
+use net::coap::message::{code, content_format, Message};
+use net::coap::server::{CoapResource, CoapServer};
+
+// Enough digits for any 32-bit value
+const MAX_DIGITS: usize = 10;
+
+/// Formats `value` as ASCII decimal digits, returning the number of bytes
+/// written to the start of `buf`.
+fn format_decimal(mut value: usize, buf: &mut [u8; MAX_DIGITS]) -> usize {
+    let mut digits = [0u8; MAX_DIGITS];
+    let mut len = 0;
+    loop {
+        digits[len] = b'0' + (value % 10) as u8;
+        len += 1;
+        value /= 10;
+        if value == 0 || len == MAX_DIGITS {
+            break;
+        }
+    }
+    for i in 0..len {
+        buf[i] = digits[len - 1 - i];
+    }
+    len
+}
+
+/// The state shared by all sensor resources: the path they are served at
+/// and the request waiting for a reading.
+struct SensorResource<'a> {
+    server: &'a CoapServer<'a>,
+    path: &'static [u8],
+    request: Cell<Option<usize>>,
+}
+
+impl<'a> SensorResource<'a> {
+    fn new(server: &'a CoapServer<'a>, path: &'static [u8]) -> SensorResource<'a> {
+        SensorResource {
+            server: server,
+            path: path,
+            request: Cell::new(None),
+        }
+    }
+
+    fn matches(&self, request: &Message) -> bool {
+        request.uri_path_matches(self.path)
+    }
+
+    // Checks the method and starts a reading with `read` if no other reading
+    // is pending
+    fn handle_request<F>(&self, request_id: usize, request: &Message, read: F) -> ReturnCode
+    where
+        F: FnOnce() -> ReturnCode,
+    {
+        if request.header.code != code::GET {
+            return ReturnCode::ENOSUPPORT;
+        }
+        if self.request.get().is_some() {
+            return ReturnCode::EBUSY;
+        }
+        self.request.set(Some(request_id));
+        let result = read();
+        if result != ReturnCode::SUCCESS {
+            self.request.set(None);
+        }
+        result
+    }
+
+    fn reading_done(&self, value: usize) {
+        self.request.take().map(|request_id| {
+            let mut payload = [0u8; MAX_DIGITS];
+            let len = format_decimal(value, &mut payload);
+            self.server.respond(
+                request_id,
+                code::CONTENT,
+                Some(content_format::TEXT_PLAIN),
+                &payload[..len],
+            );
+        });
+    }
+}
+
+pub struct TemperatureResource<'a> {
+    sensor: &'a hil::sensors::TemperatureDriver,
+    resource: SensorResource<'a>,
+}
+
+impl<'a> TemperatureResource<'a> {
+    pub fn new(
+        sensor: &'a hil::sensors::TemperatureDriver,
+        server: &'a CoapServer<'a>,
+        path: &'static [u8],
+    ) -> TemperatureResource<'a> {
+        TemperatureResource {
+            sensor: sensor,
+            resource: SensorResource::new(server, path),
+        }
+    }
+}
+
+impl<'a> CoapResource for TemperatureResource<'a> {
+    fn matches(&self, request: &Message) -> bool {
+        self.resource.matches(request)
+    }
+
+    fn handle_request(&self, request_id: usize, request: &Message) -> ReturnCode {
+        self.resource
+            .handle_request(request_id, request, || self.sensor.read_temperature())
+    }
+}
+
+impl<'a> hil::sensors::TemperatureClient for TemperatureResource<'a> {
+    fn callback(&self, value: usize) {
+        self.resource.reading_done(value);
+    }
+}
+
+pub struct HumidityResource<'a> {
+    sensor: &'a hil::sensors::HumidityDriver,
+    resource: SensorResource<'a>,
+}
+
+impl<'a> HumidityResource<'a> {
+    pub fn new(
+        sensor: &'a hil::sensors::HumidityDriver,
+        server: &'a CoapServer<'a>,
+        path: &'static [u8],
+    ) -> HumidityResource<'a> {
+        HumidityResource {
+            sensor: sensor,
+            resource: SensorResource::new(server, path),
+        }
+    }
+}
+
+impl<'a> CoapResource for HumidityResource<'a> {
+    fn matches(&self, request: &Message) -> bool {
+        self.resource.matches(request)
+    }
+
+    fn handle_request(&self, request_id: usize, request: &Message) -> ReturnCode {
+        self.resource
+            .handle_request(request_id, request, || self.sensor.read_humidity())
+    }
+}
+
+impl<'a> hil::sensors::HumidityClient for HumidityResource<'a> {
+    fn callback(&self, value: usize) {
+        self.resource.reading_done(value);
+    }
+}
+
+pub struct AmbientLightResource<'a> {
+    sensor: &'a hil::sensors::AmbientLight,
+    resource: SensorResource<'a>,
+}
+
+impl<'a> AmbientLightResource<'a> {
+    pub fn new(
+        sensor: &'a hil::sensors::AmbientLight,
+        server: &'a CoapServer<'a>,
+        path: &'static [u8],
+    ) -> AmbientLightResource<'a> {
+        AmbientLightResource {
+            sensor: sensor,
+            resource: SensorResource::new(server, path),
+        }
+    }
+}
+
+impl<'a> CoapResource for AmbientLightResource<'a> {
+    fn matches(&self, request: &Message) -> bool {
+        self.resource.matches(request)
+    }
+
+    fn handle_request(&self, request_id: usize, request: &Message) -> ReturnCode {
+        self.resource
+            .handle_request(request_id, request, || self.sensor.read_light_intensity())
+    }
+}
+
+impl<'a> hil::sensors::AmbientLightClient for AmbientLightResource<'a> {
+    fn callback(&self, lux: usize) {
+        self.resource.reading_done(lux);
+    }
+}
@@ -0,0 +1,331 @@
+//! A CoAP server that dispatches requests to registered resources.
+//!
+//! Resources implement the `CoapResource` trait and are registered with
+//! `CoapServer::add_resource`. When a request arrives, the server picks the
+//! first resource whose `matches` method accepts the request and passes it to
+//! `handle_request` along with a request ID. The resource answers, either
+//! immediately or once some asynchronous operation (such as a sensor reading)
+//! completes, by calling `CoapServer::respond` with that request ID.
+//!
+//! The server only keeps the information needed to address the response
+//! (source endpoint, message ID, token), so a resource must copy anything it
+//! needs from the request before `handle_request` returns.
+//!
+//! Requests that no resource matches are answered with 4.04 (Not Found), and
+//! requests carrying unrecognized critical options with 4.02 (Bad Option).
+//!
+//! Each response is encoded into a buffer of its own, which the messaging
+//! layer holds until the response has been sent or, for a separate response,
+//! acknowledged. The server starts with the buffer passed to `new`, and more
+//! can be added with `add_tx_buffer` so that other requests can be answered
+//! while a separate response waits for its acknowledgement.
+
+use core::cell::Cell;
+use kernel::ReturnCode;
+use kernel::common::take_cell::{MapCell, TakeCell};
+use net::coap::layer::{CoapClient, Endpoint, MessageLayer};
+use net::coap::message::{code, option_num, CoapHeader, Message, MessageType, OptionWriter};
+use net::coap::message::{encode_payload, MAX_TOKEN_LEN};
+use net::stream::SResult;
+
+const MAX_RESOURCES: usize = 8;
+const MAX_PENDING_REQUESTS: usize = 4;
+// A response can be outstanding for each exchange of the messaging layer
+const MAX_TX_BUFFERS: usize = 4;
+
+// Options a request may carry that the server understands or that are handled
+// by the resources themselves
+const KNOWN_OPTIONS: [u16; 6] = [
+    option_num::URI_HOST,
+    option_num::URI_PORT,
+    option_num::URI_PATH,
+    option_num::URI_QUERY,
+    option_num::CONTENT_FORMAT,
+    option_num::ACCEPT,
+];
+
+pub trait CoapResource {
+    /// Returns whether this resource serves the request, usually based on
+    /// its Uri-Path options.
+    fn matches(&self, request: &Message) -> bool;
+
+    /// Handles a request. The response is sent by calling
+    /// `CoapServer::respond` with `request_id`, which may happen before this
+    /// method returns. Returning an error instead causes the server to respond
+    /// on the resource's behalf: `ENOSUPPORT` with 4.05 (Method Not Allowed),
+    /// `EBUSY` with 5.03 (Service Unavailable), and anything else with 5.00
+    /// (Internal Server Error).
+    fn handle_request(&self, request_id: usize, request: &Message) -> ReturnCode;
+}
+
+/// What the server remembers about a request while a resource handles it
+#[derive(Copy, Clone)]
+struct PendingRequest {
+    in_use: bool,
+    src: Endpoint,
+    message_id: u16,
+    token_len: usize,
+    token: [u8; MAX_TOKEN_LEN],
+}
+
+impl Default for PendingRequest {
+    fn default() -> PendingRequest {
+        PendingRequest {
+            in_use: false,
+            src: Endpoint::default(),
+            message_id: 0,
+            token_len: 0,
+            token: [0; MAX_TOKEN_LEN],
+        }
+    }
+}
+
+impl PendingRequest {
+    fn new(src: Endpoint, header: &CoapHeader) -> PendingRequest {
+        let mut request = PendingRequest {
+            in_use: true,
+            src: src,
+            message_id: header.message_id,
+            token_len: header.token().len(),
+            token: [0; MAX_TOKEN_LEN],
+        };
+        request.token[..request.token_len].copy_from_slice(header.token());
+        request
+    }
+
+    fn token(&self) -> &[u8] {
+        &self.token[..self.token_len]
+    }
+}
+
+pub struct CoapServer<'a> {
+    layer: &'a MessageLayer<'a>,
+    resources: MapCell<[Option<&'a CoapResource>; MAX_RESOURCES]>,
+    num_resources: Cell<usize>,
+    requests: MapCell<[PendingRequest; MAX_PENDING_REQUESTS]>,
+
+    // Buffers in which responses are encoded. A buffer is held by the
+    // messaging layer until its response has been sent (or, for a separate
+    // response, acknowledged).
+    tx_bufs: [TakeCell<'static, [u8]>; MAX_TX_BUFFERS],
+    num_tx_bufs: Cell<usize>,
+}
+
+impl<'a> CoapServer<'a> {
+    /// Creates a new `CoapServer`. `tx_buf` must be large enough for the
+    /// largest response sent by any of the resources.
+    pub fn new(layer: &'a MessageLayer<'a>, tx_buf: &'static mut [u8]) -> CoapServer<'a> {
+        CoapServer {
+            layer: layer,
+            resources: MapCell::new(Default::default()),
+            num_resources: Cell::new(0),
+            requests: MapCell::new(Default::default()),
+            tx_bufs: [
+                TakeCell::new(tx_buf),
+                TakeCell::empty(),
+                TakeCell::empty(),
+                TakeCell::empty(),
+            ],
+            num_tx_bufs: Cell::new(1),
+        }
+    }
+
+    /// Adds a buffer for responses, so that one more response can be
+    /// outstanding at the same time. `tx_buf` must be as large as the one
+    /// passed to `new`. Returns `ENOMEM` if the server has no room for it.
+    pub fn add_tx_buffer(&self, tx_buf: &'static mut [u8]) -> ReturnCode {
+        let num_tx_bufs = self.num_tx_bufs.get();
+        if num_tx_bufs == MAX_TX_BUFFERS {
+            return ReturnCode::ENOMEM;
+        }
+        self.put_tx_buf(tx_buf);
+        self.num_tx_bufs.set(num_tx_bufs + 1);
+        ReturnCode::SUCCESS
+    }
+
+    /// Registers a resource. Resources are matched in registration order.
+    /// Returns `ENOMEM` if the maximum number of resources is registered.
+    pub fn add_resource(&self, resource: &'a CoapResource) -> ReturnCode {
+        let num_resources = self.num_resources.get();
+        if num_resources == MAX_RESOURCES {
+            return ReturnCode::ENOMEM;
+        }
+        self.resources.map(|resources| {
+            resources[num_resources] = Some(resource);
+        });
+        self.num_resources.set(num_resources + 1);
+        ReturnCode::SUCCESS
+    }
+
+    /// Sends the response to the request with `request_id`, which is then
+    /// complete. Returns `EINVAL` if there is no such request, `ESIZE` if the
+    /// response does not fit into the server's buffers and `EBUSY` if all of
+    /// them are in use by other responses. In all error cases the request is
+    /// dropped; a confirmable request will be retransmitted by the client.
+    pub fn respond(
+        &self,
+        request_id: usize,
+        code: u8,
+        content_format: Option<u16>,
+        payload: &[u8],
+    ) -> ReturnCode {
+        let request = match self.take_request(request_id) {
+            Some(request) => request,
+            None => return ReturnCode::EINVAL,
+        };
+        self.send_response(&request, code, content_format, payload)
+    }
+
+    fn take_request(&self, request_id: usize) -> Option<PendingRequest> {
+        if request_id >= MAX_PENDING_REQUESTS {
+            return None;
+        }
+        self.requests.and_then(|requests| {
+            if requests[request_id].in_use {
+                requests[request_id].in_use = false;
+                Some(requests[request_id])
+            } else {
+                None
+            }
+        })
+    }
+
+    fn add_request(&self, src: Endpoint, header: &CoapHeader) -> Option<usize> {
+        self.requests.and_then(|requests| {
+            requests
+                .iter()
+                .position(|request| !request.in_use)
+                .map(|request_id| {
+                    requests[request_id] = PendingRequest::new(src, header);
+                    request_id
+                })
+        })
+    }
+
+    fn encode_response(
+        buf: &mut [u8],
+        request: &PendingRequest,
+        code: u8,
+        content_format: Option<u16>,
+        payload: &[u8],
+    ) -> SResult {
+        // The message type and ID are filled in by the messaging layer
+        let header = CoapHeader::new(
+            MessageType::Acknowledgement,
+            code,
+            request.message_id,
+            request.token(),
+        );
+        let off = enc_consume!(buf; header; encode);
+        let mut options = OptionWriter::new();
+        let off = match content_format {
+            Some(format) => {
+                enc_consume!(buf, off; options; write_uint, option_num::CONTENT_FORMAT,
+                             format as u32)
+            }
+            None => off,
+        };
+        let off = enc_consume!(buf, off; encode_payload, payload);
+        stream_done!(off);
+    }
+
+    fn send_response(
+        &self,
+        request: &PendingRequest,
+        code: u8,
+        content_format: Option<u16>,
+        payload: &[u8],
+    ) -> ReturnCode {
+        let buf = match self.take_tx_buf() {
+            Some(buf) => buf,
+            None => return ReturnCode::EBUSY,
+        };
+        let len = match Self::encode_response(buf, request, code, content_format, payload).done()
+        {
+            Some((len, _)) => len,
+            None => {
+                self.put_tx_buf(buf);
+                return ReturnCode::ESIZE;
+            }
+        };
+        match self.layer
+            .send_response(request.src, request.message_id, buf, len)
+        {
+            Ok(()) => ReturnCode::SUCCESS,
+            Err((result, buf)) => {
+                self.put_tx_buf(buf);
+                result
+            }
+        }
+    }
+
+    fn take_tx_buf(&self) -> Option<&'static mut [u8]> {
+        self.tx_bufs.iter().filter_map(|slot| slot.take()).next()
+    }
+
+    // Returns a buffer to the first free slot. There is always one, since
+    // there are at most as many buffers as slots.
+    fn put_tx_buf(&self, buf: &'static mut [u8]) {
+        if let Some(slot) = self.tx_bufs.iter().find(|slot| slot.is_none()) {
+            slot.replace(buf);
+        }
+    }
+
+    fn find_resource(&self, request: &Message) -> Option<&'a CoapResource> {
+        let num_resources = self.num_resources.get();
+        self.resources.and_then(|resources| {
+            resources[..num_resources]
+                .iter()
+                .filter_map(|resource| *resource)
+                .find(|resource| resource.matches(request))
+        })
+    }
+}
+
+impl<'a> CoapClient for CoapServer<'a> {
+    fn receive(&self, src: Endpoint, message: &Message) {
+        // This server does not issue requests, so responses are not expected
+        if !code::is_request(message.header.code) {
+            return;
+        }
+
+        let request = PendingRequest::new(src, &message.header);
+        if message.unknown_critical_option(&KNOWN_OPTIONS).is_some() {
+            self.send_response(&request, code::BAD_OPTION, None, &[]);
+            return;
+        }
+
+        let resource = match self.find_resource(message) {
+            Some(resource) => resource,
+            None => {
+                self.send_response(&request, code::NOT_FOUND, None, &[]);
+                return;
+            }
+        };
+
+        let request_id = match self.add_request(src, &message.header) {
+            Some(request_id) => request_id,
+            None => {
+                self.send_response(&request, code::SERVICE_UNAVAILABLE, None, &[]);
+                return;
+            }
+        };
+
+        let result = resource.handle_request(request_id, message);
+        if result != ReturnCode::SUCCESS {
+            let code = match result {
+                ReturnCode::ENOSUPPORT => code::METHOD_NOT_ALLOWED,
+                ReturnCode::EBUSY => code::SERVICE_UNAVAILABLE,
+                _ => code::INTERNAL_SERVER_ERROR,
+            };
+            // The resource may have responded before failing, in which case
+            // the request is already gone
+            self.take_request(request_id)
+                .map(|request| self.send_response(&request, code, None, &[]));
+        }
+    }
+
+    fn send_done(&self, buf: &'static mut [u8], _result: ReturnCode) {
+        self.put_tx_buf(buf);
+    }
+}
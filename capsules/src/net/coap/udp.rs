@@ -0,0 +1,270 @@
+//! A CoAP `Transport` that sends and receives UDP datagrams over 6LoWPAN.
+//!
+//! The transport registers its own `SixlowpanUser`, which only receives UDP
+//! datagrams addressed to its port, so it can share a `Sixlowpan` layer with
+//! other upper layers. Messages are copied into an IPv6 packet buffer owned by
+//! the transport, behind an IPv6 header and a UDP header, and the UDP checksum
+//! is computed before the packet is passed to `Sixlowpan`. The buffer of the
+//! messaging layer is held until the packet has been sent, and then returned
+//! in `send_done`.
+//!
+//! Received datagrams are only passed up if they are addressed to the
+//! transport's IPv6 address or to a multicast address, and if their UDP length
+//! and checksum are valid. Datagrams without a checksum are dropped, as IPv6
+//! requires one; a checksum elided by 6LoWPAN compression has already been
+//! filled in by `Sixlowpan` at this point.
+//!
+//! The link-layer destination of a packet is derived from the interface
+//! identifier of its destination address: identifiers of the form
+//! `0000:00ff:fe00:XXXX` map to the short address `XXXX`, and all others to
+//! the EUI-64 with the universal/local bit inverted (RFC 4944, Section 6).
+//! Multicast packets are sent to the broadcast address.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! static mut UDP_BUF: [u8; 256] = [0; 256];
+//!
+//! let udp_transport = static_init!(
+//!     capsules::net::coap::udp::UdpTransport<'static,
+//!         VirtualMuxAlarm<'static, sam4l::ast::Ast>, Context>,
+//!     capsules::net::coap::udp::UdpTransport::new(
+//!         sixlowpan, &mut UDP_BUF, capsules::net::coap::udp::COAP_PORT)
+//! );
+//! udp_transport.set_address(ip_addr, MacAddress::Short(0x1008));
+//! udp_transport.start();
+//! ```
+
+use core::cell::Cell;
+use kernel::ReturnCode;
+use kernel::common::take_cell::TakeCell;
+use ieee802154::device::SecurityError;
+use kernel::hil::time;
+use net::coap::layer::{Endpoint, Transport, TransportClient};
+use net::ieee802154::MacAddress;
+use net::ip::{self, ip6_nh, ExtHeaderIter, IP6Header, IPAddr};
+use net::sixlowpan::{Sixlowpan, SixlowpanClient, SixlowpanUser};
+use net::sixlowpan_compression::ContextStore;
+use net::util::{slice_to_u16, u16_to_slice};
+
+/// The default port of CoAP servers (RFC 7252, Section 6.1)
+pub const COAP_PORT: u16 = 5683;
+
+pub const IP6_HEADER_SIZE: usize = 40;
+pub const UDP_HEADER_SIZE: usize = 8;
+
+const DEFAULT_HOP_LIMIT: u8 = 64;
+
+// Returns the link-layer address that a packet to `dst_addr` is sent to
+fn dst_mac_addr(dst_addr: &IPAddr) -> MacAddress {
+    if dst_addr.is_multicast() {
+        return MacAddress::Short(0xffff);
+    }
+    let iid = &dst_addr.0[8..16];
+    if iid[0..6] == [0, 0, 0, 0xff, 0xfe, 0] {
+        MacAddress::Short(slice_to_u16(&iid[6..8]))
+    } else {
+        let mut long_addr = [0; 8];
+        long_addr.copy_from_slice(iid);
+        long_addr[0] ^= 0x02;
+        MacAddress::Long(long_addr)
+    }
+}
+
+pub struct UdpTransport<'a, A: time::Alarm + 'a, C: ContextStore + 'a> {
+    sixlowpan: &'a Sixlowpan<'a, A, C>,
+    user: SixlowpanUser<'a>,
+    // Set to `user` once registered by `start`
+    registered_user: Cell<Option<&'a SixlowpanUser<'a>>>,
+    client: Cell<Option<&'a TransportClient>>,
+    port: u16,
+    addr: Cell<IPAddr>,
+    mac_addr: Cell<MacAddress>,
+
+    // The IPv6 packet being sent, and the buffer of the messaging layer it
+    // was copied from
+    packet: TakeCell<'static, [u8]>,
+    pending: TakeCell<'static, [u8]>,
+}
+
+impl<'a, A: time::Alarm + 'a, C: ContextStore + 'a> UdpTransport<'a, A, C> {
+    /// Creates a new `UdpTransport`
+    ///
+    /// # Arguments
+    ///
+    /// * `packet` - A buffer for the IPv6 packets being sent. Messages longer
+    /// than `packet.len() - IP6_HEADER_SIZE - UDP_HEADER_SIZE` are rejected.
+    ///
+    /// * `port` - The local UDP port, used both as the source port of sent
+    /// datagrams and to select the received ones.
+    pub fn new(
+        sixlowpan: &'a Sixlowpan<'a, A, C>,
+        packet: &'static mut [u8],
+        port: u16,
+    ) -> UdpTransport<'a, A, C> {
+        UdpTransport {
+            sixlowpan: sixlowpan,
+            user: SixlowpanUser::new(Some(ip6_nh::UDP), Some(port)),
+            registered_user: Cell::new(None),
+            client: Cell::new(None),
+            port: port,
+            addr: Cell::new(IPAddr::new()),
+            mac_addr: Cell::new(MacAddress::Short(0)),
+            packet: TakeCell::new(packet),
+            pending: TakeCell::empty(),
+        }
+    }
+
+    /// Sets the IPv6 address of this node and the link-layer address its
+    /// packets are sent from, which must be the address of the Mac device
+    /// below `Sixlowpan`.
+    pub fn set_address(&self, addr: IPAddr, mac_addr: MacAddress) {
+        self.addr.set(addr);
+        self.mac_addr.set(mac_addr);
+    }
+
+    /// Registers the transport with `Sixlowpan`, after which it starts
+    /// receiving datagrams. Sending returns `EOFF` until then.
+    pub fn start(&'a self) {
+        self.user.set_client(self);
+        self.sixlowpan.add_user(&self.user);
+        self.registered_user.set(Some(&self.user));
+    }
+
+    // Writes the IPv6 and UDP headers and `payload` to `packet`, and returns
+    // the length of the packet
+    fn encode_packet(&self, packet: &mut [u8], dst: Endpoint, payload: &[u8]) -> usize {
+        let udp_len = UDP_HEADER_SIZE + payload.len();
+        let mut ip6_header = IP6Header::new();
+        ip6_header.set_payload_len(udp_len as u16);
+        ip6_header.set_next_header(ip6_nh::UDP);
+        ip6_header.set_hop_limit(DEFAULT_HOP_LIMIT);
+        ip6_header.src_addr = self.addr.get();
+        ip6_header.dst_addr = dst.addr;
+        let _ = IP6Header::encode(packet, ip6_header);
+
+        let (header, rest) = packet[IP6_HEADER_SIZE..].split_at_mut(UDP_HEADER_SIZE);
+        u16_to_slice(self.port, &mut header[0..2]);
+        u16_to_slice(dst.port, &mut header[2..4]);
+        u16_to_slice(udp_len as u16, &mut header[4..6]);
+        rest[..payload.len()].copy_from_slice(payload);
+        let checksum = ip::compute_checksum(
+            &ip6_header.src_addr,
+            &ip6_header.dst_addr,
+            ip6_nh::UDP,
+            udp_len as u16,
+            &header[0..6],
+            payload,
+        );
+        u16_to_slice(checksum, &mut header[6..8]);
+        IP6_HEADER_SIZE + udp_len
+    }
+
+    // Returns the source endpoint and payload of a received UDP datagram, or
+    // `None` if it should be dropped
+    fn decode_packet<'b>(&self, packet: &'b [u8]) -> Option<(Endpoint, &'b [u8])> {
+        let (_, ip6_header) = IP6Header::decode(packet).done()?;
+        let dst_addr = ip6_header.dst_addr;
+        if dst_addr != self.addr.get() && !dst_addr.is_multicast() {
+            return None;
+        }
+
+        let mut headers = ExtHeaderIter::new(packet);
+        for _ in &mut headers {}
+        let (next_header, offset) = headers.upper_layer().ok()?;
+        if next_header != ip6_nh::UDP || packet.len() < offset + UDP_HEADER_SIZE {
+            return None;
+        }
+        let header = &packet[offset..offset + UDP_HEADER_SIZE];
+        let udp_len = slice_to_u16(&header[4..6]) as usize;
+        if udp_len < UDP_HEADER_SIZE || packet.len() < offset + udp_len {
+            return None;
+        }
+        let payload = &packet[offset + UDP_HEADER_SIZE..offset + udp_len];
+        let checksum = slice_to_u16(&header[6..8]);
+        let expected = ip::compute_checksum(
+            &ip6_header.src_addr,
+            &dst_addr,
+            ip6_nh::UDP,
+            udp_len as u16,
+            &header[0..6],
+            payload,
+        );
+        if checksum != expected {
+            return None;
+        }
+        let src = Endpoint::new(ip6_header.src_addr, slice_to_u16(&header[0..2]));
+        Some((src, payload))
+    }
+}
+
+impl<'a, A: time::Alarm + 'a, C: ContextStore + 'a> Transport<'a> for UdpTransport<'a, A, C> {
+    fn set_client(&self, client: &'a TransportClient) {
+        self.client.set(Some(client));
+    }
+
+    fn send_to(
+        &self,
+        dst: Endpoint,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        let user = match self.registered_user.get() {
+            Some(user) => user,
+            None => return Err((ReturnCode::EOFF, buf)),
+        };
+        if len > buf.len() {
+            return Err((ReturnCode::EINVAL, buf));
+        }
+        let packet = match self.packet.take() {
+            Some(packet) => packet,
+            None => return Err((ReturnCode::EBUSY, buf)),
+        };
+        if IP6_HEADER_SIZE + UDP_HEADER_SIZE + len > packet.len() {
+            self.packet.replace(packet);
+            return Err((ReturnCode::ESIZE, buf));
+        }
+
+        let packet_len = self.encode_packet(packet, dst, &buf[..len]);
+        match self.sixlowpan.transmit_packet_from(
+            user,
+            self.mac_addr.get(),
+            dst_mac_addr(&dst.addr),
+            packet,
+            packet_len,
+            None,
+        ) {
+            Ok(()) => {
+                self.pending.replace(buf);
+                Ok(())
+            }
+            Err((result, packet)) => {
+                self.packet.replace(packet);
+                Err((result, buf))
+            }
+        }
+    }
+}
+
+impl<'a, A: time::Alarm + 'a, C: ContextStore + 'a> SixlowpanClient for UdpTransport<'a, A, C> {
+    fn receive<'b>(&self, buf: &'b [u8], len: u16, result: ReturnCode) {
+        if result != ReturnCode::SUCCESS {
+            return;
+        }
+        if let Some((src, payload)) = self.decode_packet(&buf[..len as usize]) {
+            self.client.get().map(|client| client.receive(src, payload));
+        }
+    }
+
+    fn send_done(&self, buf: &'static mut [u8], _acked: bool, result: ReturnCode) {
+        self.packet.replace(buf);
+        self.pending.take().map(|buf| {
+            self.client
+                .get()
+                .map(move |client| client.send_done(buf, result));
+        });
+    }
+
+    fn receive_security_failure(&self, _: Option<MacAddress>, _: SecurityError) {}
+}
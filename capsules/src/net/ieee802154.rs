@@ -39,7 +39,15 @@ impl MacAddress {
 
 pub type PanID = u16;
 
-mod frame_control {
+/// The short address and PAN ID to which broadcast frames are sent
+pub const BROADCAST_ADDR: u16 = 0xffff;
+pub const BROADCAST_PAN: PanID = 0xffff;
+/// The short address of a device that is associated but has not been
+/// allocated a short address, and must use its long address instead
+pub const NO_SHORT_ADDR: u16 = 0xfffe;
+
+/// Flags and fields of the frame control field (IEEE 802.15.4-2015: 7.2.1)
+pub mod frame_control {
     pub const FRAME_TYPE_MASK: u16 = 0b111;
     pub const SECURITY_ENABLED: u16 = 1 << 3;
     pub const FRAME_PENDING: u16 = 1 << 4;
@@ -307,7 +315,7 @@ impl Security {
         let asn_in_nonce = (scf & security_control::ASN_IN_NONCE) != 0;
 
         // Frame counter field
-        let frame_counter_present = (scf & security_control::FRAME_COUNTER_SUPPRESSION) != 0;
+        let frame_counter_present = (scf & security_control::FRAME_COUNTER_SUPPRESSION) == 0;
         let (off, frame_counter) = if frame_counter_present {
             let (off, frame_counter_be) = dec_try!(buf, off; decode_u32);
             (off, Some(u32::from_be(frame_counter_be)))
@@ -343,6 +351,18 @@ mod ie_control {
     pub const PAYLOAD_ID_MASK: u8 = 0xf; // Only 4 bits
     pub const PAYLOAD_ID_POS: usize = 11;
 
+    // Nested IE constants
+    pub const NESTED_SHORT_LEN_MAX: usize = (1 << 8) - 1;
+    pub const NESTED_SHORT_LEN_MASK: u16 = NESTED_SHORT_LEN_MAX as u16;
+    pub const NESTED_SHORT_ID_MASK: u8 = 0x7f; // Only 7 bits
+    pub const NESTED_SHORT_ID_POS: usize = 8;
+    pub const NESTED_LONG_LEN_MAX: usize = (1 << 11) - 1;
+    pub const NESTED_LONG_LEN_MASK: u16 = NESTED_LONG_LEN_MAX as u16;
+    pub const NESTED_LONG_ID_MASK: u8 = 0xf; // Only 4 bits
+    pub const NESTED_LONG_ID_POS: usize = 11;
+    // Sub-IDs below this value use the long format
+    pub const NESTED_SHORT_ID_MIN: u8 = 0x10;
+
     pub const TYPE: u16 = 0x8000;
 }
 
@@ -454,7 +474,8 @@ impl<'a> PayloadIE<'a> {
         // Write the two octets that begin each payload IE
         let content_len = off - 2;
         stream_cond!(content_len <= ie_control::PAYLOAD_LEN_MAX);
-        let ie_ctl = ((content_len as u16) & ie_control::PAYLOAD_LEN_MASK)
+        let ie_ctl = ie_control::TYPE
+            | ((content_len as u16) & ie_control::PAYLOAD_LEN_MASK)
             | ((group_id & ie_control::PAYLOAD_ID_MASK) as u16) << ie_control::PAYLOAD_ID_POS;
         enc_consume!(buf; encode_u16, ie_ctl.to_be());
 
@@ -487,6 +508,73 @@ impl<'a> PayloadIE<'a> {
     }
 }
 
+/// An IE nested in the content of an MLME payload IE (IEEE 802.15.4-2015:
+/// 7.4.4.1). Sub-IDs below 0x10 use the long format, which allows for longer
+/// content, and the others use the short format.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct NestedIE<'a> {
+    pub sub_id: u8,
+    pub content: &'a [u8],
+}
+
+impl<'a> NestedIE<'a> {
+    pub fn is_long(&self) -> bool {
+        self.sub_id < ie_control::NESTED_SHORT_ID_MIN
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> SResult {
+        let off = enc_consume!(buf, 2; encode_bytes, self.content);
+
+        // Write the two octets that begin each nested IE
+        let content_len = off - 2;
+        let ie_ctl = if self.is_long() {
+            stream_cond!(content_len <= ie_control::NESTED_LONG_LEN_MAX);
+            ie_control::TYPE
+                | ((content_len as u16) & ie_control::NESTED_LONG_LEN_MASK)
+                | ((self.sub_id & ie_control::NESTED_LONG_ID_MASK) as u16)
+                    << ie_control::NESTED_LONG_ID_POS
+        } else {
+            stream_cond!(content_len <= ie_control::NESTED_SHORT_LEN_MAX);
+            ((content_len as u16) & ie_control::NESTED_SHORT_LEN_MASK)
+                | ((self.sub_id & ie_control::NESTED_SHORT_ID_MASK) as u16)
+                    << ie_control::NESTED_SHORT_ID_POS
+        };
+        enc_consume!(buf; encode_u16, ie_ctl.to_be());
+
+        stream_done!(off);
+    }
+
+    pub fn decode<'b>(buf: &'b [u8]) -> SResult<NestedIE<'b>> {
+        let (off, ie_ctl_be) = dec_try!(buf; decode_u16);
+        let ie_ctl = u16::from_be(ie_ctl_be);
+
+        let (content_len, sub_id) = if ie_ctl & ie_control::TYPE != 0 {
+            (
+                (ie_ctl & ie_control::NESTED_LONG_LEN_MASK) as usize,
+                ((ie_ctl >> ie_control::NESTED_LONG_ID_POS) as u8)
+                    & ie_control::NESTED_LONG_ID_MASK,
+            )
+        } else {
+            (
+                (ie_ctl & ie_control::NESTED_SHORT_LEN_MASK) as usize,
+                ((ie_ctl >> ie_control::NESTED_SHORT_ID_POS) as u8)
+                    & ie_control::NESTED_SHORT_ID_MASK,
+            )
+        };
+
+        stream_len_cond!(buf, off + content_len);
+        let content = &buf[off..off + content_len];
+
+        stream_done!(
+            off + content_len,
+            NestedIE {
+                sub_id: sub_id,
+                content: content,
+            }
+        );
+    }
+}
+
 pub const MAX_HEADER_IES: usize = 5;
 pub const MAX_PAYLOAD_IES: usize = 5;
 
@@ -729,7 +817,8 @@ impl<'a> Header<'a> {
 
         let mut has_payload_ies = false;
         if ie_present {
-            loop {
+            // The IE lists may also end with the frame, without termination
+            while off < buf.len() {
                 let (next_off, ie) = dec_try!(buf, off; HeaderIE::decode);
                 off = next_off;
                 match ie {
@@ -753,7 +842,7 @@ impl<'a> Header<'a> {
         let mac_payload_off = off;
         let unencrypted = unsecured || !security_enabled;
         if has_payload_ies && unencrypted {
-            loop {
+            while off < buf.len() {
                 let (next_off, ie) = dec_try!(buf, off; PayloadIE::decode);
                 off = next_off;
                 match ie {
@@ -863,3 +952,178 @@ impl<'a> Header<'a> {
         stream_done!(off, (dst_pan, dst_addr, src_pan, src_addr));
     }
 }
+
+/// MAC command frame identifiers, which form the first byte of the payload of
+/// a MAC command frame (IEEE 802.15.4-2015: Table 7-49)
+pub mod mac_command {
+    pub const ASSOCIATION_REQUEST: u8 = 0x01;
+    pub const ASSOCIATION_RESPONSE: u8 = 0x02;
+    pub const DISASSOCIATION_NOTIFICATION: u8 = 0x03;
+    pub const DATA_REQUEST: u8 = 0x04;
+    pub const PAN_ID_CONFLICT_NOTIFICATION: u8 = 0x05;
+    pub const ORPHAN_NOTIFICATION: u8 = 0x06;
+    pub const BEACON_REQUEST: u8 = 0x07;
+    pub const COORDINATOR_REALIGNMENT: u8 = 0x08;
+    pub const GTS_REQUEST: u8 = 0x09;
+}
+
+/// Capability Information field of an association request, which describes
+/// the device requesting association (IEEE 802.15.4-2015: 7.5.2)
+pub mod capability_info {
+    pub const DEVICE_TYPE_FFD: u8 = 1 << 1;
+    pub const POWER_SOURCE_MAINS: u8 = 1 << 2;
+    pub const RX_ON_WHEN_IDLE: u8 = 1 << 3;
+    pub const SECURITY_CAPABLE: u8 = 1 << 6;
+    pub const ALLOCATE_ADDRESS: u8 = 1 << 7;
+}
+
+/// Association Status field of an association response
+/// (IEEE 802.15.4-2015: Table 7-50)
+pub mod association_status {
+    pub const SUCCESS: u8 = 0x00;
+    pub const PAN_AT_CAPACITY: u8 = 0x01;
+    pub const PAN_ACCESS_DENIED: u8 = 0x02;
+}
+
+/// Disassociation Reason field of a disassociation notification
+/// (IEEE 802.15.4-2015: Table 7-51)
+pub mod disassociation_reason {
+    pub const COORDINATOR_WISHES_DEVICE_TO_LEAVE: u8 = 0x01;
+    pub const DEVICE_WISHES_TO_LEAVE: u8 = 0x02;
+}
+
+/// Element IDs of header IEs (IEEE 802.15.4-2015: Table 7-7)
+pub mod header_ie_id {
+    pub const ACK_NACK_TIME_CORRECTION: u8 = 0x1e;
+}
+
+/// Group IDs of payload IEs (IEEE 802.15.4-2015: Table 7-15)
+pub mod payload_ie_group {
+    pub const MLME: u8 = 0x1;
+}
+
+/// Sub-IDs of the IEs nested in an MLME payload IE
+/// (IEEE 802.15.4-2015: Tables 7-17 and 7-18)
+pub mod mlme_sub_id {
+    pub const CHANNEL_HOPPING: u8 = 0x09;
+    pub const TSCH_SYNCHRONIZATION: u8 = 0x1a;
+    pub const TSCH_SLOTFRAME_AND_LINK: u8 = 0x1b;
+    pub const TSCH_TIMESLOT: u8 = 0x1c;
+}
+
+mod superframe_spec {
+    pub const BEACON_ORDER_MASK: u16 = 0xf;
+    pub const SUPERFRAME_ORDER_POS: usize = 4;
+    pub const SUPERFRAME_ORDER_MASK: u16 = 0xf;
+    pub const FINAL_CAP_SLOT_POS: usize = 8;
+    pub const FINAL_CAP_SLOT_MASK: u16 = 0xf;
+    pub const BATTERY_LIFE_EXTENSION: u16 = 1 << 12;
+    pub const PAN_COORDINATOR: u16 = 1 << 14;
+    pub const ASSOCIATION_PERMIT: u16 = 1 << 15;
+
+    // Lengths of the GTS and pending address lists in a beacon
+    pub const GTS_DESCRIPTOR_COUNT_MASK: u8 = 0b111;
+    pub const GTS_DESCRIPTOR_LEN: usize = 3;
+    pub const PENDING_SHORT_MASK: u8 = 0b111;
+    pub const PENDING_LONG_POS: usize = 4;
+    pub const PENDING_LONG_MASK: u8 = 0b111;
+}
+
+/// The beacon order that indicates that a PAN does not send periodic beacons
+pub const BEACON_ORDER_NONBEACON: u8 = 15;
+
+/// The Superframe Specification field of a beacon
+/// (IEEE 802.15.4-2015: 7.3.1.3)
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct SuperframeSpec {
+    pub beacon_order: u8,
+    pub superframe_order: u8,
+    pub final_cap_slot: u8,
+    pub battery_life_extension: bool,
+    pub pan_coordinator: bool,
+    pub association_permit: bool,
+}
+
+impl SuperframeSpec {
+    pub fn to_u16(&self) -> u16 {
+        let mut spec = (self.beacon_order as u16) & superframe_spec::BEACON_ORDER_MASK;
+        spec |= ((self.superframe_order as u16) & superframe_spec::SUPERFRAME_ORDER_MASK)
+            << superframe_spec::SUPERFRAME_ORDER_POS;
+        spec |= ((self.final_cap_slot as u16) & superframe_spec::FINAL_CAP_SLOT_MASK)
+            << superframe_spec::FINAL_CAP_SLOT_POS;
+        if self.battery_life_extension {
+            spec |= superframe_spec::BATTERY_LIFE_EXTENSION;
+        }
+        if self.pan_coordinator {
+            spec |= superframe_spec::PAN_COORDINATOR;
+        }
+        if self.association_permit {
+            spec |= superframe_spec::ASSOCIATION_PERMIT;
+        }
+        spec
+    }
+
+    pub fn from_u16(spec: u16) -> SuperframeSpec {
+        SuperframeSpec {
+            beacon_order: (spec & superframe_spec::BEACON_ORDER_MASK) as u8,
+            superframe_order: ((spec >> superframe_spec::SUPERFRAME_ORDER_POS)
+                & superframe_spec::SUPERFRAME_ORDER_MASK) as u8,
+            final_cap_slot: ((spec >> superframe_spec::FINAL_CAP_SLOT_POS)
+                & superframe_spec::FINAL_CAP_SLOT_MASK) as u8,
+            battery_life_extension: (spec & superframe_spec::BATTERY_LIFE_EXTENSION) != 0,
+            pan_coordinator: (spec & superframe_spec::PAN_COORDINATOR) != 0,
+            association_permit: (spec & superframe_spec::ASSOCIATION_PERMIT) != 0,
+        }
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> SResult {
+        stream_len_cond!(buf, 2);
+        enc_try!(buf; encode_u16, self.to_u16().to_be());
+        stream_done!(2);
+    }
+
+    pub fn decode(buf: &[u8]) -> SResult<SuperframeSpec> {
+        let (off, spec_be) = dec_try!(buf; decode_u16);
+        stream_done!(off, SuperframeSpec::from_u16(u16::from_be(spec_be)));
+    }
+}
+
+/// Encodes the fields that precede the beacon payload in the MAC payload of a
+/// beacon frame (IEEE 802.15.4-2015: 7.3.1). Guaranteed time slots are not
+/// supported, and pending addresses are not yet advertised, so both lists are
+/// empty.
+pub fn encode_beacon(buf: &mut [u8], superframe_spec: SuperframeSpec) -> SResult {
+    let off = enc_consume!(buf; superframe_spec; encode);
+    // GTS Specification field with a descriptor count of 0, which omits the
+    // GTS Directions and GTS List fields
+    let off = enc_consume!(buf, off; encode_u8, 0);
+    // Pending Address Specification field with no pending addresses
+    let off = enc_consume!(buf, off; encode_u8, 0);
+    stream_done!(off);
+}
+
+/// Decodes the fields that precede the beacon payload in the MAC payload of a
+/// beacon frame, skipping over any GTS and pending address lists. The
+/// returned offset is that of the beacon payload.
+pub fn decode_beacon(buf: &[u8]) -> SResult<SuperframeSpec> {
+    let (off, spec) = dec_try!(buf; SuperframeSpec::decode);
+
+    let (off, gts_spec) = dec_try!(buf, off; decode_u8);
+    let gts_count = (gts_spec & superframe_spec::GTS_DESCRIPTOR_COUNT_MASK) as usize;
+    let off = if gts_count > 0 {
+        // GTS Directions field followed by the GTS descriptors
+        off + 1 + gts_count * superframe_spec::GTS_DESCRIPTOR_LEN
+    } else {
+        off
+    };
+    stream_len_cond!(buf, off + 1);
+
+    let (off, pending_spec) = dec_try!(buf, off; decode_u8);
+    let num_short = (pending_spec & superframe_spec::PENDING_SHORT_MASK) as usize;
+    let num_long = ((pending_spec >> superframe_spec::PENDING_LONG_POS)
+        & superframe_spec::PENDING_LONG_MASK) as usize;
+    let off = off + num_short * 2 + num_long * 8;
+    stream_len_cond!(buf, off);
+
+    stream_done!(off, spec);
+}
@@ -1,3 +1,4 @@
+use core::cmp;
 use net::stream::{decode_bytes, decode_u16, decode_u8};
 use net::stream::{encode_bytes, encode_u16, encode_u8};
 use net::stream::SResult;
@@ -21,7 +22,7 @@ pub mod ip6_nh {
     pub const MOBILITY: u8 = 135;
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct IPAddr(pub [u8; 16]);
 
 impl IPAddr {
@@ -216,3 +217,664 @@ impl IP6Header {
         self.hop_limit = new_hl;
     }
 }
+
+/// Types and actions of the TLV-encoded options carried in Hop-by-Hop and
+/// Destination Options headers (RFC 8200, Section 4.2)
+pub mod ip6_opt {
+    pub const PAD1: u8 = 0;
+    pub const PADN: u8 = 1;
+
+    // The two high-order bits of the option type specify the action to take
+    // if the option is not recognized
+    pub const ACTION_MASK: u8 = 0xc0;
+    pub const ACTION_SKIP: u8 = 0x00;
+    pub const ACTION_DISCARD: u8 = 0x40;
+    pub const ACTION_DISCARD_ICMP: u8 = 0x80;
+    pub const ACTION_DISCARD_ICMP_UNICAST: u8 = 0xc0;
+
+    // Set if the option data may change en route to the final destination
+    pub const CHANGE_FLAG: u8 = 0x20;
+}
+
+/// Routing header types
+pub mod ip6_routing {
+    // RPL Source Route Header (RFC 6554)
+    pub const SOURCE_ROUTE: u8 = 3;
+}
+
+/// ICMPv6 Parameter Problem message type and codes (RFC 4443, Section 3.4)
+pub mod icmp6_param_problem {
+    pub const TYPE: u8 = 4;
+    pub const ERRONEOUS_HEADER_FIELD: u8 = 0;
+    pub const UNRECOGNIZED_NEXT_HEADER: u8 = 1;
+    pub const UNRECOGNIZED_OPTION: u8 = 2;
+}
+
+// Size of the fixed IPv6 header
+const IP6_HDR_SIZE: usize = 40;
+
+// The minimum IPv6 MTU, which bounds the size of ICMPv6 error messages
+const IP6_MIN_MTU: usize = 1280;
+
+/// Returns true if `next_header` is an extension header, as opposed to an
+/// upper-layer protocol.
+pub fn is_ext_header(next_header: u8) -> bool {
+    match next_header {
+        ip6_nh::HOP_OPTS
+        | ip6_nh::ROUTING
+        | ip6_nh::FRAGMENT
+        | ip6_nh::DST_OPTS
+        | ip6_nh::MOBILITY => true,
+        _ => false,
+    }
+}
+
+/// Returns the length in bytes of the extension header of type `header_type`
+/// at the start of `buf`, or `None` if `buf` is too short.
+pub fn ext_header_len(header_type: u8, buf: &[u8]) -> Option<usize> {
+    if buf.len() < 2 {
+        return None;
+    }
+    // The fragment header has a fixed length and no length field
+    let len = if header_type == ip6_nh::FRAGMENT {
+        8
+    } else {
+        8 + (buf[1] as usize) * 8
+    };
+    if len <= buf.len() {
+        Some(len)
+    } else {
+        None
+    }
+}
+
+/// Why a packet must not be processed any further. The pointers are offsets
+/// from the start of the IPv6 header.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ExtHeaderError {
+    /// The packet must be silently discarded
+    Discard,
+    /// The packet must be discarded, and an ICMPv6 Parameter Problem message
+    /// with the given code and pointer sent to its source
+    ParameterProblem(u8, u32),
+}
+
+/// An extension header in an IPv6 packet
+#[derive(Copy, Clone)]
+pub struct ExtHeader<'a> {
+    /// The type of this header, as given by the preceding next header field
+    pub header_type: u8,
+    /// The type of the header following this one
+    pub next_header: u8,
+    /// The offset of this header from the start of the IPv6 header
+    pub offset: usize,
+    /// The whole header, including its next header and length fields
+    pub buf: &'a [u8],
+}
+
+impl<'a> ExtHeader<'a> {
+    /// Iterates over the options of a Hop-by-Hop or Destination Options
+    /// header, or returns `None` for other header types.
+    pub fn options(&self) -> Option<OptionIter<'a>> {
+        match self.header_type {
+            ip6_nh::HOP_OPTS | ip6_nh::DST_OPTS => Some(OptionIter {
+                buf: self.buf,
+                header_offset: self.offset,
+                offset: 2,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Checks the options of a Hop-by-Hop or Destination Options header.
+    ///
+    /// `is_known` is called with the type of every option other than padding,
+    /// and the action encoded in the option type is taken for the options it
+    /// does not recognize (RFC 8200, Section 4.2). ICMPv6 errors are not
+    /// requested for packets sent to a multicast address if the option type
+    /// says so.
+    pub fn check_options(
+        &self,
+        dst_is_multicast: bool,
+        is_known: &Fn(u8) -> bool,
+    ) -> Result<(), ExtHeaderError> {
+        let mut options = match self.options() {
+            Some(options) => options,
+            None => return Ok(()),
+        };
+        for option in &mut options {
+            if is_known(option.option_type) {
+                continue;
+            }
+            let problem = ExtHeaderError::ParameterProblem(
+                icmp6_param_problem::UNRECOGNIZED_OPTION,
+                option.offset as u32,
+            );
+            match option.option_type & ip6_opt::ACTION_MASK {
+                ip6_opt::ACTION_SKIP => continue,
+                ip6_opt::ACTION_DISCARD => return Err(ExtHeaderError::Discard),
+                ip6_opt::ACTION_DISCARD_ICMP => return Err(problem),
+                _ => {
+                    return Err(if dst_is_multicast {
+                        ExtHeaderError::Discard
+                    } else {
+                        problem
+                    })
+                }
+            }
+        }
+        if options.is_malformed() {
+            // Point at the option that overruns the header
+            Err(ExtHeaderError::ParameterProblem(
+                icmp6_param_problem::ERRONEOUS_HEADER_FIELD,
+                (self.offset + options.offset) as u32,
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Iterates over the extension header chain of an IPv6 packet, starting from
+/// the next header of the fixed IPv6 header.
+///
+/// Iteration stops at the first upper-layer header, or at the first header
+/// that does not fit in the packet, in which case `is_malformed` returns true.
+/// Once the iterator is exhausted, `upper_layer` returns the upper-layer
+/// protocol and its offset.
+pub struct ExtHeaderIter<'a> {
+    packet: &'a [u8],
+    next_header: u8,
+    offset: usize,
+    malformed: bool,
+}
+
+impl<'a> ExtHeaderIter<'a> {
+    pub fn new(packet: &'a [u8]) -> ExtHeaderIter<'a> {
+        match IP6Header::decode(packet).done() {
+            Some((offset, ip6_header)) => ExtHeaderIter {
+                packet: packet,
+                next_header: ip6_header.next_header,
+                offset: offset,
+                malformed: false,
+            },
+            None => ExtHeaderIter {
+                packet: packet,
+                next_header: ip6_nh::NO_NEXT,
+                offset: packet.len(),
+                malformed: true,
+            },
+        }
+    }
+
+    pub fn is_malformed(&self) -> bool {
+        self.malformed
+    }
+
+    /// Returns the next header type following the headers iterated over so
+    /// far, and its offset from the start of the IPv6 header.
+    pub fn upper_layer(&self) -> Result<(u8, usize), ()> {
+        if self.malformed {
+            Err(())
+        } else {
+            Ok((self.next_header, self.offset))
+        }
+    }
+}
+
+impl<'a> Iterator for ExtHeaderIter<'a> {
+    type Item = ExtHeader<'a>;
+
+    fn next(&mut self) -> Option<ExtHeader<'a>> {
+        if self.malformed || !is_ext_header(self.next_header) {
+            return None;
+        }
+        let header_type = self.next_header;
+        let buf = &self.packet[self.offset..];
+        match ext_header_len(header_type, buf) {
+            Some(len) => {
+                let header = ExtHeader {
+                    header_type: header_type,
+                    next_header: buf[0],
+                    offset: self.offset,
+                    buf: &buf[0..len],
+                };
+                self.next_header = header.next_header;
+                self.offset += len;
+                Some(header)
+            }
+            None => {
+                self.malformed = true;
+                None
+            }
+        }
+    }
+}
+
+/// An option in a Hop-by-Hop or Destination Options header
+#[derive(Copy, Clone)]
+pub struct TLVOption<'a> {
+    pub option_type: u8,
+    /// The offset of the option type from the start of the IPv6 header
+    pub offset: usize,
+    pub data: &'a [u8],
+}
+
+/// Iterates over the options of a Hop-by-Hop or Destination Options header,
+/// skipping Pad1 and PadN options.
+pub struct OptionIter<'a> {
+    buf: &'a [u8],
+    header_offset: usize,
+    offset: usize,
+}
+
+impl<'a> OptionIter<'a> {
+    /// Returns true if iteration stopped at an option that does not fit in
+    /// the header.
+    pub fn is_malformed(&self) -> bool {
+        self.offset < self.buf.len()
+    }
+}
+
+impl<'a> Iterator for OptionIter<'a> {
+    type Item = TLVOption<'a>;
+
+    fn next(&mut self) -> Option<TLVOption<'a>> {
+        while self.offset < self.buf.len() {
+            let option_type = self.buf[self.offset];
+            if option_type == ip6_opt::PAD1 {
+                self.offset += 1;
+                continue;
+            }
+            if self.offset + 2 > self.buf.len() {
+                return None;
+            }
+            let data_start = self.offset + 2;
+            let data_end = data_start + (self.buf[self.offset + 1] as usize);
+            if data_end > self.buf.len() {
+                return None;
+            }
+            let option = TLVOption {
+                option_type: option_type,
+                offset: self.header_offset + self.offset,
+                data: &self.buf[data_start..data_end],
+            };
+            self.offset = data_end;
+            if option_type != ip6_opt::PADN {
+                return Some(option);
+            }
+        }
+        None
+    }
+}
+
+/// Checks the extension headers of a received packet, and returns the
+/// upper-layer protocol and its offset if the packet can be processed further.
+///
+/// `is_known_option` is called with the header type and option type of every
+/// option in Hop-by-Hop and Destination Options headers. Routing headers of
+/// types other than `ip6_routing::SOURCE_ROUTE` are ignored once they have no
+/// segments left, as RFC 8200 requires. Source routes that still have
+/// segments left are not processed here; see `SourceRouteHeader::advance`.
+pub fn check_ext_headers(
+    packet: &[u8],
+    is_known_option: &Fn(u8, u8) -> bool,
+) -> Result<(u8, usize), ExtHeaderError> {
+    let mut headers = ExtHeaderIter::new(packet);
+    let dst_is_multicast = packet.len() >= IP6_HDR_SIZE && packet[24] == 0xff;
+    for header in &mut headers {
+        // The Hop-by-Hop Options header may only follow the IPv6 header
+        if header.header_type == ip6_nh::HOP_OPTS && header.offset != IP6_HDR_SIZE {
+            return Err(ExtHeaderError::ParameterProblem(
+                icmp6_param_problem::UNRECOGNIZED_NEXT_HEADER,
+                next_header_field_offset(packet, header.offset) as u32,
+            ));
+        }
+        let header_type = header.header_type;
+        header.check_options(dst_is_multicast, &|option_type| {
+            is_known_option(header_type, option_type)
+        })?;
+        if header.header_type == ip6_nh::ROUTING {
+            let routing_type = header.buf[2];
+            let segments_left = header.buf[3];
+            if routing_type != ip6_routing::SOURCE_ROUTE && segments_left != 0 {
+                return Err(ExtHeaderError::ParameterProblem(
+                    icmp6_param_problem::ERRONEOUS_HEADER_FIELD,
+                    (header.offset + 2) as u32,
+                ));
+            }
+        }
+    }
+    headers.upper_layer().map_err(|_| ExtHeaderError::Discard)
+}
+
+// Returns the offset of the next header field that identifies the header at
+// `header_offset`
+fn next_header_field_offset(packet: &[u8], header_offset: usize) -> usize {
+    let mut field_offset = 6;
+    for header in ExtHeaderIter::new(packet) {
+        if header.offset >= header_offset {
+            break;
+        }
+        field_offset = header.offset;
+    }
+    field_offset
+}
+
+/// Writes a Hop-by-Hop or Destination Options header containing `options`,
+/// each given as an option type and its data, to `buf`. The header is padded
+/// to a multiple of 8 bytes. Returns the length of the header.
+pub fn encode_options_header(
+    buf: &mut [u8],
+    next_header: u8,
+    options: &[(u8, &[u8])],
+) -> SResult<usize> {
+    let mut off = enc_consume!(buf, 0; encode_u8, next_header);
+    // The length is filled in once the options are written
+    off = enc_consume!(buf, off; encode_u8, 0);
+    for &(option_type, data) in options {
+        stream_cond!(data.len() <= 255);
+        off = enc_consume!(buf, off; encode_u8, option_type);
+        off = enc_consume!(buf, off; encode_u8, data.len() as u8);
+        off = enc_consume!(buf, off; encode_bytes, data);
+    }
+    let pad_len = (8 - off % 8) % 8;
+    if pad_len == 1 {
+        off = enc_consume!(buf, off; encode_u8, ip6_opt::PAD1);
+    } else if pad_len > 1 {
+        off = enc_consume!(buf, off; encode_u8, ip6_opt::PADN);
+        off = enc_consume!(buf, off; encode_u8, (pad_len - 2) as u8);
+        for _ in 0..pad_len - 2 {
+            off = enc_consume!(buf, off; encode_u8, 0);
+        }
+    }
+    stream_cond!(off / 8 - 1 <= 255);
+    buf[1] = (off / 8 - 1) as u8;
+    stream_done!(off, off);
+}
+
+/// Writes a Fragment header to `buf`. `fragment_offset` is in bytes, and must
+/// be a multiple of 8. Returns the length of the header.
+pub fn encode_fragment_header(
+    buf: &mut [u8],
+    next_header: u8,
+    fragment_offset: u16,
+    more_fragments: bool,
+    identification: u32,
+) -> SResult<usize> {
+    stream_cond!(fragment_offset % 8 == 0);
+    let offset_flags = fragment_offset | (more_fragments as u16);
+    let mut off = enc_consume!(buf, 0; encode_u8, next_header);
+    off = enc_consume!(buf, off; encode_u8, 0);
+    off = enc_consume!(buf, off; encode_u16, offset_flags);
+    off = enc_consume!(buf, off; encode_u16, (identification >> 16) as u16);
+    off = enc_consume!(buf, off; encode_u16, identification as u16);
+    stream_done!(off, off);
+}
+
+// Returns the number of leading bytes, at most 15, that two addresses share
+fn shared_prefix_len(addr1: &IPAddr, addr2: &IPAddr) -> usize {
+    addr1.0
+        .iter()
+        .zip(addr2.0.iter())
+        .take(15)
+        .take_while(|&(b1, b2)| b1 == b2)
+        .count()
+}
+
+/// The fixed fields of a RPL Source Route Header (RFC 6554), which carries
+/// the addresses a packet is routed through after its current destination.
+///
+/// Addresses in the header are stored without the leading bytes they share
+/// with the destination address of the IPv6 header: `cmpr_i` bytes are elided
+/// from all addresses but the last, and `cmpr_e` bytes from the last one.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SourceRouteHeader {
+    pub next_header: u8,
+    pub segments_left: u8,
+    pub cmpr_i: u8,
+    pub cmpr_e: u8,
+    pub pad: u8,
+    /// The number of addresses in the header
+    pub num_addrs: usize,
+}
+
+impl SourceRouteHeader {
+    // Size of the fields preceding the addresses
+    const FIXED_SIZE: usize = 8;
+
+    /// Writes a source route header routing a packet through `route`, after
+    /// the IPv6 destination address `ip6_dst_addr`, which is the first hop.
+    /// The last address of `route` is the final destination. Returns the
+    /// length of the header.
+    pub fn encode(
+        buf: &mut [u8],
+        next_header: u8,
+        ip6_dst_addr: &IPAddr,
+        route: &[IPAddr],
+    ) -> SResult<usize> {
+        stream_cond!(route.len() > 0 && route.len() <= 255);
+        let (last, intermediate) = route.split_last().unwrap();
+        let cmpr_e = shared_prefix_len(ip6_dst_addr, last);
+        let cmpr_i = intermediate
+            .iter()
+            .map(|addr| shared_prefix_len(ip6_dst_addr, addr))
+            .min()
+            .unwrap_or(0);
+        let addrs_len = intermediate.len() * (16 - cmpr_i) + (16 - cmpr_e);
+        let pad = (8 - addrs_len % 8) % 8;
+        let len = Self::FIXED_SIZE + addrs_len + pad;
+        stream_len_cond!(buf, len);
+
+        let mut off = enc_consume!(buf, 0; encode_u8, next_header);
+        off = enc_consume!(buf, off; encode_u8, (len / 8 - 1) as u8);
+        off = enc_consume!(buf, off; encode_u8, ip6_routing::SOURCE_ROUTE);
+        off = enc_consume!(buf, off; encode_u8, route.len() as u8);
+        off = enc_consume!(buf, off; encode_u8, ((cmpr_i as u8) << 4) | (cmpr_e as u8));
+        off = enc_consume!(buf, off; encode_u8, (pad as u8) << 4);
+        off = enc_consume!(buf, off; encode_u16, 0);
+        for addr in intermediate {
+            off = enc_consume!(buf, off; encode_bytes, &addr.0[cmpr_i..]);
+        }
+        off = enc_consume!(buf, off; encode_bytes, &last.0[cmpr_e..]);
+        for _ in 0..pad {
+            off = enc_consume!(buf, off; encode_u8, 0);
+        }
+        stream_done!(off, off);
+    }
+
+    /// Decodes the fixed fields of the source route header at the start of
+    /// `buf`, which must be the entire routing header.
+    pub fn decode(buf: &[u8]) -> SResult<SourceRouteHeader> {
+        stream_len_cond!(buf, Self::FIXED_SIZE);
+        let (off, next_header) = dec_try!(buf, 0; decode_u8);
+        let (off, hdr_ext_len) = dec_try!(buf, off; decode_u8);
+        let (off, routing_type) = dec_try!(buf, off; decode_u8);
+        stream_cond!(routing_type == ip6_routing::SOURCE_ROUTE);
+        let (off, segments_left) = dec_try!(buf, off; decode_u8);
+        let (off, cmpr) = dec_try!(buf, off; decode_u8);
+        let (off, pad_reserved) = dec_try!(buf, off; decode_u8);
+        let off = off + 2;
+
+        let len = 8 + (hdr_ext_len as usize) * 8;
+        stream_len_cond!(buf, len);
+        let cmpr_i = cmpr >> 4;
+        let cmpr_e = cmpr & 0x0f;
+        let pad = pad_reserved >> 4;
+        // n = (((Hdr Ext Len * 8) - Pad - (16 - CmprE)) / (16 - CmprI)) + 1
+        let addrs_len = (hdr_ext_len as usize) * 8;
+        stream_cond!(addrs_len >= (pad as usize) + 16 - (cmpr_e as usize));
+        let intermediate_len = addrs_len - (pad as usize) - (16 - cmpr_e as usize);
+        stream_cond!(intermediate_len % (16 - cmpr_i as usize) == 0);
+        let num_addrs = intermediate_len / (16 - cmpr_i as usize) + 1;
+        stream_done!(
+            off,
+            SourceRouteHeader {
+                next_header: next_header,
+                segments_left: segments_left,
+                cmpr_i: cmpr_i,
+                cmpr_e: cmpr_e,
+                pad: pad,
+                num_addrs: num_addrs,
+            }
+        );
+    }
+
+    // Returns the offset and length of the i-th address (starting from 0)
+    // within the header
+    fn addr_range(&self, i: usize) -> (usize, usize) {
+        let intermediate_len = 16 - self.cmpr_i as usize;
+        let len = if i + 1 == self.num_addrs {
+            16 - self.cmpr_e as usize
+        } else {
+            intermediate_len
+        };
+        (Self::FIXED_SIZE + i * intermediate_len, len)
+    }
+
+    /// Returns the i-th address (starting from 0) of the route carried in
+    /// `buf`, restoring its elided prefix from `ip6_dst_addr`.
+    pub fn get_addr(&self, buf: &[u8], i: usize, ip6_dst_addr: &IPAddr) -> IPAddr {
+        let (offset, len) = self.addr_range(i);
+        let mut addr = *ip6_dst_addr;
+        addr.0[16 - len..].copy_from_slice(&buf[offset..offset + len]);
+        addr
+    }
+
+    /// Processes the source route header at `offset` in `packet` at a node
+    /// the packet is addressed to (RFC 6554, Section 4.2). If the route has
+    /// segments left, the destination address of the packet is swapped with
+    /// the next address of the route, and true is returned to indicate that
+    /// the packet must be forwarded to its new destination. Returns false if
+    /// this node is the final destination.
+    pub fn advance(packet: &mut [u8], offset: usize) -> Result<bool, ExtHeaderError> {
+        let header = match SourceRouteHeader::decode(&packet[offset..]).done() {
+            Some((_, header)) => header,
+            None => {
+                return Err(ExtHeaderError::ParameterProblem(
+                    icmp6_param_problem::ERRONEOUS_HEADER_FIELD,
+                    (offset + 1) as u32,
+                ))
+            }
+        };
+        if header.segments_left == 0 {
+            return Ok(false);
+        }
+        if header.segments_left as usize > header.num_addrs {
+            return Err(ExtHeaderError::ParameterProblem(
+                icmp6_param_problem::ERRONEOUS_HEADER_FIELD,
+                (offset + 3) as u32,
+            ));
+        }
+        let segments_left = header.segments_left - 1;
+        let i = header.num_addrs - 1 - segments_left as usize;
+
+        let mut ip6_dst_addr = IPAddr::new();
+        ip6_dst_addr.0.copy_from_slice(&packet[24..40]);
+        let next_addr = header.get_addr(&packet[offset..], i, &ip6_dst_addr);
+        if ip6_dst_addr.is_multicast() || next_addr.is_multicast() {
+            return Err(ExtHeaderError::Discard);
+        }
+        // The current destination takes the place of the next address, which
+        // shares the same elided prefix
+        let (addr_offset, len) = header.addr_range(i);
+        let addr_offset = offset + addr_offset;
+        packet[addr_offset..addr_offset + len].copy_from_slice(&ip6_dst_addr.0[16 - len..]);
+        packet[24..40].copy_from_slice(&next_addr.0);
+        packet[offset + 3] = segments_left;
+        Ok(true)
+    }
+}
+
+/// Computes the checksum of an upper-layer packet, such as a UDP datagram or
+/// an ICMPv6 message, including the IPv6 pseudo-header (RFC 8200, Section
+/// 8.1). The packet is passed as its `header` and `payload`, which need not be
+/// contiguous; `header` must have an even length and its checksum field must
+/// either be zero or be left out. `length` is the length of the whole
+/// upper-layer packet. Returns the checksum in host byte-order.
+pub fn compute_checksum(
+    src_addr: &IPAddr,
+    dst_addr: &IPAddr,
+    next_header: u8,
+    length: u16,
+    header: &[u8],
+    payload: &[u8],
+) -> u16 {
+    let mut sum: u32 = 0;
+    for chunk in src_addr.0.chunks(2).chain(dst_addr.0.chunks(2)) {
+        sum += ((chunk[0] as u32) << 8) | (chunk[1] as u32);
+    }
+    sum += length as u32;
+    sum += next_header as u32;
+    for chunk in header.chunks(2).chain(payload.chunks(2)) {
+        sum += (chunk[0] as u32) << 8;
+        // An odd trailing byte is padded with a zero byte
+        if chunk.len() > 1 {
+            sum += chunk[1] as u32;
+        }
+    }
+    while (sum >> 16) != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    let checksum = !(sum as u16);
+    // A computed checksum of zero is sent as all ones
+    if checksum == 0 {
+        0xffff
+    } else {
+        checksum
+    }
+}
+
+/// Writes an ICMPv6 Parameter Problem message reporting an error in
+/// `invoking_packet`, as returned by `check_ext_headers`, to `buf`. The message
+/// is sent from `src_addr` to the source of the invoking packet, and includes
+/// as much of the invoking packet as fits in the minimum IPv6 MTU and in
+/// `buf`. Returns the length of the whole IPv6 packet.
+pub fn encode_parameter_problem(
+    buf: &mut [u8],
+    src_addr: IPAddr,
+    hop_limit: u8,
+    invoking_packet: &[u8],
+    code: u8,
+    pointer: u32,
+) -> SResult<usize> {
+    let (_, invoking_header) = dec_try!(IP6Header::decode(invoking_packet));
+    let icmp_header_len = 8;
+    let max_len =
+        cmp::min(IP6_MIN_MTU, buf.len()).saturating_sub(IP6_HDR_SIZE + icmp_header_len);
+    let invoking_len = if invoking_packet.len() < max_len {
+        invoking_packet.len()
+    } else {
+        max_len
+    };
+    let icmp_len = icmp_header_len + invoking_len;
+    stream_len_cond!(buf, IP6_HDR_SIZE + icmp_len);
+
+    let mut ip6_header = IP6Header::new();
+    ip6_header.src_addr = src_addr;
+    ip6_header.dst_addr = invoking_header.src_addr;
+    ip6_header.next_header = ip6_nh::ICMP;
+    ip6_header.hop_limit = hop_limit;
+    ip6_header.set_payload_len(icmp_len as u16);
+    let off = enc_consume!(IP6Header::encode(buf, ip6_header));
+
+    let icmp_start = off;
+    let mut off = enc_consume!(buf, off; encode_u8, icmp6_param_problem::TYPE);
+    off = enc_consume!(buf, off; encode_u8, code);
+    // The checksum is computed once the message is complete
+    off = enc_consume!(buf, off; encode_u16, 0);
+    off = enc_consume!(buf, off; encode_u16, (pointer >> 16) as u16);
+    off = enc_consume!(buf, off; encode_u16, pointer as u16);
+    off = enc_consume!(buf, off; encode_bytes, &invoking_packet[0..invoking_len]);
+    let checksum = compute_checksum(
+        &ip6_header.src_addr,
+        &ip6_header.dst_addr,
+        ip6_nh::ICMP,
+        (off - icmp_start) as u16,
+        &[],
+        &buf[icmp_start..off],
+    );
+    buf[icmp_start + 2] = (checksum >> 8) as u8;
+    buf[icmp_start + 3] = checksum as u8;
+    stream_done!(off, off);
+}
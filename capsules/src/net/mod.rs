@@ -1,11 +1,13 @@
 //! Modules for IPv6 over 6LoWPAN stack
 
+#[macro_use]
+pub mod stream;
 pub mod sixlowpan;
 pub mod sixlowpan_compression;
 pub mod util;
 pub mod frag_utils;
-#[macro_use]
-pub mod stream;
 pub mod ieee802154;
 pub mod thread;
 pub mod ip;
+pub mod coap;
+pub mod ble;
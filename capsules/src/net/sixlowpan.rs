@@ -10,7 +10,9 @@
 //! versa. On the transmission end, IPv6 headers are compressed and packets
 //! fragmented if they are larger than the Mac layer MTU size.  For reception,
 //! IPv6 packets are decompressed and reassembled from fragments and clients
-//! recieve callbacks for each full IPv6 packet.
+//! recieve callbacks for each full IPv6 packet. Optionally, packets can also
+//! be sent and forwarded over several link-layer hops using the mesh and
+//! broadcast headers defined in RFC 4944 ("mesh-under" routing).
 //!
 //! Usage
 //! --------------
@@ -18,8 +20,10 @@
 //! Clients use the [Sixlowpan](struct.Sixlowpan.html) struct to send packets
 //! while they implement the [SixlowpanClient](trait.Sixlowpan.html) trait to
 //! receive IPv6 packets as well as to be notified when a packet transmission
-//! has completed. [Sixlowpan](struct.Sixlowpan.html) can send one packet at a
-//! time, so any virtualization or multiplexing must be implemented elsewhere.
+//! has completed. Several upper layers, such as UDP and ICMPv6, can use
+//! [Sixlowpan](struct.Sixlowpan.html) at the same time through their own
+//! [SixlowpanUser](struct.SixlowpanUser.html)s. Each user can send one packet
+//! at a time, and receives the packets that match its next header and port.
 //!
 //! At a high level, clients interact with this module as shown in the diagrams
 //! below:
@@ -80,6 +84,38 @@
 //!            +---------+
 //! ```
 //!
+//! Additional upper layers each register their own user, and send packets
+//! with `transmit_packet_from`:
+//!
+//! ```
+//! let udp_user = static_init!(
+//!     capsules::net::sixlowpan::SixlowpanUser<'static>,
+//!     capsules::net::sixlowpan::SixlowpanUser::new(Some(ip6_nh::UDP), Some(5683)));
+//! udp_user.set_client(udp_client);
+//! sixlowpan.add_user(udp_user);
+//! ```
+//!
+//! Packets in progress are compressed and addressed using the link-layer
+//! addresses of this node, so they are discarded when its association state
+//! changes. For this, the layer is registered with the MAC layer management
+//! entity:
+//!
+//! ```
+//! mlme.add_association_client(sixlowpan);
+//! ```
+//!
+//! Received packets are only delivered once their extension headers have
+//! been checked with `ip::check_ext_headers`. This layer does not process any
+//! options, so packets that carry options are handled as their option types
+//! specify: the options are skipped, or the packet is discarded, in some
+//! cases after sending an ICMPv6 Parameter Problem message to its source.
+//! Such messages are only sent once a buffer has been provided for them:
+//!
+//! ```
+//! static mut ICMP_ERROR_BUF: [u8; 128] = [0; 128];
+//! sixlowpan.enable_icmp_errors(&mut ICMP_ERROR_BUF);
+//! ```
+//!
 //! Examples
 //! -----
 //! Examples of how to interface and use this layer are included in the file
@@ -99,16 +135,16 @@
 // documentation, please consult `capsules/src/net/sixlowpan_compression.rs`).
 //
 // This layer adds several new structures; principally, it implements the
-// Sixlowpan, TxState, and RxState structs. Further, this layer also defines
-// the SixlowpanClient trait. The Sixlowpan struct is responsible
+// Sixlowpan, SixlowpanUser, TxState, and RxState structs. Further, this layer
+// also defines the SixlowpanClient trait. The Sixlowpan struct is responsible
 // for keeping track of the global state of this layer, and contains references
-// to the TxState and the list of RxStates. The TxState is responsible for
-// maintaining the current transmit state, and how much of the current
-// IPv6 packet has been transmitted. The RxState structs maintain the
-// reassembly state corresponding to a single IPv6 packet. Note that since
-// they are maintained as a list, several RxStates can be allocated at compile
-// time, and each RxState corresponds to a distinct IPv6 packet that can be
-// reassembled simultaneously. Finally, the SixlowpanClient trait defines
+// to the list of SixlowpanUsers and the list of RxStates. Each SixlowpanUser
+// owns a TxState, which is responsible for maintaining the transmit state of
+// one packet, and how much of that IPv6 packet has been transmitted. The
+// RxState structs maintain the reassembly state corresponding to a single
+// IPv6 packet. Note that since they are maintained as a list, several
+// RxStates can be allocated at compile time, and each RxState corresponds to
+// a distinct IPv6 packet that can be reassembled simultaneously. Finally, the SixlowpanClient trait defines
 // the interface between the upper (IP) layer and the Sixlowpan layer.
 // Each object is examined in greater detail below:
 //
@@ -121,20 +157,26 @@
 // fragmenting IPv6 packets via the IP layer. As a result, the `Sixlowpan`
 // struct maintains the single, global state relevent for this layer, including
 // a reference to the radio, the context store (for (de)compressing 6LoWPAN-
-// compressed fragments), a clock, and the upper-layer client callback.
-// Additionally, this object maintains references to a single TxState, and
-// a list of RxStates.
+// compressed fragments), a clock, the buffer for the frame in transmission,
+// and the global datagram tag. Additionally, this object maintains a list of
+// SixlowpanUsers, and a list of RxStates.
 //
 // TxState:
 // The TxState struct maintains the state necessary to incrementally fragment
 // and send a full IPv6 packet. This includes the source/destination Mac
 // addresses and PanIDs, frame-level security options, a total datagram size,
-// and the current offset into the datagram. This struct also maintains some
-// minimal global transmit state, including the global datagram tag and a
-// buffer to pass to the radio. This object is visible only to the
-// Sixlowpan struct, and abstracts away the details for transmitting and
+// and the current offset into the datagram. This object is visible only to
+// the Sixlowpan struct, and abstracts away the details for transmitting and
 // fragmenting packets.
 //
+// SixlowpanUser:
+// A SixlowpanUser represents one upper layer (for example UDP, ICMPv6, or a
+// raw IPv6 user), and holds its SixlowpanClient, a TxState, and a filter on
+// the next header and UDP destination port of received packets. Users are
+// allocated statically and registered with the Sixlowpan struct, like
+// RxStates. The client passed to `Sixlowpan::set_client` is wrapped in a
+// user owned by the Sixlowpan struct, which receives all packets.
+//
 // RxState:
 // The RxState struct is analogous to the TxState struct, in that it maintains
 // state specific to reassembling an IPv6 packet. Unlike the TxState struct
@@ -154,11 +196,10 @@
 //
 // SixlowpanClient:
 // The SixlowpanClient trait has two functions; `send_done` and `receive`.
-// The Sixlowpan struct maintains a reference to the (current) SixlowpanClient,
-// and issues callbacks when transmissions have completed (`send_done`) or
-// a full IPv6 packet has been reassembled (`receive`). Note that the
-// Sixlowpan object allows for the client to change at runtime, but the
-// current assumption is a single layer sitting above the 6LoWPAN layer.
+// Each SixlowpanUser maintains a reference to its SixlowpanClient, which
+// receives callbacks when the user's transmissions have completed
+// (`send_done`) or a full IPv6 packet matching the user's filter has been
+// reassembled (`receive`).
 //
 //
 // Design Decisions
@@ -185,25 +226,25 @@
 // increased the complexity of this layer substantially, and further,
 // necessitated additional initialization complexity by the upper layer.
 //
-// Single TxState:
-// Although both the RxState and TxState structs are treated similarly by
-// the Sixlowpan layer, many aspects of their control flow differ
-// significantly. The final design decision was to have a single upper layer
-// that serialized (or virtualized) both the reception and transmission of
-// IPv6 packets. As a result, only a single outstanding transmission made
-// sense, and thus the layer was designed to have a serial transmit path.
-// Note that this differs greatly from the RxState model, but since we
-// cannot serialize reception in the same way, it did not make sense to treat
-// both RxState and TxState structs identically.
+// One TxState per user:
+// Only one frame can be handed to the radio at a time, but a fragmented
+// packet takes many frames to send. If the layer only had a single TxState,
+// a user sending a large packet would hold up every other user until all of
+// its fragments were sent, and the others would have to retry on EBUSY.
+// Instead, each SixlowpanUser has its own TxState, and whenever the radio
+// finishes sending a frame the Sixlowpan struct picks the next user with a
+// pending packet in round-robin order, so that the fragments of concurrent
+// packets are interleaved one frame at a time. This is allowed by RFC 4944,
+// since every packet has a distinct datagram tag. Forwarded mesh frames are
+// sent before the next fragment of any packet.
 //
 // SixlowpanClient Receives both Callbacks:
 // Another major design decision was to combine both the `receive` and
 // `send_done` callbacks into a single trait. This reduced overall complexity
-// as only a single client was necessary, and further, the current design
-// of the 6LoWPAN layer assumes a serialized, single-client model. Thus,
-// combining both callbacks into a single interface represented no major
-// drawbacks, and served to simplify the code. Note that this design may
-// change as additional functionality is implemented on top of this layer.
+// as only a single client is necessary per user, and an upper layer that
+// sends packets generally also receives them. Thus, combining both callbacks
+// into a single interface represented no major drawbacks, and served to
+// simplify the code.
 //
 // TODOs and Known Issues
 // ----------------------------------
@@ -212,8 +253,6 @@
 //
 //   * Implement and expose a ConfigClient interface?
 //
-//   * Implement the disassociation event, integrate with lower layer
-//
 //   * Move network constants/tuning parameters to a separate file
 //
 // Issues:
@@ -224,8 +263,9 @@
 //
 
 use core::cell::Cell;
-use ieee802154::device::{MacDevice, RxClient, TxClient};
+use ieee802154::device::{MacDevice, RxClient, SecurityError, TxClient};
 use ieee802154::framer::Frame;
+use ieee802154::mlme::AssociationClient;
 use kernel::ReturnCode;
 use kernel::common::list::{List, ListLink, ListNode};
 use kernel::common::take_cell::{MapCell, TakeCell};
@@ -233,17 +273,36 @@ use kernel::hil::radio;
 use kernel::hil::time;
 use kernel::hil::time::Frequency;
 use net::frag_utils::Bitmap;
-use net::ieee802154::{Header, KeyId, MacAddress, PanID, SecurityLevel};
+use net::ieee802154::{FrameType, Header, KeyId, MacAddress, PanID, SecurityLevel};
+use net::ip::{self, ip6_nh, ExtHeaderError, ExtHeaderIter, IP6Header, IPAddr};
 use net::sixlowpan_compression;
 use net::sixlowpan_compression::{is_lowpan, ContextStore};
+use net::stream::{decode_bytes, decode_u16, decode_u8};
+use net::stream::{encode_bytes, encode_u16, encode_u8};
+use net::stream::SResult;
 use net::util::{slice_to_u16, u16_to_slice};
 
 // Reassembly timeout in seconds
 const FRAG_TIMEOUT: u32 = 60;
 
+// Number of (originator, sequence number) pairs of broadcast frames
+// remembered for duplicate suppression
+const MAX_BROADCAST_ENTRIES: usize = 16;
+
+// Hop limit of the ICMPv6 error messages sent about received packets
+const ICMP_HOP_LIMIT: u8 = 64;
+
 pub trait SixlowpanClient {
     fn receive<'a>(&self, buf: &'a [u8], len: u16, result: ReturnCode);
     fn send_done(&self, buf: &'static mut [u8], acked: bool, result: ReturnCode);
+
+    /// Called when the Mac layer drops a frame that failed the incoming frame
+    /// security procedure. The frame may have carried a fragment of any
+    /// packet, so this is only reported to the users without a filter.
+    ///
+    /// - `src_addr`: The source address in the MAC header of the frame
+    /// - `error`: The step of the security procedure that failed
+    fn receive_security_failure(&self, src_addr: Option<MacAddress>, error: SecurityError);
 }
 
 pub mod lowpan_frag {
@@ -290,15 +349,148 @@ fn is_fragment(packet: &[u8]) -> bool {
     (mask == lowpan_frag::FRAGN_HDR) || (mask == lowpan_frag::FRAG1_HDR)
 }
 
-/// Tracks the global transmit state for a single IPv6 packet.
+pub mod lowpan_mesh {
+    pub const DISPATCH: u8 = 0b10000000;
+    pub const DISPATCH_MASK: u8 = 0b11000000;
+    // Set if the originator/final destination address is a short address
+    pub const ORIGINATOR_SHORT: u8 = 0b00100000;
+    pub const FINAL_DST_SHORT: u8 = 0b00010000;
+    pub const HOPS_LEFT_MASK: u8 = 0x0f;
+    // Hops left value indicating that the actual value follows in the next byte
+    pub const HOPS_LEFT_EXTENDED: u8 = 0x0f;
+    pub const DEFAULT_HOPS_LEFT: u8 = 8;
+
+    pub const BC0_HDR: u8 = 0b01010000;
+    pub const BC0_HDR_SIZE: usize = 2;
+
+    // Mesh header with extended hops left and two long addresses, followed
+    // by a broadcast header
+    pub const MAX_HDR_SIZE: usize = 2 + 8 + 8 + BC0_HDR_SIZE;
+}
+
+fn is_mesh(packet: &[u8]) -> bool {
+    packet.len() > 0 && (packet[0] & lowpan_mesh::DISPATCH_MASK) == lowpan_mesh::DISPATCH
+}
+
+fn is_broadcast_hdr(packet: &[u8]) -> bool {
+    packet.len() >= lowpan_mesh::BC0_HDR_SIZE && packet[0] == lowpan_mesh::BC0_HDR
+}
+
+/// Returns whether a frame destined to this address should be delivered to
+/// every node in the mesh. RFC 4944 reserves the short addresses starting
+/// with the bits `100` for multicast, and 0xffff is the broadcast address.
+fn is_group_addr(addr: MacAddress) -> bool {
+    match addr {
+        MacAddress::Short(short_addr) => short_addr == 0xffff || (short_addr & 0xe000) == 0x8000,
+        MacAddress::Long(_) => false,
+    }
+}
+
+/// The RFC 4944 Mesh Addressing header, which carries the link-layer
+/// addresses of the originator and the final destination of a frame that is
+/// forwarded over several link-layer hops.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct MeshHeader {
+    pub hops_left: u8,
+    pub originator: MacAddress,
+    pub final_dst: MacAddress,
+}
+
+impl MeshHeader {
+    pub fn encode(&self, buf: &mut [u8]) -> SResult {
+        stream_len_cond!(buf, 1);
+        let mut dispatch = lowpan_mesh::DISPATCH;
+        if let MacAddress::Short(_) = self.originator {
+            dispatch |= lowpan_mesh::ORIGINATOR_SHORT;
+        }
+        if let MacAddress::Short(_) = self.final_dst {
+            dispatch |= lowpan_mesh::FINAL_DST_SHORT;
+        }
+        let mut off = if self.hops_left < lowpan_mesh::HOPS_LEFT_EXTENDED {
+            enc_consume!(buf; encode_u8, dispatch | self.hops_left)
+        } else {
+            let off = enc_consume!(buf; encode_u8, dispatch | lowpan_mesh::HOPS_LEFT_EXTENDED);
+            enc_consume!(buf, off; encode_u8, self.hops_left)
+        };
+        for addr in [self.originator, self.final_dst].iter() {
+            off = match *addr {
+                MacAddress::Short(short_addr) => enc_consume!(buf, off; encode_u16, short_addr),
+                MacAddress::Long(ref long_addr) => enc_consume!(buf, off; encode_bytes, long_addr),
+            };
+        }
+        stream_done!(off);
+    }
+
+    pub fn decode(buf: &[u8]) -> SResult<MeshHeader> {
+        let (off, dispatch) = dec_try!(buf; decode_u8);
+        stream_cond!(dispatch & lowpan_mesh::DISPATCH_MASK == lowpan_mesh::DISPATCH);
+        let (off, hops_left) =
+            if dispatch & lowpan_mesh::HOPS_LEFT_MASK == lowpan_mesh::HOPS_LEFT_EXTENDED {
+                dec_try!(buf, off; decode_u8)
+            } else {
+                (off, dispatch & lowpan_mesh::HOPS_LEFT_MASK)
+            };
+        let (off, originator) =
+            dec_try!(buf, off; decode_mesh_addr, dispatch & lowpan_mesh::ORIGINATOR_SHORT != 0);
+        let (off, final_dst) =
+            dec_try!(buf, off; decode_mesh_addr, dispatch & lowpan_mesh::FINAL_DST_SHORT != 0);
+        stream_done!(
+            off,
+            MeshHeader {
+                hops_left: hops_left,
+                originator: originator,
+                final_dst: final_dst,
+            }
+        );
+    }
+}
+
+/// Writes the mesh header, followed by a broadcast header if `bc_seq` is
+/// present, and returns the number of bytes written.
+fn set_mesh_hdr(
+    mesh: &MeshHeader,
+    bc_seq: Option<u8>,
+    hdr: &mut [u8; lowpan_mesh::MAX_HDR_SIZE],
+) -> usize {
+    // The buffer is always large enough for the headers
+    let mut len = mesh.encode(hdr).done().map_or(0, |(off, _)| off);
+    if let Some(seq) = bc_seq {
+        hdr[len] = lowpan_mesh::BC0_HDR;
+        hdr[len + 1] = seq;
+        len += lowpan_mesh::BC0_HDR_SIZE;
+    }
+    len
+}
+
+fn decode_mesh_addr(buf: &[u8], is_short: bool) -> SResult<MacAddress> {
+    if is_short {
+        let (off, short_addr) = dec_try!(buf; decode_u16);
+        stream_done!(off, MacAddress::Short(short_addr));
+    } else {
+        let mut long_addr = [0u8; 8];
+        let off = dec_consume!(buf; decode_bytes, &mut long_addr);
+        stream_done!(off, MacAddress::Long(long_addr));
+    }
+}
+
+/// Implemented by the component deciding how frames with a mesh header are
+/// forwarded towards their final destination. This can be as simple as a
+/// static table configured by the board.
+pub trait MeshRouter {
+    /// Returns the link-layer address of the next hop towards `final_dst`, or
+    /// `None` if no route is known. Frames without a route are flooded to
+    /// all neighbors, using the broadcast header to suppress duplicates.
+    fn next_hop(&self, final_dst: MacAddress) -> Option<MacAddress>;
+}
+
+/// Tracks the transmit state for a single IPv6 packet.
 ///
-/// Since transmit is serialized, the `Sixlowpan` struct only contains
-/// a reference to a single TxState (that is, we can only have a single
-/// outstanding transmission at the same time).
+/// Each [SixlowpanUser](struct.SixlowpanUser.html) has its own TxState, so
+/// the `Sixlowpan` struct can have one outstanding transmission per user.
 ///
 /// This struct maintains a reference to the full IPv6 packet, the source/dest
-/// MAC addresses and PanIDs, security/compression/fragmentation options,
-/// per-fragmentation state, and some global state.
+/// MAC addresses and PanIDs, security/compression/fragmentation options and
+/// per-fragmentation state.
 struct TxState {
     // State for the current transmission
     packet: TakeCell<'static, [u8]>,
@@ -306,42 +498,56 @@ struct TxState {
     dst_pan: Cell<PanID>,
     src_mac_addr: Cell<MacAddress>,
     dst_mac_addr: Cell<MacAddress>,
+    // Link-layer destination of each fragment, which differs from
+    // `dst_mac_addr` when the packet is sent over several hops
+    link_dst_mac_addr: Cell<MacAddress>,
+    mesh: Cell<Option<MeshHeader>>,
+    mesh_broadcast: Cell<bool>,
     security: Cell<Option<(SecurityLevel, KeyId)>>,
     dgram_tag: Cell<u16>, // Used to identify particular fragment streams
     dgram_size: Cell<u16>,
     dgram_offset: Cell<usize>,
-
-    // Global transmit state
-    tx_dgram_tag: Cell<u16>,
     tx_busy: Cell<bool>,
-    tx_buf: TakeCell<'static, [u8]>,
+    // Set when the packet is discarded while one of its fragments is being
+    // transmitted, so that it is ended once the radio returns the frame
+    cancelled: Cell<bool>,
 }
 
 impl TxState {
     /// Creates a new `TxState`
-    ///
-    /// # Arguments
-    ///
-    /// `tx_buf` - A buffer for storing fragments the size of a 802.15.4 frame.
-    /// This buffer must be at least radio::MAX_FRAME_SIZE bytes long.
-    fn new(tx_buf: &'static mut [u8]) -> TxState {
+    fn new() -> TxState {
         TxState {
             packet: TakeCell::empty(),
             src_pan: Cell::new(0),
             dst_pan: Cell::new(0),
             src_mac_addr: Cell::new(MacAddress::Short(0)),
             dst_mac_addr: Cell::new(MacAddress::Short(0)),
+            link_dst_mac_addr: Cell::new(MacAddress::Short(0)),
+            mesh: Cell::new(None),
+            mesh_broadcast: Cell::new(false),
             security: Cell::new(None),
             dgram_tag: Cell::new(0),
             dgram_size: Cell::new(0),
             dgram_offset: Cell::new(0),
-
-            tx_dgram_tag: Cell::new(0),
             tx_busy: Cell::new(false),
-            tx_buf: TakeCell::new(tx_buf),
+            cancelled: Cell::new(false),
         }
     }
 
+    // Frames protected by a MIC may carry packets with elided UDP checksums
+    // (RFC 6282, Section 4.3.2)
+    fn has_mic(&self) -> bool {
+        self.security
+            .get()
+            .map_or(false, |(level, _)| level.mic_len() > 0)
+    }
+
+    // The first fragment of a packet sets `dgram_offset` past the compressed
+    // headers, so an offset of 0 means that nothing has been sent yet
+    fn is_transmit_started(&self) -> bool {
+        self.dgram_offset.get() > 0
+    }
+
     fn is_transmit_done(&self) -> bool {
         self.dgram_size.get() as usize <= self.dgram_offset.get()
     }
@@ -350,15 +556,42 @@ impl TxState {
         &self,
         src_mac_addr: MacAddress,
         dst_mac_addr: MacAddress,
+        link_dst_mac_addr: MacAddress,
+        mesh: Option<MeshHeader>,
+        mesh_broadcast: bool,
         packet: &'static mut [u8],
         packet_len: usize,
         security: Option<(SecurityLevel, KeyId)>,
     ) {
         self.src_mac_addr.set(src_mac_addr);
         self.dst_mac_addr.set(dst_mac_addr);
+        self.link_dst_mac_addr.set(link_dst_mac_addr);
+        self.mesh.set(mesh);
+        self.mesh_broadcast.set(mesh_broadcast);
         self.security.set(security);
         self.packet.replace(packet);
         self.dgram_size.set(packet_len as u16);
+        self.dgram_offset.set(0);
+        self.tx_busy.set(true);
+        self.cancelled.set(false);
+    }
+
+    // Writes the mesh and broadcast headers, if the packet is sent over
+    // several hops. Every frame gets a new broadcast sequence number, as
+    // receivers suppress duplicates frame by frame.
+    fn append_mesh_hdr(&self, frame: &mut Frame, bc_seq: &Cell<u8>) -> ReturnCode {
+        self.mesh.get().map_or(ReturnCode::SUCCESS, |mesh| {
+            let seq = if self.mesh_broadcast.get() {
+                let seq = bc_seq.get().wrapping_add(1);
+                bc_seq.set(seq);
+                Some(seq)
+            } else {
+                None
+            };
+            let mut mesh_header = [0 as u8; lowpan_mesh::MAX_HDR_SIZE];
+            let len = set_mesh_hdr(&mesh, seq, &mut mesh_header);
+            frame.append_payload(&mesh_header[0..len])
+        })
     }
 
     // Takes ownership of frag_buf and gives it to the radio
@@ -368,6 +601,8 @@ impl TxState {
         frag_buf: &'static mut [u8],
         radio: &MacDevice,
         ctx_store: &ContextStore,
+        bc_seq: &Cell<u8>,
+        elide_udp_checksum: bool,
     ) -> Result<(), (ReturnCode, &'static mut [u8])> {
         self.dgram_tag.set(dgram_tag);
         match self.packet.take() {
@@ -376,15 +611,20 @@ impl TxState {
                 let result = match radio.prepare_data_frame(
                     frag_buf,
                     self.dst_pan.get(),
-                    self.dst_mac_addr.get(),
+                    self.link_dst_mac_addr.get(),
                     self.src_pan.get(),
                     self.src_mac_addr.get(),
                     self.security.get(),
                 ) {
                     Err(frame) => Err((ReturnCode::FAIL, frame)),
-                    Ok(frame) => {
-                        self.prepare_transmit_first_fragment(ip6_packet, frame, radio, ctx_store)
-                    }
+                    Ok(frame) => self.prepare_transmit_first_fragment(
+                        ip6_packet,
+                        frame,
+                        radio,
+                        ctx_store,
+                        bc_seq,
+                        elide_udp_checksum,
+                    ),
                 };
                 // If the ip6_packet is Some, always want to replace even in
                 // case of errors
@@ -400,6 +640,8 @@ impl TxState {
         mut frame: Frame,
         radio: &MacDevice,
         ctx_store: &ContextStore,
+        bc_seq: &Cell<u8>,
+        elide_udp_checksum: bool,
     ) -> Result<(), (ReturnCode, &'static mut [u8])> {
         // Here, we assume that the compressed headers fit in the first MTU
         // fragment. This is consistent with RFC 6282.
@@ -407,17 +649,22 @@ impl TxState {
         let (consumed, written) = {
             match sixlowpan_compression::compress(
                 ctx_store,
-                &ip6_packet,
+                &ip6_packet[0..self.dgram_size.get() as usize],
                 self.src_mac_addr.get(),
                 self.dst_mac_addr.get(),
                 &mut lowpan_packet,
+                elide_udp_checksum,
             ) {
                 Err(_) => return Err((ReturnCode::FAIL, frame.into_buf())),
                 Ok(result) => result,
             }
         };
 
-        let remaining_payload = ip6_packet.len() - consumed;
+        if self.append_mesh_hdr(&mut frame, bc_seq) != ReturnCode::SUCCESS {
+            return Err((ReturnCode::ESIZE, frame.into_buf()));
+        }
+
+        let remaining_payload = self.dgram_size.get() as usize - consumed;
         let lowpan_len = written + remaining_payload;
         // TODO: This -2 is added to account for the FCS; this should be changed
         // in the MAC code
@@ -464,17 +711,21 @@ impl TxState {
         &self,
         frag_buf: &'static mut [u8],
         radio: &MacDevice,
+        bc_seq: &Cell<u8>,
     ) -> Result<(), (ReturnCode, &'static mut [u8])> {
         match radio.prepare_data_frame(
             frag_buf,
             self.dst_pan.get(),
-            self.dst_mac_addr.get(),
+            self.link_dst_mac_addr.get(),
             self.src_pan.get(),
             self.src_mac_addr.get(),
             self.security.get(),
         ) {
             Err(frame) => Err((ReturnCode::FAIL, frame)),
             Ok(mut frame) => {
+                if self.append_mesh_hdr(&mut frame, bc_seq) != ReturnCode::SUCCESS {
+                    return Err((ReturnCode::ESIZE, frame.into_buf()));
+                }
                 let dgram_offset = self.dgram_offset.get();
                 let remaining_capacity =
                     frame.remaining_data_capacity() - lowpan_frag::FRAGN_HDR_SIZE;
@@ -517,13 +768,11 @@ impl TxState {
 
     fn end_transmit<'a>(
         &self,
-        tx_buf: &'static mut [u8],
         client: Option<&'a SixlowpanClient>,
         acked: bool,
         result: ReturnCode,
     ) {
         self.tx_busy.set(false);
-        self.tx_buf.replace(tx_buf);
         client.map(move |client| {
             // The packet here should always be valid, as we borrow the packet
             // from the upper layer for the duration of the transmission. It
@@ -539,6 +788,116 @@ impl TxState {
     }
 }
 
+/// A user of the [Sixlowpan](struct.Sixlowpan.html) layer, such as UDP,
+/// ICMPv6 or a raw IPv6 socket.
+///
+/// Each user can have one packet in transmission at a time, independently of
+/// the other users, and gets `send_done` callbacks for its own packets only.
+/// Received packets are delivered to every user whose filter matches them:
+/// a user created with a next header value only receives packets whose
+/// upper-layer protocol, after any extension headers, is that next header,
+/// and if a port is also given, only UDP datagrams addressed to
+/// that port. A user without a next header receives all packets.
+pub struct SixlowpanUser<'a> {
+    client: Cell<Option<&'a SixlowpanClient>>,
+    next_header: Option<u8>,
+    port: Option<u16>,
+    tx_state: TxState,
+    // Set once the user is in the list of its `Sixlowpan`
+    registered: Cell<bool>,
+    next: ListLink<'a, SixlowpanUser<'a>>,
+}
+
+impl<'a> ListNode<'a, SixlowpanUser<'a>> for SixlowpanUser<'a> {
+    fn next(&'a self) -> &'a ListLink<SixlowpanUser<'a>> {
+        &self.next
+    }
+}
+
+impl<'a> SixlowpanUser<'a> {
+    /// Creates a new `SixlowpanUser`
+    ///
+    /// # Arguments
+    ///
+    /// * `next_header` - The upper-layer protocol (see `net::ip::ip6_nh`) of
+    /// the packets this user receives, or `None` to receive all packets.
+    ///
+    /// * `port` - The UDP destination port of the packets this user receives.
+    /// Only meaningful if `next_header` is `ip6_nh::UDP`.
+    pub fn new(next_header: Option<u8>, port: Option<u16>) -> SixlowpanUser<'a> {
+        SixlowpanUser {
+            client: Cell::new(None),
+            next_header: next_header,
+            port: port,
+            tx_state: TxState::new(),
+            registered: Cell::new(false),
+            next: ListLink::empty(),
+        }
+    }
+
+    /// Sets the [SixlowpanClient](trait.SixlowpanClient.html) that will
+    /// receive this user's transmission completion and packet reception
+    /// callbacks.
+    pub fn set_client(&self, client: &'a SixlowpanClient) {
+        self.client.set(Some(client));
+    }
+
+    fn is_transmit_pending(&self) -> bool {
+        self.tx_state.tx_busy.get()
+    }
+
+    // Checks the upper-layer protocol and, for UDP, the destination port of
+    // a received packet against the filter of this user. Extension headers
+    // are skipped, so they never need to be matched by a filter.
+    fn accepts(&self, packet: &[u8]) -> bool {
+        let next_header = match self.next_header {
+            Some(next_header) => next_header,
+            None => return true,
+        };
+        let mut headers = ExtHeaderIter::new(packet);
+        for _ in &mut headers {}
+        let (upper_layer, offset) = match headers.upper_layer() {
+            Ok(result) => result,
+            Err(_) => return false,
+        };
+        if upper_layer != next_header {
+            return false;
+        }
+        self.port.map_or(true, |port| {
+            next_header == ip6_nh::UDP && packet.len() >= offset + 4
+                && slice_to_u16(&packet[offset + 2..offset + 4]) == port
+        })
+    }
+
+    fn end_transmit(&self, acked: bool, result: ReturnCode) {
+        self.tx_state
+            .end_transmit(self.client.get(), acked, result);
+    }
+
+    fn receive(&self, packet: &[u8], len: u16, result: ReturnCode) {
+        // The headers of a packet that failed to be received cannot be trusted,
+        // so failures are only reported to users without a filter
+        let accepted = if result == ReturnCode::SUCCESS {
+            self.accepts(&packet[0..len as usize])
+        } else {
+            self.next_header.is_none()
+        };
+        if accepted {
+            self.client
+                .get()
+                .map(|client| client.receive(packet, len, result));
+        }
+    }
+
+    fn receive_security_failure(&self, src_addr: Option<MacAddress>, error: SecurityError) {
+        if self.next_header.is_none() {
+            self.client
+                .get()
+                .map(|client| client.receive_security_failure(src_addr, error));
+        }
+    }
+}
+
 /// Tracks the decompression and defragmentation of an IPv6 packet
 ///
 /// A list of `RxState`s is maintained by [Sixlowpan](struct.Sixlowpan.html) to
@@ -557,6 +916,12 @@ pub struct RxState<'a> {
     busy: Cell<bool>,
     // The time when packet reassembly started for the current packet.
     start_time: Cell<u32>,
+    // Set if the UDP checksum was elided from the first fragment, and must
+    // be computed once the packet is reassembled
+    checksum_deferred: Cell<bool>,
+    // Whether every fragment received so far was integrity-protected, which
+    // an elided checksum requires of the whole packet
+    integrity_checked: Cell<bool>,
 
     next: ListLink<'a, RxState<'a>>,
 }
@@ -584,6 +949,8 @@ impl<'a> RxState<'a> {
             dgram_size: Cell::new(0),
             busy: Cell::new(false),
             start_time: Cell::new(0),
+            checksum_deferred: Cell::new(false),
+            integrity_checked: Cell::new(true),
             next: ListLink::empty(),
         }
     }
@@ -626,6 +993,8 @@ impl<'a> RxState<'a> {
         self.busy.set(true);
         self.bitmap.map(|bitmap| bitmap.clear());
         self.start_time.set(current_tics);
+        self.checksum_deferred.set(false);
+        self.integrity_checked.set(true);
     }
 
     // This function assumes that the payload is a slice starting from the
@@ -638,10 +1007,11 @@ impl<'a> RxState<'a> {
         dgram_size: u16,
         dgram_offset: usize,
         ctx_store: &ContextStore,
+        integrity_checked: bool,
     ) -> Result<bool, ReturnCode> {
         let mut packet = self.packet.take().ok_or(ReturnCode::ENOMEM)?;
         let uncompressed_len = if dgram_offset == 0 {
-            let (consumed, written) = sixlowpan_compression::decompress(
+            let decompressed = sixlowpan_compression::decompress(
                 ctx_store,
                 &payload[0..payload_len as usize],
                 self.src_mac_addr.get(),
@@ -649,7 +1019,16 @@ impl<'a> RxState<'a> {
                 &mut packet,
                 dgram_size,
                 true,
-            ).map_err(|_| ReturnCode::FAIL)?;
+                integrity_checked,
+            );
+            let (consumed, written, checksum_deferred) = match decompressed {
+                Ok(result) => result,
+                Err(_) => {
+                    self.packet.replace(packet);
+                    return Err(ReturnCode::FAIL);
+                }
+            };
+            self.checksum_deferred.set(checksum_deferred);
             let remaining = payload_len - consumed;
             packet[written..written + remaining]
                 .copy_from_slice(&payload[consumed..consumed + remaining]);
@@ -660,37 +1039,85 @@ impl<'a> RxState<'a> {
             payload_len
         };
         self.packet.replace(packet);
+        self.integrity_checked
+            .set(self.integrity_checked.get() && integrity_checked);
         if !self.bitmap.map_or(false, |bitmap| {
             bitmap.set_bits(dgram_offset / 8, (dgram_offset + uncompressed_len) / 8)
         }) {
             // If this fails, we received an overlapping fragment. We can simply
             // drop the packet in this case.
             Err(ReturnCode::FAIL)
+        } else if self.bitmap
+            .map_or(false, |bitmap| bitmap.is_complete((dgram_size as usize) / 8))
+        {
+            if !self.checksum_deferred.get() {
+                return Ok(true);
+            }
+            // A UDP checksum elided from the first fragment can only be
+            // computed now that the whole packet is available, and only
+            // trusted if none of its fragments could have been altered
+            if !self.integrity_checked.get() {
+                return Err(ReturnCode::FAIL);
+            }
+            self.packet
+                .map_or(Err(()), |packet| {
+                    sixlowpan_compression::complete_udp_checksum(
+                        &mut packet[0..dgram_size as usize],
+                    )
+                })
+                .map(|_| true)
+                .map_err(|_| ReturnCode::FAIL)
         } else {
-            self.bitmap
-                .map(|bitmap| bitmap.is_complete((dgram_size as usize) / 8))
-                .ok_or(ReturnCode::FAIL)
+            Ok(false)
         }
     }
 
-    fn end_receive(&self, client: Option<&'a SixlowpanClient>, result: ReturnCode) {
+    // Checks the extension headers of the reassembled packet. No options are
+    // processed by this layer, so none of them is recognized.
+    fn check_ext_headers(&self) -> Result<(), ExtHeaderError> {
+        let len = self.dgram_size.get() as usize;
+        self.packet.map_or(Err(ExtHeaderError::Discard), |packet| {
+            ip::check_ext_headers(&packet[..len], &|_, _| false).map(|_| ())
+        })
+    }
+
+    fn end_receive(&self, users: Option<&List<'a, SixlowpanUser<'a>>>, result: ReturnCode) {
         self.busy.set(false);
         self.bitmap.map(|bitmap| bitmap.clear());
         self.start_time.set(0);
-        client.map(move |client| {
+        users.map(move |users| {
             // Since packet is borrowed from the upper layer, failing to return it
             // in the callback represents a significant error that should never
             // occur - all other calls to `packet.take()` replace the packet,
             // and thus the packet should always be here.
             self.packet
                 .map(|packet| {
-                    client.receive(&packet, self.dgram_size.get(), result);
+                    for user in users.iter() {
+                        user.receive(&packet, self.dgram_size.get(), result);
+                    }
                 })
                 .expect("Error: `packet` is None in call to end_receive.");
         });
     }
 }
 
+/// The user of the radio for the frame currently being transmitted
+#[derive(Copy, Clone)]
+enum TxOwner<'a> {
+    Idle,
+    Packet(&'a SixlowpanUser<'a>),
+    Forward,
+}
+
+impl<'a> TxOwner<'a> {
+    fn is_idle(&self) -> bool {
+        match *self {
+            TxOwner::Idle => true,
+            _ => false,
+        }
+    }
+}
+
 /// Sends a receives IPv6 packets via 6loWPAN compression and fragmentation.
 ///
 /// # Initialization
@@ -703,59 +1130,177 @@ impl<'a> RxState<'a> {
 ///
 /// Finally, `set_client` controls the client that will receive transmission
 /// completion and reception callbacks.
+///
+/// # Multiple users
+///
+/// Additional users, each represented by a
+/// [SixlowpanUser](struct.SixlowpanUser.html), are registered with
+/// `add_user` and send packets with `transmit_packet_from`. Every user can
+/// have one packet in transmission, and the fragments of concurrent packets
+/// are sent in turn, one frame per user, so that a large packet does not
+/// hold up the others. The client set with `set_client` is itself a user
+/// that receives all packets.
+///
+/// # Mesh-under forwarding
+///
+/// Frames carrying an RFC 4944 mesh header are always accepted, and the
+/// packets they carry are delivered to the client if this node is their final
+/// destination. Forwarding frames to other nodes, and sending packets over
+/// several hops, requires `enable_mesh` to be called with a
+/// [MeshRouter](trait.MeshRouter.html) that picks the next hop for each final
+/// destination. Forwarded frames are not reassembled: each fragment is sent
+/// on as soon as it arrives, with its hops left count decremented.
 pub struct Sixlowpan<'a, A: time::Alarm + 'a, C: ContextStore> {
     pub radio: &'a MacDevice<'a>,
     ctx_store: C,
     clock: &'a A,
-    client: Cell<Option<&'a SixlowpanClient>>,
+    // The user on whose behalf `transmit_packet` sends packets, registered
+    // by `set_client`
+    default_user: SixlowpanUser<'a>,
+    client_user: Cell<Option<&'a SixlowpanUser<'a>>>,
+    users: List<'a, SixlowpanUser<'a>>,
 
-    // Transmit state
-    tx_state: TxState,
+    // Global transmit state. Only one frame is in transmission at a time,
+    // whichever user it belongs to.
+    tx_owner: Cell<TxOwner<'a>>,
+    tx_buf: TakeCell<'static, [u8]>,
+    tx_dgram_tag: Cell<u16>,
+    tx_bc_seq: Cell<u8>,
+    udp_checksum_elision: Cell<bool>,
     // Receive state
     rx_states: List<'a, RxState<'a>>,
+
+    // Mesh-under forwarding state. Only one frame is forwarded at a time;
+    // frames that arrive while `fwd_buf` is in use are dropped.
+    mesh_router: Cell<Option<&'a MeshRouter>>,
+    mesh_hops_left: Cell<u8>,
+    fwd_buf: TakeCell<'static, [u8]>,
+    fwd_frame: MapCell<Frame>,
+    bc_seen: MapCell<[Option<(MacAddress, u8)>; MAX_BROADCAST_ENTRIES]>,
+    bc_seen_next: Cell<usize>,
+
+    // Sends ICMPv6 error messages about received packets, once registered
+    // by `enable_icmp_errors`. Only one message is sent at a time; errors
+    // found while `icmp_buf` is in use are not reported.
+    icmp_user: SixlowpanUser<'a>,
+    icmp_error_user: Cell<Option<&'a SixlowpanUser<'a>>>,
+    icmp_buf: TakeCell<'static, [u8]>,
 }
 
 // This function is called after transmitting a frame
 #[allow(unused_must_use)]
 impl<'a, A: time::Alarm, C: ContextStore> TxClient for Sixlowpan<'a, A, C> {
     fn send_done(&self, tx_buf: &'static mut [u8], acked: bool, result: ReturnCode) {
-        // If we are done sending the entire packet, or if the transmit failed,
-        // end the transmit state and issue callbacks.
-        if result != ReturnCode::SUCCESS || self.tx_state.is_transmit_done() {
-            self.tx_state
-                .end_transmit(tx_buf, self.client.get(), acked, result);
-        // Otherwise, send next fragment
-        } else {
-            let result = self.tx_state
-                .prepare_transmit_next_fragment(tx_buf, self.radio);
-            result.map_err(|(retcode, tx_buf)| {
-                // If we have an error, abort
-                self.tx_state
-                    .end_transmit(tx_buf, self.client.get(), acked, retcode);
-            });
-        }
+        let owner = self.tx_owner.get();
+        self.tx_owner.set(TxOwner::Idle);
+
+        let last_user = match owner {
+            TxOwner::Packet(user) => {
+                self.tx_buf.replace(tx_buf);
+                // If we are done sending the entire packet, or if the transmit
+                // failed, end the transmit state and issue callbacks.
+                if user.tx_state.cancelled.get() {
+                    user.end_transmit(false, ReturnCode::ECANCEL);
+                } else if result != ReturnCode::SUCCESS || user.tx_state.is_transmit_done() {
+                    user.end_transmit(acked, result);
+                }
+                Some(user)
+            }
+            // Forwarding is best-effort, so there is nothing to report
+            TxOwner::Forward => {
+                self.fwd_buf.replace(tx_buf);
+                None
+            }
+            TxOwner::Idle => {
+                self.tx_buf.replace(tx_buf);
+                None
+            }
+        };
+        // Forwarded frames are interleaved with the fragments of packets, and
+        // packets of different users with each other
+        self.transmit_next_frame(last_user);
+    }
+}
+
+// The client of the user that sends ICMPv6 error messages, which only
+// needs its buffer back
+impl<'a, A: time::Alarm, C: ContextStore> SixlowpanClient for Sixlowpan<'a, A, C> {
+    fn receive<'b>(&self, _: &'b [u8], _: u16, _: ReturnCode) {}
+
+    fn send_done(&self, buf: &'static mut [u8], _: bool, _: ReturnCode) {
+        self.icmp_buf.replace(buf);
     }
+
+    fn receive_security_failure(&self, _: Option<MacAddress>, _: SecurityError) {}
 }
 
 // This function is called after receiving a frame
 impl<'a, A: time::Alarm, C: ContextStore> RxClient for Sixlowpan<'a, A, C> {
-    fn receive<'b>(&self, buf: &'b [u8], header: Header<'b>, data_offset: usize, data_len: usize) {
+    fn receive<'b>(
+        &self,
+        buf: &'b [u8],
+        header: Header<'b>,
+        _: radio::RxInfo,
+        data_offset: usize,
+        data_len: usize,
+    ) {
+        // Only data frames carry 6LoWPAN packets; beacons and MAC commands are
+        // handled by the MAC layer management entity
+        if header.frame_type != FrameType::Data {
+            return;
+        }
+
         // We return if retcode is not valid, as it does not make sense to issue
         // a callback for an invalid frame reception
         // TODO: Handle the case where the addresses are None/elided - they
         // should not default to the zero address
-        let src_mac_addr = header.src_addr.unwrap_or(MacAddress::Short(0));
-        let dst_mac_addr = header.dst_addr.unwrap_or(MacAddress::Short(0));
+        let mut src_mac_addr = header.src_addr.unwrap_or(MacAddress::Short(0));
+        let mut dst_mac_addr = header.dst_addr.unwrap_or(MacAddress::Short(0));
+        let mut payload = &buf[data_offset..data_offset + data_len];
+
+        if is_mesh(payload) {
+            match self.receive_mesh_frame(payload, &header) {
+                // The originator and final destination addresses take the
+                // place of the link-layer addresses (RFC 4944, Section 11)
+                Some((offset, mesh)) => {
+                    src_mac_addr = mesh.originator;
+                    dst_mac_addr = mesh.final_dst;
+                    payload = &payload[offset..];
+                }
+                // Not for us, or a duplicate
+                None => return,
+            }
+        }
+
+        // Frames secured with a MIC may carry packets with elided UDP checksums
+        let integrity_checked = header
+            .security
+            .map_or(false, |security| security.level.mic_len() > 0);
 
         let (rx_state, returncode) = self.receive_frame(
-            &buf[data_offset..data_offset + data_len],
-            data_len,
+            payload,
+            payload.len(),
             src_mac_addr,
             dst_mac_addr,
+            integrity_checked,
         );
         // Reception completed if rx_state is not None. Note that this can
         // also occur for some fail states (e.g. dropping an invalid packet)
-        rx_state.map(|state| state.end_receive(self.client.get(), returncode));
+        rx_state.map(|state| {
+            if returncode == ReturnCode::SUCCESS {
+                self.deliver_packet(state);
+            } else {
+                state.end_receive(Some(&self.users), returncode);
+            }
+        });
+    }
+
+    fn receive_security_failure<'b>(&self, header: Header<'b>, error: SecurityError) {
+        // The payload of the frame cannot be trusted, so the failure cannot
+        // be attributed to a packet being reassembled
+        for user in self.users.iter() {
+            user.receive_security_failure(header.src_addr, error);
+        }
     }
 }
 
@@ -786,10 +1331,27 @@ impl<'a, A: time::Alarm, C: ContextStore> Sixlowpan<'a, A, C> {
             radio: radio,
             ctx_store: ctx_store,
             clock: clock,
-            client: Cell::new(None),
+            default_user: SixlowpanUser::new(None, None),
+            client_user: Cell::new(None),
+            users: List::new(),
 
-            tx_state: TxState::new(tx_buf),
+            tx_owner: Cell::new(TxOwner::Idle),
+            tx_buf: TakeCell::new(tx_buf),
+            tx_dgram_tag: Cell::new(0),
+            tx_bc_seq: Cell::new(0),
+            udp_checksum_elision: Cell::new(false),
             rx_states: List::new(),
+
+            mesh_router: Cell::new(None),
+            mesh_hops_left: Cell::new(lowpan_mesh::DEFAULT_HOPS_LEFT),
+            fwd_buf: TakeCell::empty(),
+            fwd_frame: MapCell::empty(),
+            bc_seen: MapCell::new(Default::default()),
+            bc_seen_next: Cell::new(0),
+
+            icmp_user: SixlowpanUser::new(Some(ip6_nh::ICMP), None),
+            icmp_error_user: Cell::new(None),
+            icmp_buf: TakeCell::empty(),
         }
     }
 
@@ -801,19 +1363,76 @@ impl<'a, A: time::Alarm, C: ContextStore> Sixlowpan<'a, A, C> {
         self.rx_states.push_head(rx_state);
     }
 
+    /// Registers a [SixlowpanUser](struct.SixlowpanUser.html), which can then
+    /// send packets with `transmit_packet_from` and receives the packets that
+    /// match its filter. Registering a user more than once has no effect.
+    pub fn add_user(&self, user: &'a SixlowpanUser<'a>) {
+        if !user.registered.get() {
+            user.registered.set(true);
+            self.users.push_tail(user);
+        }
+    }
+
     /// Sets the [SixlowpanClient](trait.SixlowpanClient.html) that will receive
-    /// transmission completion and new packet reception callbacks.
+    /// completion callbacks for packets sent with `transmit_packet`, as well as
+    /// all received packets.
     pub fn set_client(&'a self, client: &'a SixlowpanClient) {
-        self.client.set(Some(client));
+        self.default_user.set_client(client);
+        self.add_user(&self.default_user);
+        self.client_user.set(Some(&self.default_user));
+    }
+
+    /// Authorizes the elision of UDP checksums from transmitted packets.
+    ///
+    /// Even when authorized, a checksum is only elided if the packet is sent
+    /// with a security level that includes a MIC, which then protects the
+    /// UDP payload instead. Elision is not authorized by default.
+    pub fn set_udp_checksum_elision(&self, authorized: bool) {
+        self.udp_checksum_elision.set(authorized);
+    }
+
+    /// Enables ICMPv6 Parameter Problem messages about received packets whose
+    /// extension headers cannot be processed. `buf` holds the message being
+    /// sent; it should be large enough to include the invoking packet, which
+    /// is truncated to fit otherwise.
+    pub fn enable_icmp_errors(&'a self, buf: &'static mut [u8]) {
+        self.icmp_buf.replace(buf);
+        self.icmp_user.set_client(self);
+        self.add_user(&self.icmp_user);
+        self.icmp_error_user.set(Some(&self.icmp_user));
     }
 
-    /// Transmits the supplied IPv6 packet.
+    /// Enables mesh-under forwarding.
+    ///
+    /// # Arguments
+    ///
+    /// * `router` - Picks the next hop towards the final destination of
+    /// transmitted and forwarded frames.
+    ///
+    /// * `fwd_buf` - A buffer used for frames being forwarded to another node.
+    /// This buffer must be at least the length of an 802.15.4 frame.
+    ///
+    /// * `hops_left` - The initial hops left count of transmitted frames.
+    /// `lowpan_mesh::DEFAULT_HOPS_LEFT` is a reasonable value for small
+    /// networks.
+    pub fn enable_mesh(&self, router: &'a MeshRouter, fwd_buf: &'static mut [u8], hops_left: u8) {
+        self.mesh_router.set(Some(router));
+        self.fwd_buf.replace(fwd_buf);
+        self.mesh_hops_left.set(hops_left);
+    }
+
+    /// Transmits the supplied IPv6 packet on behalf of the client set with
+    /// `set_client`.
     ///
     /// Transmitted IPv6 packets will be optionally secured via the `security`
     /// argument.
     ///
-    /// Only one transmission is allowed at a time. Calling this method while
-    /// before a previous tranismission has completed will return an error.
+    /// Only one transmission is allowed at a time for this client. Calling
+    /// this method while before a previous tranismission has completed will
+    /// return an error, as will calling it before `set_client`.
+    ///
+    /// If mesh-under forwarding is enabled and `dst_mac_addr` is not a
+    /// neighbor, the packet is sent with a mesh header to the next hop.
     ///
     /// # Arguments
     ///
@@ -852,49 +1471,324 @@ impl<'a, A: time::Alarm, C: ContextStore> Sixlowpan<'a, A, C> {
         ip6_packet_len: usize,
         security: Option<(SecurityLevel, KeyId)>,
     ) -> Result<(), (ReturnCode, &'static mut [u8])> {
-        if self.tx_state.tx_busy.get() {
+        match self.client_user.get() {
+            Some(user) => self.transmit_packet_from(
+                user,
+                src_mac_addr,
+                dst_mac_addr,
+                ip6_packet,
+                ip6_packet_len,
+                security,
+            ),
+            None => Err((ReturnCode::EINVAL, ip6_packet)),
+        }
+    }
+
+    /// Transmits the supplied IPv6 packet on behalf of `user`, which must have
+    /// been registered with `add_user`.
+    ///
+    /// The packet is queued behind the packets of other users, if any, and
+    /// the `send_done` callback is issued to the client of `user`. Each user
+    /// can only have one packet in transmission; calling this method before
+    /// the previous packet of `user` has completed returns `EBUSY`. The other
+    /// arguments are the same as for `transmit_packet`.
+    pub fn transmit_packet_from(
+        &self,
+        user: &'a SixlowpanUser<'a>,
+        src_mac_addr: MacAddress,
+        dst_mac_addr: MacAddress,
+        ip6_packet: &'static mut [u8],
+        ip6_packet_len: usize,
+        security: Option<(SecurityLevel, KeyId)>,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if !user.registered.get() {
+            Err((ReturnCode::EINVAL, ip6_packet))
+        } else if user.is_transmit_pending() {
             Err((ReturnCode::EBUSY, ip6_packet))
         } else if ip6_packet_len > ip6_packet.len() {
             Err((ReturnCode::ENOMEM, ip6_packet))
         } else {
-            self.tx_state.init_transmit(
+            let (link_dst_mac_addr, mesh, mesh_broadcast) =
+                self.mesh_route(src_mac_addr, dst_mac_addr);
+            user.tx_state.init_transmit(
                 src_mac_addr,
                 dst_mac_addr,
+                link_dst_mac_addr,
+                mesh,
+                mesh_broadcast,
                 ip6_packet,
                 ip6_packet_len,
                 security,
             );
-            self.start_packet_transmit();
+            // Otherwise, the packet is sent when its turn comes
+            if self.tx_owner.get().is_idle() {
+                self.transmit_next_frame(None);
+            }
             Ok(())
         }
     }
 
-    fn start_packet_transmit(&self) {
-        // Increment dgram_tag
-        let dgram_tag = if (self.tx_state.tx_dgram_tag.get() + 1) == 0 {
-            1
-        } else {
-            self.tx_state.tx_dgram_tag.get() + 1
+    // Returns the link-layer destination of a packet, and the mesh header
+    // to send it with if it is not addressed to a neighbor. The boolean is
+    // true if the frames are flooded, and so need a broadcast header.
+    fn mesh_route(
+        &self,
+        src_mac_addr: MacAddress,
+        dst_mac_addr: MacAddress,
+    ) -> (MacAddress, Option<MeshHeader>, bool) {
+        let router = match self.mesh_router.get() {
+            Some(router) => router,
+            None => return (dst_mac_addr, None, false),
         };
+        let mesh = MeshHeader {
+            hops_left: self.mesh_hops_left.get(),
+            originator: src_mac_addr,
+            final_dst: dst_mac_addr,
+        };
+        if is_group_addr(dst_mac_addr) {
+            return (MacAddress::Short(0xffff), Some(mesh), true);
+        }
+        match router.next_hop(dst_mac_addr) {
+            Some(next_hop) => {
+                if next_hop == dst_mac_addr {
+                    (dst_mac_addr, None, false)
+                } else {
+                    (next_hop, Some(mesh), false)
+                }
+            }
+            None => (MacAddress::Short(0xffff), Some(mesh), true),
+        }
+    }
 
-        let frag_buf = self.tx_state
-            .tx_buf
-            .take()
-            .expect("Error: `tx_buf` is None in call to start_packet_transmit.");
+    // Returns the first user after `last_user` with a packet waiting to be
+    // sent, wrapping around to the start of the list, so that users take
+    // turns sending frames
+    fn next_pending_user(
+        &self,
+        last_user: Option<&'a SixlowpanUser<'a>>,
+    ) -> Option<&'a SixlowpanUser<'a>> {
+        let mut passed_last = false;
+        let after_last = self.users.iter().find(|user| {
+            if passed_last && user.is_transmit_pending() {
+                return true;
+            }
+            // The raw pointers are only compared, never dereferenced
+            passed_last |= last_user.map_or(false, |last| {
+                *user as *const SixlowpanUser == last as *const SixlowpanUser
+            });
+            false
+        });
+        after_last.or_else(|| self.users.iter().find(|user| user.is_transmit_pending()))
+    }
 
-        match self.tx_state
-            .start_transmit(dgram_tag, frag_buf, self.radio, &self.ctx_store)
-        {
+    // Sends the next frame if the radio is idle: a pending forwarded frame
+    // first, then the next fragment of the next user in turn. Users whose
+    // frames fail to be sent are skipped.
+    fn transmit_next_frame(&self, last_user: Option<&'a SixlowpanUser<'a>>) {
+        let mut last_user = last_user;
+        loop {
+            // The radio may have been claimed by a client callback
+            if !self.tx_owner.get().is_idle() {
+                return;
+            }
+            if self.transmit_forwarded_frame() {
+                return;
+            }
+            match self.next_pending_user(last_user) {
+                Some(user) => {
+                    if self.transmit_user_frame(user) {
+                        return;
+                    }
+                    last_user = Some(user);
+                }
+                None => return,
+            }
+        }
+    }
+
+    // Sends the first or next fragment of the packet of `user`. Returns true
+    // if the radio is now busy with it; otherwise, the transmission of the
+    // packet is aborted.
+    fn transmit_user_frame(&self, user: &'a SixlowpanUser<'a>) -> bool {
+        let tx_buf = self.tx_buf
+            .take()
+            .expect("Error: `tx_buf` is None in call to transmit_user_frame.");
+        let tx_state = &user.tx_state;
+        let result = if tx_state.is_transmit_started() {
+            tx_state.prepare_transmit_next_fragment(tx_buf, self.radio, &self.tx_bc_seq)
+        } else {
+            // Increment dgram_tag
+            let dgram_tag = if (self.tx_dgram_tag.get() + 1) == 0 {
+                1
+            } else {
+                self.tx_dgram_tag.get() + 1
+            };
+            self.tx_dgram_tag.set(dgram_tag);
+            tx_state.start_transmit(
+                dgram_tag,
+                tx_buf,
+                self.radio,
+                &self.ctx_store,
+                &self.tx_bc_seq,
+                self.udp_checksum_elision.get() && tx_state.has_mic(),
+            )
+        };
+        match result {
             // Successfully started transmitting
-            Ok(_) => {
-                self.tx_state.tx_dgram_tag.set(dgram_tag);
-                self.tx_state.tx_busy.set(true);
+            Ok(()) => {
+                self.tx_owner.set(TxOwner::Packet(user));
+                true
             }
             // Otherwise, we failed
-            Err((returncode, new_frag_buf)) => {
-                self.tx_state
-                    .end_transmit(new_frag_buf, self.client.get(), false, returncode);
+            Err((returncode, tx_buf)) => {
+                self.tx_buf.replace(tx_buf);
+                user.end_transmit(false, returncode);
+                false
+            }
+        }
+    }
+
+    // Transmits the pending forwarded frame, if any. Returns true if the
+    // radio is now busy with the forwarded frame.
+    fn transmit_forwarded_frame(&self) -> bool {
+        self.fwd_frame.take().map_or(false, |frame| {
+            let (_, buf) = self.radio.transmit(frame);
+            match buf {
+                Some(buf) => {
+                    self.fwd_buf.replace(buf);
+                    false
+                }
+                None => {
+                    self.tx_owner.set(TxOwner::Forward);
+                    true
+                }
+            }
+        })
+    }
+
+    fn is_own_addr(&self, addr: MacAddress) -> bool {
+        match addr {
+            MacAddress::Short(short_addr) => short_addr == self.radio.get_address(),
+            MacAddress::Long(long_addr) => long_addr == self.radio.get_address_long(),
+        }
+    }
+
+    // Returns true if a broadcast frame with this originator and sequence
+    // number was already received, and otherwise remembers it
+    fn is_duplicate_broadcast(&self, originator: MacAddress, seq: u8) -> bool {
+        let entry = Some((originator, seq));
+        self.bc_seen.map_or(false, |bc_seen| {
+            if bc_seen.iter().any(|seen| *seen == entry) {
+                return true;
+            }
+            let next = self.bc_seen_next.get();
+            bc_seen[next] = entry;
+            self.bc_seen_next.set((next + 1) % MAX_BROADCAST_ENTRIES);
+            false
+        })
+    }
+
+    // Processes the mesh and broadcast headers of a received frame, and
+    // forwards the frame if it is addressed to another node or to a group.
+    // Returns the offset of the rest of the frame and the mesh header if the
+    // frame should also be processed locally.
+    fn receive_mesh_frame(&self, payload: &[u8], header: &Header) -> Option<(usize, MeshHeader)> {
+        let (mut offset, mesh) = match MeshHeader::decode(payload).done() {
+            Some(result) => result,
+            None => return None,
+        };
+        // Our own frames may be flooded back to us
+        if self.is_own_addr(mesh.originator) {
+            return None;
+        }
+        let bc_seq = if is_broadcast_hdr(&payload[offset..]) {
+            let seq = payload[offset + 1];
+            offset += lowpan_mesh::BC0_HDR_SIZE;
+            if self.is_duplicate_broadcast(mesh.originator, seq) {
+                return None;
+            }
+            Some(seq)
+        } else {
+            None
+        };
+        if offset >= payload.len() {
+            return None;
+        }
+
+        let is_group = is_group_addr(mesh.final_dst);
+        let for_us = is_group || self.is_own_addr(mesh.final_dst);
+        if !for_us || is_group {
+            self.forward_frame(mesh, bc_seq, &payload[offset..], header);
+        }
+        if for_us {
+            Some((offset, mesh))
+        } else {
+            None
+        }
+    }
+
+    // Sends a received frame on towards its final destination without
+    // reassembling it, unless its hops left count is exhausted
+    fn forward_frame(&self, mesh: MeshHeader, bc_seq: Option<u8>, payload: &[u8], header: &Header) {
+        let router = match self.mesh_router.get() {
+            Some(router) => router,
+            None => return,
+        };
+        // The frame is not forwarded if hops left is decremented to 0
+        if mesh.hops_left <= 1 {
+            return;
+        }
+        let next_hop = if is_group_addr(mesh.final_dst) {
+            None
+        } else {
+            router.next_hop(mesh.final_dst)
+        };
+        let link_dst_mac_addr = match next_hop {
+            Some(next_hop) => next_hop,
+            // Flooding is only safe if duplicates can be detected
+            None => match bc_seq {
+                Some(_) => MacAddress::Short(0xffff),
+                None => return,
+            },
+        };
+
+        let fwd_buf = match self.fwd_buf.take() {
+            Some(fwd_buf) => fwd_buf,
+            None => return,
+        };
+        let pan = header.dst_pan.unwrap_or(self.radio.get_pan());
+        let security = header.security.map(|sec| (sec.level, sec.key_id));
+        let mut frame = match self.radio.prepare_data_frame(
+            fwd_buf,
+            pan,
+            link_dst_mac_addr,
+            pan,
+            MacAddress::Long(self.radio.get_address_long()),
+            security,
+        ) {
+            Ok(frame) => frame,
+            Err(fwd_buf) => {
+                self.fwd_buf.replace(fwd_buf);
+                return;
             }
+        };
+
+        let mesh = MeshHeader {
+            hops_left: mesh.hops_left - 1,
+            ..mesh
+        };
+        let mut mesh_header = [0 as u8; lowpan_mesh::MAX_HDR_SIZE];
+        let len = set_mesh_hdr(&mesh, bc_seq, &mut mesh_header);
+        if frame.append_payload(&mesh_header[0..len]) != ReturnCode::SUCCESS
+            || frame.append_payload(payload) != ReturnCode::SUCCESS
+        {
+            self.fwd_buf.replace(frame.into_buf());
+            return;
+        }
+        self.fwd_frame.put(frame);
+        // Otherwise, the frame is sent when the current transmission is done
+        if self.tx_owner.get().is_idle() {
+            self.transmit_forwarded_frame();
         }
     }
 
@@ -904,6 +1798,7 @@ impl<'a, A: time::Alarm, C: ContextStore> Sixlowpan<'a, A, C> {
         packet_len: usize,
         src_mac_addr: MacAddress,
         dst_mac_addr: MacAddress,
+        integrity_checked: bool,
     ) -> (Option<&RxState<'a>>, ReturnCode) {
         if is_fragment(packet) {
             let (is_frag1, dgram_size, dgram_tag, dgram_offset) = get_frag_hdr(&packet[0..5]);
@@ -920,9 +1815,16 @@ impl<'a, A: time::Alarm, C: ContextStore> Sixlowpan<'a, A, C> {
                 dgram_size,
                 dgram_tag,
                 dgram_offset,
+                integrity_checked,
             )
         } else {
-            self.receive_single_packet(&packet, packet_len, src_mac_addr, dst_mac_addr)
+            self.receive_single_packet(
+                &packet,
+                packet_len,
+                src_mac_addr,
+                dst_mac_addr,
+                integrity_checked,
+            )
         }
     }
 
@@ -932,10 +1834,11 @@ impl<'a, A: time::Alarm, C: ContextStore> Sixlowpan<'a, A, C> {
         payload_len: usize,
         src_mac_addr: MacAddress,
         dst_mac_addr: MacAddress,
+        integrity_checked: bool,
     ) -> (Option<&RxState<'a>>, ReturnCode) {
         let rx_state = self.rx_states
             .iter()
-            .find(|state| !state.is_busy(self.clock.now(), A::Frequency::frequency()));
+            .find(|state| !state.is_busy(A::Frequency::frequency(), self.clock.now()));
         rx_state
             .map(|state| {
                 state.start_receive(
@@ -961,15 +1864,20 @@ impl<'a, A: time::Alarm, C: ContextStore> Sixlowpan<'a, A, C> {
                         &mut packet,
                         0,
                         false,
+                        integrity_checked,
                     );
                     match decompressed {
-                        Ok((consumed, written)) => {
+                        Ok((consumed, written, _)) => {
                             let remaining = payload_len - consumed;
                             packet[written..written + remaining]
                                 .copy_from_slice(&payload[consumed..consumed + remaining]);
+                            // The packet is longer than the frame payload
+                            // once its headers are decompressed
+                            state.dgram_size.set((written + remaining) as u16);
                         }
                         Err(_) => {
-                            return (None, ReturnCode::FAIL);
+                            state.packet.replace(packet);
+                            return (Some(state), ReturnCode::FAIL);
                         }
                     }
                 } else {
@@ -993,6 +1901,7 @@ impl<'a, A: time::Alarm, C: ContextStore> Sixlowpan<'a, A, C> {
         dgram_size: u16,
         dgram_tag: u16,
         dgram_offset: usize,
+        integrity_checked: bool,
     ) -> (Option<&RxState<'a>>, ReturnCode) {
         // First try to find an rx_state in the middle of assembly
         let mut rx_state = self.rx_states
@@ -1003,7 +1912,7 @@ impl<'a, A: time::Alarm, C: ContextStore> Sixlowpan<'a, A, C> {
         if rx_state.is_none() {
             rx_state = self.rx_states
                 .iter()
-                .find(|state| !state.is_busy(self.clock.now(), A::Frequency::frequency()));
+                .find(|state| !state.is_busy(A::Frequency::frequency(), self.clock.now()));
             // Initialize new state
             rx_state.map(|state| {
                 state.start_receive(
@@ -1027,6 +1936,7 @@ impl<'a, A: time::Alarm, C: ContextStore> Sixlowpan<'a, A, C> {
                     dgram_size,
                     dgram_offset,
                     &self.ctx_store,
+                    integrity_checked,
                 );
                 match res {
                     // Some error occurred
@@ -1045,16 +1955,150 @@ impl<'a, A: time::Alarm, C: ContextStore> Sixlowpan<'a, A, C> {
             .unwrap_or((None, ReturnCode::ENOMEM))
     }
 
-    #[allow(dead_code)]
-    // FIXME
-    // This function is called when a disassociation event occurs, as we need
-    // to expire all pending state. This is not fully implemented.
-    fn discard_all_state(&self) {
+    // Delivers a reassembled packet to the users if its extension headers
+    // allow it to be processed. Otherwise, the packet is discarded, and the
+    // problem reported to its source if the headers require it.
+    fn deliver_packet(&self, state: &RxState<'a>) {
+        match state.check_ext_headers() {
+            Ok(()) => state.end_receive(Some(&self.users), ReturnCode::SUCCESS),
+            Err(error) => {
+                if let ExtHeaderError::ParameterProblem(code, pointer) = error {
+                    let len = state.dgram_size.get() as usize;
+                    state.packet.map(|packet| {
+                        self.send_parameter_problem(&packet[..len], state, code, pointer)
+                    });
+                }
+                state.end_receive(None, ReturnCode::FAIL);
+            }
+        }
+    }
+
+    // Sends an ICMPv6 Parameter Problem message about `invoking_packet`,
+    // received in `state`, back to the node it came from
+    fn send_parameter_problem(
+        &self,
+        invoking_packet: &[u8],
+        state: &RxState<'a>,
+        code: u8,
+        pointer: u32,
+    ) {
+        let user = match self.icmp_error_user.get() {
+            Some(user) => user,
+            None => return,
+        };
+        let invoking_header = match IP6Header::decode(invoking_packet).done() {
+            Some((_, header)) => header,
+            None => return,
+        };
+        // Errors are only reported to a single, known source (RFC 4443,
+        // Section 2.4)
+        let dst_addr = invoking_header.src_addr;
+        if dst_addr.is_unspecified() || dst_addr.is_multicast() {
+            return;
+        }
+        // A packet sent to a group is answered from the link-local address
+        // derived from the extended address of this node
+        let (src_mac_addr, src_addr) = if invoking_header.dst_addr.is_multicast()
+            || is_group_addr(state.dst_mac_addr.get())
+        {
+            let addr_long = self.radio.get_address_long();
+            let mut src_addr = IPAddr::new();
+            src_addr.set_unicast_link_local();
+            src_addr.0[8..16].copy_from_slice(&addr_long);
+            src_addr.0[8] ^= 0x02;
+            (MacAddress::Long(addr_long), src_addr)
+        } else {
+            (state.dst_mac_addr.get(), invoking_header.dst_addr)
+        };
+
+        self.icmp_buf.take().map(|buf| {
+            let encoded = ip::encode_parameter_problem(
+                buf,
+                src_addr,
+                ICMP_HOP_LIMIT,
+                invoking_packet,
+                code,
+                pointer,
+            ).done();
+            let result = match encoded {
+                Some((len, _)) => self.transmit_packet_from(
+                    user,
+                    src_mac_addr,
+                    state.src_mac_addr.get(),
+                    buf,
+                    len,
+                    None,
+                ),
+                None => Err((ReturnCode::ESIZE, buf)),
+            };
+            if let Err((_, buf)) = result {
+                self.icmp_buf.replace(buf);
+            }
+        });
+    }
+
+    // Aborts the packets being reassembled from, and the packets being sent
+    // to, the peers for which `matches` returns true. Aborted transmissions
+    // are reported with ECANCEL; a packet whose fragment is being transmitted
+    // is only reported once the radio returns the frame.
+    fn discard_state<F: Fn(MacAddress) -> bool>(&self, matches: F) {
         for rx_state in self.rx_states.iter() {
-            rx_state.end_receive(None, ReturnCode::FAIL);
+            if rx_state.busy.get() && matches(rx_state.src_mac_addr.get()) {
+                rx_state.end_receive(None, ReturnCode::FAIL);
+            }
         }
-        // TODO: May lose tx_buf here
-        // TODO: Need to get buffer back from Mac layer on disassociation
-        //self.tx_state.end_transmit(self.client.get(), false, ReturnCode::FAIL);
+
+        let in_flight = match self.tx_owner.get() {
+            TxOwner::Packet(user) => Some(user),
+            _ => None,
+        };
+        for user in self.users.iter() {
+            let tx_state = &user.tx_state;
+            if !user.is_transmit_pending()
+                || !(matches(tx_state.dst_mac_addr.get())
+                    || matches(tx_state.link_dst_mac_addr.get()))
+            {
+                continue;
+            }
+            // The raw pointers are only compared, never dereferenced
+            let is_in_flight = in_flight.map_or(false, |in_flight| {
+                user as *const SixlowpanUser == in_flight as *const SixlowpanUser
+            });
+            if is_in_flight {
+                tx_state.cancelled.set(true);
+            } else {
+                user.end_transmit(false, ReturnCode::ECANCEL);
+            }
+        }
+    }
+
+    // This function is called when the association state of this node
+    // changes, as packets in progress were compressed and addressed based on
+    // the former link-layer addresses and must all be expired.
+    fn discard_all_state(&self) {
+        self.discard_state(|_| true);
+        self.fwd_frame.take().map(|frame| {
+            self.fwd_buf.replace(frame.into_buf());
+        });
+    }
+}
+
+impl<'a, A: time::Alarm, C: ContextStore> AssociationClient for Sixlowpan<'a, A, C> {
+    fn associate_done(&self, result: ReturnCode, _: u16) {
+        if result == ReturnCode::SUCCESS {
+            self.discard_all_state();
+        }
+    }
+
+    fn disassociated(&self, _: u8) {
+        self.discard_all_state();
+    }
+
+    fn device_associated(&self, _: [u8; 8], _: u16) {}
+
+    fn device_disassociated(&self, addr_long: [u8; 8], short_addr: u16) {
+        self.discard_state(|addr| {
+            addr == MacAddress::Long(addr_long) || addr == MacAddress::Short(short_addr)
+        });
     }
 }
@@ -3,7 +3,8 @@
 use core::mem;
 use core::result::Result;
 use net::ieee802154::MacAddress;
-use net::ip::{IP6Header, IPAddr, ip6_nh};
+use net::ip;
+use net::ip::{ExtHeaderIter, IP6Header, IPAddr, ip6_nh, ip6_opt};
 use net::util;
 use net::util::{slice_to_u16, u16_to_slice};
 
@@ -110,7 +111,7 @@ pub fn compute_iid(mac_addr: &MacAddress) -> [u8; 8] {
         &MacAddress::Short(short_addr) => {
             // IID is 0000:00ff:fe00:XXXX, where XXXX is 16-bit MAC
             let mut iid: [u8; 8] = iphc::MAC_BASE;
-            iid[6] = (short_addr >> 1) as u8;
+            iid[6] = (short_addr >> 8) as u8;
             iid[7] = (short_addr & 0xff) as u8;
             iid
         }
@@ -193,85 +194,6 @@ fn is_ip6_nh_compressible(next_header: u8, next_headers: &[u8]) -> Result<(bool,
     }
 }
 
-trait OnesComplement {
-    fn ones_complement_add(self, other: Self) -> Self;
-}
-
-/// Implements one's complement addition for use in calculating the UDP checksum
-impl OnesComplement for u16 {
-    fn ones_complement_add(self, other: u16) -> u16 {
-        let (sum, overflow) = self.overflowing_add(other);
-        if overflow {
-            sum + 1
-        } else {
-            sum
-        }
-    }
-}
-
-/// Computes the UDP checksum for a UDP packet sent over IPv6.
-/// Returns the checksum in host byte-order.
-fn compute_udp_checksum(
-    ip6_header: &IP6Header,
-    udp_header: &[u8],
-    udp_length: u16,
-    payload: &[u8],
-) -> u16 {
-    // The UDP checksum is computed on the IPv6 pseudo-header concatenated
-    // with the UDP header and payload, but with the UDP checksum field
-    // zeroed out. Hence, this function assumes that `udp_header` has already
-    // been filled with the UDP header, except for the ignored checksum.
-    let mut checksum: u16 = 0;
-
-    // IPv6 pseudo-header
-    // +--16 bits--+--16 bits--+--16 bits--+--16 bits--+
-    // |                                               |
-    // +              Source IPv6 Address              +
-    // |                                               |
-    // +-----------+-----------+-----------+-----------+
-    // |                                               |
-    // +           Destination IPv6 Address            +
-    // |                                               |
-    // +-----------+-----------+-----------+-----------+
-    // |      UDP Length       |     0     |  NH type  |
-    // +-----------+-----------+-----------+-----------+
-
-    // Source and destination addresses
-    for two_bytes in ip6_header.src_addr.0.chunks(2) {
-        checksum = checksum.ones_complement_add(slice_to_u16(two_bytes));
-    }
-    for two_bytes in ip6_header.dst_addr.0.chunks(2) {
-        checksum = checksum.ones_complement_add(slice_to_u16(two_bytes));
-    }
-
-    // UDP length and UDP next header type. Note that we can avoid adding zeros,
-    // but the pseudo header must be in network byte-order.
-    checksum = checksum.ones_complement_add(udp_length.to_be());
-    checksum = checksum.ones_complement_add((ip6_nh::UDP as u16).to_be());
-
-    // UDP header without the checksum (which is the last two bytes)
-    for two_bytes in udp_header[0..6].chunks(2) {
-        checksum = checksum.ones_complement_add(slice_to_u16(two_bytes));
-    }
-
-    // UDP payload
-    for bytes in payload.chunks(2) {
-        checksum = checksum.ones_complement_add(if bytes.len() == 2 {
-            slice_to_u16(bytes)
-        } else {
-            (bytes[0] as u16).to_be()
-        });
-    }
-
-    // Return the complement of the checksum, unless it is 0, in which case we
-    // the checksum is one's complement -0 for a non-zero binary representation
-    if !checksum != 0 {
-        !checksum
-    } else {
-        checksum
-    }
-}
-
 /// Maps values of a IPv6 next header field to a corresponding LoWPAN
 /// NHC-encoding extension ID, if that next header type is NHC-compressible
 fn ip6_nh_to_nhc_eid(next_header: u8) -> Option<u8> {
@@ -313,12 +235,18 @@ fn nhc_to_ip6_nh(nhc: u8) -> Result<u8, ()> {
 /// compressed header bytes written into `buf`. Payload bytes and
 /// non-compressed next headers are not written, so the remaining `buf.len()
 /// - consumed` bytes must still be copied over to `buf`.
+///
+/// If `elide_udp_checksum` is true, the checksum of a UDP header is elided.
+/// Per RFC 6282, Section 4.3.2, this may only be done when the upper layer
+/// authorizes it, and when the frame carrying the packet is protected by a
+/// message integrity code at least as strong as the checksum.
 pub fn compress(
     ctx_store: &ContextStore,
     ip6_datagram: &[u8],
     src_mac_addr: MacAddress,
     dst_mac_addr: MacAddress,
     mut buf: &mut [u8],
+    elide_udp_checksum: bool,
 ) -> Result<(usize, usize), ()> {
     // Note that consumed should be constant, and equal sizeof(IP6Header)
     let (mut consumed, ip6_header) = IP6Header::decode(ip6_datagram).done().ok_or(())?;
@@ -403,6 +331,7 @@ pub fn compress(
                     src_mac_addr,
                     dst_mac_addr,
                     &mut buf[written..],
+                    elide_udp_checksum,
                 )?;
                 consumed += encap_consumed;
                 written += encap_written;
@@ -419,9 +348,17 @@ pub fn compress(
                 written += 1;
 
                 // Compress ports and checksum
+                if next_headers.len() < 8 {
+                    return Err(());
+                }
                 let udp_header = &next_headers[0..8];
                 nhc_header |= compress_udp_ports(udp_header, &mut buf, &mut written);
-                nhc_header |= compress_udp_checksum(udp_header, &mut buf, &mut written);
+                nhc_header |= compress_udp_checksum(
+                    udp_header,
+                    elide_udp_checksum,
+                    &mut buf,
+                    &mut written,
+                );
 
                 // Write the UDP LoWPAN_NHC byte
                 buf[udp_nh_offset] = nhc_header;
@@ -445,6 +382,9 @@ pub fn compress(
                 // next_nh_offset includes the next header field and the
                 // length byte, while nh_len does not
                 let next_nh_offset = 2 + (nh_len as usize);
+                if next_headers.len() < next_nh_offset {
+                    return Err(());
+                }
 
                 // Determine if the next header is compressible
                 let (next_is_nhc, next_nh_len) =
@@ -455,12 +395,19 @@ pub fn compress(
 
                 // Place NHC ID in buffer
                 buf[written] = nhc_header;
-                if ip6_nh_type != ip6_nh::FRAGMENT {
-                    // Fragment extension does not have a length field
-                    buf[written + 1] = nh_len;
+                written += 1;
+                if !next_is_nhc {
+                    // The next header field is carried inline if the
+                    // following header is not LoWPAN_NHC-compressed
+                    buf[written] = next_headers[0];
+                    written += 1;
                 }
-                written += 2;
 
+                // The length field replaces the IPv6 length field (or the
+                // reserved byte of the fragment header), and counts the
+                // octets that follow it once padding has been elided
+                let len_offset = written;
+                written += 1;
                 compress_and_elide_padding(
                     ip6_nh_type,
                     nh_len as usize,
@@ -468,6 +415,7 @@ pub fn compress(
                     &mut buf,
                     &mut written,
                 );
+                buf[len_offset] = (written - len_offset - 1) as u8;
 
                 ip6_nh_type = next_headers[0];
                 is_nhc = next_is_nhc;
@@ -707,8 +655,8 @@ fn compress_multicast(
 }
 
 fn compress_udp_ports(udp_header: &[u8], buf: &mut [u8], written: &mut usize) -> u8 {
-    let src_port = u16::from_be(slice_to_u16(&udp_header[0..2]));
-    let dst_port = u16::from_be(slice_to_u16(&udp_header[2..4]));
+    let src_port = slice_to_u16(&udp_header[0..2]);
+    let dst_port = slice_to_u16(&udp_header[2..4]);
 
     let mut udp_port_nhc = 0;
     if (src_port & nhc::UDP_4BIT_PORT_MASK) == nhc::UDP_4BIT_PORT
@@ -725,12 +673,13 @@ fn compress_udp_ports(udp_header: &[u8], buf: &mut [u8], written: &mut usize) ->
         // Source port compressed to 8 bits, destination port uncompressed
         udp_port_nhc |= nhc::UDP_SRC_PORT_FLAG;
         buf[*written] = (src_port & !nhc::UDP_8BIT_PORT_MASK) as u8;
-        u16_to_slice(dst_port.to_be(), &mut buf[*written + 1..*written + 3]);
+        u16_to_slice(dst_port, &mut buf[*written + 1..*written + 3]);
         *written += 3;
     } else if (dst_port & nhc::UDP_8BIT_PORT_MASK) == nhc::UDP_8BIT_PORT {
+        // Source port uncompressed, destination port compressed to 8 bits
         udp_port_nhc |= nhc::UDP_DST_PORT_FLAG;
-        u16_to_slice(src_port.to_be(), &mut buf[*written..*written + 2]);
-        buf[*written + 3] = (dst_port & !nhc::UDP_8BIT_PORT_MASK) as u8;
+        u16_to_slice(src_port, &mut buf[*written..*written + 2]);
+        buf[*written + 2] = (dst_port & !nhc::UDP_8BIT_PORT_MASK) as u8;
         *written += 3;
     } else {
         buf[*written..*written + 4].copy_from_slice(&udp_header[0..4]);
@@ -739,8 +688,16 @@ fn compress_udp_ports(udp_header: &[u8], buf: &mut [u8], written: &mut usize) ->
     return udp_port_nhc;
 }
 
-fn compress_udp_checksum(udp_header: &[u8], buf: &mut [u8], written: &mut usize) -> u8 {
-    // TODO: Checksum is always inline, elision is currently not supported
+fn compress_udp_checksum(
+    udp_header: &[u8],
+    elide_checksum: bool,
+    buf: &mut [u8],
+    written: &mut usize,
+) -> u8 {
+    if elide_checksum {
+        // The receiver recomputes the checksum when decompressing
+        return nhc::UDP_CHECKSUM_FLAG;
+    }
     buf[*written] = udp_header[6];
     buf[*written + 1] = udp_header[7];
     *written += 2;
@@ -849,14 +806,27 @@ fn compress_and_elide_padding(
 ///
 /// * `is_fragment` - ???
 ///
+/// * `integrity_checked` - Whether the frame carrying `buf` was protected by
+/// a link-layer message integrity code. Packets with an elided UDP checksum
+/// are rejected otherwise, as required by RFC 6282, Section 4.3.2. If the
+/// checksum is elided in the first fragment of a packet, it cannot be
+/// computed before the packet is reassembled, so the checksum field is left
+/// zero and `checksum_deferred` is set; see `complete_udp_checksum`.
+///
 /// # Returns
 ///
-/// `Ok((consumed, written))` if decompression is successful.
+/// `Ok((consumed, written, checksum_deferred))` if decompression is
+/// successful.
 ///
 /// * `consumed` is the number of header bytes consumed from the 6LoWPAN header
 ///
 /// * `written` is the number of uncompressed header bytes written into
 /// `out_buf`.
+///
+/// * `checksum_deferred` is true if the UDP checksum was elided from a
+/// fragment, and must be filled in with `complete_udp_checksum` once the
+/// packet has been reassembled. A checksum carried inline is never deferred,
+/// even if it is zero.
 pub fn decompress(
     ctx_store: &ContextStore,
     buf: &[u8],
@@ -865,7 +835,8 @@ pub fn decompress(
     out_buf: &mut [u8],
     dgram_size: u16,
     is_fragment: bool,
-) -> Result<(usize, usize), ()> {
+    integrity_checked: bool,
+) -> Result<(usize, usize, bool), ()> {
     // Get the LOWPAN_IPHC header (the first two bytes are the header)
     let iphc_header_1: u8 = buf[0];
     let iphc_header_2: u8 = buf[1];
@@ -873,6 +844,7 @@ pub fn decompress(
 
     let mut ip6_header = IP6Header::new();
     let mut written: usize = mem::size_of::<IP6Header>();
+    let mut checksum_deferred = false;
 
     // Decompress CID and CIE fields if they exist
     let (src_ctx, dst_ctx) = decompress_cie(ctx_store, iphc_header_1, &buf, &mut consumed)?;
@@ -936,7 +908,7 @@ pub fn decompress(
 
         match next_header {
             ip6_nh::IP6 => {
-                let (encap_consumed, encap_written) = decompress(
+                let (encap_consumed, encap_written, encap_deferred) = decompress(
                     ctx_store,
                     &buf[consumed..],
                     src_mac_addr,
@@ -944,30 +916,63 @@ pub fn decompress(
                     &mut next_headers,
                     dgram_size,
                     is_fragment,
+                    integrity_checked,
                 )?;
                 consumed += encap_consumed;
                 written += encap_written;
+                checksum_deferred = encap_deferred;
                 break;
             }
             ip6_nh::UDP => {
-                // UDP length includes UDP header and data in bytes
-                let udp_length = (8 + (buf.len() - consumed)) as u16;
+                let checksum_elided = (nhc_header & nhc::UDP_CHECKSUM_FLAG) != 0;
+                if checksum_elided && !integrity_checked {
+                    return Err(());
+                }
                 // Decompress UDP header fields
-                let (src_port, dst_port) = decompress_udp_ports(nhc_header, &buf, &mut consumed);
+                let (src_port, dst_port) = decompress_udp_ports(nhc_header, &buf, &mut consumed)?;
+                let inline_checksum = if checksum_elided {
+                    None
+                } else {
+                    if consumed + 2 > buf.len() {
+                        return Err(());
+                    }
+                    let checksum = slice_to_u16(&buf[consumed..consumed + 2]);
+                    consumed += 2;
+                    Some(checksum)
+                };
+                // UDP length includes UDP header and data in bytes, and
+                // extends to the end of the datagram
+                let udp_length = if is_fragment {
+                    if (dgram_size as usize) < written + 8 {
+                        return Err(());
+                    }
+                    dgram_size - (written as u16)
+                } else {
+                    (8 + (buf.len() - consumed)) as u16
+                };
                 // Fill in uncompressed UDP header
-                u16_to_slice(src_port.to_be(), &mut next_headers[0..2]);
-                u16_to_slice(dst_port.to_be(), &mut next_headers[2..4]);
-                u16_to_slice(udp_length.to_be(), &mut next_headers[4..6]);
+                u16_to_slice(src_port, &mut next_headers[0..2]);
+                u16_to_slice(dst_port, &mut next_headers[2..4]);
+                u16_to_slice(udp_length, &mut next_headers[4..6]);
                 // Need to fill in header values before computing the checksum
-                let udp_checksum = decompress_udp_checksum(
-                    nhc_header,
-                    &next_headers[0..8],
-                    udp_length,
-                    &ip6_header,
-                    &buf,
-                    &mut consumed,
-                );
-                u16_to_slice(udp_checksum.to_be(), &mut next_headers[6..8]);
+                let udp_checksum = match inline_checksum {
+                    Some(checksum) => checksum,
+                    // The payload of a fragmented packet is not available yet
+                    None if is_fragment => {
+                        checksum_deferred = true;
+                        0
+                    }
+                    // The checksum field itself is not filled in yet
+                    None => ip::compute_checksum(
+                        &ip6_header.src_addr,
+                        &ip6_header.dst_addr,
+                        ip6_nh::UDP,
+                        udp_length,
+                        &next_headers[0..6],
+                        &buf[consumed..],
+                    ),
+                };
+                u16_to_slice(udp_checksum, &mut next_headers[6..8]);
 
                 written += 8;
                 break;
@@ -977,37 +982,53 @@ pub fn decompress(
             | ip6_nh::ROUTING
             | ip6_nh::DST_OPTS
             | ip6_nh::MOBILITY => {
+                let header_type = next_header;
                 // True if the next header is also compressed
                 is_nhc = (nhc_header & nhc::NH) != 0;
 
+                // If the following header is not compressed, its type is
+                // carried inline before the length field
+                if !is_nhc {
+                    if consumed >= buf.len() {
+                        return Err(());
+                    }
+                    next_header = buf[consumed];
+                    consumed += 1;
+                }
+
                 // len is the number of octets following the length field
+                if consumed >= buf.len() {
+                    return Err(());
+                }
                 let len = buf[consumed] as usize;
                 consumed += 1;
 
-                // Check that there is a next header in the buffer,
-                // which must be the case if the last next header specifies
-                // NH = 1
-                if consumed + len >= buf.len() {
+                // Check that the header is in the buffer, followed by the
+                // LoWPAN_NHC byte of the next header if NH = 1
+                if consumed + len > buf.len() || (is_nhc && consumed + len == buf.len()) {
                     return Err(());
                 }
 
                 // Length in 8-octet units after the first 8 octets
-                // (per the IPv6 ext hdr spec)
-                let mut hdr_len_field = (len - 6) / 8;
-                if (len - 6) % 8 != 0 {
-                    hdr_len_field += 1;
+                // (per the IPv6 ext hdr spec), rounded up to account for
+                // elided padding
+                let hdr_len_field = (len + 2 + 7) / 8 - 1;
+                let pad_bytes = (hdr_len_field + 1) * 8 - len - 2;
+                // Only options headers can have had their padding elided
+                let has_options =
+                    header_type == ip6_nh::HOP_OPTS || header_type == ip6_nh::DST_OPTS;
+                if pad_bytes > 0 && !has_options {
+                    return Err(());
+                }
+                if next_headers.len() < (hdr_len_field + 1) * 8 {
+                    return Err(());
                 }
 
-                // Gets the type of the subsequent next header.  If is_nhc
-                // is true, there must be a LoWPAN NHC header byte,
-                // otherwise there is either an uncompressed next header.
-                next_header = if is_nhc {
-                    // The next header is LoWPAN NHC-compressed
-                    nhc_to_ip6_nh(buf[consumed + len])?
-                } else {
-                    // The next header is uncompressed
-                    buf[consumed + len]
-                };
+                // Gets the type of the subsequent next header from its
+                // LoWPAN NHC header byte if it is compressed
+                if is_nhc {
+                    next_header = nhc_to_ip6_nh(buf[consumed + len])?;
+                }
 
                 // Fill in the extended header in uncompressed IPv6 format
                 next_headers[0] = next_header;
@@ -1016,13 +1037,12 @@ pub fn decompress(
                 next_headers[2..2 + len].copy_from_slice(&buf[consumed..consumed + len]);
 
                 // Fill in padding
-                let pad_bytes = hdr_len_field * 8 - len + 6;
                 if pad_bytes == 1 {
                     // Pad1
-                    next_headers[2 + len] = 0;
-                } else {
+                    next_headers[2 + len] = ip6_opt::PAD1;
+                } else if pad_bytes > 1 {
                     // PadN, 2 <= pad_bytes <= 7
-                    next_headers[2 + len] = 1;
+                    next_headers[2 + len] = ip6_opt::PADN;
                     next_headers[2 + len + 1] = pad_bytes as u8 - 2;
                     for i in 2..pad_bytes {
                         next_headers[2 + len + i] = 0;
@@ -1046,7 +1066,42 @@ pub fn decompress(
     };
     ip6_header.payload_len = (payload_len as u16).to_be();
     IP6Header::encode(out_buf, ip6_header).done().ok_or(())?;
-    Ok((consumed, written))
+    Ok((consumed, written, checksum_deferred))
+}
+
+/// Computes the UDP checksum of a reassembled IPv6 packet whose checksum was
+/// elided from its first fragment, which `decompress` reports as
+/// `checksum_deferred`. Packets that do not carry UDP are left unchanged.
+/// Returns `Err(())` if the packet is malformed.
+pub fn complete_udp_checksum(ip6_packet: &mut [u8]) -> Result<(), ()> {
+    let (_, ip6_header) = IP6Header::decode(ip6_packet).done().ok_or(())?;
+
+    // Skip over any extension headers preceding the UDP header
+    let mut headers = ExtHeaderIter::new(ip6_packet);
+    for _ in &mut headers {}
+    let (next_header, offset) = headers.upper_layer()?;
+    if next_header != ip6_nh::UDP {
+        return Ok(());
+    }
+
+    if offset + 8 > ip6_packet.len() {
+        return Err(());
+    }
+    let udp_length = slice_to_u16(&ip6_packet[offset + 4..offset + 6]);
+    let udp_end = offset + (udp_length as usize);
+    if udp_length < 8 || udp_end > ip6_packet.len() {
+        return Err(());
+    }
+    let checksum = ip::compute_checksum(
+        &ip6_header.src_addr,
+        &ip6_header.dst_addr,
+        ip6_nh::UDP,
+        udp_length,
+        &ip6_packet[offset..offset + 6],
+        &ip6_packet[offset + 8..udp_end],
+    );
+    u16_to_slice(checksum, &mut ip6_packet[offset + 6..offset + 8]);
+    Ok(())
 }
 
 fn decompress_cie(
@@ -1348,9 +1403,17 @@ fn decompress_iid_context(
 }
 
 // Returns the UDP ports in host byte-order
-fn decompress_udp_ports(udp_nhc: u8, buf: &[u8], consumed: &mut usize) -> (u16, u16) {
+fn decompress_udp_ports(udp_nhc: u8, buf: &[u8], consumed: &mut usize) -> Result<(u16, u16), ()> {
     let src_compressed = (udp_nhc & nhc::UDP_SRC_PORT_FLAG) != 0;
     let dst_compressed = (udp_nhc & nhc::UDP_DST_PORT_FLAG) != 0;
+    let ports_len = match (src_compressed, dst_compressed) {
+        (true, true) => 1,
+        (false, false) => 4,
+        _ => 3,
+    };
+    if *consumed + ports_len > buf.len() {
+        return Err(());
+    }
 
     let src_port;
     let dst_port;
@@ -1365,40 +1428,19 @@ fn decompress_udp_ports(udp_nhc: u8, buf: &[u8], consumed: &mut usize) -> (u16,
         // Source port is compressed to 8 bits
         src_port = nhc::UDP_8BIT_PORT | (buf[*consumed] as u16);
         // Destination port is uncompressed
-        dst_port = u16::from_be(slice_to_u16(&buf[*consumed + 1..*consumed + 3]));
+        dst_port = slice_to_u16(&buf[*consumed + 1..*consumed + 3]);
         *consumed += 3;
     } else if dst_compressed {
         // Source port is uncompressed
-        src_port = u16::from_be(slice_to_u16(&buf[*consumed..*consumed + 2]));
+        src_port = slice_to_u16(&buf[*consumed..*consumed + 2]);
         // Destination port is compressed to 8 bits
         dst_port = nhc::UDP_8BIT_PORT | (buf[*consumed + 2] as u16);
         *consumed += 3;
     } else {
         // Both ports are uncompressed
-        src_port = u16::from_be(slice_to_u16(&buf[*consumed..*consumed + 2]));
-        dst_port = u16::from_be(slice_to_u16(&buf[*consumed + 2..*consumed + 4]));
+        src_port = slice_to_u16(&buf[*consumed..*consumed + 2]);
+        dst_port = slice_to_u16(&buf[*consumed + 2..*consumed + 4]);
         *consumed += 4;
     }
-    (src_port, dst_port)
-}
-
-// Returns the UDP checksum in host byte-order
-fn decompress_udp_checksum(
-    udp_nhc: u8,
-    udp_header: &[u8],
-    udp_length: u16,
-    ip6_header: &IP6Header,
-    buf: &[u8],
-    consumed: &mut usize,
-) -> u16 {
-    if (udp_nhc & nhc::UDP_CHECKSUM_FLAG) != 0 {
-        // TODO: Need to verify that the packet was sent with *some* kind
-        // of integrity check at a lower level (otherwise, we need to drop
-        // the packet)
-        compute_udp_checksum(ip6_header, udp_header, udp_length, &buf[*consumed..])
-    } else {
-        let checksum = u16::from_be(slice_to_u16(&buf[*consumed..*consumed + 2]));
-        *consumed += 2;
-        checksum
-    }
+    Ok((src_port, dst_port))
 }
@@ -232,6 +232,14 @@ pub fn encode_u16(buf: &mut [u8], b: u16) -> SResult {
     stream_done!(2);
 }
 
+// Bluetooth and USB use little-endian byte order
+pub fn encode_u16_le(buf: &mut [u8], b: u16) -> SResult {
+    stream_len_cond!(buf, 2);
+    buf[0] = b as u8;
+    buf[1] = (b >> 8) as u8;
+    stream_done!(2);
+}
+
 pub fn encode_u32(buf: &mut [u8], b: u32) -> SResult {
     stream_len_cond!(buf, 4);
     buf[0] = (b >> 24) as u8;
@@ -266,6 +274,11 @@ pub fn decode_u16(buf: &[u8]) -> SResult<u16> {
     stream_done!(2, (buf[0] as u16) << 8 | (buf[1] as u16));
 }
 
+pub fn decode_u16_le(buf: &[u8]) -> SResult<u16> {
+    stream_len_cond!(buf, 2);
+    stream_done!(2, (buf[1] as u16) << 8 | (buf[0] as u16));
+}
+
 pub fn decode_u32(buf: &[u8]) -> SResult<u32> {
     stream_len_cond!(buf, 4);
     let b = (buf[0] as u32) << 24 | (buf[1] as u32) << 16 | (buf[2] as u32) << 8 | (buf[3] as u32);
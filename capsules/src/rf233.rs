@@ -10,6 +10,9 @@
 //! - Support TX power control
 //! - Support channel selection
 //! - Support link-layer acknowledgements
+//!
+//! Received frames are reported with their RSSI and LQI, and timestamped
+//! with the given alarm when the start-of-frame interrupt is signaled.
 //
 // Author: Philip Levis
 // Date: Jan 12 2017
@@ -19,11 +22,13 @@
 #![allow(unused_parens)]
 
 use core::cell::Cell;
+use core::cmp::min;
 use kernel::ReturnCode;
 use kernel::common::take_cell::TakeCell;
 use kernel::hil::gpio;
 use kernel::hil::radio;
 use kernel::hil::spi;
+use kernel::hil::time::Alarm;
 use rf233_const::*;
 
 const INTERRUPT_ID: usize = 0x2154;
@@ -116,6 +121,7 @@ enum InternalState {
     CONFIG_IEEE6_SET,
     CONFIG_IEEE7_SET,
     CONFIG_POWER_SET,
+    CONFIG_CHANNEL_SET,
     CONFIG_DONE,
 
     // RX is a short-lived state for when software has detected
@@ -127,9 +133,10 @@ enum InternalState {
     RX_START_READING,     // Starting to read a packet out of the radio
     RX_READING_FRAME_LEN, // We've read the length of the frame
     RX_READING_FRAME_LEN_DONE,
-    RX_READING_FRAME,      // Reading the packet out of the radio
-    RX_READING_FRAME_DONE, // Now read a register to verify FCS
-    RX_READING_FRAME_FCS_DONE,
+    RX_READING_FRAME,          // Reading the packet out of the radio
+    RX_READING_FRAME_DONE,     // Now read a register to verify FCS
+    RX_READING_FRAME_FCS_DONE, // Now read the energy level of the frame
+    RX_READING_FRAME_ED_DONE,
 }
 
 // There are two tricky parts to this capsule: buffer management
@@ -174,8 +181,9 @@ enum InternalState {
 // and waits for the interrupt specifying the entire packet has been
 // received.
 
-pub struct RF233<'a, S: spi::SpiMasterDevice + 'a> {
+pub struct RF233<'a, S: spi::SpiMasterDevice + 'a, A: Alarm + 'a> {
     spi: &'a S,
+    alarm: &'a A,
     radio_on: Cell<bool>,
     transmitting: Cell<bool>,
     receiving: Cell<bool>,
@@ -203,6 +211,11 @@ pub struct RF233<'a, S: spi::SpiMasterDevice + 'a> {
     pan: Cell<u16>,
     tx_power: Cell<i8>,
     channel: Cell<u8>,
+    ack_frame_pending: Cell<bool>,
+    // The time of the last interrupt, and of the last start of frame
+    irq_time: Cell<u32>,
+    rx_time: Cell<Option<u32>>,
+    rx_crc_valid: Cell<bool>,
     spi_rx: TakeCell<'static, [u8]>,
     spi_tx: TakeCell<'static, [u8]>,
     spi_buf: TakeCell<'static, [u8]>,
@@ -264,7 +277,7 @@ fn interrupt_included(mask: u8, interrupt: u8) -> bool {
     (mask & interrupt) == interrupt
 }
 
-impl<'a, S: spi::SpiMasterDevice + 'a> spi::SpiMasterClient for RF233<'a, S> {
+impl<'a, S: spi::SpiMasterDevice + 'a, A: Alarm + 'a> spi::SpiMasterClient for RF233<'a, S, A> {
     fn read_write_done(
         &self,
         mut _write: &'static mut [u8],
@@ -332,6 +345,7 @@ impl<'a, S: spi::SpiMasterDevice + 'a> spi::SpiMasterClient for RF233<'a, S> {
                 if interrupt_included(interrupt, IRQ_2_RX_START) {
                     // Start of frame
                     self.receiving.set(true);
+                    self.rx_time.set(Some(self.irq_time.get()));
                     self.state.set(InternalState::RX);
                 }
 
@@ -378,8 +392,9 @@ impl<'a, S: spi::SpiMasterDevice + 'a> spi::SpiMasterClient for RF233<'a, S> {
         // receiving a frame.
         if self.interrupt_pending.get() {
             match self.state.get() {
-                InternalState::RX_READING_FRAME_DONE | InternalState::RX_READING_FRAME_FCS_DONE => {
-                }
+                InternalState::RX_READING_FRAME_DONE
+                | InternalState::RX_READING_FRAME_FCS_DONE
+                | InternalState::RX_READING_FRAME_ED_DONE => {}
                 _ => {
                     self.interrupt_pending.set(false);
                     self.handle_interrupt();
@@ -760,14 +775,23 @@ impl<'a, S: spi::SpiMasterDevice + 'a> spi::SpiMasterClient for RF233<'a, S> {
 
             // Insert read of TRX_STATUS here, checking TRAC
             InternalState::TX_RETURN_TO_RX => {
-                let ack: bool = (result & TRX_TRAC_MASK) == 0;
+                // The acknowledgement may have its Frame Pending field set
+                let trac = result & TRX_TRAC_MASK;
+                let ack: bool = trac == 0 || trac == TRX_TRAC_SUCCESS_DATA_PENDING;
+                // The CCA performed by TX_ARET found the channel busy and
+                // the frame was not sent
+                let rval = if trac == TRX_TRAC_CHANNEL_ACCESS_FAILURE {
+                    ReturnCode::EBUSY
+                } else {
+                    ReturnCode::SUCCESS
+                };
                 if status == ExternalState::RX_AACK_ON as u8 {
                     self.transmitting.set(false);
                     let buf = self.tx_buf.take();
                     self.state_transition_read(RF233Register::TRX_STATUS, InternalState::READY);
 
                     self.tx_client.get().map(|c| {
-                        c.send_done(buf.unwrap(), ack, ReturnCode::SUCCESS);
+                        c.send_done(buf.unwrap(), ack, rval);
                     });
                 } else {
                     self.register_read(RF233Register::TRX_STATUS);
@@ -808,7 +832,10 @@ impl<'a, S: spi::SpiMasterDevice + 'a> spi::SpiMasterClient for RF233<'a, S> {
                 {
                     self.state.set(InternalState::RX_READING_FRAME);
                     let rbuf = self.rx_buf.take().unwrap();
-                    self.frame_read(rbuf, frame_len);
+                    // Also read the LQI if it fits in the buffer, which it
+                    // does for all frames but those of the maximum length
+                    let read_len = min(frame_len as usize + 1, rbuf.len() - radio::PSDU_OFFSET);
+                    self.frame_read(rbuf, read_len as u8);
                 } else if self.transmitting.get() {
                     // Packet was too long and a transmission is pending,
                     // start the transmission
@@ -831,7 +858,15 @@ impl<'a, S: spi::SpiMasterDevice + 'a> spi::SpiMasterClient for RF233<'a, S> {
                 );
             }
             InternalState::RX_READING_FRAME_FCS_DONE => {
-                let crc_valid = result & PHY_RSSI_RX_CRC_VALID != 0;
+                self.rx_crc_valid.set(result & PHY_RSSI_RX_CRC_VALID != 0);
+                // The energy level measured over the frame gives its RSSI
+                self.state_transition_read(
+                    RF233Register::PHY_ED_LEVEL,
+                    InternalState::RX_READING_FRAME_ED_DONE,
+                );
+            }
+            InternalState::RX_READING_FRAME_ED_DONE => {
+                let crc_valid = self.rx_crc_valid.get();
                 self.receiving.set(false);
 
                 // Stay awake if we receive a packet, another call to stop()
@@ -851,10 +886,28 @@ impl<'a, S: spi::SpiMasterDevice + 'a> spi::SpiMasterClient for RF233<'a, S> {
                 } else {
                     self.state_transition_read(RF233Register::TRX_STATUS, InternalState::READY);
                 }
+                let rssi = if result == PHY_ED_LEVEL_INVALID {
+                    RSSI_BASE_VAL
+                } else {
+                    RSSI_BASE_VAL + min(result, PHY_ED_LEVEL_MAX) as i8
+                };
+                let timestamp = self.rx_time.get();
+                self.rx_time.set(None);
                 self.rx_client.get().map(|client| {
                     let rbuf = self.rx_buf.take().unwrap();
-                    let frame_len = rbuf[1] as usize - radio::MFR_SIZE;
-                    client.receive(rbuf, frame_len, crc_valid, ReturnCode::SUCCESS);
+                    let psdu_len = rbuf[1] as usize;
+                    let frame_len = psdu_len - radio::MFR_SIZE;
+                    // The LQI is not read for frames of the maximum length,
+                    // which are reported with the lowest LQI
+                    let lqi = rbuf
+                        .get(radio::PSDU_OFFSET + psdu_len)
+                        .map_or(0, |lqi| *lqi);
+                    let info = radio::RxInfo {
+                        rssi: rssi,
+                        lqi: lqi,
+                        timestamp: timestamp,
+                    };
+                    client.receive(rbuf, frame_len, crc_valid, info, ReturnCode::SUCCESS);
                 });
             }
 
@@ -948,6 +1001,18 @@ impl<'a, S: spi::SpiMasterDevice + 'a> spi::SpiMasterClient for RF233<'a, S> {
                 self.state_transition_write(
                     RF233Register::PHY_CC_CCA,
                     val,
+                    InternalState::CONFIG_CHANNEL_SET,
+                );
+            }
+            InternalState::CONFIG_CHANNEL_SET => {
+                let val = if self.ack_frame_pending.get() {
+                    CSMA_SEED_1 | AACK_SET_PD
+                } else {
+                    CSMA_SEED_1
+                };
+                self.state_transition_write(
+                    RF233Register::CSMA_SEED_1,
+                    val,
                     InternalState::CONFIG_DONE,
                 );
             }
@@ -962,24 +1027,27 @@ impl<'a, S: spi::SpiMasterDevice + 'a> spi::SpiMasterClient for RF233<'a, S> {
     }
 }
 
-impl<'a, S: spi::SpiMasterDevice + 'a> gpio::Client for RF233<'a, S> {
+impl<'a, S: spi::SpiMasterDevice + 'a, A: Alarm + 'a> gpio::Client for RF233<'a, S, A> {
     fn fired(&self, identifier: usize) {
         if identifier == INTERRUPT_ID {
+            self.irq_time.set(self.alarm.now());
             self.handle_interrupt();
         }
     }
 }
 
-impl<'a, S: spi::SpiMasterDevice + 'a> RF233<'a, S> {
+impl<'a, S: spi::SpiMasterDevice + 'a, A: Alarm + 'a> RF233<'a, S, A> {
     pub fn new(
         spi: &'a S,
+        alarm: &'a A,
         reset: &'a gpio::Pin,
         sleep: &'a gpio::Pin,
         irq: &'a gpio::Pin,
         ctl: &'a gpio::PinCtl,
-    ) -> RF233<'a, S> {
+    ) -> RF233<'a, S, A> {
         RF233 {
             spi: spi,
+            alarm: alarm,
             reset_pin: reset,
             sleep_pin: sleep,
             irq_pin: irq,
@@ -1007,6 +1075,10 @@ impl<'a, S: spi::SpiMasterDevice + 'a> RF233<'a, S> {
             pan: Cell::new(0),
             tx_power: Cell::new(setting_to_power(PHY_TX_PWR)),
             channel: Cell::new(PHY_CHANNEL),
+            ack_frame_pending: Cell::new(false),
+            irq_time: Cell::new(0),
+            rx_time: Cell::new(None),
+            rx_crc_valid: Cell::new(false),
             spi_rx: TakeCell::empty(),
             spi_tx: TakeCell::empty(),
             spi_buf: TakeCell::empty(),
@@ -1090,9 +1162,9 @@ impl<'a, S: spi::SpiMasterDevice + 'a> RF233<'a, S> {
     }
 }
 
-impl<'a, S: spi::SpiMasterDevice + 'a> radio::Radio for RF233<'a, S> {}
+impl<'a, S: spi::SpiMasterDevice + 'a, A: Alarm + 'a> radio::Radio for RF233<'a, S, A> {}
 
-impl<'a, S: spi::SpiMasterDevice + 'a> radio::RadioConfig for RF233<'a, S> {
+impl<'a, S: spi::SpiMasterDevice + 'a, A: Alarm + 'a> radio::RadioConfig for RF233<'a, S, A> {
     fn initialize(
         &self,
         buf: &'static mut [u8],
@@ -1213,6 +1285,10 @@ impl<'a, S: spi::SpiMasterDevice + 'a> radio::RadioConfig for RF233<'a, S> {
         }
     }
 
+    fn set_ack_frame_pending(&self, pending: bool) {
+        self.ack_frame_pending.set(pending);
+    }
+
     fn get_address(&self) -> u16 {
         self.addr.get()
     }
@@ -1257,7 +1333,7 @@ impl<'a, S: spi::SpiMasterDevice + 'a> radio::RadioConfig for RF233<'a, S> {
     }
 }
 
-impl<'a, S: spi::SpiMasterDevice + 'a> radio::RadioData for RF233<'a, S> {
+impl<'a, S: spi::SpiMasterDevice + 'a, A: Alarm + 'a> radio::RadioData for RF233<'a, S, A> {
     fn set_transmit_client(&self, client: &'static radio::TxClient) {
         self.tx_client.set(Some(client));
     }
@@ -69,6 +69,8 @@ pub const PHY_CC_CCA_MODE_ED: u8 = 1 << 5;
 pub const PHY_CC_CCA_MODE_CS: u8 = 2 << 5;
 pub const PHY_CC_CCA_MODE_CS_AND_ED: u8 = 3 << 5;
 pub const PHY_RSSI_RX_CRC_VALID: u8 = 1 << 7;
+pub const PHY_ED_LEVEL_MAX: u8 = 0x54;
+pub const PHY_ED_LEVEL_INVALID: u8 = 0xFF;
 pub const TRX_CTRL_2_RX_SAFE_MODE: u8 = 1 << 7;
 pub const TRX_CTRL_2_DATA_RATE_250: u8 = 0;
 pub const IRQ_TRXBUF_ACCESS_VIOLATION: u8 = 1 << 6;
@@ -78,6 +80,7 @@ pub const IRQ_PLL_LOCK: u8 = 1 << 0;
 pub const XAH_CTRL_1_AACK_PROM_MODE: u8 = 1 << 1;
 pub const XAH_CTRL_1_AACK_UPLD_RES_FT: u8 = 1 << 4;
 pub const XAH_CTRL_1_AACK_FLTR_RES_FT: u8 = 1 << 5;
+pub const AACK_SET_PD: u8 = 1 << 5;
 pub const AACK_FVN_MODE: u8 = 3 << 6;
 
 // Flag combinations that are used in initialization.
@@ -94,7 +97,10 @@ pub const XAH_CTRL_0: u8 = 0;
 pub const CSMA_SEED_1: u8 = AACK_FVN_MODE;
 pub const TRX_RPC: u8 = 0xFF;
 pub const TRX_TRAC_MASK: u8 = 0xE0;
-pub const TRX_TRAC_SUCCESS_DATA_PENDING: u8 = 1;
+pub const TRX_TRAC_SUCCESS_DATA_PENDING: u8 = 0x20;
+pub const TRX_TRAC_CHANNEL_ACCESS_FAILURE: u8 = 0x60;
+// The RSSI, in dBm, of a frame with an energy level of 0
+pub const RSSI_BASE_VAL: i8 = -94;
 
 // Default address settings.
 pub const PAN_ID_0: u8 = 0x22;
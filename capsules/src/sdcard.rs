@@ -2,6 +2,10 @@
 //!
 //! This allows initialization and block reads or writes on top of SPI.
 //!
+//! The card can have a second, exclusive client (for example
+//! `usb_msc::UsbMassStorage`) that takes the card with `lock()`. Until it
+//! calls `unlock()`, the operations of the regular client fail with EBUSY.
+//!
 //! Usage
 //! -----
 //!
@@ -70,6 +74,11 @@ pub struct SDCard<'a, A: hil::time::Alarm + 'a> {
     rxbuffer: TakeCell<'static, [u8]>,
 
     client: Cell<Option<&'static SDCardClient>>,
+    exclusive_client: Cell<Option<&'static SDCardClient>>,
+    /// Whether the exclusive client holds the lock on the card
+    locked: Cell<bool>,
+    /// Whether the current operation was started by the exclusive client
+    exclusive_operation: Cell<bool>,
     client_buffer: TakeCell<'static, [u8]>,
     client_offset: Cell<usize>,
 }
@@ -231,6 +240,9 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
             txbuffer: TakeCell::new(txbuffer),
             rxbuffer: TakeCell::new(rxbuffer),
             client: Cell::new(None),
+            exclusive_client: Cell::new(None),
+            locked: Cell::new(false),
+            exclusive_operation: Cell::new(false),
             client_buffer: TakeCell::empty(),
             client_offset: Cell::new(0),
         }
@@ -440,7 +452,7 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.get().map(move |client| {
+                    self.operation_client().map(move |client| {
                         client.error(ErrorCode::InitializationFailure as u32);
                     });
                 }
@@ -511,7 +523,7 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.get().map(move |client| {
+                    self.operation_client().map(move |client| {
                         client.error(ErrorCode::InitializationFailure as u32);
                     });
                 }
@@ -540,7 +552,7 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.get().map(move |client| {
+                    self.operation_client().map(move |client| {
                         client.error(ErrorCode::InitializationFailure as u32);
                     });
                 }
@@ -608,7 +620,7 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.get().map(move |client| {
+                    self.operation_client().map(move |client| {
                         client.error(ErrorCode::InitializationFailure as u32);
                     });
                 }
@@ -647,7 +659,7 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.get().map(move |client| {
+                    self.operation_client().map(move |client| {
                         client.error(ErrorCode::InitializationFailure as u32);
                     });
                 }
@@ -670,7 +682,7 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.get().map(move |client| {
+                    self.operation_client().map(move |client| {
                         client.error(ErrorCode::InitializationFailure as u32);
                     });
                 }
@@ -722,7 +734,7 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                     self.is_initialized.set(true);
 
                     // perform callback
-                    self.client.get().map(move |client| {
+                    self.operation_client().map(move |client| {
                         client.init_done(512, total_size);
                     });
                 } else {
@@ -732,7 +744,7 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.get().map(move |client| {
+                    self.operation_client().map(move |client| {
                         client.error(ErrorCode::InitializationFailure as u32);
                     });
                 }
@@ -759,7 +771,7 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.get().map(move |client| {
+                    self.operation_client().map(move |client| {
                         client.error(ErrorCode::ReadFailure as u32);
                     });
                 }
@@ -790,7 +802,7 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.get().map(move |client| {
+                    self.operation_client().map(move |client| {
                         client.error(ErrorCode::ReadFailure as u32);
                     });
                 }
@@ -816,7 +828,7 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
 
                         // callback
                         let read_len = cmp::min(read_buffer.len(), cmp::min(buffer.len(), 512));
-                        self.client.get().map(move |client| {
+                        self.operation_client().map(move |client| {
                             client.read_done(buffer, read_len);
                         });
                     });
@@ -849,7 +861,7 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.get().map(move |client| {
+                    self.operation_client().map(move |client| {
                         client.error(ErrorCode::ReadFailure as u32);
                     });
                 }
@@ -900,7 +912,7 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
 
                     // read finished, perform callback
                     self.client_buffer.take().map(move |buffer| {
-                        self.client.get().map(move |client| {
+                        self.operation_client().map(move |client| {
                             client.read_done(buffer, self.client_offset.get());
                         });
                     });
@@ -911,7 +923,7 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.get().map(move |client| {
+                    self.operation_client().map(move |client| {
                         client.error(ErrorCode::ReadFailure as u32);
                     });
                 }
@@ -964,7 +976,7 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                         self.state.set(SpiState::Idle);
                         self.alarm_state.set(AlarmState::Idle);
                         self.alarm_count.set(0);
-                        self.client.get().map(move |client| {
+                        self.operation_client().map(move |client| {
                             client.error(ErrorCode::WriteFailure as u32);
                         });
                     }
@@ -975,7 +987,7 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.get().map(move |client| {
+                    self.operation_client().map(move |client| {
                         client.error(ErrorCode::WriteFailure as u32);
                     });
                 }
@@ -999,7 +1011,7 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.get().map(move |client| {
+                    self.operation_client().map(move |client| {
                         client.error(ErrorCode::WriteFailure as u32);
                     });
                 }
@@ -1016,7 +1028,7 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_count.set(0);
                     self.client_buffer.take().map(move |buffer| {
-                        self.client.get().map(move |client| {
+                        self.operation_client().map(move |client| {
                             client.write_done(buffer);
                         });
                     });
@@ -1052,7 +1064,7 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
             self.state.set(SpiState::Idle);
             self.alarm_state.set(AlarmState::Idle);
             self.alarm_count.set(0);
-            self.client.get().map(move |client| {
+            self.operation_client().map(move |client| {
                 client.error(ErrorCode::TimeoutFailure as u32);
             });
         } else {
@@ -1061,9 +1073,13 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
 
         match self.alarm_state.get() {
             AlarmState::DetectionChange => {
-                // perform callback
+                // perform callback, to the exclusive client as well
+                let installed = self.is_installed();
                 self.client.get().map(move |client| {
-                    client.card_detection_changed(self.is_installed());
+                    client.card_detection_changed(installed);
+                });
+                self.exclusive_client.get().map(move |client| {
+                    client.card_detection_changed(installed);
                 });
 
                 // re-enable interrupts
@@ -1177,10 +1193,77 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
         }
     }
 
+    /// the client that receives the callbacks of the operations it starts
+    fn operation_client(&self) -> Option<&'static SDCardClient> {
+        if self.exclusive_operation.get() {
+            self.exclusive_client.get()
+        } else {
+            self.client.get()
+        }
+    }
+
+    /// records who started an operation, if it did start
+    fn started(&self, result: ReturnCode, exclusive: bool) -> ReturnCode {
+        if result == ReturnCode::SUCCESS {
+            self.exclusive_operation.set(exclusive);
+        }
+        result
+    }
+
+    /// records who started a read or write, if it did start
+    fn started_blocks(
+        &self,
+        result: (ReturnCode, Option<&'static mut [u8]>),
+        exclusive: bool,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        let (result, buffer) = result;
+        (self.started(result, exclusive), buffer)
+    }
+
     pub fn set_client<C: SDCardClient>(&self, client: &'static C) {
         self.client.set(Some(client));
     }
 
+    /// Set the client that can take the card for itself with `lock()`, such
+    /// as a USB mass-storage function while a host has the card mounted
+    pub fn set_exclusive_client<C: SDCardClient>(&self, client: &'static C) {
+        self.exclusive_client.set(Some(client));
+    }
+
+    /// Reserve the card for the exclusive client, which then owns the lock.
+    /// Until `unlock()`, the operations of the other client fail with EBUSY,
+    /// and the exclusive client uses the `exclusive_` operations instead.
+    /// Fails with EBUSY if an operation is in progress, and with FAIL if
+    /// there is no exclusive client to own the lock.
+    pub fn lock(&self) -> ReturnCode {
+        if self.exclusive_client.get().is_none() {
+            ReturnCode::FAIL
+        } else if self.state.get() != SpiState::Idle {
+            ReturnCode::EBUSY
+        } else {
+            self.locked.set(true);
+            ReturnCode::SUCCESS
+        }
+    }
+
+    /// Release the lock of the exclusive client, giving the card back to the
+    /// other client. An operation of the exclusive client still in progress
+    /// completes to the exclusive client.
+    pub fn unlock(&self) {
+        self.locked.set(false);
+    }
+
+    /// Whether the exclusive client holds the lock
+    pub fn is_locked(&self) -> bool {
+        self.locked.get()
+    }
+
+    /// Take back the buffer of a read or write that ended with an error
+    /// callback
+    pub fn take_buffer(&self) -> Option<&'static mut [u8]> {
+        self.client_buffer.take()
+    }
+
     pub fn is_installed(&self) -> bool {
         // if there is no detect pin, assume an sd card is installed
         self.detect_pin.get().map_or(true, |pin| {
@@ -1200,7 +1283,7 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
         });
     }
 
-    pub fn initialize(&self) -> ReturnCode {
+    fn start_initialization(&self) -> ReturnCode {
         // if not already, set card to uninitialized again
         self.is_initialized.set(false);
 
@@ -1224,103 +1307,167 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
         }
     }
 
-    pub fn read_blocks(&self, buffer: &'static mut [u8], sector: u32, count: u32) -> ReturnCode {
-        // only if initialized and installed
-        if self.is_installed() {
-            if self.is_initialized() {
-                self.txbuffer.take().map_or(ReturnCode::ENOMEM, |txbuffer| {
-                    self.rxbuffer
-                        .take()
-                        .map_or(ReturnCode::ENOMEM, move |rxbuffer| {
-                            // save the user buffer for later
-                            self.client_buffer.replace(buffer);
-                            self.client_offset.set(0);
-
-                            // convert block address to byte address for non-block
-                            //  access cards
-                            let mut address = sector;
-                            if self.card_type.get() != SDCardType::SDv2BlockAddressable {
-                                address *= 512;
-                            }
+    pub fn initialize(&self) -> ReturnCode {
+        if self.locked.get() {
+            return ReturnCode::EBUSY;
+        }
+        let result = self.start_initialization();
+        self.started(result, false)
+    }
 
-                            self.state.set(SpiState::StartReadBlocks { count: count });
-                            if count == 1 {
-                                self.send_command(
-                                    SDCmd::CMD17_ReadSingle,
-                                    address,
-                                    txbuffer,
-                                    rxbuffer,
-                                    10,
-                                );
-                            } else {
-                                self.send_command(
-                                    SDCmd::CMD18_ReadMultiple,
-                                    address,
-                                    txbuffer,
-                                    rxbuffer,
-                                    10,
-                                );
-                            }
+    /// Read `count` blocks from `sector` on into `buffer`. If the read does
+    /// not start, the buffer is returned along with the error.
+    pub fn read_blocks(
+        &self,
+        buffer: &'static mut [u8],
+        sector: u32,
+        count: u32,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if self.locked.get() {
+            return (ReturnCode::EBUSY, Some(buffer));
+        }
+        let result = self.start_read_blocks(buffer, sector, count);
+        self.started_blocks(result, false)
+    }
 
-                            // command started successfully
-                            ReturnCode::SUCCESS
-                        })
-                })
-            } else {
-                // sd card not initialized
-                ReturnCode::ERESERVE
-            }
-        } else {
-            // sd card not installed
-            ReturnCode::EUNINSTALLED
+    /// Write `count` blocks from `buffer` to `sector` on. If the write does
+    /// not start, the buffer is returned along with the error.
+    pub fn write_blocks(
+        &self,
+        buffer: &'static mut [u8],
+        sector: u32,
+        count: u32,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if self.locked.get() {
+            return (ReturnCode::EBUSY, Some(buffer));
         }
+        let result = self.start_write_blocks(buffer, sector, count);
+        self.started_blocks(result, false)
     }
 
-    pub fn write_blocks(&self, buffer: &'static mut [u8], sector: u32, count: u32) -> ReturnCode {
-        // only if initialized and installed
-        if self.is_installed() {
-            if self.is_initialized() {
-                self.txbuffer.take().map_or(ReturnCode::ENOMEM, |txbuffer| {
-                    self.rxbuffer
-                        .take()
-                        .map_or(ReturnCode::ENOMEM, move |rxbuffer| {
-                            // save the user buffer for later
-                            self.client_buffer.replace(buffer);
-                            self.client_offset.set(0);
-
-                            // convert block address to byte address for non-block
-                            //  access cards
-                            let mut address = sector;
-                            if self.card_type.get() != SDCardType::SDv2BlockAddressable {
-                                address *= 512;
-                            }
+    /// `initialize()` for the exclusive client while it holds the lock. Fails
+    /// with EBUSY if the card is not locked.
+    pub fn exclusive_initialize(&self) -> ReturnCode {
+        if !self.locked.get() {
+            return ReturnCode::EBUSY;
+        }
+        let result = self.start_initialization();
+        self.started(result, true)
+    }
 
-                            self.state.set(SpiState::StartWriteBlocks { count: count });
-                            if count == 1 {
-                                self.send_command(
-                                    SDCmd::CMD24_WriteSingle,
-                                    address,
-                                    txbuffer,
-                                    rxbuffer,
-                                    10,
-                                );
-
-                                // command started successfully
-                                ReturnCode::SUCCESS
-                            } else {
-                                // can't write multiple blocks yet
-                                ReturnCode::ENOSUPPORT
-                            }
-                        })
-                })
-            } else {
-                // sd card not initialized
-                ReturnCode::ERESERVE
+    /// `read_blocks()` for the exclusive client while it holds the lock. Fails
+    /// with EBUSY if the card is not locked.
+    pub fn exclusive_read_blocks(
+        &self,
+        buffer: &'static mut [u8],
+        sector: u32,
+        count: u32,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if !self.locked.get() {
+            return (ReturnCode::EBUSY, Some(buffer));
+        }
+        let result = self.start_read_blocks(buffer, sector, count);
+        self.started_blocks(result, true)
+    }
+
+    /// `write_blocks()` for the exclusive client while it holds the lock. Fails
+    /// with EBUSY if the card is not locked.
+    pub fn exclusive_write_blocks(
+        &self,
+        buffer: &'static mut [u8],
+        sector: u32,
+        count: u32,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if !self.locked.get() {
+            return (ReturnCode::EBUSY, Some(buffer));
+        }
+        let result = self.start_write_blocks(buffer, sector, count);
+        self.started_blocks(result, true)
+    }
+
+    /// Take the SPI buffers to start a read or write, or return the
+    /// error that prevents it from starting
+    fn take_spi_buffers(&self) -> Result<(&'static mut [u8], &'static mut [u8]), ReturnCode> {
+        // only if initialized and installed
+        if !self.is_installed() {
+            // sd card not installed
+            return Err(ReturnCode::EUNINSTALLED);
+        }
+        if !self.is_initialized() {
+            // sd card not initialized
+            return Err(ReturnCode::ERESERVE);
+        }
+        match (self.txbuffer.take(), self.rxbuffer.take()) {
+            (Some(txbuffer), Some(rxbuffer)) => Ok((txbuffer, rxbuffer)),
+            (txbuffer, rxbuffer) => {
+                txbuffer.map(|buf| self.txbuffer.replace(buf));
+                rxbuffer.map(|buf| self.rxbuffer.replace(buf));
+                Err(ReturnCode::ENOMEM)
             }
+        }
+    }
+
+    /// Convert a block address to a byte address for non-block access cards
+    fn card_address(&self, sector: u32) -> u32 {
+        if self.card_type.get() != SDCardType::SDv2BlockAddressable {
+            sector * 512
         } else {
-            // sd card not installed
-            ReturnCode::EUNINSTALLED
+            sector
+        }
+    }
+
+    fn start_read_blocks(
+        &self,
+        buffer: &'static mut [u8],
+        sector: u32,
+        count: u32,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        let (txbuffer, rxbuffer) = match self.take_spi_buffers() {
+            Ok(buffers) => buffers,
+            Err(error) => return (error, Some(buffer)),
+        };
+
+        // save the user buffer for later
+        self.client_buffer.replace(buffer);
+        self.client_offset.set(0);
+
+        let address = self.card_address(sector);
+        self.state.set(SpiState::StartReadBlocks { count: count });
+        if count == 1 {
+            self.send_command(SDCmd::CMD17_ReadSingle, address, txbuffer, rxbuffer, 10);
+        } else {
+            self.send_command(SDCmd::CMD18_ReadMultiple, address, txbuffer, rxbuffer, 10);
+        }
+
+        // command started successfully
+        (ReturnCode::SUCCESS, None)
+    }
+
+    fn start_write_blocks(
+        &self,
+        buffer: &'static mut [u8],
+        sector: u32,
+        count: u32,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if count != 1 {
+            // can't write multiple blocks yet
+            return (ReturnCode::ENOSUPPORT, Some(buffer));
         }
+        let (txbuffer, rxbuffer) = match self.take_spi_buffers() {
+            Ok(buffers) => buffers,
+            Err(error) => return (error, Some(buffer)),
+        };
+
+        // save the user buffer for later
+        self.client_buffer.replace(buffer);
+        self.client_offset.set(0);
+
+        let address = self.card_address(sector);
+        self.state.set(SpiState::StartWriteBlocks { count: count });
+        self.send_command(SDCmd::CMD24_WriteSingle, address, txbuffer, rxbuffer, 10);
+
+        // command started successfully
+        (ReturnCode::SUCCESS, None)
     }
 }
 
@@ -1355,7 +1502,7 @@ impl<'a, A: hil::time::Alarm + 'a> hil::gpio::Client for SDCard<'a, A> {
             //  send an error callback
             self.state.set(SpiState::Idle);
             self.alarm_state.set(AlarmState::Idle);
-            self.client.get().map(move |client| {
+            self.operation_client().map(move |client| {
                 client.error(ErrorCode::CardStateChanged as u32);
             });
         }
@@ -1527,6 +1674,10 @@ impl<'a, A: hil::time::Alarm + 'a> Driver for SDCardDriver<'a, A> {
             // check if present
             0 => ReturnCode::SUCCESS,
 
+            // the card is reserved for another user, don't give up the
+            //  kernel buffer
+            2...4 if self.sdcard.is_locked() => ReturnCode::EBUSY,
+
             // is_installed
             1 => {
                 let value = self.sdcard.is_installed() as usize;
@@ -1540,7 +1691,9 @@ impl<'a, A: hil::time::Alarm + 'a> Driver for SDCardDriver<'a, A> {
             3 => self.kernel_buf
                 .take()
                 .map_or(ReturnCode::EBUSY, |kernel_buf| {
-                    self.sdcard.read_blocks(kernel_buf, data as u32, 1)
+                    let (result, kernel_buf) = self.sdcard.read_blocks(kernel_buf, data as u32, 1);
+                    kernel_buf.map(|buf| self.kernel_buf.replace(buf));
+                    result
                 }),
 
             // write_block
@@ -1562,7 +1715,10 @@ impl<'a, A: hil::time::Alarm + 'a> Driver for SDCardDriver<'a, A> {
                                     }
 
                                     // begin writing
-                                    self.sdcard.write_blocks(kernel_buf, data as u32, 1)
+                                    let (result, kernel_buf) =
+                                        self.sdcard.write_blocks(kernel_buf, data as u32, 1);
+                                    kernel_buf.map(|buf| self.kernel_buf.replace(buf));
+                                    result
                                 })
                         })
                 })
@@ -0,0 +1,1015 @@
+//! Simulated IEEE 802.15.4 radios sharing an in-memory medium.
+//!
+//! `SimRadio` implements `hil::radio::Radio` for a simulated node, and
+//! `SimMedium` carries the frames transmitted by each node to the others. With
+//! them, complete network stacks (Mac layers, `Framer`, `Sixlowpan` and the
+//! protocols above) can be instantiated several times in one program and
+//! exchange frames as if they were separate devices.
+//!
+//! The simulation runs on virtual time: `SimClock` holds the time of the
+//! simulation, and `SimAlarm`s, which implement `hil::time::Alarm`, are fired
+//! by `SimClock::step` as it advances the time to the earliest of them.
+//! `SimRng` provides seeded random numbers, for example for the backoffs of
+//! the Mac layers. A simulation is driven by calling `step` until the
+//! scenario completes or no alarm is armed; see `capsules::test::sim_lowpan`
+//! for a complete example.
+//!
+//! The simulation does not run on a host. Like the rest of the capsules, it
+//! only builds for the targets of the boards, and the runners in
+//! `capsules::test` that use it are run on a board, such as imix with
+//! `boards/imix/src/sim_lowpan_test.rs`. It only saves the radio hardware and
+//! the physical testbed: a single board runs every node of a simulation.
+//!
+//! The medium models the following:
+//!
+//! * Links are directional and have their own loss probability, delay, RSSI
+//!   and LQI. A node can only hear the nodes that it has a link from. Links
+//!   are set with `set_link` and `connect`, or computed for every frame by a
+//!   `Topology` set with `set_topology`, which can script topologies that
+//!   change over time.
+//! * Frames occupy the medium for as long as they would take to send at 250
+//!   kbit/s. A node that hears two frames that overlap in time receives
+//!   neither of them.
+//! * Before transmitting, a node performs a clear channel assessment, and the
+//!   transmission completes with `ReturnCode::EBUSY` if it hears another
+//!   transmission. A node cannot receive while it transmits.
+//! * Nodes only hear the nodes on the same channel, and only while they are
+//!   on and have a receive buffer. A node that receives a frame keeps it until
+//!   the link delay has elapsed, and misses the frames that arrive meanwhile.
+//! * Frames that request an acknowledgement are acknowledged by the node that
+//!   their destination address matches, if it received them and the link back
+//!   to the sender does not lose the acknowledgement. Radios below a Mac layer
+//!   that sends its own acknowledgements, like TSCH, must disable this with
+//!   `SimRadio::set_auto_ack`.
+//!
+//! Received frames are reported with the RSSI and LQI of their link, and with
+//! the time at which their start-of-frame delimiter was received. Losses are
+//! drawn from a pseudo-random generator seeded with `set_seed`, so that a
+//! simulation is reproducible.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let clock = static_init!(
+//!     capsules::sim_radio::SimClock<'static>,
+//!     capsules::sim_radio::SimClock::new()
+//! );
+//! let medium_alarm = static_init!(
+//!     capsules::sim_radio::SimAlarm<'static>,
+//!     capsules::sim_radio::SimAlarm::new(clock)
+//! );
+//! clock.add_alarm(medium_alarm);
+//!
+//! let medium = static_init!(
+//!     capsules::sim_radio::SimMedium<'static, SimAlarm<'static>>,
+//!     capsules::sim_radio::SimMedium::new(medium_alarm)
+//! );
+//! medium_alarm.set_client(medium);
+//!
+//! let radio0 = static_init!(
+//!     capsules::sim_radio::SimRadio<'static, SimAlarm<'static>>,
+//!     capsules::sim_radio::SimRadio::new(medium)
+//! );
+//! medium.add_node(radio0);
+//! // ... and likewise for radio1
+//!
+//! medium.connect(
+//!     0,
+//!     1,
+//!     capsules::sim_radio::LinkParams {
+//!         loss_percent: 10,
+//!         delay_us: 0,
+//!         rssi: -70,
+//!         lqi: 200,
+//!     },
+//! );
+//!
+//! // ... the stacks of the nodes start their transmissions
+//!
+//! while clock.step() {}
+//! ```
+
+use core::cell::Cell;
+use core::iter;
+use kernel::ReturnCode;
+use kernel::common::{List, ListLink, ListNode};
+use kernel::common::take_cell::{MapCell, TakeCell};
+use kernel::hil::radio;
+use kernel::hil::rng;
+use kernel::hil::time::{self, Alarm, Frequency, Time};
+use net::ieee802154::{Header, MacAddress};
+
+/// The maximum number of nodes sharing a medium
+pub const MAX_SIM_NODES: usize = 16;
+
+/// The time needed to send one byte at 250 kbit/s, in microseconds
+const BYTE_DURATION_US: u32 = 32;
+
+/// The synchronization header (preamble and SFD) and the PHY header
+const SHR_SIZE: usize = 5;
+const PHR_SIZE: usize = 1;
+
+/// The duration of a clear channel assessment (8 symbols), in microseconds
+const CCA_DURATION_US: u32 = 128;
+
+/// The time a sender waits for an acknowledgement (macAckWaitDuration, 54
+/// symbols), in microseconds
+const ACK_WAIT_DURATION_US: u32 = 864;
+
+/// The quality of a link between two nodes
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct LinkParams {
+    /// The percentage of the frames sent over the link that are lost
+    pub loss_percent: u8,
+    /// The time between the end of a transmission and the reception of the
+    /// frame, in microseconds
+    pub delay_us: u32,
+    /// The RSSI reported for the frames received over the link, in dBm
+    pub rssi: i8,
+    /// The LQI reported for the frames received over the link
+    pub lqi: u8,
+}
+
+/// Computes the links between nodes, instead of the table of links of the
+/// medium
+pub trait Topology {
+    /// The link from node `src` to node `dst` when a frame is sent at `time`,
+    /// in tics of the medium's alarm, or `None` if `dst` cannot hear `src`.
+    fn link(&self, src: usize, dst: usize, time: u32) -> Option<LinkParams>;
+}
+
+/// Counters of what happened to the frames sent over a medium
+#[derive(Copy, Clone, Default, Eq, PartialEq, Debug)]
+pub struct SimStats {
+    /// Frames transmitted
+    pub transmitted: u32,
+    /// Transmissions that did not take place because the channel was busy
+    pub channel_busy: u32,
+    /// Frames received by a node
+    pub received: u32,
+    /// Frames that a node did not receive because they collided with another
+    pub collisions: u32,
+    /// Frames that a node did not receive because the link lost them
+    pub losses: u32,
+    /// Frames that a node did not receive because it was still holding a
+    /// previous frame or had no receive buffer
+    pub overruns: u32,
+}
+
+/// A frame on the medium
+#[derive(Copy, Clone)]
+struct Transmission {
+    channel: u8,
+    start: u32,
+    // The end of the frame, or of the failed clear channel assessment
+    end: u32,
+    // When the sender learns the outcome of the transmission
+    done: u32,
+    cca_failed: bool,
+    // Whether the frame has been delivered to its receivers, at its end
+    delivered: bool,
+    acked: bool,
+    // Bit masks of the nodes that hear the frame, and of those for which it
+    // collided with another
+    receivers: u32,
+    corrupted: u32,
+}
+
+pub struct SimMedium<'a, A: Alarm + 'a> {
+    alarm: &'a A,
+    nodes: List<'a, SimRadio<'a, A>>,
+    num_nodes: Cell<usize>,
+    links: MapCell<[[Option<LinkParams>; MAX_SIM_NODES]; MAX_SIM_NODES]>,
+    topology: Cell<Option<&'a Topology>>,
+    transmissions: MapCell<[Option<Transmission>; MAX_SIM_NODES]>,
+    random: Cell<u32>,
+    stats: Cell<SimStats>,
+}
+
+/// Whether `time` is at or before `now`
+fn is_due(time: u32, now: u32) -> bool {
+    now.wrapping_sub(time) as i32 >= 0
+}
+
+/// The next state of a xorshift pseudo-random generator
+fn xorshift(mut x: u32) -> u32 {
+    x ^= x << 13;
+    x ^= x >> 17;
+    x ^= x << 5;
+    x
+}
+
+impl<'a, A: Alarm + 'a> SimMedium<'a, A> {
+    pub fn new(alarm: &'a A) -> SimMedium<'a, A> {
+        SimMedium {
+            alarm: alarm,
+            nodes: List::new(),
+            num_nodes: Cell::new(0),
+            links: MapCell::new([[None; MAX_SIM_NODES]; MAX_SIM_NODES]),
+            topology: Cell::new(None),
+            transmissions: MapCell::new([None; MAX_SIM_NODES]),
+            random: Cell::new(1),
+            stats: Cell::new(SimStats::default()),
+        }
+    }
+
+    /// Attaches a node to the medium. Nodes are numbered from 0 in the order
+    /// in which they are added. Returns ENOMEM if the medium is full.
+    pub fn add_node(&self, node: &'a SimRadio<'a, A>) -> ReturnCode {
+        let id = self.num_nodes.get();
+        if id == MAX_SIM_NODES {
+            return ReturnCode::ENOMEM;
+        }
+        node.id.set(id);
+        self.nodes.push_tail(node);
+        self.num_nodes.set(id + 1);
+        ReturnCode::SUCCESS
+    }
+
+    /// Sets the link from node `src` to node `dst`, or removes it if `None`
+    pub fn set_link(&self, src: usize, dst: usize, link: Option<LinkParams>) {
+        if src < MAX_SIM_NODES && dst < MAX_SIM_NODES && src != dst {
+            self.links.map(|links| links[src][dst] = link);
+        }
+    }
+
+    /// Sets the links in both directions between nodes `a` and `b`
+    pub fn connect(&self, a: usize, b: usize, link: LinkParams) {
+        self.set_link(a, b, Some(link));
+        self.set_link(b, a, Some(link));
+    }
+
+    /// Computes links with `topology` instead of the table of links
+    pub fn set_topology(&self, topology: &'a Topology) {
+        self.topology.set(Some(topology));
+    }
+
+    /// Seeds the generator that draws the frames lost by links
+    pub fn set_seed(&self, seed: u32) {
+        // The generator must not be seeded with 0
+        self.random.set(if seed == 0 { 1 } else { seed });
+    }
+
+    pub fn get_stats(&self) -> SimStats {
+        self.stats.get()
+    }
+
+    pub fn reset_stats(&self) {
+        self.stats.set(SimStats::default());
+    }
+
+    fn update_stats<F: FnOnce(&mut SimStats)>(&self, f: F) {
+        let mut stats = self.stats.get();
+        f(&mut stats);
+        self.stats.set(stats);
+    }
+
+    fn link(&self, src: usize, dst: usize, time: u32) -> Option<LinkParams> {
+        if src == dst {
+            return None;
+        }
+        match self.topology.get() {
+            Some(topology) => topology.link(src, dst, time),
+            None => self.links.map_or(None, |links| links[src][dst]),
+        }
+    }
+
+    fn node(&self, id: usize) -> Option<&'a SimRadio<'a, A>> {
+        self.nodes.iter().nth(id)
+    }
+
+    /// Draws whether a frame sent over `link` is lost
+    fn lost(&self, link: &LinkParams) -> bool {
+        let x = xorshift(self.random.get());
+        self.random.set(x);
+        x % 100 < link.loss_percent as u32
+    }
+
+    fn us_to_tics(us: u32) -> u32 {
+        (us as u64 * <A::Frequency>::frequency() as u64 / 1_000_000) as u32
+    }
+
+    /// The nodes that hear a frame sent by `src` on `channel` at `time`
+    fn audible(&self, src: usize, channel: u8, time: u32) -> u32 {
+        let mut receivers = 0;
+        for node in self.nodes.iter() {
+            let id = node.id.get();
+            if node.on.get() && node.channel.get() == channel && self.link(src, id, time).is_some()
+            {
+                receivers |= 1 << id;
+            }
+        }
+        receivers
+    }
+
+    /// Puts the frame of `node` on the medium, or fails the clear channel
+    /// assessment if the node hears a frame on its channel
+    fn transmit(&self, node: &SimRadio<'a, A>, frame_len: usize) {
+        let src = node.id.get();
+        let channel = node.channel.get();
+        let now = self.alarm.now();
+        let busy = self.transmissions.map_or(false, |transmissions| {
+            transmissions
+                .iter()
+                .enumerate()
+                .any(|(other, transmission)| match *transmission {
+                    Some(ref t) => {
+                        !t.cca_failed
+                            && t.channel == channel
+                            && self.link(other, src, t.start).is_some()
+                    }
+                    None => false,
+                })
+        });
+
+        let transmission = if busy {
+            self.update_stats(|stats| stats.channel_busy += 1);
+            let end = now.wrapping_add(Self::us_to_tics(CCA_DURATION_US));
+            Transmission {
+                channel: channel,
+                start: now,
+                end: end,
+                done: end,
+                cca_failed: true,
+                delivered: true,
+                acked: false,
+                receivers: 0,
+                corrupted: 0,
+            }
+        } else {
+            self.update_stats(|stats| stats.transmitted += 1);
+            let bytes = SHR_SIZE + PHR_SIZE + frame_len + radio::MFR_SIZE;
+            let end = now.wrapping_add(Self::us_to_tics(bytes as u32 * BYTE_DURATION_US));
+            let ack_requested = node.tx_buf
+                .map_or(None, |buf| {
+                    Header::decode(&buf[radio::PSDU_OFFSET..], false)
+                        .done()
+                        .map(|(_, (header, _))| header.ack_requested)
+                })
+                .unwrap_or(false);
+            let done = if ack_requested {
+                end.wrapping_add(Self::us_to_tics(ACK_WAIT_DURATION_US))
+            } else {
+                end
+            };
+            // Nodes that are themselves transmitting cannot hear the frame
+            let mut receivers = self.audible(src, channel, now);
+            let mut corrupted = 0;
+            self.transmissions.map(|transmissions| {
+                for (id, transmission) in transmissions.iter_mut().enumerate() {
+                    if let Some(ref mut t) = *transmission {
+                        if t.cca_failed {
+                            continue;
+                        }
+                        receivers &= !(1 << id);
+                        if t.channel == channel {
+                            let both = t.receivers & receivers;
+                            t.corrupted |= both;
+                            corrupted |= both;
+                        }
+                    }
+                }
+            });
+            Transmission {
+                channel: channel,
+                start: now,
+                end: end,
+                done: done,
+                cca_failed: false,
+                delivered: false,
+                acked: false,
+                receivers: receivers,
+                corrupted: corrupted,
+            }
+        };
+        self.transmissions
+            .map(|transmissions| transmissions[src] = Some(transmission));
+        self.schedule();
+    }
+
+    /// Delivers the frame sent by `src` to the nodes that heard it without a
+    /// collision, and finds out whether it was acknowledged
+    fn deliver(&self, src: usize, t: Transmission) {
+        let sender = match self.node(src) {
+            Some(sender) => sender,
+            None => return,
+        };
+        let frame_len = sender.tx_len.get();
+        let sfd_time = t.start
+            .wrapping_add(Self::us_to_tics(SHR_SIZE as u32 * BYTE_DURATION_US));
+        let mut acked = false;
+        for node in self.nodes.iter() {
+            let id = node.id.get();
+            if t.receivers & (1 << id) == 0 {
+                continue;
+            }
+            let link = match self.link(src, id, t.start) {
+                Some(link) => link,
+                None => continue,
+            };
+            // The receiver may have been turned off or retuned since
+            if !node.on.get() || node.channel.get() != t.channel {
+                continue;
+            }
+            if t.corrupted & (1 << id) != 0 {
+                self.update_stats(|stats| stats.collisions += 1);
+                continue;
+            }
+            if self.lost(&link) {
+                self.update_stats(|stats| stats.losses += 1);
+                continue;
+            }
+            let received = sender.tx_buf.map_or(None, |frame| {
+                node.receive_frame(
+                    &frame[..radio::PSDU_OFFSET + frame_len],
+                    frame_len,
+                    radio::RxInfo {
+                        rssi: link.rssi,
+                        lqi: link.lqi,
+                        timestamp: Some(sfd_time),
+                    },
+                    t.end.wrapping_add(Self::us_to_tics(link.delay_us)),
+                )
+            });
+            match received {
+                Some(header_addressed) => {
+                    self.update_stats(|stats| stats.received += 1);
+                    if header_addressed {
+                        acked = self.link(id, src, t.end)
+                            .map_or(false, |link| !self.lost(&link));
+                    }
+                }
+                None => self.update_stats(|stats| stats.overruns += 1),
+            }
+        }
+        self.transmissions.map(|transmissions| {
+            transmissions[src].as_mut().map(|t| {
+                t.delivered = true;
+                t.acked = acked;
+            });
+        });
+    }
+
+    /// Handles the next event that is due, if any. Returns whether there was
+    /// one.
+    fn next_event(&self) -> bool {
+        let now = self.alarm.now();
+
+        // Frames that reach their end are delivered
+        let ended = self.transmissions.map_or(None, |transmissions| {
+            transmissions
+                .iter()
+                .enumerate()
+                .filter_map(|(src, t)| t.map(|t| (src, t)))
+                .find(|&(_, t)| !t.delivered && is_due(t.end, now))
+        });
+        if let Some((src, t)) = ended {
+            self.deliver(src, t);
+            return true;
+        }
+
+        // Senders learn the outcome of their transmissions
+        let done = self.transmissions.map_or(None, |transmissions| {
+            let src = transmissions.iter().position(|t| match *t {
+                Some(ref t) => t.delivered && is_due(t.done, now),
+                None => false,
+            });
+            src.and_then(|src| transmissions[src].take().map(|t| (src, t)))
+        });
+        if let Some((src, t)) = done {
+            self.node(src).map(|node| {
+                let result = if t.cca_failed {
+                    ReturnCode::EBUSY
+                } else {
+                    ReturnCode::SUCCESS
+                };
+                node.transmit_done(t.acked, result)
+            });
+            return true;
+        }
+
+        // Receivers deliver the frames that they hold once the link delay has
+        // elapsed, and nodes report configuration and power changes
+        for node in self.nodes.iter() {
+            if node.process_event(now) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Sets the alarm for the next event
+    fn schedule(&self) {
+        let now = self.alarm.now();
+        let mut next: Option<u32> = None;
+        {
+            let mut consider = |time: u32| {
+                // Events that are already due are handled right away
+                let time = if is_due(time, now) {
+                    now.wrapping_add(1)
+                } else {
+                    time
+                };
+                next = Some(match next {
+                    Some(next) if next.wrapping_sub(now) <= time.wrapping_sub(now) => next,
+                    _ => time,
+                });
+            };
+            self.transmissions.map(|transmissions| {
+                for t in transmissions.iter().filter_map(|t| t.as_ref()) {
+                    consider(if t.delivered { t.done } else { t.end });
+                }
+            });
+            for node in self.nodes.iter() {
+                node.next_event().map(|time| consider(time));
+            }
+        }
+        match next {
+            Some(time) => self.alarm.set_alarm(time),
+            None => self.alarm.disable(),
+        }
+    }
+}
+
+impl<'a, A: Alarm + 'a> time::Client for SimMedium<'a, A> {
+    fn fired(&self) {
+        while self.next_event() {}
+        self.schedule();
+    }
+}
+
+pub struct SimRadio<'a, A: Alarm + 'a> {
+    medium: &'a SimMedium<'a, A>,
+    id: Cell<usize>,
+    next: ListLink<'a, SimRadio<'a, A>>,
+
+    on: Cell<bool>,
+    addr: Cell<u16>,
+    addr_long: Cell<[u8; 8]>,
+    pan: Cell<u16>,
+    channel: Cell<u8>,
+    tx_power: Cell<i8>,
+    ack_frame_pending: Cell<bool>,
+    auto_ack: Cell<bool>,
+
+    tx_client: Cell<Option<&'static radio::TxClient>>,
+    rx_client: Cell<Option<&'static radio::RxClient>>,
+    config_client: Cell<Option<&'static radio::ConfigClient>>,
+    power_client: Cell<Option<&'static radio::PowerClient>>,
+
+    tx_buf: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+
+    rx_buf: TakeCell<'static, [u8]>,
+    // A received frame held until `rx_time`, its length and metadata
+    rx_pending: Cell<bool>,
+    rx_time: Cell<u32>,
+    rx_len: Cell<usize>,
+    rx_info: Cell<radio::RxInfo>,
+
+    // Callbacks to make from the medium's alarm rather than from the calls
+    // that trigger them
+    config_pending: Cell<bool>,
+    power_pending: Cell<bool>,
+}
+
+impl<'a, A: Alarm + 'a> ListNode<'a, SimRadio<'a, A>> for SimRadio<'a, A> {
+    fn next(&'a self) -> &'a ListLink<'a, SimRadio<'a, A>> {
+        &self.next
+    }
+}
+
+impl<'a, A: Alarm + 'a> SimRadio<'a, A> {
+    pub fn new(medium: &'a SimMedium<'a, A>) -> SimRadio<'a, A> {
+        SimRadio {
+            medium: medium,
+            id: Cell::new(0),
+            next: ListLink::empty(),
+            on: Cell::new(false),
+            addr: Cell::new(0),
+            addr_long: Cell::new([0; 8]),
+            pan: Cell::new(0),
+            channel: Cell::new(26),
+            tx_power: Cell::new(0),
+            ack_frame_pending: Cell::new(false),
+            auto_ack: Cell::new(true),
+            tx_client: Cell::new(None),
+            rx_client: Cell::new(None),
+            config_client: Cell::new(None),
+            power_client: Cell::new(None),
+            tx_buf: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            rx_buf: TakeCell::empty(),
+            rx_pending: Cell::new(false),
+            rx_time: Cell::new(0),
+            rx_len: Cell::new(0),
+            rx_info: Cell::new(radio::RxInfo::default()),
+            config_pending: Cell::new(false),
+            power_pending: Cell::new(false),
+        }
+    }
+
+    /// The number of the node on its medium
+    pub fn get_id(&self) -> usize {
+        self.id.get()
+    }
+
+    /// Sets whether the radio acknowledges the frames addressed to it, which
+    /// it does by default
+    pub fn set_auto_ack(&self, enabled: bool) {
+        self.auto_ack.set(enabled);
+    }
+
+    /// Copies a frame heard on the medium into the receive buffer, to be
+    /// delivered at `time`. Returns `None` if the node cannot take it, and
+    /// otherwise whether the node acknowledges the frame.
+    fn receive_frame(
+        &self,
+        frame: &[u8],
+        frame_len: usize,
+        info: radio::RxInfo,
+        time: u32,
+    ) -> Option<bool> {
+        if self.rx_pending.get() {
+            return None;
+        }
+        self.rx_buf.map_or(None, |buf| {
+            buf[..frame.len()].copy_from_slice(frame);
+            self.rx_pending.set(true);
+            self.rx_time.set(time);
+            self.rx_len.set(frame_len);
+            self.rx_info.set(info);
+            let header = Header::decode(&frame[radio::PSDU_OFFSET..], false)
+                .done()
+                .map(|(_, (header, _))| header);
+            Some(header.map_or(false, |header| {
+                self.auto_ack.get() && header.ack_requested
+                    && match header.dst_addr {
+                        Some(MacAddress::Short(addr)) => addr == self.addr.get(),
+                        Some(MacAddress::Long(addr)) => addr == self.addr_long.get(),
+                        None => false,
+                    }
+            }))
+        })
+    }
+
+    fn transmit_done(&self, acked: bool, result: ReturnCode) {
+        self.tx_buf.take().map(|buf| {
+            self.tx_client.get().map(move |c| c.send_done(buf, acked, result));
+        });
+    }
+
+    /// The time of the next event of the node, if any
+    fn next_event(&self) -> Option<u32> {
+        if self.config_pending.get() || self.power_pending.get() {
+            Some(self.medium.alarm.now())
+        } else if self.rx_pending.get() {
+            Some(self.rx_time.get())
+        } else {
+            None
+        }
+    }
+
+    /// Handles the next event of the node if it is due. Returns whether there
+    /// was one.
+    fn process_event(&self, now: u32) -> bool {
+        if self.config_pending.get() {
+            self.config_pending.set(false);
+            self.config_client
+                .get()
+                .map(|c| c.config_done(ReturnCode::SUCCESS));
+            true
+        } else if self.power_pending.get() {
+            self.power_pending.set(false);
+            let on = self.on.get();
+            self.power_client.get().map(|c| c.changed(on));
+            true
+        } else if self.rx_pending.get() && is_due(self.rx_time.get(), now) {
+            self.rx_pending.set(false);
+            self.rx_buf.take().map(|buf| {
+                let frame_len = self.rx_len.get();
+                let info = self.rx_info.get();
+                self.rx_client.get().map(move |c| {
+                    c.receive(buf, frame_len, true, info, ReturnCode::SUCCESS)
+                });
+            });
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Makes the callbacks of a configuration or power change from the
+    /// medium's alarm
+    fn defer_callback(&self, pending: &Cell<bool>) {
+        pending.set(true);
+        self.medium.schedule();
+    }
+}
+
+impl<'a, A: Alarm + 'a> radio::Radio for SimRadio<'a, A> {}
+
+impl<'a, A: Alarm + 'a> radio::RadioConfig for SimRadio<'a, A> {
+    fn initialize(
+        &self,
+        _spi_buf: &'static mut [u8],
+        _reg_write: &'static mut [u8],
+        _reg_read: &'static mut [u8],
+    ) -> ReturnCode {
+        // There are no registers to access
+        ReturnCode::SUCCESS
+    }
+
+    fn reset(&self) -> ReturnCode {
+        ReturnCode::SUCCESS
+    }
+
+    fn start(&self) -> ReturnCode {
+        self.on.set(true);
+        self.defer_callback(&self.power_pending);
+        ReturnCode::SUCCESS
+    }
+
+    fn stop(&self) -> ReturnCode {
+        self.on.set(false);
+        self.defer_callback(&self.power_pending);
+        ReturnCode::SUCCESS
+    }
+
+    fn is_on(&self) -> bool {
+        self.on.get()
+    }
+
+    fn busy(&self) -> bool {
+        self.tx_buf.is_some()
+    }
+
+    fn set_power_client(&self, client: &'static radio::PowerClient) {
+        self.power_client.set(Some(client));
+    }
+
+    fn config_commit(&self) {
+        self.defer_callback(&self.config_pending);
+    }
+
+    fn set_config_client(&self, client: &'static radio::ConfigClient) {
+        self.config_client.set(Some(client));
+    }
+
+    fn get_address(&self) -> u16 {
+        self.addr.get()
+    }
+
+    fn get_address_long(&self) -> [u8; 8] {
+        self.addr_long.get()
+    }
+
+    fn get_pan(&self) -> u16 {
+        self.pan.get()
+    }
+
+    fn get_tx_power(&self) -> i8 {
+        self.tx_power.get()
+    }
+
+    fn get_channel(&self) -> u8 {
+        self.channel.get()
+    }
+
+    fn set_address(&self, addr: u16) {
+        self.addr.set(addr);
+    }
+
+    fn set_address_long(&self, addr: [u8; 8]) {
+        self.addr_long.set(addr);
+    }
+
+    fn set_pan(&self, id: u16) {
+        self.pan.set(id);
+    }
+
+    fn set_tx_power(&self, power: i8) -> ReturnCode {
+        self.tx_power.set(power);
+        ReturnCode::SUCCESS
+    }
+
+    fn set_channel(&self, chan: u8) -> ReturnCode {
+        if chan < 11 || chan > 26 {
+            return ReturnCode::EINVAL;
+        }
+        self.channel.set(chan);
+        ReturnCode::SUCCESS
+    }
+
+    fn set_ack_frame_pending(&self, pending: bool) {
+        self.ack_frame_pending.set(pending);
+    }
+}
+
+impl<'a, A: Alarm + 'a> radio::RadioData for SimRadio<'a, A> {
+    fn set_transmit_client(&self, client: &'static radio::TxClient) {
+        self.tx_client.set(Some(client));
+    }
+
+    fn set_receive_client(
+        &self,
+        client: &'static radio::RxClient,
+        receive_buffer: &'static mut [u8],
+    ) {
+        self.rx_client.set(Some(client));
+        self.rx_buf.replace(receive_buffer);
+    }
+
+    fn set_receive_buffer(&self, receive_buffer: &'static mut [u8]) {
+        self.rx_buf.replace(receive_buffer);
+    }
+
+    fn transmit(
+        &self,
+        spi_buf: &'static mut [u8],
+        frame_len: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if !self.on.get() {
+            return (ReturnCode::EOFF, Some(spi_buf));
+        } else if self.tx_buf.is_some() {
+            return (ReturnCode::EBUSY, Some(spi_buf));
+        } else if radio::PSDU_OFFSET + frame_len + radio::MFR_SIZE > spi_buf.len()
+            || frame_len + radio::MFR_SIZE > radio::MAX_FRAME_SIZE
+        {
+            return (ReturnCode::ESIZE, Some(spi_buf));
+        }
+        self.tx_buf.replace(spi_buf);
+        self.tx_len.set(frame_len);
+        self.medium.transmit(self, frame_len);
+        (ReturnCode::SUCCESS, None)
+    }
+}
+
+/// The virtual time of a simulation, shared by its `SimAlarm`s
+///
+/// Time only advances when `step` is called, which jumps straight to the
+/// earliest armed alarm and fires it. A simulation therefore runs as fast as
+/// the events that it contains can be processed, and stops when no alarm is
+/// armed.
+pub struct SimClock<'a> {
+    now: Cell<u32>,
+    alarms: List<'a, SimAlarm<'a>>,
+}
+
+impl<'a> SimClock<'a> {
+    pub fn new() -> SimClock<'a> {
+        SimClock {
+            now: Cell::new(0),
+            alarms: List::new(),
+        }
+    }
+
+    /// Registers an alarm, which is only fired by `step` once registered
+    pub fn add_alarm(&self, alarm: &'a SimAlarm<'a>) {
+        self.alarms.push_tail(alarm);
+    }
+
+    /// The current time, in tics of `SimAlarm`
+    pub fn now(&self) -> u32 {
+        self.now.get()
+    }
+
+    /// Advances the time to the earliest armed alarm, or not at all if it is
+    /// already due, and fires it. Alarms due at the same time fire in the
+    /// order in which they were registered. Returns false if no alarm is
+    /// armed.
+    pub fn step(&self) -> bool {
+        let now = self.now.get();
+        let next = self.alarms
+            .iter()
+            .filter(|alarm| alarm.armed.get())
+            .min_by_key(|alarm| {
+                let time = alarm.alarm.get();
+                if is_due(time, now) {
+                    0
+                } else {
+                    time.wrapping_sub(now)
+                }
+            });
+        match next {
+            Some(alarm) => {
+                if !is_due(alarm.alarm.get(), now) {
+                    self.now.set(alarm.alarm.get());
+                }
+                alarm.armed.set(false);
+                alarm.client.get().map(|c| c.fired());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// An alarm that runs on the virtual time of a `SimClock`
+pub struct SimAlarm<'a> {
+    clock: &'a SimClock<'a>,
+    next: ListLink<'a, SimAlarm<'a>>,
+    armed: Cell<bool>,
+    alarm: Cell<u32>,
+    client: Cell<Option<&'a time::Client>>,
+}
+
+impl<'a> ListNode<'a, SimAlarm<'a>> for SimAlarm<'a> {
+    fn next(&'a self) -> &'a ListLink<'a, SimAlarm<'a>> {
+        &self.next
+    }
+}
+
+impl<'a> SimAlarm<'a> {
+    pub fn new(clock: &'a SimClock<'a>) -> SimAlarm<'a> {
+        SimAlarm {
+            clock: clock,
+            next: ListLink::empty(),
+            armed: Cell::new(false),
+            alarm: Cell::new(0),
+            client: Cell::new(None),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a time::Client) {
+        self.client.set(Some(client));
+    }
+}
+
+impl<'a> Time for SimAlarm<'a> {
+    type Frequency = time::Freq32KHz;
+
+    fn disable(&self) {
+        self.armed.set(false);
+    }
+
+    fn is_armed(&self) -> bool {
+        self.armed.get()
+    }
+}
+
+impl<'a> Alarm for SimAlarm<'a> {
+    fn now(&self) -> u32 {
+        self.clock.now()
+    }
+
+    fn set_alarm(&self, tics: u32) {
+        self.alarm.set(tics);
+        self.armed.set(true);
+    }
+
+    fn get_alarm(&self) -> u32 {
+        self.alarm.get()
+    }
+}
+
+/// A seeded pseudo-random generator for simulated nodes, such as the
+/// backoffs of a Mac layer. Like a hardware generator, it provides its
+/// numbers asynchronously, from its own `SimAlarm`, which must have it as
+/// client.
+pub struct SimRng<'a> {
+    alarm: &'a SimAlarm<'a>,
+    random: Cell<u32>,
+    client: Cell<Option<&'a rng::Client>>,
+}
+
+impl<'a> SimRng<'a> {
+    pub fn new(alarm: &'a SimAlarm<'a>, seed: u32) -> SimRng<'a> {
+        SimRng {
+            alarm: alarm,
+            // The generator must not be seeded with 0
+            random: Cell::new(if seed == 0 { 1 } else { seed }),
+            client: Cell::new(None),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a rng::Client) {
+        self.client.set(Some(client));
+    }
+
+    fn next_random(&self) -> u32 {
+        let x = xorshift(self.random.get());
+        self.random.set(x);
+        x
+    }
+}
+
+impl<'a> rng::RNG for SimRng<'a> {
+    fn get(&self) {
+        if !self.alarm.is_armed() {
+            self.alarm.set_alarm(self.alarm.now());
+        }
+    }
+}
+
+impl<'a> time::Client for SimRng<'a> {
+    fn fired(&self) {
+        self.client.get().map(|client| {
+            let mut randomness = iter::repeat(()).map(|_| self.next_random());
+            if client.randomness_available(&mut randomness) == rng::Continue::More {
+                self.alarm.set_alarm(self.alarm.now());
+            }
+        });
+    }
+}
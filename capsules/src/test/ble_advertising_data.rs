@@ -0,0 +1,131 @@
+//! Test the decoding and building of BLE advertising data in
+//! `ble_advertising_data`.
+//!
+//! The tests check that:
+//!
+//! - `AdStructures` returns each AD structure along with its offset, and
+//!   stops at a length of zero or at an AD structure that does not fit.
+//! - `validate` returns the length of the significant part of the data, and
+//!   accepts zero padding beyond 31 bytes, but not AD structures or other
+//!   bytes there, nor AD structures that do not fit.
+//! - `AdvertisingData::set` appends an AD structure, or replaces the one of
+//!   the same AD type in place without moving the others out of order, and
+//!   leaves the data unchanged when it would no longer fit.
+//! - `AdvertisingData::remove` removes an AD structure and keeps the others.
+//!
+//! The tests do not need any hardware, and can be run from a board's
+//! `reset_handler` with `capsules::test::ble_advertising_data::run()`.
+
+use ble_advertising_data::{ad_type, flags, validate, AdStructures, AdvertisingData};
+use kernel::ReturnCode;
+
+/// Flags, a complete local name and a TX power level
+const DATA: [u8; 12] = [
+    0x02, 0x01, 0x06, 0x05, 0x09, 0x54, 0x6f, 0x63, 0x6b, 0x02, 0x0a, 0x00,
+];
+
+const NAME: [u8; 4] = [0x54, 0x6f, 0x63, 0x6b];
+
+pub fn run() {
+    debug!("BLE advertising data tests");
+    let tests: [(&'static str, fn() -> bool); 5] = [
+        ("AD structures", test_ad_structures),
+        ("truncated AD structures", test_truncated_ad_structures),
+        ("validate", test_validate),
+        ("set", test_set),
+        ("remove", test_remove),
+    ];
+    let mut passed = 0;
+    for &(name, test) in tests.iter() {
+        if test() {
+            passed += 1;
+        } else {
+            debug!("Test failed: {}", name);
+        }
+    }
+    debug!("{} of {} tests passed", passed, tests.len());
+}
+
+fn test_ad_structures() -> bool {
+    // The offset, AD type and AD data of each AD structure of `DATA`
+    let expected: [(usize, u8, &[u8]); 3] = [
+        (0, ad_type::FLAGS, &[0x06]),
+        (3, ad_type::COMPLETE_LOCAL_NAME, &NAME),
+        (9, ad_type::TX_POWER_LEVEL, &[0x00]),
+    ];
+    AdStructures::new(&DATA).count() == expected.len()
+        && AdStructures::new(&DATA).zip(expected.iter()).all(
+            |((offset, structure), &(expected_offset, ad_type, data))| {
+                offset == expected_offset && structure.ad_type == ad_type && structure.data == data
+            },
+        )
+}
+
+fn test_truncated_ad_structures() -> bool {
+    // A length of zero ends the significant part
+    let mut padded = [0; 8];
+    padded[..3].copy_from_slice(&DATA[..3]);
+    padded[4] = 0x02;
+    let padded_count = AdStructures::new(&padded).count();
+
+    // The local name does not fit
+    let truncated_count = AdStructures::new(&DATA[..8]).count();
+    padded_count == 1 && truncated_count == 1
+}
+
+fn test_validate() -> bool {
+    let mut buf = [0; 40];
+    buf[..DATA.len()].copy_from_slice(&DATA);
+    let padded = validate(&buf) == Ok(DATA.len());
+
+    // Only zero padding may follow the first 31 bytes
+    buf[35] = 0x01;
+    let not_padding = validate(&buf) == Err(ReturnCode::ESIZE);
+    buf[35] = 0x00;
+
+    // 12 + 22 bytes of AD structures
+    buf[DATA.len()] = 21;
+    buf[DATA.len() + 1] = ad_type::MANUFACTURER_SPECIFIC_DATA;
+    let too_long = validate(&buf) == Err(ReturnCode::ESIZE);
+
+    let truncated = validate(&DATA[..8]) == Err(ReturnCode::EINVAL);
+    padded && not_padding && too_long && truncated && validate(&[]) == Ok(0)
+}
+
+fn test_set() -> bool {
+    let mut data = AdvertisingData::new();
+    let appended = data.set(ad_type::FLAGS, &[flags::LE_GENERAL_DISCOVERABLE])
+        == ReturnCode::SUCCESS
+        && data.set(ad_type::COMPLETE_LOCAL_NAME, &NAME[..2]) == ReturnCode::SUCCESS
+        && data.set(ad_type::TX_POWER_LEVEL, &[0x00]) == ReturnCode::SUCCESS;
+
+    // The flags and the local name are replaced in place
+    let replaced = data.set(
+        ad_type::FLAGS,
+        &[flags::LE_GENERAL_DISCOVERABLE | flags::BR_EDR_NOT_SUPPORTED],
+    ) == ReturnCode::SUCCESS
+        && data.set(ad_type::COMPLETE_LOCAL_NAME, &NAME) == ReturnCode::SUCCESS
+        && data.as_slice() == &DATA[..];
+
+    // 12 + 2 + 18 bytes do not fit, and 12 + 2 + 17 bytes do
+    let too_long = data.set(ad_type::MANUFACTURER_SPECIFIC_DATA, &[0xaa; 18]) == ReturnCode::ESIZE
+        && data.as_slice() == &DATA[..];
+    let full = data.set(ad_type::MANUFACTURER_SPECIFIC_DATA, &[0xaa; 17]) == ReturnCode::SUCCESS
+        && data.len() == 31
+        && data.get(ad_type::MANUFACTURER_SPECIFIC_DATA) == Some(&[0xaa; 17][..]);
+
+    let reserved = data.set(0, &[]) == ReturnCode::EINVAL;
+    appended && replaced && too_long && full && reserved
+}
+
+fn test_remove() -> bool {
+    let mut data = AdvertisingData::new();
+    if data.set_all(&DATA) != ReturnCode::SUCCESS {
+        return false;
+    }
+    let removed = data.remove(ad_type::COMPLETE_LOCAL_NAME) == ReturnCode::SUCCESS
+        && data.as_slice() == &[0x02, 0x01, 0x06, 0x02, 0x0a, 0x00]
+        && data.get(ad_type::COMPLETE_LOCAL_NAME).is_none();
+    let missing = data.remove(ad_type::COMPLETE_LOCAL_NAME) == ReturnCode::EINVAL;
+    removed && missing && data.get(ad_type::TX_POWER_LEVEL) == Some(&[0x00][..])
+}
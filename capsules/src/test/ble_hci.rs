@@ -0,0 +1,527 @@
+//! Test the HCI controller of `capsules::ble_hci` by exchanging H4 packets with it, on
+//! top of a mock radio, UART and alarm.
+//!
+//! The host side of the tests feeds HCI command packets to the controller
+//! through the mock UART and checks the events the controller writes back,
+//! while the radio side checks the advertising channel PDUs the controller
+//! sends and feeds it the PDUs of other devices. The tests check that:
+//!
+//! - Reset is answered with a Command Complete event.
+//! - LE Set Random Address, LE Set Advertising Parameters, LE Set Advertising
+//!   Data and LE Set Advertise Enable are answered with Command Complete
+//!   events, after which the advertisement is sent on each advertising
+//!   channel, again when the alarm fires for the next advertising event, and
+//!   no more once advertising is disabled.
+//! - After Set Event Mask, to enable LE Meta events, and LE Set Scan Enable,
+//!   answered with Command Complete events, the radio listens on the
+//!   advertising channels in turn, and an advertisement from another device
+//!   is reported with an LE Advertising Report event.
+//! - Unknown commands are answered with a Command Status event, unsupported
+//!   parameters with an error status, and ACL data is discarded.
+//!
+//! The mocks only record what the controller asks of them and call it back
+//! when the tests tell them to, so the tests need neither a radio nor a host.
+//! `boards/nrf52dk/src/ble_hci_test.rs` shows how to instantiate them, and runs
+//! the test with `ble_hci_test::run()`.
+
+use ble_hci::HciController;
+use core::cell::Cell;
+use kernel::common::take_cell::TakeCell;
+use kernel::hil::ble_advertising::{BleAdvertisementDriver, BleConfig, BleLinkLayerRadio,
+                                   RadioChannel, RxClient, TxClient, Turnaround};
+use kernel::hil::time::{self, Alarm, Freq32KHz, Time};
+use kernel::hil::uart::{self, UART};
+use kernel::returncode::ReturnCode;
+
+/// Largest advertising channel PDU, header included
+pub const PACKET_LENGTH: usize = 39;
+
+// Command packets, each with the event that answers it
+const RESET: [(&'static [u8], &'static [u8]); 1] = [(
+    &[0x01, 0x03, 0x0c, 0x00],
+    &[0x04, 0x0e, 0x04, 0x01, 0x03, 0x0c, 0x00],
+)];
+
+const START_ADVERTISING: [(&'static [u8], &'static [u8]); 4] = [
+    // LE Set Random Address, to a static address
+    (
+        &[0x01, 0x05, 0x20, 0x06, 0x01, 0x02, 0x03, 0x04, 0x05, 0xc6],
+        &[0x04, 0x0e, 0x04, 0x01, 0x05, 0x20, 0x00],
+    ),
+    // LE Set Advertising Parameters: non-connectable undirected advertising
+    // every 100 ms from the random address, on every advertising channel
+    (
+        &[
+            0x01, 0x06, 0x20, 0x0f, 0xa0, 0x00, 0xa0, 0x00, 0x03, 0x01, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x07, 0x00,
+        ],
+        &[0x04, 0x0e, 0x04, 0x01, 0x06, 0x20, 0x00],
+    ),
+    // LE Set Advertising Data: the flags and the complete local name
+    (
+        &[
+            0x01, 0x08, 0x20, 0x20, 0x09, 0x02, 0x01, 0x06, 0x05, 0x09, 0x54, 0x6f, 0x63, 0x6b,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ],
+        &[0x04, 0x0e, 0x04, 0x01, 0x08, 0x20, 0x00],
+    ),
+    // LE Set Advertise Enable
+    (
+        &[0x01, 0x0a, 0x20, 0x01, 0x01],
+        &[0x04, 0x0e, 0x04, 0x01, 0x0a, 0x20, 0x00],
+    ),
+];
+
+const STOP_ADVERTISING: [(&'static [u8], &'static [u8]); 1] = [(
+    &[0x01, 0x0a, 0x20, 0x01, 0x00],
+    &[0x04, 0x0e, 0x04, 0x01, 0x0a, 0x20, 0x00],
+)];
+
+/// The ADV_NONCONN_IND sent with the parameters and data above
+const ADVERTISEMENT: [u8; 17] = [
+    0x42, 0x0f, 0x01, 0x02, 0x03, 0x04, 0x05, 0xc6, 0x02, 0x01, 0x06, 0x05, 0x09, 0x54, 0x6f, 0x63,
+    0x6b,
+];
+
+const START_SCANNING: [(&'static [u8], &'static [u8]); 2] = [
+    // Set Event Mask, to the default mask and LE Meta events
+    (
+        &[
+            0x01, 0x01, 0x0c, 0x08, 0xff, 0xff, 0xff, 0xff, 0xff, 0x1f, 0x00, 0x20,
+        ],
+        &[0x04, 0x0e, 0x04, 0x01, 0x01, 0x0c, 0x00],
+    ),
+    // LE Set Scan Enable, for passive scanning without filtering out
+    // duplicates
+    (
+        &[0x01, 0x0c, 0x20, 0x02, 0x01, 0x00],
+        &[0x04, 0x0e, 0x04, 0x01, 0x0c, 0x20, 0x00],
+    ),
+];
+
+const STOP_SCANNING: [(&'static [u8], &'static [u8]); 1] = [(
+    &[0x01, 0x0c, 0x20, 0x02, 0x00, 0x00],
+    &[0x04, 0x0e, 0x04, 0x01, 0x0c, 0x20, 0x00],
+)];
+
+/// An ADV_NONCONN_IND from a device with a public address, and the LE
+/// Advertising Report event that reports it
+const SCANNED: [u8; 11] = [
+    0x02, 0x09, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x02, 0x01, 0x06,
+];
+const REPORT: [u8; 18] = [
+    0x04, 0x3e, 0x0f, 0x02, 0x01, 0x03, 0x00, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x03, 0x02, 0x01,
+    0x06, 0x7f,
+];
+
+const ERRORS: [(&'static [u8], &'static [u8]); 5] = [
+    // A vendor specific command
+    (
+        &[0x01, 0x01, 0xfc, 0x00],
+        &[0x04, 0x0f, 0x04, 0x01, 0x01, 0x01, 0xfc],
+    ),
+    // LE Set Advertising Parameters, for connectable advertising
+    (
+        &[
+            0x01, 0x06, 0x20, 0x0f, 0xa0, 0x00, 0xa0, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x07, 0x00,
+        ],
+        &[0x04, 0x0e, 0x04, 0x01, 0x06, 0x20, 0x11],
+    ),
+    // ACL data, which is not answered
+    (&[0x02, 0x40, 0x00, 0x03, 0x00, 0xaa, 0xbb, 0xcc], &[]),
+    // LE Set Advertising Parameters, for scannable advertising
+    (
+        &[
+            0x01, 0x06, 0x20, 0x0f, 0xa0, 0x00, 0xa0, 0x00, 0x02, 0x01, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x07, 0x00,
+        ],
+        &[0x04, 0x0e, 0x04, 0x01, 0x06, 0x20, 0x00],
+    ),
+    // LE Set Advertise Enable, before a random address was set
+    (
+        &[0x01, 0x0a, 0x20, 0x01, 0x01],
+        &[0x04, 0x0e, 0x04, 0x01, 0x0a, 0x20, 0x12],
+    ),
+];
+
+/// A radio that records the advertisements sent and the channel listened on
+pub struct MockRadio {
+    tx_client: Cell<Option<&'static TxClient>>,
+    rx_client: Cell<Option<&'static RxClient>>,
+
+    // The advertisement being sent, and its channel
+    sent: Cell<[u8; PACKET_LENGTH]>,
+    sent_len: Cell<usize>,
+    sent_channel: Cell<Option<RadioChannel>>,
+    receiving: Cell<Option<RadioChannel>>,
+    rx_buf: TakeCell<'static, [u8]>,
+}
+
+impl MockRadio {
+    /// `rx_buf`, of at least `PACKET_LENGTH` bytes, is passed to the
+    /// controller with the PDU the tests deliver. Like the buffer of a real
+    /// radio, the controller does not give it back, so the tests deliver a
+    /// single PDU.
+    pub fn new(rx_buf: &'static mut [u8]) -> MockRadio {
+        MockRadio {
+            tx_client: Cell::new(None),
+            rx_client: Cell::new(None),
+            sent: Cell::new([0; PACKET_LENGTH]),
+            sent_len: Cell::new(0),
+            sent_channel: Cell::new(None),
+            receiving: Cell::new(None),
+            rx_buf: TakeCell::new(rx_buf),
+        }
+    }
+
+    /// Checks that `expected` is being sent on `channel`, and completes the
+    /// transmission
+    fn check_sent(&self, channel: RadioChannel, expected: &[u8]) -> bool {
+        let matches = self.sent_channel.get() == Some(channel)
+            && &self.sent.get()[..self.sent_len.get()] == expected;
+        if !matches {
+            debug!(
+                "Expected {:?} on {:?}, got {:?} on {:?}",
+                expected,
+                channel,
+                &self.sent.get()[..self.sent_len.get()],
+                self.sent_channel.get()
+            );
+        }
+        self.sent_channel.set(None);
+        self.tx_client
+            .get()
+            .map(|client| client.transmit_event(ReturnCode::SUCCESS));
+        matches
+    }
+
+    /// Checks that the radio is listening on `channel`, and passes `pdu` to
+    /// the controller as if it had been received
+    fn deliver(&self, channel: RadioChannel, pdu: &[u8]) -> bool {
+        if self.receiving.get() != Some(channel) {
+            debug!(
+                "Expected to listen on {:?}, listening on {:?}",
+                channel,
+                self.receiving.get()
+            );
+            return false;
+        }
+        self.receiving.set(None);
+        let buf = match self.rx_buf.take() {
+            Some(buf) => buf,
+            None => return false,
+        };
+        buf[..pdu.len()].copy_from_slice(pdu);
+        let len = pdu.len() as u8;
+        self.rx_client
+            .get()
+            .map(move |client| client.receive_event(buf, len, ReturnCode::SUCCESS));
+        true
+    }
+
+    fn is_receiving(&self, channel: RadioChannel) -> bool {
+        self.receiving.get() == Some(channel)
+    }
+
+    fn is_idle(&self) -> bool {
+        self.sent_channel.get().is_none() && self.receiving.get().is_none()
+    }
+}
+
+impl BleAdvertisementDriver for MockRadio {
+    fn transmit_advertisement(
+        &self,
+        buf: &'static mut [u8],
+        len: usize,
+        channel: RadioChannel,
+    ) -> &'static mut [u8] {
+        let mut sent = [0; PACKET_LENGTH];
+        sent[..len].copy_from_slice(&buf[..len]);
+        self.sent.set(sent);
+        self.sent_len.set(len);
+        self.sent_channel.set(Some(channel));
+        self.receiving.set(None);
+        buf
+    }
+
+    fn receive_advertisement(&self, channel: RadioChannel) {
+        self.receiving.set(Some(channel));
+    }
+
+    fn set_receive_client(&self, client: &'static RxClient) {
+        self.rx_client.set(Some(client));
+    }
+
+    fn set_transmit_client(&self, client: &'static TxClient) {
+        self.tx_client.set(Some(client));
+    }
+}
+
+impl BleConfig for MockRadio {
+    fn set_tx_power(&self, _power: u8) -> ReturnCode {
+        ReturnCode::SUCCESS
+    }
+}
+
+impl BleLinkLayerRadio for MockRadio {
+    fn set_access_address(&self, _access_address: u32, _crc_init: u32) {}
+
+    fn set_turnaround(&self, _turnaround: Turnaround) {}
+
+    fn set_response(&self, buf: &'static mut [u8], _len: usize) -> &'static mut [u8] {
+        buf
+    }
+
+    fn disable(&self) {
+        self.sent_channel.set(None);
+        self.receiving.set(None);
+    }
+}
+
+/// A UART that holds each buffer written by the controller until it is
+/// checked, and completes receptions with the bytes fed to it
+pub struct MockUart {
+    client: Cell<Option<&'static uart::Client>>,
+    rx_buf: TakeCell<'static, [u8]>,
+    rx_len: Cell<usize>,
+    tx_buf: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+}
+
+impl MockUart {
+    pub fn new() -> MockUart {
+        MockUart {
+            client: Cell::new(None),
+            rx_buf: TakeCell::empty(),
+            rx_len: Cell::new(0),
+            tx_buf: TakeCell::empty(),
+            tx_len: Cell::new(0),
+        }
+    }
+
+    /// Completes the receptions of the controller with `bytes`, and returns
+    /// whether it received all of them
+    fn feed(&self, bytes: &[u8]) -> bool {
+        let mut fed = 0;
+        while fed < bytes.len() {
+            let buf = match self.rx_buf.take() {
+                Some(buf) => buf,
+                None => return false,
+            };
+            let len = self.rx_len.get();
+            if fed + len > bytes.len() {
+                self.rx_buf.replace(buf);
+                return false;
+            }
+            buf[..len].copy_from_slice(&bytes[fed..fed + len]);
+            fed += len;
+            self.client
+                .get()
+                .map(move |client| client.receive_complete(buf, len, uart::Error::CommandComplete));
+        }
+        true
+    }
+
+    /// Checks the bytes being written, if any, against `expected`, and
+    /// completes the transmission
+    fn check_sent(&self, expected: &[u8]) -> bool {
+        match self.tx_buf.take() {
+            Some(buf) => {
+                let matches = &buf[..self.tx_len.get()] == expected;
+                if !matches {
+                    debug!(
+                        "Expected {:?}, got {:?}",
+                        expected,
+                        &buf[..self.tx_len.get()]
+                    );
+                }
+                self.client
+                    .get()
+                    .map(move |client| client.transmit_complete(buf, uart::Error::CommandComplete));
+                matches
+            }
+            None => expected.is_empty(),
+        }
+    }
+}
+
+impl UART for MockUart {
+    fn set_client(&self, client: &'static uart::Client) {
+        self.client.set(Some(client));
+    }
+
+    fn init(&self, _params: uart::UARTParams) {}
+
+    fn transmit(&self, tx_data: &'static mut [u8], tx_len: usize) {
+        self.tx_len.set(tx_len);
+        self.tx_buf.replace(tx_data);
+    }
+
+    fn receive(&self, rx_buffer: &'static mut [u8], rx_len: usize) {
+        self.rx_len.set(rx_len);
+        self.rx_buf.replace(rx_buffer);
+    }
+}
+
+/// An alarm whose time only advances when the test fires it
+pub struct MockAlarm {
+    client: Cell<Option<&'static time::Client>>,
+    now: Cell<u32>,
+    alarm: Cell<Option<u32>>,
+}
+
+impl MockAlarm {
+    pub fn new() -> MockAlarm {
+        MockAlarm {
+            client: Cell::new(None),
+            now: Cell::new(0),
+            alarm: Cell::new(None),
+        }
+    }
+
+    pub fn set_client(&self, client: &'static time::Client) {
+        self.client.set(Some(client));
+    }
+
+    /// Advances the time to the alarm and fires it. Returns whether the
+    /// alarm was set.
+    fn fire(&self) -> bool {
+        match self.alarm.get() {
+            Some(tics) => {
+                self.now.set(tics);
+                self.alarm.set(None);
+                self.client.get().map(|client| client.fired());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Time for MockAlarm {
+    type Frequency = Freq32KHz;
+
+    fn disable(&self) {
+        self.alarm.set(None);
+    }
+
+    fn is_armed(&self) -> bool {
+        self.alarm.get().is_some()
+    }
+}
+
+impl Alarm for MockAlarm {
+    fn now(&self) -> u32 {
+        self.now.get()
+    }
+
+    fn set_alarm(&self, tics: u32) {
+        self.alarm.set(Some(tics));
+    }
+
+    fn get_alarm(&self) -> u32 {
+        self.alarm.get().unwrap_or(0)
+    }
+}
+
+pub type TestController<'a> = HciController<'a, MockRadio, MockUart, MockAlarm>;
+
+pub struct HciTest<'a> {
+    hci: &'a TestController<'a>,
+    radio: &'a MockRadio,
+    uart: &'a MockUart,
+    alarm: &'a MockAlarm,
+}
+
+impl<'a> HciTest<'a> {
+    /// The controller must be the client of the mocks
+    pub fn new(
+        hci: &'a TestController<'a>,
+        radio: &'a MockRadio,
+        uart: &'a MockUart,
+        alarm: &'a MockAlarm,
+    ) -> HciTest<'a> {
+        HciTest {
+            hci: hci,
+            radio: radio,
+            uart: uart,
+            alarm: alarm,
+        }
+    }
+
+    pub fn run(&self) {
+        debug!("BLE HCI controller tests");
+        self.hci.start();
+        let tests: [(&'static str, fn(&HciTest<'a>) -> bool); 4] = [
+            ("reset", HciTest::test_reset),
+            ("advertising", HciTest::test_advertising),
+            ("scanning", HciTest::test_scanning),
+            ("errors", HciTest::test_errors),
+        ];
+        let mut passed = 0;
+        for &(name, test) in tests.iter() {
+            if test(self) {
+                passed += 1;
+            } else {
+                debug!("Test failed: {}", name);
+            }
+        }
+        debug!("{} of {} tests passed", passed, tests.len());
+    }
+
+    /// Sends each command packet to the controller, and checks the event that
+    /// answers it
+    fn command_all(&self, commands: &[(&[u8], &[u8])]) -> bool {
+        commands
+            .iter()
+            .all(|&(packet, event)| self.uart.feed(packet) && self.uart.check_sent(event))
+    }
+
+    fn test_reset(&self) -> bool {
+        self.command_all(&RESET) && self.radio.is_idle()
+    }
+
+    fn test_advertising(&self) -> bool {
+        // An advertising event starts as soon as advertising is enabled
+        let first_event = self.command_all(&START_ADVERTISING)
+            && self.radio
+                .check_sent(RadioChannel::AdvertisingChannel37, &ADVERTISEMENT)
+            && self.radio
+                .check_sent(RadioChannel::AdvertisingChannel38, &ADVERTISEMENT)
+            && self.radio
+                .check_sent(RadioChannel::AdvertisingChannel39, &ADVERTISEMENT)
+            && self.radio.is_idle();
+
+        // The next one starts when the alarm fires, and is completed after
+        // advertising is disabled
+        let next_event = self.alarm.fire()
+            && self.radio
+                .check_sent(RadioChannel::AdvertisingChannel37, &ADVERTISEMENT)
+            && self.command_all(&STOP_ADVERTISING)
+            && self.radio
+                .check_sent(RadioChannel::AdvertisingChannel38, &ADVERTISEMENT)
+            && self.radio.is_idle() && !self.alarm.is_armed();
+        first_event && next_event
+    }
+
+    fn test_scanning(&self) -> bool {
+        self.command_all(&START_SCANNING)
+            && self.radio
+                .deliver(RadioChannel::AdvertisingChannel37, &SCANNED)
+            && self.uart.check_sent(&REPORT)
+            && self.radio
+                .is_receiving(RadioChannel::AdvertisingChannel37)
+            // The scan interval is over, and the next channel is scanned
+            && self.alarm.fire()
+            && self.radio
+                .is_receiving(RadioChannel::AdvertisingChannel38)
+            && self.command_all(&STOP_SCANNING) && self.radio.is_idle()
+    }
+
+    fn test_errors(&self) -> bool {
+        // Reset forgets the random address
+        self.command_all(&RESET) && self.command_all(&ERRORS) && self.radio.is_idle()
+    }
+}
@@ -0,0 +1,445 @@
+//! Test the capture of the frames sent and received by a simulated node,
+//! node 0, which exchanges frames with another simulated node, node 1.
+//!
+//! `Capture` is inserted between the `AwakeMac` and the `Framer` of node 0,
+//! and writes to a `TestUart` that records the stream instead of sending it.
+//! The tests check that:
+//!
+//! - The stream starts with the Section Header Block and Interface
+//!   Description Block that `pcapng::write_headers` writes, which the host
+//!   tool also writes when it joins a capture in progress.
+//! - A frame sent by node 0 is captured in an outbound Enhanced Packet Block,
+//!   without its FCS, with the channel in its TAP header.
+//! - A frame received by node 0 is captured in an inbound Enhanced Packet
+//!   Block, with the RSSI and LQI of the link in its TAP header, and a later
+//!   timestamp.
+//! - While the UART is too slow to keep up, frames that do not fit in the
+//!   queue are dropped, and the number of frames dropped is recorded in the
+//!   next captured block.
+//!
+//! The simulation runs on the virtual time of a `SimClock` over a lossless
+//! `SimMedium`, so the tests do not need any radio hardware.
+//! `boards/imix/src/capture_test.rs` shows how to instantiate the nodes, and
+//! runs the test with `capture_test::run()`.
+
+use core::cell::Cell;
+use ieee802154::capture::Capture;
+use ieee802154::device::{MacDevice, RxClient, SecurityError, TxClient};
+use ieee802154::mac::{AwakeMac, Mac};
+use ieee802154::pcapng::*;
+use kernel::ReturnCode;
+use kernel::common::take_cell::TakeCell;
+use kernel::hil::radio::RxInfo;
+use kernel::hil::time::{Frequency, Time};
+use kernel::hil::uart::{self, UART};
+use net::ieee802154::*;
+use sim_radio::{LinkParams, SimAlarm, SimClock, SimMedium, SimRadio};
+use test::sim_lowpan::{NODE0_ADDR_LONG, NODE1_ADDR_LONG, PAN};
+
+pub type SimAwakeMac<'a> = AwakeMac<'a, SimRadio<'a, SimAlarm<'a>>, SimAlarm<'a>>;
+pub type SimCapture<'a> = Capture<'a, SimAwakeMac<'a>, TestUart, SimAlarm<'a>>;
+
+const PAYLOAD: [u8; 8] = [0x43, 0x41, 0x50, 0x54, 0x00, 0x01, 0x02, 0x03];
+
+const LINK_RSSI: i8 = -60;
+const LINK_LQI: u8 = 200;
+
+/// The single-precision representation of `LINK_RSSI`, -60.0
+const LINK_RSSI_BITS: u32 = 0xc270_0000;
+
+/// How long a transmission may take, in milliseconds of virtual time
+const TX_TIMEOUT_MS: u32 = 1_000;
+
+/// More frames than fit in the queue of the capture, which the board gives
+/// two buffers of 1024 bytes
+const STALLED_FRAMES: usize = 16;
+
+/// Directions of the `epb_flags` option
+const EPB_FLAGS_INBOUND: u32 = 1;
+const EPB_FLAGS_OUTBOUND: u32 = 2;
+
+/// TAP TLV types
+const TAP_RSS: u16 = 1;
+const TAP_CHANNEL_ASSIGNMENT: u16 = 3;
+const TAP_LQI: u16 = 10;
+
+/// A UART that records the bytes written to it in a buffer. A write completes
+/// when `complete` is called, unless the UART is stalled.
+pub struct TestUart {
+    client: Cell<Option<&'static uart::Client>>,
+    tx_buf: TakeCell<'static, [u8]>,
+    stalled: Cell<bool>,
+    stream: TakeCell<'static, [u8]>,
+    stream_len: Cell<usize>,
+}
+
+impl TestUart {
+    pub fn new(stream: &'static mut [u8]) -> TestUart {
+        TestUart {
+            client: Cell::new(None),
+            tx_buf: TakeCell::empty(),
+            stalled: Cell::new(false),
+            stream: TakeCell::new(stream),
+            stream_len: Cell::new(0),
+        }
+    }
+
+    /// Completes the write in progress, if any
+    fn complete(&self) {
+        if self.stalled.get() {
+            return;
+        }
+        self.tx_buf.take().map(|buf| {
+            self.client
+                .get()
+                .map(move |client| client.transmit_complete(buf, uart::Error::CommandComplete));
+        });
+    }
+
+    fn clear(&self) {
+        self.stream_len.set(0);
+    }
+
+    /// Calls `f` with the bytes recorded since the last call to `clear`
+    fn map_stream<F: FnOnce(&[u8]) -> bool>(&self, f: F) -> bool {
+        let len = self.stream_len.get();
+        self.stream.map_or(false, |stream| f(&stream[..len]))
+    }
+}
+
+impl UART for TestUart {
+    fn set_client(&self, client: &'static uart::Client) {
+        self.client.set(Some(client));
+    }
+
+    fn init(&self, _params: uart::UARTParams) {}
+
+    fn transmit(&self, tx_data: &'static mut [u8], tx_len: usize) {
+        self.stream.map(|stream| {
+            let start = self.stream_len.get();
+            let len = if start + tx_len > stream.len() {
+                stream.len() - start
+            } else {
+                tx_len
+            };
+            stream[start..start + len].copy_from_slice(&tx_data[..len]);
+            self.stream_len.set(start + len);
+        });
+        self.tx_buf.replace(tx_data);
+    }
+
+    fn receive(&self, _rx_buffer: &'static mut [u8], _rx_len: usize) {}
+}
+
+fn read_u16(buf: &[u8]) -> u16 {
+    buf[0] as u16 | (buf[1] as u16) << 8
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    read_u16(buf) as u32 | (read_u16(&buf[2..]) as u32) << 16
+}
+
+/// Calls `f` with each block of `stream`, and returns false if the stream
+/// does not consist of complete blocks
+fn for_each_block<F: FnMut(&[u8])>(stream: &[u8], mut f: F) -> bool {
+    let mut offset = 0;
+    while offset + 12 <= stream.len() {
+        let len = read_u32(&stream[offset + 4..]) as usize;
+        if len < 12 || offset + len > stream.len()
+            || read_u32(&stream[offset + len - 4..]) as usize != len
+        {
+            return false;
+        }
+        f(&stream[offset..offset + len]);
+        offset += len;
+    }
+    offset == stream.len()
+}
+
+/// Finds the value of option `code` among the options starting at `offset`
+/// in `buf`, which are laid out as pcap-ng options and TAP TLVs are
+fn find_option(buf: &[u8], mut offset: usize, code: u16) -> Option<&[u8]> {
+    while offset + 4 <= buf.len() {
+        let option_code = read_u16(&buf[offset..]);
+        let len = read_u16(&buf[offset + 2..]) as usize;
+        let value = offset + 4;
+        if value + len > buf.len() {
+            return None;
+        }
+        if option_code == code {
+            return Some(&buf[value..value + len]);
+        }
+        offset = value + (len + 3) / 4 * 4;
+    }
+    None
+}
+
+/// The packet captured in an Enhanced Packet Block, which is the TAP header
+/// followed by the frame
+fn epb_packet(block: &[u8]) -> &[u8] {
+    let captured_len = read_u32(&block[20..]) as usize;
+    &block[28..28 + captured_len]
+}
+
+/// The value of option `code` of an Enhanced Packet Block
+fn epb_option(block: &[u8], code: u16) -> Option<&[u8]> {
+    let options = 28 + (epb_packet(block).len() + 3) / 4 * 4;
+    find_option(&block[..block.len() - 4], options, code)
+}
+
+fn epb_timestamp(block: &[u8]) -> u64 {
+    (read_u32(&block[12..]) as u64) << 32 | read_u32(&block[16..]) as u64
+}
+
+/// The value of TLV `tlv_type` of the TAP header of an Enhanced Packet Block
+fn tap_tlv(block: &[u8], tlv_type: u16) -> Option<&[u8]> {
+    let packet = epb_packet(block);
+    let tap_len = read_u16(&packet[2..]) as usize;
+    find_option(&packet[..tap_len], 4, tlv_type)
+}
+
+/// The frame captured in an Enhanced Packet Block
+fn epb_frame(block: &[u8]) -> &[u8] {
+    let packet = epb_packet(block);
+    &packet[read_u16(&packet[2..]) as usize..]
+}
+
+fn epb_direction(block: &[u8]) -> Option<u32> {
+    epb_option(block, EPB_FLAGS).map(read_u32)
+}
+
+fn epb_dropcount(block: &[u8]) -> u32 {
+    epb_option(block, EPB_DROPCOUNT).map_or(0, read_u32)
+}
+
+pub struct CaptureTest<'a> {
+    clock: &'a SimClock<'a>,
+    medium: &'a SimMedium<'a, SimAlarm<'a>>,
+    capture: &'a SimCapture<'a>,
+    uart: &'a TestUart,
+    mac0: &'a MacDevice<'a>,
+    mac1: &'a MacDevice<'a>,
+    tx_buf: TakeCell<'static, [u8]>,
+
+    tx_result: Cell<Option<ReturnCode>>,
+    // The timestamp of the frame sent in the transmit test
+    tx_timestamp: Cell<u64>,
+}
+
+impl<'a> CaptureTest<'a> {
+    /// `capture` writes to `uart` and sits below `mac0`, the `MacDevice` of
+    /// node 0, whose extended address is `NODE0_ADDR_LONG`. `mac1` is the
+    /// `MacDevice` of node 1, whose extended address is `NODE1_ADDR_LONG`.
+    /// The nodes must be the first two nodes of `medium`, in that order, and
+    /// the test must be the transmit and receive client of both
+    /// `MacDevice`s.
+    pub fn new(
+        clock: &'a SimClock<'a>,
+        medium: &'a SimMedium<'a, SimAlarm<'a>>,
+        capture: &'a SimCapture<'a>,
+        uart: &'a TestUart,
+        mac0: &'a MacDevice<'a>,
+        mac1: &'a MacDevice<'a>,
+        tx_buf: &'static mut [u8],
+    ) -> CaptureTest<'a> {
+        CaptureTest {
+            clock: clock,
+            medium: medium,
+            capture: capture,
+            uart: uart,
+            mac0: mac0,
+            mac1: mac1,
+            tx_buf: TakeCell::new(tx_buf),
+            tx_result: Cell::new(None),
+            tx_timestamp: Cell::new(0),
+        }
+    }
+
+    pub fn run(&self) {
+        debug!("Capture of the frames of a simulated node");
+        self.medium.connect(
+            0,
+            1,
+            LinkParams {
+                loss_percent: 0,
+                delay_us: 0,
+                rssi: LINK_RSSI,
+                lqi: LINK_LQI,
+            },
+        );
+
+        let tests: [(&'static str, fn(&CaptureTest<'a>) -> bool); 4] = [
+            ("headers", CaptureTest::test_headers),
+            ("transmit", CaptureTest::test_transmit),
+            ("receive", CaptureTest::test_receive),
+            ("dropped frames", CaptureTest::test_dropped),
+        ];
+        let mut passed = 0;
+        for &(name, test) in tests.iter() {
+            if test(self) {
+                passed += 1;
+            } else {
+                debug!("Test failed: {}", name);
+            }
+        }
+        debug!("{} of {} tests passed", passed, tests.len());
+    }
+
+    /// Runs the simulation until `done` returns true, the nodes are idle, or
+    /// `timeout_ms` milliseconds have elapsed, and then completes the writes
+    /// to the UART
+    fn run_until<F: Fn(&CaptureTest<'a>) -> bool>(&self, timeout_ms: u32, done: F) {
+        let freq = <<SimAlarm as Time>::Frequency as Frequency>::frequency();
+        let timeout = (freq / 1000) * timeout_ms;
+        let start = self.clock.now();
+        while !done(self) && self.clock.now().wrapping_sub(start) < timeout && self.clock.step() {}
+        // Both buffers of the capture may be written in turn
+        self.uart.complete();
+        self.uart.complete();
+    }
+
+    /// Sends `PAYLOAD` from `src_mac` to the other node, and returns the
+    /// result of the transmission
+    fn send(&self, src_mac: &MacDevice<'a>, src_addr: [u8; 8], dst_addr: [u8; 8]) -> ReturnCode {
+        self.tx_result.set(None);
+        let buf = match self.tx_buf.take() {
+            Some(buf) => buf,
+            None => return ReturnCode::ENOMEM,
+        };
+        let mut frame = match src_mac.prepare_data_frame(
+            buf,
+            PAN,
+            MacAddress::Long(dst_addr),
+            PAN,
+            MacAddress::Long(src_addr),
+            None,
+        ) {
+            Ok(frame) => frame,
+            Err(buf) => {
+                self.tx_buf.replace(buf);
+                return ReturnCode::FAIL;
+            }
+        };
+        frame.append_payload(&PAYLOAD);
+        let (rval, buf) = src_mac.transmit(frame);
+        if rval != ReturnCode::SUCCESS {
+            buf.map(|buf| self.tx_buf.replace(buf));
+            return rval;
+        }
+        self.run_until(TX_TIMEOUT_MS, |t| t.tx_result.get().is_some());
+        self.tx_result.get().unwrap_or(ReturnCode::FAIL)
+    }
+
+    /// Checks that the stream recorded since the last test consists of a
+    /// single Enhanced Packet Block, which `check` accepts
+    fn check_single_frame<F: Fn(&[u8]) -> bool>(&self, check: F) -> bool {
+        self.uart.map_stream(|stream| {
+            let mut blocks = 0;
+            let mut valid = true;
+            let complete = for_each_block(stream, |block| {
+                blocks += 1;
+                valid = valid && read_u32(block) == ENHANCED_PACKET_BLOCK && check(block);
+            });
+            complete && blocks == 1 && valid
+        })
+    }
+
+    fn test_headers(&self) -> bool {
+        self.capture.start();
+        self.uart.complete();
+        let mut headers = [0; HEADERS_SIZE];
+        let len = write_headers(&mut headers);
+        let matches = self.uart.map_stream(|stream| stream == &headers[..len]);
+        self.uart.clear();
+        matches
+    }
+
+    fn test_transmit(&self) -> bool {
+        let channel = self.capture.get_channel();
+        if self.send(self.mac0, NODE0_ADDR_LONG, NODE1_ADDR_LONG) != ReturnCode::SUCCESS {
+            return false;
+        }
+        let result = self.check_single_frame(|block| {
+            self.tx_timestamp.set(epb_timestamp(block));
+            let frame = epb_frame(block);
+            epb_direction(block) == Some(EPB_FLAGS_OUTBOUND)
+                && tap_tlv(block, TAP_CHANNEL_ASSIGNMENT) == Some(&[channel, 0, 0])
+                && tap_tlv(block, TAP_RSS).is_none()
+                && frame.len() > PAYLOAD.len()
+                && frame[frame.len() - PAYLOAD.len()..] == PAYLOAD
+        });
+        self.uart.clear();
+        result
+    }
+
+    fn test_receive(&self) -> bool {
+        if self.send(self.mac1, NODE1_ADDR_LONG, NODE0_ADDR_LONG) != ReturnCode::SUCCESS {
+            return false;
+        }
+        let rssi_bits = [
+            LINK_RSSI_BITS as u8,
+            (LINK_RSSI_BITS >> 8) as u8,
+            (LINK_RSSI_BITS >> 16) as u8,
+            (LINK_RSSI_BITS >> 24) as u8,
+        ];
+        let result = self.check_single_frame(|block| {
+            let frame = epb_frame(block);
+            epb_direction(block) == Some(EPB_FLAGS_INBOUND)
+                && tap_tlv(block, TAP_RSS) == Some(&rssi_bits)
+                && tap_tlv(block, TAP_LQI) == Some(&[LINK_LQI])
+                && epb_timestamp(block) > self.tx_timestamp.get()
+                && frame.len() > PAYLOAD.len()
+                && frame[frame.len() - PAYLOAD.len()..] == PAYLOAD
+        });
+        self.uart.clear();
+        result
+    }
+
+    fn test_dropped(&self) -> bool {
+        self.uart.stalled.set(true);
+        for _ in 0..STALLED_FRAMES {
+            if self.send(self.mac0, NODE0_ADDR_LONG, NODE1_ADDR_LONG) != ReturnCode::SUCCESS {
+                return false;
+            }
+        }
+        self.uart.stalled.set(false);
+        self.uart.complete();
+        if self.send(self.mac0, NODE0_ADDR_LONG, NODE1_ADDR_LONG) != ReturnCode::SUCCESS {
+            return false;
+        }
+
+        // Every frame sent is either in the stream or counted as dropped
+        let result = self.uart.map_stream(|stream| {
+            let mut frames = 0;
+            let mut dropped = 0;
+            let complete = for_each_block(stream, |block| {
+                frames += 1;
+                dropped += epb_dropcount(block);
+            });
+            complete && dropped > 0 && frames + dropped as usize == STALLED_FRAMES + 1
+        });
+        self.uart.clear();
+        result
+    }
+}
+
+impl<'a> TxClient for CaptureTest<'a> {
+    fn send_done(&self, spi_buf: &'static mut [u8], _acked: bool, result: ReturnCode) {
+        self.tx_buf.replace(spi_buf);
+        self.tx_result.set(Some(result));
+    }
+}
+
+impl<'a> RxClient for CaptureTest<'a> {
+    fn receive<'b>(
+        &self,
+        _buf: &'b [u8],
+        _header: Header<'b>,
+        _info: RxInfo,
+        _data_offset: usize,
+        _data_len: usize,
+    ) {
+    }
+
+    fn receive_security_failure<'b>(&self, _header: Header<'b>, _error: SecurityError) {}
+}
@@ -0,0 +1,391 @@
+//! Test the CoAP server, messaging layer and UDP transport between two nodes
+//! of a simulated 6LoWPAN network.
+//!
+//! Node 1 runs a `CoapServer` on a `CoapLayer` and a `UdpTransport`, with this
+//! test registered as its resource. Node 0 only has a `UdpTransport`, through
+//! which the test sends recorded requests and receives the replies. The tests
+//! check that:
+//!
+//! - A request that is answered right away gets a piggybacked response, and
+//!   unknown resources get 4.04 (Not Found).
+//! - A request that is answered late is acknowledged with an empty ACK, and
+//!   its response is sent as a separate confirmable message, which is
+//!   retransmitted until it is acknowledged.
+//! - Other requests are still answered while a separate response waits for
+//!   its acknowledgement, which needs the second transmit buffer of the
+//!   server.
+//! - A duplicate of a non-idempotent request is acknowledged but not
+//!   processed again.
+//! - The UDP transport of the server drops a datagram with a corrupted
+//!   checksum, and accepts it once the checksum is fixed.
+//!
+//! The nodes are those of `capsules::test::sim_lowpan`, connected by a
+//! lossless link. The simulation runs on the virtual time of a `SimClock`, so
+//! the tests do not need any radio hardware. `boards/imix/src/coap_test.rs`
+//! shows how to instantiate the two nodes, and runs the test with
+//! `coap_test::run()`.
+
+use core::cell::Cell;
+use kernel::ReturnCode;
+use kernel::common::take_cell::{MapCell, TakeCell};
+use kernel::hil::time::{self, Alarm, Frequency, Time};
+use net::coap::layer::{Endpoint, Transport, TransportClient};
+use net::coap::message::{code, Message, MessageType};
+use net::coap::server::{CoapResource, CoapServer};
+use net::coap::udp::{COAP_PORT, IP6_HEADER_SIZE, UDP_HEADER_SIZE};
+use net::ip::{self, ip6_nh, IP6Header};
+use net::sixlowpan::SixlowpanClient;
+use net::util::u16_to_slice;
+use sim_radio::{LinkParams, SimAlarm, SimClock, SimMedium};
+use test::sim_lowpan::{link_local_addr, NODE0_ADDR_LONG, NODE1_ADDR_LONG};
+
+/// The UDP port of the client on node 0
+pub const CLIENT_PORT: u16 = 49152;
+
+/// The first message ID of the server's messaging layer
+pub const SERVER_MESSAGE_ID: u16 = 0x1000;
+
+/// The size of the buffers of the client and the server
+pub const BUF_LEN: usize = 64;
+
+// Confirmable requests, with their message ID and a one-byte token equal to
+// the low byte of the message ID
+
+// GET /hello
+const GET_HELLO: [u8; 11] = [
+    0x41, 0x01, 0x00, 0x01, 0x01, 0xb5, b'h', b'e', b'l', b'l', b'o',
+];
+// GET /missing
+const GET_MISSING: [u8; 13] = [
+    0x41, 0x01, 0x00, 0x02, 0x02, 0xb7, b'm', b'i', b's', b's', b'i', b'n', b'g',
+];
+// GET /slow
+const GET_SLOW: [u8; 10] = [0x41, 0x01, 0x00, 0x03, 0x03, 0xb4, b's', b'l', b'o', b'w'];
+// GET /hello, sent while the response to GET /slow is outstanding
+const GET_HELLO_AGAIN: [u8; 11] = [
+    0x41, 0x01, 0x00, 0x04, 0x04, 0xb5, b'h', b'e', b'l', b'l', b'o',
+];
+// POST /count
+const POST_COUNT: [u8; 11] = [
+    0x41, 0x02, 0x00, 0x05, 0x05, 0xb5, b'c', b'o', b'u', b'n', b't',
+];
+
+const HELLO: &'static [u8] = b"hi";
+const SLOW: &'static [u8] = b"late";
+
+/// How long to wait for a reply that is expected, in milliseconds of virtual
+/// time. The first retransmission of a confirmable message comes after 2 to
+/// 3 seconds.
+const REPLY_TIMEOUT_MS: u32 = 4000;
+
+/// How long the slow resource takes to answer, well after the empty ACK
+const SLOW_DELAY_MS: u32 = 1000;
+
+/// A reply received by the client
+#[derive(Copy, Clone)]
+struct Reply {
+    len: usize,
+    buf: [u8; BUF_LEN],
+}
+
+pub struct CoapTest<'a> {
+    clock: &'a SimClock<'a>,
+    medium: &'a SimMedium<'a, SimAlarm<'a>>,
+    alarm: &'a SimAlarm<'a>,
+    client: &'a Transport<'a>,
+    server: &'a CoapServer<'a>,
+    server_udp: &'a SixlowpanClient,
+
+    request: TakeCell<'static, [u8]>,
+    reply: MapCell<Reply>,
+    replies: Cell<usize>,
+    timed_out: Cell<bool>,
+
+    slow_request: Cell<Option<usize>>,
+    count: Cell<usize>,
+}
+
+impl<'a> CoapTest<'a> {
+    /// `client` is the transport of node 0, and `server` and `server_udp` the
+    /// server and transport of node 1. `alarm` is used to time out waits, and
+    /// `request` is a buffer of `BUF_LEN` bytes. The test must be the client
+    /// of `client` and `alarm`, and a resource of `server`.
+    pub fn new(
+        clock: &'a SimClock<'a>,
+        medium: &'a SimMedium<'a, SimAlarm<'a>>,
+        alarm: &'a SimAlarm<'a>,
+        client: &'a Transport<'a>,
+        server: &'a CoapServer<'a>,
+        server_udp: &'a SixlowpanClient,
+        request: &'static mut [u8],
+    ) -> CoapTest<'a> {
+        CoapTest {
+            clock: clock,
+            medium: medium,
+            alarm: alarm,
+            client: client,
+            server: server,
+            server_udp: server_udp,
+            request: TakeCell::new(request),
+            reply: MapCell::new(Reply {
+                len: 0,
+                buf: [0; BUF_LEN],
+            }),
+            replies: Cell::new(0),
+            timed_out: Cell::new(false),
+            slow_request: Cell::new(None),
+            count: Cell::new(0),
+        }
+    }
+
+    pub fn run(&self) {
+        debug!("CoAP over a simulated 6LoWPAN network");
+        self.medium.connect(
+            0,
+            1,
+            LinkParams {
+                loss_percent: 0,
+                delay_us: 0,
+                rssi: -60,
+                lqi: 255,
+            },
+        );
+        let tests: [(&'static str, fn(&CoapTest<'a>) -> bool); 5] = [
+            ("piggybacked response", CoapTest::test_piggybacked),
+            ("not found", CoapTest::test_not_found),
+            ("separate response", CoapTest::test_separate),
+            ("duplicate request", CoapTest::test_duplicate),
+            ("corrupted checksum", CoapTest::test_checksum),
+        ];
+        let mut passed = 0;
+        for &(name, test) in tests.iter() {
+            if test(self) {
+                passed += 1;
+            } else {
+                debug!("Test failed: {}", name);
+            }
+        }
+        debug!("{} of {} tests passed", passed, tests.len());
+    }
+
+    fn server_endpoint() -> Endpoint {
+        Endpoint::new(link_local_addr(NODE1_ADDR_LONG), COAP_PORT)
+    }
+
+    fn ms_to_tics(ms: u32) -> u32 {
+        let freq = <<SimAlarm as Time>::Frequency as Frequency>::frequency();
+        (freq / 1000) * ms
+    }
+
+    /// Sends `message` from the client to the server, and waits until it has
+    /// been transmitted.
+    fn send(&self, message: &[u8]) -> bool {
+        let buf = match self.request.take() {
+            Some(buf) => buf,
+            None => return false,
+        };
+        buf[..message.len()].copy_from_slice(message);
+        if let Err((result, buf)) = self.client
+            .send_to(Self::server_endpoint(), buf, message.len())
+        {
+            debug!("Failed to send request: {:?}", result);
+            self.request.replace(buf);
+            return false;
+        }
+        while self.request.is_none() && self.clock.step() {}
+        true
+    }
+
+    /// Runs the simulation until the client receives a reply or until `ms`
+    /// milliseconds have elapsed, and returns whether a reply arrived.
+    fn wait(&self, ms: u32) -> bool {
+        let replies = self.replies.get();
+        self.timed_out.set(false);
+        self.alarm
+            .set_alarm(self.clock.now().wrapping_add(Self::ms_to_tics(ms)));
+        while self.replies.get() == replies && !self.timed_out.get() && self.clock.step() {}
+        self.alarm.disable();
+        self.replies.get() != replies
+    }
+
+    /// Waits for a reply, and checks its type, code, token and payload.
+    /// Returns its message ID if it matches.
+    fn expect(&self, mtype: MessageType, code: u8, token: &[u8], payload: &[u8]) -> Option<u16> {
+        if !self.wait(REPLY_TIMEOUT_MS) {
+            debug!("No reply received");
+            return None;
+        }
+        self.reply.and_then(|reply| {
+            let (_, message) = Message::decode(&reply.buf[..reply.len]).done()?;
+            let header = message.header;
+            if header.mtype == mtype && header.code == code && header.token() == token
+                && message.payload == payload
+            {
+                Some(header.message_id)
+            } else {
+                debug!("Unexpected reply: {:?}", &reply.buf[..reply.len]);
+                None
+            }
+        })
+    }
+
+    /// Sends an empty acknowledgement of the message with `message_id`
+    fn acknowledge(&self, message_id: u16) -> bool {
+        self.send(&[0x60, 0x00, (message_id >> 8) as u8, message_id as u8])
+    }
+
+    fn test_piggybacked(&self) -> bool {
+        self.send(&GET_HELLO)
+            && self.expect(MessageType::Acknowledgement, code::CONTENT, &[0x01], HELLO)
+                == Some(0x0001)
+    }
+
+    fn test_not_found(&self) -> bool {
+        self.send(&GET_MISSING)
+            && self.expect(MessageType::Acknowledgement, code::NOT_FOUND, &[0x02], &[])
+                == Some(0x0002)
+    }
+
+    fn test_separate(&self) -> bool {
+        if !self.send(&GET_SLOW)
+            || self.expect(MessageType::Acknowledgement, code::EMPTY, &[], &[]) != Some(0x0003)
+        {
+            return false;
+        }
+
+        // The slow resource answers later, with a separate response
+        self.wait(SLOW_DELAY_MS);
+        let result = self.slow_request
+            .take()
+            .map_or(ReturnCode::FAIL, |request_id| {
+                self.server.respond(request_id, code::CONTENT, None, SLOW)
+            });
+        if result != ReturnCode::SUCCESS {
+            debug!("Failed to send the separate response: {:?}", result);
+            return false;
+        }
+        let message_id = match self.expect(MessageType::Confirmable, code::CONTENT, &[0x03], SLOW)
+        {
+            Some(message_id) => message_id,
+            None => return false,
+        };
+
+        // The server still answers while the separate response is
+        // outstanding
+        if !self.send(&GET_HELLO_AGAIN)
+            || self.expect(MessageType::Acknowledgement, code::CONTENT, &[0x04], HELLO)
+                != Some(0x0004)
+        {
+            return false;
+        }
+
+        // The separate response is retransmitted until it is acknowledged
+        if self.expect(MessageType::Confirmable, code::CONTENT, &[0x03], SLOW) != Some(message_id)
+        {
+            return false;
+        }
+        self.acknowledge(message_id) && !self.wait(2 * REPLY_TIMEOUT_MS)
+    }
+
+    fn test_duplicate(&self) -> bool {
+        if !self.send(&POST_COUNT)
+            || self.expect(MessageType::Acknowledgement, code::CHANGED, &[0x05], &[])
+                != Some(0x0005)
+        {
+            return false;
+        }
+        // The retransmitted request is only acknowledged
+        self.send(&POST_COUNT)
+            && self.expect(MessageType::Acknowledgement, code::EMPTY, &[], &[]) == Some(0x0005)
+            && self.count.get() == 1
+    }
+
+    fn test_checksum(&self) -> bool {
+        // The datagram is handed to the server's transport as `Sixlowpan`
+        // would after reassembly
+        const PACKET_LEN: usize = IP6_HEADER_SIZE + UDP_HEADER_SIZE + GET_HELLO_AGAIN.len();
+        let mut packet = [0; PACKET_LEN];
+        encode_request(&mut packet, &GET_HELLO_AGAIN);
+        packet[IP6_HEADER_SIZE + 7] ^= 0x01;
+        self.server_udp
+            .receive(&packet, PACKET_LEN as u16, ReturnCode::SUCCESS);
+        if self.wait(REPLY_TIMEOUT_MS) {
+            return false;
+        }
+        packet[IP6_HEADER_SIZE + 7] ^= 0x01;
+        self.server_udp
+            .receive(&packet, PACKET_LEN as u16, ReturnCode::SUCCESS);
+        self.expect(MessageType::Acknowledgement, code::CONTENT, &[0x04], HELLO) == Some(0x0004)
+    }
+}
+
+impl<'a> TransportClient for CoapTest<'a> {
+    fn receive(&self, _src: Endpoint, buf: &[u8]) {
+        self.reply.map(|reply| {
+            reply.len = buf.len();
+            reply.buf[..buf.len()].copy_from_slice(buf);
+        });
+        self.replies.set(self.replies.get() + 1);
+    }
+
+    fn send_done(&self, buf: &'static mut [u8], result: ReturnCode) {
+        if result != ReturnCode::SUCCESS {
+            debug!("Failed to send request: {:?}", result);
+        }
+        self.request.replace(buf);
+    }
+}
+
+impl<'a> CoapResource for CoapTest<'a> {
+    fn matches(&self, request: &Message) -> bool {
+        request.uri_path_matches(b"hello") || request.uri_path_matches(b"slow")
+            || request.uri_path_matches(b"count")
+    }
+
+    fn handle_request(&self, request_id: usize, request: &Message) -> ReturnCode {
+        if request.uri_path_matches(b"hello") {
+            self.server
+                .respond(request_id, code::CONTENT, None, HELLO)
+        } else if request.uri_path_matches(b"slow") {
+            self.slow_request.set(Some(request_id));
+            ReturnCode::SUCCESS
+        } else if request.header.code == code::POST {
+            self.count.set(self.count.get() + 1);
+            self.server.respond(request_id, code::CHANGED, None, &[])
+        } else {
+            ReturnCode::ENOSUPPORT
+        }
+    }
+}
+
+impl<'a> time::Client for CoapTest<'a> {
+    fn fired(&self) {
+        self.timed_out.set(true);
+    }
+}
+
+/// Writes an IPv6 packet carrying `message` from the client to the server
+fn encode_request(packet: &mut [u8], message: &[u8]) {
+    let udp_len = (UDP_HEADER_SIZE + message.len()) as u16;
+    let mut ip6_header = IP6Header::new();
+    ip6_header.set_payload_len(udp_len);
+    ip6_header.set_next_header(ip6_nh::UDP);
+    ip6_header.src_addr = link_local_addr(NODE0_ADDR_LONG);
+    ip6_header.dst_addr = link_local_addr(NODE1_ADDR_LONG);
+    let _ = IP6Header::encode(packet, ip6_header);
+
+    let (header, payload) = packet[IP6_HEADER_SIZE..].split_at_mut(UDP_HEADER_SIZE);
+    u16_to_slice(CLIENT_PORT, &mut header[0..2]);
+    u16_to_slice(COAP_PORT, &mut header[2..4]);
+    u16_to_slice(udp_len, &mut header[4..6]);
+    payload.copy_from_slice(message);
+    let checksum = ip::compute_checksum(
+        &ip6_header.src_addr,
+        &ip6_header.dst_addr,
+        ip6_nh::UDP,
+        udp_len,
+        &header[0..6],
+        payload,
+    );
+    u16_to_slice(checksum, &mut header[6..8]);
+}
@@ -0,0 +1,268 @@
+//! Test how the `Framer` numbers the frames it sends, and discards the frames
+//! it receives twice, between simulated nodes.
+//!
+//! Each node runs a `Framer` on top of an `AwakeMac` with retransmissions.
+//! Nodes 0 and 2 send frames to node 1, and the tests check that:
+//!
+//! - When every acknowledgement from node 1 is lost, node 0 retransmits its
+//!   frame until it gives up, and node 1 passes it up only once.
+//! - A frame from node 2 that has the same sequence number as the last frame
+//!   received from node 0 is not mistaken for a retransmission.
+//! - Once acknowledgements get through again, a new frame from node 0 is
+//!   passed up, with the next sequence number.
+//! - Frames that are prepared before the first of them is sent get
+//!   sequence numbers of their own, and are all passed up.
+//!
+//! The simulation runs on the virtual time of a `SimClock`, so the tests do
+//! not need any radio hardware, but like every runner in `capsules::test`
+//! they run on a board: `boards/imix/src/framer_test.rs` shows how to
+//! instantiate the nodes, and runs the test with `framer_test::run()`.
+
+use core::cell::Cell;
+use ieee802154::device::{MacDevice, RxClient, SecurityError, TxClient};
+use ieee802154::framer::Frame;
+use kernel::ReturnCode;
+use kernel::common::take_cell::TakeCell;
+use kernel::hil::radio::RxInfo;
+use kernel::hil::time::{Frequency, Time};
+use net::ieee802154::*;
+use sim_radio::{LinkParams, SimAlarm, SimClock, SimMedium};
+use test::sim_lowpan::{NODE0_ADDR_LONG, NODE1_ADDR_LONG, PAN};
+
+pub const NODE2_ADDR_LONG: [u8; 8] = [0x00, 0x12, 0x4b, 0x00, 0x00, 0x00, 0x00, 0x03];
+
+const PAYLOAD: [u8; 8] = [0x46, 0x52, 0x41, 0x4d, 0x00, 0x01, 0x02, 0x03];
+
+const LINK: LinkParams = LinkParams {
+    loss_percent: 0,
+    delay_us: 0,
+    rssi: -60,
+    lqi: 200,
+};
+
+/// A link that loses every frame, and so every acknowledgement sent over it
+const LOSSY_LINK: LinkParams = LinkParams {
+    loss_percent: 100,
+    delay_us: 0,
+    rssi: -60,
+    lqi: 200,
+};
+
+/// How long a transmission may take, in milliseconds of virtual time
+const TX_TIMEOUT_MS: u32 = 1_000;
+
+pub struct FramerTest<'a> {
+    clock: &'a SimClock<'a>,
+    medium: &'a SimMedium<'a, SimAlarm<'a>>,
+    mac0: &'a MacDevice<'a>,
+    mac2: &'a MacDevice<'a>,
+    tx_buf1: TakeCell<'static, [u8]>,
+    tx_buf2: TakeCell<'static, [u8]>,
+
+    tx_result: Cell<Option<(ReturnCode, bool)>>,
+    // The frames passed up by node 1, and the source and sequence number of
+    // the last of them
+    rx_count: Cell<usize>,
+    rx_last: Cell<Option<(MacAddress, u8)>>,
+}
+
+impl<'a> FramerTest<'a> {
+    /// `mac0` and `mac2` are the `MacDevice`s of nodes 0 and 2, whose
+    /// extended addresses are `NODE0_ADDR_LONG` and `NODE2_ADDR_LONG`, and
+    /// node 1 has the extended address `NODE1_ADDR_LONG`. The nodes must be
+    /// the first three nodes of `medium`, in that order. The test must be the
+    /// transmit client of nodes 0 and 2, and the receive client of node 1.
+    pub fn new(
+        clock: &'a SimClock<'a>,
+        medium: &'a SimMedium<'a, SimAlarm<'a>>,
+        mac0: &'a MacDevice<'a>,
+        mac2: &'a MacDevice<'a>,
+        tx_buf1: &'static mut [u8],
+        tx_buf2: &'static mut [u8],
+    ) -> FramerTest<'a> {
+        FramerTest {
+            clock: clock,
+            medium: medium,
+            mac0: mac0,
+            mac2: mac2,
+            tx_buf1: TakeCell::new(tx_buf1),
+            tx_buf2: TakeCell::new(tx_buf2),
+            tx_result: Cell::new(None),
+            rx_count: Cell::new(0),
+            rx_last: Cell::new(None),
+        }
+    }
+
+    pub fn run(&self) {
+        debug!("Framer sequence numbers between simulated nodes");
+        self.medium.connect(0, 1, LINK);
+        self.medium.connect(2, 1, LINK);
+
+        let tests: [(&'static str, fn(&FramerTest<'a>) -> bool); 4] = [
+            ("lost acknowledgement", FramerTest::test_lost_ack),
+            ("other sender", FramerTest::test_other_sender),
+            ("new frame", FramerTest::test_new_frame),
+            (
+                "frames prepared together",
+                FramerTest::test_prepared_together,
+            ),
+        ];
+        let mut passed = 0;
+        for &(name, test) in tests.iter() {
+            if test(self) {
+                passed += 1;
+            } else {
+                debug!("Test failed: {}", name);
+            }
+        }
+        debug!("{} of {} tests passed", passed, tests.len());
+    }
+
+    /// Runs the simulation until `done` returns true, the nodes are idle, or
+    /// `timeout_ms` milliseconds have elapsed
+    fn run_until<F: Fn(&FramerTest<'a>) -> bool>(&self, timeout_ms: u32, done: F) {
+        let freq = <<SimAlarm as Time>::Frequency as Frequency>::frequency();
+        let timeout = (freq / 1000) * timeout_ms;
+        let start = self.clock.now();
+        while !done(self) && self.clock.now().wrapping_sub(start) < timeout && self.clock.step() {}
+    }
+
+    /// Prepares a frame carrying `PAYLOAD` from `src_mac` to node 1 in
+    /// `tx_buf`
+    fn prepare(
+        &self,
+        src_mac: &MacDevice<'a>,
+        src_addr: [u8; 8],
+        tx_buf: &TakeCell<'static, [u8]>,
+    ) -> Option<Frame> {
+        let buf = match tx_buf.take() {
+            Some(buf) => buf,
+            None => return None,
+        };
+        match src_mac.prepare_data_frame(
+            buf,
+            PAN,
+            MacAddress::Long(NODE1_ADDR_LONG),
+            PAN,
+            MacAddress::Long(src_addr),
+            None,
+        ) {
+            Ok(mut frame) => {
+                frame.append_payload(&PAYLOAD);
+                Some(frame)
+            }
+            Err(buf) => {
+                tx_buf.replace(buf);
+                None
+            }
+        }
+    }
+
+    /// Sends a frame, and returns the result of its transmission along with
+    /// whether it was acknowledged
+    fn send(&self, src_mac: &MacDevice<'a>, frame: Frame) -> Option<(ReturnCode, bool)> {
+        self.tx_result.set(None);
+        let (rval, buf) = src_mac.transmit(frame);
+        if rval != ReturnCode::SUCCESS {
+            buf.map(|buf| self.return_buf(buf));
+            return Some((rval, false));
+        }
+        self.run_until(TX_TIMEOUT_MS, |t| t.tx_result.get().is_some());
+        self.tx_result.get()
+    }
+
+    /// Sends a frame carrying `PAYLOAD` from `src_mac` to node 1, and returns
+    /// the result of its transmission along with whether it was acknowledged
+    fn prepare_and_send(
+        &self,
+        src_mac: &MacDevice<'a>,
+        src_addr: [u8; 8],
+    ) -> Option<(ReturnCode, bool)> {
+        self.prepare(src_mac, src_addr, &self.tx_buf1)
+            .and_then(|frame| self.send(src_mac, frame))
+    }
+
+    fn return_buf(&self, buf: &'static mut [u8]) {
+        if self.tx_buf1.is_none() {
+            self.tx_buf1.replace(buf);
+        } else {
+            self.tx_buf2.replace(buf);
+        }
+    }
+
+    fn test_lost_ack(&self) -> bool {
+        self.medium.set_link(1, 0, Some(LOSSY_LINK));
+        self.medium.reset_stats();
+        self.rx_count.set(0);
+        let result = self.prepare_and_send(self.mac0, NODE0_ADDR_LONG);
+        self.medium.set_link(1, 0, Some(LINK));
+        // Node 1 received every copy, but only passed up the first
+        result == Some((ReturnCode::SUCCESS, false)) && self.medium.get_stats().received > 1
+            && self.rx_count.get() == 1
+    }
+
+    fn test_other_sender(&self) -> bool {
+        let last_seq = match self.rx_last.get() {
+            Some((_, seq)) => seq,
+            None => return false,
+        };
+        self.rx_count.set(0);
+        // Node 2 has not sent anything yet, so it starts from the same
+        // sequence number as node 0 did
+        let result = self.prepare_and_send(self.mac2, NODE2_ADDR_LONG);
+        result == Some((ReturnCode::SUCCESS, true)) && self.rx_count.get() == 1
+            && self.rx_last.get() == Some((MacAddress::Long(NODE2_ADDR_LONG), last_seq))
+    }
+
+    fn test_new_frame(&self) -> bool {
+        let last_seq = match self.rx_last.get() {
+            Some((_, seq)) => seq,
+            None => return false,
+        };
+        self.rx_count.set(0);
+        let result = self.prepare_and_send(self.mac0, NODE0_ADDR_LONG);
+        result == Some((ReturnCode::SUCCESS, true)) && self.rx_count.get() == 1
+            && self.rx_last.get()
+                == Some((MacAddress::Long(NODE0_ADDR_LONG), last_seq.wrapping_add(1)))
+    }
+
+    fn test_prepared_together(&self) -> bool {
+        self.rx_count.set(0);
+        let frame1 = self.prepare(self.mac0, NODE0_ADDR_LONG, &self.tx_buf1);
+        let frame2 = self.prepare(self.mac0, NODE0_ADDR_LONG, &self.tx_buf2);
+        let (frame1, frame2) = match (frame1, frame2) {
+            (Some(frame1), Some(frame2)) => (frame1, frame2),
+            _ => return false,
+        };
+        let first = self.send(self.mac0, frame1);
+        let first_rx = self.rx_last.get();
+        let second = self.send(self.mac0, frame2);
+        let second_rx = self.rx_last.get();
+        first == Some((ReturnCode::SUCCESS, true)) && second == Some((ReturnCode::SUCCESS, true))
+            && self.rx_count.get() == 2 && first_rx != second_rx
+    }
+}
+
+impl<'a> TxClient for FramerTest<'a> {
+    fn send_done(&self, spi_buf: &'static mut [u8], acked: bool, result: ReturnCode) {
+        self.return_buf(spi_buf);
+        self.tx_result.set(Some((result, acked)));
+    }
+}
+
+impl<'a> RxClient for FramerTest<'a> {
+    fn receive<'b>(
+        &self,
+        _buf: &'b [u8],
+        header: Header<'b>,
+        _info: RxInfo,
+        _data_offset: usize,
+        _data_len: usize,
+    ) {
+        self.rx_count.set(self.rx_count.get() + 1);
+        self.rx_last
+            .set(header.src_addr.and_then(|addr| header.seq.map(|seq| (addr, seq))));
+    }
+
+    fn receive_security_failure<'b>(&self, _header: Header<'b>, _error: SecurityError) {}
+}
@@ -0,0 +1,429 @@
+//! Test the ATT PDU codec and the GATT server with recorded ATT PDUs.
+//!
+//! The PDUs are those of a phone connecting to a heart rate sensor: the
+//! phone exchanges the MTU, discovers the services, characteristics and
+//! descriptors, reads and writes values and enables notifications. The
+//! tests check that:
+//!
+//! - Every recorded PDU is decoded to the expected parameters, consuming the
+//!   whole PDU, and encoded back to the same bytes. Truncated PDUs are
+//!   rejected.
+//! - A `GattServer` with the same database as the sensor (a GAP service with
+//!   the device name, and a heart rate service with a heart rate measurement
+//!   characteristic) answers each recorded request with the recorded
+//!   response, and does not answer commands.
+//! - Writes reach the `GattClient`, notifications are only sent once enabled,
+//!   and configurations are reset when the connection is closed.
+//!
+//! The server sends its responses to a `TestBearer`, which holds them until
+//! they are checked, so the tests do not need a radio. They can be run from
+//! a board's `reset_handler`, as in `boards/imix/src/gatt_test.rs`.
+
+use core::cell::Cell;
+use kernel::ReturnCode;
+use kernel::common::take_cell::TakeCell;
+use net::ble::att::{error, opcode, AttBearer, AttBearerClient, AttPdu, Uuid};
+use net::ble::gatt::{configuration, properties, uuid, GattClient, GattServer};
+
+/// The size of the transmit buffer of the server, which is its ATT_MTU
+pub const TX_BUF_LEN: usize = 64;
+
+const DEVICE_NAME: &'static [u8] = b"Tock";
+const HEART_RATE_SERVICE: u16 = 0x180d;
+const HEART_RATE_MEASUREMENT: u16 = 0x2a37;
+
+/// The handle of the heart rate measurement value in the database
+const MEASUREMENT_VALUE: u16 = 6;
+
+/// Flags (8-bit value, sensor contact detected) and 72 bpm
+const MEASUREMENT: [u8; 2] = [0x06, 0x48];
+
+const MAX_VALUE_LEN: usize = 8;
+
+// (request, response) pairs, where an empty response means that the server
+// must not answer
+const DISCOVERY: [(&'static [u8], &'static [u8]); 7] = [
+    // Exchange MTU: the phone's receive MTU is 158
+    (&[0x02, 0x9e, 0x00], &[0x03, 0x40, 0x00]),
+    // Read By Group Type, primary services
+    (
+        &[0x10, 0x01, 0x00, 0xff, 0xff, 0x00, 0x28],
+        &[
+            0x11, 0x06, 0x01, 0x00, 0x03, 0x00, 0x00, 0x18, 0x04, 0x00, 0x07, 0x00, 0x0d, 0x18,
+        ],
+    ),
+    (
+        &[0x10, 0x08, 0x00, 0xff, 0xff, 0x00, 0x28],
+        &[0x01, 0x10, 0x08, 0x00, 0x0a],
+    ),
+    // Read By Type, characteristic declarations
+    (
+        &[0x08, 0x01, 0x00, 0xff, 0xff, 0x03, 0x28],
+        &[
+            0x09, 0x07, 0x02, 0x00, 0x02, 0x03, 0x00, 0x00, 0x2a, 0x05, 0x00, 0x1a, 0x06, 0x00,
+            0x37, 0x2a,
+        ],
+    ),
+    (
+        &[0x08, 0x07, 0x00, 0xff, 0xff, 0x03, 0x28],
+        &[0x01, 0x08, 0x07, 0x00, 0x0a],
+    ),
+    // Find Information, the descriptors of the heart rate measurement
+    (
+        &[0x04, 0x07, 0x00, 0x07, 0x00],
+        &[0x05, 0x01, 0x07, 0x00, 0x02, 0x29],
+    ),
+    // Find By Type Value, the heart rate service
+    (
+        &[0x06, 0x01, 0x00, 0xff, 0xff, 0x00, 0x28, 0x0d, 0x18],
+        &[0x07, 0x04, 0x00, 0x07, 0x00],
+    ),
+];
+
+const READ_WRITE: [(&'static [u8], &'static [u8]); 9] = [
+    // Read and Read Blob of the device name
+    (&[0x0a, 0x03, 0x00], b"\x0bTock"),
+    (&[0x0c, 0x03, 0x00, 0x02, 0x00], b"\x0dck"),
+    (
+        &[0x0c, 0x03, 0x00, 0x05, 0x00],
+        &[0x01, 0x0c, 0x03, 0x00, 0x07],
+    ),
+    // Read and Read By Type of the heart rate measurement
+    (&[0x0a, 0x06, 0x00], &[0x0b, 0x06, 0x48]),
+    (
+        &[0x08, 0x01, 0x00, 0xff, 0xff, 0x37, 0x2a],
+        &[0x09, 0x04, 0x06, 0x00, 0x06, 0x48],
+    ),
+    // Write Request, to the heart rate measurement and to the device name
+    (&[0x12, 0x06, 0x00, 0x01, 0x02, 0x03], &[0x13]),
+    (&[0x12, 0x03, 0x00, 0x01], &[0x01, 0x12, 0x03, 0x00, 0x03]),
+    // Write Command, which is never answered, and is ignored because the
+    // heart rate measurement does not permit writes without response
+    (&[0x52, 0x06, 0x00, 0x06, 0x48], &[]),
+    (&[0x0a, 0x06, 0x00], &[0x0b, 0x01, 0x02, 0x03]),
+];
+
+const CONFIGURATION: [(&'static [u8], &'static [u8]); 3] = [
+    // Indications are not supported by the heart rate measurement
+    (
+        &[0x12, 0x07, 0x00, 0x02, 0x00],
+        &[0x01, 0x12, 0x07, 0x00, 0xfd],
+    ),
+    (&[0x12, 0x07, 0x00, 0x01, 0x00], &[0x13]),
+    (&[0x0a, 0x07, 0x00], &[0x0b, 0x01, 0x00]),
+];
+
+const ERRORS: [(&'static [u8], &'static [u8]); 5] = [
+    // Read of a handle past the database
+    (&[0x0a, 0x08, 0x00], &[0x01, 0x0a, 0x08, 0x00, 0x01]),
+    // Read By Group Type of secondary services, and of an unsupported type
+    (
+        &[0x10, 0x01, 0x00, 0xff, 0xff, 0x01, 0x28],
+        &[0x01, 0x10, 0x01, 0x00, 0x0a],
+    ),
+    (
+        &[0x10, 0x01, 0x00, 0xff, 0xff, 0x03, 0x28],
+        &[0x01, 0x10, 0x01, 0x00, 0x10],
+    ),
+    // Prepare Write, which is not supported
+    (
+        &[0x16, 0x06, 0x00, 0x00, 0x00, 0x01],
+        &[0x01, 0x16, 0x00, 0x00, 0x06],
+    ),
+    // A truncated Read Request
+    (&[0x0a, 0x06], &[0x01, 0x0a, 0x00, 0x00, 0x04]),
+];
+
+/// An `AttBearer` that holds each PDU sent by the server until it is
+/// checked by the test
+pub struct TestBearer<'a> {
+    client: Cell<Option<&'a AttBearerClient>>,
+    sent: TakeCell<'static, [u8]>,
+    sent_len: Cell<usize>,
+}
+
+impl<'a> TestBearer<'a> {
+    pub fn new() -> TestBearer<'a> {
+        TestBearer {
+            client: Cell::new(None),
+            sent: TakeCell::empty(),
+            sent_len: Cell::new(0),
+        }
+    }
+
+    /// Passes `request` to the server, and checks that it answers with
+    /// `response`, or not at all if `response` is empty
+    fn exchange(&self, request: &[u8], response: &[u8]) -> bool {
+        self.client.get().map(|client| client.receive(request));
+        self.check_sent(response)
+    }
+
+    /// Checks the PDU held, if any, against `expected`, and returns it to the
+    /// server
+    fn check_sent(&self, expected: &[u8]) -> bool {
+        match self.sent.take() {
+            Some(buf) => {
+                let matches = &buf[..self.sent_len.get()] == expected;
+                if !matches {
+                    debug!(
+                        "Expected {:?}, got {:?}",
+                        expected,
+                        &buf[..self.sent_len.get()]
+                    );
+                }
+                self.client
+                    .get()
+                    .map(move |client| client.send_done(buf, ReturnCode::SUCCESS));
+                matches
+            }
+            None => expected.is_empty(),
+        }
+    }
+}
+
+impl<'a> AttBearer<'a> for TestBearer<'a> {
+    fn set_client(&self, client: &'a AttBearerClient) {
+        self.client.set(Some(client));
+    }
+
+    fn send(
+        &self,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if self.sent.is_some() {
+            return Err((ReturnCode::EBUSY, buf));
+        }
+        self.sent_len.set(len);
+        self.sent.replace(buf);
+        Ok(())
+    }
+}
+
+pub struct GattTest<'a> {
+    server: &'a GattServer<'a>,
+    bearer: &'a TestBearer<'a>,
+
+    // The value of the heart rate measurement, owned by the client
+    value: Cell<[u8; MAX_VALUE_LEN]>,
+    value_len: Cell<usize>,
+    configuration: Cell<u16>,
+    notified: Cell<Option<(u16, ReturnCode)>>,
+}
+
+impl<'a> GattTest<'a> {
+    /// `bearer` must be the bearer of `server`, and the test the client of
+    /// `server`
+    pub fn new(server: &'a GattServer<'a>, bearer: &'a TestBearer<'a>) -> GattTest<'a> {
+        let mut value = [0; MAX_VALUE_LEN];
+        value[..MEASUREMENT.len()].copy_from_slice(&MEASUREMENT);
+        GattTest {
+            server: server,
+            bearer: bearer,
+            value: Cell::new(value),
+            value_len: Cell::new(MEASUREMENT.len()),
+            configuration: Cell::new(0),
+            notified: Cell::new(None),
+        }
+    }
+
+    pub fn run(&self) {
+        debug!("ATT codec and GATT server tests");
+        if !self.build_database() {
+            debug!("Failed to build the GATT database");
+            return;
+        }
+        let tests: [(&'static str, fn(&GattTest<'a>) -> bool); 7] = [
+            ("decode", GattTest::test_decode),
+            ("encode", GattTest::test_encode),
+            ("discovery", GattTest::test_discovery),
+            ("reads and writes", GattTest::test_read_write),
+            ("notifications", GattTest::test_notifications),
+            ("errors", GattTest::test_errors),
+            ("disconnection", GattTest::test_disconnection),
+        ];
+        let mut passed = 0;
+        for &(name, test) in tests.iter() {
+            if test(self) {
+                passed += 1;
+            } else {
+                debug!("Test failed: {}", name);
+            }
+        }
+        debug!("{} of {} tests passed", passed, tests.len());
+    }
+
+    fn build_database(&self) -> bool {
+        let gap = self.server.add_service(Uuid::Uuid16(uuid::GAP_SERVICE), 2);
+        let device_name = gap.and_then(|gap| {
+            self.server
+                .add_static_characteristic(gap, Uuid::Uuid16(uuid::DEVICE_NAME), DEVICE_NAME)
+        });
+        let heart_rate = self.server
+            .add_service(Uuid::Uuid16(HEART_RATE_SERVICE), 3);
+        let measurement = heart_rate.and_then(|heart_rate| {
+            self.server.add_characteristic(
+                heart_rate,
+                Uuid::Uuid16(HEART_RATE_MEASUREMENT),
+                properties::READ | properties::WRITE | properties::NOTIFY,
+            )
+        });
+        device_name.is_ok() && measurement == Ok(MEASUREMENT_VALUE)
+    }
+
+    fn exchange_all(&self, pdus: &[(&[u8], &[u8])]) -> bool {
+        pdus.iter()
+            .all(|&(request, response)| self.bearer.exchange(request, response))
+    }
+
+    fn test_decode(&self) -> bool {
+        let decoded = [
+            (&DISCOVERY[0].0[..], AttPdu::ExchangeMtuReq { mtu: 158 }),
+            (
+                &DISCOVERY[1].0[..],
+                AttPdu::ReadByGroupTypeReq {
+                    start: 0x0001,
+                    end: 0xffff,
+                    group_type: Uuid::Uuid16(uuid::PRIMARY_SERVICE),
+                },
+            ),
+            (
+                &DISCOVERY[3].1[..],
+                AttPdu::ReadByTypeRsp {
+                    length: 7,
+                    data: &DISCOVERY[3].1[2..],
+                },
+            ),
+            (
+                &DISCOVERY[6].0[..],
+                AttPdu::FindByTypeValueReq {
+                    start: 0x0001,
+                    end: 0xffff,
+                    attribute_type: uuid::PRIMARY_SERVICE,
+                    value: &[0x0d, 0x18],
+                },
+            ),
+            (
+                &READ_WRITE[2].1[..],
+                AttPdu::ErrorRsp {
+                    request: opcode::READ_BLOB_REQ,
+                    handle: 0x0003,
+                    error: error::INVALID_OFFSET,
+                },
+            ),
+            (
+                &READ_WRITE[7].0[..],
+                AttPdu::WriteCmd {
+                    handle: MEASUREMENT_VALUE,
+                    value: &MEASUREMENT,
+                },
+            ),
+        ];
+        let truncated: [&[u8]; 3] = [
+            &[0x0a, 0x06],
+            &[0x02],
+            &[0x10, 0x01, 0x00, 0xff, 0xff, 0x00],
+        ];
+
+        decoded
+            .iter()
+            .all(|&(pdu, expected)| AttPdu::decode(pdu).done() == Some((pdu.len(), expected)))
+            && truncated
+                .iter()
+                .all(|&pdu| AttPdu::decode(pdu).done().is_none())
+    }
+
+    fn test_encode(&self) -> bool {
+        let recorded = DISCOVERY
+            .iter()
+            .chain(READ_WRITE.iter())
+            .chain(CONFIGURATION.iter());
+        let mut buf = [0 as u8; TX_BUF_LEN];
+        for &(request, response) in recorded {
+            for &pdu in [request, response].iter() {
+                if pdu.is_empty() {
+                    continue;
+                }
+                let reencoded = match AttPdu::decode(pdu).done() {
+                    Some((_, decoded)) => match decoded.encode(&mut buf).done() {
+                        Some((len, _)) => &buf[..len] == pdu,
+                        None => false,
+                    },
+                    None => false,
+                };
+                if !reencoded {
+                    debug!("Failed to decode and encode {:?}", pdu);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    fn test_discovery(&self) -> bool {
+        self.bearer.client.get().map(|client| client.connected());
+        self.exchange_all(&DISCOVERY) && self.server.mtu() == TX_BUF_LEN as u16
+    }
+
+    fn test_read_write(&self) -> bool {
+        // The Write Request sets the value, which the Write Command leaves
+        self.exchange_all(&READ_WRITE[..5])
+            && self.bearer.exchange(READ_WRITE[5].0, READ_WRITE[5].1)
+            && self.value_len.get() == 3 && self.value.get()[..3] == [0x01, 0x02, 0x03]
+            && self.exchange_all(&READ_WRITE[6..])
+    }
+
+    fn test_notifications(&self) -> bool {
+        let disabled = self.server.notify(MEASUREMENT_VALUE, &[0x06, 0x49]) == ReturnCode::EOFF;
+        let configured = self.exchange_all(&CONFIGURATION)
+            && self.configuration.get() == configuration::NOTIFICATIONS
+            && self.server.configuration(MEASUREMENT_VALUE) == Some(configuration::NOTIFICATIONS);
+        let notified = self.server.notify(MEASUREMENT_VALUE, &[0x06, 0x49]) == ReturnCode::SUCCESS
+            && self.bearer.check_sent(&[0x1b, 0x06, 0x00, 0x06, 0x49])
+            && self.notified.get() == Some((MEASUREMENT_VALUE, ReturnCode::SUCCESS));
+        disabled && configured && notified
+    }
+
+    fn test_errors(&self) -> bool {
+        self.exchange_all(&ERRORS)
+    }
+
+    fn test_disconnection(&self) -> bool {
+        self.bearer.client.get().map(|client| client.disconnected());
+        self.configuration.get() == 0
+            && self.server.configuration(MEASUREMENT_VALUE) == Some(0)
+            && self.server.notify(MEASUREMENT_VALUE, &MEASUREMENT) == ReturnCode::EOFF
+    }
+}
+
+impl<'a> GattClient for GattTest<'a> {
+    fn read(&self, _handle: u16, offset: usize, buf: &mut [u8]) -> Result<usize, u8> {
+        let value = self.value.get();
+        let value_len = self.value_len.get();
+        if offset > value_len {
+            return Err(error::INVALID_OFFSET);
+        }
+        let len = ::core::cmp::min(value_len - offset, buf.len());
+        buf[..len].copy_from_slice(&value[offset..offset + len]);
+        Ok(len)
+    }
+
+    fn write(&self, _handle: u16, value: &[u8], _response: bool) -> Result<(), u8> {
+        if value.len() > MAX_VALUE_LEN {
+            return Err(error::INVALID_ATTRIBUTE_VALUE_LENGTH);
+        }
+        let mut stored = [0; MAX_VALUE_LEN];
+        stored[..value.len()].copy_from_slice(value);
+        self.value.set(stored);
+        self.value_len.set(value.len());
+        Ok(())
+    }
+
+    fn configuration_changed(&self, _handle: u16, configuration: u16) {
+        self.configuration.set(configuration);
+    }
+
+    fn notify_done(&self, handle: u16, result: ReturnCode) {
+        self.notified.set(Some((handle, result)));
+    }
+}
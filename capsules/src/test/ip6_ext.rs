@@ -0,0 +1,215 @@
+//! Test the parsing and generation of IPv6 extension headers (RFC 8200), and
+//! their LoWPAN_NHC compression (RFC 6282, Section 4.2).
+//!
+//! The tests check that:
+//!
+//! - Hop-by-Hop and Destination Options headers are compressed to reference
+//!   encodings, with trailing padding elided and the next header carried
+//!   inline when the following header is not compressed, and decompressed
+//!   back to the original packets.
+//! - Unrecognized options are skipped, or cause the packet to be discarded
+//!   with or without an ICMPv6 Parameter Problem message, as their option
+//!   type specifies.
+//! - Source routing headers (RFC 6554) are generated with the common prefix
+//!   of their addresses elided, and processed at each hop of the route.
+//!
+//! The tests do not need any hardware, and can be run from a board's
+//! `reset_handler` with `capsules::test::ip6_ext::run()`.
+
+use net::ip::{check_ext_headers, compute_checksum, encode_options_header,
+              encode_parameter_problem};
+use net::ip::{icmp6_param_problem, ip6_nh, ExtHeaderError, IPAddr, SourceRouteHeader};
+use test::lowpan_util::{check_compress, check_decompress, run_tests, BUF_SIZE};
+
+// Offset of the option type in HBH_UDP
+const OPTION_OFFSET: usize = 42;
+
+pub fn run() {
+    debug!("IPv6 extension header tests");
+    let tests: [(&'static str, fn() -> bool); 6] = [
+        ("compression", test_compression),
+        ("options header", test_encode_options),
+        ("unrecognized options", test_unrecognized_options),
+        ("hop-by-hop position", test_hop_by_hop_position),
+        ("parameter problem", test_parameter_problem),
+        ("source route", test_source_route),
+    ];
+    run_tests(&tests);
+}
+
+fn test_compression() -> bool {
+    check_compress(&HBH_UDP, &HBH_UDP_LOWPAN, false)
+        && check_decompress(&HBH_UDP_LOWPAN, &HBH_UDP)
+        && check_compress(&DST_ICMP, &DST_ICMP_LOWPAN, false)
+        && check_decompress(&DST_ICMP_LOWPAN, &DST_ICMP)
+}
+
+fn test_encode_options() -> bool {
+    let mut buf = [0 as u8; 8];
+    let options: [(u8, &[u8]); 1] = [(0x1e, &[0xab, 0xcd])];
+    match encode_options_header(&mut buf, ip6_nh::UDP, &options).done() {
+        Some((len, _)) => &buf[..len] == &HBH_UDP[40..48],
+        None => false,
+    }
+}
+
+fn test_unrecognized_options() -> bool {
+    let unicast_problem = Err(ExtHeaderError::ParameterProblem(
+        icmp6_param_problem::UNRECOGNIZED_OPTION,
+        OPTION_OFFSET as u32,
+    ));
+    // (option type, multicast destination, expected result)
+    let cases = [
+        (0x1e, false, Ok((ip6_nh::UDP, 48))),
+        (0x5e, false, Err(ExtHeaderError::Discard)),
+        (0x9e, false, unicast_problem),
+        (0x9e, true, unicast_problem),
+        (0xde, false, unicast_problem),
+        (0xde, true, Err(ExtHeaderError::Discard)),
+    ];
+    let mut packet = HBH_UDP;
+    cases.iter().all(|&(option_type, multicast, result)| {
+        packet[OPTION_OFFSET] = option_type;
+        packet[24] = if multicast { 0xff } else { 0xfe };
+        let unknown = check_ext_headers(&packet, &|_, _| false) == result;
+        // Recognized options are always accepted
+        let known = check_ext_headers(&packet, &|_, option_type| option_type == packet[42])
+            == Ok((ip6_nh::UDP, 48));
+        unknown && known
+    })
+}
+
+fn test_hop_by_hop_position() -> bool {
+    // A Hop-by-Hop Options header after the Destination Options header
+    let mut packet = DST_ICMP;
+    packet[40] = ip6_nh::HOP_OPTS;
+    check_ext_headers(&packet, &|_, _| true)
+        == Err(ExtHeaderError::ParameterProblem(
+            icmp6_param_problem::UNRECOGNIZED_NEXT_HEADER,
+            40,
+        ))
+}
+
+fn test_parameter_problem() -> bool {
+    let mut packet = HBH_UDP;
+    packet[OPTION_OFFSET] = 0x9e;
+    let mut src_addr = IPAddr::new();
+    src_addr.0.copy_from_slice(&packet[24..40]);
+    let mut buf = [0 as u8; BUF_SIZE];
+    let len = match encode_parameter_problem(
+        &mut buf,
+        src_addr,
+        64,
+        &packet,
+        icmp6_param_problem::UNRECOGNIZED_OPTION,
+        OPTION_OFFSET as u32,
+    ).done()
+    {
+        Some((len, _)) => len,
+        None => return false,
+    };
+    let header_ok = len == 40 + 8 + packet.len() && buf[6] == ip6_nh::ICMP
+        && &buf[8..24] == &packet[24..40] && &buf[24..40] == &packet[8..24];
+    let message_ok = &buf[40..42] == &[icmp6_param_problem::TYPE, 2]
+        && &buf[44..48] == &[0, 0, 0, OPTION_OFFSET as u8]
+        && &buf[48..len] == &packet[..];
+
+    // Recompute the checksum over the message with a zero checksum field
+    let checksum = ((buf[42] as u16) << 8) | (buf[43] as u16);
+    buf[42] = 0;
+    buf[43] = 0;
+    let mut dst_addr = IPAddr::new();
+    dst_addr.0.copy_from_slice(&packet[8..24]);
+    let expected = compute_checksum(
+        &src_addr,
+        &dst_addr,
+        ip6_nh::ICMP,
+        (len - 40) as u16,
+        &[],
+        &buf[40..len],
+    );
+    header_ok && message_ok && checksum == expected
+}
+
+fn test_source_route() -> bool {
+    let first_hop = addr(1);
+    let route = [addr(2), addr(3)];
+    let mut packet = [0 as u8; 56];
+    packet[..40].copy_from_slice(&HBH_UDP[..40]);
+    packet[6] = ip6_nh::ROUTING;
+    packet[24..40].copy_from_slice(&first_hop.0);
+    let encoded = match SourceRouteHeader::encode(
+        &mut packet[40..],
+        ip6_nh::NO_NEXT,
+        &first_hop,
+        &route,
+    ).done()
+    {
+        Some((len, _)) => len == 16 && &packet[40..56] == &SOURCE_ROUTE,
+        None => false,
+    };
+    let header = match SourceRouteHeader::decode(&packet[40..]).done() {
+        Some((_, header)) => header,
+        None => return false,
+    };
+    let decoded = header.num_addrs == 2 && header.segments_left == 2
+        && header.get_addr(&packet[40..], 1, &first_hop) == addr(3);
+
+    // The first and second hops forward the packet to the next address of
+    // the route, which then holds the addresses it was forwarded through
+    let forwarded = SourceRouteHeader::advance(&mut packet, 40) == Ok(true)
+        && &packet[24..40] == &addr(2).0
+        && SourceRouteHeader::advance(&mut packet, 40) == Ok(true)
+        && &packet[24..40] == &addr(3).0
+        && SourceRouteHeader::advance(&mut packet, 40) == Ok(false);
+    let recorded = header.get_addr(&packet[40..], 0, &addr(3)) == addr(1)
+        && header.get_addr(&packet[40..], 1, &addr(3)) == addr(2)
+        && check_ext_headers(&packet, &|_, _| false) == Ok((ip6_nh::NO_NEXT, 56));
+    encoded && decoded && forwarded && recorded
+}
+
+// Returns the address fd00::<last_byte>
+fn addr(last_byte: u8) -> IPAddr {
+    let mut addr = IPAddr::new();
+    addr.0[0] = 0xfd;
+    addr.0[15] = last_byte;
+    addr
+}
+
+// fe80::212:4b00:0:1 -> fe80::212:4b00:0:2, hop limit 64, with a Hop-by-Hop
+// Options header carrying option 0x1e and a PadN option, followed by UDP
+// 0xf0b1 -> 0xf0b2 with payload "hello"
+static HBH_UDP: [u8; 61] = [
+    0x60, 0x00, 0x00, 0x00, 0x00, 0x15, 0x00, 0x40, 0xfe, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x02, 0x12, 0x4b, 0x00, 0x00, 0x00, 0x00, 0x01, 0xfe, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x02, 0x12, 0x4b, 0x00, 0x00, 0x00, 0x00, 0x02, 0x11, 0x00, 0x1e, 0x02, 0xab, 0xcd, 0x01, 0x00,
+    0xf0, 0xb1, 0xf0, 0xb2, 0x00, 0x0d, 0x43, 0x74, 0x68, 0x65, 0x6c, 0x6c, 0x6f,
+];
+
+// The Hop-by-Hop NHC byte with NH = 1 and the length of the option without
+// its padding, followed by the UDP header compressed as in the UDP tests
+static HBH_UDP_LOWPAN: [u8; 17] = [
+    0x7e, 0x33, 0xe1, 0x04, 0x1e, 0x02, 0xab, 0xcd, 0xf3, 0x12, 0x43, 0x74, 0x68, 0x65, 0x6c, 0x6c,
+    0x6f,
+];
+
+// A Destination Options header holding only padding, followed by an ICMPv6
+// echo request, which is not compressed
+static DST_ICMP: [u8; 56] = [
+    0x60, 0x00, 0x00, 0x00, 0x00, 0x10, 0x3c, 0x40, 0xfe, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x02, 0x12, 0x4b, 0x00, 0x00, 0x00, 0x00, 0x01, 0xfe, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x02, 0x12, 0x4b, 0x00, 0x00, 0x00, 0x00, 0x02, 0x3a, 0x00, 0x01, 0x04, 0x00, 0x00, 0x00, 0x00,
+    0x80, 0x00, 0xe8, 0x91, 0x00, 0x01, 0x00, 0x01,
+];
+
+// The Destination Options NHC byte with NH = 0, the inline next header and a
+// zero length, as the padding is elided
+static DST_ICMP_LOWPAN: [u8; 13] = [
+    0x7e, 0x33, 0xe6, 0x3a, 0x00, 0x80, 0x00, 0xe8, 0x91, 0x00, 0x01, 0x00, 0x01,
+];
+
+// Source route through fd00::2 to fd00::3 with 15 bytes of each address
+// elided, two segments left and 6 bytes of padding
+static SOURCE_ROUTE: [u8; 16] = [
+    0x3b, 0x01, 0x03, 0x02, 0xff, 0x60, 0x00, 0x00, 0x02, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
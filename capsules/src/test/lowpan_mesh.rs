@@ -0,0 +1,300 @@
+//! Test 6LoWPAN mesh-under forwarding between four simulated nodes in a line.
+//!
+//! Node `i` only hears nodes `i - 1` and `i + 1`, and routes every frame
+//! towards its final destination through the neighbor on that side. The
+//! tests check that:
+//!
+//! - Mesh Addressing headers with short and long addresses and with an
+//!   extended hops left count are encoded to the bytes of RFC 4944 and
+//!   decoded back, and that truncated headers are rejected.
+//! - A packet from node 0 to node 3 is forwarded by nodes 1 and 2, which do
+//!   not deliver it themselves, and reaches node 3 exactly once.
+//! - Each forwarder decrements the hops left count: a packet from node 3,
+//!   which sends with a count of 2, is dropped by node 1 before it reaches
+//!   node 0.
+//! - A broadcast from node 0 is flooded to every other node, and each of them
+//!   delivers it exactly once even though it hears the rebroadcasts of both
+//!   of its neighbors. A second broadcast, with the next sequence number, is
+//!   delivered again.
+//!
+//! The simulation runs on the virtual time of a `SimClock` over a lossless
+//! `SimMedium`, so the tests do not need any radio hardware.
+//! `boards/imix/src/lowpan_mesh_test.rs` shows how to instantiate the nodes,
+//! and runs the test with `lowpan_mesh_test::run()`.
+
+use core::cell::Cell;
+use ieee802154::device::SecurityError;
+use kernel::ReturnCode;
+use kernel::common::take_cell::TakeCell;
+use kernel::hil::time::{Frequency, Time};
+use net::ieee802154::MacAddress;
+use net::ip::{IP6Header, IPAddr};
+use net::sixlowpan::{MeshHeader, MeshRouter, Sixlowpan, SixlowpanClient};
+use net::sixlowpan_compression::Context;
+use sim_radio::{LinkParams, SimAlarm, SimClock, SimMedium};
+use test::sim_lowpan::link_local_addr;
+
+pub const NUM_NODES: usize = 4;
+
+pub const NODE_ADDRS_LONG: [[u8; 8]; NUM_NODES] = [
+    [0x00, 0x12, 0x4b, 0x00, 0x00, 0x00, 0x00, 0x01],
+    [0x00, 0x12, 0x4b, 0x00, 0x00, 0x00, 0x00, 0x02],
+    [0x00, 0x12, 0x4b, 0x00, 0x00, 0x00, 0x00, 0x03],
+    [0x00, 0x12, 0x4b, 0x00, 0x00, 0x00, 0x00, 0x04],
+];
+
+/// The hops left count each node sends its packets with. Three hops are
+/// needed to cross the line.
+pub const HOPS_LEFT: [u8; NUM_NODES] = [3, 3, 3, 2];
+
+pub const PACKET_LEN: usize = 60;
+
+const IP6_HDR_SIZE: usize = 40;
+
+/// The all-nodes link-local multicast address, ff02::1
+const ALL_NODES: IPAddr = IPAddr([0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01]);
+
+/// How long a packet may take to cross the line, in milliseconds of virtual
+/// time
+const TIMEOUT_MS: u32 = 1000;
+
+// Mesh Addressing headers, with the header they decode to
+const SHORT_ADDRS: [u8; 5] = [0xb5, 0x12, 0x34, 0xff, 0xff];
+const LONG_ADDRS: [u8; 17] = [
+    0x83, 0x00, 0x12, 0x4b, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x12, 0x4b, 0x00, 0x00, 0x00, 0x00,
+    0x04,
+];
+const EXTENDED_HOPS_LEFT: [u8; 12] = [
+    0xaf, 0x14, 0x12, 0x34, 0x00, 0x12, 0x4b, 0x00, 0x00, 0x00, 0x00, 0x04,
+];
+
+/// A node of the line, which receives the packets of its `Sixlowpan` layer
+/// and routes its frames
+pub struct MeshNode<'a> {
+    index: usize,
+    sixlowpan: &'a Sixlowpan<'a, SimAlarm<'a>, Context>,
+    packet: TakeCell<'static, [u8]>,
+    received: Cell<usize>,
+    send_result: Cell<Option<ReturnCode>>,
+}
+
+impl<'a> MeshNode<'a> {
+    /// Node `index` of the line. `packet` is a buffer of `PACKET_LEN` bytes.
+    /// The node must be the client and the mesh router of `sixlowpan`.
+    pub fn new(
+        index: usize,
+        sixlowpan: &'a Sixlowpan<'a, SimAlarm<'a>, Context>,
+        packet: &'static mut [u8],
+    ) -> MeshNode<'a> {
+        MeshNode {
+            index: index,
+            sixlowpan: sixlowpan,
+            packet: TakeCell::new(packet),
+            received: Cell::new(0),
+            send_result: Cell::new(None),
+        }
+    }
+
+    fn addr_long(&self) -> [u8; 8] {
+        NODE_ADDRS_LONG[self.index]
+    }
+
+    /// Sends a packet to node `dst`, or to all nodes if `None`
+    fn send(&self, dst: Option<usize>) -> bool {
+        let (dst_addr, dst_mac_addr) = match dst {
+            Some(dst) => (
+                link_local_addr(NODE_ADDRS_LONG[dst]),
+                MacAddress::Long(NODE_ADDRS_LONG[dst]),
+            ),
+            None => (ALL_NODES, MacAddress::Short(0xffff)),
+        };
+        self.packet.take().map_or(false, |packet| {
+            let mut ip6_header = IP6Header::new();
+            ip6_header.set_payload_len((PACKET_LEN - IP6_HDR_SIZE) as u16);
+            ip6_header.src_addr = link_local_addr(self.addr_long());
+            ip6_header.dst_addr = dst_addr;
+            let _ = IP6Header::encode(packet, ip6_header);
+            for (i, byte) in packet[IP6_HDR_SIZE..PACKET_LEN].iter_mut().enumerate() {
+                *byte = i as u8;
+            }
+
+            self.send_result.set(None);
+            match self.sixlowpan.transmit_packet(
+                MacAddress::Long(self.addr_long()),
+                dst_mac_addr,
+                packet,
+                PACKET_LEN,
+                None,
+            ) {
+                Ok(()) => true,
+                Err((result, packet)) => {
+                    debug!("Node {} failed to send: {:?}", self.index, result);
+                    self.packet.replace(packet);
+                    false
+                }
+            }
+        })
+    }
+}
+
+impl<'a> SixlowpanClient for MeshNode<'a> {
+    fn receive<'b>(&self, _buf: &'b [u8], len: u16, result: ReturnCode) {
+        if result == ReturnCode::SUCCESS && len as usize == PACKET_LEN {
+            self.received.set(self.received.get() + 1);
+        }
+    }
+
+    fn send_done(&self, buf: &'static mut [u8], _acked: bool, result: ReturnCode) {
+        self.packet.replace(buf);
+        self.send_result.set(Some(result));
+    }
+
+    fn receive_security_failure(&self, _: Option<MacAddress>, _: SecurityError) {}
+}
+
+impl<'a> MeshRouter for MeshNode<'a> {
+    fn next_hop(&self, final_dst: MacAddress) -> Option<MacAddress> {
+        let dst = NODE_ADDRS_LONG
+            .iter()
+            .position(|&addr| final_dst == MacAddress::Long(addr))?;
+        let next = if dst > self.index {
+            self.index + 1
+        } else if dst < self.index {
+            self.index - 1
+        } else {
+            return None;
+        };
+        Some(MacAddress::Long(NODE_ADDRS_LONG[next]))
+    }
+}
+
+pub struct MeshTest<'a> {
+    clock: &'a SimClock<'a>,
+    medium: &'a SimMedium<'a, SimAlarm<'a>>,
+    nodes: [&'a MeshNode<'a>; NUM_NODES],
+}
+
+impl<'a> MeshTest<'a> {
+    /// `nodes` must be attached to `medium` in order, so that node `i` is
+    /// node `i` of the medium.
+    pub fn new(
+        clock: &'a SimClock<'a>,
+        medium: &'a SimMedium<'a, SimAlarm<'a>>,
+        nodes: [&'a MeshNode<'a>; NUM_NODES],
+    ) -> MeshTest<'a> {
+        MeshTest {
+            clock: clock,
+            medium: medium,
+            nodes: nodes,
+        }
+    }
+
+    pub fn run(&self) {
+        debug!("6LoWPAN mesh-under forwarding over a line of simulated nodes");
+        for i in 0..NUM_NODES - 1 {
+            self.medium.connect(
+                i,
+                i + 1,
+                LinkParams {
+                    loss_percent: 0,
+                    delay_us: 0,
+                    rssi: -60,
+                    lqi: 255,
+                },
+            );
+        }
+        let tests: [(&'static str, fn(&MeshTest<'a>) -> bool); 4] = [
+            ("mesh header", MeshTest::test_mesh_header),
+            ("forwarding", MeshTest::test_forwarding),
+            ("hops left", MeshTest::test_hops_left),
+            ("broadcast", MeshTest::test_broadcast),
+        ];
+        let mut passed = 0;
+        for &(name, test) in tests.iter() {
+            if test(self) {
+                passed += 1;
+            } else {
+                debug!("Test failed: {}", name);
+            }
+        }
+        debug!("{} of {} tests passed", passed, tests.len());
+    }
+
+    /// Sends a packet from node `src`, runs the simulation until the network
+    /// is idle, and returns the number of packets each node received.
+    fn exchange(&self, src: usize, dst: Option<usize>) -> Option<[usize; NUM_NODES]> {
+        for node in self.nodes.iter() {
+            node.received.set(0);
+        }
+        if !self.nodes[src].send(dst) {
+            return None;
+        }
+        let freq = <<SimAlarm as Time>::Frequency as Frequency>::frequency();
+        let timeout = (freq / 1000) * TIMEOUT_MS;
+        let start = self.clock.now();
+        while self.clock.now().wrapping_sub(start) < timeout && self.clock.step() {}
+
+        if self.nodes[src].send_result.get() != Some(ReturnCode::SUCCESS) {
+            debug!("Node {} failed to send its packet", src);
+            return None;
+        }
+        let mut received = [0; NUM_NODES];
+        for (count, node) in received.iter_mut().zip(self.nodes.iter()) {
+            *count = node.received.get();
+        }
+        Some(received)
+    }
+
+    fn test_mesh_header(&self) -> bool {
+        let node0 = MacAddress::Long(NODE_ADDRS_LONG[0]);
+        let node3 = MacAddress::Long(NODE_ADDRS_LONG[3]);
+        let headers: [(&[u8], MeshHeader); 3] = [
+            (
+                &SHORT_ADDRS,
+                MeshHeader {
+                    hops_left: 5,
+                    originator: MacAddress::Short(0x1234),
+                    final_dst: MacAddress::Short(0xffff),
+                },
+            ),
+            (
+                &LONG_ADDRS,
+                MeshHeader {
+                    hops_left: 3,
+                    originator: node0,
+                    final_dst: node3,
+                },
+            ),
+            (
+                &EXTENDED_HOPS_LEFT,
+                MeshHeader {
+                    hops_left: 20,
+                    originator: MacAddress::Short(0x1234),
+                    final_dst: node3,
+                },
+            ),
+        ];
+        headers.iter().all(|&(bytes, header)| {
+            let mut buf = [0; 20];
+            let encoded = header.encode(&mut buf).done() == Some((bytes.len(), ()))
+                && &buf[..bytes.len()] == bytes;
+            let decoded = MeshHeader::decode(bytes).done() == Some((bytes.len(), header));
+            let truncated = MeshHeader::decode(&bytes[..bytes.len() - 1])
+                .done()
+                .is_none();
+            encoded && decoded && truncated
+        })
+    }
+
+    fn test_forwarding(&self) -> bool {
+        self.exchange(0, Some(3)) == Some([0, 0, 0, 1])
+    }
+
+    fn test_hops_left(&self) -> bool {
+        self.exchange(3, Some(0)) == Some([0, 0, 0, 0])
+    }
+
+    fn test_broadcast(&self) -> bool {
+        self.exchange(0, None) == Some([0, 1, 1, 1]) && self.exchange(0, None) == Some([0, 1, 1, 1])
+    }
+}
@@ -0,0 +1,435 @@
+//! Test the reception of 6LoWPAN packets: the handling of their UDP
+//! checksums (RFC 6282, Section 4.3.2) and of their extension headers.
+//!
+//! The test takes the place of the Mac layer below the `Sixlowpan` layer of
+//! node 1, and passes it packets, whole or in two fragments, as if they had
+//! been received from node 0 in secured or unsecured frames. It checks that:
+//!
+//! - A checksum carried inline is delivered unchanged, even if it is zero.
+//! - A checksum elided from the first fragment is computed once the packet is
+//!   reassembled, when every fragment was protected by a MIC.
+//! - A packet with an elided checksum is dropped if one of its fragments was
+//!   received without a MIC, even if the first fragment was protected.
+//! - An unrecognized option in a Hop-by-Hop Options header is skipped, or
+//!   causes the packet to be discarded, as its option type specifies. When
+//!   it requires it, node 1 sends an ICMPv6 Parameter Problem message, which
+//!   node 0 receives over a `SimMedium`.
+//! - A frame dropped by the Mac layer because it failed the security
+//!   procedure is reported with `receive_security_failure`, along with its
+//!   source, and not as a received packet.
+//!
+//! The compressed headers are produced by `compress`, whose encodings are
+//! checked against reference packets by `test::udp_nhc` and
+//! `test::ip6_ext`. The test does not need any radio hardware:
+//! `boards/imix/src/lowpan_rx_test.rs` shows how to instantiate the two
+//! nodes, and runs the test with `lowpan_rx_test::run()`.
+
+use core::cell::Cell;
+use ieee802154::device::{RxClient, SecurityError};
+use kernel::ReturnCode;
+use kernel::common::take_cell::TakeCell;
+use kernel::hil::radio::RxInfo;
+use kernel::hil::time::{Frequency, Time};
+use net::ieee802154::{FrameType, FrameVersion, Header, KeyId, MacAddress, Security,
+                      SecurityLevel};
+use net::ip::{self, icmp6_param_problem, ip6_nh, IP6Header};
+use net::sixlowpan::{lowpan_frag, Sixlowpan, SixlowpanClient};
+use net::sixlowpan_compression::{self, Context};
+use net::util::{slice_to_u16, u16_to_slice};
+use sim_radio::{LinkParams, SimAlarm, SimClock, SimMedium};
+use test::sim_lowpan::{link_local_addr, NODE0_ADDR_LONG, NODE1_ADDR_LONG, PAN};
+
+/// The length of the buffer in which received packets are recorded
+pub const BUF_LEN: usize = 128;
+
+/// The length of the ICMPv6 error messages that node 1 can send
+pub const ICMP_ERROR_LEN: usize = 128;
+
+const IP6_HDR_SIZE: usize = 40;
+const HBH_HDR_SIZE: usize = 8;
+const UDP_HDR_SIZE: usize = 8;
+
+// The payload lengths of the fragmented packets, and of the packets with a
+// Hop-by-Hop Options header
+const PAYLOAD_LEN: usize = 64;
+const HBH_PAYLOAD_LEN: usize = 16;
+
+const PACKET_LEN: usize = IP6_HDR_SIZE + UDP_HDR_SIZE + PAYLOAD_LEN;
+const HBH_PACKET_LEN: usize = IP6_HDR_SIZE + HBH_HDR_SIZE + UDP_HDR_SIZE + HBH_PAYLOAD_LEN;
+
+// Offset of the option type in the packets with a Hop-by-Hop Options header
+const OPTION_OFFSET: usize = IP6_HDR_SIZE + 2;
+
+// Both ports can be compressed to 4 bits
+const SRC_PORT: u16 = 0xf0b1;
+const DST_PORT: u16 = 0xf0b2;
+
+/// The number of payload bytes carried by the first fragment. The headers
+/// and these bytes make up a multiple of 8 bytes once decompressed, as the
+/// offsets of the following fragments require.
+const FRAG1_PAYLOAD_LEN: usize = 16;
+
+const FRAME_SIZE: usize = 127;
+
+/// How long an ICMPv6 message may take to reach node 0, in milliseconds of
+/// virtual time
+const TIMEOUT_MS: u32 = 1000;
+
+pub struct LowpanRxTest<'a> {
+    clock: &'a SimClock<'a>,
+    medium: &'a SimMedium<'a, SimAlarm<'a>>,
+    sixlowpan: &'a Sixlowpan<'a, SimAlarm<'a>, Context>,
+    dgram_tag: Cell<u16>,
+
+    // The last packet delivered by the `Sixlowpan` layer
+    received: TakeCell<'static, [u8]>,
+    received_len: Cell<usize>,
+    result: Cell<Option<ReturnCode>>,
+    security_failure: Cell<Option<(Option<MacAddress>, SecurityError)>>,
+}
+
+impl<'a> LowpanRxTest<'a> {
+    /// `sixlowpan` is the `Sixlowpan` layer of node 1, whose Mac layer has
+    /// the extended address `NODE1_ADDR_LONG`, and which must have ICMPv6
+    /// errors enabled with a buffer of `ICMP_ERROR_LEN` bytes. Node 0 and
+    /// node 1 must be the first two nodes of `medium`, in that order, and the
+    /// test the client of both `Sixlowpan` layers. `received` is a buffer of
+    /// `BUF_LEN` bytes.
+    pub fn new(
+        clock: &'a SimClock<'a>,
+        medium: &'a SimMedium<'a, SimAlarm<'a>>,
+        sixlowpan: &'a Sixlowpan<'a, SimAlarm<'a>, Context>,
+        received: &'static mut [u8],
+    ) -> LowpanRxTest<'a> {
+        LowpanRxTest {
+            clock: clock,
+            medium: medium,
+            sixlowpan: sixlowpan,
+            dgram_tag: Cell::new(0),
+            received: TakeCell::new(received),
+            received_len: Cell::new(0),
+            result: Cell::new(None),
+            security_failure: Cell::new(None),
+        }
+    }
+
+    pub fn run(&self) {
+        debug!("6LoWPAN reception of UDP checksums and extension headers");
+        self.medium.connect(
+            0,
+            1,
+            LinkParams {
+                loss_percent: 0,
+                delay_us: 0,
+                rssi: -60,
+                lqi: 255,
+            },
+        );
+        let tests: [(&'static str, fn(&LowpanRxTest<'a>) -> bool); 7] = [
+            ("inline zero checksum", LowpanRxTest::test_inline_zero_checksum),
+            ("elided checksum", LowpanRxTest::test_elided_checksum),
+            ("unprotected fragment", LowpanRxTest::test_unprotected_fragment),
+            ("skipped option", LowpanRxTest::test_skipped_option),
+            ("discarded option", LowpanRxTest::test_discarded_option),
+            ("parameter problem", LowpanRxTest::test_parameter_problem),
+            ("security failure", LowpanRxTest::test_security_failure),
+        ];
+        let mut passed = 0;
+        for &(name, test) in tests.iter() {
+            if test(self) {
+                passed += 1;
+            } else {
+                debug!("Test failed: {}", name);
+            }
+        }
+        debug!("{} of {} tests passed", passed, tests.len());
+    }
+
+    /// Passes `frame` to the `Sixlowpan` layer as the payload of a data frame
+    /// from node 0 to node 1, secured with a MIC if `secured` is true.
+    fn deliver(&self, frame: &[u8], secured: bool) {
+        let info = RxInfo {
+            rssi: -60,
+            lqi: 255,
+            timestamp: None,
+        };
+        RxClient::receive(self.sixlowpan, frame, header(secured), info, 0, frame.len());
+    }
+
+    /// Compresses `packet`, eliding its UDP checksum if `elide_checksum` is
+    /// true, and passes it to the `Sixlowpan` layer in two fragments, which
+    /// are secured as given by `secured`. Returns the result with which the
+    /// packet was delivered, if it was.
+    fn receive_fragmented(
+        &self,
+        packet: &[u8],
+        elide_checksum: bool,
+        secured: [bool; 2],
+    ) -> Option<ReturnCode> {
+        let mut lowpan = [0 as u8; FRAME_SIZE];
+        let (consumed, written) = sixlowpan_compression::compress(
+            &context(),
+            packet,
+            MacAddress::Long(NODE0_ADDR_LONG),
+            MacAddress::Long(NODE1_ADDR_LONG),
+            &mut lowpan,
+            elide_checksum,
+        ).ok()?;
+        let frag1_end = consumed + FRAG1_PAYLOAD_LEN;
+        let dgram_tag = self.dgram_tag.get().wrapping_add(1);
+        self.dgram_tag.set(dgram_tag);
+        self.result.set(None);
+
+        let mut frame = [0 as u8; FRAME_SIZE];
+        u16_to_slice(packet.len() as u16, &mut frame[0..2]);
+        frame[0] |= lowpan_frag::FRAG1_HDR;
+        u16_to_slice(dgram_tag, &mut frame[2..4]);
+        let mut len = lowpan_frag::FRAG1_HDR_SIZE;
+        frame[len..len + written].copy_from_slice(&lowpan[..written]);
+        len += written;
+        frame[len..len + FRAG1_PAYLOAD_LEN].copy_from_slice(&packet[consumed..frag1_end]);
+        len += FRAG1_PAYLOAD_LEN;
+        self.deliver(&frame[..len], secured[0]);
+
+        u16_to_slice(packet.len() as u16, &mut frame[0..2]);
+        frame[0] |= lowpan_frag::FRAGN_HDR;
+        u16_to_slice(dgram_tag, &mut frame[2..4]);
+        frame[4] = (frag1_end / 8) as u8;
+        let rest = &packet[frag1_end..];
+        len = lowpan_frag::FRAGN_HDR_SIZE + rest.len();
+        frame[lowpan_frag::FRAGN_HDR_SIZE..len].copy_from_slice(rest);
+        self.deliver(&frame[..len], secured[1]);
+
+        self.result.get()
+    }
+
+    /// Compresses `packet` and passes it to the `Sixlowpan` layer in a single
+    /// unsecured frame. Returns the result with which the packet was
+    /// delivered, if it was.
+    fn receive_whole(&self, packet: &[u8]) -> Option<ReturnCode> {
+        let mut frame = [0 as u8; FRAME_SIZE];
+        let (consumed, written) = sixlowpan_compression::compress(
+            &context(),
+            packet,
+            MacAddress::Long(NODE0_ADDR_LONG),
+            MacAddress::Long(NODE1_ADDR_LONG),
+            &mut frame,
+            false,
+        ).ok()?;
+        let len = written + packet.len() - consumed;
+        frame[written..len].copy_from_slice(&packet[consumed..]);
+        self.result.set(None);
+        self.deliver(&frame[..len], false);
+        self.result.get()
+    }
+
+    /// Runs the simulation until the nodes are idle, and returns the result
+    /// with which a packet was received in the meantime, if one was.
+    fn run_until_idle(&self) -> Option<ReturnCode> {
+        self.result.set(None);
+        let freq = <<SimAlarm as Time>::Frequency as Frequency>::frequency();
+        let timeout = (freq / 1000) * TIMEOUT_MS;
+        let start = self.clock.now();
+        while self.clock.now().wrapping_sub(start) < timeout && self.clock.step() {}
+        self.result.get()
+    }
+
+    /// Returns true if the last packet was delivered successfully and is
+    /// identical to `packet`
+    fn received_intact(&self, packet: &[u8]) -> bool {
+        self.result.get() == Some(ReturnCode::SUCCESS)
+            && self.received
+                .map_or(false, |buf| &buf[..self.received_len.get()] == packet)
+    }
+
+    fn test_inline_zero_checksum(&self) -> bool {
+        let mut packet = [0 as u8; PACKET_LEN];
+        encode_packet(&mut packet, Some(0));
+        self.receive_fragmented(&packet, false, [false, false]).is_some()
+            && self.received_intact(&packet)
+    }
+
+    fn test_elided_checksum(&self) -> bool {
+        let mut packet = [0 as u8; PACKET_LEN];
+        encode_packet(&mut packet, None);
+        let checksum = slice_to_u16(&packet[IP6_HDR_SIZE + 6..IP6_HDR_SIZE + 8]);
+        checksum != 0 && self.receive_fragmented(&packet, true, [true, true]).is_some()
+            && self.received_intact(&packet)
+    }
+
+    fn test_unprotected_fragment(&self) -> bool {
+        let mut packet = [0 as u8; PACKET_LEN];
+        encode_packet(&mut packet, None);
+        self.receive_fragmented(&packet, true, [true, false]) == Some(ReturnCode::FAIL)
+    }
+
+    fn test_skipped_option(&self) -> bool {
+        let mut packet = [0 as u8; HBH_PACKET_LEN];
+        encode_hbh_packet(&mut packet, 0x1e);
+        self.receive_whole(&packet).is_some() && self.received_intact(&packet)
+            && self.run_until_idle().is_none()
+    }
+
+    fn test_discarded_option(&self) -> bool {
+        let mut packet = [0 as u8; HBH_PACKET_LEN];
+        encode_hbh_packet(&mut packet, 0x5e);
+        // Neither delivered nor answered
+        self.receive_whole(&packet).is_none() && self.run_until_idle().is_none()
+    }
+
+    fn test_parameter_problem(&self) -> bool {
+        let mut packet = [0 as u8; HBH_PACKET_LEN];
+        encode_hbh_packet(&mut packet, 0x9e);
+        if self.receive_whole(&packet).is_some()
+            || self.run_until_idle() != Some(ReturnCode::SUCCESS)
+        {
+            return false;
+        }
+        // The message from node 1 to node 0 includes the whole packet
+        let len = IP6_HDR_SIZE + 8 + HBH_PACKET_LEN;
+        let pointer = [0, 0, 0, OPTION_OFFSET as u8];
+        self.received.map_or(false, |buf| {
+            self.received_len.get() == len && buf[6] == ip6_nh::ICMP
+                && &buf[8..24] == &packet[24..40] && &buf[24..40] == &packet[8..24]
+                && buf[40] == icmp6_param_problem::TYPE
+                && buf[41] == icmp6_param_problem::UNRECOGNIZED_OPTION
+                && &buf[44..48] == &pointer && &buf[48..len] == &packet[..]
+        })
+    }
+
+    fn test_security_failure(&self) -> bool {
+        self.result.set(None);
+        self.security_failure.set(None);
+        RxClient::receive_security_failure(self.sixlowpan, header(true), SecurityError::MicFailure);
+        let src_addr = Some(MacAddress::Long(NODE0_ADDR_LONG));
+        self.result.get().is_none()
+            && self.security_failure.get() == Some((src_addr, SecurityError::MicFailure))
+    }
+}
+
+impl<'a> SixlowpanClient for LowpanRxTest<'a> {
+    fn receive<'b>(&self, buf: &'b [u8], len: u16, result: ReturnCode) {
+        self.result.set(Some(result));
+        let len = len as usize;
+        self.received.map(|received| {
+            if result == ReturnCode::SUCCESS && len <= received.len() {
+                received[..len].copy_from_slice(&buf[..len]);
+                self.received_len.set(len);
+            } else {
+                self.received_len.set(0);
+            }
+        });
+    }
+
+    fn send_done(&self, _buf: &'static mut [u8], _acked: bool, _result: ReturnCode) {}
+
+    fn receive_security_failure(&self, src_addr: Option<MacAddress>, error: SecurityError) {
+        self.security_failure.set(Some((src_addr, error)));
+    }
+}
+
+// The context of the receiving node, which only derives link-local addresses
+fn context() -> Context {
+    Context {
+        prefix: [0; 16],
+        prefix_len: 0,
+        id: 0,
+        compress: false,
+    }
+}
+
+/// The MAC header of a data frame from node 0 to node 1, secured with a MIC
+/// if `secured` is true
+fn header<'b>(secured: bool) -> Header<'b> {
+    let security = if secured {
+        Some(Security {
+            level: SecurityLevel::Mic32,
+            asn_in_nonce: false,
+            frame_counter: Some(0),
+            key_id: KeyId::Implicit,
+        })
+    } else {
+        None
+    };
+    Header {
+        frame_type: FrameType::Data,
+        frame_pending: false,
+        ack_requested: true,
+        version: FrameVersion::V2006,
+        seq: Some(0),
+        dst_pan: Some(PAN),
+        dst_addr: Some(MacAddress::Long(NODE1_ADDR_LONG)),
+        src_pan: Some(PAN),
+        src_addr: Some(MacAddress::Long(NODE0_ADDR_LONG)),
+        security: security,
+        header_ies: Default::default(),
+        header_ies_len: 0,
+        payload_ies: Default::default(),
+        payload_ies_len: 0,
+    }
+}
+
+/// Writes a UDP packet from node 0 to node 1 to `buf`, with a Hop-by-Hop
+/// Options header carrying a two-byte option of type `option_type`
+fn encode_hbh_packet(buf: &mut [u8], option_type: u8) {
+    let udp_len = (UDP_HDR_SIZE + HBH_PAYLOAD_LEN) as u16;
+    let mut ip6_header = IP6Header::new();
+    ip6_header.set_payload_len((HBH_HDR_SIZE as u16) + udp_len);
+    ip6_header.set_next_header(ip6_nh::HOP_OPTS);
+    ip6_header.set_hop_limit(64);
+    ip6_header.src_addr = link_local_addr(NODE0_ADDR_LONG);
+    ip6_header.dst_addr = link_local_addr(NODE1_ADDR_LONG);
+    let _ = IP6Header::encode(buf, ip6_header);
+    let options: [(u8, &[u8]); 1] = [(option_type, &[0xab, 0xcd])];
+    let _ = ip::encode_options_header(&mut buf[IP6_HDR_SIZE..], ip6_nh::UDP, &options);
+
+    let udp_start = IP6_HDR_SIZE + HBH_HDR_SIZE;
+    let (header, payload) = buf[udp_start..HBH_PACKET_LEN].split_at_mut(UDP_HDR_SIZE);
+    u16_to_slice(SRC_PORT, &mut header[0..2]);
+    u16_to_slice(DST_PORT, &mut header[2..4]);
+    u16_to_slice(udp_len, &mut header[4..6]);
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    let checksum = ip::compute_checksum(
+        &ip6_header.src_addr,
+        &ip6_header.dst_addr,
+        ip6_nh::UDP,
+        udp_len,
+        &header[0..6],
+        payload,
+    );
+    u16_to_slice(checksum, &mut header[6..8]);
+}
+
+/// Writes a UDP packet from node 0 to node 1 to `buf`, with the checksum
+/// `checksum`, or with its correct checksum if `None`
+fn encode_packet(buf: &mut [u8], checksum: Option<u16>) {
+    let udp_len = (UDP_HDR_SIZE + PAYLOAD_LEN) as u16;
+    let mut ip6_header = IP6Header::new();
+    ip6_header.set_payload_len(udp_len);
+    ip6_header.set_next_header(ip6_nh::UDP);
+    ip6_header.set_hop_limit(64);
+    ip6_header.src_addr = link_local_addr(NODE0_ADDR_LONG);
+    ip6_header.dst_addr = link_local_addr(NODE1_ADDR_LONG);
+    let _ = IP6Header::encode(buf, ip6_header);
+
+    let (header, payload) = buf[IP6_HDR_SIZE..PACKET_LEN].split_at_mut(UDP_HDR_SIZE);
+    u16_to_slice(SRC_PORT, &mut header[0..2]);
+    u16_to_slice(DST_PORT, &mut header[2..4]);
+    u16_to_slice(udp_len, &mut header[4..6]);
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    let checksum = checksum.unwrap_or_else(|| {
+        ip::compute_checksum(
+            &ip6_header.src_addr,
+            &ip6_header.dst_addr,
+            ip6_nh::UDP,
+            udp_len,
+            &header[0..6],
+            payload,
+        )
+    });
+    u16_to_slice(checksum, &mut header[6..8]);
+}
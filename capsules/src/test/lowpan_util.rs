@@ -0,0 +1,84 @@
+//! Fixtures shared by the tests of 6LoWPAN header compression, `udp_nhc` and
+//! `ip6_ext`, which compare packets with reference encodings.
+//!
+//! The reference packets are sent between two nodes with the extended
+//! addresses `SRC_MAC_ADDR` and `DST_MAC_ADDR`, and are compressed with the
+//! context `CONTEXT`.
+
+use net::ieee802154::MacAddress;
+use net::sixlowpan_compression::{compress, decompress, Context};
+
+pub const SRC_MAC_ADDR: MacAddress =
+    MacAddress::Long([0x00, 0x12, 0x4b, 0x00, 0x00, 0x00, 0x00, 0x01]);
+pub const DST_MAC_ADDR: MacAddress =
+    MacAddress::Long([0x00, 0x12, 0x4b, 0x00, 0x00, 0x00, 0x00, 0x02]);
+
+pub const CONTEXT: Context = Context {
+    prefix: [0xfd, 0x00, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    prefix_len: 64,
+    id: 0,
+    compress: false,
+};
+
+/// The size of the buffers that packets are compressed and decompressed into
+pub const BUF_SIZE: usize = 128;
+
+/// Runs `tests`, each given as a name and a function that returns true if
+/// the test passed, and prints the name of every test that failed and the
+/// number of tests that passed.
+pub fn run_tests(tests: &[(&'static str, fn() -> bool)]) {
+    let mut passed = 0;
+    for &(name, test) in tests.iter() {
+        if test() {
+            passed += 1;
+        } else {
+            debug!("Test failed: {}", name);
+        }
+    }
+    debug!("{} of {} tests passed", passed, tests.len());
+}
+
+/// Returns true if `ip6_packet` is compressed to `lowpan_packet`, eliding
+/// its UDP checksum if `elide_checksum` is true.
+pub fn check_compress(ip6_packet: &[u8], lowpan_packet: &[u8], elide_checksum: bool) -> bool {
+    let mut buf = [0 as u8; BUF_SIZE];
+    match compress(
+        &CONTEXT,
+        ip6_packet,
+        SRC_MAC_ADDR,
+        DST_MAC_ADDR,
+        &mut buf,
+        elide_checksum,
+    ) {
+        Ok((consumed, written)) => {
+            // The payload is copied over by the caller
+            let payload_len = ip6_packet.len() - consumed;
+            buf[written..written + payload_len].copy_from_slice(&ip6_packet[consumed..]);
+            &buf[..written + payload_len] == lowpan_packet
+        }
+        Err(_) => false,
+    }
+}
+
+/// Returns true if `lowpan_packet`, received in an integrity-protected frame,
+/// is decompressed to `ip6_packet`.
+pub fn check_decompress(lowpan_packet: &[u8], ip6_packet: &[u8]) -> bool {
+    let mut buf = [0 as u8; BUF_SIZE];
+    match decompress(
+        &CONTEXT,
+        lowpan_packet,
+        SRC_MAC_ADDR,
+        DST_MAC_ADDR,
+        &mut buf,
+        0,
+        false,
+        true,
+    ) {
+        Ok((consumed, written, _)) => {
+            let payload_len = lowpan_packet.len() - consumed;
+            buf[written..written + payload_len].copy_from_slice(&lowpan_packet[consumed..]);
+            &buf[..written + payload_len] == ip6_packet
+        }
+        Err(_) => false,
+    }
+}
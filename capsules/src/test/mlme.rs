@@ -0,0 +1,404 @@
+//! Test the MAC layer management entity (MLME) between a simulated PAN
+//! coordinator, node 0, and a simulated device, node 1.
+//!
+//! The `MacManager` of each node runs directly on top of its `Framer`. The
+//! tests check that:
+//!
+//! - A passive scan does not find a coordinator that does not send beacons
+//!   periodically, and an active scan finds it with the PAN descriptor of its
+//!   beacon, only on its channel, and restores the channel of the device.
+//! - A passive scan finds a coordinator that sends beacons periodically, and
+//!   reports its beacon order.
+//! - A beacon that fails the incoming frame security procedure during a scan
+//!   is counted in the scan result, while other frames are not.
+//! - An association request is left unanswered while the coordinator does not
+//!   permit association, and the device gives up without a coordinator, back
+//!   on the channel and PAN it was configured with before.
+//! - Once association is permitted, the device is allocated a short address,
+//!   which it configures, and both nodes report the association. An
+//!   association response from a node outside the PAN is ignored.
+//! - A disassociation notification from the device removes it from the PAN,
+//!   and resets its short address.
+//!
+//! The simulation runs on the virtual time of a `SimClock` over a lossless
+//! `SimMedium`, so the tests do not need any radio hardware.
+//! `boards/imix/src/mlme_test.rs` shows how to instantiate the nodes, and
+//! runs the test with `mlme_test::run()`.
+
+use core::cell::Cell;
+use ieee802154::device::{MacDevice, RxClient, SecurityError};
+use ieee802154::mlme::{AssociationClient, MacManager, Mlme, PanDescriptor, ScanClient, ScanType};
+use kernel::ReturnCode;
+use kernel::hil::radio::RxInfo;
+use kernel::hil::time::{Frequency, Time};
+use net::ieee802154::*;
+use sim_radio::{LinkParams, SimAlarm, SimClock, SimMedium};
+use test::sim_lowpan::{NODE1_ADDR_LONG, PAN};
+
+pub type SimMacManager<'a> = MacManager<'a, SimAlarm<'a>>;
+
+/// The short address of the coordinator
+pub const COORD_ADDR: u16 = 0x1000;
+
+/// The channel of the PAN, on which both nodes start
+pub const CHANNEL: u8 = 26;
+
+/// Another channel and PAN, on which no coordinator operates
+const OTHER_CHANNEL: u8 = 11;
+const OTHER_PAN: PanID = 0x1234;
+
+/// The address from which a node outside the PAN sends a forged association
+/// response
+const FORGED_ADDR_LONG: [u8; 8] = [0x00, 0x12, 0x4b, 0x00, 0x00, 0x00, 0x00, 0x03];
+const FORGED_SHORT_ADDR: u16 = 0x0042;
+
+/// The short address the coordinator allocates to the device: the lowest one
+/// it does not use itself
+const ALLOCATED_ADDR: u16 = 0;
+
+/// The beacon order of the coordinator while it sends beacons periodically.
+/// A passive scan of the same duration hears at least one beacon.
+const BEACON_ORDER: u8 = 4;
+
+/// The scan duration of the other scans
+const SCAN_DURATION: u8 = 2;
+
+const LQI: u8 = 200;
+
+/// How long an operation may take, in milliseconds of virtual time
+const TIMEOUT_MS: u32 = 2000;
+
+/// How long the device waits for the coordinator to prepare its association
+/// response (macResponseWaitTime), in milliseconds
+const RESPONSE_WAIT_TIME_MS: u32 = 491;
+
+pub struct MlmeTest<'a> {
+    clock: &'a SimClock<'a>,
+    medium: &'a SimMedium<'a, SimAlarm<'a>>,
+    coord: &'a SimMacManager<'a>,
+    coord_mac: &'a MacDevice<'a>,
+    device: &'a SimMacManager<'a>,
+    device_mac: &'a MacDevice<'a>,
+
+    // The results reported by the MLMEs of the nodes
+    scan_result: Cell<Option<ReturnCode>>,
+    pan_descriptor: Cell<Option<PanDescriptor>>,
+    num_pan_descriptors: Cell<usize>,
+    security_failures: Cell<usize>,
+    associate_result: Cell<Option<(ReturnCode, u16)>>,
+    disassociated: Cell<Option<u8>>,
+    joined: Cell<Option<([u8; 8], u16)>>,
+    left: Cell<Option<([u8; 8], u16)>>,
+}
+
+impl<'a> MlmeTest<'a> {
+    /// `coord` and `device` are the MLMEs of nodes 0 and 1, on top of
+    /// `coord_mac` and `device_mac`, whose extended addresses are
+    /// `NODE0_ADDR_LONG` and `NODE1_ADDR_LONG`. The nodes must be the first
+    /// two nodes of `medium`, in that order. The test must be the scan client
+    /// of `device`, and an association client of both MLMEs.
+    pub fn new(
+        clock: &'a SimClock<'a>,
+        medium: &'a SimMedium<'a, SimAlarm<'a>>,
+        coord: &'a SimMacManager<'a>,
+        coord_mac: &'a MacDevice<'a>,
+        device: &'a SimMacManager<'a>,
+        device_mac: &'a MacDevice<'a>,
+    ) -> MlmeTest<'a> {
+        MlmeTest {
+            clock: clock,
+            medium: medium,
+            coord: coord,
+            coord_mac: coord_mac,
+            device: device,
+            device_mac: device_mac,
+            scan_result: Cell::new(None),
+            pan_descriptor: Cell::new(None),
+            num_pan_descriptors: Cell::new(0),
+            security_failures: Cell::new(0),
+            associate_result: Cell::new(None),
+            disassociated: Cell::new(None),
+            joined: Cell::new(None),
+            left: Cell::new(None),
+        }
+    }
+
+    pub fn run(&self) {
+        debug!("IEEE 802.15.4 MLME between a simulated coordinator and device");
+        self.medium.connect(
+            0,
+            1,
+            LinkParams {
+                loss_percent: 0,
+                delay_us: 0,
+                rssi: -60,
+                lqi: LQI,
+            },
+        );
+        self.coord_mac.set_address(COORD_ADDR);
+        self.coord_mac.config_commit();
+        self.device_mac.set_address(NO_SHORT_ADDR);
+        self.device_mac.set_pan(BROADCAST_PAN);
+        self.device_mac.config_commit();
+        self.coord.start_coordinator(BEACON_ORDER_NONBEACON);
+
+        let tests: [(&'static str, fn(&MlmeTest<'a>) -> bool); 7] = [
+            ("passive scan", MlmeTest::test_passive_scan),
+            ("active scan", MlmeTest::test_active_scan),
+            ("beacons", MlmeTest::test_beacons),
+            ("security failure", MlmeTest::test_security_failure),
+            ("association denied", MlmeTest::test_association_denied),
+            ("association", MlmeTest::test_association),
+            ("disassociation", MlmeTest::test_disassociation),
+        ];
+        let mut passed = 0;
+        for &(name, test) in tests.iter() {
+            if test(self) {
+                passed += 1;
+            } else {
+                debug!("Test failed: {}", name);
+            }
+        }
+        debug!("{} of {} tests passed", passed, tests.len());
+    }
+
+    /// Runs the simulation until `done` returns true, the nodes are idle, or
+    /// `timeout_ms` milliseconds have elapsed
+    fn run_until<F: Fn(&MlmeTest<'a>) -> bool>(&self, timeout_ms: u32, done: F) {
+        let freq = <<SimAlarm as Time>::Frequency as Frequency>::frequency();
+        let timeout = (freq / 1000) * timeout_ms;
+        let start = self.clock.now();
+        while !done(self) && self.clock.now().wrapping_sub(start) < timeout && self.clock.step() {}
+    }
+
+    /// Scans for PANs from the device, and returns the result of the scan
+    fn scan(&self, scan_type: ScanType, channels: u32, duration: u8) -> Option<ReturnCode> {
+        self.scan_result.set(None);
+        if self.device.scan(scan_type, channels, duration) != ReturnCode::SUCCESS {
+            return None;
+        }
+        self.run_until(TIMEOUT_MS, |t| t.scan_result.get().is_some());
+        self.scan_result.get()
+    }
+
+    /// Requests the association of the device with the coordinator, and
+    /// returns the result of the attempt. If `forge_response` is true, a
+    /// node outside the PAN answers the request before the coordinator does.
+    fn associate(&self, forge_response: bool) -> Option<(ReturnCode, u16)> {
+        self.associate_result.set(None);
+        self.joined.set(None);
+        if self.device.associate(
+            CHANNEL,
+            PAN,
+            MacAddress::Short(COORD_ADDR),
+            capability_info::ALLOCATE_ADDRESS,
+        ) != ReturnCode::SUCCESS
+        {
+            return None;
+        }
+        if forge_response {
+            self.run_until(RESPONSE_WAIT_TIME_MS / 2, |_| false);
+            self.forge_association_response();
+        }
+        // The coordinator completes the association once the device has
+        // acknowledged its response
+        self.run_until(TIMEOUT_MS, |_| false);
+        self.associate_result.get()
+    }
+
+    /// Passes the device an association response allocating it
+    /// `FORGED_SHORT_ADDR`, from a node outside the PAN
+    fn forge_association_response(&self) {
+        let header = Header {
+            frame_type: FrameType::MACCommand,
+            frame_pending: false,
+            ack_requested: true,
+            version: FrameVersion::V2006,
+            seq: Some(0),
+            dst_pan: Some(OTHER_PAN),
+            dst_addr: Some(MacAddress::Long(NODE1_ADDR_LONG)),
+            src_pan: Some(OTHER_PAN),
+            src_addr: Some(MacAddress::Long(FORGED_ADDR_LONG)),
+            security: None,
+            header_ies: Default::default(),
+            header_ies_len: 0,
+            payload_ies: Default::default(),
+            payload_ies_len: 0,
+        };
+        let payload = [
+            mac_command::ASSOCIATION_RESPONSE,
+            FORGED_SHORT_ADDR as u8,
+            (FORGED_SHORT_ADDR >> 8) as u8,
+            association_status::SUCCESS,
+        ];
+        let info = RxInfo {
+            rssi: -60,
+            lqi: LQI,
+            timestamp: None,
+        };
+        RxClient::receive(self.device, &payload, header, info, 0, payload.len());
+    }
+
+    fn test_passive_scan(&self) -> bool {
+        self.scan(ScanType::Passive, 1 << CHANNEL, SCAN_DURATION) == Some(ReturnCode::SUCCESS)
+            && self.num_pan_descriptors.get() == 0
+    }
+
+    fn test_active_scan(&self) -> bool {
+        self.device_mac.set_channel(OTHER_CHANNEL);
+        self.device_mac.config_commit();
+        let channels = (1 << OTHER_CHANNEL) | (1 << CHANNEL);
+        let result = self.scan(ScanType::Active, channels, SCAN_DURATION);
+        let restored = self.device_mac.get_channel() == OTHER_CHANNEL;
+        self.device_mac.set_channel(CHANNEL);
+        self.device_mac.config_commit();
+
+        let expected = PanDescriptor {
+            channel: CHANNEL,
+            coord_pan: PAN,
+            coord_addr: MacAddress::Short(COORD_ADDR),
+            superframe_spec: SuperframeSpec {
+                beacon_order: BEACON_ORDER_NONBEACON,
+                superframe_order: BEACON_ORDER_NONBEACON,
+                final_cap_slot: 15,
+                battery_life_extension: false,
+                pan_coordinator: true,
+                association_permit: false,
+            },
+            lqi: LQI,
+        };
+        result == Some(ReturnCode::SUCCESS) && restored && self.num_pan_descriptors.get() == 1
+            && self.pan_descriptor.get() == Some(expected)
+    }
+
+    fn test_beacons(&self) -> bool {
+        self.coord.start_coordinator(BEACON_ORDER);
+        let result = self.scan(ScanType::Passive, 1 << CHANNEL, BEACON_ORDER);
+        self.coord.start_coordinator(BEACON_ORDER_NONBEACON);
+        let beacon_order = self.pan_descriptor
+            .get()
+            .map(|descriptor| descriptor.superframe_spec.beacon_order);
+        result == Some(ReturnCode::SUCCESS) && self.num_pan_descriptors.get() == 1
+            && beacon_order == Some(BEACON_ORDER)
+    }
+
+    fn test_security_failure(&self) -> bool {
+        self.scan_result.set(None);
+        if self.device.scan(ScanType::Passive, 1 << CHANNEL, 0) != ReturnCode::SUCCESS {
+            return false;
+        }
+        RxClient::receive_security_failure(
+            self.device,
+            secured_header(FrameType::Beacon),
+            SecurityError::MicFailure,
+        );
+        RxClient::receive_security_failure(
+            self.device,
+            secured_header(FrameType::Data),
+            SecurityError::MicFailure,
+        );
+        self.run_until(TIMEOUT_MS, |t| t.scan_result.get().is_some());
+        self.scan_result.get() == Some(ReturnCode::SUCCESS)
+            && self.num_pan_descriptors.get() == 0 && self.security_failures.get() == 1
+    }
+
+    fn test_association_denied(&self) -> bool {
+        self.coord.set_association_permit(false);
+        self.device_mac.set_channel(OTHER_CHANNEL);
+        self.device_mac.set_pan(OTHER_PAN);
+        self.device_mac.config_commit();
+        let result = self.associate(false).map(|(result, _)| result);
+        let restored = self.device_mac.get_channel() == OTHER_CHANNEL
+            && self.device_mac.get_pan() == OTHER_PAN;
+        self.device_mac.set_channel(CHANNEL);
+        self.device_mac.set_pan(BROADCAST_PAN);
+        self.device_mac.config_commit();
+        result == Some(ReturnCode::ENOACK) && restored && self.device.get_coordinator().is_none()
+            && self.joined.get().is_none()
+    }
+
+    fn test_association(&self) -> bool {
+        self.coord.set_association_permit(true);
+        let result = self.associate(true);
+        let device = self.coord
+            .get_associated_device(0)
+            .map(|device| (device.addr_long, device.short_addr));
+        result == Some((ReturnCode::SUCCESS, ALLOCATED_ADDR))
+            && self.device_mac.get_address() == ALLOCATED_ADDR
+            && self.device.get_coordinator() == Some((PAN, MacAddress::Short(COORD_ADDR)))
+            && self.joined.get() == Some((NODE1_ADDR_LONG, ALLOCATED_ADDR))
+            && device == Some((NODE1_ADDR_LONG, ALLOCATED_ADDR))
+    }
+
+    fn test_disassociation(&self) -> bool {
+        self.disassociated.set(None);
+        self.left.set(None);
+        if self.device.disassociate() != ReturnCode::SUCCESS {
+            return false;
+        }
+        self.run_until(TIMEOUT_MS, |_| false);
+        self.disassociated.get() == Some(disassociation_reason::DEVICE_WISHES_TO_LEAVE)
+            && self.left.get() == Some((NODE1_ADDR_LONG, ALLOCATED_ADDR))
+            && self.device.get_coordinator().is_none()
+            && self.coord.get_associated_device(0).is_none()
+            && self.device_mac.get_address() == BROADCAST_ADDR
+    }
+}
+
+impl<'a> ScanClient for MlmeTest<'a> {
+    fn scan_done(
+        &self,
+        result: ReturnCode,
+        pan_descriptors: &[PanDescriptor],
+        security_failures: usize,
+    ) {
+        self.scan_result.set(Some(result));
+        self.pan_descriptor.set(pan_descriptors.first().cloned());
+        self.num_pan_descriptors.set(pan_descriptors.len());
+        self.security_failures.set(security_failures);
+    }
+}
+
+impl<'a> AssociationClient for MlmeTest<'a> {
+    fn associate_done(&self, result: ReturnCode, short_addr: u16) {
+        self.associate_result.set(Some((result, short_addr)));
+    }
+
+    fn disassociated(&self, reason: u8) {
+        self.disassociated.set(Some(reason));
+    }
+
+    fn device_associated(&self, addr_long: [u8; 8], short_addr: u16) {
+        self.joined.set(Some((addr_long, short_addr)));
+    }
+
+    fn device_disassociated(&self, addr_long: [u8; 8], short_addr: u16) {
+        self.left.set(Some((addr_long, short_addr)));
+    }
+}
+
+/// The MAC header of a frame of type `frame_type` from the coordinator,
+/// secured with a MIC
+fn secured_header<'b>(frame_type: FrameType) -> Header<'b> {
+    Header {
+        frame_type: frame_type,
+        frame_pending: false,
+        ack_requested: false,
+        version: FrameVersion::V2006,
+        seq: Some(0),
+        dst_pan: None,
+        dst_addr: None,
+        src_pan: Some(PAN),
+        src_addr: Some(MacAddress::Short(COORD_ADDR)),
+        security: Some(Security {
+            level: SecurityLevel::Mic32,
+            asn_in_nonce: false,
+            frame_counter: Some(0),
+            key_id: KeyId::Implicit,
+        }),
+        header_ies: Default::default(),
+        header_ies_len: 0,
+        payload_ies: Default::default(),
+        payload_ies_len: 0,
+    }
+}
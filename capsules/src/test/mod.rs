@@ -1,2 +1,16 @@
 pub mod aes;
 pub mod aes_ccm;
+pub mod ble_advertising_data;
+pub mod ble_hci;
+pub mod capture;
+pub mod coap;
+pub mod framer;
+pub mod gatt;
+pub mod ip6_ext;
+pub mod lowpan_mesh;
+pub mod lowpan_rx;
+pub mod lowpan_util;
+pub mod mlme;
+pub mod sim_lowpan;
+pub mod tsch;
+pub mod udp_nhc;
@@ -0,0 +1,212 @@
+//! Test 6LoWPAN fragmentation and reassembly between two nodes that exchange
+//! packets over a lossy simulated medium.
+//!
+//! Each node runs a complete stack on a `SimRadio`: an `AwakeMac` with
+//! CSMA-CA and retransmissions, a `Framer` and a `Sixlowpan` layer. Node 0
+//! sends `NUM_PACKETS` packets of `PACKET_LEN` bytes to node 1, which each
+//! take several fragments, over a link that loses `LOSS_PERCENT` percent of
+//! the frames and acknowledgements in each direction. The test checks that:
+//!
+//! - Every packet is sent successfully, the lost fragments being recovered
+//!   by the retransmissions of the Mac layer.
+//! - Node 1 reassembles every packet, in order and identical to the packet
+//!   that was sent, even when a fragment is received twice because its
+//!   acknowledgement was lost.
+//! - The link did lose frames, so that the losses were exercised.
+//!
+//! The simulation runs on the virtual time of a `SimClock`, which `run`
+//! advances until the nodes are idle, and the losses and backoffs are drawn
+//! from seeded generators, so the test is reproducible and does not need any
+//! radio hardware. It does not run on a host, but on a single board that
+//! runs both nodes: `boards/imix/src/sim_lowpan_test.rs` shows how to
+//! instantiate the two nodes, and runs the test with
+//! `sim_lowpan_test::run()`.
+
+use core::cell::Cell;
+use ieee802154::device::SecurityError;
+use kernel::ReturnCode;
+use kernel::common::take_cell::TakeCell;
+use kernel::hil::time::{Frequency, Time};
+use net::ieee802154::MacAddress;
+use net::ip::{IP6Header, IPAddr};
+use net::sixlowpan::{Sixlowpan, SixlowpanClient};
+use net::sixlowpan_compression::Context;
+use sim_radio::{LinkParams, SimAlarm, SimClock, SimMedium};
+
+pub const NUM_PACKETS: usize = 8;
+pub const PACKET_LEN: usize = 400;
+pub const LOSS_PERCENT: u8 = 10;
+
+/// The seed of the losses of the medium
+pub const SEED: u32 = 0x5eed;
+
+pub const NODE0_ADDR_LONG: [u8; 8] = [0x00, 0x12, 0x4b, 0x00, 0x00, 0x00, 0x00, 0x01];
+pub const NODE1_ADDR_LONG: [u8; 8] = [0x00, 0x12, 0x4b, 0x00, 0x00, 0x00, 0x00, 0x02];
+pub const PAN: u16 = 0xabcd;
+
+const IP6_HDR_SIZE: usize = 40;
+
+/// How long the simulation may run before the test gives up, in seconds of
+/// virtual time
+const TIMEOUT_S: u32 = 60;
+
+pub struct SimLowpanTest<'a> {
+    clock: &'a SimClock<'a>,
+    medium: &'a SimMedium<'a, SimAlarm<'a>>,
+    sender: &'a Sixlowpan<'a, SimAlarm<'a>, Context>,
+
+    packet: TakeCell<'static, [u8]>,
+    sent: Cell<usize>,
+    send_failures: Cell<usize>,
+    received: Cell<usize>,
+    corrupted: Cell<usize>,
+}
+
+impl<'a> SimLowpanTest<'a> {
+    /// `sender` is the `Sixlowpan` layer of node 0, and `packet` a buffer of
+    /// at least `PACKET_LEN` bytes. The test must be the client of the
+    /// `Sixlowpan` layers of both nodes.
+    pub fn new(
+        clock: &'a SimClock<'a>,
+        medium: &'a SimMedium<'a, SimAlarm<'a>>,
+        sender: &'a Sixlowpan<'a, SimAlarm<'a>, Context>,
+        packet: &'static mut [u8],
+    ) -> SimLowpanTest<'a> {
+        SimLowpanTest {
+            clock: clock,
+            medium: medium,
+            sender: sender,
+            packet: TakeCell::new(packet),
+            sent: Cell::new(0),
+            send_failures: Cell::new(0),
+            received: Cell::new(0),
+            corrupted: Cell::new(0),
+        }
+    }
+
+    pub fn run(&self) {
+        debug!("6LoWPAN over a lossy simulated medium");
+        self.medium.set_seed(SEED);
+        self.medium.connect(
+            0,
+            1,
+            LinkParams {
+                loss_percent: LOSS_PERCENT,
+                delay_us: 0,
+                rssi: -70,
+                lqi: 200,
+            },
+        );
+        self.medium.reset_stats();
+
+        self.send_next();
+        let timeout = TIMEOUT_S * <<SimAlarm as Time>::Frequency as Frequency>::frequency();
+        let start = self.clock.now();
+        while self.clock.now().wrapping_sub(start) < timeout && self.clock.step() {}
+
+        let stats = self.medium.get_stats();
+        debug!(
+            "{} of {} packets sent, {} received intact, {} corrupted",
+            self.sent.get() - self.send_failures.get(),
+            NUM_PACKETS,
+            self.received.get() - self.corrupted.get(),
+            self.corrupted.get()
+        );
+        debug!(
+            "{} frames transmitted, {} lost, {} collisions",
+            stats.transmitted, stats.losses, stats.collisions
+        );
+        let passed = self.sent.get() == NUM_PACKETS && self.send_failures.get() == 0
+            && self.received.get() == NUM_PACKETS && self.corrupted.get() == 0
+            && stats.losses > 0;
+        debug!(
+            "6LoWPAN simulation test {}",
+            if passed { "passed" } else { "failed" }
+        );
+    }
+
+    fn send_next(&self) {
+        let index = self.sent.get();
+        self.packet.take().map(|packet| {
+            fill_packet(packet, index);
+            let result = self.sender.transmit_packet(
+                MacAddress::Long(NODE0_ADDR_LONG),
+                MacAddress::Long(NODE1_ADDR_LONG),
+                packet,
+                PACKET_LEN,
+                None,
+            );
+            if let Err((code, packet)) = result {
+                debug!("Failed to send packet {}: {:?}", index, code);
+                self.packet.replace(packet);
+            }
+        });
+    }
+}
+
+impl<'a> SixlowpanClient for SimLowpanTest<'a> {
+    fn receive<'b>(&self, buf: &'b [u8], len: u16, result: ReturnCode) {
+        let index = self.received.get();
+        self.received.set(index + 1);
+        if result != ReturnCode::SUCCESS || !check_packet(&buf[..len as usize], index) {
+            debug!("Packet {} was not received intact", index);
+            self.corrupted.set(self.corrupted.get() + 1);
+        }
+    }
+
+    fn send_done(&self, buf: &'static mut [u8], _acked: bool, result: ReturnCode) {
+        let index = self.sent.get();
+        self.sent.set(index + 1);
+        self.packet.replace(buf);
+        if result != ReturnCode::SUCCESS {
+            debug!("Failed to send packet {}: {:?}", index, result);
+            self.send_failures.set(self.send_failures.get() + 1);
+        }
+        if index + 1 < NUM_PACKETS {
+            self.send_next();
+        }
+    }
+
+    fn receive_security_failure(&self, src_addr: Option<MacAddress>, error: SecurityError) {
+        debug!("Dropped a frame from {:?}: {:?}", src_addr, error);
+    }
+}
+
+/// The link-local address of the node with the extended address `addr_long`
+pub fn link_local_addr(addr_long: [u8; 8]) -> IPAddr {
+    let mut addr = IPAddr::new();
+    addr.set_unicast_link_local();
+    addr.0[8..16].copy_from_slice(&addr_long);
+    addr.0[8] ^= 0x02;
+    addr
+}
+
+fn encode_header(buf: &mut [u8]) {
+    let mut ip6_header = IP6Header::new();
+    ip6_header.set_payload_len((PACKET_LEN - IP6_HDR_SIZE) as u16);
+    ip6_header.src_addr = link_local_addr(NODE0_ADDR_LONG);
+    ip6_header.dst_addr = link_local_addr(NODE1_ADDR_LONG);
+    IP6Header::encode(buf, ip6_header);
+}
+
+/// The byte at `offset` in the payload of packet `index`
+fn payload_byte(index: usize, offset: usize) -> u8 {
+    (index * 31 + offset) as u8
+}
+
+fn fill_packet(buf: &mut [u8], index: usize) {
+    encode_header(buf);
+    for (offset, byte) in buf[IP6_HDR_SIZE..PACKET_LEN].iter_mut().enumerate() {
+        *byte = payload_byte(index, offset);
+    }
+}
+
+fn check_packet(buf: &[u8], index: usize) -> bool {
+    let mut header = [0; IP6_HDR_SIZE];
+    encode_header(&mut header);
+    buf.len() == PACKET_LEN && buf[..IP6_HDR_SIZE] == header[..]
+        && buf[IP6_HDR_SIZE..]
+            .iter()
+            .enumerate()
+            .all(|(offset, &byte)| byte == payload_byte(index, offset))
+}
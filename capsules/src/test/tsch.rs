@@ -0,0 +1,262 @@
+//! Test the TSCH Mac layer between a simulated PAN coordinator, node 0, and a
+//! simulated node that joins its network, node 1.
+//!
+//! Each node runs a `Framer` on top of its `TschMac`, with the minimal
+//! schedule. The tests check that:
+//!
+//! - Node 1 joins the network from the Enhanced Beacons of the coordinator,
+//!   which becomes its time source, and follows the same timeslots.
+//! - A data frame from node 1 reaches the coordinator in a shared link, and is
+//!   acknowledged.
+//! - A broadcast frame from the coordinator reaches node 1.
+//! - Node 1 remains synchronized with the coordinator over several keep-alive
+//!   periods without any other traffic.
+//! - Once node 1 leaves the network, it can no longer send frames.
+//!
+//! The simulation runs on the virtual time of a `SimClock` over a lossless
+//! `SimMedium`, so the tests do not need any radio hardware.
+//! `boards/imix/src/tsch_test.rs` shows how to instantiate the nodes, and runs
+//! the test with `tsch_test::run()`.
+
+use core::cell::Cell;
+use ieee802154::device::{MacDevice, RxClient, SecurityError, TxClient};
+use ieee802154::tsch::{TschMac, HOPPING_SEQUENCE, MINIMAL_SLOTFRAME_SIZE};
+use kernel::ReturnCode;
+use kernel::common::take_cell::TakeCell;
+use kernel::hil::radio::RxInfo;
+use kernel::hil::time::{Frequency, Time};
+use net::ieee802154::*;
+use sim_radio::{LinkParams, SimAlarm, SimClock, SimMedium, SimRadio};
+use test::sim_lowpan::{NODE0_ADDR_LONG, NODE1_ADDR_LONG, PAN};
+
+pub type SimTschMac<'a> = TschMac<'a, SimRadio<'a, SimAlarm<'a>>, SimAlarm<'a>>;
+
+/// The channel on which node 1 listens for Enhanced Beacons
+pub const JOIN_CHANNEL: u8 = HOPPING_SEQUENCE[0];
+
+const PAYLOAD: [u8; 8] = [0x54, 0x53, 0x43, 0x48, 0x00, 0x01, 0x02, 0x03];
+
+/// How long joining the network may take, in milliseconds of virtual time
+const JOIN_TIMEOUT_MS: u32 = 20_000;
+
+/// How long a transmission may take: frames wait for the next shared link,
+/// and may be retransmitted after a backoff of several slotframes
+const TX_TIMEOUT_MS: u32 = 10_000;
+
+/// How long node 1 runs without traffic, over several keep-alive periods
+const IDLE_MS: u32 = 60_000;
+
+pub struct TschTest<'a> {
+    clock: &'a SimClock<'a>,
+    medium: &'a SimMedium<'a, SimAlarm<'a>>,
+    coord: &'a SimTschMac<'a>,
+    coord_mac: &'a MacDevice<'a>,
+    node: &'a SimTschMac<'a>,
+    node_mac: &'a MacDevice<'a>,
+    tx_buf: TakeCell<'static, [u8]>,
+
+    tx_result: Cell<Option<(ReturnCode, bool)>>,
+    // The source and payload of the last frame received by either node
+    rx_src: Cell<Option<MacAddress>>,
+    rx_payload: Cell<Option<[u8; 8]>>,
+}
+
+impl<'a> TschTest<'a> {
+    /// `coord` and `node` are the TSCH layers of nodes 0 and 1, below
+    /// `coord_mac` and `node_mac`, whose extended addresses are
+    /// `NODE0_ADDR_LONG` and `NODE1_ADDR_LONG`. The nodes must be the first
+    /// two nodes of `medium`, in that order, and their radios must not
+    /// acknowledge frames themselves. The test must be the transmit and
+    /// receive client of both `MacDevice`s.
+    pub fn new(
+        clock: &'a SimClock<'a>,
+        medium: &'a SimMedium<'a, SimAlarm<'a>>,
+        coord: &'a SimTschMac<'a>,
+        coord_mac: &'a MacDevice<'a>,
+        node: &'a SimTschMac<'a>,
+        node_mac: &'a MacDevice<'a>,
+        tx_buf: &'static mut [u8],
+    ) -> TschTest<'a> {
+        TschTest {
+            clock: clock,
+            medium: medium,
+            coord: coord,
+            coord_mac: coord_mac,
+            node: node,
+            node_mac: node_mac,
+            tx_buf: TakeCell::new(tx_buf),
+            tx_result: Cell::new(None),
+            rx_src: Cell::new(None),
+            rx_payload: Cell::new(None),
+        }
+    }
+
+    pub fn run(&self) {
+        debug!("TSCH between a simulated coordinator and node");
+        self.medium.connect(
+            0,
+            1,
+            LinkParams {
+                loss_percent: 0,
+                delay_us: 0,
+                rssi: -60,
+                lqi: 200,
+            },
+        );
+        self.coord.start_network();
+
+        let tests: [(&'static str, fn(&TschTest<'a>) -> bool); 5] = [
+            ("join", TschTest::test_join),
+            ("data", TschTest::test_data),
+            ("broadcast", TschTest::test_broadcast),
+            ("synchronization", TschTest::test_synchronization),
+            ("stop", TschTest::test_stop),
+        ];
+        let mut passed = 0;
+        for &(name, test) in tests.iter() {
+            if test(self) {
+                passed += 1;
+            } else {
+                debug!("Test failed: {}", name);
+            }
+        }
+        debug!("{} of {} tests passed", passed, tests.len());
+    }
+
+    /// Runs the simulation until `done` returns true, the nodes are idle, or
+    /// `timeout_ms` milliseconds have elapsed
+    fn run_until<F: Fn(&TschTest<'a>) -> bool>(&self, timeout_ms: u32, done: F) {
+        let freq = <<SimAlarm as Time>::Frequency as Frequency>::frequency();
+        let timeout = (freq / 1000) * timeout_ms;
+        let start = self.clock.now();
+        while !done(self) && self.clock.now().wrapping_sub(start) < timeout && self.clock.step() {}
+    }
+
+    /// Whether node 1 is synchronized with the coordinator, and both nodes
+    /// are in or waiting for the same timeslot
+    fn synchronized(&self) -> bool {
+        self.node.is_synchronized()
+            && self.node.get_time_source() == Some(MacAddress::Long(NODE0_ADDR_LONG))
+            && self.node.get_asn() == self.coord.get_asn()
+    }
+
+    /// Sends `PAYLOAD` from `src_mac` to `dst_addr`, and returns the result of
+    /// the transmission along with whether it was acknowledged
+    fn send(
+        &self,
+        src_mac: &MacDevice<'a>,
+        src_addr: [u8; 8],
+        dst_addr: MacAddress,
+    ) -> Option<(ReturnCode, bool)> {
+        self.tx_result.set(None);
+        self.rx_src.set(None);
+        self.rx_payload.set(None);
+        let buf = match self.tx_buf.take() {
+            Some(buf) => buf,
+            None => return None,
+        };
+        let mut frame = match src_mac.prepare_data_frame(
+            buf,
+            PAN,
+            dst_addr,
+            PAN,
+            MacAddress::Long(src_addr),
+            None,
+        ) {
+            Ok(frame) => frame,
+            Err(buf) => {
+                self.tx_buf.replace(buf);
+                return None;
+            }
+        };
+        frame.append_payload(&PAYLOAD);
+        let (rval, buf) = src_mac.transmit(frame);
+        if rval != ReturnCode::SUCCESS {
+            buf.map(|buf| self.tx_buf.replace(buf));
+            return Some((rval, false));
+        }
+        self.run_until(TX_TIMEOUT_MS, |t| t.tx_result.get().is_some());
+        self.tx_result.get()
+    }
+
+    fn test_join(&self) -> bool {
+        // The coordinator sends a beacon in every slotframe while node 1
+        // joins, so that its beacons go through every channel of the hopping
+        // sequence within 16 slotframes. Beacons are then disabled, as they
+        // would otherwise compete with data frames for the single shared
+        // link of the schedule.
+        self.coord.set_eb_period(MINIMAL_SLOTFRAME_SIZE as u64);
+        self.node.set_eb_period(0);
+        if self.node.join(JOIN_CHANNEL) != ReturnCode::SUCCESS {
+            return false;
+        }
+        self.run_until(JOIN_TIMEOUT_MS, |t| t.node.is_synchronized());
+        self.coord.set_eb_period(0);
+        self.synchronized()
+    }
+
+    fn test_data(&self) -> bool {
+        let result = self.send(
+            self.node_mac,
+            NODE1_ADDR_LONG,
+            MacAddress::Long(NODE0_ADDR_LONG),
+        );
+        result == Some((ReturnCode::SUCCESS, true))
+            && self.rx_src.get() == Some(MacAddress::Long(NODE1_ADDR_LONG))
+            && self.rx_payload.get() == Some(PAYLOAD)
+    }
+
+    fn test_broadcast(&self) -> bool {
+        let result = self.send(
+            self.coord_mac,
+            NODE0_ADDR_LONG,
+            MacAddress::Short(BROADCAST_ADDR),
+        );
+        result == Some((ReturnCode::SUCCESS, false))
+            && self.rx_src.get() == Some(MacAddress::Long(NODE0_ADDR_LONG))
+            && self.rx_payload.get() == Some(PAYLOAD)
+    }
+
+    fn test_synchronization(&self) -> bool {
+        self.run_until(IDLE_MS, |_| false);
+        self.synchronized()
+    }
+
+    fn test_stop(&self) -> bool {
+        // Node 1 cannot leave the network during a timeslot
+        self.run_until(TX_TIMEOUT_MS, |t| t.node.stop() != ReturnCode::EBUSY);
+        let result = self.send(
+            self.node_mac,
+            NODE1_ADDR_LONG,
+            MacAddress::Long(NODE0_ADDR_LONG),
+        );
+        result == Some((ReturnCode::EOFF, false)) && !self.node.is_synchronized()
+    }
+}
+
+impl<'a> TxClient for TschTest<'a> {
+    fn send_done(&self, spi_buf: &'static mut [u8], acked: bool, result: ReturnCode) {
+        self.tx_buf.replace(spi_buf);
+        self.tx_result.set(Some((result, acked)));
+    }
+}
+
+impl<'a> RxClient for TschTest<'a> {
+    fn receive<'b>(
+        &self,
+        buf: &'b [u8],
+        header: Header<'b>,
+        _info: RxInfo,
+        data_offset: usize,
+        data_len: usize,
+    ) {
+        if data_len == PAYLOAD.len() {
+            let mut payload = [0; 8];
+            payload.copy_from_slice(&buf[data_offset..data_offset + data_len]);
+            self.rx_src.set(header.src_addr);
+            self.rx_payload.set(Some(payload));
+        }
+    }
+
+    fn receive_security_failure<'b>(&self, _header: Header<'b>, _error: SecurityError) {}
+}
@@ -1,9 +1,12 @@
 //! Platform-independent USB 2.0 protocol library
 
 use core::cell::Cell;
+use core::cmp::min;
 use core::convert::From;
 use core::fmt;
 use kernel::common::VolatileCell;
+use kernel::hil::usb::{CtrlInResult, CtrlOutResult, CtrlSetupResult, InResult, OutResult,
+                       TransferType};
 
 /// The datastructure sent in a SETUP handshake
 #[derive(Debug, Copy, Clone)]
@@ -72,7 +75,10 @@ impl SetupData {
                 10 => Some(StandardDeviceRequest::GetInterface {
                     interface: self.index,
                 }),
-                11 => Some(StandardDeviceRequest::SetInterface),
+                11 => Some(StandardDeviceRequest::SetInterface {
+                    alternate_setting: self.value,
+                    interface: self.index,
+                }),
                 12 => Some(StandardDeviceRequest::SynchFrame),
                 _ => None,
             },
@@ -117,7 +123,10 @@ pub enum StandardDeviceRequest {
     GetInterface {
         interface: u16,
     },
-    SetInterface,
+    SetInterface {
+        alternate_setting: u16,
+        interface: u16,
+    },
     SynchFrame,
 }
 
@@ -448,6 +457,50 @@ impl Descriptor for InterfaceDescriptor {
     }
 }
 
+#[derive(Copy, Clone)]
+pub struct EndpointAddress(u8);
+
+impl EndpointAddress {
+    pub fn new(endpoint: usize, direction: TransferDirection) -> Self {
+        EndpointAddress(
+            (endpoint as u8) & 0xf | match direction {
+                TransferDirection::HostToDevice => 0,
+                TransferDirection::DeviceToHost => 1 << 7,
+            },
+        )
+    }
+}
+
+impl From<EndpointAddress> for u8 {
+    fn from(ea: EndpointAddress) -> u8 {
+        ea.0
+    }
+}
+
+pub struct EndpointDescriptor {
+    pub endpoint_address: EndpointAddress,
+    pub transfer_type: TransferType,
+    pub max_packet_size: u16,
+    /// Polling interval for interrupt endpoints, in frames
+    pub interval: u8,
+}
+
+impl Descriptor for EndpointDescriptor {
+    fn size(&self) -> usize {
+        7
+    }
+
+    fn write_to_unchecked(&self, buf: &[Cell<u8>]) -> usize {
+        buf[0].set(7); // Size of descriptor
+        buf[1].set(DescriptorType::Endpoint as u8);
+        buf[2].set(From::from(self.endpoint_address));
+        buf[3].set(self.transfer_type as u8);
+        put_u16(&buf[4..6], self.max_packet_size);
+        buf[6].set(self.interval);
+        7
+    }
+}
+
 pub struct LanguagesDescriptor<'a> {
     pub langs: &'a [u16],
 }
@@ -497,6 +550,290 @@ impl<'a> Descriptor for StringDescriptor<'a> {
     }
 }
 
+/// Groups the interfaces of a function that has more than one, so that the
+/// host binds a single driver to them
+pub struct InterfaceAssociationDescriptor {
+    pub first_interface: u8,
+    pub interface_count: u8,
+    pub function_class: u8,
+    pub function_subclass: u8,
+    pub function_protocol: u8,
+    pub string_index: u8,
+}
+
+impl Descriptor for InterfaceAssociationDescriptor {
+    fn size(&self) -> usize {
+        8
+    }
+
+    fn write_to_unchecked(&self, buf: &[Cell<u8>]) -> usize {
+        buf[0].set(8); // Size of descriptor
+        buf[1].set(INTERFACE_ASSOCIATION_DESCRIPTOR_TYPE);
+        buf[2].set(self.first_interface);
+        buf[3].set(self.interface_count);
+        buf[4].set(self.function_class);
+        buf[5].set(self.function_subclass);
+        buf[6].set(self.function_protocol);
+        buf[7].set(self.string_index);
+        8
+    }
+}
+
+/// Descriptor type of `InterfaceAssociationDescriptor`, which was added to
+/// USB 2.0 by an ECN
+const INTERFACE_ASSOCIATION_DESCRIPTOR_TYPE: u8 = 11;
+
+/// Largest descriptor that a `DescriptorWindow` or `usbc_client::Client` can
+/// serialize
+pub const MAX_DESCRIPTOR_SIZE: usize = 32;
+
+/// Serializes part of a sequence of descriptors, given in pieces, as if they
+/// were written one after the other: their bytes from `offset` on are copied
+/// into `buf`, up to its length.  This avoids keeping all the descriptors of a
+/// configuration in a buffer.
+pub struct DescriptorWindow<'b> {
+    offset: usize,
+    buf: &'b [VolatileCell<u8>],
+    position: usize,
+    copied: usize,
+}
+
+impl<'b> DescriptorWindow<'b> {
+    pub fn new(offset: usize, buf: &'b [VolatileCell<u8>]) -> Self {
+        DescriptorWindow {
+            offset: offset,
+            buf: buf,
+            position: 0,
+            copied: 0,
+        }
+    }
+
+    /// Append descriptors to the sequence
+    pub fn copy(&mut self, descriptors: &[&Descriptor]) {
+        let scratch: [Cell<u8>; MAX_DESCRIPTOR_SIZE] = Default::default();
+        for d in descriptors.iter() {
+            let len = d.size();
+            if self.position + len > self.offset && self.copied < self.buf.len() {
+                d.write_to(&scratch);
+                let from = self.offset + self.copied - self.position;
+                let n = min(len - from, self.buf.len() - self.copied);
+                for i in 0..n {
+                    self.buf[self.copied + i].set(scratch[from + i].get());
+                }
+                self.copied += n;
+            }
+            self.position += len;
+        }
+    }
+
+    /// Length of the sequence so far
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Number of bytes copied into the buffer
+    pub fn copied(&self) -> usize {
+        self.copied
+    }
+}
+
+/// A function of a USB device, such as a serial port or a keyboard, which
+/// owns some of the device's interfaces and endpoints.
+///
+/// Functions register with `usbc_client::Client`, which allocates their
+/// interface and endpoint numbers, includes their descriptors in the
+/// configuration, and routes to them the control requests addressed to their
+/// interfaces or endpoints and the traffic on their endpoints.
+pub trait ClassDriver {
+    /// Number of interfaces of the function
+    fn num_interfaces(&self) -> u8;
+
+    /// Number of endpoints used by the function, besides endpoint 0
+    fn num_endpoints(&self) -> usize;
+
+    /// Called on registration with the numbers of the function's first
+    /// interface and first endpoint.  The others follow consecutively.
+    fn assign(&self, first_interface: u8, first_endpoint: usize);
+
+    /// Append the descriptors of the function to the configuration: its
+    /// interface descriptors, each followed by its class-specific and endpoint
+    /// descriptors
+    fn descriptors(&self, window: &mut DescriptorWindow);
+
+    /// Set up the buffers of the function's endpoints and enable them
+    fn enable(&self);
+
+    /// The bus was reset.  The hardware layer reconfigures the endpoints.
+    fn bus_reset(&self);
+
+    /// Handle a Control Setup transaction addressed to one of the function's
+    /// interfaces or endpoints.  If the result is `Ok`, the Data and Status
+    /// stages of the request are passed on too.
+    fn ctrl_setup(&self, setup_data: SetupData) -> CtrlSetupResult;
+
+    /// Write the next packet of a Control Read into `buf`
+    fn ctrl_in(&self, buf: &[VolatileCell<u8>]) -> CtrlInResult;
+
+    /// Take the next packet of a Control Write from `buf`
+    fn ctrl_out(&self, buf: &[VolatileCell<u8>], packet_bytes: u32) -> CtrlOutResult;
+
+    /// The Status stage of the control request completed
+    fn ctrl_status_complete(&self);
+
+    /// Write the next packet to send on one of the function's IN endpoints
+    fn packet_in(&self, transfer_type: TransferType, endpoint: usize) -> InResult;
+
+    /// Take a packet received on one of the function's OUT endpoints
+    fn packet_out(&self, transfer_type: TransferType, endpoint: usize, packet_bytes: u32)
+        -> OutResult;
+
+    /// The packet last written by `packet_in` was sent to the host
+    fn packet_transmitted(&self, endpoint: usize);
+}
+
+/// Languages of the strings given to `DescriptorBuilder`
+static LANGUAGES: &'static [u16] = &[
+    0x0409 // English (United States)
+];
+
+/// The board-specific part of the descriptors of a device: its identifiers,
+/// strings and power requirements.  It builds the device, string and
+/// configuration descriptors, the latter with the descriptors of the
+/// device's functions.
+///
+/// ```rust
+/// let descriptors = static_init!(
+///     capsules::usb::DescriptorBuilder<'static>,
+///     capsules::usb::DescriptorBuilder::new(0x6667, 0xabcd)
+///         .strings("XYZ Corp.", "The Zorpinator", "Serial No. 5")
+///         .power(false, 100));
+/// ```
+pub struct DescriptorBuilder<'a> {
+    vendor_id: u16,
+    product_id: u16,
+    device_release: u16,
+    strings: [&'a str; 3],
+    self_powered: bool,
+    max_power: u8,
+}
+
+impl<'a> DescriptorBuilder<'a> {
+    pub fn new(vendor_id: u16, product_id: u16) -> Self {
+        DescriptorBuilder {
+            vendor_id: vendor_id,
+            product_id: product_id,
+            device_release: 0x0001,
+            strings: ["", "", ""],
+            self_powered: true,
+            max_power: 0,
+        }
+    }
+
+    /// Set the device release number, in binary coded decimal
+    pub fn device_release(mut self, device_release: u16) -> Self {
+        self.device_release = device_release;
+        self
+    }
+
+    /// Set the manufacturer, product and serial number strings.  Empty
+    /// strings are left out.  Each string must fit in a
+    /// `MAX_DESCRIPTOR_SIZE` descriptor, which holds 15 characters of
+    /// the Basic Multilingual Plane.
+    pub fn strings(
+        mut self,
+        manufacturer: &'a str,
+        product: &'a str,
+        serial_number: &'a str,
+    ) -> Self {
+        self.strings = [manufacturer, product, serial_number];
+        self
+    }
+
+    /// Set whether the device has its own power supply, and the current it
+    /// draws from the bus in mA
+    pub fn power(mut self, self_powered: bool, max_power_ma: u16) -> Self {
+        self.self_powered = self_powered;
+        self.max_power = min(max_power_ma / 2, 0xff) as u8;
+        self
+    }
+
+    /// Index of the string descriptor for `strings[i]`, or 0 if it is empty
+    fn string_index(&self, i: usize) -> u8 {
+        if self.strings[i].is_empty() {
+            0
+        } else {
+            i as u8 + 1
+        }
+    }
+
+    pub fn device_descriptor(&self) -> DeviceDescriptor {
+        DeviceDescriptor {
+            // Functions with several interfaces are grouped by interface
+            // association descriptors
+            class: 0xef,
+            subclass: 0x02,
+            protocol: 0x01,
+            vendor_id: self.vendor_id,
+            product_id: self.product_id,
+            device_release: self.device_release,
+            manufacturer_string: self.string_index(0),
+            product_string: self.string_index(1),
+            serial_number_string: self.string_index(2),
+            ..Default::default()
+        }
+    }
+
+    /// Write the string descriptor with the given index into `buf`, or the
+    /// list of supported languages for index 0.  Returns the length of the
+    /// descriptor, or `None` if there is no such string.
+    pub fn write_string_descriptor(
+        &self,
+        index: u8,
+        lang_id: u16,
+        buf: &[Cell<u8>],
+    ) -> Option<usize> {
+        match index {
+            0 => Some(LanguagesDescriptor { langs: LANGUAGES }.write_to(buf)),
+            i if (i as usize) <= self.strings.len()
+                && self.string_index(i as usize - 1) != 0
+                && lang_id == LANGUAGES[0] =>
+            {
+                let d = StringDescriptor {
+                    string: self.strings[i as usize - 1],
+                };
+                match d.write_to(buf) {
+                    0 => None,
+                    len => Some(len),
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Append the configuration descriptor followed by the descriptors of
+    /// the device's functions, which `functions` appends, to `window`
+    pub fn configuration_descriptors(
+        &self,
+        num_interfaces: u8,
+        functions: &Fn(&mut DescriptorWindow),
+        window: &mut DescriptorWindow,
+    ) {
+        let mut related = DescriptorWindow::new(0, &[]);
+        functions(&mut related);
+
+        let dc = ConfigurationDescriptor {
+            num_interfaces: num_interfaces,
+            configuration_value: 1,
+            attributes: ConfigurationAttributes::new(self.self_powered, false),
+            max_power: self.max_power,
+            related_descriptor_length: related.position(),
+            ..Default::default()
+        };
+        window.copy(&[&dc]);
+        functions(window);
+    }
+}
+
 /// Parse a `u16` from two bytes as received on the bus
 fn get_u16(b0: u8, b1: u8) -> u16 {
     (b0 as u16) | ((b1 as u16) << 8)
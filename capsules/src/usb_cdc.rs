@@ -0,0 +1,488 @@
+//! USB CDC-ACM serial port
+//!
+//! This function of a composite USB device (`usbc_client::Client`) is a CDC
+//! Abstract Control Model (ACM) serial port, which hosts support without a
+//! vendor driver (`/dev/ttyACM0` on Linux).  It implements `hil::uart::UART`,
+//! so that `console`, `debug` or a UART mux can use it in place of a USART.
+//!
+//! The function has a communication interface with an interrupt IN endpoint
+//! for notifications, which are never sent, and a data interface with a bulk
+//! IN and a bulk OUT endpoint carrying the serial data.  The line coding set
+//! by the host with SET_LINE_CODING is reported back by GET_LINE_CODING but
+//! has no effect, and so do the parameters passed to `init()`.
+//!
+//! Data given to `transmit()` waits in the IN endpoint until the host reads
+//! it, which it only does while the port is open.  A bus reset aborts the
+//! current transmission and reception with `Error::ResetError`.
+//!
+//! ## Instantiation
+//!
+//! ```rust
+//! let cdc = static_init!(
+//!     capsules::usb_cdc::CdcAcm<'static, sam4l::usbc::Usbc<'static>>,
+//!     capsules::usb_cdc::CdcAcm::new(&sam4l::usbc::USBC));
+//! usb_client.register(cdc);
+//!
+//! // Use the CDC-ACM port as the console
+//! let console = static_init!(
+//!     capsules::console::Console<capsules::usb_cdc::CdcAcm<sam4l::usbc::Usbc>>,
+//!     capsules::console::Console::new(
+//!         cdc,
+//!         115200,
+//!         &mut capsules::console::WRITE_BUF,
+//!         kernel::Grant::create()));
+//! hil::uart::UART::set_client(cdc, console);
+//!
+//! // Enumerate
+//! hil::usb::Client::enable(usb_client);
+//! hil::usb::Client::attach(usb_client);
+//! ```
+
+use core::cell::Cell;
+use core::cmp::min;
+use core::default::Default;
+use kernel::common::VolatileCell;
+use kernel::common::take_cell::TakeCell;
+use kernel::hil::uart;
+use kernel::hil::usb::*;
+use usb::*;
+
+/// Size of the packets on all endpoints
+const PACKET_SIZE: usize = 8;
+
+// Interfaces and endpoints, relative to the first ones assigned
+const COMMUNICATION_INTERFACE: u8 = 0;
+const DATA_INTERFACE: u8 = 1;
+const NUM_INTERFACES: u8 = 2;
+
+const ENDPOINT_IN: usize = 0;
+const ENDPOINT_OUT: usize = 1;
+const ENDPOINT_NOTIFICATION: usize = 2;
+const NUM_ENDPOINTS: usize = 3;
+
+// Class, subclass and protocol codes
+// (Universal Serial Bus Class Definitions for Communications Devices 1.2)
+const CDC_CLASS: u8 = 0x02;
+const ACM_SUBCLASS: u8 = 0x02;
+const DATA_CLASS: u8 = 0x0a;
+// No AT commands, so that hosts do not probe the port for a modem
+const NO_PROTOCOL: u8 = 0x00;
+
+// Class-specific descriptors
+const CS_INTERFACE: u8 = 0x24;
+const HEADER_FUNCTIONAL_DESCRIPTOR: u8 = 0x00;
+const CALL_MANAGEMENT_FUNCTIONAL_DESCRIPTOR: u8 = 0x01;
+const ACM_FUNCTIONAL_DESCRIPTOR: u8 = 0x02;
+const UNION_FUNCTIONAL_DESCRIPTOR: u8 = 0x06;
+
+// Supports SET_LINE_CODING, GET_LINE_CODING and SET_CONTROL_LINE_STATE
+const ACM_CAPABILITIES: u8 = 0x02;
+
+// Class-specific requests
+const SET_LINE_CODING: u8 = 0x20;
+const GET_LINE_CODING: u8 = 0x21;
+const SET_CONTROL_LINE_STATE: u8 = 0x22;
+
+/// dwDTERate (little-endian), bCharFormat, bParityType and bDataBits
+const LINE_CODING_LENGTH: usize = 7;
+const DEFAULT_LINE_CODING: [u8; LINE_CODING_LENGTH] = [0x00, 0xc2, 0x01, 0x00, 0, 0, 8];
+
+/// A class-specific descriptor of the communication interface
+struct FunctionalDescriptor<'a> {
+    subtype: u8,
+    data: &'a [u8],
+}
+
+impl<'a> Descriptor for FunctionalDescriptor<'a> {
+    fn size(&self) -> usize {
+        3 + self.data.len()
+    }
+
+    fn write_to_unchecked(&self, buf: &[Cell<u8>]) -> usize {
+        let len = self.size();
+        buf[0].set(len as u8);
+        buf[1].set(CS_INTERFACE);
+        buf[2].set(self.subtype);
+        for (i, b) in self.data.iter().enumerate() {
+            buf[3 + i].set(*b);
+        }
+        len
+    }
+}
+
+pub struct CdcAcm<'a, C: 'a> {
+    controller: &'a C,
+    state: Cell<State>,
+    first_interface: Cell<u8>,
+    first_endpoint: Cell<usize>,
+    in_storage: [VolatileCell<u8>; PACKET_SIZE],
+    out_storage: [VolatileCell<u8>; PACKET_SIZE],
+    notification_storage: [VolatileCell<u8>; PACKET_SIZE],
+    line_coding: Cell<[u8; LINE_CODING_LENGTH]>,
+
+    client: Cell<Option<&'static uart::Client>>,
+    tx_buffer: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    tx_offset: Cell<usize>,
+    rx_buffer: TakeCell<'static, [u8]>,
+    rx_len: Cell<usize>,
+    rx_offset: Cell<usize>,
+    /// Bytes of the packet in the OUT endpoint already passed to the client
+    rx_packet_offset: Cell<usize>,
+}
+
+#[derive(Copy, Clone)]
+enum State {
+    Init,
+
+    /// We will send the line coding to the host, up to the given length
+    GetLineCoding(usize),
+
+    /// We will accept the line coding from the host
+    SetLineCoding,
+}
+
+impl<'a, C: UsbController> CdcAcm<'a, C> {
+    pub fn new(controller: &'a C) -> Self {
+        CdcAcm {
+            controller: controller,
+            state: Cell::new(State::Init),
+            first_interface: Cell::new(0),
+            first_endpoint: Cell::new(0),
+            in_storage: [VolatileCell::new(0); PACKET_SIZE],
+            out_storage: [VolatileCell::new(0); PACKET_SIZE],
+            notification_storage: [VolatileCell::new(0); PACKET_SIZE],
+            line_coding: Cell::new(DEFAULT_LINE_CODING),
+            client: Cell::new(None),
+            tx_buffer: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            tx_offset: Cell::new(0),
+            rx_buffer: TakeCell::empty(),
+            rx_len: Cell::new(0),
+            rx_offset: Cell::new(0),
+            rx_packet_offset: Cell::new(0),
+        }
+    }
+
+    /// The number of one of our endpoints
+    fn endpoint(&self, endpoint: usize) -> usize {
+        self.first_endpoint.get() + endpoint
+    }
+
+    /// The number of one of our interfaces
+    fn interface(&self, interface: u8) -> u8 {
+        self.first_interface.get() + interface
+    }
+
+    /// Abort the current transmission and reception
+    fn abort(&self, error: uart::Error) {
+        self.tx_buffer.take().map(|buf| {
+            self.client
+                .get()
+                .map(move |client| client.transmit_complete(buf, error));
+        });
+        self.rx_packet_offset.set(0);
+        self.rx_buffer.take().map(|buf| {
+            let rx_offset = self.rx_offset.get();
+            self.client
+                .get()
+                .map(move |client| client.receive_complete(buf, rx_offset, error));
+        });
+    }
+}
+
+impl<'a, C: UsbController> ClassDriver for CdcAcm<'a, C> {
+    fn num_interfaces(&self) -> u8 {
+        NUM_INTERFACES
+    }
+
+    fn num_endpoints(&self) -> usize {
+        NUM_ENDPOINTS
+    }
+
+    fn assign(&self, first_interface: u8, first_endpoint: usize) {
+        self.first_interface.set(first_interface);
+        self.first_endpoint.set(first_endpoint);
+    }
+
+    fn descriptors(&self, window: &mut DescriptorWindow) {
+        let communication_interface = self.interface(COMMUNICATION_INTERFACE);
+        let data_interface = self.interface(DATA_INTERFACE);
+        let packet_size = PACKET_SIZE as u16;
+        window.copy(&[
+            &InterfaceAssociationDescriptor {
+                first_interface: communication_interface,
+                interface_count: NUM_INTERFACES,
+                function_class: CDC_CLASS,
+                function_subclass: ACM_SUBCLASS,
+                function_protocol: NO_PROTOCOL,
+                string_index: 0,
+            },
+            &InterfaceDescriptor {
+                interface_number: communication_interface,
+                num_endpoints: 1,
+                interface_class: CDC_CLASS,
+                interface_subclass: ACM_SUBCLASS,
+                interface_protocol: NO_PROTOCOL,
+                ..Default::default()
+            },
+            &FunctionalDescriptor {
+                subtype: HEADER_FUNCTIONAL_DESCRIPTOR,
+                data: &[0x10, 0x01], // CDC 1.1
+            },
+            &FunctionalDescriptor {
+                subtype: CALL_MANAGEMENT_FUNCTIONAL_DESCRIPTOR,
+                data: &[0x00, data_interface], // No call management
+            },
+            &FunctionalDescriptor {
+                subtype: ACM_FUNCTIONAL_DESCRIPTOR,
+                data: &[ACM_CAPABILITIES],
+            },
+            &FunctionalDescriptor {
+                subtype: UNION_FUNCTIONAL_DESCRIPTOR,
+                data: &[communication_interface, data_interface],
+            },
+            &EndpointDescriptor {
+                endpoint_address: EndpointAddress::new(
+                    self.endpoint(ENDPOINT_NOTIFICATION),
+                    TransferDirection::DeviceToHost,
+                ),
+                transfer_type: TransferType::Interrupt,
+                max_packet_size: packet_size,
+                interval: 255,
+            },
+            &InterfaceDescriptor {
+                interface_number: data_interface,
+                num_endpoints: 2,
+                interface_class: DATA_CLASS,
+                interface_subclass: 0,
+                interface_protocol: 0,
+                ..Default::default()
+            },
+            &EndpointDescriptor {
+                endpoint_address: EndpointAddress::new(
+                    self.endpoint(ENDPOINT_OUT),
+                    TransferDirection::HostToDevice,
+                ),
+                transfer_type: TransferType::Bulk,
+                max_packet_size: packet_size,
+                interval: 0,
+            },
+            &EndpointDescriptor {
+                endpoint_address: EndpointAddress::new(
+                    self.endpoint(ENDPOINT_IN),
+                    TransferDirection::DeviceToHost,
+                ),
+                transfer_type: TransferType::Bulk,
+                max_packet_size: packet_size,
+                interval: 0,
+            },
+        ]);
+    }
+
+    fn enable(&self) {
+        let endpoint_in = self.endpoint(ENDPOINT_IN) as u32;
+        let endpoint_out = self.endpoint(ENDPOINT_OUT) as u32;
+        let endpoint_notification = self.endpoint(ENDPOINT_NOTIFICATION) as u32;
+        self.controller
+            .endpoint_set_buffer(endpoint_in, &self.in_storage);
+        self.controller
+            .endpoint_set_buffer(endpoint_out, &self.out_storage);
+        self.controller
+            .endpoint_set_buffer(endpoint_notification, &self.notification_storage);
+
+        self.controller
+            .endpoint_in_enable(TransferType::Bulk, endpoint_in);
+        self.controller
+            .endpoint_out_enable(TransferType::Bulk, endpoint_out);
+        self.controller
+            .endpoint_in_enable(TransferType::Interrupt, endpoint_notification);
+    }
+
+    fn bus_reset(&self) {
+        // Any packet the hardware was holding is lost
+        self.abort(uart::Error::ResetError);
+    }
+
+    /// Handle a class-specific request to the communication interface
+    fn ctrl_setup(&self, setup_data: SetupData) -> CtrlSetupResult {
+        match (
+            setup_data.request_type.request_type(),
+            setup_data.request_type.recipient(),
+        ) {
+            (RequestType::Class, Recipient::Interface)
+                if setup_data.index == self.interface(COMMUNICATION_INTERFACE) as u16 =>
+            {
+                match setup_data.request_code {
+                    SET_LINE_CODING if setup_data.length as usize == LINE_CODING_LENGTH => {
+                        self.state.set(State::SetLineCoding);
+                        CtrlSetupResult::Ok
+                    }
+                    GET_LINE_CODING => {
+                        let len = min(LINE_CODING_LENGTH, setup_data.length as usize);
+                        self.state.set(State::GetLineCoding(len));
+                        CtrlSetupResult::Ok
+                    }
+                    SET_CONTROL_LINE_STATE => {
+                        // DTR and RTS do not matter
+                        self.state.set(State::Init);
+                        CtrlSetupResult::Ok
+                    }
+                    _ => CtrlSetupResult::ErrNonstandardRequest,
+                }
+            }
+            _ => CtrlSetupResult::ErrNonstandardRequest,
+        }
+    }
+
+    fn ctrl_in(&self, buf: &[VolatileCell<u8>]) -> CtrlInResult {
+        match self.state.get() {
+            State::GetLineCoding(len) => {
+                for (i, b) in self.line_coding.get()[..len].iter().enumerate() {
+                    buf[i].set(*b);
+                }
+                CtrlInResult::Packet(len, true)
+            }
+            _ => CtrlInResult::Error,
+        }
+    }
+
+    fn ctrl_out(&self, buf: &[VolatileCell<u8>], packet_bytes: u32) -> CtrlOutResult {
+        match self.state.get() {
+            State::SetLineCoding if packet_bytes as usize == LINE_CODING_LENGTH => {
+                let mut line_coding = [0; LINE_CODING_LENGTH];
+                for (i, b) in buf[..LINE_CODING_LENGTH].iter().enumerate() {
+                    line_coding[i] = b.get();
+                }
+                self.line_coding.set(line_coding);
+                CtrlOutResult::Ok
+            }
+            _ => CtrlOutResult::Halted,
+        }
+    }
+
+    fn ctrl_status_complete(&self) {
+        self.state.set(State::Init);
+    }
+
+    /// Send the next packet of the current transmission
+    fn packet_in(&self, transfer_type: TransferType, endpoint: usize) -> InResult {
+        if transfer_type == TransferType::Bulk && endpoint == self.endpoint(ENDPOINT_IN) {
+            self.tx_buffer.map_or(InResult::Delay, |buf| {
+                let start = self.tx_offset.get();
+                let packet_bytes = min(PACKET_SIZE, self.tx_len.get() - start);
+                if packet_bytes == 0 {
+                    // Wait for the last packet to be sent
+                    return InResult::Delay;
+                }
+                for (i, b) in buf[start..start + packet_bytes].iter().enumerate() {
+                    self.in_storage[i].set(*b);
+                }
+                self.tx_offset.set(start + packet_bytes);
+                InResult::Packet(packet_bytes)
+            })
+        } else if transfer_type == TransferType::Interrupt
+            && endpoint == self.endpoint(ENDPOINT_NOTIFICATION)
+        {
+            // There are no notifications to send
+            InResult::Delay
+        } else {
+            InResult::Error
+        }
+    }
+
+    /// Pass the bytes of a received packet to the current reception
+    fn packet_out(
+        &self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> OutResult {
+        if transfer_type != TransferType::Bulk || endpoint != self.endpoint(ENDPOINT_OUT) {
+            return OutResult::Halted;
+        }
+
+        let packet_bytes = min(packet_bytes as usize, PACKET_SIZE);
+        loop {
+            let buf = match self.rx_buffer.take() {
+                Some(buf) => buf,
+                // Keep the rest of the packet in the endpoint until
+                // the client calls receive()
+                None => return OutResult::Delay,
+            };
+
+            let start = self.rx_packet_offset.get();
+            let rx_offset = self.rx_offset.get();
+            let len = min(packet_bytes - start, self.rx_len.get() - rx_offset);
+            for i in 0..len {
+                buf[rx_offset + i] = self.out_storage[start + i].get();
+            }
+            self.rx_offset.set(rx_offset + len);
+            self.rx_packet_offset.set(start + len);
+
+            if self.rx_offset.get() == self.rx_len.get() {
+                let rx_len = self.rx_len.get();
+                self.client.get().map(move |client| {
+                    client.receive_complete(buf, rx_len, uart::Error::CommandComplete)
+                });
+            } else {
+                self.rx_buffer.replace(buf);
+            }
+
+            if self.rx_packet_offset.get() == packet_bytes {
+                self.rx_packet_offset.set(0);
+                return OutResult::Ok;
+            }
+        }
+    }
+
+    fn packet_transmitted(&self, endpoint: usize) {
+        if endpoint == self.endpoint(ENDPOINT_IN) && self.tx_offset.get() == self.tx_len.get() {
+            self.tx_buffer.take().map(|buf| {
+                self.client
+                    .get()
+                    .map(move |client| client.transmit_complete(buf, uart::Error::CommandComplete));
+            });
+        }
+    }
+}
+
+impl<'a, C: UsbController> uart::UART for CdcAcm<'a, C> {
+    fn set_client(&self, client: &'static uart::Client) {
+        self.client.set(Some(client));
+    }
+
+    /// The line settings do not apply to USB
+    fn init(&self, _params: uart::UARTParams) {}
+
+    fn transmit(&self, tx_data: &'static mut [u8], tx_len: usize) {
+        // Quit the current transmission if any
+        self.tx_buffer.take().map(|buf| {
+            self.client
+                .get()
+                .map(move |client| client.transmit_complete(buf, uart::Error::RepeatCallError));
+        });
+
+        self.tx_len.set(min(tx_len, tx_data.len()));
+        self.tx_offset.set(0);
+        self.tx_buffer.replace(tx_data);
+        self.controller
+            .endpoint_resume_in(self.endpoint(ENDPOINT_IN) as u32);
+    }
+
+    fn receive(&self, rx_buffer: &'static mut [u8], rx_len: usize) {
+        // Quit the current reception if any
+        self.rx_buffer.take().map(|buf| {
+            let rx_offset = self.rx_offset.get();
+            self.client.get().map(move |client| {
+                client.receive_complete(buf, rx_offset, uart::Error::RepeatCallError)
+            });
+        });
+
+        self.rx_len.set(min(rx_len, rx_buffer.len()));
+        self.rx_offset.set(0);
+        self.rx_buffer.replace(rx_buffer);
+        self.controller
+            .endpoint_resume_out(self.endpoint(ENDPOINT_OUT) as u32);
+    }
+}
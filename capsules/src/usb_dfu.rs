@@ -0,0 +1,690 @@
+//! USB Device Firmware Upgrade (DFU 1.1)
+//!
+//! This function of a composite USB device (`usbc_client::Client`) lets a
+//! host read and write regions of flash through `hil::flash::Flash`, with
+//! standard tools such as `dfu-util`.  Each region, for example the
+//! applications or the kernel, is an alternate setting of the DFU interface.
+//!
+//! The function starts in run-time mode.  After a DFU_DETACH request, a bus
+//! reset within the detach timeout switches it to DFU mode, where the host
+//! downloads or uploads an image one block at a time; without one, the
+//! function goes back to run-time mode once the timeout expires.  A block is
+//! one flash page.  The function is manifestation tolerant: once the last
+//! block of a download is written, the client is told and the next bus reset
+//! switches the function back to run-time mode.
+//!
+//! The USB controller cannot delay the data of a control read, so the page
+//! to upload next is read ahead whenever the function becomes idle and after
+//! each uploaded block.  If the host asks for another block, the request is
+//! stalled and that block is read instead, once any flash operation in
+//! progress is done, so that the host can ask for it again.
+//!
+//! ## Instantiation
+//!
+//! ```rust
+//! pub static mut DFU_PAGE: sam4l::flashcalw::Sam4lPage = sam4l::flashcalw::Sam4lPage::new();
+//!
+//! // Alternate setting 0 holds the applications, 1 the kernel
+//! let dfu_regions = static_init!(
+//!     [capsules::usb_dfu::Region; 2],
+//!     [
+//!         capsules::usb_dfu::Region {
+//!             start_page: 0x30000 / 512,
+//!             num_pages: 0x50000 / 512,
+//!         },
+//!         capsules::usb_dfu::Region {
+//!             start_page: 0x10000 / 512,
+//!             num_pages: 0x20000 / 512,
+//!         },
+//!     ]);
+//! let dfu_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+//!     VirtualMuxAlarm::new(mux_alarm));
+//! let dfu = static_init!(
+//!     capsules::usb_dfu::UsbDfu<
+//!         'static,
+//!         sam4l::flashcalw::FLASHCALW,
+//!         VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+//!     >,
+//!     capsules::usb_dfu::UsbDfu::new(
+//!         &sam4l::flashcalw::FLASH_CONTROLLER,
+//!         dfu_alarm,
+//!         dfu_regions,
+//!         &mut DFU_PAGE));
+//! hil::flash::HasClient::set_client(&sam4l::flashcalw::FLASH_CONTROLLER, dfu);
+//! dfu_alarm.set_client(dfu);
+//! usb_client.register(dfu);
+//! ```
+
+use core::cell::Cell;
+use core::cmp::min;
+use core::default::Default;
+use kernel::ReturnCode;
+use kernel::common::VolatileCell;
+use kernel::common::take_cell::TakeCell;
+use kernel::hil;
+use kernel::hil::time::{self, Frequency};
+use kernel::hil::usb::*;
+use usb::*;
+
+// Class, subclass and protocol codes
+const APPLICATION_SPECIFIC_CLASS: u8 = 0xfe;
+const DFU_SUBCLASS: u8 = 0x01;
+const RUNTIME_PROTOCOL: u8 = 0x01;
+const DFU_MODE_PROTOCOL: u8 = 0x02;
+
+const DFU_FUNCTIONAL_DESCRIPTOR_TYPE: u8 = 0x21;
+
+// bitCanDnload, bitCanUpload and bitManifestationTolerant
+const DFU_ATTRIBUTES: u8 = 0x07;
+
+/// Time the host may wait for a bus reset after DFU_DETACH, in ms
+const DETACH_TIMEOUT: u16 = 1000;
+
+/// Time the host should wait before asking again whether a block is
+/// written, in ms
+const POLL_TIMEOUT: u32 = 10;
+
+// Class-specific requests
+const DFU_DETACH: u8 = 0;
+const DFU_DNLOAD: u8 = 1;
+const DFU_UPLOAD: u8 = 2;
+const DFU_GETSTATUS: u8 = 3;
+const DFU_CLRSTATUS: u8 = 4;
+const DFU_GETSTATE: u8 = 5;
+const DFU_ABORT: u8 = 6;
+
+const STATUS_LENGTH: usize = 6;
+
+/// A range of flash pages that the host can read and write
+#[derive(Copy, Clone)]
+pub struct Region {
+    pub start_page: usize,
+    pub num_pages: usize,
+}
+
+/// Receives notifications from the DFU function
+pub trait Client {
+    /// A download to the region of the given alternate setting is complete,
+    /// for example so that the board can restart
+    fn manifested(&self, alternate_setting: u8);
+}
+
+/// The DFU functional descriptor, which follows the interface descriptors
+struct FunctionalDescriptor {
+    transfer_size: u16,
+}
+
+impl Descriptor for FunctionalDescriptor {
+    fn size(&self) -> usize {
+        9
+    }
+
+    fn write_to_unchecked(&self, buf: &[Cell<u8>]) -> usize {
+        buf[0].set(9); // Size of descriptor
+        buf[1].set(DFU_FUNCTIONAL_DESCRIPTOR_TYPE);
+        buf[2].set(DFU_ATTRIBUTES);
+        buf[3].set(DETACH_TIMEOUT as u8);
+        buf[4].set((DETACH_TIMEOUT >> 8) as u8);
+        buf[5].set(self.transfer_size as u8);
+        buf[6].set((self.transfer_size >> 8) as u8);
+        buf[7].set(0x10); // DFU 1.1
+        buf[8].set(0x01);
+        9
+    }
+}
+
+/// The states of the DFU 1.1 specification
+#[derive(Copy, Clone, PartialEq)]
+enum DfuState {
+    AppIdle = 0,
+    AppDetach = 1,
+    DfuIdle = 2,
+    DnloadSync = 3,
+    DnBusy = 4,
+    DnloadIdle = 5,
+    ManifestSync = 6,
+    UploadIdle = 9,
+    Error = 10,
+}
+
+/// The status codes of the DFU 1.1 specification
+#[derive(Copy, Clone, PartialEq)]
+enum DfuStatus {
+    Ok = 0x00,
+    ErrWrite = 0x03,
+    ErrAddress = 0x08,
+    ErrNotDone = 0x09,
+    ErrStalledPkt = 0x0f,
+}
+
+/// The control transfer in progress
+#[derive(Copy, Clone)]
+enum Transfer {
+    None,
+
+    /// We will send the first bytes of `response`
+    Response(usize),
+
+    /// We will send the status that ends a download, then tell the client
+    ManifestStatus,
+
+    /// We are receiving a block into the page buffer, up to the given
+    /// extent
+    Download(usize, usize),
+
+    /// We are sending a block from the page buffer, with the given extent
+    /// remaining to send
+    Upload(usize, usize),
+}
+
+pub struct UsbDfu<'a, F: hil::flash::Flash + 'static, A: time::Alarm + 'a> {
+    flash: &'a F,
+    alarm: &'a A,
+    regions: &'a [Region],
+    client: Cell<Option<&'a Client>>,
+    page: TakeCell<'static, F::Page>,
+    page_size: usize,
+    /// The page in the page buffer, if it holds a copy of one
+    page_number: Cell<Option<usize>>,
+    /// The page to read into the page buffer for the next upload
+    wanted_page: Cell<Option<usize>>,
+    /// A flash operation is in progress
+    busy: Cell<bool>,
+
+    first_interface: Cell<u8>,
+    alternate_setting: Cell<u8>,
+    state: Cell<DfuState>,
+    status: Cell<DfuStatus>,
+    /// A download completed since we entered DFU mode
+    manifested: Cell<bool>,
+    /// The block being downloaded or uploaded
+    block: Cell<usize>,
+    transfer: Cell<Transfer>,
+    response: Cell<[u8; STATUS_LENGTH]>,
+}
+
+impl<'a, F: hil::flash::Flash + 'static, A: time::Alarm + 'a> UsbDfu<'a, F, A> {
+    pub fn new(
+        flash: &'a F,
+        alarm: &'a A,
+        regions: &'a [Region],
+        page: &'static mut F::Page,
+    ) -> Self {
+        let page_size = page.as_mut().len();
+        UsbDfu {
+            flash: flash,
+            alarm: alarm,
+            regions: regions,
+            client: Cell::new(None),
+            page: TakeCell::new(page),
+            page_size: page_size,
+            page_number: Cell::new(None),
+            wanted_page: Cell::new(None),
+            busy: Cell::new(false),
+            first_interface: Cell::new(0),
+            alternate_setting: Cell::new(0),
+            state: Cell::new(DfuState::AppIdle),
+            status: Cell::new(DfuStatus::Ok),
+            manifested: Cell::new(false),
+            block: Cell::new(0),
+            transfer: Cell::new(Transfer::None),
+            response: Cell::new([0; STATUS_LENGTH]),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a Client) {
+        self.client.set(Some(client));
+    }
+
+    fn region(&self) -> Region {
+        self.regions[self.alternate_setting.get() as usize]
+    }
+
+    fn in_runtime_mode(&self) -> bool {
+        match self.state.get() {
+            DfuState::AppIdle | DfuState::AppDetach => true,
+            _ => false,
+        }
+    }
+
+    /// Read the given block of the current region into the page buffer,
+    /// unless it is there already, so that it can be uploaded
+    fn read_ahead(&self, block: usize) {
+        let region = self.region();
+        if block >= region.num_pages {
+            self.wanted_page.set(None);
+        } else {
+            self.wanted_page.set(Some(region.start_page + block));
+        }
+        self.read_wanted_page();
+    }
+
+    /// Read the page wanted for the next upload, once the flash operation in
+    /// progress, if any, is done
+    fn read_wanted_page(&self) {
+        let page_number = match self.wanted_page.get() {
+            Some(page_number) => page_number,
+            None => return,
+        };
+        if self.busy.get() || self.page_number.get() == Some(page_number) {
+            return;
+        }
+        self.page.take().map(|page| {
+            self.page_number.set(None);
+            if self.flash.read_page(page_number, page) == ReturnCode::SUCCESS {
+                self.busy.set(true);
+                self.page_number.set(Some(page_number));
+            } else {
+                self.wanted_page.set(None);
+            }
+        });
+    }
+
+    /// Abort any operation and wait for the next one
+    fn idle(&self) {
+        self.state.set(DfuState::DfuIdle);
+        self.status.set(DfuStatus::Ok);
+        self.read_ahead(0);
+    }
+
+    /// Reject a request that is not allowed in the current state
+    fn stall(&self) -> CtrlSetupResult {
+        if !self.in_runtime_mode() {
+            self.state.set(DfuState::Error);
+            self.status.set(DfuStatus::ErrStalledPkt);
+        }
+        CtrlSetupResult::ErrGeneric
+    }
+
+    /// Arrange to send `response`, of at most `STATUS_LENGTH` bytes
+    fn respond(&self, response: &[u8]) -> CtrlSetupResult {
+        let mut buf = [0; STATUS_LENGTH];
+        buf[..response.len()].copy_from_slice(response);
+        self.response.set(buf);
+        self.transfer.set(Transfer::Response(response.len()));
+        CtrlSetupResult::Ok
+    }
+
+    fn send_response(&self, buf: &[VolatileCell<u8>], len: usize) -> CtrlInResult {
+        for (i, b) in self.response.get()[..len].iter().enumerate() {
+            buf[i].set(*b);
+        }
+        CtrlInResult::Packet(len, true)
+    }
+
+    fn get_status(&self) -> CtrlSetupResult {
+        let mut reported_state = self.state.get();
+        let mut manifested = false;
+        match self.state.get() {
+            DfuState::DnloadSync => {
+                if self.busy.get() {
+                    // The host will ask again after the poll timeout
+                    reported_state = DfuState::DnBusy;
+                } else if self.status.get() == DfuStatus::Ok {
+                    self.state.set(DfuState::DnloadIdle);
+                    reported_state = DfuState::DnloadIdle;
+                } else {
+                    self.state.set(DfuState::Error);
+                    reported_state = DfuState::Error;
+                }
+            }
+            DfuState::ManifestSync => {
+                // All the blocks are written already
+                manifested = true;
+                self.manifested.set(true);
+                self.idle();
+                reported_state = DfuState::DfuIdle;
+            }
+            _ => {}
+        }
+
+        let poll_timeout = if reported_state == DfuState::DnBusy {
+            POLL_TIMEOUT
+        } else {
+            0
+        };
+        let result = self.respond(&[
+            self.status.get() as u8,
+            poll_timeout as u8,
+            (poll_timeout >> 8) as u8,
+            (poll_timeout >> 16) as u8,
+            reported_state as u8,
+            0, // iString
+        ]);
+        if manifested {
+            self.transfer.set(Transfer::ManifestStatus);
+        }
+        result
+    }
+
+    fn download(&self, block: usize, length: usize) -> CtrlSetupResult {
+        match self.state.get() {
+            DfuState::DfuIdle | DfuState::DnloadIdle if length == 0 => {
+                if self.state.get() == DfuState::DfuIdle {
+                    // There is nothing to manifest
+                    return self.stall();
+                }
+                self.state.set(DfuState::ManifestSync);
+                self.transfer.set(Transfer::None);
+                CtrlSetupResult::Ok
+            }
+            DfuState::DfuIdle | DfuState::DnloadIdle => {
+                if length > self.page_size {
+                    return self.stall();
+                }
+                if block >= self.region().num_pages {
+                    self.state.set(DfuState::Error);
+                    self.status.set(DfuStatus::ErrAddress);
+                    return CtrlSetupResult::ErrGeneric;
+                }
+                if self.page.is_none() {
+                    // The page buffer is still being read ahead
+                    return CtrlSetupResult::ErrGeneric;
+                }
+                self.block.set(block);
+                self.page_number.set(None);
+                self.wanted_page.set(None);
+                self.transfer.set(Transfer::Download(0, length));
+                CtrlSetupResult::Ok
+            }
+            _ => self.stall(),
+        }
+    }
+
+    fn upload(&self, block: usize, length: usize) -> CtrlSetupResult {
+        match self.state.get() {
+            DfuState::DfuIdle | DfuState::UploadIdle => {
+                if block >= self.region().num_pages {
+                    // A short packet ends the upload
+                    self.state.set(DfuState::DfuIdle);
+                    self.transfer.set(Transfer::Upload(0, 0));
+                    return CtrlSetupResult::Ok;
+                }
+                let page_number = self.region().start_page + block;
+                if self.busy.get() || self.page_number.get() != Some(page_number) {
+                    // The read ahead did not guess this block, or is not
+                    // done yet, so read this block for the host to ask again
+                    self.read_ahead(block);
+                    return CtrlSetupResult::ErrGeneric;
+                }
+                self.block.set(block);
+                self.state.set(DfuState::UploadIdle);
+                self.transfer
+                    .set(Transfer::Upload(0, min(length, self.page_size)));
+                CtrlSetupResult::Ok
+            }
+            _ => self.stall(),
+        }
+    }
+
+    /// Write the block that was downloaded
+    fn write_block(&self) {
+        let region = self.region();
+        let page_number = region.start_page + self.block.get();
+        self.state.set(DfuState::DnloadSync);
+        self.page.take().map(|page| {
+            if self.flash.write_page(page_number, page) == ReturnCode::SUCCESS {
+                self.busy.set(true);
+                self.page_number.set(Some(page_number));
+            } else {
+                self.status.set(DfuStatus::ErrWrite);
+            }
+        });
+    }
+}
+
+impl<'a, F: hil::flash::Flash + 'static, A: time::Alarm + 'a> ClassDriver for UsbDfu<'a, F, A> {
+    fn num_interfaces(&self) -> u8 {
+        1
+    }
+
+    fn num_endpoints(&self) -> usize {
+        0
+    }
+
+    fn assign(&self, first_interface: u8, _first_endpoint: usize) {
+        self.first_interface.set(first_interface);
+    }
+
+    fn descriptors(&self, window: &mut DescriptorWindow) {
+        let interface = InterfaceDescriptor {
+            interface_number: self.first_interface.get(),
+            interface_class: APPLICATION_SPECIFIC_CLASS,
+            interface_subclass: DFU_SUBCLASS,
+            interface_protocol: RUNTIME_PROTOCOL,
+            ..Default::default()
+        };
+        if self.in_runtime_mode() {
+            window.copy(&[&interface]);
+        } else {
+            for i in 0..self.regions.len() {
+                window.copy(&[&InterfaceDescriptor {
+                    alternate_setting: i as u8,
+                    interface_protocol: DFU_MODE_PROTOCOL,
+                    ..interface
+                }]);
+            }
+        }
+        window.copy(&[&FunctionalDescriptor {
+            transfer_size: self.page_size as u16,
+        }]);
+    }
+
+    /// There are no endpoints besides endpoint 0
+    fn enable(&self) {}
+
+    fn bus_reset(&self) {
+        self.transfer.set(Transfer::None);
+        match self.state.get() {
+            DfuState::AppIdle => {}
+            DfuState::AppDetach => {
+                self.alarm.disable();
+                self.alternate_setting.set(0);
+                self.manifested.set(false);
+                self.idle();
+            }
+            _ => {
+                if self.manifested.get() {
+                    self.state.set(DfuState::AppIdle);
+                } else {
+                    self.idle();
+                }
+            }
+        }
+    }
+
+    fn ctrl_setup(&self, setup_data: SetupData) -> CtrlSetupResult {
+        self.transfer.set(Transfer::None);
+        if let Some(request) = setup_data.get_standard_request() {
+            return match request {
+                StandardDeviceRequest::SetInterface {
+                    alternate_setting, ..
+                } => {
+                    let valid = if self.in_runtime_mode() {
+                        alternate_setting == 0
+                    } else {
+                        (alternate_setting as usize) < self.regions.len()
+                            && self.state.get() == DfuState::DfuIdle
+                    };
+                    if valid {
+                        self.alternate_setting.set(alternate_setting as u8);
+                        if !self.in_runtime_mode() {
+                            self.idle();
+                        }
+                        CtrlSetupResult::Ok
+                    } else {
+                        CtrlSetupResult::ErrGeneric
+                    }
+                }
+                StandardDeviceRequest::GetInterface { .. } => {
+                    self.respond(&[self.alternate_setting.get()])
+                }
+                _ => CtrlSetupResult::ErrUnrecognizedRequestType,
+            };
+        }
+
+        match setup_data.request_type.request_type() {
+            RequestType::Class => {}
+            _ => return CtrlSetupResult::ErrNonstandardRequest,
+        }
+        let value = setup_data.value as usize;
+        let length = setup_data.length as usize;
+        match (self.in_runtime_mode(), setup_data.request_code) {
+            (_, DFU_GETSTATUS) => self.get_status(),
+            (_, DFU_GETSTATE) => self.respond(&[self.state.get() as u8]),
+            (true, DFU_DETACH) => {
+                // The host resets the bus to switch us to DFU mode, within
+                // the timeout it gives, which is at most ours
+                let timeout_ms = min(value, DETACH_TIMEOUT as usize) as u64;
+                let freq = <A::Frequency>::frequency() as u64;
+                let tics = (timeout_ms * freq / 1000) as u32;
+                self.alarm.set_alarm(self.alarm.now().wrapping_add(tics));
+                self.state.set(DfuState::AppDetach);
+                CtrlSetupResult::Ok
+            }
+            (true, _) => self.stall(),
+            (false, DFU_DNLOAD) => self.download(value, length),
+            (false, DFU_UPLOAD) => self.upload(value, length),
+            (false, DFU_CLRSTATUS) if self.state.get() == DfuState::Error => {
+                self.idle();
+                CtrlSetupResult::Ok
+            }
+            (false, DFU_ABORT) => match self.state.get() {
+                DfuState::DfuIdle
+                | DfuState::DnloadIdle
+                | DfuState::UploadIdle
+                | DfuState::ManifestSync => {
+                    self.idle();
+                    CtrlSetupResult::Ok
+                }
+                _ => self.stall(),
+            },
+            (false, _) => self.stall(),
+        }
+    }
+
+    fn ctrl_in(&self, buf: &[VolatileCell<u8>]) -> CtrlInResult {
+        match self.transfer.get() {
+            Transfer::Response(len) => self.send_response(buf, len),
+            Transfer::ManifestStatus => self.send_response(buf, STATUS_LENGTH),
+            Transfer::Upload(start, end) => {
+                let packet_bytes = min(buf.len(), end - start);
+                if packet_bytes == 0 {
+                    return CtrlInResult::Packet(0, true);
+                }
+                self.page.map_or(CtrlInResult::Error, |page| {
+                    let page = page.as_mut();
+                    for i in 0..packet_bytes {
+                        buf[i].set(page[start + i]);
+                    }
+                    self.transfer
+                        .set(Transfer::Upload(start + packet_bytes, end));
+                    CtrlInResult::Packet(packet_bytes, start + packet_bytes == end)
+                })
+            }
+            _ => CtrlInResult::Error,
+        }
+    }
+
+    fn ctrl_out(&self, buf: &[VolatileCell<u8>], packet_bytes: u32) -> CtrlOutResult {
+        match self.transfer.get() {
+            Transfer::Download(start, end) => {
+                let packet_bytes = min(packet_bytes as usize, end - start);
+                self.page.map_or(CtrlOutResult::Halted, |page| {
+                    let page = page.as_mut();
+                    for i in 0..packet_bytes {
+                        page[start + i] = buf[i].get();
+                    }
+                    self.transfer
+                        .set(Transfer::Download(start + packet_bytes, end));
+                    CtrlOutResult::Ok
+                })
+            }
+            _ => CtrlOutResult::Halted,
+        }
+    }
+
+    fn ctrl_status_complete(&self) {
+        match self.transfer.get() {
+            Transfer::Download(len, end) => {
+                if len < end {
+                    self.state.set(DfuState::Error);
+                    self.status.set(DfuStatus::ErrNotDone);
+                } else {
+                    // Fill the rest of a short block as if it were erased
+                    self.page.map(|page| {
+                        for b in page.as_mut()[len..].iter_mut() {
+                            *b = 0xff;
+                        }
+                    });
+                    self.write_block();
+                }
+            }
+            Transfer::Upload(_, _) => {
+                if self.state.get() == DfuState::UploadIdle {
+                    self.read_ahead(self.block.get() + 1);
+                }
+            }
+            Transfer::ManifestStatus => {
+                let alternate_setting = self.alternate_setting.get();
+                self.client
+                    .get()
+                    .map(|client| client.manifested(alternate_setting));
+            }
+            _ => {}
+        }
+        self.transfer.set(Transfer::None);
+    }
+
+    fn packet_in(&self, _transfer_type: TransferType, _endpoint: usize) -> InResult {
+        InResult::Error
+    }
+
+    fn packet_out(
+        &self,
+        _transfer_type: TransferType,
+        _endpoint: usize,
+        _packet_bytes: u32,
+    ) -> OutResult {
+        OutResult::Halted
+    }
+
+    fn packet_transmitted(&self, _endpoint: usize) {}
+}
+
+impl<'a, F: hil::flash::Flash + 'static, A: time::Alarm + 'a> hil::flash::Client<F>
+    for UsbDfu<'a, F, A>
+{
+    fn read_complete(&self, page: &'static mut F::Page, error: hil::flash::Error) {
+        self.busy.set(false);
+        if error != hil::flash::Error::CommandComplete {
+            self.page_number.set(None);
+            self.wanted_page.set(None);
+        }
+        self.page.replace(page);
+        // The host may have asked for another block in the meantime
+        self.read_wanted_page();
+    }
+
+    fn write_complete(&self, page: &'static mut F::Page, error: hil::flash::Error) {
+        self.busy.set(false);
+        if error != hil::flash::Error::CommandComplete {
+            self.page_number.set(None);
+            self.status.set(DfuStatus::ErrWrite);
+        }
+        self.page.replace(page);
+        self.read_wanted_page();
+    }
+
+    fn erase_complete(&self, _error: hil::flash::Error) {}
+}
+
+impl<'a, F: hil::flash::Flash + 'static, A: time::Alarm + 'a> time::Client for UsbDfu<'a, F, A> {
+    /// The detach timeout expired without a bus reset
+    fn fired(&self) {
+        if self.state.get() == DfuState::AppDetach {
+            self.state.set(DfuState::AppIdle);
+        }
+    }
+}
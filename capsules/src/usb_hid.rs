@@ -0,0 +1,607 @@
+//! USB Human Interface Device (HID 1.11) for applications
+//!
+//! This function of a composite USB device (`usbc_client::Client`) is a HID
+//! device whose reports are defined by an application.  The application
+//! shares its report descriptor with the kernel, which hands it to the host,
+//! and then sends input reports, such as key presses or vendor-defined
+//! telemetry, over an interrupt IN endpoint.  Output reports from the host,
+//! such as keyboard LEDs, arrive on an interrupt OUT endpoint or with
+//! SET_REPORT, and are copied to the application's output buffer.
+//!
+//! The first application to share a report descriptor owns the function
+//! until it exits.  The host reads the report descriptor when it enumerates
+//! the device, so the application must share it before it attaches the
+//! device (with `usb_user`), and a change only takes effect when the device
+//! is enumerated again.
+//!
+//! The function answers GET_REPORT with the last input report sent, and
+//! accepts SET_IDLE, but only sends reports when the application asks for
+//! it.  It does not implement the boot protocol.
+//!
+//! ## Instantiation
+//!
+//! ```rust
+//! let hid = static_init!(
+//!     capsules::usb_hid::UsbHid<'static, sam4l::usbc::Usbc<'static>>,
+//!     capsules::usb_hid::UsbHid::new(&sam4l::usbc::USBC, kernel::Grant::create()));
+//! usb_client.register(hid);
+//! ```
+
+use core::cell::Cell;
+use core::cmp::min;
+use core::default::Default;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+use kernel::common::VolatileCell;
+use kernel::hil::usb::*;
+use usb::*;
+
+/// Syscall number
+pub const DRIVER_NUM: usize = 0x20006;
+
+/// Size of the packets on both endpoints
+const PACKET_SIZE: usize = 8;
+
+/// Largest input or output report
+pub const MAX_REPORT_SIZE: usize = 64;
+
+// Endpoints, relative to the first one assigned
+const ENDPOINT_IN: usize = 0;
+const ENDPOINT_OUT: usize = 1;
+const NUM_ENDPOINTS: usize = 2;
+
+/// Time between two reports the host asks for, in ms
+const POLL_INTERVAL: u8 = 1;
+
+const HID_CLASS: u8 = 0x03;
+
+const HID_DESCRIPTOR_TYPE: u8 = 0x21;
+const REPORT_DESCRIPTOR_TYPE: u8 = 0x22;
+const HID_DESCRIPTOR_LENGTH: usize = 9;
+
+// Standard request to the interface for the class descriptors
+const GET_DESCRIPTOR: u8 = 6;
+
+// Class-specific requests
+const GET_REPORT: u8 = 0x01;
+const GET_IDLE: u8 = 0x02;
+const SET_REPORT: u8 = 0x09;
+const SET_IDLE: u8 = 0x0a;
+
+/// Events passed to the application's callback
+mod event {
+    pub const INPUT_REPORT_SENT: usize = 0;
+    pub const OUTPUT_REPORT_RECEIVED: usize = 1;
+}
+
+/// The HID descriptor, which follows the interface descriptor
+struct HidDescriptor {
+    report_descriptor_length: u16,
+}
+
+impl HidDescriptor {
+    fn bytes(&self) -> [u8; HID_DESCRIPTOR_LENGTH] {
+        [
+            HID_DESCRIPTOR_LENGTH as u8,
+            HID_DESCRIPTOR_TYPE,
+            0x11, // HID 1.11
+            0x01,
+            0, // No country code
+            1, // One class descriptor
+            REPORT_DESCRIPTOR_TYPE,
+            self.report_descriptor_length as u8,
+            (self.report_descriptor_length >> 8) as u8,
+        ]
+    }
+}
+
+impl Descriptor for HidDescriptor {
+    fn size(&self) -> usize {
+        HID_DESCRIPTOR_LENGTH
+    }
+
+    fn write_to_unchecked(&self, buf: &[Cell<u8>]) -> usize {
+        for (i, b) in self.bytes().iter().enumerate() {
+            buf[i].set(*b);
+        }
+        HID_DESCRIPTOR_LENGTH
+    }
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    report_descriptor: Option<AppSlice<Shared, u8>>,
+    input_report: Option<AppSlice<Shared, u8>>,
+    output_report: Option<AppSlice<Shared, u8>>,
+}
+
+/// The control transfer in progress
+#[derive(Copy, Clone)]
+enum Transfer {
+    None,
+
+    /// We will send the report descriptor from the given offset up to the
+    /// given length
+    ReportDescriptor(usize, usize),
+
+    /// We will send `response` from the given offset up to the given length
+    Response(usize, usize),
+
+    /// We are receiving an output report, of which we have the given number
+    /// of bytes out of the given length
+    SetReport(usize, usize),
+}
+
+pub struct UsbHid<'a, C: 'a> {
+    controller: &'a C,
+    apps: Grant<App>,
+    /// The app whose report descriptor we present
+    owner: Cell<Option<AppId>>,
+
+    interface: Cell<u8>,
+    first_endpoint: Cell<usize>,
+    in_storage: [VolatileCell<u8>; PACKET_SIZE],
+    out_storage: [VolatileCell<u8>; PACKET_SIZE],
+    transfer: Cell<Transfer>,
+    response: Cell<[u8; MAX_REPORT_SIZE]>,
+    idle_rate: Cell<u8>,
+
+    /// The input report being sent, or the last one sent
+    input: Cell<[u8; MAX_REPORT_SIZE]>,
+    input_len: Cell<usize>,
+    /// The bytes of the input report handed to the controller, while it is
+    /// being sent
+    input_sent: Cell<Option<usize>>,
+    /// The output report being received
+    output: Cell<[u8; MAX_REPORT_SIZE]>,
+    output_len: Cell<usize>,
+}
+
+impl<'a, C: UsbController> UsbHid<'a, C> {
+    pub fn new(controller: &'a C, apps: Grant<App>) -> Self {
+        UsbHid {
+            controller: controller,
+            apps: apps,
+            owner: Cell::new(None),
+            interface: Cell::new(0),
+            first_endpoint: Cell::new(0),
+            in_storage: [VolatileCell::new(0); PACKET_SIZE],
+            out_storage: [VolatileCell::new(0); PACKET_SIZE],
+            transfer: Cell::new(Transfer::None),
+            response: Cell::new([0; MAX_REPORT_SIZE]),
+            idle_rate: Cell::new(0),
+            input: Cell::new([0; MAX_REPORT_SIZE]),
+            input_len: Cell::new(0),
+            input_sent: Cell::new(None),
+            output: Cell::new([0; MAX_REPORT_SIZE]),
+            output_len: Cell::new(0),
+        }
+    }
+
+    /// The number of one of our endpoints
+    fn endpoint(&self, endpoint: usize) -> usize {
+        self.first_endpoint.get() + endpoint
+    }
+
+    /// Performs an action on the app that owns the function, if it is
+    /// still alive
+    fn with_owner<F, R>(&self, default: R, closure: F) -> R
+    where
+        F: FnOnce(&mut App) -> R,
+        R: Copy,
+    {
+        self.owner.get().map_or(default, |appid| {
+            self.apps
+                .enter(appid, |app, _| closure(app))
+                .unwrap_or(default)
+        })
+    }
+
+    fn report_descriptor_length(&self) -> usize {
+        self.with_owner(0, |app| {
+            app.report_descriptor.as_ref().map_or(0, |descriptor| {
+                min(descriptor.len(), u16::max_value() as usize)
+            })
+        })
+    }
+
+    /// Arrange to send `response`, up to the length of the request
+    fn respond(&self, response: &[u8], length: u16) -> CtrlSetupResult {
+        let len = min(response.len(), length as usize);
+        let mut buf = [0; MAX_REPORT_SIZE];
+        buf[..len].copy_from_slice(&response[..len]);
+        self.response.set(buf);
+        self.transfer.set(Transfer::Response(0, len));
+        CtrlSetupResult::Ok
+    }
+
+    /// Pass an output report to the owner
+    fn output_report_received(&self, len: usize) {
+        let output = self.output.get();
+        self.with_owner((), |app| {
+            let copied = app.output_report.as_mut().map_or(0, |buf| {
+                let copied = min(len, buf.len());
+                buf.as_mut()[..copied].copy_from_slice(&output[..copied]);
+                copied
+            });
+            app.callback.map(|mut cb| {
+                cb.schedule(event::OUTPUT_REPORT_RECEIVED, copied, 0);
+            });
+        });
+    }
+
+    /// Tell the owner that the input report is sent, or dropped if `len` is
+    /// zero
+    fn input_report_sent(&self, len: usize) {
+        self.input_sent.set(None);
+        self.with_owner((), |app| {
+            app.callback.map(|mut cb| {
+                cb.schedule(event::INPUT_REPORT_SENT, len, 0);
+            });
+        });
+    }
+
+    /// Send the first `len` bytes of the app's input report buffer
+    fn send_input_report(&self, appid: AppId, len: usize) -> ReturnCode {
+        if self.owner.get() != Some(appid) || self.input_sent.get().is_some() {
+            return ReturnCode::EBUSY;
+        }
+        if len == 0 || len > MAX_REPORT_SIZE {
+            return ReturnCode::ESIZE;
+        }
+        let mut input = [0; MAX_REPORT_SIZE];
+        let result = self.with_owner(ReturnCode::ENOMEM, |app| {
+            app.input_report
+                .as_ref()
+                .map_or(ReturnCode::ENOMEM, |buf| {
+                    if len > buf.len() {
+                        return ReturnCode::ESIZE;
+                    }
+                    input[..len].copy_from_slice(&buf.as_ref()[..len]);
+                    ReturnCode::SUCCESS
+                })
+        });
+        if result == ReturnCode::SUCCESS {
+            self.input.set(input);
+            self.input_len.set(len);
+            self.input_sent.set(Some(0));
+            self.controller
+                .endpoint_resume_in(self.endpoint(ENDPOINT_IN) as u32);
+        }
+        result
+    }
+}
+
+impl<'a, C: UsbController> ClassDriver for UsbHid<'a, C> {
+    fn num_interfaces(&self) -> u8 {
+        1
+    }
+
+    fn num_endpoints(&self) -> usize {
+        NUM_ENDPOINTS
+    }
+
+    fn assign(&self, first_interface: u8, first_endpoint: usize) {
+        self.interface.set(first_interface);
+        self.first_endpoint.set(first_endpoint);
+    }
+
+    fn descriptors(&self, window: &mut DescriptorWindow) {
+        let packet_size = PACKET_SIZE as u16;
+        window.copy(&[
+            &InterfaceDescriptor {
+                interface_number: self.interface.get(),
+                num_endpoints: NUM_ENDPOINTS as u8,
+                interface_class: HID_CLASS,
+                interface_subclass: 0,
+                interface_protocol: 0,
+                ..Default::default()
+            },
+            &HidDescriptor {
+                report_descriptor_length: self.report_descriptor_length() as u16,
+            },
+            &EndpointDescriptor {
+                endpoint_address: EndpointAddress::new(
+                    self.endpoint(ENDPOINT_IN),
+                    TransferDirection::DeviceToHost,
+                ),
+                transfer_type: TransferType::Interrupt,
+                max_packet_size: packet_size,
+                interval: POLL_INTERVAL,
+            },
+            &EndpointDescriptor {
+                endpoint_address: EndpointAddress::new(
+                    self.endpoint(ENDPOINT_OUT),
+                    TransferDirection::HostToDevice,
+                ),
+                transfer_type: TransferType::Interrupt,
+                max_packet_size: packet_size,
+                interval: POLL_INTERVAL,
+            },
+        ]);
+    }
+
+    fn enable(&self) {
+        let endpoint_in = self.endpoint(ENDPOINT_IN) as u32;
+        let endpoint_out = self.endpoint(ENDPOINT_OUT) as u32;
+        self.controller
+            .endpoint_set_buffer(endpoint_in, &self.in_storage);
+        self.controller
+            .endpoint_set_buffer(endpoint_out, &self.out_storage);
+
+        self.controller
+            .endpoint_in_enable(TransferType::Interrupt, endpoint_in);
+        self.controller
+            .endpoint_out_enable(TransferType::Interrupt, endpoint_out);
+    }
+
+    fn bus_reset(&self) {
+        // Any packet the hardware was holding is lost
+        self.transfer.set(Transfer::None);
+        self.output_len.set(0);
+        if self.input_sent.get().is_some() {
+            self.input_report_sent(0);
+        }
+    }
+
+    fn ctrl_setup(&self, setup_data: SetupData) -> CtrlSetupResult {
+        match setup_data.request_type.recipient() {
+            Recipient::Interface if setup_data.index == self.interface.get() as u16 => {}
+            _ => return CtrlSetupResult::ErrNonstandardRequest,
+        }
+
+        let value_high = (setup_data.value >> 8) as u8;
+        match (
+            setup_data.request_type.request_type(),
+            setup_data.request_code,
+        ) {
+            (RequestType::Standard, GET_DESCRIPTOR) => match value_high {
+                HID_DESCRIPTOR_TYPE => {
+                    let descriptor = HidDescriptor {
+                        report_descriptor_length: self.report_descriptor_length() as u16,
+                    };
+                    self.respond(&descriptor.bytes(), setup_data.length)
+                }
+                REPORT_DESCRIPTOR_TYPE => {
+                    let len = min(self.report_descriptor_length(), setup_data.length as usize);
+                    self.transfer.set(Transfer::ReportDescriptor(0, len));
+                    CtrlSetupResult::Ok
+                }
+                _ => CtrlSetupResult::ErrUnrecognizedDescriptorType,
+            },
+            (RequestType::Class, GET_REPORT) => {
+                let input = self.input.get();
+                self.respond(&input[..self.input_len.get()], setup_data.length)
+            }
+            (RequestType::Class, SET_REPORT) => {
+                let len = setup_data.length as usize;
+                if len > MAX_REPORT_SIZE {
+                    return CtrlSetupResult::ErrGeneric;
+                }
+                self.transfer.set(Transfer::SetReport(0, len));
+                CtrlSetupResult::Ok
+            }
+            (RequestType::Class, GET_IDLE) => {
+                self.respond(&[self.idle_rate.get()], setup_data.length)
+            }
+            (RequestType::Class, SET_IDLE) => {
+                // Reports are only sent on request anyway
+                self.idle_rate.set(value_high);
+                self.transfer.set(Transfer::None);
+                CtrlSetupResult::Ok
+            }
+            _ => CtrlSetupResult::ErrNonstandardRequest,
+        }
+    }
+
+    fn ctrl_in(&self, buf: &[VolatileCell<u8>]) -> CtrlInResult {
+        match self.transfer.get() {
+            Transfer::ReportDescriptor(offset, len) => {
+                let packet_bytes = min(PACKET_SIZE, len - offset);
+                let copied = self.with_owner(false, |app| {
+                    app.report_descriptor.as_ref().map_or(false, |descriptor| {
+                        if descriptor.len() < offset + packet_bytes {
+                            return false;
+                        }
+                        for (i, b) in descriptor.as_ref()[offset..offset + packet_bytes]
+                            .iter()
+                            .enumerate()
+                        {
+                            buf[i].set(*b);
+                        }
+                        true
+                    })
+                });
+                if !copied {
+                    // The app went away
+                    return CtrlInResult::Error;
+                }
+                self.transfer
+                    .set(Transfer::ReportDescriptor(offset + packet_bytes, len));
+                CtrlInResult::Packet(packet_bytes, offset + packet_bytes == len)
+            }
+            Transfer::Response(offset, len) => {
+                let packet_bytes = min(PACKET_SIZE, len - offset);
+                for (i, b) in self.response.get()[offset..offset + packet_bytes]
+                    .iter()
+                    .enumerate()
+                {
+                    buf[i].set(*b);
+                }
+                self.transfer
+                    .set(Transfer::Response(offset + packet_bytes, len));
+                CtrlInResult::Packet(packet_bytes, offset + packet_bytes == len)
+            }
+            _ => CtrlInResult::Error,
+        }
+    }
+
+    fn ctrl_out(&self, buf: &[VolatileCell<u8>], packet_bytes: u32) -> CtrlOutResult {
+        match self.transfer.get() {
+            Transfer::SetReport(received, len) => {
+                let packet_bytes = min(packet_bytes as usize, len - received);
+                let mut output = self.output.get();
+                for i in 0..packet_bytes {
+                    output[received + i] = buf[i].get();
+                }
+                self.output.set(output);
+                self.transfer
+                    .set(Transfer::SetReport(received + packet_bytes, len));
+                if received + packet_bytes == len {
+                    self.output_report_received(len);
+                }
+                CtrlOutResult::Ok
+            }
+            _ => CtrlOutResult::Halted,
+        }
+    }
+
+    fn ctrl_status_complete(&self) {
+        self.transfer.set(Transfer::None);
+    }
+
+    /// Send the next packet of the input report
+    fn packet_in(&self, transfer_type: TransferType, endpoint: usize) -> InResult {
+        if transfer_type != TransferType::Interrupt || endpoint != self.endpoint(ENDPOINT_IN) {
+            return InResult::Error;
+        }
+
+        let len = self.input_len.get();
+        match self.input_sent.get() {
+            Some(sent) if sent < len => {
+                let packet_bytes = min(PACKET_SIZE, len - sent);
+                for (i, b) in self.input.get()[sent..sent + packet_bytes]
+                    .iter()
+                    .enumerate()
+                {
+                    self.in_storage[i].set(*b);
+                }
+                self.input_sent.set(Some(sent + packet_bytes));
+                InResult::Packet(packet_bytes)
+            }
+            // Wait for the last packet to be sent, or for the next report
+            _ => InResult::Delay,
+        }
+    }
+
+    /// Collect the packets of an output report, which ends with a short
+    /// packet
+    fn packet_out(
+        &self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> OutResult {
+        if transfer_type != TransferType::Interrupt || endpoint != self.endpoint(ENDPOINT_OUT) {
+            return OutResult::Halted;
+        }
+
+        let packet_bytes = min(packet_bytes as usize, PACKET_SIZE);
+        let received = self.output_len.get();
+        let len = min(packet_bytes, MAX_REPORT_SIZE - received);
+        let mut output = self.output.get();
+        for i in 0..len {
+            output[received + i] = self.out_storage[i].get();
+        }
+        self.output.set(output);
+
+        if packet_bytes < PACKET_SIZE || received + len == MAX_REPORT_SIZE {
+            self.output_len.set(0);
+            self.output_report_received(received + len);
+        } else {
+            self.output_len.set(received + len);
+        }
+        OutResult::Ok
+    }
+
+    fn packet_transmitted(&self, endpoint: usize) {
+        if endpoint != self.endpoint(ENDPOINT_IN) {
+            return;
+        }
+        if self.input_sent.get() == Some(self.input_len.get()) {
+            self.input_report_sent(self.input_len.get());
+        }
+    }
+}
+
+impl<'a, C: UsbController> Driver for UsbHid<'a, C> {
+    /// Share buffers with the function.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: The report descriptor. The first app to share one owns the
+    ///        function; other apps get EBUSY.
+    /// - `1`: The input report buffer.
+    /// - `2`: The output report buffer.
+    fn allow(&self, appid: AppId, allow_num: usize, slice: AppSlice<Shared, u8>) -> ReturnCode {
+        match allow_num {
+            0 => {
+                let owned_by_other = self.owner.get().map_or(false, |owner| {
+                    owner != appid && self.apps.enter(owner, |_, _| ()).is_ok()
+                });
+                if owned_by_other {
+                    return ReturnCode::EBUSY;
+                }
+                self.apps
+                    .enter(appid, |app, _| {
+                        app.report_descriptor = Some(slice);
+                        self.owner.set(Some(appid));
+                        ReturnCode::SUCCESS
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+            1 => self.apps
+                .enter(appid, |app, _| {
+                    app.input_report = Some(slice);
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            2 => self.apps
+                .enter(appid, |app, _| {
+                    app.output_report = Some(slice);
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Setup callback for HID events. The callback signature is
+    ///        `fn(event, len, 0)`, where `event` is:
+    ///   - `0`: The input report of `len` bytes was sent. `len` is zero if a
+    ///          bus reset dropped it.
+    ///   - `1`: An output report was copied to the output buffer, of which
+    ///          `len` bytes are valid.
+    fn subscribe(&self, subscribe_num: usize, callback: Callback) -> ReturnCode {
+        match subscribe_num {
+            0 => self.apps
+                .enter(callback.app_id(), |app, _| {
+                    app.callback = Some(callback);
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// HID control.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Send the first `arg1` bytes of the input report buffer as an
+    ///        input report. Returns EBUSY if another app owns the function
+    ///        or a report is being sent, and ESIZE if `arg1` is zero, larger
+    ///        than the buffer or larger than `MAX_REPORT_SIZE`.
+    fn command(&self, command_num: usize, arg1: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self.send_input_report(appid, arg1),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
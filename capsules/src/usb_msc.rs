@@ -0,0 +1,801 @@
+//! USB mass storage for an SD card
+//!
+//! This function of a composite USB device (`usbc_client::Client`) exposes
+//! the card of `sdcard::SDCard` as a USB Mass Storage device, using the
+//! Bulk-Only Transport and the SCSI transparent command set, so that hosts
+//! mount it as a removable disk.  It handles INQUIRY, TEST UNIT READY,
+//! REQUEST SENSE, READ CAPACITY(10), READ(10) and WRITE(10), as well as
+//! MODE SENSE(6), PREVENT ALLOW MEDIUM REMOVAL and START STOP UNIT, which
+//! hosts send when they mount and eject the disk.
+//!
+//! The function is the exclusive client of the SD card.  It locks the card
+//! the first time the host checks that the medium is ready, and initializes
+//! it to learn its size.  Until the host ejects the disk or resets the bus,
+//! the kernel users of the card get EBUSY.  If a kernel user is in the middle
+//! of an operation, the function reports that the medium is not ready yet,
+//! and the host retries.
+//!
+//! Blocks are read and written one at a time.  Commands with a data stage
+//! that does not match the command fail, and the host is never sent a
+//! STALL: a short data stage ends with a short or zero-length packet, and
+//! unwanted data from the host is thrown away.
+//!
+//! ## Instantiation
+//!
+//! ```rust
+//! let msc = static_init!(
+//!     capsules::usb_msc::UsbMassStorage<'static,
+//!         sam4l::usbc::Usbc<'static>,
+//!         VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     capsules::usb_msc::UsbMassStorage::new(
+//!         &sam4l::usbc::USBC,
+//!         sdcard,
+//!         &mut capsules::usb_msc::BUFFER));
+//! sdcard.set_exclusive_client(msc);
+//! usb_client.register(msc);
+//! ```
+
+use core::cell::Cell;
+use core::cmp::min;
+use core::default::Default;
+use kernel::ReturnCode;
+use kernel::common::VolatileCell;
+use kernel::common::take_cell::TakeCell;
+use kernel::hil;
+use kernel::hil::usb::*;
+use sdcard::{SDCard, SDCardClient};
+use usb::*;
+
+/// Size of the packets on both endpoints
+const PACKET_SIZE: usize = 8;
+
+const BLOCK_SIZE: usize = 512;
+
+// Endpoints, relative to the first one assigned
+const ENDPOINT_IN: usize = 0;
+const ENDPOINT_OUT: usize = 1;
+const NUM_ENDPOINTS: usize = 2;
+
+// Class, subclass and protocol codes
+// (Universal Serial Bus Mass Storage Class Specification Overview 1.4)
+const MASS_STORAGE_CLASS: u8 = 0x08;
+const SCSI_TRANSPARENT_SUBCLASS: u8 = 0x06;
+const BULK_ONLY_PROTOCOL: u8 = 0x50;
+
+// Class-specific requests
+const GET_MAX_LUN: u8 = 0xfe;
+const BULK_ONLY_RESET: u8 = 0xff;
+
+// Command block and command status wrappers
+const CBW_SIGNATURE: u32 = 0x43425355; // "USBC"
+const CBW_LENGTH: usize = 31;
+const CSW_SIGNATURE: u32 = 0x53425355; // "USBS"
+const CSW_LENGTH: usize = 13;
+const CSW_PASSED: u8 = 0;
+const CSW_FAILED: u8 = 1;
+
+// SCSI operation codes
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const INQUIRY: u8 = 0x12;
+const MODE_SENSE_6: u8 = 0x1a;
+const START_STOP_UNIT: u8 = 0x1b;
+const PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1e;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2a;
+
+/// Standard INQUIRY data: a removable direct-access device
+#[cfg_attr(rustfmt, rustfmt_skip)]
+const INQUIRY_DATA: [u8; 36] = [
+    0x00, 0x80, 0x04, 0x02, 31, 0, 0, 0, // SPC-2, 31 more bytes
+    b'T', b'o', b'c', b'k', b' ', b' ', b' ', b' ', // Vendor
+    b'S', b'D', b' ', b'c', b'a', b'r', b'd', b' ', // Product
+    b' ', b' ', b' ', b' ', b' ', b' ', b' ', b' ',
+    b'1', b'.', b'0', b' ', // Revision
+];
+
+const SENSE_LENGTH: usize = 18;
+
+/// Buffer for one block of the card, assigned in board `main.rs` files
+pub static mut BUFFER: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
+
+/// The reason the last command failed, reported by REQUEST SENSE
+#[derive(Copy, Clone, PartialEq)]
+enum Sense {
+    NoSense,
+    BecomingReady,
+    MediumNotPresent,
+    ReadError,
+    WriteError,
+    InvalidCommand,
+    InvalidField,
+    OutOfRange,
+}
+
+impl Sense {
+    /// The sense key, additional sense code and qualifier
+    fn codes(self) -> (u8, u8, u8) {
+        match self {
+            Sense::NoSense => (0x00, 0x00, 0x00),
+            Sense::BecomingReady => (0x02, 0x04, 0x01),
+            Sense::MediumNotPresent => (0x02, 0x3a, 0x00),
+            Sense::ReadError => (0x03, 0x11, 0x00),
+            Sense::WriteError => (0x03, 0x0c, 0x00),
+            Sense::InvalidCommand => (0x05, 0x20, 0x00),
+            Sense::InvalidField => (0x05, 0x24, 0x00),
+            Sense::OutOfRange => (0x05, 0x21, 0x00),
+        }
+    }
+}
+
+/// What the host sees of the card
+#[derive(Copy, Clone, PartialEq)]
+enum Medium {
+    /// We don't hold the card, or it is not usable
+    Absent,
+
+    /// We hold the card and wait for it to initialize
+    Initializing,
+
+    /// We hold the card, which has the given number of blocks
+    Ready(u32),
+
+    /// The host ejected the disk and gave the card back
+    Ejected,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    /// We are receiving a command block wrapper, of which we have the given
+    /// number of bytes
+    Command(usize),
+
+    /// We will send the buffer from the given offset up to the given length
+    DataIn(usize, usize),
+
+    /// We are receiving a block into the buffer, up to the given offset
+    DataOut(usize),
+
+    /// The card is reading the current block
+    Reading,
+
+    /// The card is writing the current block
+    Writing,
+
+    /// We will send a zero-length packet to end a short data stage
+    ShortPacket,
+
+    /// We are throwing away the given number of bytes of data from the host
+    Discard(u32),
+
+    /// We will send the command status wrapper from the given offset
+    Status(usize),
+}
+
+pub struct UsbMassStorage<'a, C: 'a, A: hil::time::Alarm + 'a> {
+    controller: &'a C,
+    sdcard: &'a SDCard<'a, A>,
+    buffer: TakeCell<'static, [u8]>,
+    medium: Cell<Medium>,
+
+    interface: Cell<u8>,
+    first_endpoint: Cell<usize>,
+    in_storage: [VolatileCell<u8>; PACKET_SIZE],
+    out_storage: [VolatileCell<u8>; PACKET_SIZE],
+    /// We will answer GET_MAX_LUN
+    get_max_lun: Cell<bool>,
+
+    state: Cell<State>,
+    cbw: Cell<[u8; CBW_LENGTH]>,
+    csw: Cell<[u8; CSW_LENGTH]>,
+    tag: Cell<u32>,
+    /// The host expects data from us rather than to us
+    data_in: Cell<bool>,
+    /// The data transfer length of the command
+    data_length: Cell<u32>,
+    /// The part of the data transfer length not transferred yet
+    residue: Cell<u32>,
+    failed: Cell<bool>,
+    sense: Cell<Sense>,
+    /// The block to read or write next, and how many are left
+    block: Cell<u32>,
+    blocks_left: Cell<u32>,
+}
+
+fn read_be16(buf: &[u8]) -> u16 {
+    (buf[0] as u16) << 8 | buf[1] as u16
+}
+
+fn read_be32(buf: &[u8]) -> u32 {
+    (buf[0] as u32) << 24 | (buf[1] as u32) << 16 | (buf[2] as u32) << 8 | buf[3] as u32
+}
+
+fn read_le32(buf: &[u8]) -> u32 {
+    (buf[3] as u32) << 24 | (buf[2] as u32) << 16 | (buf[1] as u32) << 8 | buf[0] as u32
+}
+
+fn write_le32(buf: &mut [u8], value: u32) {
+    buf[0] = value as u8;
+    buf[1] = (value >> 8) as u8;
+    buf[2] = (value >> 16) as u8;
+    buf[3] = (value >> 24) as u8;
+}
+
+impl<'a, C: UsbController, A: hil::time::Alarm + 'a> UsbMassStorage<'a, C, A> {
+    pub fn new(
+        controller: &'a C,
+        sdcard: &'a SDCard<'a, A>,
+        buffer: &'static mut [u8; BLOCK_SIZE],
+    ) -> Self {
+        UsbMassStorage {
+            controller: controller,
+            sdcard: sdcard,
+            buffer: TakeCell::new(buffer),
+            medium: Cell::new(Medium::Absent),
+            interface: Cell::new(0),
+            first_endpoint: Cell::new(0),
+            in_storage: [VolatileCell::new(0); PACKET_SIZE],
+            out_storage: [VolatileCell::new(0); PACKET_SIZE],
+            get_max_lun: Cell::new(false),
+            state: Cell::new(State::Command(0)),
+            cbw: Cell::new([0; CBW_LENGTH]),
+            csw: Cell::new([0; CSW_LENGTH]),
+            tag: Cell::new(0),
+            data_in: Cell::new(false),
+            data_length: Cell::new(0),
+            residue: Cell::new(0),
+            failed: Cell::new(false),
+            sense: Cell::new(Sense::NoSense),
+            block: Cell::new(0),
+            blocks_left: Cell::new(0),
+        }
+    }
+
+    /// The number of one of our endpoints
+    fn endpoint(&self, endpoint: usize) -> usize {
+        self.first_endpoint.get() + endpoint
+    }
+
+    fn resume_in(&self) {
+        self.controller
+            .endpoint_resume_in(self.endpoint(ENDPOINT_IN) as u32);
+    }
+
+    fn resume_out(&self) {
+        self.controller
+            .endpoint_resume_out(self.endpoint(ENDPOINT_OUT) as u32);
+    }
+
+    /// Drop the command in progress and wait for the next one
+    fn reset(&self) {
+        self.state.set(State::Command(0));
+        self.blocks_left.set(0);
+    }
+
+    /// Give the card back to its kernel users
+    fn release(&self, medium: Medium) {
+        self.medium.set(medium);
+        self.sdcard.unlock();
+    }
+
+    /// Check that the host can access the card, taking and initializing the
+    /// card if necessary
+    fn check_medium(&self) -> Result<u32, Sense> {
+        match self.medium.get() {
+            Medium::Ready(num_blocks) => Ok(num_blocks),
+            Medium::Initializing => Err(Sense::BecomingReady),
+            Medium::Ejected => Err(Sense::MediumNotPresent),
+            Medium::Absent => {
+                if !self.sdcard.is_installed() {
+                    return Err(Sense::MediumNotPresent);
+                }
+                if !self.sdcard.is_locked() && self.sdcard.lock() != ReturnCode::SUCCESS {
+                    // A kernel user is in the middle of an operation
+                    return Err(Sense::BecomingReady);
+                }
+                if self.sdcard.exclusive_initialize() == ReturnCode::SUCCESS {
+                    self.medium.set(Medium::Initializing);
+                    Err(Sense::BecomingReady)
+                } else {
+                    Err(Sense::MediumNotPresent)
+                }
+            }
+        }
+    }
+
+    /// Handle a complete command block wrapper
+    fn command(&self) {
+        let cbw = self.cbw.get();
+        let cb_length = cbw[14] as usize;
+        let meaningful = read_le32(&cbw[0..4]) == CBW_SIGNATURE && cbw[13] == 0 && cb_length >= 1
+            && cb_length <= 16;
+        if !meaningful {
+            // Wait for a valid one
+            self.state.set(State::Command(0));
+            return;
+        }
+
+        self.tag.set(read_le32(&cbw[4..8]));
+        self.data_length.set(read_le32(&cbw[8..12]));
+        self.residue.set(self.data_length.get());
+        self.data_in.set(cbw[12] & 0x80 != 0);
+        self.failed.set(false);
+
+        let cb = &cbw[15..15 + cb_length];
+        if cb[0] == REQUEST_SENSE {
+            let (key, code, qualifier) = self.sense.get().codes();
+            let mut sense = [0; SENSE_LENGTH];
+            sense[0] = 0x70; // Current error, fixed format
+            sense[2] = key;
+            sense[7] = (SENSE_LENGTH - 8) as u8;
+            sense[12] = code;
+            sense[13] = qualifier;
+            self.sense.set(Sense::NoSense);
+            self.respond(&sense, cb.get(4).map_or(0, |&len| len as usize));
+            return;
+        }
+        self.sense.set(Sense::NoSense);
+
+        match cb[0] {
+            TEST_UNIT_READY => match self.check_medium() {
+                Ok(_) => self.end_data(),
+                Err(sense) => self.fail(sense),
+            },
+            INQUIRY if cb_length >= 6 => {
+                self.respond(&INQUIRY_DATA, read_be16(&cb[3..5]) as usize);
+            }
+            MODE_SENSE_6 if cb_length >= 6 => {
+                // Header only: no medium type, not write-protected, no
+                // block descriptors or pages
+                self.respond(&[3, 0, 0, 0], cb[4] as usize);
+            }
+            START_STOP_UNIT if cb_length >= 6 => {
+                match cb[4] & 0x03 {
+                    // Eject
+                    0x02 => self.release(Medium::Ejected),
+                    // Load
+                    0x03 => self.release(Medium::Absent),
+                    _ => {}
+                }
+                self.end_data();
+            }
+            PREVENT_ALLOW_MEDIUM_REMOVAL => {
+                // The card can be pulled out anyway
+                self.end_data();
+            }
+            READ_CAPACITY_10 => match self.check_medium() {
+                Ok(num_blocks) => {
+                    let last_block = num_blocks.wrapping_sub(1);
+                    self.respond(
+                        &[
+                            (last_block >> 24) as u8,
+                            (last_block >> 16) as u8,
+                            (last_block >> 8) as u8,
+                            last_block as u8,
+                            0,
+                            0,
+                            (BLOCK_SIZE >> 8) as u8,
+                            BLOCK_SIZE as u8,
+                        ],
+                        8,
+                    );
+                }
+                Err(sense) => self.fail(sense),
+            },
+            READ_10 | WRITE_10 if cb_length >= 10 => {
+                let num_blocks = match self.check_medium() {
+                    Ok(num_blocks) => num_blocks,
+                    Err(sense) => return self.fail(sense),
+                };
+                let block = read_be32(&cb[2..6]);
+                let count = read_be16(&cb[7..9]) as u32;
+                let data_in = cb[0] == READ_10;
+                if block as u64 + count as u64 > num_blocks as u64 {
+                    self.fail(Sense::OutOfRange);
+                } else if (count > 0 && data_in != self.data_in.get())
+                    || (self.data_length.get() as u64) < count as u64 * BLOCK_SIZE as u64
+                {
+                    // The data stage does not match the command
+                    self.fail(Sense::InvalidField);
+                } else if count == 0 {
+                    self.end_data();
+                } else {
+                    self.block.set(block);
+                    self.blocks_left.set(count);
+                    if data_in {
+                        self.read_block();
+                    } else {
+                        self.state.set(State::DataOut(0));
+                    }
+                }
+            }
+            _ => self.fail(Sense::InvalidCommand),
+        }
+    }
+
+    /// Send `data`, up to the allocation length of the command
+    fn respond(&self, data: &[u8], allocation_length: usize) {
+        let len = min(
+            min(data.len(), allocation_length),
+            self.residue.get() as usize,
+        );
+        if !self.data_in.get() {
+            return self.fail(Sense::InvalidField);
+        }
+        let copied = self.buffer.map(|buffer| {
+            buffer[..len].copy_from_slice(&data[..len]);
+        });
+        if copied.is_none() {
+            return self.fail(Sense::BecomingReady);
+        }
+        if len == 0 {
+            return self.end_data();
+        }
+        self.state.set(State::DataIn(0, len));
+        self.resume_in();
+    }
+
+    /// Complete the command with an error
+    fn fail(&self, sense: Sense) {
+        self.failed.set(true);
+        self.sense.set(sense);
+        self.blocks_left.set(0);
+        self.end_data();
+    }
+
+    /// Finish the data stage if the host expects more than we transferred,
+    /// then send the status
+    fn end_data(&self) {
+        let residue = self.residue.get();
+        if residue == 0 {
+            self.status();
+        } else if self.data_in.get() {
+            let sent = self.data_length.get() - residue;
+            if sent as usize % PACKET_SIZE == 0 {
+                // The last packet we sent, if any, was not short
+                self.state.set(State::ShortPacket);
+                self.resume_in();
+            } else {
+                self.status();
+            }
+        } else {
+            self.state.set(State::Discard(residue));
+            self.resume_out();
+        }
+    }
+
+    fn status(&self) {
+        let mut csw = [0; CSW_LENGTH];
+        write_le32(&mut csw[0..4], CSW_SIGNATURE);
+        write_le32(&mut csw[4..8], self.tag.get());
+        write_le32(&mut csw[8..12], self.residue.get());
+        csw[12] = if self.failed.get() {
+            CSW_FAILED
+        } else {
+            CSW_PASSED
+        };
+        self.csw.set(csw);
+        self.state.set(State::Status(0));
+        self.resume_in();
+    }
+
+    /// Have the card read the current block
+    fn read_block(&self) {
+        self.state.set(State::Reading);
+        if !self.sdcard.is_initialized() {
+            return self.fail(Sense::ReadError);
+        }
+        let result = self.buffer.take().map_or(ReturnCode::ENOMEM, |buffer| {
+            let (result, buffer) = self
+                .sdcard
+                .exclusive_read_blocks(buffer, self.block.get(), 1);
+            buffer.map(|buffer| self.buffer.replace(buffer));
+            result
+        });
+        if result != ReturnCode::SUCCESS {
+            self.fail(Sense::ReadError);
+        }
+    }
+
+    /// Have the card write the received block
+    fn write_block(&self) {
+        self.state.set(State::Writing);
+        if !self.sdcard.is_initialized() {
+            return self.fail(Sense::WriteError);
+        }
+        let result = self.buffer.take().map_or(ReturnCode::ENOMEM, |buffer| {
+            let (result, buffer) = self
+                .sdcard
+                .exclusive_write_blocks(buffer, self.block.get(), 1);
+            buffer.map(|buffer| self.buffer.replace(buffer));
+            result
+        });
+        if result != ReturnCode::SUCCESS {
+            self.fail(Sense::WriteError);
+        }
+    }
+
+    /// Move on to the next block of a read or write, if any
+    fn next_block(&self) -> bool {
+        let blocks_left = self.blocks_left.get().saturating_sub(1);
+        self.blocks_left.set(blocks_left);
+        self.block.set(self.block.get().wrapping_add(1));
+        blocks_left > 0
+    }
+}
+
+impl<'a, C: UsbController, A: hil::time::Alarm + 'a> ClassDriver for UsbMassStorage<'a, C, A> {
+    fn num_interfaces(&self) -> u8 {
+        1
+    }
+
+    fn num_endpoints(&self) -> usize {
+        NUM_ENDPOINTS
+    }
+
+    fn assign(&self, first_interface: u8, first_endpoint: usize) {
+        self.interface.set(first_interface);
+        self.first_endpoint.set(first_endpoint);
+    }
+
+    fn descriptors(&self, window: &mut DescriptorWindow) {
+        let packet_size = PACKET_SIZE as u16;
+        window.copy(&[
+            &InterfaceDescriptor {
+                interface_number: self.interface.get(),
+                num_endpoints: NUM_ENDPOINTS as u8,
+                interface_class: MASS_STORAGE_CLASS,
+                interface_subclass: SCSI_TRANSPARENT_SUBCLASS,
+                interface_protocol: BULK_ONLY_PROTOCOL,
+                ..Default::default()
+            },
+            &EndpointDescriptor {
+                endpoint_address: EndpointAddress::new(
+                    self.endpoint(ENDPOINT_IN),
+                    TransferDirection::DeviceToHost,
+                ),
+                transfer_type: TransferType::Bulk,
+                max_packet_size: packet_size,
+                interval: 0,
+            },
+            &EndpointDescriptor {
+                endpoint_address: EndpointAddress::new(
+                    self.endpoint(ENDPOINT_OUT),
+                    TransferDirection::HostToDevice,
+                ),
+                transfer_type: TransferType::Bulk,
+                max_packet_size: packet_size,
+                interval: 0,
+            },
+        ]);
+    }
+
+    fn enable(&self) {
+        let endpoint_in = self.endpoint(ENDPOINT_IN) as u32;
+        let endpoint_out = self.endpoint(ENDPOINT_OUT) as u32;
+        self.controller
+            .endpoint_set_buffer(endpoint_in, &self.in_storage);
+        self.controller
+            .endpoint_set_buffer(endpoint_out, &self.out_storage);
+
+        self.controller
+            .endpoint_in_enable(TransferType::Bulk, endpoint_in);
+        self.controller
+            .endpoint_out_enable(TransferType::Bulk, endpoint_out);
+    }
+
+    fn bus_reset(&self) {
+        // The host will enumerate the device and mount the disk again
+        self.reset();
+        self.release(Medium::Absent);
+    }
+
+    fn ctrl_setup(&self, setup_data: SetupData) -> CtrlSetupResult {
+        match (
+            setup_data.request_type.request_type(),
+            setup_data.request_type.recipient(),
+        ) {
+            (RequestType::Class, Recipient::Interface)
+                if setup_data.index == self.interface.get() as u16 =>
+            {
+                match setup_data.request_code {
+                    GET_MAX_LUN if setup_data.length > 0 => {
+                        self.get_max_lun.set(true);
+                        CtrlSetupResult::Ok
+                    }
+                    BULK_ONLY_RESET if setup_data.length == 0 => {
+                        self.reset();
+                        self.resume_out();
+                        CtrlSetupResult::Ok
+                    }
+                    _ => CtrlSetupResult::ErrNonstandardRequest,
+                }
+            }
+            _ => CtrlSetupResult::ErrNonstandardRequest,
+        }
+    }
+
+    fn ctrl_in(&self, buf: &[VolatileCell<u8>]) -> CtrlInResult {
+        if self.get_max_lun.get() {
+            // The card is the only logical unit
+            buf[0].set(0);
+            CtrlInResult::Packet(1, true)
+        } else {
+            CtrlInResult::Error
+        }
+    }
+
+    fn ctrl_out(&self, _buf: &[VolatileCell<u8>], _packet_bytes: u32) -> CtrlOutResult {
+        CtrlOutResult::Halted
+    }
+
+    fn ctrl_status_complete(&self) {
+        self.get_max_lun.set(false);
+    }
+
+    fn packet_in(&self, transfer_type: TransferType, endpoint: usize) -> InResult {
+        if transfer_type != TransferType::Bulk || endpoint != self.endpoint(ENDPOINT_IN) {
+            return InResult::Error;
+        }
+
+        match self.state.get() {
+            State::DataIn(offset, len) => {
+                let packet_bytes = min(PACKET_SIZE, len - offset);
+                let copied = self.buffer.map(|buffer| {
+                    for (i, b) in buffer[offset..offset + packet_bytes].iter().enumerate() {
+                        self.in_storage[i].set(*b);
+                    }
+                });
+                if copied.is_none() {
+                    return InResult::Delay;
+                }
+                self.residue.set(self.residue.get() - packet_bytes as u32);
+                if offset + packet_bytes < len {
+                    self.state.set(State::DataIn(offset + packet_bytes, len));
+                } else if self.blocks_left.get() > 0 && self.next_block() {
+                    self.read_block();
+                } else {
+                    self.end_data();
+                }
+                InResult::Packet(packet_bytes)
+            }
+            State::ShortPacket => {
+                self.status();
+                InResult::Packet(0)
+            }
+            State::Status(offset) => {
+                let packet_bytes = min(PACKET_SIZE, CSW_LENGTH - offset);
+                for (i, b) in self.csw.get()[offset..offset + packet_bytes]
+                    .iter()
+                    .enumerate()
+                {
+                    self.in_storage[i].set(*b);
+                }
+                if offset + packet_bytes < CSW_LENGTH {
+                    self.state.set(State::Status(offset + packet_bytes));
+                } else {
+                    // The host may have sent the next command already
+                    self.reset();
+                    self.resume_out();
+                }
+                InResult::Packet(packet_bytes)
+            }
+            _ => InResult::Delay,
+        }
+    }
+
+    fn packet_out(
+        &self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> OutResult {
+        if transfer_type != TransferType::Bulk || endpoint != self.endpoint(ENDPOINT_OUT) {
+            return OutResult::Halted;
+        }
+
+        let packet_bytes = min(packet_bytes as usize, PACKET_SIZE);
+        match self.state.get() {
+            State::Command(received) => {
+                if received + packet_bytes > CBW_LENGTH {
+                    // Not a command block wrapper, wait for the next one
+                    self.state.set(State::Command(0));
+                    return OutResult::Ok;
+                }
+                let mut cbw = self.cbw.get();
+                for i in 0..packet_bytes {
+                    cbw[received + i] = self.out_storage[i].get();
+                }
+                self.cbw.set(cbw);
+                if received + packet_bytes == CBW_LENGTH {
+                    self.command();
+                } else {
+                    self.state.set(State::Command(received + packet_bytes));
+                }
+                OutResult::Ok
+            }
+            State::DataOut(offset) => {
+                let len = min(packet_bytes, BLOCK_SIZE - offset);
+                let copied = self.buffer.map(|buffer| {
+                    for i in 0..len {
+                        buffer[offset + i] = self.out_storage[i].get();
+                    }
+                });
+                if copied.is_none() {
+                    return OutResult::Delay;
+                }
+                self.residue.set(self.residue.get() - len as u32);
+                if offset + len < BLOCK_SIZE {
+                    self.state.set(State::DataOut(offset + len));
+                } else {
+                    self.write_block();
+                }
+                OutResult::Ok
+            }
+            State::Discard(remaining) => {
+                let remaining = remaining.saturating_sub(packet_bytes as u32);
+                if remaining > 0 {
+                    self.state.set(State::Discard(remaining));
+                } else {
+                    self.status();
+                }
+                OutResult::Ok
+            }
+            _ => OutResult::Delay,
+        }
+    }
+
+    fn packet_transmitted(&self, _endpoint: usize) {}
+}
+
+impl<'a, C: UsbController, A: hil::time::Alarm + 'a> SDCardClient for UsbMassStorage<'a, C, A> {
+    fn card_detection_changed(&self, _installed: bool) {
+        // The card has to be initialized again, if it is still there
+        if self.medium.get() != Medium::Ejected {
+            self.medium.set(Medium::Absent);
+        }
+    }
+
+    fn init_done(&self, block_size: u32, total_size: u64) {
+        if self.medium.get() == Medium::Initializing {
+            let num_blocks = total_size / block_size as u64;
+            self.medium.set(Medium::Ready(num_blocks as u32));
+        }
+    }
+
+    fn read_done(&self, data: &'static mut [u8], _len: usize) {
+        self.buffer.replace(data);
+        if self.state.get() == State::Reading {
+            self.state.set(State::DataIn(0, BLOCK_SIZE));
+            self.resume_in();
+        }
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8]) {
+        self.buffer.replace(buffer);
+        if self.state.get() == State::Writing {
+            if self.next_block() {
+                self.state.set(State::DataOut(0));
+                self.resume_out();
+            } else {
+                self.end_data();
+            }
+        }
+    }
+
+    fn error(&self, _error: u32) {
+        if self.medium.get() == Medium::Initializing {
+            self.medium.set(Medium::Absent);
+        }
+        let sense = match self.state.get() {
+            State::Reading => Sense::ReadError,
+            State::Writing => Sense::WriteError,
+            _ => return,
+        };
+        self.sdcard.take_buffer().map(|buffer| {
+            self.buffer.replace(buffer);
+        });
+        self.fail(sense);
+    }
+}
@@ -11,9 +11,12 @@
 //!
 //! ```rust
 //! // Configure the USB controller
+//! let usb_descriptors = static_init!(
+//!     capsules::usb::DescriptorBuilder<'static>,
+//!     capsules::usb::DescriptorBuilder::new(0x6667, 0xabcd));
 //! let usb_client = static_init!(
 //!     capsules::usbc_client::Client<'static, sam4l::usbc::Usbc<'static>>,
-//!     capsules::usbc_client::Client::new(&sam4l::usbc::USBC));
+//!     capsules::usbc_client::Client::new(&sam4l::usbc::USBC, usb_descriptors));
 //! sam4l::usbc::USBC.set_client(usb_client);
 //!
 //! // Configure the USB userspace driver
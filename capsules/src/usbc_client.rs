@@ -1,35 +1,60 @@
-//! A bare-bones client of the USB hardware interface
+//! A composite USB device
 //!
-//! It responds to standard device requests and can be enumerated.
+//! This client of the USB hardware interface responds to standard device
+//! requests, with descriptors built from a board-specific
+//! `usb::DescriptorBuilder`, and can be enumerated.  Functions implementing
+//! `usb::ClassDriver` register with it to get interfaces and endpoints; the
+//! control requests addressed to those, and the traffic on those endpoints,
+//! are routed to them.  Without functions, the device has no interfaces.
+//!
+//! ## Instantiation
+//!
+//! ```rust
+//! let descriptors = static_init!(
+//!     capsules::usb::DescriptorBuilder<'static>,
+//!     capsules::usb::DescriptorBuilder::new(0x6667, 0xabcd)
+//!         .strings("XYZ Corp.", "The Zorpinator", "Serial No. 5"));
+//! let usb_client = static_init!(
+//!     capsules::usbc_client::Client<'static, sam4l::usbc::Usbc<'static>>,
+//!     capsules::usbc_client::Client::new(&sam4l::usbc::USBC, descriptors));
+//! sam4l::usbc::USBC.set_client(usb_client);
+//!
+//! // Functions register before the device is enabled
+//! usb_client.register(cdc);
+//! ```
 
 use core::cell::Cell;
 use core::cmp::min;
 use core::default::Default;
+use kernel::ReturnCode;
 use kernel::common::VolatileCell;
 use kernel::hil;
 use kernel::hil::usb::*;
 use usb::*;
 
-const VENDOR_ID: u16 = 0x6667;
-const PRODUCT_ID: u16 = 0xabcd;
+/// Maximum number of functions of the device
+const MAX_FUNCTIONS: usize = 4;
 
-static LANGUAGES: &'static [u16] = &[
-    0x0409 // English (United States)
-];
+/// Number of endpoints of the controller, including endpoint 0
+const NUM_ENDPOINTS: usize = 8;
 
-static STRINGS: &'static [&'static str] = &[
-    "XYZ Corp.",      // Manufacturer
-    "The Zorpinator", // Product
-    "Serial No. 5",   // Serial number
-];
-
-const DESCRIPTOR_BUFLEN: usize = 30;
+/// A registered function, with its first interface and endpoint numbers
+#[derive(Copy, Clone)]
+struct Function<'a> {
+    class: &'a ClassDriver,
+    first_interface: u8,
+    first_endpoint: usize,
+}
 
 pub struct Client<'a, C: 'a> {
     controller: &'a C,
+    descriptors: &'a DescriptorBuilder<'a>,
+    functions: [Cell<Option<Function<'a>>>; MAX_FUNCTIONS],
+    num_interfaces: Cell<u8>,
+    next_endpoint: Cell<usize>,
     state: Cell<State>,
     ep0_storage: [VolatileCell<u8>; 8],
-    descriptor_storage: [Cell<u8>; DESCRIPTOR_BUFLEN],
+    descriptor_storage: [Cell<u8>; MAX_DESCRIPTOR_SIZE],
 }
 
 #[derive(Copy, Clone)]
@@ -41,39 +66,191 @@ enum State {
     /// remaining to send
     CtrlIn(usize, usize),
 
-    /// We will accept data from the host
-    CtrlOut,
+    /// We are doing a Control In transfer of the configuration descriptors,
+    /// with the given extent remaining to send
+    CtrlInConfiguration(usize, usize),
+
+    /// The function with the given index is handling a control request
+    Function(usize),
 
     SetAddress,
 }
 
 impl<'a, C: UsbController> Client<'a, C> {
-    pub fn new(controller: &'a C) -> Self {
+    pub fn new(controller: &'a C, descriptors: &'a DescriptorBuilder<'a>) -> Self {
         Client {
             controller: controller,
+            descriptors: descriptors,
+            functions: Default::default(),
+            num_interfaces: Cell::new(0),
+            next_endpoint: Cell::new(1),
             state: Cell::new(State::Init),
             ep0_storage: [VolatileCell::new(0); 8],
             descriptor_storage: Default::default(),
         }
     }
 
+    /// Add a function to the device and assign it interface and endpoint
+    /// numbers.  This must be done before the device is enabled.
+    pub fn register(&self, class: &'a ClassDriver) -> ReturnCode {
+        let first_endpoint = self.next_endpoint.get();
+        if first_endpoint + class.num_endpoints() > NUM_ENDPOINTS {
+            return ReturnCode::ENOMEM;
+        }
+        match self.functions.iter().find(|f| f.get().is_none()) {
+            Some(slot) => {
+                let first_interface = self.num_interfaces.get();
+                slot.set(Some(Function {
+                    class: class,
+                    first_interface: first_interface,
+                    first_endpoint: first_endpoint,
+                }));
+                self.num_interfaces
+                    .set(first_interface + class.num_interfaces());
+                self.next_endpoint
+                    .set(first_endpoint + class.num_endpoints());
+                class.assign(first_interface, first_endpoint);
+                ReturnCode::SUCCESS
+            }
+            None => ReturnCode::ENOMEM,
+        }
+    }
+
     #[inline]
     fn ep0_buf(&self) -> &[VolatileCell<u8>] {
         &self.ep0_storage
     }
 
     #[inline]
-    fn descriptor_buf(&'a self) -> &'a [Cell<u8>] {
+    fn descriptor_buf(&self) -> &[Cell<u8>] {
         &self.descriptor_storage
     }
+
+    fn function(&self, index: usize) -> Option<&'a ClassDriver> {
+        self.functions[index].get().map(|f| f.class)
+    }
+
+    /// Index of the function that owns the given interface
+    fn function_of_interface(&self, interface: u8) -> Option<usize> {
+        self.functions.iter().position(|f| {
+            f.get().map_or(false, |f| {
+                interface >= f.first_interface
+                    && interface - f.first_interface < f.class.num_interfaces()
+            })
+        })
+    }
+
+    /// Index of the function that owns the given endpoint
+    fn function_of_endpoint(&self, endpoint: usize) -> Option<usize> {
+        self.functions.iter().position(|f| {
+            f.get().map_or(false, |f| {
+                endpoint >= f.first_endpoint
+                    && endpoint - f.first_endpoint < f.class.num_endpoints()
+            })
+        })
+    }
+
+    /// Append the descriptors of all the functions to `window`
+    fn function_descriptors(&self, window: &mut DescriptorWindow) {
+        for f in self.functions.iter() {
+            f.get().map(|f| f.class.descriptors(window));
+        }
+    }
+
+    /// Handle a standard request addressed to the device
+    fn standard_request(&self, request: StandardDeviceRequest) -> CtrlSetupResult {
+        match request {
+            StandardDeviceRequest::GetDescriptor {
+                descriptor_type,
+                descriptor_index,
+                lang_id,
+                requested_length,
+            } => {
+                match descriptor_type {
+                    DescriptorType::Device => match descriptor_index {
+                        0 => {
+                            let buf = self.descriptor_buf();
+                            let len = self.descriptors.device_descriptor().write_to(buf);
+                            let end = min(len, requested_length as usize);
+                            self.state.set(State::CtrlIn(0, end));
+                            CtrlSetupResult::Ok
+                        }
+                        _ => CtrlSetupResult::ErrInvalidDeviceIndex,
+                    },
+                    DescriptorType::Configuration => match descriptor_index {
+                        0 => {
+                            let mut window = DescriptorWindow::new(0, &[]);
+                            self.descriptors.configuration_descriptors(
+                                self.num_interfaces.get(),
+                                &|w| self.function_descriptors(w),
+                                &mut window,
+                            );
+                            let end = min(window.position(), requested_length as usize);
+                            self.state.set(State::CtrlInConfiguration(0, end));
+                            CtrlSetupResult::Ok
+                        }
+                        _ => CtrlSetupResult::ErrInvalidConfigurationIndex,
+                    },
+                    DescriptorType::String => {
+                        let buf = self.descriptor_buf();
+                        match self.descriptors
+                            .write_string_descriptor(descriptor_index, lang_id, buf)
+                        {
+                            Some(len) => {
+                                let end = min(len, requested_length as usize);
+                                self.state.set(State::CtrlIn(0, end));
+                                CtrlSetupResult::Ok
+                            }
+                            None => CtrlSetupResult::ErrInvalidStringIndex,
+                        }
+                    }
+                    DescriptorType::DeviceQualifier => {
+                        // We are full-speed only, so we must
+                        // respond with a request error
+                        CtrlSetupResult::ErrNoDeviceQualifier
+                    }
+                    _ => CtrlSetupResult::ErrUnrecognizedDescriptorType,
+                } // match descriptor_type
+            }
+            StandardDeviceRequest::GetStatus { .. } => {
+                // Neither self-powered nor halted
+                let buf = self.descriptor_buf();
+                buf[0].set(0);
+                buf[1].set(0);
+                self.state.set(State::CtrlIn(0, 2));
+                CtrlSetupResult::Ok
+            }
+            StandardDeviceRequest::SetAddress { device_address } => {
+                // Load the address we've been assigned ...
+                self.controller.set_address(device_address);
+
+                // ... and when this request gets to the Status stage
+                // we will actually enable the address.
+                self.state.set(State::SetAddress);
+                CtrlSetupResult::Ok
+            }
+            StandardDeviceRequest::SetConfiguration { .. } => {
+                // We have been assigned a particular configuration: fine!
+                CtrlSetupResult::Ok
+            }
+            _ => CtrlSetupResult::ErrUnrecognizedRequestType,
+        }
+    }
 }
 
 impl<'a, C: UsbController> hil::usb::Client for Client<'a, C> {
     fn enable(&self) {
         self.controller.endpoint_set_buffer(0, self.ep0_buf());
-        self.controller.enable_device(false);
+
+        // Bulk endpoints are not allowed on low-speed devices
+        let full_speed = self.next_endpoint.get() > 1;
+        self.controller.enable_device(full_speed);
         self.controller.endpoint_ctrl_out_enable(0);
 
+        for f in self.functions.iter() {
+            f.get().map(|f| f.class.enable());
+        }
+
         // XXX
         // static es: C::EndpointState = Default::default();
         // self.controller.endpoint_configure(&es, 0);
@@ -84,151 +261,38 @@ impl<'a, C: UsbController> hil::usb::Client for Client<'a, C> {
     }
 
     fn bus_reset(&self) {
-        // Should the client initiate reconfiguration here?
-        // For now, the hardware layer does it.
+        // The hardware layer reconfigures the endpoints
+        for f in self.functions.iter() {
+            f.get().map(|f| f.class.bus_reset());
+        }
     }
 
     /// Handle a Control Setup transaction
     fn ctrl_setup(&self) -> CtrlSetupResult {
         SetupData::get(self.ep0_buf()).map_or(CtrlSetupResult::ErrNoParse, |setup_data| {
-            setup_data.get_standard_request().map_or_else(
-                || {
-                    // XX: CtrlSetupResult::ErrNonstandardRequest
-
-                    // For now, promiscuously accept vendor data and even supply
-                    // a few debugging bytes when host does a read
-
-                    match setup_data.request_type.transfer_direction() {
-                        TransferDirection::HostToDevice => {
-                            self.state.set(State::CtrlOut);
-                            CtrlSetupResult::Ok
-                        }
-                        TransferDirection::DeviceToHost => {
-                            // Arrange to some crap back
-                            let buf = self.descriptor_buf();
-                            buf[0].set(0xa);
-                            buf[1].set(0xb);
-                            buf[2].set(0xc);
-                            self.state.set(State::CtrlIn(0, 3));
-                            CtrlSetupResult::Ok
-                        }
-                    }
-                },
-                |request| {
-                    match request {
-                        StandardDeviceRequest::GetDescriptor {
-                            descriptor_type,
-                            descriptor_index,
-                            lang_id,
-                            requested_length,
-                        } => {
-                            match descriptor_type {
-                                DescriptorType::Device => match descriptor_index {
-                                    0 => {
-                                        let buf = self.descriptor_buf();
-                                        let d = DeviceDescriptor {
-                                            vendor_id: VENDOR_ID,
-                                            product_id: PRODUCT_ID,
-                                            manufacturer_string: 1,
-                                            product_string: 2,
-                                            serial_number_string: 3,
-                                            ..Default::default()
-                                        };
-                                        let len = d.write_to(buf);
-                                        let end = min(len, requested_length as usize);
-                                        self.state.set(State::CtrlIn(0, end));
-                                        CtrlSetupResult::Ok
-                                    }
-                                    _ => CtrlSetupResult::ErrInvalidDeviceIndex,
-                                },
-                                DescriptorType::Configuration => {
-                                    match descriptor_index {
-                                        0 => {
-                                            // Place all the descriptors
-                                            // related to this configuration
-                                            // into a buffer contiguously,
-                                            // starting with the last
-
-                                            let buf = self.descriptor_buf();
-                                            let mut storage_avail = buf.len();
-
-                                            let di = InterfaceDescriptor::default();
-                                            storage_avail -=
-                                                di.write_to(&buf[storage_avail - di.size()..]);
-
-                                            let dc = ConfigurationDescriptor {
-                                                num_interfaces: 1,
-                                                related_descriptor_length: di.size(),
-                                                ..Default::default()
-                                            };
-                                            storage_avail -=
-                                                dc.write_to(&buf[storage_avail - dc.size()..]);
-
-                                            let request_start = storage_avail;
-                                            let request_end = min(
-                                                request_start + (requested_length as usize),
-                                                buf.len(),
-                                            );
-                                            self.state
-                                                .set(State::CtrlIn(request_start, request_end));
-                                            CtrlSetupResult::Ok
-                                        }
-                                        _ => CtrlSetupResult::ErrInvalidConfigurationIndex,
-                                    }
-                                }
-                                DescriptorType::String => {
-                                    if let Some(buf) = match descriptor_index {
-                                        0 => {
-                                            let buf = self.descriptor_buf();
-                                            let d = LanguagesDescriptor { langs: LANGUAGES };
-                                            let len = d.write_to(buf);
-                                            let end = min(len, requested_length as usize);
-                                            Some(&buf[..end])
-                                        }
-                                        i if i > 0 && (i as usize) <= STRINGS.len()
-                                            && lang_id == LANGUAGES[0] =>
-                                        {
-                                            let buf = self.descriptor_buf();
-                                            let d = StringDescriptor {
-                                                string: STRINGS[i as usize - 1],
-                                            };
-                                            let len = d.write_to(buf);
-                                            let end = min(len, requested_length as usize);
-                                            Some(&buf[..end])
-                                        }
-                                        _ => None,
-                                    } {
-                                        self.state.set(State::CtrlIn(0, buf.len()));
-                                        CtrlSetupResult::Ok
-                                    } else {
-                                        CtrlSetupResult::ErrInvalidStringIndex
-                                    }
-                                }
-                                DescriptorType::DeviceQualifier => {
-                                    // We are full-speed only, so we must
-                                    // respond with a request error
-                                    CtrlSetupResult::ErrNoDeviceQualifier
-                                }
-                                _ => CtrlSetupResult::ErrUnrecognizedDescriptorType,
-                            } // match descriptor_type
-                        }
-                        StandardDeviceRequest::SetAddress { device_address } => {
-                            // Load the address we've been assigned ...
-                            self.controller.set_address(device_address);
-
-                            // ... and when this request gets to the Status stage
-                            // we will actually enable the address.
-                            self.state.set(State::SetAddress);
-                            CtrlSetupResult::Ok
-                        }
-                        StandardDeviceRequest::SetConfiguration { .. } => {
-                            // We have been assigned a particular configuration: fine!
-                            CtrlSetupResult::Ok
-                        }
-                        _ => CtrlSetupResult::ErrUnrecognizedRequestType,
-                    }
-                },
-            )
+            // Requests addressed to an interface or endpoint go to the
+            // function that owns it, which may leave standard requests to us
+            let function = match setup_data.request_type.recipient() {
+                Recipient::Interface => self.function_of_interface(setup_data.index as u8),
+                Recipient::Endpoint => self.function_of_endpoint((setup_data.index & 0xf) as usize),
+                _ => None,
+            };
+            let result = function.map_or(CtrlSetupResult::ErrNonstandardRequest, |index| {
+                let result = self.function(index)
+                    .map_or(CtrlSetupResult::ErrNonstandardRequest, |class| {
+                        class.ctrl_setup(setup_data)
+                    });
+                if let CtrlSetupResult::Ok = result {
+                    self.state.set(State::Function(index));
+                }
+                result
+            });
+            match result {
+                CtrlSetupResult::Ok => result,
+                _ => setup_data
+                    .get_standard_request()
+                    .map_or(result, |request| self.standard_request(request)),
+            }
         })
     }
 
@@ -258,6 +322,30 @@ impl<'a, C: UsbController> hil::usb::Client for Client<'a, C> {
                     CtrlInResult::Packet(0, true)
                 }
             }
+            State::CtrlInConfiguration(start, end) => {
+                let len = end.saturating_sub(start);
+                if len > 0 {
+                    // Serialize only the descriptors in this packet
+                    let mut window = DescriptorWindow::new(start, &self.ep0_buf()[..min(8, len)]);
+                    self.descriptors.configuration_descriptors(
+                        self.num_interfaces.get(),
+                        &|w| self.function_descriptors(w),
+                        &mut window,
+                    );
+                    let packet_bytes = window.copied();
+
+                    let start = start + packet_bytes;
+                    let transfer_complete = start >= end;
+
+                    self.state.set(State::CtrlInConfiguration(start, end));
+
+                    CtrlInResult::Packet(packet_bytes, transfer_complete)
+                } else {
+                    CtrlInResult::Packet(0, true)
+                }
+            }
+            State::Function(index) => self.function(index)
+                .map_or(CtrlInResult::Error, |class| class.ctrl_in(self.ep0_buf())),
             _ => CtrlInResult::Error,
         }
     }
@@ -265,11 +353,10 @@ impl<'a, C: UsbController> hil::usb::Client for Client<'a, C> {
     /// Handle a Control Out transaction
     fn ctrl_out(&self, packet_bytes: u32) -> CtrlOutResult {
         match self.state.get() {
-            State::CtrlOut => {
-                debug!("Received {} vendor control bytes", packet_bytes);
-                // &self.ep0_buf()[0 .. packet_bytes as usize]
-                CtrlOutResult::Ok
-            }
+            State::Function(index) => self.function(index)
+                .map_or(CtrlOutResult::Halted, |class| {
+                    class.ctrl_out(self.ep0_buf(), packet_bytes)
+                }),
             _ => {
                 // Bad state
                 CtrlOutResult::Halted
@@ -290,8 +377,39 @@ impl<'a, C: UsbController> hil::usb::Client for Client<'a, C> {
             State::SetAddress => {
                 self.controller.enable_address();
             }
+            State::Function(index) => {
+                self.function(index)
+                    .map(|class| class.ctrl_status_complete());
+            }
             _ => {}
         };
         self.state.set(State::Init);
     }
+
+    fn packet_in(&self, transfer_type: TransferType, endpoint: usize) -> InResult {
+        self.function_of_endpoint(endpoint)
+            .and_then(|index| self.function(index))
+            .map_or(InResult::Error, |class| {
+                class.packet_in(transfer_type, endpoint)
+            })
+    }
+
+    fn packet_out(
+        &self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> OutResult {
+        self.function_of_endpoint(endpoint)
+            .and_then(|index| self.function(index))
+            .map_or(OutResult::Halted, |class| {
+                class.packet_out(transfer_type, endpoint, packet_bytes)
+            })
+    }
+
+    fn packet_transmitted(&self, endpoint: usize) {
+        self.function_of_endpoint(endpoint)
+            .and_then(|index| self.function(index))
+            .map(|class| class.packet_transmitted(endpoint));
+    }
 }
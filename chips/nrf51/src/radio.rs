@@ -14,8 +14,8 @@ use core::cell::Cell;
 use core::convert::TryFrom;
 use kernel;
 use kernel::ReturnCode;
+use kernel::hil::ble_advertising::{RadioChannel, Turnaround};
 use nrf5x;
-use nrf5x::ble_advertising_hil::RadioChannel;
 use nrf5x::constants::TxPower;
 use peripheral_registers;
 
@@ -25,8 +25,15 @@ static mut PAYLOAD: [u8; nrf5x::constants::RADIO_PAYLOAD_LENGTH] =
 pub struct Radio {
     regs: *const peripheral_registers::RADIO_REGS,
     tx_power: Cell<TxPower>,
-    rx_client: Cell<Option<&'static nrf5x::ble_advertising_hil::RxClient>>,
-    tx_client: Cell<Option<&'static nrf5x::ble_advertising_hil::TxClient>>,
+    rx_client: Cell<Option<&'static kernel::hil::ble_advertising::RxClient>>,
+    tx_client: Cell<Option<&'static kernel::hil::ble_advertising::TxClient>>,
+    access_address: Cell<u32>,
+    crc_init: Cell<u32>,
+    // What the radio does at the end of the current packet, or at the end of the next one
+    // while it is turning around
+    turnaround: Cell<Turnaround>,
+    turning_around: Cell<bool>,
+    transmitting: Cell<bool>,
 }
 
 pub static mut RADIO: Radio = Radio::new();
@@ -38,6 +45,11 @@ impl Radio {
             tx_power: Cell::new(TxPower::ZerodBm),
             rx_client: Cell::new(None),
             tx_client: Cell::new(None),
+            access_address: Cell::new(kernel::hil::ble_advertising::ADVERTISING_ACCESS_ADDRESS),
+            crc_init: Cell::new(kernel::hil::ble_advertising::ADVERTISING_CRC_INIT),
+            turnaround: Cell::new(Turnaround::Disable),
+            turning_around: Cell::new(false),
+            transmitting: Cell::new(false),
         }
     }
 
@@ -55,9 +67,10 @@ impl Radio {
         self.set_channel_freq(channel);
         self.set_data_whitening(channel);
 
-        // Set PREFIX | BASE Address
-        regs.prefix0.set(0x0000008e);
-        regs.base0.set(0x89bed600);
+        // Set PREFIX | BASE Address, e.g. 0x8E | 0x89BED6 for advertising
+        let access_address = self.access_address.get();
+        regs.prefix0.set(access_address >> 24);
+        regs.base0.set(access_address << 8);
 
         self.set_tx_address(0x00);
         self.set_rx_address(0x01);
@@ -68,18 +81,22 @@ impl Radio {
         // CRC Config
         self.set_crc_config();
 
+        self.set_turnaround_shorts(self.turnaround.get());
+
         // Buffer configuration
         self.set_dma_ptr();
     }
 
     fn tx(&self) {
         let regs = unsafe { &*self.regs };
+        self.transmitting.set(true);
         regs.ready.set(0);
         regs.txen.set(1);
     }
 
     fn rx(&self) {
         let regs = unsafe { &*self.regs };
+        self.transmitting.set(false);
         regs.ready.set(0);
         regs.rxen.set(1);
     }
@@ -91,7 +108,7 @@ impl Radio {
                 | nrf5x::constants::RADIO_CRCCNF_SKIPADDR
                     << nrf5x::constants::RADIO_CRCCNF_SKIPADDR_POS,
         );
-        regs.crcinit.set(nrf5x::constants::RADIO_CRCINIT_BLE);
+        regs.crcinit.set(self.crc_init.get());
         regs.crcpoly.set(nrf5x::constants::RADIO_CRCPOLY_BLE);
     }
 
@@ -119,6 +136,27 @@ impl Radio {
         );
     }
 
+    // With a turnaround, the radio disables itself at the end of the packet and enables the
+    // receiver or the transmitter again, starting the next packet T_IFS after the end of it
+    fn set_turnaround_shorts(&self, turnaround: Turnaround) {
+        let regs = unsafe { &*self.regs };
+        let shorts = match turnaround {
+            Turnaround::Disable => 0,
+            Turnaround::Receive => {
+                nrf5x::constants::RADIO_SHORTS_READY_START
+                    | nrf5x::constants::RADIO_SHORTS_END_DISABLE
+                    | nrf5x::constants::RADIO_SHORTS_DISABLED_RXEN
+            }
+            Turnaround::Transmit => {
+                nrf5x::constants::RADIO_SHORTS_READY_START
+                    | nrf5x::constants::RADIO_SHORTS_END_DISABLE
+                    | nrf5x::constants::RADIO_SHORTS_DISABLED_TXEN
+            }
+        };
+        regs.tifs.set(nrf5x::constants::RADIO_TIFS_BLE);
+        regs.shorts.set(shorts);
+    }
+
     fn set_rx_address(&self, _: u32) {
         let regs = unsafe { &*self.regs };
         regs.rxaddresses.set(0x01);
@@ -176,12 +214,6 @@ impl Radio {
         let regs = unsafe { &*self.regs };
         self.disable_interrupts();
 
-        if regs.ready.get() == 1 {
-            regs.ready.set(0);
-            regs.end.set(0);
-            regs.start.set(1);
-        }
-
         if regs.payload.get() == 1 {
             regs.payload.set(0);
         }
@@ -190,9 +222,10 @@ impl Radio {
             regs.address.set(0);
         }
 
+        // Handled before the READY event because, after a turnaround, the radio may already be
+        // ready for the next packet when the end of this one is handled
         if regs.end.get() == 1 {
             regs.end.set(0);
-            regs.disable.set(1);
 
             let result = if regs.crcstatus.get() == 1 {
                 ReturnCode::SUCCESS
@@ -200,29 +233,45 @@ impl Radio {
                 ReturnCode::FAIL
             };
 
-            match regs.state.get() {
-                nrf5x::constants::RADIO_STATE_TXRU
-                | nrf5x::constants::RADIO_STATE_TXIDLE
-                | nrf5x::constants::RADIO_STATE_TXDISABLE
-                | nrf5x::constants::RADIO_STATE_TX => {
+            let transmitted = self.transmitting.get();
+            match self.turnaround.get() {
+                Turnaround::Disable => {
+                    regs.disable.set(1);
                     self.radio_off();
-                    self.tx_client
+                }
+                turnaround => {
+                    // The radio turns around by itself, the turnaround requested by the
+                    // clients from now on applies to the end of the next packet
+                    self.turnaround.set(Turnaround::Disable);
+                    self.turning_around.set(true);
+                    self.transmitting.set(turnaround == Turnaround::Transmit);
+                }
+            }
+
+            if transmitted {
+                self.tx_client
+                    .get()
+                    .map(|client| client.transmit_event(result));
+            } else {
+                unsafe {
+                    self.rx_client
                         .get()
-                        .map(|client| client.transmit_event(result));
+                        .map(|client| client.receive_event(&mut PAYLOAD, PAYLOAD[1] + 1, result));
                 }
-                nrf5x::constants::RADIO_STATE_RXRU
-                | nrf5x::constants::RADIO_STATE_RXIDLE
-                | nrf5x::constants::RADIO_STATE_RXDISABLE
-                | nrf5x::constants::RADIO_STATE_RX => {
-                    self.radio_off();
-                    unsafe {
-                        self.rx_client.get().map(|client| {
-                            client.receive_event(&mut PAYLOAD, PAYLOAD[1] + 1, result)
-                        });
-                    }
+            }
+        }
+
+        if regs.ready.get() == 1 {
+            regs.ready.set(0);
+            if self.turning_around.get() {
+                // The packet after the turnaround was started by the READY_START short
+                self.turning_around.set(false);
+                self.set_turnaround_shorts(self.turnaround.get());
+            } else {
+                regs.end.set(0);
+                if regs.shorts.get() & nrf5x::constants::RADIO_SHORTS_READY_START == 0 {
+                    regs.start.set(1);
                 }
-                // Radio state - Disabled
-                _ => (),
             }
         }
         self.enable_interrupts();
@@ -254,7 +303,7 @@ impl Radio {
     }
 }
 
-impl nrf5x::ble_advertising_hil::BleAdvertisementDriver for Radio {
+impl kernel::hil::ble_advertising::BleAdvertisementDriver for Radio {
     fn transmit_advertisement(
         &self,
         buf: &'static mut [u8],
@@ -274,16 +323,46 @@ impl nrf5x::ble_advertising_hil::BleAdvertisementDriver for Radio {
         self.enable_interrupts();
     }
 
-    fn set_receive_client(&self, client: &'static nrf5x::ble_advertising_hil::RxClient) {
+    fn set_receive_client(&self, client: &'static kernel::hil::ble_advertising::RxClient) {
         self.rx_client.set(Some(client));
     }
 
-    fn set_transmit_client(&self, client: &'static nrf5x::ble_advertising_hil::TxClient) {
+    fn set_transmit_client(&self, client: &'static kernel::hil::ble_advertising::TxClient) {
         self.tx_client.set(Some(client));
     }
 }
 
-impl nrf5x::ble_advertising_hil::BleConfig for Radio {
+impl kernel::hil::ble_advertising::BleLinkLayerRadio for Radio {
+    fn set_access_address(&self, access_address: u32, crc_init: u32) {
+        self.access_address.set(access_address);
+        self.crc_init.set(crc_init);
+    }
+
+    fn set_turnaround(&self, turnaround: Turnaround) {
+        self.turnaround.set(turnaround);
+        // While turning around, the shorts still describe the ongoing turnaround and are
+        // updated once the next packet has started
+        if !self.turning_around.get() {
+            self.set_turnaround_shorts(turnaround);
+        }
+    }
+
+    fn set_response(&self, buf: &'static mut [u8], len: usize) -> &'static mut [u8] {
+        self.replace_radio_buffer(buf, len)
+    }
+
+    fn disable(&self) {
+        let regs = unsafe { &*self.regs };
+        self.disable_interrupts();
+        regs.shorts.set(0);
+        regs.disable.set(1);
+        self.turnaround.set(Turnaround::Disable);
+        self.turning_around.set(false);
+        self.radio_off();
+    }
+}
+
+impl kernel::hil::ble_advertising::BleConfig for Radio {
     // The BLE Advertising Driver validates that the `tx_power` is between -20 to 10 dBm but then
     // underlying chip must validate if the current `tx_power` is supported as well
     fn set_tx_power(&self, tx_power: u8) -> kernel::ReturnCode {
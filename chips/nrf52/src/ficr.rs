@@ -77,6 +77,23 @@ impl FICR {
             _ => Variant::Unspecified,
         }
     }
+
+    /// The random device address programmed in factory, least significant
+    /// byte first, with the two most significant bits set as required for a
+    /// BLE static random address
+    pub fn address(&self) -> [u8; 6] {
+        let regs = unsafe { &*self.registers };
+        let low = regs.deviceaddr0.get();
+        let high = regs.deviceaddr1.get();
+        [
+            low as u8,
+            (low >> 8) as u8,
+            (low >> 16) as u8,
+            (low >> 24) as u8,
+            high as u8,
+            (high >> 8) as u8 | 0xc0,
+        ]
+    }
 }
 
 /// Static instance for the board. Only one (read-only) set of factory registers.
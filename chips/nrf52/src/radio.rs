@@ -37,8 +37,8 @@ use core::cell::Cell;
 use core::convert::TryFrom;
 use kernel;
 use kernel::ReturnCode;
+use kernel::hil::ble_advertising::{RadioChannel, Turnaround};
 use nrf5x;
-use nrf5x::ble_advertising_hil::RadioChannel;
 use nrf5x::constants::TxPower;
 use peripheral_registers;
 
@@ -54,8 +54,15 @@ static mut PAYLOAD: [u8; nrf5x::constants::RADIO_PAYLOAD_LENGTH] =
 pub struct Radio {
     regs: *const peripheral_registers::RADIO,
     tx_power: Cell<TxPower>,
-    rx_client: Cell<Option<&'static nrf5x::ble_advertising_hil::RxClient>>,
-    tx_client: Cell<Option<&'static nrf5x::ble_advertising_hil::TxClient>>,
+    rx_client: Cell<Option<&'static kernel::hil::ble_advertising::RxClient>>,
+    tx_client: Cell<Option<&'static kernel::hil::ble_advertising::TxClient>>,
+    access_address: Cell<u32>,
+    crc_init: Cell<u32>,
+    // What the radio does at the end of the current packet, or at the end of the next one
+    // while it is turning around
+    turnaround: Cell<Turnaround>,
+    turning_around: Cell<bool>,
+    transmitting: Cell<bool>,
 }
 
 pub static mut RADIO: Radio = Radio::new();
@@ -67,17 +74,24 @@ impl Radio {
             tx_power: Cell::new(TxPower::ZerodBm),
             rx_client: Cell::new(None),
             tx_client: Cell::new(None),
+            access_address: Cell::new(kernel::hil::ble_advertising::ADVERTISING_ACCESS_ADDRESS),
+            crc_init: Cell::new(kernel::hil::ble_advertising::ADVERTISING_CRC_INIT),
+            turnaround: Cell::new(Turnaround::Disable),
+            turning_around: Cell::new(false),
+            transmitting: Cell::new(false),
         }
     }
 
     fn tx(&self) {
         let regs = unsafe { &*self.regs };
+        self.transmitting.set(true);
         regs.event_ready.set(0);
         regs.task_txen.set(1);
     }
 
     fn rx(&self) {
         let regs = unsafe { &*self.regs };
+        self.transmitting.set(false);
         regs.event_ready.set(0);
         regs.task_rxen.set(1);
     }
@@ -121,12 +135,6 @@ impl Radio {
         let regs = unsafe { &*self.regs };
         self.disable_all_interrupts();
 
-        if regs.event_ready.get() == 1 {
-            regs.event_ready.set(0);
-            regs.event_end.set(0);
-            regs.task_start.set(1);
-        }
-
         if regs.event_address.get() == 1 {
             regs.event_address.set(0);
         }
@@ -135,6 +143,9 @@ impl Radio {
         }
 
         // tx or rx finished!
+        //
+        // This is handled before the READY event because, after a turnaround, the radio may
+        // already be ready for the next packet when the end of this one is handled
         if regs.event_end.get() == 1 {
             regs.event_end.set(0);
 
@@ -144,30 +155,43 @@ impl Radio {
                 ReturnCode::FAIL
             };
 
-            match regs.state.get() {
-                nrf5x::constants::RADIO_STATE_TXRU
-                | nrf5x::constants::RADIO_STATE_TXIDLE
-                | nrf5x::constants::RADIO_STATE_TXDISABLE
-                | nrf5x::constants::RADIO_STATE_TX => {
-                    self.radio_off();
-                    self.tx_client
-                        .get()
-                        .map(|client| client.transmit_event(result));
+            let transmitted = self.transmitting.get();
+            match self.turnaround.get() {
+                Turnaround::Disable => self.radio_off(),
+                turnaround => {
+                    // The radio turns around by itself, the turnaround requested by the
+                    // clients from now on applies to the end of the next packet
+                    self.turnaround.set(Turnaround::Disable);
+                    self.turning_around.set(true);
+                    self.transmitting.set(turnaround == Turnaround::Transmit);
                 }
-                nrf5x::constants::RADIO_STATE_RXRU
-                | nrf5x::constants::RADIO_STATE_RXIDLE
-                | nrf5x::constants::RADIO_STATE_RXDISABLE
-                | nrf5x::constants::RADIO_STATE_RX => {
-                    self.radio_off();
-                    unsafe {
-                        self.rx_client.get().map(|client| {
-                            // length is S0 (1 Byte) + Length (1 Bytes) + S1 (0 Bytes) + Payload
-                            client.receive_event(&mut PAYLOAD, PAYLOAD[1] + 2, result)
-                        });
-                    }
+            }
+
+            if transmitted {
+                self.tx_client
+                    .get()
+                    .map(|client| client.transmit_event(result));
+            } else {
+                unsafe {
+                    self.rx_client.get().map(|client| {
+                        // length is S0 (1 Byte) + Length (1 Bytes) + S1 (0 Bytes) + Payload
+                        client.receive_event(&mut PAYLOAD, PAYLOAD[1] + 2, result)
+                    });
+                }
+            }
+        }
+
+        if regs.event_ready.get() == 1 {
+            regs.event_ready.set(0);
+            if self.turning_around.get() {
+                // The packet after the turnaround was started by the READY_START short
+                self.turning_around.set(false);
+                self.ble_set_turnaround(self.turnaround.get());
+            } else {
+                regs.event_end.set(0);
+                if regs.shorts.get() & nrf5x::constants::RADIO_SHORTS_READY_START == 0 {
+                    regs.task_start.set(1);
                 }
-                // Radio state - Disabled
-                _ => (),
             }
         }
         self.enable_interrupts();
@@ -222,10 +246,12 @@ impl Radio {
         self.set_rx_address();
 
         self.ble_set_packet_config();
-        self.ble_set_advertising_access_address();
+        self.ble_set_access_address();
 
         self.ble_set_crc_config();
 
+        self.ble_set_turnaround(self.turnaround.get());
+
         self.set_dma_ptr();
     }
 
@@ -236,16 +262,40 @@ impl Radio {
             nrf5x::constants::RADIO_CRCCNF_SKIPADDR << nrf5x::constants::RADIO_CRCCNF_SKIPADDR_POS
                 | nrf5x::constants::RADIO_CRCCNF_LEN_3BYTES,
         );
-        regs.crcinit.set(nrf5x::constants::RADIO_CRCINIT_BLE);
+        regs.crcinit.set(self.crc_init.get());
         regs.crcpoly.set(nrf5x::constants::RADIO_CRCPOLY_BLE);
     }
 
     // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.1.2 Access Address
-    // Set access address to 0x8E89BED6
-    fn ble_set_advertising_access_address(&self) {
+    // The most significant byte of the access address is the prefix and the three other bytes
+    // are the base address, e.g. 0x8E and 0x89BED6 for the advertising access address
+    fn ble_set_access_address(&self) {
         let regs = unsafe { &*self.regs };
-        regs.prefix0.set(0x0000008e);
-        regs.base0.set(0x89bed600);
+        let access_address = self.access_address.get();
+        regs.prefix0.set(access_address >> 24);
+        regs.base0.set(access_address << 8);
+    }
+
+    // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 4.1 Inter Frame Space
+    // With a turnaround, the radio disables itself at the end of the packet and enables the
+    // receiver or the transmitter again, starting the next packet T_IFS after the end of it
+    fn ble_set_turnaround(&self, turnaround: Turnaround) {
+        let regs = unsafe { &*self.regs };
+        let shorts = match turnaround {
+            Turnaround::Disable => 0,
+            Turnaround::Receive => {
+                nrf5x::constants::RADIO_SHORTS_READY_START
+                    | nrf5x::constants::RADIO_SHORTS_END_DISABLE
+                    | nrf5x::constants::RADIO_SHORTS_DISABLED_RXEN
+            }
+            Turnaround::Transmit => {
+                nrf5x::constants::RADIO_SHORTS_READY_START
+                    | nrf5x::constants::RADIO_SHORTS_END_DISABLE
+                    | nrf5x::constants::RADIO_SHORTS_DISABLED_TXEN
+            }
+        };
+        regs.tifs.set(nrf5x::constants::RADIO_TIFS_BLE);
+        regs.shorts.set(shorts);
     }
 
     // Packet configuration
@@ -319,7 +369,7 @@ impl Radio {
     }
 }
 
-impl nrf5x::ble_advertising_hil::BleAdvertisementDriver for Radio {
+impl kernel::hil::ble_advertising::BleAdvertisementDriver for Radio {
     fn transmit_advertisement(
         &self,
         buf: &'static mut [u8],
@@ -339,16 +389,46 @@ impl nrf5x::ble_advertising_hil::BleAdvertisementDriver for Radio {
         self.enable_interrupts();
     }
 
-    fn set_receive_client(&self, client: &'static nrf5x::ble_advertising_hil::RxClient) {
+    fn set_receive_client(&self, client: &'static kernel::hil::ble_advertising::RxClient) {
         self.rx_client.set(Some(client));
     }
 
-    fn set_transmit_client(&self, client: &'static nrf5x::ble_advertising_hil::TxClient) {
+    fn set_transmit_client(&self, client: &'static kernel::hil::ble_advertising::TxClient) {
         self.tx_client.set(Some(client));
     }
 }
 
-impl nrf5x::ble_advertising_hil::BleConfig for Radio {
+impl kernel::hil::ble_advertising::BleLinkLayerRadio for Radio {
+    fn set_access_address(&self, access_address: u32, crc_init: u32) {
+        self.access_address.set(access_address);
+        self.crc_init.set(crc_init);
+    }
+
+    fn set_turnaround(&self, turnaround: Turnaround) {
+        self.turnaround.set(turnaround);
+        // While turning around, the shorts still describe the ongoing turnaround and are
+        // updated once the next packet has started
+        if !self.turning_around.get() {
+            self.ble_set_turnaround(turnaround);
+        }
+    }
+
+    fn set_response(&self, buf: &'static mut [u8], len: usize) -> &'static mut [u8] {
+        self.replace_radio_buffer(buf, len)
+    }
+
+    fn disable(&self) {
+        let regs = unsafe { &*self.regs };
+        self.disable_all_interrupts();
+        regs.shorts.set(0);
+        regs.task_disable.set(1);
+        self.turnaround.set(Turnaround::Disable);
+        self.turning_around.set(false);
+        self.radio_off();
+    }
+}
+
+impl kernel::hil::ble_advertising::BleConfig for Radio {
     // The BLE Advertising Driver validates that the `tx_power` is between -20 to 10 dBm but then
     // underlying chip must validate if the current `tx_power` is supported as well
     fn set_tx_power(&self, tx_power: u8) -> kernel::ReturnCode {
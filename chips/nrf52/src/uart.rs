@@ -14,6 +14,7 @@ static mut BUF: [u8; BUF_SIZE] = [0; BUF_SIZE];
 const NRF_UARTE_INTR_ENDTX: u32 = 1 << 8;
 const NRF_UARTE_INTR_ENDRX: u32 = 1 << 4;
 const NRF_UARTE_ENABLE: u32 = 8;
+const NRF_UARTE_CONFIG_HWFC: u32 = 1;
 
 pub struct UARTE {
     regs: *const peripheral_registers::UARTE,
@@ -79,6 +80,15 @@ impl UARTE {
         }
     }
 
+    fn set_flow_control(&self, hw_flow_control: bool) {
+        let regs = unsafe { &*self.regs };
+        if hw_flow_control {
+            regs.config.set(regs.config.get() | NRF_UARTE_CONFIG_HWFC);
+        } else {
+            regs.config.set(regs.config.get() & !NRF_UARTE_CONFIG_HWFC);
+        }
+    }
+
     fn enable(&self) {
         let regs = unsafe { &*self.regs };
         regs.enable.set(NRF_UARTE_ENABLE);
@@ -194,6 +204,7 @@ impl kernel::hil::uart::UART for UARTE {
     fn init(&self, params: kernel::hil::uart::UARTParams) {
         self.enable();
         self.set_baud_rate(params.baud_rate);
+        self.set_flow_control(params.hw_flow_control);
     }
 
     fn transmit(&self, tx_data: &'static mut [u8], tx_len: usize) {
@@ -46,6 +46,15 @@ pub const RADIO_INTENSET_PAYLOAD: u32 = 1 << 2;
 pub const RADIO_INTENSET_END: u32 = 1 << 3;
 pub const RADIO_INTENSET_DISABLED: u32 = 1 << 4;
 
+// SHORTS
+pub const RADIO_SHORTS_READY_START: u32 = 1;
+pub const RADIO_SHORTS_END_DISABLE: u32 = 1 << 1;
+pub const RADIO_SHORTS_DISABLED_TXEN: u32 = 1 << 2;
+pub const RADIO_SHORTS_DISABLED_RXEN: u32 = 1 << 3;
+
+// TIFS
+pub const RADIO_TIFS_BLE: u32 = 150;
+
 // STATE
 pub const RADIO_STATE_DISABLE: u32 = 0;
 pub const RADIO_STATE_RXRU: u32 = 1;
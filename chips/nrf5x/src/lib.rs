@@ -8,8 +8,6 @@ extern crate kernel;
 mod peripheral_registers;
 
 pub mod aes;
-pub mod ble_advertising_driver;
-pub mod ble_advertising_hil;
 pub mod clock;
 pub mod gpio;
 pub mod peripheral_interrupts;
@@ -3,6 +3,7 @@
 use core::fmt;
 use core::ptr;
 use kernel::common::VolatileCell;
+use kernel::hil::usb::TransferType;
 use usbc::common::register::*;
 
 #[derive(Copy, Clone, PartialEq, Debug)]
@@ -37,6 +38,16 @@ pub enum DeviceState {
     CtrlInDelay,
 }
 
+/// State of an endpoint other than the control endpoint
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum DataEndpoint {
+    Disabled,
+    /// An IN endpoint, and whether a packet was handed to the controller and
+    /// not yet sent
+    In(TransferType, EndpointConfig, bool),
+    Out(TransferType, EndpointConfig),
+}
+
 // value for USBCON.UIMOD
 impl ToWord for Mode {
     fn to_word(self) -> u32 {
@@ -262,6 +273,17 @@ pub enum EndpointType {
     Interrupt,
 }
 
+impl From<TransferType> for EndpointType {
+    fn from(tt: TransferType) -> EndpointType {
+        match tt {
+            TransferType::Control => EndpointType::Control,
+            TransferType::Isochronous => EndpointType::Isochronous,
+            TransferType::Bulk => EndpointType::Bulk,
+            TransferType::Interrupt => EndpointType::Interrupt,
+        }
+    }
+}
+
 pub struct EndpointIndex(u32);
 
 impl EndpointIndex {
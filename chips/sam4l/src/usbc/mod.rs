@@ -35,6 +35,7 @@ pub struct Usbc<'a> {
     descriptors: [Endpoint; 8],
     client: Option<&'a hil::usb::Client>,
     state: MapCell<State>,
+    data_endpoints: [Cell<DataEndpoint>; 8],
 }
 
 #[derive(Default)]
@@ -76,6 +77,38 @@ impl<'a> UsbController for Usbc<'a> {
         self.endpoint_enable(e, cfg);
     }
 
+    fn endpoint_in_enable(&self, transfer_type: TransferType, e: u32) {
+        let cfg = EndpointConfig::new(
+            BankCount::Single,
+            EndpointSize::Bytes8,
+            EndpointDirection::In,
+            From::from(transfer_type),
+            EndpointIndex::new(e),
+        );
+        self.data_endpoint_enable(e, DataEndpoint::In(transfer_type, cfg, false));
+    }
+
+    fn endpoint_out_enable(&self, transfer_type: TransferType, e: u32) {
+        let cfg = EndpointConfig::new(
+            BankCount::Single,
+            EndpointSize::Bytes8,
+            EndpointDirection::Out,
+            From::from(transfer_type),
+            EndpointIndex::new(e),
+        );
+        self.data_endpoint_enable(e, DataEndpoint::Out(transfer_type, cfg));
+    }
+
+    fn endpoint_resume_in(&self, e: u32) {
+        // The TXIN flag is still set from when the client delayed
+        endpoint_enable_interrupts(e as usize, TXIN);
+    }
+
+    fn endpoint_resume_out(&self, e: u32) {
+        // The RXOUT flag is still set from when the client delayed
+        endpoint_enable_interrupts(e as usize, RXOUT);
+    }
+
     fn set_address(&self, addr: u16) {
         // The hardware can do only 7-bit addresses
         let addr = (addr as u8) & 0b1111111;
@@ -108,6 +141,16 @@ impl<'a> Usbc<'a> {
                 new_endpoint(),
                 new_endpoint(),
             ],
+            data_endpoints: [
+                Cell::new(DataEndpoint::Disabled),
+                Cell::new(DataEndpoint::Disabled),
+                Cell::new(DataEndpoint::Disabled),
+                Cell::new(DataEndpoint::Disabled),
+                Cell::new(DataEndpoint::Disabled),
+                Cell::new(DataEndpoint::Disabled),
+                Cell::new(DataEndpoint::Disabled),
+                Cell::new(DataEndpoint::Disabled),
+            ],
         }
     }
 
@@ -286,6 +329,38 @@ impl<'a> Usbc<'a> {
         debug!("Enabled endpoint {}", endpoint);
     }
 
+    /// Configure and enable a bulk or interrupt endpoint
+    fn data_endpoint_enable(&self, endpoint: u32, data_endpoint: DataEndpoint) {
+        if endpoint == 0 || endpoint as usize >= self.data_endpoints.len() {
+            panic!("Bad data endpoint index");
+        }
+        self.data_endpoints[endpoint as usize].set(data_endpoint);
+
+        UERST.set_bit(endpoint);
+        self.data_endpoint_configure(endpoint as usize, data_endpoint);
+        UDINTESET.set_bit(12 + endpoint);
+
+        debug!("Enabled endpoint {}", endpoint);
+    }
+
+    fn data_endpoint_configure(&self, endpoint: usize, data_endpoint: DataEndpoint) {
+        match data_endpoint {
+            DataEndpoint::Disabled => {}
+            DataEndpoint::In(_, cfg, _) => {
+                UECFGn[endpoint].write(From::from(cfg));
+
+                // The bank is free: ask the client for a packet
+                endpoint_enable_only_interrupts(endpoint, TXIN | RAMACERR);
+            }
+            DataEndpoint::Out(_, cfg) => {
+                UECFGn[endpoint].write(From::from(cfg));
+
+                // Wait for a packet from the host
+                endpoint_enable_only_interrupts(endpoint, RXOUT | RAMACERR);
+            }
+        }
+    }
+
     fn endpoint_configure(&self, endpoint: usize, cfg: EndpointConfig) {
         // Configure the endpoint
         UECFGn[endpoint].write(From::from(cfg));
@@ -342,6 +417,13 @@ impl<'a> Usbc<'a> {
             if let Some(ref config) = *config {
                 self.endpoint_configure(0, *config);
             }
+            for (endpoint, data_endpoint) in self.data_endpoints.iter().enumerate() {
+                // Any packet handed to the controller is lost
+                if let DataEndpoint::In(transfer_type, cfg, true) = data_endpoint.get() {
+                    data_endpoint.set(DataEndpoint::In(transfer_type, cfg, false));
+                }
+                self.data_endpoint_configure(endpoint, data_endpoint.get());
+            }
 
             // Re-initialize our record of the controller state
             *dstate = DeviceState::Init;
@@ -701,8 +783,102 @@ impl<'a> Usbc<'a> {
                 // again = false; // XX
             } // while again
         } // for endpoint
+
+        // Process bulk and interrupt endpoints
+        for endpoint in 1..self.data_endpoints.len() {
+            if udint & (1 << (12 + endpoint)) != 0 {
+                self.handle_data_endpoint_interrupt(endpoint);
+            }
+        }
     } // handle_device_interrupt
 
+    fn handle_data_endpoint_interrupt(&self, endpoint: usize) {
+        let status = UESTAn[endpoint].read();
+
+        if status & STALLED != 0 {
+            debug!("D({}) STALLED/CRCERR", endpoint);
+
+            // Acknowledge
+            UESTAnCLR[endpoint].write(STALLED);
+        }
+
+        if status & RAMACERR != 0 {
+            debug!("D({}) RAMACERR", endpoint);
+
+            // Acknowledge
+            UESTAnCLR[endpoint].write(RAMACERR);
+        }
+
+        match self.data_endpoints[endpoint].get() {
+            DataEndpoint::In(transfer_type, cfg, in_flight) => {
+                if status & TXIN != 0 {
+                    // The bank is free again, so any packet handed to the
+                    // controller has been sent
+                    self.data_endpoints[endpoint].set(DataEndpoint::In(transfer_type, cfg, false));
+                    if in_flight {
+                        self.client.map(|c| c.packet_transmitted(endpoint));
+                    }
+
+                    let result = self.client.map(|c| c.packet_in(transfer_type, endpoint));
+                    match result {
+                        Some(InResult::Packet(packet_bytes)) => {
+                            self.descriptors[endpoint][0]
+                                .packet_size
+                                .set(PacketSize::single(packet_bytes as u32));
+                            self.data_endpoints[endpoint]
+                                .set(DataEndpoint::In(transfer_type, cfg, true));
+
+                            // Acknowledge, and hand the bank to the controller
+                            UESTAnCLR[endpoint].write(TXIN);
+                            UECONnCLR[endpoint].write(FIFOCON);
+                        }
+                        Some(InResult::Delay) => {
+                            // Wait for the client to call endpoint_resume_in()
+                            endpoint_disable_interrupts(endpoint, TXIN);
+                        }
+                        _ => {
+                            // Respond with STALL to IN transactions
+                            UECONnSET[endpoint].write(STALLRQ);
+                            endpoint_disable_interrupts(endpoint, TXIN);
+
+                            debug!("D({}) Client IN err => STALL", endpoint);
+                        }
+                    }
+                }
+            }
+            DataEndpoint::Out(transfer_type, _) => {
+                if status & RXOUT != 0 {
+                    let packet_bytes = self.descriptors[endpoint][0].packet_size.get().byte_count();
+                    let result = self.client
+                        .map(|c| c.packet_out(transfer_type, endpoint, packet_bytes));
+                    match result {
+                        Some(OutResult::Ok) => {
+                            // Acknowledge, and free the bank for the next packet
+                            UESTAnCLR[endpoint].write(RXOUT);
+                            UECONnCLR[endpoint].write(FIFOCON);
+                        }
+                        Some(OutResult::Delay) => {
+                            // Don't acknowledge; hardware will have to send NAK
+                            // until the client calls endpoint_resume_out()
+                            endpoint_disable_interrupts(endpoint, RXOUT);
+                        }
+                        _ => {
+                            // Respond with STALL to OUT transactions
+                            UECONnSET[endpoint].write(STALLRQ);
+
+                            debug!("D({}) Client OUT err => STALL", endpoint);
+
+                            // Drop the packet
+                            UESTAnCLR[endpoint].write(RXOUT);
+                            UECONnCLR[endpoint].write(FIFOCON);
+                        }
+                    }
+                }
+            }
+            DataEndpoint::Disabled => {}
+        }
+    }
+
     #[allow(dead_code)]
     fn debug_show_d0(&self) {
         for bi in 0..1 {
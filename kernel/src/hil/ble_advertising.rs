@@ -45,7 +45,7 @@
 //!
 //! ```
 
-use kernel::ReturnCode;
+use returncode::ReturnCode;
 
 pub trait BleAdvertisementDriver {
     fn transmit_advertisement(
@@ -71,6 +71,57 @@ pub trait TxClient {
     fn transmit_event(&self, result: ReturnCode);
 }
 
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.1.2 Access Address
+pub const ADVERTISING_ACCESS_ADDRESS: u32 = 0x8E89BED6;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 3.1.1 CRC Generation
+pub const ADVERTISING_CRC_INIT: u32 = 0x555555;
+
+/// What the radio does once it has sent or received a packet
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum Turnaround {
+    /// Turn the radio off
+    Disable,
+    /// Receive a packet on the same channel
+    Receive,
+    /// Send the packet given to `set_response` on the same channel
+    Transmit,
+}
+
+/// Radio operations used by the link layer to accept and maintain
+/// connections, and by the advertising driver for active scanning and scan
+/// responses, on top of those of `BleAdvertisementDriver`
+///
+/// Packets exchanged in a connection event, and the `CONNECT_REQ`,
+/// `SCAN_REQ` and `SCAN_RSP` sent in response to advertising PDUs, follow
+/// each other with an inter frame space of T_IFS (150 us), which is too
+/// short to start a new operation from a `TxClient` or `RxClient` callback.
+/// Instead, the client sets a `Turnaround` before the packet ends and the
+/// radio starts the next operation by itself. A client that does not want to
+/// answer a received packet after all calls `disable` from
+/// `RxClient::receive_event`.
+pub trait BleLinkLayerRadio {
+    /// Sets the access address and the CRC initialization value of the
+    /// packets sent and received from the next operation on. They are
+    /// `ADVERTISING_ACCESS_ADDRESS` and `ADVERTISING_CRC_INIT` for the
+    /// advertising channels and are chosen by the master for a connection.
+    fn set_access_address(&self, access_address: u32, crc_init: u32);
+
+    /// Sets what the radio does at the end of the current packet, or of the
+    /// next one if it is idle. The turnaround only applies to one packet and
+    /// goes back to `Turnaround::Disable` after it.
+    fn set_turnaround(&self, turnaround: Turnaround);
+
+    /// Sets the packet sent on a `Turnaround::Transmit`. It must be called
+    /// from `RxClient::receive_event`, before the radio starts transmitting,
+    /// and overwrites the buffer of the received packet.
+    fn set_response(&self, buf: &'static mut [u8], len: usize) -> &'static mut [u8];
+
+    /// Stops any ongoing operation and turns the radio off without calling
+    /// the clients
+    fn disable(&self);
+}
+
 // Bluetooth Core Specification:Vol. 6. Part B, section 1.4.1 Advertising and Data Channel Indices
 #[derive(PartialEq, Debug, Copy, Clone)]
 pub enum RadioChannel {
@@ -117,6 +168,52 @@ pub enum RadioChannel {
 }
 
 impl RadioChannel {
+    pub fn from_channel_index(index: u8) -> Option<RadioChannel> {
+        match index {
+            0 => Some(RadioChannel::DataChannel0),
+            1 => Some(RadioChannel::DataChannel1),
+            2 => Some(RadioChannel::DataChannel2),
+            3 => Some(RadioChannel::DataChannel3),
+            4 => Some(RadioChannel::DataChannel4),
+            5 => Some(RadioChannel::DataChannel5),
+            6 => Some(RadioChannel::DataChannel6),
+            7 => Some(RadioChannel::DataChannel7),
+            8 => Some(RadioChannel::DataChannel8),
+            9 => Some(RadioChannel::DataChannel9),
+            10 => Some(RadioChannel::DataChannel10),
+            11 => Some(RadioChannel::DataChannel11),
+            12 => Some(RadioChannel::DataChannel12),
+            13 => Some(RadioChannel::DataChannel13),
+            14 => Some(RadioChannel::DataChannel14),
+            15 => Some(RadioChannel::DataChannel15),
+            16 => Some(RadioChannel::DataChannel16),
+            17 => Some(RadioChannel::DataChannel17),
+            18 => Some(RadioChannel::DataChannel18),
+            19 => Some(RadioChannel::DataChannel19),
+            20 => Some(RadioChannel::DataChannel20),
+            21 => Some(RadioChannel::DataChannel21),
+            22 => Some(RadioChannel::DataChannel22),
+            23 => Some(RadioChannel::DataChannel23),
+            24 => Some(RadioChannel::DataChannel24),
+            25 => Some(RadioChannel::DataChannel25),
+            26 => Some(RadioChannel::DataChannel26),
+            27 => Some(RadioChannel::DataChannel27),
+            28 => Some(RadioChannel::DataChannel28),
+            29 => Some(RadioChannel::DataChannel29),
+            30 => Some(RadioChannel::DataChannel30),
+            31 => Some(RadioChannel::DataChannel31),
+            32 => Some(RadioChannel::DataChannel32),
+            33 => Some(RadioChannel::DataChannel33),
+            34 => Some(RadioChannel::DataChannel34),
+            35 => Some(RadioChannel::DataChannel35),
+            36 => Some(RadioChannel::DataChannel36),
+            37 => Some(RadioChannel::AdvertisingChannel37),
+            38 => Some(RadioChannel::AdvertisingChannel38),
+            39 => Some(RadioChannel::AdvertisingChannel39),
+            _ => None,
+        }
+    }
+
     pub fn get_channel_index(&self) -> u32 {
         match *self {
             RadioChannel::DataChannel0 => 0,
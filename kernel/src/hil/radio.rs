@@ -10,6 +10,46 @@
 use returncode::ReturnCode;
 pub trait TxClient {
     fn send_done(&self, buf: &'static mut [u8], acked: bool, result: ReturnCode);
+
+    /// Like `send_done`, but also reports how many times the frame was
+    /// retransmitted and found the channel busy. Layers that retransmit frames
+    /// or perform CSMA-CA in software call this instead of `send_done`;
+    /// clients that are not interested in these counts can rely on the
+    /// default implementation.
+    fn send_done_info(
+        &self,
+        buf: &'static mut [u8],
+        acked: bool,
+        result: ReturnCode,
+        _info: TxInfo,
+    ) {
+        self.send_done(buf, acked, result);
+    }
+}
+
+/// Metadata about the transmission of a frame that is counted by the layers
+/// that retransmit frames or perform CSMA-CA in software
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct TxInfo {
+    /// The number of times the frame was retransmitted because it was not
+    /// acknowledged
+    pub retries: u8,
+    /// The number of clear channel assessments that found the channel busy,
+    /// over all transmission attempts
+    pub channel_busy: u8,
+}
+
+/// Metadata about a received frame that is measured by the radio
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct RxInfo {
+    /// Received signal strength, in dBm
+    pub rssi: i8,
+    /// Link quality indication, from 0 (lowest quality) to 255 (highest)
+    pub lqi: u8,
+    /// The time at which the start-of-frame delimiter was received, in tics
+    /// of the alarm used by the radio, if it timestamps frames. Virtual alarms
+    /// multiplexed on the same hardware alarm share this time base.
+    pub timestamp: Option<u32>,
 }
 
 pub trait RxClient {
@@ -18,6 +58,7 @@ pub trait RxClient {
         buf: &'static mut [u8],
         frame_len: usize,
         crc_valid: bool,
+        info: RxInfo,
         result: ReturnCode,
     );
 }
@@ -95,6 +136,11 @@ pub trait RadioConfig {
     fn set_pan(&self, id: u16);
     fn set_tx_power(&self, power: i8) -> ReturnCode;
     fn set_channel(&self, chan: u8) -> ReturnCode;
+
+    /// Sets whether the acknowledgements the radio sends in response to data
+    /// requests have their Frame Pending field set, telling the polling
+    /// device that frames are held for it. Committed with `config_commit`.
+    fn set_ack_frame_pending(&self, pending: bool);
 }
 
 pub trait RadioData {
@@ -102,6 +148,10 @@ pub trait RadioData {
     fn set_receive_client(&self, client: &'static RxClient, receive_buffer: &'static mut [u8]);
     fn set_receive_buffer(&self, receive_buffer: &'static mut [u8]);
 
+    /// Transmits a frame. Radios that perform a clear channel assessment
+    /// before transmitting complete the transmission with
+    /// `ReturnCode::EBUSY` if the channel was found busy and the frame was
+    /// not sent.
     fn transmit(
         &self,
         spi_buf: &'static mut [u8],
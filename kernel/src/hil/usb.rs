@@ -17,6 +17,22 @@ pub trait UsbController {
 
     fn endpoint_ctrl_out_enable(&self, e: u32);
 
+    /// Enable endpoint `e` as a bulk or interrupt IN endpoint.  The client is
+    /// then asked for packets to send with `Client::packet_in`
+    fn endpoint_in_enable(&self, transfer_type: TransferType, e: u32);
+
+    /// Enable endpoint `e` as a bulk or interrupt OUT endpoint.  Packets from
+    /// the host are passed to the client with `Client::packet_out`
+    fn endpoint_out_enable(&self, transfer_type: TransferType, e: u32);
+
+    /// Ask the client for packets again on IN endpoint `e`, after it answered
+    /// `InResult::Delay`
+    fn endpoint_resume_in(&self, e: u32);
+
+    /// Pass packets to the client again on OUT endpoint `e`, after it answered
+    /// `OutResult::Delay`
+    fn endpoint_resume_out(&self, e: u32);
+
     fn set_address(&self, addr: u16);
 
     fn enable_address(&self);
@@ -33,6 +49,26 @@ pub trait Client {
     fn ctrl_out(&self, packet_bytes: u32) -> CtrlOutResult;
     fn ctrl_status(&self);
     fn ctrl_status_complete(&self);
+
+    /// The IN endpoint can take a packet: the client may write one into the
+    /// endpoint buffer
+    fn packet_in(&self, transfer_type: TransferType, endpoint: usize) -> InResult;
+
+    /// A packet of `packet_bytes` bytes was received into the buffer of the
+    /// OUT endpoint
+    fn packet_out(&self, transfer_type: TransferType, endpoint: usize, packet_bytes: u32)
+        -> OutResult;
+
+    /// The packet last written by `packet_in` was sent to the host
+    fn packet_transmitted(&self, endpoint: usize);
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum TransferType {
+    Control = 0,
+    Isochronous,
+    Bulk,
+    Interrupt,
 }
 
 #[derive(Debug)]
@@ -50,6 +86,9 @@ pub enum CtrlSetupResult {
     ErrInvalidDeviceIndex,
     ErrInvalidConfigurationIndex,
     ErrInvalidStringIndex,
+
+    /// The request is valid but cannot be carried out in the current state
+    ErrGeneric,
 }
 
 pub enum CtrlInResult {
@@ -76,3 +115,28 @@ pub enum CtrlOutResult {
     /// In halt state (send STALL)
     Halted,
 }
+
+pub enum InResult {
+    /// A packet of the given size was written into the endpoint buffer
+    Packet(usize),
+
+    /// The client has no data to send yet.  The controller sends NAK tokens
+    /// until the client calls `endpoint_resume_in`.
+    Delay,
+
+    /// The client cannot send data.  This result causes the controller to
+    /// send a STALL token to the host.
+    Error,
+}
+
+pub enum OutResult {
+    /// The packet was consumed (send ACK)
+    Ok,
+
+    /// The client cannot take the packet yet.  The controller sends NAK
+    /// tokens until the client calls `endpoint_resume_out`.
+    Delay,
+
+    /// In halt state (send STALL)
+    Halted,
+}
@@ -0,0 +1,259 @@
+//! This utility saves the IEEE 802.15.4 frames captured by a Tock board
+//! running the `capsules::ieee802154::capture` capsule, which streams them
+//! over a UART in the pcap-ng format, into a `.pcapng` file that Wireshark
+//! can open, or pipes them to Wireshark for a live capture.
+//!
+//! Usage:
+//!
+//!     ieee802154-capture [-b BAUD] INPUT [OUTPUT]
+//!
+//! `INPUT` is the serial device connected to the capture UART (for example
+//! `/dev/ttyUSB1`), or `-` for the standard input. With `-b`, the serial
+//! device is first configured with `stty` for raw input at `BAUD`, which must
+//! match the baud rate passed to the capsule. `OUTPUT` is the file to write
+//! the capture to; the capture is written to the standard output if it is
+//! `-` or omitted, so that it can be piped to Wireshark:
+//!
+//!     ieee802154-capture -b 921600 /dev/ttyUSB1 | wireshark -k -i -
+//!
+//! The tool may be started after the board, in which case it synchronizes
+//! with the stream at the next complete pcap-ng block and writes the headers
+//! that it missed itself. The headers come from the capsule's own `pcapng`
+//! module, which this tool includes, so they always match those of the
+//! board. Bytes that are not part of a valid block are skipped. Statistics about the capture are printed on the standard error
+//! when the input ends, or when the output is closed.
+
+use std::env;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::process::{self, Command};
+
+// The headers and block types of the stream are defined by the capsule
+#[allow(dead_code)]
+#[path = "../../../capsules/src/ieee802154/pcapng.rs"]
+mod pcapng;
+
+use pcapng::*;
+
+/// No block sent by the capsule is larger than this
+const MAX_BLOCK_SIZE: usize = 512;
+
+fn read_u16(buf: &[u8]) -> u16 {
+    buf[0] as u16 | (buf[1] as u16) << 8
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    read_u16(buf) as u32 | (read_u16(&buf[2..]) as u32) << 16
+}
+
+fn read_u64(buf: &[u8]) -> u64 {
+    read_u32(buf) as u64 | (read_u32(&buf[4..]) as u64) << 32
+}
+
+#[derive(Default)]
+struct Stats {
+    frames: u64,
+    dropped: u64,
+    skipped_bytes: u64,
+}
+
+/// Splits the stream received from the board into pcap-ng blocks
+struct BlockReader {
+    buf: Vec<u8>,
+    synchronized: bool,
+}
+
+enum Block {
+    /// A complete block
+    Block(Vec<u8>),
+    /// More bytes are needed
+    Incomplete,
+    /// The stream does not start with a valid block, and its first byte
+    /// should be skipped
+    Invalid,
+}
+
+impl BlockReader {
+    fn new() -> BlockReader {
+        BlockReader {
+            buf: Vec::new(),
+            synchronized: false,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn next_block(&mut self) -> Block {
+        if self.buf.len() < 12 {
+            return Block::Incomplete;
+        }
+        let block_type = read_u32(&self.buf);
+        let len = read_u32(&self.buf[4..]) as usize;
+        let valid_type = match block_type {
+            SECTION_HEADER_BLOCK => read_u32(&self.buf[8..]) == BYTE_ORDER_MAGIC,
+            INTERFACE_DESCRIPTION_BLOCK | ENHANCED_PACKET_BLOCK => true,
+            _ => false,
+        };
+        if !valid_type || len % 4 != 0 || len < 12 || len > MAX_BLOCK_SIZE {
+            return Block::Invalid;
+        }
+        if self.buf.len() < len {
+            return Block::Incomplete;
+        }
+        if read_u32(&self.buf[len - 4..]) as usize != len {
+            return Block::Invalid;
+        }
+        Block::Block(self.buf.drain(..len).collect())
+    }
+}
+
+/// Returns the value of the `epb_dropcount` option of an Enhanced Packet
+/// Block, or 0 if it has none
+fn dropcount(block: &[u8]) -> u64 {
+    let captured_len = read_u32(&block[20..]) as usize;
+    let mut offset = 28 + (captured_len + 3) / 4 * 4;
+    while offset + 4 <= block.len() - 4 {
+        let code = read_u16(&block[offset..]);
+        let len = read_u16(&block[offset + 2..]) as usize;
+        let value = offset + 4;
+        if code == EPB_DROPCOUNT && len == 8 && value + 8 <= block.len() - 4 {
+            return read_u64(&block[value..]);
+        }
+        if code == 0 {
+            break;
+        }
+        offset = value + (len + 3) / 4 * 4;
+    }
+    0
+}
+
+fn print_stats(stats: &Stats) {
+    eprintln!(
+        "{} frames captured, {} dropped by the board, {} bytes skipped",
+        stats.frames, stats.dropped, stats.skipped_bytes
+    );
+}
+
+fn usage() -> ! {
+    eprintln!("usage: ieee802154-capture [-b BAUD] INPUT [OUTPUT]");
+    process::exit(2);
+}
+
+/// Configures the serial device for raw input at the given baud rate
+fn configure_serial(device: &str, baud: &str) {
+    let device_flag = if cfg!(target_os = "macos") {
+        "-f"
+    } else {
+        "-F"
+    };
+    let status = Command::new("stty")
+        .args(&[device_flag, device, baud, "raw", "-echo"])
+        .status();
+    match status {
+        Ok(ref status) if status.success() => {}
+        _ => {
+            eprintln!("Failed to configure {} with stty", device);
+            process::exit(1);
+        }
+    }
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let mut baud = None;
+    if args.first().map_or(false, |arg| arg == "-b") {
+        if args.len() < 2 {
+            usage();
+        }
+        baud = Some(args.remove(1));
+        args.remove(0);
+    }
+    if args.is_empty() || args.len() > 2 {
+        usage();
+    }
+
+    let mut input: Box<Read> = if args[0] == "-" {
+        Box::new(io::stdin())
+    } else {
+        if let Some(ref baud) = baud {
+            configure_serial(&args[0], baud);
+        }
+        match File::open(&args[0]) {
+            Ok(file) => Box::new(file),
+            Err(e) => {
+                eprintln!("Opening {}: {}", args[0], e);
+                process::exit(1);
+            }
+        }
+    };
+    let mut output: Box<Write> = match args.get(1) {
+        Some(path) if path != "-" => match File::create(path) {
+            Ok(file) => Box::new(file),
+            Err(e) => {
+                eprintln!("Creating {}: {}", path, e);
+                process::exit(1);
+            }
+        },
+        _ => Box::new(io::stdout()),
+    };
+
+    let mut reader = BlockReader::new();
+    let mut stats = Stats::default();
+    let mut buf = [0; 1024];
+    loop {
+        let n = match input.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                eprintln!("Reading {}: {}", args[0], e);
+                break;
+            }
+        };
+        reader.push(&buf[..n]);
+        loop {
+            let block = match reader.next_block() {
+                Block::Block(block) => block,
+                Block::Incomplete => break,
+                Block::Invalid => {
+                    reader.buf.remove(0);
+                    stats.skipped_bytes += 1;
+                    continue;
+                }
+            };
+            let block_type = read_u32(&block);
+            if !reader.synchronized {
+                reader.synchronized = true;
+                if block_type != SECTION_HEADER_BLOCK {
+                    eprintln!("Joined the capture in progress");
+                    let mut headers = [0; HEADERS_SIZE];
+                    let len = write_headers(&mut headers);
+                    if output.write_all(&headers[..len]).is_err() {
+                        print_stats(&stats);
+                        return;
+                    }
+                    // The interface was described by the headers written
+                    if block_type == INTERFACE_DESCRIPTION_BLOCK {
+                        continue;
+                    }
+                }
+            }
+            if block_type == ENHANCED_PACKET_BLOCK {
+                stats.frames += 1;
+                stats.dropped += dropcount(&block);
+            }
+            // Stop when the reader of the pipe goes away
+            if output
+                .write_all(&block)
+                .and_then(|_| output.flush())
+                .is_err()
+            {
+                print_stats(&stats);
+                return;
+            }
+        }
+    }
+    print_stats(&stats);
+}
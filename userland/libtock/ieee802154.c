@@ -9,6 +9,9 @@ const int ALLOW_CFG = 2;
 
 const int SUBSCRIBE_RX = 0;
 const int SUBSCRIBE_TX = 1;
+const int SUBSCRIBE_SCAN = 2;
+const int SUBSCRIBE_ASSOCIATION = 3;
+const int SUBSCRIBE_INDIRECT    = 4;
 
 const int COMMAND_STATUS        = 1;
 const int COMMAND_SET_ADDR      = 2;
@@ -41,6 +44,30 @@ const int COMMAND_REMOVE_KEY    = 25;
 
 const int COMMAND_SEND = 26;
 
+const int COMMAND_PASSIVE_SCAN      = 27;
+const int COMMAND_ACTIVE_SCAN       = 28;
+const int COMMAND_START_COORDINATOR = 29;
+const int COMMAND_STOP_COORDINATOR  = 30;
+
+const int COMMAND_ASSOCIATE              = 31;
+const int COMMAND_DISASSOCIATE           = 32;
+const int COMMAND_SET_ASSOCIATION_PERMIT = 33;
+const int COMMAND_DISASSOCIATE_DEVICE    = 34;
+
+const int COMMAND_GET_DUTY_CYCLE_STATS   = 35;
+const int COMMAND_RESET_DUTY_CYCLE_STATS = 36;
+
+const int COMMAND_SEND_INDIRECT     = 37;
+const int COMMAND_SET_POLL_INTERVAL = 38;
+const int COMMAND_POLL              = 39;
+
+const int COMMAND_GET_RX_INFO    = 40;
+const int COMMAND_GET_LINK_STATS = 41;
+
+// Events reported to the association callback
+#define ASSOCIATION_EVENT_ASSOCIATE_DONE 0
+#define ASSOCIATION_EVENT_DISASSOCIATED  1
+
 // Temporary buffer used for some commands where the system call interface
 // parameters / return codes are not enough te contain the required data.
 unsigned char BUF_CFG[27];
@@ -120,7 +147,7 @@ int ieee802154_get_pan(unsigned short *pan) {
 
 int ieee802154_get_channel(unsigned char *channel) {
   if (!channel) return TOCK_EINVAL;
-  int err = command(RADIO_DRIVER, COMMAND_GET_PAN, 0, 0);
+  int err = command(RADIO_DRIVER, COMMAND_GET_CHANNEL, 0, 0);
   if (err > 0) {
     // Driver adds 1 to make the value positive.
     *channel = (unsigned char) (err - 1);
@@ -290,22 +317,23 @@ int ieee802154_remove_key(unsigned index) {
 // Internal callback for transmission
 static int tx_result;
 static int tx_acked;
+static int tx_counts;
 static void tx_done_callback(int result,
                              int acked,
-                             __attribute__ ((unused)) int arg3,
+                             int counts,
                              void* ud) {
   tx_result     = result;
   tx_acked      = acked;
+  tx_counts     = counts;
   *((bool*) ud) = true;
 }
 
-int ieee802154_send(unsigned short addr,
-                    security_level_t level,
+// Sets up the security parameters in ALLOW_CFG and the payload in ALLOW_TX
+static int allow_tx(security_level_t level,
                     key_id_mode_t key_id_mode,
                     unsigned char *key_id,
                     const char *payload,
                     unsigned char len) {
-  // Setup parameters in ALLOW_CFG and ALLOW_TX
   int err = allow(RADIO_DRIVER, ALLOW_CFG, (void *) BUF_CFG, 11);
   if (err < 0) return err;
   BUF_CFG[0] = level;
@@ -314,7 +342,16 @@ int ieee802154_send(unsigned short addr,
   if (bytes > 0) {
     memcpy(BUF_CFG + 2, key_id, bytes);
   }
-  err = allow(RADIO_DRIVER, ALLOW_TX, (void *) payload, len);
+  return allow(RADIO_DRIVER, ALLOW_TX, (void *) payload, len);
+}
+
+int ieee802154_send(unsigned short addr,
+                    security_level_t level,
+                    key_id_mode_t key_id_mode,
+                    unsigned char *key_id,
+                    const char *payload,
+                    unsigned char len) {
+  int err = allow_tx(level, key_id_mode, key_id, payload, len);
   if (err < 0) return err;
 
   // Subscribe to the transmit callback
@@ -330,6 +367,262 @@ int ieee802154_send(unsigned short addr,
   return tx_result;
 }
 
+int ieee802154_tx_retries(void) {
+  return tx_counts & 0xff;
+}
+
+int ieee802154_tx_channel_busy(void) {
+  return (tx_counts >> 8) & 0xff;
+}
+
+// Internal callback for frames held for indirect transmission
+static int indirect_result;
+static int indirect_acked;
+static int indirect_addr;
+static void indirect_done_callback(int result,
+                                   int acked,
+                                   int addr,
+                                   void* ud) {
+  indirect_result = result;
+  indirect_acked  = acked;
+  indirect_addr   = addr;
+  *((bool*) ud)   = true;
+}
+
+int ieee802154_send_indirect(unsigned short addr,
+                             security_level_t level,
+                             key_id_mode_t key_id_mode,
+                             unsigned char *key_id,
+                             const char *payload,
+                             unsigned char len) {
+  int err = allow_tx(level, key_id_mode, key_id, payload, len);
+  if (err < 0) return err;
+
+  bool done = false;
+  err = subscribe(RADIO_DRIVER, SUBSCRIBE_INDIRECT,
+                  indirect_done_callback, (void *) &done);
+  if (err < 0) return err;
+  err = command(RADIO_DRIVER, COMMAND_SEND_INDIRECT, (unsigned int) addr, 0);
+  if (err < 0) return err;
+
+  // Skip the completions of frames held for other devices
+  while (true) {
+    yield_for(&done);
+    if (indirect_addr == addr) break;
+    done = false;
+    err  = subscribe(RADIO_DRIVER, SUBSCRIBE_INDIRECT,
+                     indirect_done_callback, (void *) &done);
+    if (err < 0) return err;
+  }
+  if (indirect_result < 0) return indirect_result;
+  return indirect_acked ? TOCK_SUCCESS : TOCK_ENOACK;
+}
+
+int ieee802154_set_poll_interval(unsigned int interval_ms) {
+  return command(RADIO_DRIVER, COMMAND_SET_POLL_INTERVAL, interval_ms, 0);
+}
+
+int ieee802154_poll(void) {
+  return command(RADIO_DRIVER, COMMAND_POLL, 0, 0);
+}
+
+// Size of each PAN descriptor written by the kernel after a scan
+#define PAN_DESCRIPTOR_LEN 15
+
+// Buffer into which the kernel writes the PAN descriptors found by a scan
+static unsigned char BUF_SCAN[IEEE802154_MAX_PAN_DESCRIPTORS * PAN_DESCRIPTOR_LEN];
+
+// Internal callback for scans
+static int scan_result;
+static int scan_found;
+static int scan_security_failures;
+static void scan_done_callback(int result,
+                               int found,
+                               int security_failures,
+                               void* ud) {
+  scan_result            = result;
+  scan_found             = found;
+  scan_security_failures = security_failures;
+  *((bool*) ud)          = true;
+}
+
+int ieee802154_scan(bool active,
+                    unsigned int channels,
+                    unsigned char duration,
+                    ieee802154_pan_descriptor_t *descriptors,
+                    unsigned max_descriptors) {
+  if (!descriptors) return TOCK_EINVAL;
+  int err = allow(RADIO_DRIVER, ALLOW_CFG, (void *) BUF_SCAN, sizeof(BUF_SCAN));
+  if (err < 0) return err;
+
+  // Subscribe to the scan callback
+  bool scan_done = false;
+  err = subscribe(RADIO_DRIVER, SUBSCRIBE_SCAN,
+                  scan_done_callback, (void *) &scan_done);
+  if (err < 0) return err;
+
+  // Issue the scan command and wait for all channels to be scanned.
+  int command_num = active ? COMMAND_ACTIVE_SCAN : COMMAND_PASSIVE_SCAN;
+  err = command(RADIO_DRIVER, command_num, channels, (unsigned int) duration);
+  if (err < 0) return err;
+  yield_for(&scan_done);
+  if (scan_result < 0) return scan_result;
+
+  // Unpack the PAN descriptors, whose fields are little-endian.
+  unsigned found = (unsigned) scan_found;
+  if (found > max_descriptors) {
+    found = max_descriptors;
+  }
+  for (unsigned i = 0; i < found; i++) {
+    unsigned char *raw = BUF_SCAN + i * PAN_DESCRIPTOR_LEN;
+    ieee802154_pan_descriptor_t *desc = &descriptors[i];
+    desc->channel   = raw[0];
+    desc->pan       = raw[1] | (raw[2] << 8);
+    desc->addr_mode = (addr_mode_t) raw[3];
+    if (desc->addr_mode == ADDR_SHORT) {
+      desc->short_addr = raw[4] | (raw[5] << 8);
+    } else {
+      memcpy(desc->long_addr, raw + 4, 8);
+    }
+    desc->superframe_spec = raw[12] | (raw[13] << 8);
+    desc->lqi = raw[14];
+  }
+  return (int) found;
+}
+
+int ieee802154_scan_security_failures(void) {
+  return scan_security_failures;
+}
+
+int ieee802154_start_coordinator(unsigned char beacon_order) {
+  return command(RADIO_DRIVER, COMMAND_START_COORDINATOR, (unsigned int) beacon_order, 0);
+}
+
+int ieee802154_stop_coordinator(void) {
+  return command(RADIO_DRIVER, COMMAND_STOP_COORDINATOR, 0, 0);
+}
+
+// Internal callback for association events
+static int association_event;
+static int association_status;
+static int association_short_addr;
+static void association_callback(int event,
+                                 int status,
+                                 int short_addr,
+                                 void* ud) {
+  association_event      = event;
+  association_status     = status;
+  association_short_addr = short_addr;
+  *((bool*) ud) = true;
+}
+
+// Issues a command and waits for the given association event. Events are
+// delivered to a single callback, so any other event is skipped by
+// subscribing again.
+static int command_wait_association_event(int command_num,
+                                          unsigned int arg,
+                                          int event) {
+  bool done = false;
+  int err = subscribe(RADIO_DRIVER, SUBSCRIBE_ASSOCIATION,
+                      association_callback, (void *) &done);
+  if (err < 0) return err;
+  err = command(RADIO_DRIVER, command_num, arg, 0);
+  if (err < 0) return err;
+  while (true) {
+    yield_for(&done);
+    if (association_event == event) return TOCK_SUCCESS;
+    done = false;
+    err  = subscribe(RADIO_DRIVER, SUBSCRIBE_ASSOCIATION,
+                     association_callback, (void *) &done);
+    if (err < 0) return err;
+  }
+}
+
+int ieee802154_associate(const ieee802154_pan_descriptor_t *pan,
+                         unsigned char capability) {
+  if (!pan) return TOCK_EINVAL;
+  // The coordinator is described by the first 12 bytes of a PAN descriptor
+  // in the kernel format, followed by the capability information.
+  int err = allow(RADIO_DRIVER, ALLOW_CFG, (void *) BUF_CFG, 13);
+  if (err < 0) return err;
+  BUF_CFG[0] = pan->channel;
+  BUF_CFG[1] = pan->pan & 0xff;
+  BUF_CFG[2] = pan->pan >> 8;
+  BUF_CFG[3] = pan->addr_mode;
+  if (pan->addr_mode == ADDR_SHORT) {
+    BUF_CFG[4] = pan->short_addr & 0xff;
+    BUF_CFG[5] = pan->short_addr >> 8;
+  } else {
+    memcpy(BUF_CFG + 4, pan->long_addr, 8);
+  }
+  BUF_CFG[12] = capability;
+
+  // The capability information is also passed as the command argument
+  err = command_wait_association_event(COMMAND_ASSOCIATE, capability,
+                                       ASSOCIATION_EVENT_ASSOCIATE_DONE);
+  if (err < 0) return err;
+  if (association_status < 0) return association_status;
+  return association_short_addr;
+}
+
+int ieee802154_disassociate(void) {
+  return command_wait_association_event(COMMAND_DISASSOCIATE, 0,
+                                        ASSOCIATION_EVENT_DISASSOCIATED);
+}
+
+int ieee802154_set_association_permit(bool permit) {
+  return command(RADIO_DRIVER, COMMAND_SET_ASSOCIATION_PERMIT, permit ? 1 : 0, 0);
+}
+
+int ieee802154_disassociate_device(unsigned char *addr_long) {
+  if (!addr_long) return TOCK_EINVAL;
+  int err = allow(RADIO_DRIVER, ALLOW_CFG, (void *) BUF_CFG, 8);
+  if (err < 0) return err;
+  memcpy(BUF_CFG, addr_long, 8);
+  return command(RADIO_DRIVER, COMMAND_DISASSOCIATE_DEVICE, 0, 0);
+}
+
+// Reads a little-endian 32-bit value
+static unsigned int read_u32_le(const unsigned char *raw) {
+  return raw[0] | (raw[1] << 8) | (raw[2] << 16) | ((unsigned int) raw[3] << 24);
+}
+
+int ieee802154_get_duty_cycle_stats(ieee802154_duty_cycle_stats_t *stats) {
+  if (!stats) return TOCK_EINVAL;
+  int err = allow(RADIO_DRIVER, ALLOW_CFG, (void *) BUF_CFG, 20);
+  if (err < 0) return err;
+  err = command(RADIO_DRIVER, COMMAND_GET_DUTY_CYCLE_STATS, 0, 0);
+  if (err < 0) return err;
+  stats->awake_ms         = read_u32_le(BUF_CFG);
+  stats->elapsed_ms       = read_u32_le(BUF_CFG + 4);
+  stats->preambles_sent   = read_u32_le(BUF_CFG + 8);
+  stats->rendezvous       = read_u32_le(BUF_CFG + 12);
+  stats->wake_interval_ms = read_u32_le(BUF_CFG + 16);
+  return TOCK_SUCCESS;
+}
+
+int ieee802154_reset_duty_cycle_stats(void) {
+  return command(RADIO_DRIVER, COMMAND_RESET_DUTY_CYCLE_STATS, 0, 0);
+}
+
+int ieee802154_get_link_stats(unsigned index, ieee802154_link_stats_t *stats) {
+  if (!stats) return TOCK_EINVAL;
+  int err = allow(RADIO_DRIVER, ALLOW_CFG, (void *) BUF_CFG, 13);
+  if (err < 0) return err;
+  err = command(RADIO_DRIVER, COMMAND_GET_LINK_STATS, (unsigned int) index, 0);
+  if (err < 0) return err;
+  stats->addr_mode = (addr_mode_t) BUF_CFG[0];
+  if (stats->addr_mode == ADDR_SHORT) {
+    stats->short_addr = BUF_CFG[1] | (BUF_CFG[2] << 8);
+  } else {
+    memcpy(stats->long_addr, BUF_CFG + 1, 8);
+  }
+  stats->rssi = (signed char) BUF_CFG[9];
+  stats->lqi  = BUF_CFG[10];
+  stats->etx  = BUF_CFG[11] | (BUF_CFG[12] << 8);
+  return TOCK_SUCCESS;
+}
+
 // Internal callback for receive
 static void rx_done_callback(__attribute__ ((unused)) int pans,
                              __attribute__ ((unused)) int dst_addr,
@@ -362,6 +655,19 @@ int ieee802154_receive(subscribe_cb callback,
   return subscribe(RADIO_DRIVER, SUBSCRIBE_RX, callback, NULL);
 }
 
+int ieee802154_get_rx_info(ieee802154_rx_info_t *info) {
+  if (!info) return TOCK_EINVAL;
+  int err = allow(RADIO_DRIVER, ALLOW_CFG, (void *) BUF_CFG, 7);
+  if (err < 0) return err;
+  err = command(RADIO_DRIVER, COMMAND_GET_RX_INFO, 0, 0);
+  if (err < 0) return err;
+  info->rssi          = (signed char) BUF_CFG[0];
+  info->lqi           = BUF_CFG[1];
+  info->has_timestamp = BUF_CFG[2] != 0;
+  info->timestamp     = read_u32_le(BUF_CFG + 3);
+  return TOCK_SUCCESS;
+}
+
 int ieee802154_frame_get_length(const char *frame) {
   if (!frame) return 0;
   // data_offset + data_len - 2 header bytes
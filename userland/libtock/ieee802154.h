@@ -223,6 +223,35 @@ int ieee802154_send(unsigned short addr,
                     const char *payload,
                     unsigned char len);
 
+// Returns the number of times the frame sent by the last call to
+// `ieee802154_send` was retransmitted because it was not acknowledged, and the
+// number of times its transmission attempts found the channel busy. Both are
+// only counted if the MAC layer performs CSMA-CA and retransmissions in
+// software, and are 0 otherwise.
+int ieee802154_tx_retries(void);
+int ieee802154_tx_channel_busy(void);
+
+// Sends an IEEE 802.15.4 frame synchronously to a sleepy device through the
+// coordinator of the PAN, which must be this device. The frame is held until
+// the destination polls for it, and takes the same arguments as
+// `ieee802154_send`. Returns TOCK_SUCCESS once the frame is acknowledged,
+// TOCK_ENOACK if it was not acknowledged or expired before the destination
+// polled for it, and TOCK_ENOMEM if too many frames are already held.
+int ieee802154_send_indirect(unsigned short addr,
+                             security_level_t level,
+                             key_id_mode_t key_id_mode,
+                             unsigned char *key_id,
+                             const char *payload,
+                             unsigned char len);
+
+// Sets the interval at which an associated device polls its coordinator for
+// frames held for it. Received frames are delivered as usual.
+// `interval_ms` (in): The interval, in milliseconds. 0 disables polling.
+int ieee802154_set_poll_interval(unsigned int interval_ms);
+
+// Polls the coordinator once for frames held for this device.
+int ieee802154_poll(void);
+
 // Maximum size required of a buffer to contain the IEEE 802.15.4 frame data
 // passed to userspace from the kernel. Consists of 2 extra bytes followed by
 // the whole IEEE 802.15.4 MTU, which is 127 bytes.
@@ -251,6 +280,24 @@ int ieee802154_receive(subscribe_cb callback,
                        const char *frame,
                        unsigned char len);
 
+// Metadata of a received frame, as measured by the radio.
+typedef struct {
+  // Received signal strength, in dBm
+  signed char rssi;
+  // Link quality indication, from 0 (lowest) to 255 (highest)
+  unsigned char lqi;
+  // Whether the radio timestamped the frame
+  bool has_timestamp;
+  // Time at which the start of the frame was received, in ticks of the alarm
+  // used by the radio
+  unsigned int timestamp;
+} ieee802154_rx_info_t;
+
+// Gets the metadata of the last frame received into the buffer provided to
+// `ieee802154_receive_*`. Returns TOCK_EINVAL if no frame was received yet.
+// `info` (out): The metadata of the frame.
+int ieee802154_get_rx_info(ieee802154_rx_info_t *info);
+
 // IEEE 802.15.4 received frame inspection functions. The frames are returned
 // to userspace in a particular format that might include more bytes than just
 // the raw 802.15.4 frame. In all of the below calls, `frame` is assumed to be
@@ -312,6 +359,145 @@ bool ieee802154_frame_get_dst_pan(const char *frame,
 bool ieee802154_frame_get_src_pan(const char *frame,
                                   unsigned short *pan);
 
+// IEEE 802.15.4 PAN discovery and coordination functions. These are only
+// available if the kernel provides a MAC layer management entity to the
+// driver; otherwise they return TOCK_ENOSUPPORT.
+
+// Maximum number of PANs that can be found by a single scan
+#define IEEE802154_MAX_PAN_DESCRIPTORS 8
+
+// Channel mask selecting all channels of the 2.4 GHz band, 11 to 26
+#define IEEE802154_CHANNELS_2450MHZ 0x07fff800
+
+// Information about a PAN, collected from one of its beacons.
+typedef struct {
+  // Channel on which the beacon was received
+  unsigned char channel;
+  // PAN ID and address of the coordinator. Only one of `short_addr` and
+  // `long_addr` is valid, depending on `addr_mode`.
+  unsigned short pan;
+  addr_mode_t addr_mode;
+  unsigned short short_addr;
+  unsigned char long_addr[8];
+  // Superframe specification advertised in the beacon: the beacon order is in
+  // the lowest 4 bits, and bit 15 is set if the PAN permits association.
+  unsigned short superframe_spec;
+  // Link quality of the beacon, from 0 (lowest) to 255 (highest)
+  unsigned char lqi;
+} ieee802154_pan_descriptor_t;
+
+// Scans channels synchronously for PANs. Returns the number of PANs found, or
+// a negative error code. When done, the radio is returned to its channel.
+// `active` (in): Whether to broadcast a beacon request on each channel, or to
+//   only listen for beacons sent periodically.
+// `channels` (in): Mask of the channels to scan. Bit `n` selects channel `n`.
+// `duration` (in): The time spent on each channel is `960 * (2^duration + 1)`
+//   symbols, that is 30.72ms for `duration` 0. 0 <= duration <= 14.
+// `descriptors` (out): Array in which to write the PANs found.
+// `max_descriptors` (in): Length of the `descriptors` array.
+int ieee802154_scan(bool active,
+                    unsigned int channels,
+                    unsigned char duration,
+                    ieee802154_pan_descriptor_t *descriptors,
+                    unsigned max_descriptors);
+
+// Returns the number of beacons that the last scan dropped because they failed
+// the incoming frame security procedure. The PANs that sent them are not
+// among the PANs found by the scan.
+int ieee802154_scan_security_failures(void);
+
+// Starts acting as the coordinator of the configured PAN, answering beacon
+// requests. Beacons are also sent every `960 * 2^beacon_order` symbols if
+// `beacon_order` is less than 15.
+int ieee802154_start_coordinator(unsigned char beacon_order);
+
+// Stops acting as a PAN coordinator.
+int ieee802154_stop_coordinator(void);
+
+// Capability information flags describing a device requesting association
+#define IEEE802154_CAPABILITY_FFD              (1 << 1)
+#define IEEE802154_CAPABILITY_MAINS_POWERED    (1 << 2)
+#define IEEE802154_CAPABILITY_RX_ON_WHEN_IDLE  (1 << 3)
+#define IEEE802154_CAPABILITY_SECURITY         (1 << 6)
+#define IEEE802154_CAPABILITY_ALLOCATE_ADDRESS (1 << 7)
+
+// Associates synchronously with the coordinator of a PAN, typically found by
+// `ieee802154_scan`. Returns the short address allocated by the coordinator,
+// which is now the address of the radio, or a negative error code:
+// TOCK_ENOMEM if the PAN is at capacity, TOCK_FAIL if the coordinator denied
+// access, and TOCK_ENOACK if it did not respond. 0xfffe is returned if no
+// short address was requested.
+// `pan` (in): The PAN to join.
+// `capability` (in): A combination of IEEE802154_CAPABILITY_* flags.
+int ieee802154_associate(const ieee802154_pan_descriptor_t *pan,
+                         unsigned char capability);
+
+// Notifies the coordinator synchronously that this device leaves the PAN.
+// The short address and PAN ID of the radio are then reset to 0xffff.
+int ieee802154_disassociate(void);
+
+// Sets whether association requests are accepted while acting as a PAN
+// coordinator.
+int ieee802154_set_association_permit(bool permit);
+
+// Asks the associated device with the given long address to leave the PAN
+// coordinated by this device.
+// `addr_long` (in): The 8-byte long address of the device.
+int ieee802154_disassociate_device(unsigned char *addr_long);
+
+// Energy usage statistics of a MAC layer that duty-cycles the radio, such as
+// X-MAC. These functions return TOCK_ENOSUPPORT if the kernel does not expose
+// such a MAC layer to the driver.
+typedef struct {
+  // Time during which the radio was on, in milliseconds
+  unsigned int awake_ms;
+  // Total time elapsed, in milliseconds
+  unsigned int elapsed_ms;
+  // Number of preambles (wake-up frames) transmitted
+  unsigned int preambles_sent;
+  // Number of transmissions for which the destination woke up and
+  // acknowledged a preamble
+  unsigned int rendezvous;
+  // Current interval between two wake-ups of the radio, in milliseconds
+  unsigned int wake_interval_ms;
+} ieee802154_duty_cycle_stats_t;
+
+// Gets the statistics accumulated since they were last reset.
+// `stats` (out): The statistics.
+int ieee802154_get_duty_cycle_stats(ieee802154_duty_cycle_stats_t *stats);
+
+// Resets the accumulated statistics.
+int ieee802154_reset_duty_cycle_stats(void);
+
+// ETX values are multiplied by this divisor
+#define IEEE802154_ETX_DIVISOR 128
+
+// Link quality estimates that the kernel keeps for each neighbor from which
+// frames were received. Neighbors are identified by the address used in
+// their frames. These functions return TOCK_ENOSUPPORT if the kernel does not
+// track link quality.
+typedef struct {
+  // Address of the neighbor. Only one of `short_addr` and `long_addr` is
+  // valid, depending on `addr_mode`.
+  addr_mode_t addr_mode;
+  unsigned short short_addr;
+  unsigned char long_addr[8];
+  // Average RSSI of the frames received from the neighbor, in dBm
+  signed char rssi;
+  // Average LQI of the frames received from the neighbor
+  unsigned char lqi;
+  // Average number of transmissions needed for a frame sent to the neighbor
+  // to be acknowledged, multiplied by IEEE802154_ETX_DIVISOR, or 0xffff if no
+  // frame was sent to it
+  unsigned short etx;
+} ieee802154_link_stats_t;
+
+// Gets the link quality estimates of the neighbor at `index`. Neighbors are
+// numbered from 0, and TOCK_EINVAL is returned past the last one.
+// `index` (in): The index of the neighbor.
+// `stats` (out): The link quality estimates.
+int ieee802154_get_link_stats(unsigned index, ieee802154_link_stats_t *stats);
+
 #ifdef __cplusplus
 }
 #endif
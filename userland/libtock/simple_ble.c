@@ -14,6 +14,9 @@
 
 static unsigned char advertisement_buf[ADV_SIZE];
 
+// allowed with a size of 0 to disable a buffer, as the kernel rejects NULL
+static uint8_t empty_buf[1];
+
 /*******************************************************************************
  *   INTERNAL BLE HELPER FUNCTION Prototypes
  *
@@ -131,6 +134,36 @@ int ble_advertise_manufacturer_specific_data(uint8_t *data, uint8_t size_b) {
   }
 }
 
+int ble_set_advertising_data(uint8_t *data, uint8_t len) {
+  if (data == NULL) {
+    return TOCK_FAIL;
+  } else {
+    return allow(BLE_DRIVER_NUMBER, BLE_CFG_ADV_DATA_ALLOW, (void *)data, len);
+  }
+}
+
+int ble_set_ad_structure(GapAdvertisementData_t ad_type, uint8_t *data,
+                         uint8_t len) {
+  // potential buffer overflow in libtock generate error
+  if (len + 2 > MAX_SIZE || (data == NULL && len > 0)) {
+    return TOCK_FAIL;
+  } else {
+    // the kernel copies the AD structure
+    uint8_t structure[MAX_SIZE];
+    structure[0] = len + 1;
+    structure[1] = ad_type;
+    if (len > 0) {
+      memcpy(structure + 2, data, len);
+    }
+    return allow(BLE_DRIVER_NUMBER, BLE_CFG_AD_STRUCTURE_ALLOW,
+                 (void *)structure, len + 2);
+  }
+}
+
+int ble_remove_ad_structure(GapAdvertisementData_t ad_type) {
+  return command(BLE_DRIVER_NUMBER, BLE_REMOVE_AD_STRUCTURE_CMD, ad_type, 0);
+}
+
 int ble_start_passive_scan(uint8_t *data, uint8_t max_len,
                            subscribe_cb callback) {
   if (data == NULL || callback == NULL) {
@@ -155,6 +188,71 @@ int ble_stop_passive_scan(void) {
   return command(BLE_DRIVER_NUMBER, BLE_ADV_STOP_CMD, 1, 0);
 }
 
+int ble_start_active_scan(uint8_t *data, uint8_t max_len,
+                          subscribe_cb callback) {
+  if (data == NULL || callback == NULL) {
+    return TOCK_FAIL;
+  } else {
+    int err;
+
+    err = subscribe(BLE_DRIVER_NUMBER, BLE_SCAN_SUB, callback, NULL);
+    if (err < TOCK_SUCCESS)
+      return err;
+
+    err =
+      allow(BLE_DRIVER_NUMBER, BLE_CFG_SCAN_BUF_ALLOW, (void *)data, max_len);
+    if (err < TOCK_SUCCESS)
+      return err;
+
+    return command(BLE_DRIVER_NUMBER, BLE_ACTIVE_SCAN_CMD, 1, 0);
+  }
+}
+
+int ble_stop_active_scan(void) {
+  return command(BLE_DRIVER_NUMBER, BLE_ADV_STOP_CMD, 1, 0);
+}
+
+int ble_scan_filter_duplicates(bool enable) {
+  return command(BLE_DRIVER_NUMBER, BLE_SCAN_DUPLICATE_FILTER_CMD, enable, 0);
+}
+
+int ble_scan_filter_ad_type(GapAdvertisementData_t ad_type) {
+  return command(BLE_DRIVER_NUMBER, BLE_SCAN_AD_TYPE_FILTER_CMD, ad_type, 0);
+}
+
+int ble_scan_filter_addresses(uint8_t *addresses, uint8_t len) {
+  // an empty buffer disables the filter
+  if (addresses == NULL) {
+    return allow(BLE_DRIVER_NUMBER, BLE_CFG_SCAN_ADDRESS_FILTER_ALLOW,
+                 (void *)empty_buf, 0);
+  } else {
+    return allow(BLE_DRIVER_NUMBER, BLE_CFG_SCAN_ADDRESS_FILTER_ALLOW,
+                 (void *)addresses, len);
+  }
+}
+
+int ble_scan_ad_index(uint8_t *index, uint8_t len) {
+  // an empty buffer disables the index
+  if (index == NULL) {
+    return allow(BLE_DRIVER_NUMBER, BLE_CFG_SCAN_AD_INDEX_ALLOW,
+                 (void *)empty_buf, 0);
+  } else {
+    return allow(BLE_DRIVER_NUMBER, BLE_CFG_SCAN_AD_INDEX_ALLOW, (void *)index,
+                 len);
+  }
+}
+
+int ble_set_scan_response(uint8_t *data, uint8_t len) {
+  // an empty buffer disables scan responses
+  if (data == NULL) {
+    return allow(BLE_DRIVER_NUMBER, BLE_CFG_SCAN_RESPONSE_ALLOW,
+                 (void *)empty_buf, 0);
+  } else {
+    return allow(BLE_DRIVER_NUMBER, BLE_CFG_SCAN_RESPONSE_ALLOW, (void *)data,
+                 len);
+  }
+}
+
 int ble_set_tx_power(TxPower_t power_level) {
   return command(BLE_DRIVER_NUMBER, BLE_CFG_TX_POWER_CMD, power_level, 0);
 }
@@ -25,9 +25,22 @@ extern "C" {
 #define BLE_ADV_CLEAR_DATA_CMD 4
 #define BLE_SCAN_CMD 5
 #define BLE_REQ_ADV_ADDR 6
+#define BLE_ACTIVE_SCAN_CMD 7
+#define BLE_SCAN_DUPLICATE_FILTER_CMD 8
+#define BLE_SCAN_AD_TYPE_FILTER_CMD 9
+#define BLE_REMOVE_AD_STRUCTURE_CMD 10
 #define BLE_SCAN_SUB 0
 #define BLE_CFG_SCAN_BUF_ALLOW 0x31
 #define BLE_CFG_ADV_BUF_ALLOW 0x32
+#define BLE_CFG_SCAN_RESPONSE_ALLOW 0x33
+#define BLE_CFG_SCAN_ADDRESS_FILTER_ALLOW 0x34
+#define BLE_CFG_ADV_DATA_ALLOW 0x35
+#define BLE_CFG_AD_STRUCTURE_ALLOW 0x36
+#define BLE_CFG_SCAN_AD_INDEX_ALLOW 0x37
+
+// Size of a scanning buffer that holds any advertisement along with the
+// data of its scan response, when scanning actively
+#define BLE_ACTIVE_SCAN_BUF_SIZE (39 + 31)
 
 typedef enum {
   GAP_FLAGS = 0x01, /* Flags, see enum below */
@@ -149,6 +162,31 @@ int ble_advertise_service_data(uint16_t uuid16, uint8_t *data, uint8_t size_b);
 // size_b               - size of data in bytes
 int ble_advertise_manufacturer_specific_data(uint8_t *data, uint8_t size_b);
 
+// replace the advertising data, excluding the advertisement address, with
+// pre-built AD structures, which are copied
+//
+// data                 - AD structures (up to 31 bytes), each made of a
+//                        length, an AD type and the AD data
+// len                  - size of data in bytes
+//
+int ble_set_advertising_data(uint8_t *data, uint8_t len);
+
+// add an AD structure to the advertising data, or replace the one of the
+// same AD type, leaving the others as they are
+//
+// ad_type              - the AD type
+// data                 - the AD data
+// len                  - size of data in bytes
+//
+int ble_set_ad_structure(GapAdvertisementData_t ad_type, uint8_t *data,
+                         uint8_t len);
+
+// remove the AD structure of an AD type from the advertising data
+//
+// ad_type              - the AD type
+//
+int ble_remove_ad_structure(GapAdvertisementData_t ad_type);
+
 // passive scanning of advertisements
 //
 // data                 - array of bytes to write the received advertisment to
@@ -175,6 +213,82 @@ int ble_start_passive_scan(uint8_t *data, uint8_t len, subscribe_cb callback);
 // stop passive scanning
 int ble_stop_passive_scan(void);
 
+// active scanning of advertisements
+//
+// Like passive scanning, but scannable advertisements are answered with a
+// scan request, and reported along with the data of the scan response.
+//
+// data                 - array of bytes to write the received advertisement
+//                        to, followed by the data of its scan response
+//                        (BLE_ACTIVE_SCAN_BUF_SIZE bytes)
+// len                  - max_size
+// callback             - callback handler to call when an advertisement is
+//                        received
+//
+// type signature of the callback handler:
+// static void callback(int result,
+//                      int len,
+//                      int scan_response_len,
+//                      __attribute__((unused)) void* ud);
+//
+// result               - kernel indicates whether the radio rx was successful
+//                        or not
+// len                  - the number of bytes of the advertisement
+// scan_response_len    - the number of bytes of scan response data following
+//                        the advertisement, 0 if there is none
+//
+int ble_start_active_scan(uint8_t *data, uint8_t len, subscribe_cb callback);
+
+// stop active scanning
+int ble_stop_active_scan(void);
+
+// only report the first advertisement of each advertiser while scanning
+//
+// enable               - true to filter out duplicates, false to report
+//                        every advertisement
+//
+int ble_scan_filter_duplicates(bool enable);
+
+// only report advertisements that contain an AD type while scanning
+//
+// ad_type              - the AD type, or 0 to report advertisements of any
+//                        type
+//
+int ble_scan_filter_ad_type(GapAdvertisementData_t ad_type);
+
+// only report advertisements from a list of addresses while scanning
+//
+// addresses            - array of 6-byte addresses, in over-the-air order,
+//                        which must remain valid while scanning, or NULL to
+//                        report advertisements from any address
+// len                  - size of addresses in bytes
+//
+int ble_scan_filter_addresses(uint8_t *addresses, uint8_t len);
+
+// have the kernel index the AD structures of each advertisement reported
+// while scanning
+//
+// The kernel writes a 3-byte entry per AD structure before calling the
+// scanning callback: the AD type, the offset of the AD data in the scanning
+// buffer and its length, followed by an AD type of 0 if there is room for it.
+//
+// index                - array of bytes to write the entries to, which must
+//                        remain valid while scanning, or NULL to disable the
+//                        index
+// len                  - size of index in bytes
+//
+int ble_scan_ad_index(uint8_t *index, uint8_t len);
+
+// configure the scan response data, and advertise that the device is
+// scannable
+//
+// data                 - AD structures of the scan response (up to 31
+//                        bytes), which must remain valid while advertising,
+//                        or NULL to disable scan responses
+// len                  - size of data in bytes
+//
+int ble_set_scan_response(uint8_t *data, uint8_t len);
+
 // configure tx_power
 //
 // power_level          - transmitting power in dBM of the radio
@@ -0,0 +1,82 @@
+#include "usb_hid.h"
+
+int usb_hid_exists(void) {
+  return command(DRIVER_NUM_USB_HID, 0, 0, 0) >= 0;
+}
+
+int usb_hid_set_report_descriptor(const void* descriptor, size_t len) {
+  return allow(DRIVER_NUM_USB_HID, 0, (void*) descriptor, len);
+}
+
+int usb_hid_set_input_buffer(const void* buf, size_t len) {
+  return allow(DRIVER_NUM_USB_HID, 1, (void*) buf, len);
+}
+
+int usb_hid_set_output_buffer(void* buf, size_t len) {
+  return allow(DRIVER_NUM_USB_HID, 2, buf, len);
+}
+
+int usb_hid_subscribe(subscribe_cb callback, void *ud) {
+  return subscribe(DRIVER_NUM_USB_HID, 0, callback, ud);
+}
+
+int usb_hid_send_input_report_async(size_t len) {
+  return command(DRIVER_NUM_USB_HID, 1, len, 0);
+}
+
+struct data {
+  bool fired;
+  int event;
+  int len;
+};
+
+static void callback(int event, int len, __attribute__((unused)) int v2, void *data)
+{
+  struct data *d = data;
+
+  // Only the event being waited for ends the wait
+  if (event == d->event) {
+    d->fired = true;
+    d->len   = len;
+  }
+}
+
+int usb_hid_send_input_report(const void* report, size_t len)
+{
+  int status;
+
+  struct data d = { .fired = false, .event = USB_HID_INPUT_REPORT_SENT };
+
+  if ((status = usb_hid_set_input_buffer(report, len)) != TOCK_SUCCESS) {
+    return status;
+  }
+
+  if ((status = usb_hid_subscribe(callback, (void *) &d)) != TOCK_SUCCESS) {
+    return status;
+  }
+
+  if ((status = usb_hid_send_input_report_async(len)) != TOCK_SUCCESS) {
+    return status;
+  }
+
+  yield_for(&d.fired);
+  return d.len == 0 ? TOCK_FAIL : TOCK_SUCCESS;
+}
+
+int usb_hid_receive_output_report(void* buf, size_t len)
+{
+  int status;
+
+  struct data d = { .fired = false, .event = USB_HID_OUTPUT_REPORT_RECEIVED };
+
+  if ((status = usb_hid_set_output_buffer(buf, len)) != TOCK_SUCCESS) {
+    return status;
+  }
+
+  if ((status = usb_hid_subscribe(callback, (void *) &d)) != TOCK_SUCCESS) {
+    return status;
+  }
+
+  yield_for(&d.fired);
+  return d.len;
+}
@@ -0,0 +1,70 @@
+#pragma once
+
+#include "tock.h"
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define DRIVER_NUM_USB_HID 0x20006
+
+// Events passed to the callback
+#define USB_HID_INPUT_REPORT_SENT      0
+#define USB_HID_OUTPUT_REPORT_RECEIVED 1
+
+// Largest input or output report
+#define USB_HID_MAX_REPORT_SIZE 64
+
+// Does the driver exist?
+int usb_hid_exists(void);
+
+// Share the report descriptor, which the host reads when it enumerates the
+// device
+//
+// The first app to share a report descriptor owns the HID function until it
+// exits. Share it before the device is enumerated, as a change only takes
+// effect when the device is enumerated again.
+//
+// Returns EBUSY if another app owns the function.
+int usb_hid_set_report_descriptor(const void* descriptor, size_t len);
+
+// Provide the buffer that input reports are sent from
+int usb_hid_set_input_buffer(const void* buf, size_t len);
+
+// Provide the buffer that output reports from the host are copied to
+int usb_hid_set_output_buffer(void* buf, size_t len);
+
+// Register a callback to receive HID events
+//
+// The callback will receive these parameters, in order:
+//    event: USB_HID_INPUT_REPORT_SENT or USB_HID_OUTPUT_REPORT_RECEIVED
+//    len:   For USB_HID_INPUT_REPORT_SENT, the length of the input report
+//           sent, or 0 if a bus reset dropped it. For
+//           USB_HID_OUTPUT_REPORT_RECEIVED, the number of bytes of the output
+//           report copied to the output buffer.
+int usb_hid_subscribe(subscribe_cb, void *);
+
+// Send the first `len` bytes of the input buffer as an input report
+//
+// If SUCCESS is returned, USB_HID_INPUT_REPORT_SENT is passed to the
+// registered callback once the host has read the report.
+//
+// Returns EBUSY if another app owns the function or a report is being sent.
+// Returns ESIZE if `len` is 0, or larger than the input buffer or
+// USB_HID_MAX_REPORT_SIZE.
+int usb_hid_send_input_report_async(size_t len);
+
+// Send an input report and wait for the host to read it
+//
+// Returns SUCCESS once the host has read the report, and FAIL if a bus reset
+// dropped it.
+int usb_hid_send_input_report(const void* report, size_t len);
+
+// Wait for an output report from the host
+//
+// Returns the number of bytes of the report copied to `buf`.
+int usb_hid_receive_output_report(void* buf, size_t len);
+
+#ifdef __cplusplus
+}
+#endif